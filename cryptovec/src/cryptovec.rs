@@ -1,6 +1,7 @@
 use std::ops::{Deref, DerefMut, Index, IndexMut, Range, RangeFrom, RangeFull, RangeTo};
 
-use crate::platform::{self, memset, mlock, munlock};
+use crate::platform::memset;
+use crate::pool;
 
 /// A buffer which zeroes its memory on `.clear()`, `.resize()`, and
 /// reallocations, to avoid copying secrets around.
@@ -47,14 +48,25 @@ impl From<String> for CryptoVec {
     }
 }
 
+/// Copies the bytes, and then zeroes the vector.
 impl From<Vec<u8>> for CryptoVec {
-    fn from(e: Vec<u8>) -> Self {
+    fn from(mut e: Vec<u8>) -> Self {
         let mut c = CryptoVec::new_zeroed(e.len());
         c.clone_from_slice(&e[..]);
+        wipe(&mut e);
         c
     }
 }
 
+/// Zero `bytes`, in a way the compiler can't optimise away even if
+/// they are never read again.
+pub(crate) fn wipe(bytes: &mut [u8]) {
+    for b in bytes.iter_mut() {
+        unsafe { std::ptr::write_volatile(b, 0) }
+    }
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
 // Indexing implementations
 impl Index<RangeFrom<usize>> for CryptoVec {
     type Output = [u8];
@@ -142,9 +154,7 @@ impl CryptoVec {
     pub fn new_zeroed(size: usize) -> CryptoVec {
         unsafe {
             let capacity = size.next_power_of_two();
-            let layout = std::alloc::Layout::from_size_align_unchecked(capacity, 1);
-            let p = std::alloc::alloc_zeroed(layout);
-            mlock(p, capacity);
+            let p = pool::alloc(capacity);
             CryptoVec { p, capacity, size }
         }
     }
@@ -153,9 +163,7 @@ impl CryptoVec {
     pub fn with_capacity(capacity: usize) -> CryptoVec {
         unsafe {
             let capacity = capacity.next_power_of_two();
-            let layout = std::alloc::Layout::from_size_align_unchecked(capacity, 1);
-            let p = std::alloc::alloc_zeroed(layout);
-            mlock(p, capacity);
+            let p = pool::alloc(capacity);
             CryptoVec {
                 p,
                 capacity,
@@ -200,18 +208,14 @@ impl CryptoVec {
             unsafe {
                 let next_capacity = size.next_power_of_two();
                 let old_ptr = self.p;
-                let next_layout = std::alloc::Layout::from_size_align_unchecked(next_capacity, 1);
-                self.p = std::alloc::alloc_zeroed(next_layout);
-                mlock(self.p, next_capacity);
+                self.p = pool::alloc(next_capacity);
 
                 if self.capacity > 0 {
                     std::ptr::copy_nonoverlapping(old_ptr, self.p, self.size);
                     for i in 0..self.size {
                         std::ptr::write_volatile(old_ptr.add(i), 0)
                     }
-                    munlock(old_ptr, self.capacity);
-                    let layout = std::alloc::Layout::from_size_align_unchecked(self.capacity, 1);
-                    std::alloc::dealloc(old_ptr, layout);
+                    pool::free(old_ptr, self.capacity);
                 }
 
                 if self.p.is_null() {
@@ -348,9 +352,8 @@ impl Drop for CryptoVec {
                 for i in 0..self.size {
                     std::ptr::write_volatile(self.p.add(i), 0);
                 }
-                platform::munlock(self.p, self.capacity);
-                let layout = std::alloc::Layout::from_size_align_unchecked(self.capacity, 1);
-                std::alloc::dealloc(self.p, layout);
+                // The memory beyond `size` is already zero.
+                pool::free(self.p, self.capacity);
             }
         }
     }
@@ -516,4 +519,17 @@ mod test {
         crypto_vec.resize_mut(4).clone_from_slice(b"test");
         assert_eq!(crypto_vec.as_ref(), b"test");
     }
+
+    #[wasm_bindgen_test]
+    fn test_pooled_buffer_reuse() {
+        let mut crypto_vec = CryptoVec::with_capacity(64);
+        crypto_vec.extend(&[0xff; 64]);
+        let p = crypto_vec.p;
+        drop(crypto_vec);
+
+        // The buffer comes back from the pool, zeroed.
+        let crypto_vec = CryptoVec::new_zeroed(64);
+        assert_eq!(crypto_vec.p, p);
+        assert!(crypto_vec.iter().all(|&x| x == 0));
+    }
 }
@@ -24,8 +24,14 @@
 mod cryptovec;
 pub use cryptovec::CryptoVec;
 
+mod secret;
+pub use secret::SecretBuffer;
+
 // Platform-specific modules
 mod platform;
 
+// Pool of locked buffers
+mod pool;
+
 #[cfg(feature = "ssh-encoding")]
 mod ssh;
@@ -0,0 +1,111 @@
+//! Per-thread pool of locked buffers, so that the buffers allocated
+//! and freed for each packet don't cost an allocation, an `mlock` and
+//! a `munlock` every time.
+//!
+//! Buffers are zeroed before being put back in the pool, and only
+//! buffers whose capacity is a power of two up to
+//! [`MAX_POOLED_CAPACITY`] are kept.
+
+use std::alloc::Layout;
+use std::cell::RefCell;
+
+use crate::platform::{mlock, munlock};
+
+/// Larger buffers are always freed: SSH packets are at most 35000
+/// bytes long, which fits in 64kiB.
+const MAX_POOLED_CAPACITY: usize = 1 << 16;
+
+/// Number of free buffers kept per capacity and per thread.
+const MAX_POOLED_BUFFERS: usize = 16;
+
+const BUCKETS: usize = MAX_POOLED_CAPACITY.trailing_zeros() as usize + 1;
+
+struct Pool {
+    /// Free buffers, indexed by the log2 of their capacity.
+    free: [Vec<*mut u8>; BUCKETS],
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        for (i, bucket) in self.free.iter_mut().enumerate() {
+            for p in bucket.drain(..) {
+                unsafe { release(p, 1 << i) }
+            }
+        }
+    }
+}
+
+thread_local! {
+    static POOL: RefCell<Pool> = RefCell::new(Pool {
+        free: Default::default(),
+    });
+}
+
+fn bucket(capacity: usize) -> Option<usize> {
+    if capacity.is_power_of_two() && capacity <= MAX_POOLED_CAPACITY {
+        Some(capacity.trailing_zeros() as usize)
+    } else {
+        None
+    }
+}
+
+/// Allocate a zeroed and locked buffer of `capacity` bytes, reusing a
+/// pooled buffer if there is one.
+///
+/// # Safety
+///
+/// `capacity` must not be 0.
+pub(crate) unsafe fn alloc(capacity: usize) -> *mut u8 {
+    if let Some(i) = bucket(capacity) {
+        let pooled = POOL
+            .try_with(|pool| {
+                pool.try_borrow_mut()
+                    .ok()
+                    .and_then(|mut pool| pool.free.get_mut(i).and_then(Vec::pop))
+            })
+            .ok()
+            .flatten();
+        if let Some(p) = pooled {
+            return p;
+        }
+    }
+    let p = std::alloc::alloc_zeroed(Layout::from_size_align_unchecked(capacity, 1));
+    mlock(p, capacity);
+    p
+}
+
+/// Give back a buffer returned by [`alloc`]. Its contents must have
+/// been zeroed.
+///
+/// # Safety
+///
+/// `p` must have been returned by [`alloc`] with the same `capacity`,
+/// and must not be used afterwards.
+pub(crate) unsafe fn free(p: *mut u8, capacity: usize) {
+    if let Some(i) = bucket(capacity) {
+        let pooled = POOL
+            .try_with(|pool| {
+                let mut pool = match pool.try_borrow_mut() {
+                    Ok(pool) => pool,
+                    Err(_) => return false,
+                };
+                match pool.free.get_mut(i) {
+                    Some(bucket) if bucket.len() < MAX_POOLED_BUFFERS => {
+                        bucket.push(p);
+                        true
+                    }
+                    _ => false,
+                }
+            })
+            .unwrap_or(false);
+        if pooled {
+            return;
+        }
+    }
+    release(p, capacity)
+}
+
+unsafe fn release(p: *mut u8, capacity: usize) {
+    munlock(p, capacity);
+    std::alloc::dealloc(p, Layout::from_size_align_unchecked(capacity, 1));
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+
+use subtle::ConstantTimeEq;
+
+use crate::CryptoVec;
+
+/// Secret bytes, such as a password or key material.
+///
+/// Like a [`CryptoVec`], the bytes are locked in memory and zeroed
+/// when dropped. Unlike it, a `SecretBuffer` can't be cloned or
+/// printed, and comparisons take the same time wherever the first
+/// difference is, so that they can be used to check passwords.
+///
+/// ```
+/// use russh_cryptovec::SecretBuffer;
+///
+/// let expected = SecretBuffer::from("hunter2");
+/// assert!(expected.eq_bytes(b"hunter2"));
+/// assert_eq!(format!("{:?}", expected), "SecretBuffer([hidden])");
+/// ```
+#[derive(Default)]
+pub struct SecretBuffer(CryptoVec);
+
+impl SecretBuffer {
+    pub fn new() -> Self {
+        SecretBuffer(CryptoVec::new())
+    }
+
+    /// Copy `bytes`. The caller remains responsible for zeroing them.
+    pub fn from_slice(bytes: &[u8]) -> Self {
+        SecretBuffer(CryptoVec::from_slice(bytes))
+    }
+
+    /// The secret bytes. Copies of them won't be zeroed.
+    pub fn expose_secret(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The secret as a string, if it is valid UTF-8.
+    pub fn expose_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Append `bytes` to the secret.
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.0.extend(bytes)
+    }
+
+    /// Zero the secret, and make it empty.
+    pub fn clear(&mut self) {
+        self.0.clear()
+    }
+
+    /// Compare the secret with `other` in constant time. Only the
+    /// lengths may be told apart by timing.
+    pub fn eq_bytes(&self, other: &[u8]) -> bool {
+        self.0[..].ct_eq(other).into()
+    }
+}
+
+impl PartialEq for SecretBuffer {
+    fn eq(&self, other: &Self) -> bool {
+        self.eq_bytes(&other.0)
+    }
+}
+
+impl Eq for SecretBuffer {}
+
+impl fmt::Debug for SecretBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBuffer([hidden])")
+    }
+}
+
+/// Moves the bytes of the vector, zeroing it.
+impl From<Vec<u8>> for SecretBuffer {
+    fn from(bytes: Vec<u8>) -> Self {
+        SecretBuffer(CryptoVec::from(bytes))
+    }
+}
+
+/// Moves the bytes of the string, zeroing it.
+impl From<String> for SecretBuffer {
+    fn from(s: String) -> Self {
+        SecretBuffer(CryptoVec::from(s))
+    }
+}
+
+impl From<&str> for SecretBuffer {
+    fn from(s: &str) -> Self {
+        SecretBuffer::from_slice(s.as_bytes())
+    }
+}
+
+impl From<CryptoVec> for SecretBuffer {
+    fn from(bytes: CryptoVec) -> Self {
+        SecretBuffer(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    #[test]
+    fn test_secret_buffer() {
+        let mut secret = SecretBuffer::from(String::from("password"));
+        assert_eq!(secret.expose_str(), Some("password"));
+        assert!(secret.eq_bytes(b"password"));
+        assert!(!secret.eq_bytes(b"passwore"));
+        assert!(!secret.eq_bytes(b"pass"));
+        assert_eq!(secret, SecretBuffer::from("password"));
+        secret.extend(b"!");
+        assert_ne!(secret, SecretBuffer::from("password"));
+        assert!(!format!("{:?}", secret).contains("password"));
+        secret.clear();
+        assert!(secret.is_empty());
+    }
+}
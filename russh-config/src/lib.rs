@@ -4,12 +4,14 @@
     clippy::indexing_slicing,
     clippy::panic
 )]
+use std::collections::{HashMap, HashSet};
 use std::io::Read;
-use std::net::ToSocketAddrs;
-use std::path::Path;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 
 use globset::Glob;
 use log::debug;
+pub use russh_util::net::{AddressFamily, IpQos, ProxyConnector, ProxyKind, SocketOptions};
 use thiserror::*;
 
 #[derive(Debug, Error)]
@@ -21,22 +23,97 @@ pub enum Error {
     NoHome,
     #[error("Cannot resolve the address")]
     NotResolvable,
+    #[error("Include loop or maximum include depth reached at {0:?}")]
+    IncludeDepth(PathBuf),
     #[error("{}", 0)]
     Io(#[from] std::io::Error),
 }
 
 mod proxy;
 pub use proxy::*;
+pub mod server;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
     pub user: String,
     pub host_name: String,
+    /// The host name given to [`parse`], before any `HostName`.
+    pub original_host: String,
     pub port: u16,
+    /// The first `IdentityFile` found for this host.
     pub identity_file: Option<String>,
+    /// All `IdentityFile` entries found for this host, in order. See
+    /// [`Config::identities`] to get the default ones if empty.
+    pub identity_files: Vec<PathBuf>,
+    /// All `CertificateFile` entries found for this host, in order.
+    pub certificate_files: Vec<PathBuf>,
     pub proxy_command: Option<String>,
     pub proxy_jump: Option<String>,
+    /// `ProxyUrl`: an HTTP or SOCKS5 proxy to connect to `HostName`
+    /// through, written as in [`ProxyConnector::parse`]. This is not an
+    /// OpenSSH option, which needs a `ProxyCommand` for this.
+    pub proxy: Option<ProxyConnector>,
     pub add_keys_to_agent: AddKeysToAgent,
+    pub strict_host_key_checking: StrictHostKeyChecking,
+    /// `UserKnownHostsFile` entries. Empty means the default
+    /// `~/.ssh/known_hosts`.
+    pub user_known_hosts_files: Vec<String>,
+    pub ciphers: Option<AlgorithmList>,
+    pub macs: Option<AlgorithmList>,
+    pub kex_algorithms: Option<AlgorithmList>,
+    pub host_key_algorithms: Option<AlgorithmList>,
+    /// `BindAddress`: the local address to connect from.
+    pub bind_address: Option<IpAddr>,
+    /// `AddressFamily`: the families of the addresses of `HostName`
+    /// to try.
+    pub address_family: AddressFamily,
+    /// `IPQoS`: the type of service of connections, see
+    /// [`Config::socket_options`].
+    pub ip_qos: Option<IpQos>,
+    /// `TCPKeepAlive`, enabled by default.
+    pub tcp_keep_alive: bool,
+    /// `SendEnv`: patterns of the names of the local environment
+    /// variables to send, see [`Config::environment`].
+    pub send_env: Vec<String>,
+    /// `SetEnv`: variables to send with these values.
+    pub set_env: Vec<(String, String)>,
+    /// Options russh-config does not interpret, keyed by their
+    /// lowercase name. Values from all matching blocks are kept in
+    /// order, so for single-valued options the first one applies.
+    pub extra_options: HashMap<String, Vec<String>>,
+}
+
+/// Keys tried when there is no `IdentityFile`, same as OpenSSH.
+const DEFAULT_IDENTITIES: &[&str] = &[
+    "id_rsa",
+    "id_ecdsa",
+    "id_ecdsa_sk",
+    "id_ed25519",
+    "id_ed25519_sk",
+];
+
+/// Tokens allowed in `HostName`.
+const HOSTNAME_TOKENS: &str = "h";
+/// Tokens allowed in `ProxyCommand`.
+const PROXY_COMMAND_TOKENS: &str = "hnpr";
+/// Tokens allowed in `IdentityFile`, `CertificateFile`,
+/// `UserKnownHostsFile` and `Match exec`.
+const FILE_TOKENS: &str = "CdhijkLlnpru";
+
+#[allow(deprecated)] // `whoami::fallible::hostname` isn't in all 1.x versions
+fn local_hostname() -> String {
+    whoami::hostname()
+}
+
+#[cfg(unix)]
+fn local_uid() -> Option<u32> {
+    // SAFETY: getuid is always successful.
+    Some(unsafe { libc::getuid() })
+}
+
+#[cfg(not(unix))]
+fn local_uid() -> Option<u32> {
+    None
 }
 
 impl Config {
@@ -44,47 +121,299 @@ impl Config {
         Config {
             user: whoami::username(),
             host_name: host_name.to_string(),
+            original_host: host_name.to_string(),
             port: 22,
             identity_file: None,
+            identity_files: Vec::new(),
+            certificate_files: Vec::new(),
             proxy_command: None,
             proxy_jump: None,
+            proxy: None,
             add_keys_to_agent: AddKeysToAgent::default(),
+            strict_host_key_checking: StrictHostKeyChecking::default(),
+            user_known_hosts_files: Vec::new(),
+            ciphers: None,
+            macs: None,
+            kex_algorithms: None,
+            host_key_algorithms: None,
+            bind_address: None,
+            address_family: AddressFamily::default(),
+            ip_qos: None,
+            tcp_keep_alive: true,
+            send_env: Vec::new(),
+            set_env: Vec::new(),
+            extra_options: HashMap::new(),
         }
     }
+
+    /// The effective value of an option not modelled by this crate,
+    /// e.g. `config.extra_option("ServerAliveInterval")`.
+    pub fn extra_option(&self, name: &str) -> Option<&str> {
+        self.extra_options
+            .get(&name.to_lowercase())
+            .and_then(|v| v.first())
+            .map(|v| v.as_str())
+    }
 }
 
 impl Config {
     // Look for any of the ssh_config(5) percent-style tokens and expand them
     // based on current data in the struct, returning a new String. This function
     // can be employed late/lazy eg just before establishing a stream using ProxyCommand
-    // but also can be used to modify Hostname as config parse time
-    fn expand_tokens(&self, original: &str) -> String {
-        let mut string = original.to_string();
-        string = string.replace("%u", &self.user);
-        string = string.replace("%h", &self.host_name); // remote hostname (from context "host")
-        string = string.replace("%H", &self.host_name); // remote hostname (from context "host")
-        string = string.replace("%p", &format!("{}", self.port)); // original typed hostname (from context "host")
-        string = string.replace("%%", "%");
+    // but also can be used to modify Hostname as config parse time.
+    //
+    // Only the tokens in `allowed` are expanded, as different options accept
+    // different tokens. Others are left untouched.
+    fn expand_tokens(&self, original: &str, allowed: &str) -> String {
+        let mut string = String::with_capacity(original.len());
+        let mut chars = original.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                string.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => string.push('%'),
+                Some(t) if allowed.contains(t) => match self.token(t) {
+                    Some(value) => string.push_str(&value),
+                    None => {
+                        string.push('%');
+                        string.push(t)
+                    }
+                },
+                Some(t) => {
+                    debug!("Token %{} not allowed in {:?}", t, original);
+                    string.push('%');
+                    string.push(t)
+                }
+                None => string.push('%'),
+            }
+        }
         string
     }
 
+    fn token(&self, t: char) -> Option<String> {
+        Some(match t {
+            // local user name
+            'u' => whoami::username(),
+            // remote user name
+            'r' => self.user.clone(),
+            // remote host name (`HostName`) and port
+            'h' | 'H' => self.host_name.clone(),
+            'p' => format!("{}", self.port),
+            // original typed host name
+            'n' | 'k' => self.original_host.clone(),
+            'L' => local_hostname().split('.').next().unwrap_or("").to_string(),
+            'l' => local_hostname(),
+            'd' => home::home_dir()?.to_str()?.to_string(),
+            'i' => local_uid()?.to_string(),
+            'j' => self.proxy_jump.clone().unwrap_or_default(),
+            'C' => {
+                use sha1::Digest;
+                let mut hasher = sha1::Sha1::new();
+                for t in ['l', 'h', 'p', 'r', 'j'] {
+                    hasher.update(self.token(t)?.as_bytes());
+                }
+                hasher
+                    .finalize()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect()
+            }
+            _ => return None,
+        })
+    }
+
+    /// Expand the tokens of options that refer to files, which can only be
+    /// done once the whole configuration is known.
+    fn expand_file_tokens(&mut self) {
+        let expand = |config: &Config, files: &[String]| -> Vec<String> {
+            files
+                .iter()
+                .map(|f| config.expand_tokens(f, FILE_TOKENS))
+                .collect()
+        };
+        let expand_paths = |config: &Config, files: &[PathBuf]| -> Vec<PathBuf> {
+            files
+                .iter()
+                .map(|f| match f.to_str() {
+                    Some(f) => PathBuf::from(config.expand_tokens(f, FILE_TOKENS)),
+                    None => f.clone(),
+                })
+                .collect()
+        };
+        self.identity_files = expand_paths(self, &self.identity_files);
+        self.certificate_files = expand_paths(self, &self.certificate_files);
+        self.user_known_hosts_files = expand(self, &self.user_known_hosts_files);
+        self.identity_file = self
+            .identity_files
+            .first()
+            .and_then(|f| f.to_str())
+            .map(String::from);
+    }
+
+    /// The identity files to try, in order: the `IdentityFile`
+    /// entries, or OpenSSH's default keys in `~/.ssh` if there are
+    /// none. Only the files that exist are returned.
+    pub fn identities(&self) -> Vec<PathBuf> {
+        if !self.identity_files.is_empty() {
+            return self
+                .identity_files
+                .iter()
+                .filter(|f| f.is_file())
+                .cloned()
+                .collect();
+        }
+        let Some(home) = home::home_dir() else {
+            return Vec::new();
+        };
+        DEFAULT_IDENTITIES
+            .iter()
+            .map(|f| home.join(".ssh").join(f))
+            .filter(|f| f.is_file())
+            .collect()
+    }
+
+    /// The hosts listed in `ProxyJump`, in the order they must be
+    /// connected to. Empty if there is no `ProxyJump`, or if it is
+    /// `none`.
+    pub fn proxy_jump_hosts(&self) -> Vec<JumpHost> {
+        match self.proxy_jump.as_deref() {
+            None | Some("none") => Vec::new(),
+            Some(jumps) => jumps
+                .split(',')
+                .map(|j| j.trim())
+                .filter(|j| !j.is_empty())
+                .map(JumpHost::parse)
+                .collect(),
+        }
+    }
+
+    /// The options of TCP connections to this host: `TCPKeepAlive`, and
+    /// `IPQoS` or OpenSSH's default (`af21` for interactive sessions,
+    /// `cs1` otherwise).
+    pub fn socket_options(&self) -> SocketOptions {
+        SocketOptions {
+            keepalive: self.tcp_keep_alive,
+            ip_qos: self.ip_qos.unwrap_or(IpQos {
+                interactive: Some(0x48),
+                bulk: Some(0x20),
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// The environment variables to send to the server before starting
+    /// a shell or a command: the local variables matching `SendEnv`,
+    /// followed by the ones of `SetEnv`, which take precedence.
+    pub fn environment(&self) -> Vec<(String, String)> {
+        let mut env: Vec<(String, String)> = std::env::vars()
+            .filter(|(name, _)| {
+                self.send_env
+                    .iter()
+                    .any(|p| check_host_against_glob_pattern(name, p))
+                    && !self.set_env.iter().any(|(n, _)| n == name)
+            })
+            .collect();
+        env.extend(self.set_env.iter().cloned());
+        env
+    }
+
+    /// Connect to `HostName` and `Port`, through the `ProxyCommand` or
+    /// the `ProxyUrl` if any. `ProxyJump` is not handled here, since it
+    /// needs an SSH client: see `russh::client::connect_with_config`.
+    ///
+    /// `HostName` is resolved without blocking the runtime, and its
+    /// addresses of the `AddressFamily` are tried as described in
+    /// [`russh_util::net::connect`], from the `BindAddress` if any, with
+    /// the [`Config::socket_options`]. Through a `ProxyUrl`, the proxy
+    /// resolves `HostName` instead.
     pub async fn stream(&self) -> Result<Stream, Error> {
         if let Some(ref proxy_command) = self.proxy_command {
-            let proxy_command = self.expand_tokens(proxy_command);
+            let proxy_command = self.expand_tokens(proxy_command, PROXY_COMMAND_TOKENS);
             let cmd: Vec<&str> = proxy_command.split(' ').collect();
             Stream::proxy_command(cmd.first().unwrap_or(&""), cmd.get(1..).unwrap_or(&[]))
                 .await
                 .map_err(Into::into)
+        } else if let Some(ref proxy) = self.proxy {
+            let stream = proxy
+                .connect(
+                    &self.host_name,
+                    self.port,
+                    self.bind_address,
+                    &self.socket_options(),
+                )
+                .await?;
+            Ok(Stream::Tcp(stream))
         } else {
-            let address = (self.host_name.as_str(), self.port)
-                .to_socket_addrs()?
-                .next()
-                .ok_or(Error::NotResolvable)?;
-            Stream::tcp_connect(&address).await.map_err(Into::into)
+            let addresses =
+                russh_util::net::resolve((self.host_name.as_str(), self.port), self.address_family)
+                    .await?;
+            if addresses.is_empty() {
+                return Err(Error::NotResolvable);
+            }
+            Stream::tcp_connect_with_options(&addresses, self.bind_address, &self.socket_options())
+                .await
+                .map_err(Into::into)
         }
     }
 }
 
+/// One hop of a `ProxyJump` chain, written `[user@]host[:port]` or
+/// `ssh://[user@]host[:port]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JumpHost {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl JumpHost {
+    fn parse(jump: &str) -> Self {
+        let jump = jump.strip_prefix("ssh://").unwrap_or(jump);
+        let (user, host_port) = match jump.rsplit_once('@') {
+            Some((user, host_port)) => (Some(user.to_string()), host_port),
+            None => (None, jump),
+        };
+        // Bracketed IPv6 addresses, such as `[::1]:2222`.
+        let (host, port) = if let Some(rest) = host_port.strip_prefix('[') {
+            match rest.split_once(']') {
+                Some((host, port)) => (host, port.strip_prefix(':')),
+                None => (rest, None),
+            }
+        } else {
+            match host_port.rsplit_once(':') {
+                Some((host, port)) if !host.contains(':') => (host, Some(port)),
+                _ => (host_port, None),
+            }
+        };
+        JumpHost {
+            user,
+            host: host.to_string(),
+            port: port.and_then(|p| p.parse().ok()),
+        }
+    }
+
+    /// The configuration of this jump host, from `~/.ssh/config` if
+    /// there is one, with the user and port of the jump overriding it.
+    pub fn config(&self) -> Result<Config, Error> {
+        let mut config = match parse_home(&self.host) {
+            Ok(config) => config,
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                Config::default(&self.host)
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(ref user) = self.user {
+            config.user.clone_from(user)
+        }
+        if let Some(port) = self.port {
+            config.port = port
+        }
+        Ok(config)
+    }
+}
+
 pub fn parse_home(host: &str) -> Result<Config, Error> {
     let mut home = if let Some(home) = home::home_dir() {
         home
@@ -97,10 +426,91 @@ pub fn parse_home(host: &str) -> Result<Config, Error> {
 }
 
 pub fn parse_path<P: AsRef<Path>>(path: P, host: &str) -> Result<Config, Error> {
+    let mut config = Config::default(host);
+    let mut state = ParseState::default();
+    parse_file(path.as_ref(), host, &mut config, true, &mut state)?;
+    config.expand_file_tokens();
+    Ok(config)
+}
+
+/// Maximum nesting of `Include` directives, same as OpenSSH.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// State carried across the files of a single parse.
+#[derive(Default)]
+struct ParseState {
+    /// Files currently being parsed, to detect `Include` loops.
+    includes: Vec<PathBuf>,
+    /// Options (lowercase) that already got a value. Like OpenSSH, the
+    /// first value obtained for an option wins.
+    seen: HashSet<String>,
+}
+
+fn parse_file(
+    path: &Path,
+    host: &str,
+    config: &mut Config,
+    matches_current: bool,
+    state: &mut ParseState,
+) -> Result<(), Error> {
+    let canonical = path.canonicalize()?;
+    if state.includes.len() >= MAX_INCLUDE_DEPTH || state.includes.contains(&canonical) {
+        return Err(Error::IncludeDepth(canonical));
+    }
     let mut s = String::new();
     let mut b = std::fs::File::open(path)?;
     b.read_to_string(&mut s)?;
-    parse(&s, host)
+    state.includes.push(canonical);
+    let r = parse_lines(&s, host, config, matches_current, state);
+    state.includes.pop();
+    r
+}
+
+/// Expand the argument of an `Include` directive into the list of
+/// files it refers to, sorted lexically. Relative paths are resolved
+/// against `~/.ssh`, and the last path component may contain glob
+/// patterns.
+fn include_paths(pattern: &str) -> Result<Vec<PathBuf>, Error> {
+    let path = if let Some(rest) = pattern.strip_prefix("~/") {
+        home::home_dir().ok_or(Error::NoHome)?.join(rest)
+    } else if Path::new(pattern).is_absolute() {
+        PathBuf::from(pattern)
+    } else {
+        let mut home = home::home_dir().ok_or(Error::NoHome)?;
+        home.push(".ssh");
+        home.push(pattern);
+        home
+    };
+    let file_pattern = match path.file_name().and_then(|f| f.to_str()) {
+        Some(f) if f.contains(['*', '?', '[']) => f.to_string(),
+        _ => {
+            // No glob: missing files are silently skipped, like OpenSSH.
+            return Ok(if path.is_file() {
+                vec![path]
+            } else {
+                Vec::new()
+            });
+        }
+    };
+    let dir = path.parent().unwrap_or_else(|| Path::new("/"));
+    let matcher = match Glob::new(&file_pattern) {
+        Ok(glob) => glob.compile_matcher(),
+        Err(_) => return Ok(Vec::new()),
+    };
+    let mut paths = Vec::new();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(paths),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if matcher.is_match(entry.file_name()) && entry.path().is_file() {
+            paths.push(entry.path())
+        }
+    }
+    paths.sort();
+    Ok(paths)
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
@@ -112,69 +522,439 @@ pub enum AddKeysToAgent {
     No,
 }
 
+/// Value of the `StrictHostKeyChecking` option.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StrictHostKeyChecking {
+    /// Never add keys automatically, refuse unknown hosts.
+    Yes,
+    /// Add keys of unknown hosts, refuse changed keys.
+    AcceptNew,
+    /// Same as `AcceptNew`: unlike OpenSSH, changed keys are still
+    /// refused.
+    No,
+    /// Ask the user about unknown hosts.
+    #[default]
+    Ask,
+}
+
+/// The value of an algorithm list option, such as `Ciphers` or
+/// `KexAlgorithms`, which either replaces or modifies the default
+/// list. Names may contain `*` and `?` wildcards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AlgorithmList {
+    /// `a,b`: use exactly these algorithms.
+    Replace(Vec<String>),
+    /// `+a,b`: append these algorithms to the default list.
+    Append(Vec<String>),
+    /// `-a,b`: remove these algorithms from the default list.
+    Remove(Vec<String>),
+    /// `^a,b`: move these algorithms to the head of the default list.
+    Prepend(Vec<String>),
+}
+
+impl AlgorithmList {
+    pub fn parse(value: &str) -> Self {
+        let value = value.trim();
+        let list = |l: &str| -> Vec<String> {
+            l.split(',')
+                .map(|x| x.trim())
+                .filter(|x| !x.is_empty())
+                .map(String::from)
+                .collect()
+        };
+        if let Some(l) = value.strip_prefix('+') {
+            AlgorithmList::Append(list(l))
+        } else if let Some(l) = value.strip_prefix('-') {
+            AlgorithmList::Remove(list(l))
+        } else if let Some(l) = value.strip_prefix('^') {
+            AlgorithmList::Prepend(list(l))
+        } else {
+            AlgorithmList::Replace(list(value))
+        }
+    }
+
+    /// Compute the resulting list of algorithms, given the `default`
+    /// list and all the `supported` algorithms, which wildcards are
+    /// matched against.
+    pub fn apply<S: AsRef<str>>(&self, default: &[S], supported: &[S]) -> Vec<String> {
+        let expand = |patterns: &[String]| -> Vec<String> {
+            let mut result: Vec<String> = Vec::new();
+            for pattern in patterns {
+                if pattern.contains(['*', '?']) {
+                    for s in supported {
+                        if check_host_against_glob_pattern(s.as_ref(), pattern)
+                            && !result.iter().any(|r| r == s.as_ref())
+                        {
+                            result.push(s.as_ref().to_string())
+                        }
+                    }
+                } else if !result.contains(pattern) {
+                    result.push(pattern.clone())
+                }
+            }
+            result
+        };
+        let default = default.iter().map(|d| d.as_ref().to_string());
+        match self {
+            AlgorithmList::Replace(l) => expand(l),
+            AlgorithmList::Append(l) => {
+                let mut result: Vec<String> = default.collect();
+                for a in expand(l) {
+                    if !result.contains(&a) {
+                        result.push(a)
+                    }
+                }
+                result
+            }
+            AlgorithmList::Remove(l) => default
+                .filter(|d| !l.iter().any(|p| check_host_against_glob_pattern(d, p)))
+                .collect(),
+            AlgorithmList::Prepend(l) => {
+                let mut result = expand(l);
+                for d in default {
+                    if !result.contains(&d) {
+                        result.push(d)
+                    }
+                }
+                result
+            }
+        }
+    }
+}
+
 pub fn parse(file: &str, host: &str) -> Result<Config, Error> {
     let mut config = Config::default(host);
-    let mut matches_current = false;
+    let mut state = ParseState::default();
+    parse_lines(file, host, &mut config, true, &mut state)?;
+    config.expand_file_tokens();
+    Ok(config)
+}
+
+fn parse_lines(
+    file: &str,
+    host: &str,
+    config: &mut Config,
+    mut matches_current: bool,
+    state: &mut ParseState,
+) -> Result<(), Error> {
     for line in file.lines() {
         let tokens = line.trim().splitn(2, ' ').collect::<Vec<&str>>();
         if tokens.len() == 2 {
             let (key, value) = (tokens.first().unwrap_or(&""), tokens.get(1).unwrap_or(&""));
+            let value = value.trim_start();
             let lower = key.to_lowercase();
             if lower.as_str() == "host" {
                 matches_current = value
                     .split_whitespace()
                     .any(|x| check_host_against_glob_pattern(host, x));
+            } else if lower.as_str() == "match" {
+                matches_current = check_match_criteria(host, config, value)?;
+            }
+            if !matches_current {
+                continue;
             }
-            if matches_current {
-                match lower.as_str() {
-                    "user" => {
-                        config.user.clear();
-                        config.user.push_str(value.trim_start());
+            match lower.as_str() {
+                "host" | "match" => {}
+                "include" => {
+                    for pattern in value.split_whitespace() {
+                        for path in include_paths(pattern)? {
+                            parse_file(&path, host, config, matches_current, state)?;
+                        }
                     }
-                    "hostname" => config.host_name = config.expand_tokens(value.trim_start()),
-                    "port" => {
-                        if let Ok(port) = value.trim_start().parse() {
-                            config.port = port
+                }
+                // List-valued options accumulate across all matching blocks.
+                "identityfile" => {
+                    let id = expand_home(value)?;
+                    if config.identity_file.is_none() {
+                        config.identity_file = Some(id.clone())
+                    }
+                    config.identity_files.push(id.into())
+                }
+                "certificatefile" => {
+                    let cert = expand_home(value)?;
+                    config.certificate_files.push(cert.into())
+                }
+                "user"
+                | "hostname"
+                | "port"
+                | "proxycommand"
+                | "proxyjump"
+                | "proxyurl"
+                | "addkeystoagent"
+                | "stricthostkeychecking"
+                | "userknownhostsfile"
+                | "ciphers"
+                | "macs"
+                | "kexalgorithms"
+                | "hostkeyalgorithms"
+                | "bindaddress"
+                | "addressfamily"
+                | "ipqos"
+                | "tcpkeepalive"
+                    if !state.seen.insert(lower.clone()) =>
+                {
+                    debug!("{:?} already set, ignoring", key);
+                }
+                "user" => {
+                    config.user.clear();
+                    config.user.push_str(value);
+                }
+                "hostname" => config.host_name = config.expand_tokens(value, HOSTNAME_TOKENS),
+                "port" => {
+                    if let Ok(port) = value.parse() {
+                        config.port = port
+                    }
+                }
+                "proxycommand" => config.proxy_command = Some(value.to_string()),
+                "proxyjump" => config.proxy_jump = Some(value.to_string()),
+                "proxyurl" => match value {
+                    "none" => config.proxy = None,
+                    _ => match ProxyConnector::parse(value) {
+                        Some(proxy) => config.proxy = Some(proxy),
+                        None => debug!("Invalid ProxyUrl {:?}", value),
+                    },
+                },
+                "addkeystoagent" => match value.to_lowercase().as_str() {
+                    "yes" => config.add_keys_to_agent = AddKeysToAgent::Yes,
+                    "confirm" => config.add_keys_to_agent = AddKeysToAgent::Confirm,
+                    "ask" => config.add_keys_to_agent = AddKeysToAgent::Ask,
+                    _ => config.add_keys_to_agent = AddKeysToAgent::No,
+                },
+                "stricthostkeychecking" => match value.to_lowercase().as_str() {
+                    "yes" => config.strict_host_key_checking = StrictHostKeyChecking::Yes,
+                    "accept-new" => {
+                        config.strict_host_key_checking = StrictHostKeyChecking::AcceptNew
+                    }
+                    "no" | "off" => config.strict_host_key_checking = StrictHostKeyChecking::No,
+                    _ => config.strict_host_key_checking = StrictHostKeyChecking::Ask,
+                },
+                "ciphers" => config.ciphers = Some(AlgorithmList::parse(value)),
+                "macs" => config.macs = Some(AlgorithmList::parse(value)),
+                "kexalgorithms" => config.kex_algorithms = Some(AlgorithmList::parse(value)),
+                "hostkeyalgorithms" => {
+                    config.host_key_algorithms = Some(AlgorithmList::parse(value))
+                }
+                "bindaddress" => match value.parse() {
+                    Ok(address) => config.bind_address = Some(address),
+                    Err(_) => debug!("Invalid BindAddress {:?}", value),
+                },
+                "addressfamily" => match value.to_lowercase().as_str() {
+                    "inet" => config.address_family = AddressFamily::Inet,
+                    "inet6" => config.address_family = AddressFamily::Inet6,
+                    _ => config.address_family = AddressFamily::Any,
+                },
+                "ipqos" => match IpQos::parse(value) {
+                    Some(ip_qos) => config.ip_qos = Some(ip_qos),
+                    None => debug!("Invalid IPQoS {:?}", value),
+                },
+                "tcpkeepalive" => config.tcp_keep_alive = value.eq_ignore_ascii_case("yes"),
+                "sendenv" => {
+                    for pattern in value.split_whitespace() {
+                        // `-PATTERN` removes the patterns sent so far.
+                        match pattern.strip_prefix('-') {
+                            Some(removed) => config
+                                .send_env
+                                .retain(|p| !check_host_against_glob_pattern(p, removed)),
+                            None => config.send_env.push(pattern.to_string()),
                         }
                     }
-                    "identityfile" => {
-                        let id = value.trim_start();
-                        if id.starts_with("~/") {
-                            if let Some(mut home) = home::home_dir() {
-                                home.push(id.split_at(2).1);
-                                config.identity_file = Some(
-                                    home.to_str()
-                                        .ok_or_else(|| {
-                                            std::io::Error::new(
-                                                std::io::ErrorKind::Other,
-                                                "Failed to convert home directory to string",
-                                            )
-                                        })?
-                                        .to_string(),
-                                );
-                            } else {
-                                return Err(Error::NoHome);
-                            }
-                        } else {
-                            config.identity_file = Some(id.to_string())
+                }
+                "setenv" => {
+                    for var in split_quoted(value) {
+                        let Some((name, value)) = var.split_once('=') else {
+                            debug!("Invalid SetEnv {:?}", var);
+                            continue;
+                        };
+                        // The first value of each variable applies.
+                        if !config.set_env.iter().any(|(n, _)| n == name) {
+                            config.set_env.push((name.to_string(), value.to_string()))
                         }
                     }
-                    "proxycommand" => config.proxy_command = Some(value.trim_start().to_string()),
-                    "proxyjump" => config.proxy_jump = Some(value.trim_start().to_string()),
-                    "addkeystoagent" => match value.to_lowercase().as_str() {
-                        "yes" => config.add_keys_to_agent = AddKeysToAgent::Yes,
-                        "confirm" => config.add_keys_to_agent = AddKeysToAgent::Confirm,
-                        "ask" => config.add_keys_to_agent = AddKeysToAgent::Ask,
-                        _ => config.add_keys_to_agent = AddKeysToAgent::No,
-                    },
-                    key => {
-                        debug!("{:?}", key);
+                }
+                "userknownhostsfile" => {
+                    for file in value.split_whitespace() {
+                        if file != "none" {
+                            config.user_known_hosts_files.push(expand_home(file)?)
+                        }
                     }
                 }
+                _ => {
+                    debug!("{:?}", key);
+                    config
+                        .extra_options
+                        .entry(lower)
+                        .or_default()
+                        .push(value.to_string());
+                }
             }
         }
     }
-    Ok(config)
+    Ok(())
+}
+
+/// Split `value` on whitespace, except between double quotes, which
+/// are removed.
+fn split_quoted(value: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut in_word = false;
+    for c in value.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word)
+    }
+    words
+}
+
+/// Replace a leading `~/` with the user's home directory.
+fn expand_home(path: &str) -> Result<String, Error> {
+    if let Some(rest) = path.strip_prefix("~/") {
+        let mut home = home::home_dir().ok_or(Error::NoHome)?;
+        home.push(rest);
+        Ok(home
+            .to_str()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "Failed to convert home directory to string",
+                )
+            })?
+            .to_string())
+    } else {
+        Ok(path.to_string())
+    }
+}
+
+/// Evaluate the criteria of a `Match` line. All criteria must be
+/// satisfied for the block to apply. `host` is matched against the
+/// (possibly rewritten) `HostName`, while `originalhost` is matched
+/// against the host name passed to [`parse`].
+fn check_match_criteria(host: &str, config: &Config, criteria: &str) -> Result<bool, Error> {
+    let args = split_arguments(criteria);
+    let mut args = args.iter();
+    let mut result = true;
+    while let Some(criterion) = args.next() {
+        let (negate, criterion) = match criterion.strip_prefix('!') {
+            Some(c) => (true, c.to_lowercase()),
+            None => (false, criterion.to_lowercase()),
+        };
+        let matched = match criterion.as_str() {
+            "all" => true,
+            // We don't canonicalize host names, there is a single pass.
+            "canonical" | "final" => true,
+            "host" | "originalhost" | "user" | "localuser" | "exec" => {
+                let arg = if let Some(arg) = args.next() {
+                    arg
+                } else {
+                    debug!("Missing argument to Match {}", criterion);
+                    return Ok(false);
+                };
+                match criterion.as_str() {
+                    "host" => check_pattern_list(&config.host_name, arg),
+                    "originalhost" => check_pattern_list(host, arg),
+                    "user" => check_pattern_list(&config.user, arg),
+                    "localuser" => check_pattern_list(&whoami::username(), arg),
+                    _ => run_match_exec(&config.expand_tokens(arg, FILE_TOKENS)),
+                }
+            }
+            criterion => {
+                debug!("Unsupported Match criterion {:?}", criterion);
+                false
+            }
+        };
+        result &= matched != negate;
+    }
+    Ok(result)
+}
+
+/// Run the command of a `Match exec` criterion, which matches if the
+/// command exits successfully.
+fn run_match_exec(command: &str) -> bool {
+    #[cfg(windows)]
+    let mut cmd = std::process::Command::new("cmd");
+    #[cfg(windows)]
+    cmd.arg("/C");
+    #[cfg(not(windows))]
+    let mut cmd = std::process::Command::new("/bin/sh");
+    #[cfg(not(windows))]
+    cmd.arg("-c");
+    match cmd
+        .arg(command)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .status()
+    {
+        Ok(status) => status.success(),
+        Err(e) => {
+            debug!("Match exec {:?} failed: {:?}", command, e);
+            false
+        }
+    }
+}
+
+/// Split a line into whitespace-separated arguments, keeping
+/// double-quoted arguments together.
+fn split_arguments(line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut has_arg = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                has_arg = true;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if has_arg {
+                    args.push(std::mem::take(&mut current));
+                    has_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_arg = true;
+            }
+        }
+    }
+    if has_arg {
+        args.push(current)
+    }
+    args
+}
+
+/// Match a candidate against a comma-separated pattern list, where
+/// patterns prefixed with `!` are negated: any negated match makes the
+/// whole list fail.
+fn check_pattern_list(candidate: &str, list: &str) -> bool {
+    let mut matched = false;
+    for pattern in list.split(',') {
+        if let Some(pattern) = pattern.strip_prefix('!') {
+            if check_host_against_glob_pattern(candidate, pattern) {
+                return false;
+            }
+        } else if check_host_against_glob_pattern(candidate, pattern) {
+            matched = true
+        }
+    }
+    matched
 }
 
 fn check_host_against_glob_pattern(candidate: &str, glob_pattern: &str) -> bool {
@@ -183,3 +963,398 @@ fn check_host_against_glob_pattern(candidate: &str, glob_pattern: &str) -> bool
         _ => false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    #[test]
+    fn include_glob_and_loop() {
+        let dir = std::env::temp_dir().join(format!("russh-config-{}", std::process::id()));
+        let conf_d = dir.join("config.d");
+        std::fs::create_dir_all(&conf_d).unwrap();
+        std::fs::write(
+            dir.join("config"),
+            format!(
+                "Include {}/*.conf\nHost other\n  Port 23\n",
+                conf_d.display()
+            ),
+        )
+        .unwrap();
+        std::fs::write(conf_d.join("10-user.conf"), "Host example\n  User alice\n").unwrap();
+        std::fs::write(conf_d.join("20-port.conf"), "Host example\n  Port 2222\n").unwrap();
+        std::fs::write(conf_d.join("ignored"), "Host example\n  Port 1\n").unwrap();
+
+        let config = parse_path(dir.join("config"), "example").unwrap();
+        assert_eq!(config.user, "alice");
+        assert_eq!(config.port, 2222);
+
+        std::fs::write(
+            conf_d.join("30-loop.conf"),
+            format!("Include {}\n", dir.join("config").display()),
+        )
+        .unwrap();
+        assert!(matches!(
+            parse_path(dir.join("config"), "example"),
+            Err(Error::IncludeDepth(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn match_blocks() {
+        let file = "Host example\n  HostName other.example.com\n\
+                    Match originalhost example localuser *,!nobody\n  Port 2222\n\
+                    Match host other.example.com,!*.example.org\n  User bob\n\
+                    Match host example\n  Port 1\n\
+                    Match exec \"exit 1\"\n  ProxyJump nope\n\
+                    Match all\n  AddKeysToAgent yes\n";
+        let config = parse(file, "example").unwrap();
+        assert_eq!(config.port, 2222);
+        assert_eq!(config.host_name, "other.example.com");
+        assert_eq!(config.user, "bob");
+        assert_eq!(config.proxy_jump, None);
+        assert_eq!(config.add_keys_to_agent, AddKeysToAgent::Yes);
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let file = "Host example\n  User alice\n  IdentityFile /keys/a\n\
+                    Host *\n  User bob\n  Port 2222\n  IdentityFile /keys/b\n\
+                    CertificateFile /keys/b-cert.pub\n";
+        let config = parse(file, "example").unwrap();
+        assert_eq!(config.user, "alice");
+        assert_eq!(config.port, 2222);
+        assert_eq!(config.identity_file.as_deref(), Some("/keys/a"));
+        assert_eq!(
+            config.identity_files,
+            [PathBuf::from("/keys/a"), PathBuf::from("/keys/b")]
+        );
+        assert_eq!(
+            config.certificate_files,
+            [PathBuf::from("/keys/b-cert.pub")]
+        );
+    }
+
+    #[test]
+    fn proxy_jump_hosts() {
+        let config = parse(
+            "Host example\n  ProxyJump alice@jump1:2222,jump2,ssh://bob@[::1]:22\n",
+            "example",
+        )
+        .unwrap();
+        let jumps = config.proxy_jump_hosts();
+        assert_eq!(
+            jumps,
+            [
+                JumpHost {
+                    user: Some("alice".to_string()),
+                    host: "jump1".to_string(),
+                    port: Some(2222)
+                },
+                JumpHost {
+                    user: None,
+                    host: "jump2".to_string(),
+                    port: None
+                },
+                JumpHost {
+                    user: Some("bob".to_string()),
+                    host: "::1".to_string(),
+                    port: Some(22)
+                },
+            ]
+        );
+        assert!(parse("ProxyJump none\n", "example")
+            .unwrap()
+            .proxy_jump_hosts()
+            .is_empty());
+    }
+
+    #[test]
+    fn tokens() {
+        let file = "Host example\n  HostName %h.example.com\n  User bob\n  Port 2222\n\
+                    IdentityFile /keys/%r@%h:%p-%n-%%\n  ProxyCommand nc %h %p %u\n";
+        let config = parse(file, "example").unwrap();
+        assert_eq!(config.host_name, "example.example.com");
+        assert_eq!(
+            config.identity_files,
+            [PathBuf::from(
+                "/keys/bob@example.example.com:2222-example-%"
+            )]
+        );
+        assert_eq!(
+            config.expand_tokens(
+                config.proxy_command.as_deref().unwrap(),
+                PROXY_COMMAND_TOKENS
+            ),
+            "nc example.example.com 2222 %u"
+        );
+        let hash = config.expand_tokens("%C", FILE_TOKENS);
+        assert_eq!(hash.len(), 40);
+        assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn algorithm_lists() {
+        let file = "Host example\n  Ciphers aes128-ctr,aes256-*\n  MACs -*sha1*\n\
+                    KexAlgorithms ^curve25519-sha256\n  HostKeyAlgorithms +ssh-rsa\n";
+        let config = parse(file, "example").unwrap();
+        let supported = [
+            "aes128-ctr",
+            "aes256-ctr",
+            "aes256-gcm@openssh.com",
+            "3des-cbc",
+        ];
+        assert_eq!(
+            config.ciphers.unwrap().apply(&["aes128-ctr"], &supported),
+            ["aes128-ctr", "aes256-ctr", "aes256-gcm@openssh.com"]
+        );
+        assert_eq!(
+            config
+                .macs
+                .unwrap()
+                .apply(&["hmac-sha2-256", "hmac-sha1"], &[]),
+            ["hmac-sha2-256"]
+        );
+        assert_eq!(
+            config
+                .kex_algorithms
+                .unwrap()
+                .apply(&["ecdh-sha2-nistp256", "curve25519-sha256"], &[]),
+            ["curve25519-sha256", "ecdh-sha2-nistp256"]
+        );
+        assert_eq!(
+            config
+                .host_key_algorithms
+                .unwrap()
+                .apply(&["ssh-ed25519"], &[]),
+            ["ssh-ed25519", "ssh-rsa"]
+        );
+    }
+
+    #[test]
+    fn extra_options() {
+        let file = "Host example\n  ServerAliveInterval 30\n  LocalForward 8080 localhost:80\n\
+                    Host *\n  serveraliveinterval 60\n  LocalForward 8443 localhost:443\n";
+        let config = parse(file, "example").unwrap();
+        assert_eq!(config.extra_option("ServerAliveInterval"), Some("30"));
+        let local_forwards = ["8080 localhost:80", "8443 localhost:443"].map(String::from);
+        assert_eq!(
+            config.extra_options.get("localforward").map(Vec::as_slice),
+            Some(&local_forwards[..])
+        );
+        assert_eq!(config.extra_option("Ciphers"), None);
+    }
+
+    #[test]
+    fn bind_address_and_family() {
+        let file = "Host example\n  BindAddress 127.0.0.1\n  AddressFamily inet6\n\
+                    Host *\n  AddressFamily inet\n";
+        let config = parse(file, "example").unwrap();
+        assert_eq!(config.bind_address, Some("127.0.0.1".parse().unwrap()));
+        assert_eq!(config.address_family, AddressFamily::Inet6);
+        let config = parse(file, "other").unwrap();
+        assert_eq!(config.bind_address, None);
+        assert_eq!(config.address_family, AddressFamily::Inet);
+    }
+
+    #[test]
+    fn ip_qos() {
+        let config = parse(
+            "Host example\n  IPQoS lowdelay throughput\n  TCPKeepAlive no\n",
+            "example",
+        )
+        .unwrap();
+        assert_eq!(
+            config.ip_qos,
+            Some(IpQos {
+                interactive: Some(0x10),
+                bulk: Some(0x08)
+            })
+        );
+        assert!(!config.socket_options().keepalive);
+        let config = parse("Host example\n  IPQoS none\n", "example").unwrap();
+        assert_eq!(config.socket_options().ip_qos, IpQos::default());
+        let config = parse("Host example\n  IPQoS ef\n", "example").unwrap();
+        assert_eq!(config.ip_qos.unwrap().class(false), Some(0xb8));
+        let config = parse("Host example\n", "example").unwrap();
+        assert!(config.socket_options().keepalive);
+        assert_eq!(config.socket_options().ip_qos.class(true), Some(0x48));
+        assert_eq!(IpQos::parse("af21 cs1 ef"), None);
+        assert_eq!(IpQos::parse("unknown"), None);
+        assert_eq!(IpQos::parse("0x20 4").unwrap().class(false), Some(4));
+    }
+
+    #[tokio::test]
+    async fn connect_next_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+
+        let file = format!(
+            "Host example\n  HostName localhost\n  Port {}\n  AddressFamily inet\n\
+             BindAddress 127.0.0.1\n",
+            listener.local_addr().unwrap().port()
+        );
+        let config = parse(&file, "example").unwrap();
+        let _stream = config.stream().await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.ip(), config.bind_address.unwrap());
+
+        // The first address refuses the connection, the second one is tried.
+        let addrs = [closed_addr, listener.local_addr().unwrap()];
+        let _stream = Stream::tcp_connect_any(&addrs, None).await.unwrap();
+        listener.accept().await.unwrap();
+        assert!(Stream::tcp_connect_any(&addrs[..1], None).await.is_err());
+        assert!(Stream::tcp_connect_any(&[], None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn socket_options() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let options = SocketOptions {
+            keepalive: true,
+            ip_qos: IpQos::parse("lowdelay throughput").unwrap(),
+            ..Default::default()
+        };
+        let stream =
+            Stream::tcp_connect_with_options(&[listener.local_addr().unwrap()], None, &options)
+                .await
+                .unwrap();
+        let Stream::Tcp(stream) = stream else {
+            panic!("not a TCP stream")
+        };
+        assert!(!stream.nodelay().unwrap());
+
+        let control = russh_util::net::SocketControl::new(&stream, options).unwrap();
+        control.set_interactive(true).unwrap();
+        assert!(stream.nodelay().unwrap());
+        control.set_interactive(false).unwrap();
+        assert!(!stream.nodelay().unwrap());
+    }
+
+    #[test]
+    fn send_and_set_env() {
+        let config = parse(
+            "Host example\n  SendEnv LANG LC_* RUSSH_TEST_*\n  SendEnv -LC_*\n  \
+             SetEnv FOO=bar RUSSH_TEST_SEND_AND_SET=\"a b\"\n  SetEnv FOO=ignored\n",
+            "example",
+        )
+        .unwrap();
+        assert_eq!(config.send_env, ["LANG", "RUSSH_TEST_*"]);
+        assert_eq!(
+            config.set_env,
+            [
+                ("FOO".to_string(), "bar".to_string()),
+                ("RUSSH_TEST_SEND_AND_SET".to_string(), "a b".to_string()),
+            ]
+        );
+
+        std::env::set_var("RUSSH_TEST_SEND_AND_SET", "local");
+        std::env::set_var("RUSSH_TEST_SEND_ONLY", "sent");
+        let env = config.environment();
+        assert!(env.contains(&("RUSSH_TEST_SEND_ONLY".to_string(), "sent".to_string())));
+        assert!(env.contains(&("RUSSH_TEST_SEND_AND_SET".to_string(), "a b".to_string())));
+        assert!(!env.iter().any(|(_, v)| v == "local"));
+    }
+
+    #[test]
+    fn proxy_url() {
+        let file = "Host example\n  ProxyUrl http://alice:p@ss@proxy:3128\n\
+                    Host other\n  ProxyUrl none\nHost *\n  ProxyUrl socks5://[::1]\n";
+        let proxy = parse(file, "example").unwrap().proxy.unwrap();
+        assert_eq!(
+            proxy,
+            ProxyConnector::new(ProxyKind::Http, "proxy", 3128).with_credentials("alice", "p@ss")
+        );
+        assert!(!format!("{:?}", proxy).contains("p@ss"));
+        assert_eq!(parse(file, "other").unwrap().proxy, None);
+        assert_eq!(
+            parse(file, "third").unwrap().proxy,
+            Some(ProxyConnector::new(ProxyKind::Socks5, "::1", 1080))
+        );
+        assert_eq!(ProxyConnector::parse("ftp://proxy"), None);
+        assert_eq!(ProxyConnector::parse("http://proxy:port"), None);
+        assert_eq!(ProxyConnector::parse("proxy:3128"), None);
+    }
+
+    #[tokio::test]
+    async fn http_proxy() {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let file = format!(
+            "Host example\n  HostName ssh.example.com\n  ProxyUrl http://alice:secret@{}\n",
+            listener.local_addr().unwrap()
+        );
+        let config = parse(&file, "example").unwrap();
+        let proxy = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut request = Vec::new();
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                request.push(line);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nSSH-2.0-test\r\n")
+                .await
+                .unwrap();
+            request
+        });
+        let mut stream = config.stream().await.unwrap();
+        let mut banner = [0; 14];
+        stream.read_exact(&mut banner).await.unwrap();
+        assert_eq!(&banner, b"SSH-2.0-test\r\n");
+        let request = proxy.await.unwrap();
+        assert_eq!(
+            request.first().map(String::as_str),
+            Some("CONNECT ssh.example.com:22 HTTP/1.1\r\n")
+        );
+        // "alice:secret" in base64.
+        assert!(request.contains(&"Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n".to_string()));
+    }
+
+    #[tokio::test]
+    async fn socks5_proxy() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let file = format!(
+            "Host example\n  HostName ssh.example.com\n  Port 2222\n\
+             ProxyUrl socks5://alice:secret@{}\n",
+            listener.local_addr().unwrap()
+        );
+        let config = parse(&file, "example").unwrap();
+        let proxy = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 4];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 2, 0, 2]);
+            stream.write_all(&[5, 2]).await.unwrap();
+            let mut auth = [0; 14];
+            stream.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x05alice\x06secret");
+            stream.write_all(&[1, 0]).await.unwrap();
+            let mut request = [0; 22];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"\x05\x01\x00\x03\x0fssh.example.com\x08\xae");
+            stream
+                .write_all(b"\x05\x00\x00\x01\x7f\x00\x00\x01\x00\x16hello")
+                .await
+                .unwrap();
+        });
+        let mut stream = config.stream().await.unwrap();
+        let mut hello = [0; 5];
+        stream.read_exact(&mut hello).await.unwrap();
+        assert_eq!(&hello, b"hello");
+        proxy.await.unwrap();
+    }
+}
@@ -1,9 +1,10 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
 use std::process::Stdio;
 
 use futures::ready;
 use futures::task::*;
+use russh_util::net::SocketOptions;
 use tokio::io::ReadBuf;
 use tokio::net::TcpStream;
 use tokio::process::Command;
@@ -21,6 +22,25 @@ impl Stream {
     pub async fn tcp_connect(addr: &SocketAddr) -> Result<Stream, std::io::Error> {
         Ok(Stream::Tcp(tokio::net::TcpStream::connect(addr).await?))
     }
+    /// Connect a direct TCP stream to the first of `addrs` that
+    /// answers, from `bind` if given, see [`russh_util::net::connect`].
+    pub async fn tcp_connect_any(
+        addrs: &[SocketAddr],
+        bind: Option<IpAddr>,
+    ) -> Result<Stream, std::io::Error> {
+        Ok(Stream::Tcp(russh_util::net::connect(addrs, bind).await?))
+    }
+    /// Like [`Stream::tcp_connect_any`], with `options` applied to the
+    /// connection.
+    pub async fn tcp_connect_with_options(
+        addrs: &[SocketAddr],
+        bind: Option<IpAddr>,
+        options: &SocketOptions,
+    ) -> Result<Stream, std::io::Error> {
+        Ok(Stream::Tcp(
+            russh_util::net::connect_with_options(addrs, bind, options).await?,
+        ))
+    }
     /// Connect through a proxy command.
     pub async fn proxy_command(cmd: &str, args: &[&str]) -> Result<Stream, std::io::Error> {
         Ok(Stream::Child(
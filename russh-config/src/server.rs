@@ -0,0 +1,668 @@
+//! A subset of OpenSSH's `sshd_config`, for servers replacing sshd
+//! with the same configuration files.
+//!
+//! [`parse`] reads the global options and the `Match` blocks, and
+//! [`ServerConfig::settings`] gives the options that apply to a
+//! connection, once its user is known. Turning them into a russh server
+//! configuration is done by `russh::server::Config::from_sshd_config`.
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::{check_pattern_list, split_arguments, split_quoted, Error};
+
+/// `PermitRootLogin`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PermitRootLogin {
+    Yes,
+    /// `prohibit-password` (or `without-password`): root can only log
+    /// in with keys and other methods that aren't passwords.
+    #[default]
+    ProhibitPassword,
+    /// Root can only log in with a key that has a forced command.
+    ForcedCommandsOnly,
+    No,
+}
+
+/// The options that `Match` blocks can change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Settings {
+    pub permit_root_login: PermitRootLogin,
+    pub password_authentication: bool,
+    pub pubkey_authentication: bool,
+    pub kbd_interactive_authentication: bool,
+    pub permit_empty_passwords: bool,
+    /// `AllowUsers`: `user` or `user@host` patterns. If not empty,
+    /// only the users matching one of them may log in.
+    pub allow_users: Vec<String>,
+    pub deny_users: Vec<String>,
+    pub allow_groups: Vec<String>,
+    pub deny_groups: Vec<String>,
+    pub max_auth_tries: usize,
+    pub authorized_keys_files: Vec<String>,
+    pub banner: Option<PathBuf>,
+    pub force_command: Option<String>,
+    pub allow_tcp_forwarding: bool,
+    pub allow_agent_forwarding: bool,
+    pub x11_forwarding: bool,
+    pub permit_tty: bool,
+    pub client_alive_interval: u64,
+    pub client_alive_count_max: usize,
+    /// Options russh-config does not interpret, keyed by their
+    /// lowercase name.
+    pub extra_options: HashMap<String, Vec<String>>,
+}
+
+impl Default for Settings {
+    /// The defaults of sshd.
+    fn default() -> Self {
+        Settings {
+            permit_root_login: PermitRootLogin::default(),
+            password_authentication: true,
+            pubkey_authentication: true,
+            kbd_interactive_authentication: true,
+            permit_empty_passwords: false,
+            allow_users: Vec::new(),
+            deny_users: Vec::new(),
+            allow_groups: Vec::new(),
+            deny_groups: Vec::new(),
+            max_auth_tries: 6,
+            authorized_keys_files: vec![
+                ".ssh/authorized_keys".to_string(),
+                ".ssh/authorized_keys2".to_string(),
+            ],
+            banner: None,
+            force_command: None,
+            allow_tcp_forwarding: true,
+            allow_agent_forwarding: true,
+            x11_forwarding: false,
+            permit_tty: true,
+            client_alive_interval: 0,
+            client_alive_count_max: 3,
+            extra_options: HashMap::new(),
+        }
+    }
+}
+
+fn yes(value: &str) -> bool {
+    value.eq_ignore_ascii_case("yes")
+}
+
+impl Settings {
+    /// Apply one option. Like sshd, the first value of an option
+    /// wins, and list options accumulate.
+    fn apply(&mut self, key: &str, value: &str, seen: &mut HashSet<String>) {
+        let lower = key.to_lowercase();
+        let first = seen.insert(lower.clone());
+        let list = |list: &mut Vec<String>| {
+            // A `Match` block replaces the global list.
+            if first {
+                list.clear()
+            }
+            list.extend(value.split_whitespace().map(String::from))
+        };
+        match lower.as_str() {
+            "allowusers" => return list(&mut self.allow_users),
+            "denyusers" => return list(&mut self.deny_users),
+            "allowgroups" => return list(&mut self.allow_groups),
+            "denygroups" => return list(&mut self.deny_groups),
+            _ if !first => {
+                debug!("{:?} already set, ignoring", key);
+                return;
+            }
+            _ => {}
+        }
+        match lower.as_str() {
+            "permitrootlogin" => {
+                self.permit_root_login = match value.to_lowercase().as_str() {
+                    "yes" => PermitRootLogin::Yes,
+                    "no" => PermitRootLogin::No,
+                    "forced-commands-only" => PermitRootLogin::ForcedCommandsOnly,
+                    _ => PermitRootLogin::ProhibitPassword,
+                }
+            }
+            "passwordauthentication" => self.password_authentication = yes(value),
+            "pubkeyauthentication" => self.pubkey_authentication = yes(value),
+            "kbdinteractiveauthentication" | "challengeresponseauthentication" => {
+                self.kbd_interactive_authentication = yes(value)
+            }
+            "permitemptypasswords" => self.permit_empty_passwords = yes(value),
+            "maxauthtries" => match value.parse() {
+                Ok(n) => self.max_auth_tries = n,
+                Err(_) => debug!("Invalid MaxAuthTries {:?}", value),
+            },
+            "authorizedkeysfile" => {
+                self.authorized_keys_files = split_quoted(value)
+                    .into_iter()
+                    .filter(|f| f != "none")
+                    .collect()
+            }
+            "banner" => {
+                self.banner = match value {
+                    "none" => None,
+                    _ => Some(PathBuf::from(value)),
+                }
+            }
+            "forcecommand" => {
+                self.force_command = match value {
+                    "none" => None,
+                    _ => Some(value.to_string()),
+                }
+            }
+            "allowtcpforwarding" => self.allow_tcp_forwarding = !value.eq_ignore_ascii_case("no"),
+            "allowagentforwarding" => self.allow_agent_forwarding = yes(value),
+            "x11forwarding" => self.x11_forwarding = yes(value),
+            "permittty" => self.permit_tty = yes(value),
+            "clientaliveinterval" => match value.parse() {
+                Ok(n) => self.client_alive_interval = n,
+                Err(_) => debug!("Invalid ClientAliveInterval {:?}", value),
+            },
+            "clientalivecountmax" => match value.parse() {
+                Ok(n) => self.client_alive_count_max = n,
+                Err(_) => debug!("Invalid ClientAliveCountMax {:?}", value),
+            },
+            _ => {
+                debug!("{:?}", key);
+                self.extra_options
+                    .entry(lower)
+                    .or_default()
+                    .push(value.to_string());
+            }
+        }
+    }
+
+    /// Whether `user`, a member of `groups`, may log in from `host`
+    /// (its name or address), checking `DenyUsers`, `AllowUsers`,
+    /// `DenyGroups` and `AllowGroups` in this order, as sshd does.
+    pub fn allows_user(&self, user: &str, groups: &[String], host: &str) -> bool {
+        let user_matches = |pattern: &String| match pattern.split_once('@') {
+            Some((u, h)) => check_pattern_list(user, u) && check_pattern_list(host, h),
+            None => check_pattern_list(user, pattern),
+        };
+        let group_matches =
+            |pattern: &String| groups.iter().any(|g| check_pattern_list(g, pattern));
+        if self.deny_users.iter().any(user_matches) {
+            debug!("user {:?} denied by DenyUsers", user);
+            return false;
+        }
+        if !self.allow_users.is_empty() && !self.allow_users.iter().any(user_matches) {
+            debug!("user {:?} not in AllowUsers", user);
+            return false;
+        }
+        if self.deny_groups.iter().any(group_matches) {
+            debug!("user {:?} denied by DenyGroups", user);
+            return false;
+        }
+        if !self.allow_groups.is_empty() && !self.allow_groups.iter().any(group_matches) {
+            debug!("user {:?} not in AllowGroups", user);
+            return false;
+        }
+        true
+    }
+
+    /// Whether `user` may authenticate with `method` (`"password"`,
+    /// `"publickey"`, `"keyboard-interactive"`...) according to the
+    /// `*Authentication` options and `PermitRootLogin`.
+    /// `forced_command` tells if the key has a forced command.
+    pub fn allows_method(&self, user: &str, method: &str, forced_command: bool) -> bool {
+        let enabled = match method {
+            "password" => self.password_authentication,
+            "publickey" => self.pubkey_authentication,
+            "keyboard-interactive" => self.kbd_interactive_authentication,
+            _ => true,
+        };
+        if !enabled || user != "root" {
+            return enabled;
+        }
+        match self.permit_root_login {
+            PermitRootLogin::Yes => true,
+            PermitRootLogin::ProhibitPassword => {
+                !matches!(method, "password" | "keyboard-interactive")
+            }
+            PermitRootLogin::ForcedCommandsOnly => method == "publickey" && forced_command,
+            PermitRootLogin::No => false,
+        }
+    }
+}
+
+/// What `Match` blocks are matched against.
+#[derive(Debug, Clone, Default)]
+pub struct Connection {
+    pub user: String,
+    pub groups: Vec<String>,
+    /// The host name of the client, if it was resolved.
+    pub host: Option<String>,
+    pub address: Option<IpAddr>,
+    pub local_address: Option<IpAddr>,
+    pub local_port: Option<u16>,
+}
+
+/// A `Match` block: its criteria, and the options it sets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchBlock {
+    pub criteria: String,
+    pub options: Vec<(String, String)>,
+}
+
+impl MatchBlock {
+    /// Whether the criteria of the block apply to `connection`.
+    pub fn matches(&self, connection: &Connection) -> bool {
+        let args = split_arguments(&self.criteria);
+        let mut args = args.iter();
+        while let Some(criterion) = args.next() {
+            let criterion = criterion.to_lowercase();
+            if criterion == "all" {
+                continue;
+            }
+            let Some(arg) = args.next() else {
+                debug!("Missing argument to Match {}", criterion);
+                return false;
+            };
+            let matched = match criterion.as_str() {
+                "user" => check_pattern_list(&connection.user, arg),
+                "group" => connection.groups.iter().any(|g| check_pattern_list(g, arg)),
+                "host" => connection
+                    .host
+                    .as_deref()
+                    .map_or(false, |h| check_pattern_list(h, arg)),
+                "address" => connection
+                    .address
+                    .map_or(false, |a| check_address_list(a, arg)),
+                "localaddress" => connection
+                    .local_address
+                    .map_or(false, |a| check_address_list(a, arg)),
+                "localport" => connection
+                    .local_port
+                    .map_or(false, |p| check_pattern_list(&p.to_string(), arg)),
+                criterion => {
+                    debug!("Unsupported Match criterion {:?}", criterion);
+                    false
+                }
+            };
+            if !matched {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Whether `address` matches the comma-separated list of patterns and
+/// CIDR blocks, possibly negated with `!`.
+fn check_address_list(address: IpAddr, list: &str) -> bool {
+    let address = match address {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+        v4 => v4,
+    };
+    let mut matched = false;
+    for pattern in list.split(',') {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(p) => (true, p),
+            None => (false, pattern),
+        };
+        let m = match pattern.split_once('/') {
+            Some((network, len)) => match (network.parse(), len.parse()) {
+                (Ok(network), Ok(len)) => in_network(address, network, len),
+                _ => false,
+            },
+            None => check_pattern_list(&address.to_string(), pattern),
+        };
+        if m && negated {
+            return false;
+        }
+        matched |= m;
+    }
+    matched
+}
+
+fn in_network(address: IpAddr, network: IpAddr, len: u32) -> bool {
+    let (a, n, bits): (u128, u128, u32) = match (address, network) {
+        (IpAddr::V4(a), IpAddr::V4(n)) => (u128::from(u32::from(a)), u128::from(u32::from(n)), 32),
+        (IpAddr::V6(a), IpAddr::V6(n)) => (u128::from(a), u128::from(n), 128),
+        _ => return false,
+    };
+    let shift = bits.saturating_sub(len.min(bits));
+    shift >= 128 || a >> shift == n >> shift
+}
+
+/// The options of an `sshd_config` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    /// `Port`, `22` if there is none.
+    pub ports: Vec<u16>,
+    /// `ListenAddress`, as written (`host`, `host:port` or
+    /// `[host]:port`).
+    pub listen_addresses: Vec<String>,
+    /// `HostKey` files.
+    pub host_keys: Vec<PathBuf>,
+    /// `Subsystem` names and commands.
+    pub subsystems: Vec<(String, String)>,
+    pub login_grace_time: u64,
+    pub ciphers: Option<crate::AlgorithmList>,
+    pub macs: Option<crate::AlgorithmList>,
+    pub kex_algorithms: Option<crate::AlgorithmList>,
+    /// The global values of the options `Match` blocks can change.
+    pub settings: Settings,
+    pub matches: Vec<MatchBlock>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            ports: Vec::new(),
+            listen_addresses: Vec::new(),
+            host_keys: Vec::new(),
+            subsystems: Vec::new(),
+            login_grace_time: 120,
+            ciphers: None,
+            macs: None,
+            kex_algorithms: None,
+            settings: Settings::default(),
+            matches: Vec::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The options that apply to `connection`: the global ones,
+    /// overridden by the first value of each option in the `Match`
+    /// blocks that apply.
+    pub fn settings(&self, connection: &Connection) -> Settings {
+        let mut settings = self.settings.clone();
+        let mut seen = HashSet::new();
+        for block in self.matches.iter().filter(|b| b.matches(connection)) {
+            for (key, value) in &block.options {
+                settings.apply(key, value, &mut seen)
+            }
+        }
+        settings
+    }
+
+    /// The addresses to listen on, with their ports: each
+    /// `ListenAddress` without a port is used with every `Port`. All
+    /// addresses are used if there is no `ListenAddress`.
+    pub fn addresses(&self) -> Vec<(String, u16)> {
+        let ports = if self.ports.is_empty() {
+            vec![22]
+        } else {
+            self.ports.clone()
+        };
+        let listen = if self.listen_addresses.is_empty() {
+            vec!["0.0.0.0".to_string(), "::".to_string()]
+        } else {
+            self.listen_addresses.clone()
+        };
+        let mut addresses = Vec::new();
+        for address in listen {
+            let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+                match rest.split_once(']') {
+                    Some((host, port)) => (host.to_string(), port.strip_prefix(':')),
+                    None => (rest.to_string(), None),
+                }
+            } else {
+                match address.rsplit_once(':') {
+                    Some((host, port)) if !host.contains(':') => (host.to_string(), Some(port)),
+                    _ => (address.clone(), None),
+                }
+            };
+            match port.map(|p| p.parse::<u16>()) {
+                Some(Ok(port)) => addresses.push((host, port)),
+                Some(Err(_)) => debug!("Invalid ListenAddress {:?}", address),
+                None => addresses.extend(ports.iter().map(|p| (host.clone(), *p))),
+            }
+        }
+        addresses
+    }
+}
+
+/// Parse the server configuration in `/etc/ssh/sshd_config`.
+pub fn parse_default() -> Result<ServerConfig, Error> {
+    parse_path("/etc/ssh/sshd_config")
+}
+
+/// Parse the server configuration file at `path`, and the files it
+/// includes.
+pub fn parse_path<P: AsRef<Path>>(path: P) -> Result<ServerConfig, Error> {
+    let mut config = ServerConfig::default();
+    let mut state = State::default();
+    parse_file(path.as_ref(), &mut config, &mut state)?;
+    Ok(config)
+}
+
+/// Parse the contents of a server configuration file. Relative
+/// `Include` paths are resolved in `/etc/ssh`.
+pub fn parse(file: &str) -> Result<ServerConfig, Error> {
+    let mut config = ServerConfig::default();
+    let mut state = State::default();
+    parse_lines(file, &mut config, &mut state)?;
+    Ok(config)
+}
+
+#[derive(Default)]
+struct State {
+    includes: Vec<PathBuf>,
+    seen: HashSet<String>,
+    /// Whether the lines are in a `Match` block.
+    in_match: bool,
+}
+
+fn parse_file(path: &Path, config: &mut ServerConfig, state: &mut State) -> Result<(), Error> {
+    let canonical = path.canonicalize()?;
+    if state.includes.len() >= crate::MAX_INCLUDE_DEPTH || state.includes.contains(&canonical) {
+        return Err(Error::IncludeDepth(canonical));
+    }
+    let s = std::fs::read_to_string(path)?;
+    state.includes.push(canonical);
+    let r = parse_lines(&s, config, state);
+    state.includes.pop();
+    r
+}
+
+fn parse_lines(file: &str, config: &mut ServerConfig, state: &mut State) -> Result<(), Error> {
+    for line in file.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // Options are separated from their values by whitespace or `=`.
+        let (key, value) = match line.split_once(|c: char| c.is_whitespace() || c == '=') {
+            Some((key, value)) => (
+                key,
+                value
+                    .trim_start_matches(|c: char| c.is_whitespace() || c == '=')
+                    .trim(),
+            ),
+            None => (line, ""),
+        };
+        let lower = key.to_lowercase();
+        if lower == "match" {
+            state.in_match = true;
+            config.matches.push(MatchBlock {
+                criteria: value.to_string(),
+                options: Vec::new(),
+            });
+            continue;
+        }
+        if lower == "include" {
+            for pattern in value.split_whitespace() {
+                let pattern = if Path::new(pattern).is_absolute() {
+                    pattern.to_string()
+                } else {
+                    format!("/etc/ssh/{}", pattern)
+                };
+                for path in crate::include_paths(&pattern)? {
+                    parse_file(&path, config, state)?;
+                }
+            }
+            continue;
+        }
+        if state.in_match {
+            if let Some(block) = config.matches.last_mut() {
+                block.options.push((key.to_string(), value.to_string()))
+            }
+            continue;
+        }
+        match lower.as_str() {
+            "port" => match value.parse() {
+                Ok(port) => config.ports.push(port),
+                Err(_) => debug!("Invalid Port {:?}", value),
+            },
+            "listenaddress" => config.listen_addresses.push(value.to_string()),
+            "hostkey" => config.host_keys.push(PathBuf::from(value)),
+            "subsystem" => match value.split_once(char::is_whitespace) {
+                Some((name, command)) => {
+                    if config.subsystems.iter().any(|(n, _)| n == name) {
+                        debug!("Subsystem {:?} already defined, ignoring", name);
+                    } else {
+                        config
+                            .subsystems
+                            .push((name.to_string(), command.trim().to_string()))
+                    }
+                }
+                None => debug!("Invalid Subsystem {:?}", value),
+            },
+            "logingracetime" | "ciphers" | "macs" | "kexalgorithms"
+                if !state.seen.insert(lower.clone()) =>
+            {
+                debug!("{:?} already set, ignoring", key);
+            }
+            "logingracetime" => match parse_time(value) {
+                Some(t) => config.login_grace_time = t,
+                None => debug!("Invalid LoginGraceTime {:?}", value),
+            },
+            "ciphers" => config.ciphers = Some(crate::AlgorithmList::parse(value)),
+            "macs" => config.macs = Some(crate::AlgorithmList::parse(value)),
+            "kexalgorithms" => config.kex_algorithms = Some(crate::AlgorithmList::parse(value)),
+            _ => config.settings.apply(key, value, &mut state.seen),
+        }
+    }
+    Ok(())
+}
+
+/// Parse a time in seconds, possibly with units, such as `2m` or `1h30m`.
+fn parse_time(value: &str) -> Option<u64> {
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return None,
+        };
+        total += number.parse::<u64>().ok()? * unit;
+        number.clear();
+    }
+    if !number.is_empty() {
+        total += number.parse::<u64>().ok()?;
+    }
+    Some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    const SSHD_CONFIG: &str = "
+# A typical configuration.
+Port 22
+Port 2222
+ListenAddress 192.0.2.1
+ListenAddress [::1]:2022
+HostKey /etc/ssh/ssh_host_ed25519_key
+PermitRootLogin no
+PasswordAuthentication no
+AllowUsers alice bob@192.0.2.*
+LoginGraceTime 1m30s
+Subsystem sftp /usr/lib/openssh/sftp-server
+Subsystem sftp internal-sftp
+
+Match User bob Address 192.0.2.0/24
+    PasswordAuthentication yes
+    ForceCommand /usr/bin/backup
+
+Match Group admins
+    PermitRootLogin prohibit-password
+    PasswordAuthentication no
+    AllowUsers root
+";
+
+    #[test]
+    fn global_options() {
+        let config = parse(SSHD_CONFIG).unwrap();
+        assert_eq!(config.ports, [22, 2222]);
+        assert_eq!(
+            config.addresses(),
+            [
+                ("192.0.2.1".to_string(), 22),
+                ("192.0.2.1".to_string(), 2222),
+                ("::1".to_string(), 2022)
+            ]
+        );
+        assert_eq!(
+            config.host_keys,
+            [PathBuf::from("/etc/ssh/ssh_host_ed25519_key")]
+        );
+        assert_eq!(config.login_grace_time, 90);
+        assert_eq!(
+            config.subsystems,
+            [(
+                "sftp".to_string(),
+                "/usr/lib/openssh/sftp-server".to_string()
+            )]
+        );
+        assert_eq!(config.settings.permit_root_login, PermitRootLogin::No);
+        assert!(!config.settings.password_authentication);
+        assert_eq!(config.matches.len(), 2);
+
+        let settings = &config.settings;
+        assert!(settings.allows_user("alice", &[], "198.51.100.1"));
+        assert!(settings.allows_user("bob", &[], "192.0.2.7"));
+        assert!(!settings.allows_user("bob", &[], "198.51.100.1"));
+        assert!(!settings.allows_user("eve", &[], "192.0.2.7"));
+        assert!(!settings.allows_method("alice", "password", false));
+        assert!(settings.allows_method("alice", "publickey", false));
+        assert!(!settings.allows_method("root", "publickey", false));
+    }
+
+    #[test]
+    fn match_blocks() {
+        let config = parse(SSHD_CONFIG).unwrap();
+        let bob = Connection {
+            user: "bob".to_string(),
+            address: Some("192.0.2.7".parse().unwrap()),
+            ..Default::default()
+        };
+        let settings = config.settings(&bob);
+        assert!(settings.password_authentication);
+        assert_eq!(settings.force_command.as_deref(), Some("/usr/bin/backup"));
+
+        let elsewhere = Connection {
+            address: Some("198.51.100.1".parse().unwrap()),
+            ..bob
+        };
+        assert!(!config.settings(&elsewhere).password_authentication);
+
+        let root = Connection {
+            user: "root".to_string(),
+            groups: vec!["admins".to_string()],
+            ..Default::default()
+        };
+        let settings = config.settings(&root);
+        assert!(settings.allows_user("root", &root.groups, "localhost"));
+        assert!(!settings.allows_user("alice", &[], "localhost"));
+        assert!(settings.allows_method("root", "publickey", false));
+        assert!(!settings.allows_method("root", "password", false));
+    }
+}
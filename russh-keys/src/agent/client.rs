@@ -5,12 +5,13 @@ use bytes::Bytes;
 use log::debug;
 use russh_cryptovec::CryptoVec;
 use ssh_encoding::{Decode, Encode, Reader};
-use ssh_key::{Algorithm, HashAlg, PrivateKey, PublicKey, Signature};
+use ssh_key::{Algorithm, HashAlg, PrivateKey, PublicKey};
 use tokio;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use super::{msg, Constraint};
 use crate::helpers::EncodedExt;
+use crate::key::SshSignature;
 use crate::{key, Error};
 
 pub trait AgentStream: AsyncRead + AsyncWrite {}
@@ -111,6 +112,36 @@ impl AgentClient<tokio::net::windows::named_pipe::NamedPipeClient> {
     }
 }
 
+#[cfg(windows)]
+impl AgentClient<Box<dyn AgentStream + Send + Unpin + 'static>> {
+    /// Connect to the SSH agent of the user: the named pipe in the
+    /// SSH_AUTH_SOCK environment variable if it is set, else the agent
+    /// of OpenSSH for Windows if it is running, else Pageant.
+    pub async fn connect_env() -> Result<Self, Error> {
+        if let Ok(var) = std::env::var("SSH_AUTH_SOCK") {
+            return match AgentClient::connect_named_pipe(var).await {
+                Ok(client) => Ok(client.dynamic()),
+                Err(Error::IO(io_err)) if io_err.kind() == std::io::ErrorKind::NotFound => {
+                    Err(Error::BadAuthSock)
+                }
+                Err(e) => Err(e),
+            };
+        }
+        match AgentClient::connect_named_pipe(super::server::OPENSSH_AGENT_PIPE).await {
+            Ok(client) => return Ok(client.dynamic()),
+            Err(Error::IO(io_err)) if io_err.kind() == std::io::ErrorKind::NotFound => {
+                debug!("no OpenSSH agent pipe, trying Pageant")
+            }
+            Err(e) => return Err(e),
+        }
+        if pageant::is_pageant_running() {
+            Ok(AgentClient::connect_pageant().await.dynamic())
+        } else {
+            Err(Error::BadAuthSock)
+        }
+    }
+}
+
 impl<S: AgentStream + Unpin> AgentClient<S> {
     async fn read_response(&mut self) -> Result<(), Error> {
         // Writing the message
@@ -158,27 +189,8 @@ impl<S: AgentStream + Unpin> AgentClient<S> {
         }
 
         key.key_data().encode(&mut self.buf)?;
-        "".encode(&mut self.buf)?; // comment field
-
-        if !constraints.is_empty() {
-            for cons in constraints {
-                match *cons {
-                    Constraint::KeyLifetime { seconds } => {
-                        msg::CONSTRAIN_LIFETIME.encode(&mut self.buf)?;
-                        seconds.encode(&mut self.buf)?;
-                    }
-                    Constraint::Confirm => self.buf.push(msg::CONSTRAIN_CONFIRM),
-                    Constraint::Extensions {
-                        ref name,
-                        ref details,
-                    } => {
-                        msg::CONSTRAIN_EXTENSION.encode(&mut self.buf)?;
-                        name.encode(&mut self.buf)?;
-                        details.encode(&mut self.buf)?;
-                    }
-                }
-            }
-        }
+        key.comment().encode(&mut self.buf)?;
+        encode_constraints(constraints, &mut self.buf)?;
         let len = self.buf.len() - 4;
         BigEndian::write_u32(&mut self.buf[..], len as u32);
 
@@ -203,30 +215,10 @@ impl<S: AgentStream + Unpin> AgentClient<S> {
         }
         id.encode(&mut self.buf)?;
         pin.encode(&mut self.buf)?;
-        if !constraints.is_empty() {
-            (constraints.len() as u32).encode(&mut self.buf)?;
-            for cons in constraints {
-                match *cons {
-                    Constraint::KeyLifetime { seconds } => {
-                        msg::CONSTRAIN_LIFETIME.encode(&mut self.buf)?;
-                        seconds.encode(&mut self.buf)?;
-                    }
-                    Constraint::Confirm => self.buf.push(msg::CONSTRAIN_CONFIRM),
-                    Constraint::Extensions {
-                        ref name,
-                        ref details,
-                    } => {
-                        msg::CONSTRAIN_EXTENSION.encode(&mut self.buf)?;
-                        name.encode(&mut self.buf)?;
-                        details.encode(&mut self.buf)?;
-                    }
-                }
-            }
-        }
+        encode_constraints(constraints, &mut self.buf)?;
         let len = self.buf.len() - 4;
         BigEndian::write_u32(&mut self.buf[..], len as u32);
-        self.read_response().await?;
-        Ok(())
+        self.read_success().await
     }
 
     /// Lock the agent, making it refuse to sign until unlocked.
@@ -237,11 +229,11 @@ impl<S: AgentStream + Unpin> AgentClient<S> {
         passphrase.encode(&mut self.buf)?;
         let len = self.buf.len() - 4;
         BigEndian::write_u32(&mut self.buf[..], len as u32);
-        self.read_response().await?;
-        Ok(())
+        self.read_success().await
     }
 
-    /// Unlock the agent, allowing it to sign again.
+    /// Unlock the agent, allowing it to sign again. Fails with
+    /// [`Error::AgentFailure`] if the passphrase is wrong.
     pub async fn unlock(&mut self, passphrase: &[u8]) -> Result<(), Error> {
         self.buf.clear();
         self.buf.resize(4);
@@ -250,8 +242,7 @@ impl<S: AgentStream + Unpin> AgentClient<S> {
         let len = self.buf.len() - 4;
         #[allow(clippy::indexing_slicing)] // static length
         BigEndian::write_u32(&mut self.buf[..], len as u32);
-        self.read_response().await?;
-        Ok(())
+        self.read_success().await
     }
 
     /// Ask the agent for a list of the currently registered secret
@@ -376,12 +367,13 @@ impl<S: AgentStream + Unpin> AgentClient<S> {
         }
     }
 
-    /// Ask the agent to sign the supplied piece of data, and return a `Signature`.
+    /// Ask the agent to sign the supplied piece of data, and return the
+    /// signature, which may be an `ssh-rsa` one for RSA keys.
     pub async fn sign_request_signature(
         &mut self,
         public: &ssh_key::PublicKey,
         data: &[u8],
-    ) -> Result<Signature, Error> {
+    ) -> Result<SshSignature, Error> {
         debug!("sign_request: {:?}", data);
 
         self.prepare_sign_request(public, data)?;
@@ -390,7 +382,7 @@ impl<S: AgentStream + Unpin> AgentClient<S> {
         match self.buf.split_first() {
             Some((&msg::SIGN_RESPONSE, mut r)) => {
                 let mut resp = &Bytes::decode(&mut r)?[..];
-                let sig = Signature::decode(&mut resp)?;
+                let sig = SshSignature::decode(&mut resp)?;
                 Ok(sig)
             }
             _ => Err(Error::AgentProtocolError),
@@ -405,8 +397,7 @@ impl<S: AgentStream + Unpin> AgentClient<S> {
         public.key_data().encoded()?.encode(&mut self.buf)?;
         let len = self.buf.len() - 4;
         BigEndian::write_u32(&mut self.buf[..], len as u32);
-        self.read_response().await?;
-        Ok(())
+        self.read_success().await
     }
 
     /// Ask the agent to remove a smartcard from its memory.
@@ -418,8 +409,7 @@ impl<S: AgentStream + Unpin> AgentClient<S> {
         pin.encode(&mut self.buf)?;
         let len = self.buf.len() - 4;
         BigEndian::write_u32(&mut self.buf[..], len as u32);
-        self.read_response().await?;
-        Ok(())
+        self.read_success().await
     }
 
     /// Ask the agent to forget all known keys.
@@ -427,9 +417,9 @@ impl<S: AgentStream + Unpin> AgentClient<S> {
         self.buf.clear();
         self.buf.resize(4);
         msg::REMOVE_ALL_IDENTITIES.encode(&mut self.buf)?;
-        1u32.encode(&mut self.buf)?;
-        self.read_success().await?;
-        Ok(())
+        let len = self.buf.len() - 4;
+        BigEndian::write_u32(&mut self.buf[..], len as u32);
+        self.read_success().await
     }
 
     /// Send a custom message to the agent.
@@ -440,9 +430,8 @@ impl<S: AgentStream + Unpin> AgentClient<S> {
         typ.encode(&mut self.buf)?;
         ext.encode(&mut self.buf)?;
         let len = self.buf.len() - 4;
-        (len as u32).encode(&mut self.buf)?;
-        self.read_response().await?;
-        Ok(())
+        BigEndian::write_u32(&mut self.buf[..], len as u32);
+        self.read_success().await
     }
 
     /// Ask the agent what extensions about supported extensions.
@@ -452,7 +441,7 @@ impl<S: AgentStream + Unpin> AgentClient<S> {
         msg::EXTENSION.encode(&mut self.buf)?;
         typ.encode(&mut self.buf)?;
         let len = self.buf.len() - 4;
-        (len as u32).encode(&mut self.buf)?;
+        BigEndian::write_u32(&mut self.buf[..], len as u32);
         self.read_response().await?;
 
         match self.buf.split_first() {
@@ -464,3 +453,26 @@ impl<S: AgentStream + Unpin> AgentClient<S> {
         }
     }
 }
+
+/// Append key constraints to an `ADD_ID_CONSTRAINED` or
+/// `ADD_SMARTCARD_KEY_CONSTRAINED` message.
+fn encode_constraints(constraints: &[Constraint], buf: &mut CryptoVec) -> Result<(), Error> {
+    for cons in constraints {
+        match *cons {
+            Constraint::KeyLifetime { seconds } => {
+                msg::CONSTRAIN_LIFETIME.encode(buf)?;
+                seconds.encode(buf)?;
+            }
+            Constraint::Confirm => buf.push(msg::CONSTRAIN_CONFIRM),
+            Constraint::Extensions {
+                ref name,
+                ref details,
+            } => {
+                msg::CONSTRAIN_EXTENSION.encode(buf)?;
+                name.encode(buf)?;
+                details.encode(buf)?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -8,23 +8,25 @@ use byteorder::{BigEndian, ByteOrder};
 use bytes::Bytes;
 use futures::future::Future;
 use futures::stream::{Stream, StreamExt};
-use russh_cryptovec::CryptoVec;
+use log::debug;
+use russh_cryptovec::{CryptoVec, SecretBuffer};
 use ssh_encoding::{Decode, Encode, Reader};
-use ssh_key::PrivateKey;
+use ssh_key::{HashAlg, PrivateKey, PublicKey};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::time::sleep;
 use {std, tokio};
 
 use super::{msg, Constraint};
 use crate::helpers::EncodedExt;
-use crate::Error;
+use crate::key::SshSignature;
+use crate::{key, Error};
 
 #[derive(Clone)]
 #[allow(clippy::type_complexity)]
 struct KeyStore(Arc<RwLock<HashMap<Vec<u8>, (Arc<PrivateKey>, SystemTime, Vec<Constraint>)>>>);
 
-#[derive(Clone)]
-struct Lock(Arc<RwLock<CryptoVec>>);
+#[derive(Clone, Default)]
+struct Lock(Arc<RwLock<SecretBuffer>>);
 
 #[allow(missing_docs)]
 #[derive(Debug)]
@@ -57,22 +59,98 @@ pub trait Agent: Clone + Send + 'static {
     }
 }
 
-pub async fn serve<S, L, A>(mut listener: L, agent: A) -> Result<(), Error>
+/// Where an agent served with [`serve_backend`] keeps its keys, for
+/// instance in memory, in an HSM, or behind a signing policy. Only
+/// listing identities and signing are mandatory: requests the backend
+/// doesn't support are answered with a failure.
+///
+/// Locking the agent is handled by the server, which refuses all
+/// other requests until it is unlocked.
+#[async_trait]
+pub trait AgentBackend: Send + Sync + 'static {
+    /// Called before handling a request, which is refused if this
+    /// returns `false`.
+    async fn confirm_request(&self, _msg: MessageType) -> bool {
+        true
+    }
+
+    /// The public keys available for signing, with their comments.
+    async fn request_identities(&self) -> Result<Vec<(PublicKey, String)>, Error>;
+
+    /// Sign `data` with the private key of `key`, or return `None` to
+    /// refuse. `flags` are the `SSH_AGENT_RSA_SHA2_*` flags of the
+    /// request, see [`rsa_hash_alg`].
+    async fn sign(
+        &self,
+        key: &PublicKey,
+        data: &[u8],
+        flags: u32,
+    ) -> Result<Option<SshSignature>, Error>;
+
+    /// Add a key sent by a client, returning `false` if it was refused.
+    async fn add_identity(
+        &self,
+        _key: PrivateKey,
+        _constraints: Vec<Constraint>,
+    ) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    /// Remove a key, returning `false` if it wasn't found or can't be
+    /// removed.
+    async fn remove_identity(&self, _key: &PublicKey) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    /// Remove all keys, returning `false` if this isn't supported.
+    async fn remove_all_identities(&self) -> Result<bool, Error> {
+        Ok(false)
+    }
+}
+
+/// The RSA hash requested by the flags of a signature request, `None`
+/// meaning SHA-1 (`ssh-rsa`).
+pub fn rsa_hash_alg(flags: u32) -> Option<HashAlg> {
+    if flags & msg::RSA_SHA2_512 != 0 {
+        Some(HashAlg::Sha512)
+    } else if flags & msg::RSA_SHA2_256 != 0 {
+        Some(HashAlg::Sha256)
+    } else {
+        None
+    }
+}
+
+/// Serve an agent keeping the keys added by its clients in memory, and
+/// asking `agent` to confirm requests, and signatures with keys added
+/// with [`Constraint::Confirm`].
+pub async fn serve<S, L, A>(listener: L, agent: A) -> Result<(), Error>
 where
     S: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
     L: Stream<Item = tokio::io::Result<S>> + Unpin,
     A: Agent + Send + Sync + 'static,
 {
-    let keys = KeyStore(Arc::new(RwLock::new(HashMap::new())));
-    let lock = Lock(Arc::new(RwLock::new(CryptoVec::new())));
+    let backend = MemoryBackend {
+        keys: KeyStore(Arc::new(RwLock::new(HashMap::new()))),
+        agent,
+    };
+    serve_backend(listener, backend).await
+}
+
+/// Serve an agent on the connections of `listener`, delegating
+/// storage and signing to `backend`.
+pub async fn serve_backend<S, L, B>(mut listener: L, backend: B) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+    L: Stream<Item = tokio::io::Result<S>> + Unpin,
+    B: AgentBackend,
+{
+    let backend = Arc::new(backend);
+    let lock = Lock::default();
     while let Some(Ok(stream)) = listener.next().await {
-        let mut buf = CryptoVec::new();
-        buf.resize(4);
         russh_util::runtime::spawn(
             (Connection {
                 lock: lock.clone(),
-                keys: keys.clone(),
-                agent: Some(agent.clone()),
+                backend: backend.clone(),
                 s: stream,
                 buf: CryptoVec::new(),
             })
@@ -82,23 +160,161 @@ where
     Ok(())
 }
 
+/// Bind a Unix socket at `path`, the value to give to clients in
+/// `SSH_AUTH_SOCK`, and serve `backend` on it.
+#[cfg(unix)]
+pub async fn serve_unix<P: AsRef<std::path::Path>, B: AgentBackend>(
+    path: P,
+    backend: B,
+) -> Result<(), Error> {
+    let listener = tokio::net::UnixListener::bind(path)?;
+    serve_backend(
+        tokio_stream::wrappers::UnixListenerStream::new(listener),
+        backend,
+    )
+    .await
+}
+
+/// The named pipe of the agent of OpenSSH for Windows.
+#[cfg(windows)]
+pub const OPENSSH_AGENT_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+
+/// Create the named pipe `path`, for instance [`OPENSSH_AGENT_PIPE`],
+/// and serve `backend` on it.
+#[cfg(windows)]
+pub async fn serve_named_pipe<P: AsRef<std::ffi::OsStr>, B: AgentBackend>(
+    path: P,
+    backend: B,
+) -> Result<(), Error> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let backend = Arc::new(backend);
+    let lock = Lock::default();
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(path.as_ref())?;
+    loop {
+        server.connect().await?;
+        // Create the next instance before handing this one over, so
+        // that clients never find the pipe missing.
+        let stream = std::mem::replace(&mut server, ServerOptions::new().create(path.as_ref())?);
+        russh_util::runtime::spawn(
+            (Connection {
+                lock: lock.clone(),
+                backend: backend.clone(),
+                s: stream,
+                buf: CryptoVec::new(),
+            })
+            .run(),
+        );
+    }
+}
+
 impl Agent for () {
     fn confirm(self, _: Arc<PrivateKey>) -> Box<dyn Future<Output = (Self, bool)> + Unpin + Send> {
         Box::new(futures::future::ready((self, true)))
     }
 }
 
-struct Connection<S: AsyncRead + AsyncWrite + Send + 'static, A: Agent> {
-    lock: Lock,
+/// The backend of [`serve`].
+struct MemoryBackend<A> {
     keys: KeyStore,
-    agent: Option<A>,
+    agent: A,
+}
+
+#[async_trait]
+impl<A: Agent + Sync> AgentBackend for MemoryBackend<A> {
+    async fn confirm_request(&self, msg: MessageType) -> bool {
+        self.agent.confirm_request(msg).await
+    }
+
+    async fn request_identities(&self) -> Result<Vec<(PublicKey, String)>, Error> {
+        let keys = self.keys.0.read().or(Err(Error::AgentFailure))?;
+        Ok(keys
+            .values()
+            .map(|(key, _, _)| (key.public_key().clone(), key.comment().to_string()))
+            .collect())
+    }
+
+    async fn sign(
+        &self,
+        public: &PublicKey,
+        data: &[u8],
+        flags: u32,
+    ) -> Result<Option<SshSignature>, Error> {
+        let (key, needs_confirm) = {
+            let blob = public.key_data().encoded()?;
+            let keys = self.keys.0.read().or(Err(Error::AgentFailure))?;
+            let Some((key, _, constraints)) = keys.get(&blob) else {
+                return Ok(None);
+            };
+            (key.clone(), constraints.contains(&Constraint::Confirm))
+        };
+        if needs_confirm {
+            let (_, ok) = self.agent.clone().confirm(key.clone()).await;
+            if !ok {
+                return Ok(None);
+            }
+        }
+        Ok(Some(key::sign_with_hash_alg(
+            &key,
+            rsa_hash_alg(flags),
+            data,
+        )?))
+    }
+
+    async fn add_identity(
+        &self,
+        key: PrivateKey,
+        constraints: Vec<Constraint>,
+    ) -> Result<bool, Error> {
+        let blob = key.public_key().key_data().encoded()?;
+        let now = SystemTime::now();
+        for constraint in constraints.iter() {
+            if let Constraint::KeyLifetime { seconds } = *constraint {
+                let blob = blob.clone();
+                let keys = self.keys.clone();
+                russh_util::runtime::spawn(async move {
+                    sleep(Duration::from_secs(seconds as u64)).await;
+                    if let Ok(mut keys) = keys.0.write() {
+                        let delete = if let Some(&(_, time, _)) = keys.get(&blob) {
+                            time == now
+                        } else {
+                            false
+                        };
+                        if delete {
+                            keys.remove(&blob);
+                        }
+                    }
+                });
+            }
+        }
+        let mut keys = self.keys.0.write().or(Err(Error::AgentFailure))?;
+        keys.insert(blob, (Arc::new(key), now, constraints));
+        Ok(true)
+    }
+
+    async fn remove_identity(&self, key: &PublicKey) -> Result<bool, Error> {
+        let blob = key.key_data().encoded()?;
+        let mut keys = self.keys.0.write().or(Err(Error::AgentFailure))?;
+        Ok(keys.remove(&blob).is_some())
+    }
+
+    async fn remove_all_identities(&self) -> Result<bool, Error> {
+        let mut keys = self.keys.0.write().or(Err(Error::AgentFailure))?;
+        keys.clear();
+        Ok(true)
+    }
+}
+
+struct Connection<S: AsyncRead + AsyncWrite + Send + 'static, B: AgentBackend> {
+    lock: Lock,
+    backend: Arc<B>,
     s: S,
     buf: CryptoVec,
 }
 
-impl<S: AsyncRead + AsyncWrite + Send + Unpin + 'static, A: Agent + Send + Sync + 'static>
-    Connection<S, A>
-{
+impl<S: AsyncRead + AsyncWrite + Send + Unpin + 'static, B: AgentBackend> Connection<S, B> {
     async fn run(mut self) -> Result<(), Error> {
         let mut writebuf = CryptoVec::new();
         loop {
@@ -128,101 +344,90 @@ impl<S: AsyncRead + AsyncWrite + Send + Unpin + 'static, A: Agent + Send + Sync
             }
         };
         writebuf.extend(&[0, 0, 0, 0]);
-        let agentref = self.agent.as_ref().ok_or(Error::AgentFailure)?;
+        let backend = self.backend.clone();
 
-        match self.buf.split_first() {
-            Some((&11, _))
-                if !is_locked && agentref.confirm_request(MessageType::RequestKeys).await =>
+        let success = match self.buf.split_first() {
+            Some((&msg::REQUEST_IDENTITIES, _))
+                if !is_locked && backend.confirm_request(MessageType::RequestKeys).await =>
             {
-                // request identities
-                if let Ok(keys) = self.keys.0.read() {
-                    msg::IDENTITIES_ANSWER.encode(writebuf)?;
-                    (keys.len() as u32).encode(writebuf)?;
-                    for (k, _) in keys.iter() {
-                        k.encode(writebuf)?;
-                        "".encode(writebuf)?;
+                match backend.request_identities().await {
+                    Ok(keys) => {
+                        msg::IDENTITIES_ANSWER.encode(writebuf)?;
+                        (keys.len() as u32).encode(writebuf)?;
+                        for (key, comment) in keys.iter() {
+                            key.key_data().encoded()?.encode(writebuf)?;
+                            comment.encode(writebuf)?;
+                        }
+                        true
+                    }
+                    Err(e) => {
+                        debug!("request_identities: {e}");
+                        false
                     }
-                } else {
-                    msg::FAILURE.encode(writebuf)?
                 }
             }
-            Some((&13, mut r))
-                if !is_locked && agentref.confirm_request(MessageType::Sign).await =>
+            Some((&msg::SIGN_REQUEST, mut r))
+                if !is_locked && backend.confirm_request(MessageType::Sign).await =>
             {
-                // sign request
-                let agent = self.agent.take().ok_or(Error::AgentFailure)?;
-                let (agent, signed) = self.try_sign(agent, &mut r, writebuf).await?;
-                self.agent = Some(agent);
-                if signed {
-                    return Ok(());
-                } else {
-                    writebuf.resize(4);
-                    writebuf.push(msg::FAILURE)
+                match self.try_sign(&mut r).await {
+                    Ok(Some(signature)) => {
+                        msg::SIGN_RESPONSE.encode(writebuf)?;
+                        signature.encoded()?.encode(writebuf)?;
+                        true
+                    }
+                    Ok(None) => false,
+                    Err(e) => {
+                        debug!("sign: {e}");
+                        false
+                    }
                 }
             }
-            Some((&17, mut r))
-                if !is_locked && agentref.confirm_request(MessageType::AddKeys).await =>
+            Some((&msg::ADD_IDENTITY, mut r))
+                if !is_locked && backend.confirm_request(MessageType::AddKeys).await =>
             {
-                // add identity
-                if let Ok(true) = self.add_key(&mut r, false, writebuf).await {
-                } else {
-                    writebuf.push(msg::FAILURE)
-                }
+                self.add_key(&mut r, false).await.unwrap_or(false)
             }
-            Some((&18, mut r))
-                if !is_locked && agentref.confirm_request(MessageType::RemoveKeys).await =>
+            Some((&msg::ADD_ID_CONSTRAINED, mut r))
+                if !is_locked && backend.confirm_request(MessageType::AddKeys).await =>
             {
-                // remove identity
-                if let Ok(true) = self.remove_identity(&mut r) {
-                    writebuf.push(msg::SUCCESS)
-                } else {
-                    writebuf.push(msg::FAILURE)
-                }
+                self.add_key(&mut r, true).await.unwrap_or(false)
             }
-            Some((&19, _))
-                if !is_locked && agentref.confirm_request(MessageType::RemoveAllKeys).await =>
+            Some((&msg::REMOVE_IDENTITY, mut r))
+                if !is_locked && backend.confirm_request(MessageType::RemoveKeys).await =>
             {
-                // remove all identities
-                if let Ok(mut keys) = self.keys.0.write() {
-                    keys.clear();
-                    writebuf.push(msg::SUCCESS)
-                } else {
-                    writebuf.push(msg::FAILURE)
+                match Bytes::decode(&mut r) {
+                    Ok(blob) => match key::parse_public_key(&blob) {
+                        Ok(key) => backend.remove_identity(&key).await.unwrap_or(false),
+                        Err(_) => false,
+                    },
+                    Err(_) => false,
                 }
             }
-            Some((&22, mut r))
-                if !is_locked && agentref.confirm_request(MessageType::Lock).await =>
+            Some((&msg::REMOVE_ALL_IDENTITIES, _))
+                if !is_locked && backend.confirm_request(MessageType::RemoveAllKeys).await =>
             {
-                // lock
-                if let Ok(()) = self.lock(&mut r) {
-                    writebuf.push(msg::SUCCESS)
-                } else {
-                    writebuf.push(msg::FAILURE)
-                }
+                backend.remove_all_identities().await.unwrap_or(false)
             }
-            Some((&23, mut r))
-                if is_locked && agentref.confirm_request(MessageType::Unlock).await =>
+            Some((&msg::LOCK, mut r))
+                if !is_locked && backend.confirm_request(MessageType::Lock).await =>
             {
-                // unlock
-                if let Ok(true) = self.unlock(&mut r) {
-                    writebuf.push(msg::SUCCESS)
-                } else {
-                    writebuf.push(msg::FAILURE)
-                }
+                self.lock(&mut r).is_ok()
             }
-            Some((&25, mut r))
-                if !is_locked && agentref.confirm_request(MessageType::AddKeys).await =>
+            Some((&msg::UNLOCK, mut r))
+                if is_locked && backend.confirm_request(MessageType::Unlock).await =>
             {
-                // add identity constrained
-                if let Ok(true) = self.add_key(&mut r, true, writebuf).await {
-                } else {
-                    writebuf.push(msg::FAILURE)
-                }
+                matches!(self.unlock(&mut r), Ok(true))
             }
             _ => {
                 // Message not understood
-                writebuf.push(msg::FAILURE)
+                false
             }
+        };
+        if !success {
+            writebuf.resize(4);
+            writebuf.push(msg::FAILURE)
+        } else if writebuf.len() == 4 {
+            writebuf.push(msg::SUCCESS)
         }
         let len = writebuf.len() - 4;
         BigEndian::write_u32(&mut writebuf[..], len as u32);
@@ -230,16 +435,17 @@ impl<S: AsyncRead + AsyncWrite + Send + Unpin + 'static, A: Agent + Send + Sync
     }
 
     fn lock<R: Reader>(&self, r: &mut R) -> Result<(), Error> {
-        let password = Bytes::decode(r)?;
+        // Converting the password to a `CryptoVec` zeroes the vector.
+        let password = CryptoVec::from(Vec::<u8>::decode(r)?);
         let mut lock = self.lock.0.write().or(Err(Error::AgentFailure))?;
         lock.extend(&password);
         Ok(())
     }
 
     fn unlock<R: Reader>(&self, r: &mut R) -> Result<bool, Error> {
-        let password = Bytes::decode(r)?;
+        let password = CryptoVec::from(Vec::<u8>::decode(r)?);
         let mut lock = self.lock.0.write().or(Err(Error::AgentFailure))?;
-        if lock[..] == password {
+        if lock.eq_bytes(&password) {
             lock.clear();
             Ok(true)
         } else {
@@ -247,107 +453,36 @@ impl<S: AsyncRead + AsyncWrite + Send + Unpin + 'static, A: Agent + Send + Sync
         }
     }
 
-    fn remove_identity<R: Reader>(&self, r: &mut R) -> Result<bool, Error> {
-        if let Ok(mut keys) = self.keys.0.write() {
-            if keys.remove(&Bytes::decode(r)?.to_vec()).is_some() {
-                Ok(true)
-            } else {
-                Ok(false)
-            }
-        } else {
-            Ok(false)
-        }
-    }
-
-    async fn add_key<R: Reader>(
-        &self,
-        r: &mut R,
-        constrained: bool,
-        writebuf: &mut CryptoVec,
-    ) -> Result<bool, Error> {
-        let (blob, key_pair) = {
-            use ssh_encoding::Decode;
-
-            let private_key =
-                ssh_key::private::PrivateKey::new(ssh_key::private::KeypairData::decode(r)?, "")?;
-            let _comment = String::decode(r)?;
-
-            (private_key.public_key().key_data().encoded()?, private_key)
-        };
-        writebuf.push(msg::SUCCESS);
-        let mut w = self.keys.0.write().or(Err(Error::AgentFailure))?;
-        let now = SystemTime::now();
+    async fn add_key<R: Reader>(&self, r: &mut R, constrained: bool) -> Result<bool, Error> {
+        let key_pair = ssh_key::private::KeypairData::decode(r)?;
+        let comment = String::decode(r)?;
+        let private_key = PrivateKey::new(key_pair, comment)?;
+        let mut constraints = Vec::new();
         if constrained {
-            let mut c = Vec::new();
             while let Ok(t) = u8::decode(r) {
                 if t == msg::CONSTRAIN_LIFETIME {
                     let seconds = u32::decode(r)?;
-                    c.push(Constraint::KeyLifetime { seconds });
-                    let blob = blob.clone();
-                    let keys = self.keys.clone();
-                    russh_util::runtime::spawn(async move {
-                        sleep(Duration::from_secs(seconds as u64)).await;
-                        if let Ok(mut keys) = keys.0.write() {
-                            let delete = if let Some(&(_, time, _)) = keys.get(&blob) {
-                                time == now
-                            } else {
-                                false
-                            };
-                            if delete {
-                                keys.remove(&blob);
-                            }
-                        }
-                    });
+                    constraints.push(Constraint::KeyLifetime { seconds });
                 } else if t == msg::CONSTRAIN_CONFIRM {
-                    c.push(Constraint::Confirm)
+                    constraints.push(Constraint::Confirm)
+                } else if t == msg::CONSTRAIN_EXTENSION {
+                    let name = Vec::<u8>::decode(r)?;
+                    let details = Vec::<u8>::decode(r)?;
+                    constraints.push(Constraint::Extensions { name, details })
                 } else {
                     return Ok(false);
                 }
             }
-            w.insert(blob, (Arc::new(key_pair), now, c));
-        } else {
-            w.insert(blob, (Arc::new(key_pair), now, Vec::new()));
         }
-        Ok(true)
+        self.backend.add_identity(private_key, constraints).await
     }
 
-    async fn try_sign<R: Reader>(
-        &self,
-        agent: A,
-        r: &mut R,
-        writebuf: &mut CryptoVec,
-    ) -> Result<(A, bool), Error> {
-        let mut needs_confirm = false;
-        let key = {
-            let blob = Bytes::decode(r)?;
-            let k = self.keys.0.read().or(Err(Error::AgentFailure))?;
-            if let Some((key, _, constraints)) = k.get(&blob.to_vec()) {
-                if constraints.iter().any(|c| *c == Constraint::Confirm) {
-                    needs_confirm = true;
-                }
-                key.clone()
-            } else {
-                return Ok((agent, false));
-            }
-        };
-        let agent = if needs_confirm {
-            let (agent, ok) = agent.confirm(key.clone()).await;
-            if !ok {
-                return Ok((agent, false));
-            }
-            agent
-        } else {
-            agent
-        };
-        writebuf.push(msg::SIGN_RESPONSE);
+    async fn try_sign<R: Reader>(&self, r: &mut R) -> Result<Option<SshSignature>, Error> {
+        let blob = Bytes::decode(r)?;
         let data = Bytes::decode(r)?;
-
-        let signature = signature::Signer::try_sign(&*key, &data)?;
-        signature.encoded()?.encode(writebuf)?;
-
-        let len = writebuf.len();
-        BigEndian::write_u32(writebuf, (len - 4) as u32);
-
-        Ok((agent, true))
+        // The flags are missing in requests from old clients.
+        let flags = u32::decode(r).unwrap_or(0);
+        let key = key::parse_public_key(&blob)?;
+        self.backend.sign(&key, &data, flags).await
     }
 }
@@ -0,0 +1,852 @@
+//! OpenSSH `authorized_keys` files, and the restrictions their options
+//! put on the keys and certificates they authorize.
+//!
+//! A server's [`Handler::auth_publickey`] can look the client's key up
+//! with [`AuthorizedKeys::authorize_key`], or its certificate with
+//! [`AuthorizedKeys::authorize_certificate`], and keep the returned
+//! [`KeyOptions`] to check the channels and requests of the session
+//! against them.
+//!
+//! ```
+//! use russh_keys::authorized_keys::AuthorizedKeys;
+//!
+//! let keys: AuthorizedKeys = "restrict,pty,from=\"10.0.0.0/8\" \
+//!     ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIK2pr+Bfus1hvPof4Wb/plZCzSbqadHH0u+/f4ODtKnE"
+//!     .parse()
+//!     .unwrap();
+//! let key = &keys.entries()[0].key;
+//! let options = keys
+//!     .authorize_key(key, Some("10.1.2.3".parse().unwrap()))
+//!     .unwrap();
+//! assert!(options.permits_request("pty-req"));
+//! assert!(!options.permits_channel("direct-tcpip"));
+//! assert!(keys.authorize_key(key, Some("192.0.2.1".parse().unwrap())).is_none());
+//! ```
+//!
+//! [`Handler::auth_publickey`]: https://docs.rs/russh/latest/russh/server/trait.Handler.html#method.auth_publickey
+
+use std::convert::TryFrom;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use ssh_key::{Certificate, PublicKey};
+
+use crate::known_hosts::match_wildcard;
+use crate::Error;
+
+/// The options of an `authorized_keys` line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyOptions {
+    /// `cert-authority`: the key is a CA trusted to sign user
+    /// certificates.
+    pub cert_authority: bool,
+    /// `command="..."`: the only command the key may run, instead of
+    /// the one requested by the client.
+    pub command: Option<String>,
+    /// `environment="NAME=value"`: variables set for the session.
+    pub environment: Vec<(String, String)>,
+    /// `from="..."`: the comma-separated patterns the address of the
+    /// client must match, possibly negated with `!`. Only addresses are
+    /// matched, with wildcards or in CIDR notation, not host names.
+    pub from: Option<String>,
+    /// `expiry-time="YYYYMMDD[HHMM[SS]]"`: when the key stops being
+    /// accepted. Times are read as UTC.
+    pub expiry_time: Option<SystemTime>,
+    /// `principals="..."`: the principals of which a certificate must
+    /// list one, instead of the name of the user, with `cert-authority`.
+    pub principals: Option<Vec<String>>,
+    /// `permitopen="host:port"`: the only destinations of port
+    /// forwarding, if not empty.
+    pub permit_open: Vec<String>,
+    /// `permitlisten="[host:]port"`: the only addresses remote
+    /// forwarding may listen on, if not empty.
+    pub permit_listen: Vec<String>,
+    /// Cleared by `no-port-forwarding` or `restrict`, set again by
+    /// `port-forwarding`.
+    pub port_forwarding: bool,
+    /// Cleared by `no-agent-forwarding` or `restrict`, set again by
+    /// `agent-forwarding`.
+    pub agent_forwarding: bool,
+    /// Cleared by `no-X11-forwarding` or `restrict`, set again by
+    /// `X11-forwarding`.
+    pub x11_forwarding: bool,
+    /// Cleared by `no-pty` or `restrict`, set again by `pty`.
+    pub pty: bool,
+    /// Cleared by `no-user-rc` or `restrict`, set again by `user-rc`.
+    pub user_rc: bool,
+    /// `no-touch-required`: signatures of security keys are accepted
+    /// without user presence.
+    pub no_touch_required: bool,
+    /// `verify-required`: signatures of security keys must have
+    /// verified the user, with a PIN for instance.
+    pub verify_required: bool,
+}
+
+impl Default for KeyOptions {
+    /// Everything is permitted, as for a line without options.
+    fn default() -> Self {
+        KeyOptions {
+            cert_authority: false,
+            command: None,
+            environment: Vec::new(),
+            from: None,
+            expiry_time: None,
+            principals: None,
+            permit_open: Vec::new(),
+            permit_listen: Vec::new(),
+            port_forwarding: true,
+            agent_forwarding: true,
+            x11_forwarding: true,
+            pty: true,
+            user_rc: true,
+            no_touch_required: false,
+            verify_required: false,
+        }
+    }
+}
+
+impl KeyOptions {
+    fn parse(options: &str) -> Result<Self, Error> {
+        let mut parsed = KeyOptions::default();
+        let mut rest = options;
+        while !rest.is_empty() {
+            let end = rest.find(['=', ',']).unwrap_or(rest.len());
+            let (name, after) = rest.split_at(end);
+            let (value, after) = match after.strip_prefix('=') {
+                Some(quoted) => {
+                    let (value, after) = unquote(quoted)?;
+                    (Some(value), after)
+                }
+                None => (None, after),
+            };
+            rest = match after.strip_prefix(',') {
+                Some(rest) if !rest.is_empty() => rest,
+                None if after.is_empty() => after,
+                _ => return Err(invalid(format!("unexpected `{after}` in options"))),
+            };
+            parsed.set(name, value)?;
+        }
+        Ok(parsed)
+    }
+
+    fn set(&mut self, name: &str, value: Option<String>) -> Result<(), Error> {
+        let name = name.to_ascii_lowercase();
+        let enable = !name.starts_with("no-");
+        let flag = |value: Option<String>| match value {
+            None => Ok(()),
+            Some(_) => Err(invalid(format!("option `{name}` takes no value"))),
+        };
+        let required = |value: Option<String>| {
+            value.ok_or_else(|| invalid(format!("option `{name}` needs a value")))
+        };
+        match name.as_str() {
+            "cert-authority" => {
+                flag(value)?;
+                self.cert_authority = true
+            }
+            "restrict" => {
+                flag(value)?;
+                self.port_forwarding = false;
+                self.agent_forwarding = false;
+                self.x11_forwarding = false;
+                self.pty = false;
+                self.user_rc = false;
+            }
+            "port-forwarding" | "no-port-forwarding" => {
+                flag(value)?;
+                self.port_forwarding = enable
+            }
+            "agent-forwarding" | "no-agent-forwarding" => {
+                flag(value)?;
+                self.agent_forwarding = enable
+            }
+            "x11-forwarding" | "no-x11-forwarding" => {
+                flag(value)?;
+                self.x11_forwarding = enable
+            }
+            "pty" | "no-pty" => {
+                flag(value)?;
+                self.pty = enable
+            }
+            "user-rc" | "no-user-rc" => {
+                flag(value)?;
+                self.user_rc = enable
+            }
+            "no-touch-required" => {
+                flag(value)?;
+                self.no_touch_required = true
+            }
+            "verify-required" => {
+                flag(value)?;
+                self.verify_required = true
+            }
+            "command" => self.command = Some(required(value)?),
+            "from" => self.from = Some(required(value)?),
+            "permitopen" => self.permit_open.push(required(value)?),
+            "permitlisten" => self.permit_listen.push(required(value)?),
+            "principals" => {
+                self.principals = Some(required(value)?.split(',').map(String::from).collect())
+            }
+            "environment" => {
+                let value = required(value)?;
+                match value.split_once('=') {
+                    Some((var, val)) if !var.is_empty() => {
+                        self.environment.push((var.to_string(), val.to_string()))
+                    }
+                    _ => return Err(invalid(format!("invalid environment `{value}`"))),
+                }
+            }
+            "expiry-time" => {
+                let value = required(value)?;
+                self.expiry_time = Some(
+                    parse_time(&value)
+                        .ok_or_else(|| invalid(format!("invalid expiry time `{value}`")))?,
+                )
+            }
+            _ => return Err(invalid(format!("unknown option `{name}`"))),
+        }
+        Ok(())
+    }
+
+    /// Whether the client may open a channel of type `channel_type`,
+    /// such as `"session"` or `"direct-tcpip"`.
+    pub fn permits_channel(&self, channel_type: &str) -> bool {
+        match channel_type {
+            "direct-tcpip" | "direct-streamlocal@openssh.com" => self.port_forwarding,
+            _ => true,
+        }
+    }
+
+    /// Whether the client may send a channel or global request of type
+    /// `request`, such as `"pty-req"` or `"tcpip-forward"`.
+    pub fn permits_request(&self, request: &str) -> bool {
+        match request {
+            "pty-req" => self.pty,
+            "x11-req" => self.x11_forwarding,
+            "auth-agent-req@openssh.com" => self.agent_forwarding,
+            "tcpip-forward" | "streamlocal-forward@openssh.com" => self.port_forwarding,
+            _ => true,
+        }
+    }
+
+    /// Whether the client may forward connections to `host` on `port`.
+    pub fn permits_open(&self, host: &str, port: u32) -> bool {
+        self.port_forwarding
+            && (self.permit_open.is_empty()
+                || self
+                    .permit_open
+                    .iter()
+                    .any(|p| permits_host_port(p, Some(host), port)))
+    }
+
+    /// Whether the client may listen on `port` of `address` for remote
+    /// forwarding.
+    pub fn permits_listen(&self, address: &str, port: u32) -> bool {
+        self.port_forwarding
+            && (self.permit_listen.is_empty()
+                || self.permit_listen.iter().any(|p| {
+                    if p.contains(':') {
+                        permits_host_port(p, Some(address), port)
+                    } else {
+                        permits_host_port(p, None, port)
+                    }
+                }))
+    }
+
+    /// Whether the line applies to a client at `client` now.
+    fn applies(&self, client: Option<IpAddr>) -> bool {
+        if let Some(expiry) = self.expiry_time {
+            if SystemTime::now() >= expiry {
+                debug!("authorized key expired");
+                return false;
+            }
+        }
+        if let Some(ref from) = self.from {
+            if !client.map_or(false, |client| match_address(client, from)) {
+                debug!("client {:?} not allowed by from={:?}", client, from);
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn invalid(reason: String) -> Error {
+    Error::InvalidAuthorizedKey { reason }
+}
+
+/// Read a quoted value, in which `\"` is a quote, and return it with
+/// what follows.
+fn unquote(s: &str) -> Result<(String, &str), Error> {
+    let mut chars = s
+        .strip_prefix('"')
+        .ok_or_else(|| invalid("unquoted option value".to_string()))?
+        .char_indices();
+    let mut value = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, s.get(i + 2..).unwrap_or(""))),
+            '\\' if chars.clone().next().map(|(_, c)| c) == Some('"') => {
+                chars.next();
+                value.push('"')
+            }
+            c => value.push(c),
+        }
+    }
+    Err(invalid("unterminated quote".to_string()))
+}
+
+/// Parse `YYYYMMDD[HHMM[SS]]`, possibly followed by `Z`, as UTC.
+fn parse_time(s: &str) -> Option<SystemTime> {
+    let s = s.strip_suffix(['Z', 'z']).unwrap_or(s);
+    if !matches!(s.len(), 8 | 12 | 14) || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let field = |range: std::ops::Range<usize>| s.get(range).and_then(|f| f.parse::<i64>().ok());
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let hour = field(8..10).unwrap_or(0);
+    let minute = field(10..12).unwrap_or(0);
+    let second = field(12..14).unwrap_or(0);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    // Days since 1970-01-01 in the proleptic Gregorian calendar.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second.min(60);
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+/// Whether `client` matches the comma-separated `patterns`, which are
+/// wildcards or CIDR blocks, possibly negated.
+fn match_address(client: IpAddr, patterns: &str) -> bool {
+    let client = match client {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(client, IpAddr::V4),
+        v4 => v4,
+    };
+    let mut matched = false;
+    for pattern in patterns.split(',') {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let matches = match pattern.split_once('/') {
+            Some((addr, len)) => match (addr.parse::<IpAddr>(), len.parse::<u32>()) {
+                (Ok(addr), Ok(len)) => in_cidr(client, addr, len),
+                _ => false,
+            },
+            None => match_wildcard(pattern.as_bytes(), client.to_string().as_bytes()),
+        };
+        if matches && negated {
+            return false;
+        }
+        matched |= matches;
+    }
+    matched
+}
+
+fn in_cidr(client: IpAddr, network: IpAddr, len: u32) -> bool {
+    let (client, network, bits) = match (client, network) {
+        (IpAddr::V4(c), IpAddr::V4(n)) => (u128::from(u32::from(c)), u128::from(u32::from(n)), 32),
+        (IpAddr::V6(c), IpAddr::V6(n)) => (u128::from(c), u128::from(n), 128),
+        _ => return false,
+    };
+    if len > bits {
+        return false;
+    }
+    let shift = bits - len;
+    shift >= 128 || client >> shift == network >> shift
+}
+
+/// Whether `pattern`, `host:port` or `port`, allows `host` and `port`,
+/// `*` standing for any host or port.
+fn permits_host_port(pattern: &str, host: Option<&str>, port: u32) -> bool {
+    let (pattern_host, pattern_port) = match pattern.rsplit_once(':') {
+        Some((h, p)) => (Some(h.trim_start_matches('[').trim_end_matches(']')), p),
+        None => (None, pattern),
+    };
+    let host_ok = match (pattern_host, host) {
+        (None, _) | (Some("*"), _) => true,
+        (Some(p), Some(h)) => p.eq_ignore_ascii_case(h),
+        (Some(_), None) => false,
+    };
+    host_ok && (pattern_port == "*" || pattern_port.parse::<u32>().ok() == Some(port))
+}
+
+/// The options and key of a line of an `authorized_keys` file.
+#[derive(Debug, Clone)]
+pub struct AuthorizedKey {
+    /// Line number in the file, starting at 1.
+    pub line: usize,
+    pub options: KeyOptions,
+    /// The key, with the comment of the line.
+    pub key: PublicKey,
+}
+
+impl FromStr for AuthorizedKey {
+    type Err = Error;
+
+    /// Parse a line, which must not be empty or a comment.
+    fn from_str(line: &str) -> Result<Self, Error> {
+        let line = line.trim();
+        // Lines start with the key type, unless they have options.
+        let first = line.split_whitespace().next().unwrap_or("");
+        let is_key_type = matches!(
+            ssh_key::Algorithm::new(first),
+            Ok(algorithm) if !matches!(algorithm, ssh_key::Algorithm::Other(_))
+        );
+        let (options, key) = if is_key_type {
+            (KeyOptions::default(), line)
+        } else {
+            let end = options_end(line);
+            let (options, key) = line.split_at(end);
+            (KeyOptions::parse(options)?, key.trim_start())
+        };
+        Ok(AuthorizedKey {
+            line: 0,
+            options,
+            key: PublicKey::from_openssh(key)?,
+        })
+    }
+}
+
+/// The length of the options at the start of `line`, which end at the
+/// first whitespace outside of quotes.
+fn options_end(line: &str) -> usize {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' if !escaped => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => return i,
+            _ => {}
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    line.len()
+}
+
+/// The keys of an `authorized_keys` file.
+#[derive(Debug, Clone, Default)]
+pub struct AuthorizedKeys {
+    entries: Vec<AuthorizedKey>,
+}
+
+impl FromStr for AuthorizedKeys {
+    type Err = Error;
+
+    /// Parse a file. Like sshd, invalid lines are skipped.
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let mut entries = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            match trimmed.parse::<AuthorizedKey>() {
+                Ok(mut entry) => {
+                    entry.line = i + 1;
+                    entries.push(entry)
+                }
+                Err(e) => debug!("skipping authorized_keys line {}: {}", i + 1, e),
+            }
+        }
+        Ok(AuthorizedKeys { entries })
+    }
+}
+
+impl AuthorizedKeys {
+    /// Read the file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// The lines of the file that were understood.
+    pub fn entries(&self) -> &[AuthorizedKey] {
+        &self.entries
+    }
+
+    /// The options of the first line listing `key`, which must not be
+    /// expired, and must allow a client at `client`.
+    pub fn authorize_key(&self, key: &PublicKey, client: Option<IpAddr>) -> Option<KeyOptions> {
+        self.entries
+            .iter()
+            .filter(|entry| !entry.options.cert_authority)
+            .filter(|entry| entry.key.key_data() == key.key_data())
+            .find(|entry| entry.options.applies(client))
+            .map(|entry| entry.options.clone())
+    }
+
+    /// The options for `cert`, a user certificate valid for `user`,
+    /// or for one of the `principals` of the line, signed by a
+    /// `cert-authority` key. The options of the line are combined with
+    /// the critical options and extensions of the certificate.
+    pub fn authorize_certificate(
+        &self,
+        cert: &Certificate,
+        user: &str,
+        client: Option<IpAddr>,
+    ) -> Option<KeyOptions> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.options.cert_authority)
+            .filter(|entry| entry.key.key_data() == cert.signature_key())
+            .filter(|entry| entry.options.applies(client))
+            .find_map(|entry| {
+                let ca = std::slice::from_ref(&entry.key);
+                let valid = match entry.options.principals {
+                    Some(ref principals) => principals.iter().any(|p| {
+                        !cert.valid_principals().is_empty()
+                            && crate::certificate::validate_user_certificate(cert, ca, p).is_ok()
+                    }),
+                    None => crate::certificate::validate_user_certificate(cert, ca, user).is_ok(),
+                };
+                if !valid {
+                    debug!("certificate not valid for line {}", entry.line);
+                    return None;
+                }
+                with_certificate(entry.options.clone(), cert, client)
+            })
+    }
+}
+
+/// Restrict `options` to what `cert` permits.
+fn with_certificate(
+    mut options: KeyOptions,
+    cert: &Certificate,
+    client: Option<IpAddr>,
+) -> Option<KeyOptions> {
+    for (name, value) in cert.critical_options().iter() {
+        match name.as_str() {
+            "force-command" => match options.command {
+                Some(ref command) if command != value => {
+                    debug!("forced commands of certificate and key differ");
+                    return None;
+                }
+                _ => options.command = Some(value.clone()),
+            },
+            "source-address" => {
+                if !client.map_or(false, |client| match_address(client, value)) {
+                    debug!("client {:?} not in source-address of certificate", client);
+                    return None;
+                }
+            }
+            "verify-required" => options.verify_required = true,
+            _ => {
+                debug!("unknown critical option {:?}", name);
+                return None;
+            }
+        }
+    }
+    let extensions = cert.extensions();
+    options.port_forwarding &= extensions.contains_key("permit-port-forwarding");
+    options.agent_forwarding &= extensions.contains_key("permit-agent-forwarding");
+    options.x11_forwarding &= extensions.contains_key("permit-X11-forwarding");
+    options.pty &= extensions.contains_key("permit-pty");
+    options.user_rc &= extensions.contains_key("permit-user-rc");
+    options.no_touch_required |= extensions.contains_key("no-touch-required");
+    Some(options)
+}
+
+/// An `authorized_keys` file being edited. Lines are kept as they
+/// are, including comments and lines that can't be parsed, and the
+/// file is replaced at once when saved.
+///
+/// ```no_run
+/// use russh_keys::authorized_keys::AuthorizedKeysFile;
+///
+/// let key = russh_keys::load_public_key("id_ed25519.pub").unwrap();
+/// let mut file = AuthorizedKeysFile::open("/home/alice/.ssh/authorized_keys").unwrap();
+/// if file.add(&key, "restrict,pty").unwrap() {
+///     file.save().unwrap();
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct AuthorizedKeysFile {
+    path: PathBuf,
+    lines: Vec<String>,
+}
+
+impl AuthorizedKeysFile {
+    /// Read the file at `path`, or start an empty one if it doesn't
+    /// exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let lines = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents.lines().map(String::from).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(AuthorizedKeysFile { path, lines })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The keys of the file, as currently edited.
+    pub fn keys(&self) -> AuthorizedKeys {
+        // Parsing a file never fails, invalid lines are skipped.
+        self.to_string().parse().unwrap_or_default()
+    }
+
+    /// Whether `key` is listed, as a key or as a certificate authority.
+    pub fn contains(&self, key: &PublicKey) -> bool {
+        self.keys()
+            .entries()
+            .iter()
+            .any(|entry| entry.key.key_data() == key.key_data())
+    }
+
+    /// Add a line for `key`, with its comment, and with `options` such
+    /// as `"restrict,command=\"uptime\""`, which may be empty.
+    /// Returns `false`, and leaves the file unchanged, if the key is
+    /// already listed.
+    pub fn add(&mut self, key: &PublicKey, options: &str) -> Result<bool, Error> {
+        if self.contains(key) {
+            return Ok(false);
+        }
+        let options = options.trim();
+        let line = if options.is_empty() {
+            key.to_openssh()?
+        } else {
+            if options_end(options) != options.len() {
+                return Err(invalid(format!("unexpected whitespace in `{options}`")));
+            }
+            KeyOptions::parse(options)?;
+            format!("{} {}", options, key.to_openssh()?)
+        };
+        self.lines.push(line);
+        Ok(true)
+    }
+
+    /// Remove the lines listing `key`, and return how many there were.
+    pub fn remove(&mut self, key: &PublicKey) -> usize {
+        let before = self.lines.len();
+        self.lines.retain(|line| {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                return true;
+            }
+            match trimmed.parse::<AuthorizedKey>() {
+                Ok(entry) => entry.key.key_data() != key.key_data(),
+                Err(_) => true,
+            }
+        });
+        before - self.lines.len()
+    }
+
+    /// Write the file, by writing a temporary file in the same
+    /// directory and renaming it, so that sshd never sees a partial
+    /// file. On Unix, the file is only writable by its owner, and the
+    /// directory is created with mode 0700 if it doesn't exist, as
+    /// sshd requires with `StrictModes`.
+    pub fn save(&self) -> Result<(), Error> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if !dir.exists() {
+            let mut builder = std::fs::DirBuilder::new();
+            builder.recursive(true);
+            #[cfg(unix)]
+            std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+            builder.create(dir)?;
+        }
+        let mut name = self
+            .path
+            .file_name()
+            .map(|n| n.to_os_string())
+            .unwrap_or_default();
+        name.push(format!(".{}.tmp", std::process::id()));
+        let tmp = dir.join(name);
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let result = options.open(&tmp).and_then(|mut file| {
+            file.write_all(self.to_string().as_bytes())?;
+            file.sync_all()?;
+            std::fs::rename(&tmp, &self.path)
+        });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        Ok(result?)
+    }
+}
+
+impl std::fmt::Display for AuthorizedKeysFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use rand_core::OsRng;
+    use ssh_key::{Algorithm, PrivateKey};
+
+    use super::*;
+    use crate::certificate::CertificateExt;
+
+    fn key() -> PrivateKey {
+        PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let key = key();
+        let line = format!(
+            "command=\"echo \\\"hi\\\", there\",environment=\"LANG=C\",no-pty,permitopen=\"db:5432\",expiry-time=\"20300101\" {}",
+            key.public_key().to_openssh().unwrap()
+        );
+        let entry: AuthorizedKey = line.parse().unwrap();
+        assert_eq!(entry.options.command.as_deref(), Some("echo \"hi\", there"));
+        assert_eq!(
+            entry.options.environment,
+            [("LANG".to_string(), "C".to_string())]
+        );
+        assert!(!entry.options.pty);
+        assert!(entry.options.port_forwarding);
+        assert!(entry.options.permits_open("db", 5432));
+        assert!(!entry.options.permits_open("db", 22));
+        assert_eq!(
+            entry.options.expiry_time,
+            Some(UNIX_EPOCH + Duration::from_secs(1893456000))
+        );
+        assert_eq!(entry.key.key_data(), key.public_key().key_data());
+
+        assert!(format!("bogus {}", key.public_key().to_openssh().unwrap())
+            .parse::<AuthorizedKey>()
+            .is_err());
+        assert!(
+            format!("command=\"x {}", key.public_key().to_openssh().unwrap())
+                .parse::<AuthorizedKey>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_authorize_key() {
+        let (a, b, c) = (key(), key(), key());
+        let file = format!(
+            "# comment\n\n{}\nfrom=\"192.0.2.*,!192.0.2.7\" {}\nexpiry-time=\"19990101\" {}\nnot a key\n",
+            a.public_key().to_openssh().unwrap(),
+            b.public_key().to_openssh().unwrap(),
+            c.public_key().to_openssh().unwrap(),
+        );
+        let keys: AuthorizedKeys = file.parse().unwrap();
+        assert_eq!(keys.entries().len(), 3);
+        assert_eq!(keys.entries().get(1).unwrap().line, 4);
+
+        assert!(keys.authorize_key(a.public_key(), None).is_some());
+        let addr = |a: &str| Some(a.parse().unwrap());
+        assert!(keys
+            .authorize_key(b.public_key(), addr("192.0.2.1"))
+            .is_some());
+        assert!(keys
+            .authorize_key(b.public_key(), addr("::ffff:192.0.2.1"))
+            .is_some());
+        assert!(keys
+            .authorize_key(b.public_key(), addr("192.0.2.7"))
+            .is_none());
+        assert!(keys.authorize_key(b.public_key(), None).is_none());
+        assert!(keys.authorize_key(c.public_key(), None).is_none());
+        assert!(match_address("10.1.2.3".parse().unwrap(), "10.0.0.0/8"));
+        assert!(!match_address("11.1.2.3".parse().unwrap(), "10.0.0.0/8"));
+    }
+
+    #[test]
+    fn test_authorize_certificate() {
+        let (ca, user) = (key(), key());
+        let keys: AuthorizedKeys = format!(
+            "cert-authority,principals=\"admins,ops\",no-agent-forwarding {}",
+            ca.public_key().to_openssh().unwrap()
+        )
+        .parse()
+        .unwrap();
+
+        let cert = Certificate::builder(user.public_key())
+            .principal("ops")
+            .critical_option("force-command", "/usr/bin/uptime")
+            .sign(&ca)
+            .unwrap();
+        let options = keys.authorize_certificate(&cert, "alice", None).unwrap();
+        assert_eq!(options.command.as_deref(), Some("/usr/bin/uptime"));
+        assert!(!options.agent_forwarding);
+        assert!(options.pty);
+        // The key itself is not authorized.
+        assert!(keys.authorize_key(user.public_key(), None).is_none());
+
+        let cert = Certificate::builder(user.public_key())
+            .principal("alice")
+            .sign(&ca)
+            .unwrap();
+        assert!(keys.authorize_certificate(&cert, "alice", None).is_none());
+        let other = Certificate::builder(user.public_key())
+            .principal("ops")
+            .sign(&key())
+            .unwrap();
+        assert!(keys.authorize_certificate(&other, "alice", None).is_none());
+    }
+
+    #[test]
+    fn test_authorized_keys_file() {
+        let dir = tempdir::TempDir::new("russh").unwrap();
+        let path = dir.path().join(".ssh").join("authorized_keys");
+        let (a, b) = (key(), key());
+        let mut file = AuthorizedKeysFile::open(&path).unwrap();
+        assert!(file.add(a.public_key(), "").unwrap());
+        assert!(!file.add(a.public_key(), "no-pty").unwrap());
+        assert!(file.add(b.public_key(), "bogus").is_err());
+        assert!(file.add(b.public_key(), "no-pty, pty").is_err());
+        assert!(file
+            .add(b.public_key(), "command=\"echo hi\",no-pty")
+            .unwrap());
+        file.save().unwrap();
+
+        std::fs::write(
+            &path,
+            format!(
+                "# keys\n{}garbage\n",
+                std::fs::read_to_string(&path).unwrap()
+            ),
+        )
+        .unwrap();
+        let mut file = AuthorizedKeysFile::open(&path).unwrap();
+        let keys = file.keys();
+        assert_eq!(keys.entries().len(), 2);
+        let options = keys.authorize_key(b.public_key(), None).unwrap();
+        assert_eq!(options.command.as_deref(), Some("echo hi"));
+        assert!(!options.pty);
+
+        assert_eq!(file.remove(a.public_key()), 1);
+        assert!(!file.contains(a.public_key()));
+        file.save().unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.starts_with("# keys\ncommand="));
+        assert!(contents.ends_with("\ngarbage\n"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |p: &Path| std::fs::metadata(p).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&path), 0o600);
+            assert_eq!(mode(path.parent().unwrap()), 0o700);
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! Issuing OpenSSH certificates, and validating them against trusted
+//! certificate authorities.
+//!
+//! Certificates themselves are parsed with [`ssh_key::Certificate`],
+//! or loaded from a file with [`crate::load_openssh_certificate`].
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::RngCore;
+use ssh_key::certificate::{Builder, CertType};
+use ssh_key::{Certificate, HashAlg, PrivateKey, PublicKey};
+
+use crate::Error;
+
+/// The extensions `ssh-keygen -s` puts in user certificates.
+const DEFAULT_USER_EXTENSIONS: &[&str] = &[
+    "permit-X11-forwarding",
+    "permit-agent-forwarding",
+    "permit-port-forwarding",
+    "permit-pty",
+    "permit-user-rc",
+];
+
+pub trait CertificateExt {
+    /// Start building a user certificate for `key`.
+    fn builder(key: &PublicKey) -> CertificateBuilder;
+}
+
+impl CertificateExt for Certificate {
+    fn builder(key: &PublicKey) -> CertificateBuilder {
+        CertificateBuilder::new(key)
+    }
+}
+
+/// Builder for certificates signed by a CA key, the equivalent of
+/// `ssh-keygen -s`.
+///
+/// ```
+/// use russh_keys::certificate::CertificateExt;
+/// use russh_keys::ssh_key::{Algorithm, Certificate, PrivateKey};
+///
+/// let ca = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519).unwrap();
+/// let user = PrivateKey::random(&mut rand::thread_rng(), Algorithm::Ed25519).unwrap();
+/// let cert = Certificate::builder(user.public_key())
+///     .principal("alice")
+///     .key_id("alice@example.com")
+///     .valid_for(std::time::Duration::from_secs(3600))
+///     .sign(&ca)
+///     .unwrap();
+/// assert_eq!(cert.valid_principals(), ["alice"]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct CertificateBuilder {
+    key: PublicKey,
+    cert_type: CertType,
+    serial: u64,
+    key_id: String,
+    principals: Vec<String>,
+    valid_after: SystemTime,
+    valid_before: Option<SystemTime>,
+    critical_options: BTreeMap<String, String>,
+    extensions: BTreeMap<String, String>,
+    comment: String,
+}
+
+impl CertificateBuilder {
+    /// A user certificate for `key`, valid forever for all principals,
+    /// with the default extensions of `ssh-keygen`.
+    pub fn new(key: &PublicKey) -> Self {
+        CertificateBuilder {
+            key: key.clone(),
+            cert_type: CertType::User,
+            serial: 0,
+            key_id: String::new(),
+            principals: Vec::new(),
+            valid_after: UNIX_EPOCH,
+            valid_before: None,
+            critical_options: BTreeMap::new(),
+            extensions: DEFAULT_USER_EXTENSIONS
+                .iter()
+                .map(|e| (e.to_string(), String::new()))
+                .collect(),
+            comment: String::new(),
+        }
+    }
+
+    /// Make this a host certificate. Host certificates have no
+    /// extensions.
+    pub fn host(mut self) -> Self {
+        self.cert_type = CertType::Host;
+        self.extensions.clear();
+        self
+    }
+
+    pub fn serial(mut self, serial: u64) -> Self {
+        self.serial = serial;
+        self
+    }
+
+    /// The key identifier, logged by servers when the certificate is used.
+    pub fn key_id<S: Into<String>>(mut self, key_id: S) -> Self {
+        self.key_id = key_id.into();
+        self
+    }
+
+    /// Add a user or host name the certificate is valid for. Without
+    /// principals, the certificate is valid for all of them.
+    pub fn principal<S: Into<String>>(mut self, principal: S) -> Self {
+        self.principals.push(principal.into());
+        self
+    }
+
+    /// The validity interval. `None` means forever.
+    pub fn validity(mut self, valid_after: SystemTime, valid_before: Option<SystemTime>) -> Self {
+        self.valid_after = valid_after;
+        self.valid_before = valid_before;
+        self
+    }
+
+    /// Valid from now on, for `duration`.
+    pub fn valid_for(self, duration: Duration) -> Self {
+        let now = SystemTime::now();
+        self.validity(now, Some(now + duration))
+    }
+
+    /// Add a critical option, such as `force-command` or
+    /// `source-address`.
+    pub fn critical_option<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.critical_options.insert(name.into(), value.into());
+        self
+    }
+
+    /// Add an extension, such as `permit-pty` (with an empty value).
+    pub fn extension<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.extensions.insert(name.into(), value.into());
+        self
+    }
+
+    /// Remove all the extensions, including the default ones.
+    pub fn clear_extensions(mut self) -> Self {
+        self.extensions.clear();
+        self
+    }
+
+    pub fn comment<S: Into<String>>(mut self, comment: S) -> Self {
+        self.comment = comment.into();
+        self
+    }
+
+    /// Sign the certificate with the CA key.
+    pub fn sign(self, ca: &PrivateKey) -> Result<Certificate, Error> {
+        let mut nonce = [0; 32];
+        crate::key::safe_rng().fill_bytes(&mut nonce);
+        let valid_before = match self.valid_before {
+            Some(t) => unix_time(t)?,
+            // The largest time ssh-key accepts, for certificates valid forever.
+            None => i64::MAX as u64,
+        };
+        let mut builder = Builder::new(
+            nonce,
+            self.key.key_data().clone(),
+            unix_time(self.valid_after)?,
+            valid_before,
+        )?;
+        builder
+            .cert_type(self.cert_type)?
+            .serial(self.serial)?
+            .key_id(self.key_id)?
+            .comment(self.comment)?;
+        if self.principals.is_empty() {
+            builder.all_principals_valid()?;
+        }
+        for principal in self.principals {
+            builder.valid_principal(principal)?;
+        }
+        for (name, value) in self.critical_options {
+            builder.critical_option(name, value)?;
+        }
+        for (name, value) in self.extensions {
+            builder.extension(name, value)?;
+        }
+        Ok(builder.sign(ca)?)
+    }
+}
+
+fn unix_time(t: SystemTime) -> Result<u64, Error> {
+    Ok(t.duration_since(UNIX_EPOCH)
+        .map_err(|_| Error::InvalidParameters)?
+        .as_secs())
+}
+
+/// Check that `cert` is a valid user certificate for `user`, signed
+/// by one of `trusted_cas`, as sshd does for `TrustedUserCAKeys`.
+pub fn validate_user_certificate(
+    cert: &Certificate,
+    trusted_cas: &[PublicKey],
+    user: &str,
+) -> Result<(), Error> {
+    validate(cert, trusted_cas, CertType::User, user)
+}
+
+/// Check that `cert` is a valid host certificate for `host`, signed
+/// by one of `trusted_cas` (usually the `@cert-authority` lines of
+/// known_hosts).
+pub fn validate_host_certificate(
+    cert: &Certificate,
+    trusted_cas: &[PublicKey],
+    host: &str,
+) -> Result<(), Error> {
+    validate(cert, trusted_cas, CertType::Host, host)
+}
+
+fn validate(
+    cert: &Certificate,
+    trusted_cas: &[PublicKey],
+    cert_type: CertType,
+    principal: &str,
+) -> Result<(), Error> {
+    let fingerprints: Vec<_> = trusted_cas
+        .iter()
+        .map(|ca| ca.fingerprint(HashAlg::Sha256))
+        .collect();
+    let now = unix_time(SystemTime::now())?;
+    // Checks the CA, the signature and the validity interval.
+    cert.validate_at(now, &fingerprints)?;
+    if cert.cert_type() != cert_type {
+        return Err(Error::CertificateType);
+    }
+    // An empty list of principals means any principal.
+    let principals = cert.valid_principals();
+    if !principals.is_empty() && !principals.iter().any(|p| p == principal) {
+        return Err(Error::CertificatePrincipal {
+            principal: principal.to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use rand_core::OsRng;
+    use ssh_key::certificate::Builder;
+    use ssh_key::{Algorithm, PrivateKey};
+
+    use super::*;
+
+    fn certificate(ca: &PrivateKey, cert_type: CertType, principal: &str) -> Certificate {
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut builder = Builder::new(
+            [0; 16],
+            key.public_key().key_data().clone(),
+            now - 60,
+            now + 60,
+        )
+        .unwrap();
+        builder.cert_type(cert_type).unwrap();
+        builder.valid_principal(principal).unwrap();
+        builder.sign(ca).unwrap()
+    }
+
+    #[test]
+    fn test_validate_certificates() {
+        let ca = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let other_ca = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let trusted = [ca.public_key().clone()];
+
+        let user = certificate(&ca, CertType::User, "alice");
+        validate_user_certificate(&user, &trusted, "alice").unwrap();
+        assert!(matches!(
+            validate_user_certificate(&user, &trusted, "bob"),
+            Err(Error::CertificatePrincipal { .. })
+        ));
+        assert!(matches!(
+            validate_host_certificate(&user, &trusted, "alice"),
+            Err(Error::CertificateType)
+        ));
+        assert!(
+            validate_user_certificate(&user, &[other_ca.public_key().clone()], "alice").is_err()
+        );
+
+        let host = certificate(&ca, CertType::Host, "example.com");
+        validate_host_certificate(&host, &trusted, "example.com").unwrap();
+    }
+
+    #[test]
+    fn test_certificate_builder() {
+        let ca = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let cert = Certificate::builder(key.public_key())
+            .serial(42)
+            .key_id("alice@example.com")
+            .principal("alice")
+            .valid_for(Duration::from_secs(3600))
+            .critical_option("force-command", "/bin/true")
+            .sign(&ca)
+            .unwrap();
+        assert_eq!(cert.serial(), 42);
+        assert_eq!(cert.key_id(), "alice@example.com");
+        assert!(cert.extensions().contains_key("permit-pty"));
+        assert_eq!(
+            cert.critical_options()
+                .get("force-command")
+                .map(|s| s.as_str()),
+            Some("/bin/true")
+        );
+        validate_user_certificate(&cert, &[ca.public_key().clone()], "alice").unwrap();
+
+        let host = Certificate::builder(key.public_key())
+            .host()
+            .principal("example.com")
+            .sign(&ca)
+            .unwrap();
+        assert!(host.extensions().is_empty());
+        validate_host_certificate(&host, &[ca.public_key().clone()], "example.com").unwrap();
+    }
+}
@@ -0,0 +1,284 @@
+//! Fingerprints of public keys, formatted like `ssh-keygen -l` does,
+//! to let users compare keys.
+//!
+//! ```
+//! use russh_keys::fingerprint::PublicKeyFingerprint;
+//!
+//! let key = russh_keys::parse_public_key_base64(
+//!     "AAAAC3NzaC1lZDI1NTE5AAAAIJdD7y3aLq454yWBdwLWbieU1ebz9/cu7/QEXn9OIeZJ",
+//! )
+//! .unwrap();
+//! assert_eq!(
+//!     key.fingerprint_sha256(),
+//!     "SHA256:T7SvZ2cslqpPj6nKzitCBHHlpVF3r3MvLwmFL0fk0IE"
+//! );
+//! println!("{}", key.fingerprint_randomart());
+//! ```
+
+use ssh_key::public::{EcdsaPublicKey, KeyData};
+use ssh_key::{EcdsaCurve, HashAlg, Mpint, PublicKey};
+
+use crate::helpers::EncodedExt;
+
+/// Width and height of randomart pictures.
+const FIELD_X: usize = 17;
+const FIELD_Y: usize = 9;
+
+/// The characters of the cells visited 0, 1, 2... times, then of the
+/// start and end cells.
+const AUGMENTATION: &[u8] = b" .o+=*BOX@%&#/^SE";
+
+/// Fingerprints of a public key.
+pub trait PublicKeyFingerprint {
+    /// `SHA256:` followed by the unpadded base64 of the hash of the
+    /// key, the default format of OpenSSH.
+    fn fingerprint_sha256(&self) -> String;
+
+    /// `MD5:` followed by the hexadecimal bytes of the hash of the key
+    /// separated by colons, the format of older versions of OpenSSH.
+    fn fingerprint_md5(&self) -> String;
+
+    /// The "randomart" picture of the SHA256 fingerprint of the key,
+    /// exactly as `ssh-keygen -lv` draws it, without a final newline.
+    fn fingerprint_randomart(&self) -> String;
+
+    /// The randomart picture of the MD5 fingerprint of the key.
+    fn fingerprint_randomart_md5(&self) -> String;
+
+    /// The size of the key, its SHA256 fingerprint, comment and type,
+    /// as printed by `ssh-keygen -l`, such as `256 SHA256:... alice@host
+    /// (ED25519)`.
+    fn fingerprint_line(&self) -> String;
+}
+
+impl PublicKeyFingerprint for PublicKey {
+    fn fingerprint_sha256(&self) -> String {
+        self.fingerprint(HashAlg::Sha256).to_string()
+    }
+
+    fn fingerprint_md5(&self) -> String {
+        let digest = md5::compute(blob(self));
+        let hex: Vec<String> = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("MD5:{}", hex.join(":"))
+    }
+
+    fn fingerprint_randomart(&self) -> String {
+        let fingerprint = self.fingerprint(HashAlg::Sha256);
+        randomart(fingerprint.as_bytes(), &title(self), "SHA256")
+    }
+
+    fn fingerprint_randomart_md5(&self) -> String {
+        randomart(&md5::compute(blob(self)).0, &title(self), "MD5")
+    }
+
+    fn fingerprint_line(&self) -> String {
+        let comment = if self.comment().is_empty() {
+            "no comment"
+        } else {
+            self.comment()
+        };
+        match name_and_bits(self) {
+            Some((name, bits)) => format!(
+                "{} {} {} ({})",
+                bits,
+                self.fingerprint_sha256(),
+                comment,
+                name
+            ),
+            None => format!(
+                "{} {} ({})",
+                self.fingerprint_sha256(),
+                comment,
+                self.algorithm().as_str()
+            ),
+        }
+    }
+}
+
+fn blob(key: &PublicKey) -> Vec<u8> {
+    key.key_data().encoded().unwrap_or_default()
+}
+
+/// The title of randomart pictures, such as `[ED25519 256]`.
+fn title(key: &PublicKey) -> String {
+    let (name, bits) = match name_and_bits(key) {
+        Some(name_and_bits) => name_and_bits,
+        None => return format!("[{}]", key.algorithm().as_str()),
+    };
+    let title = format!("[{} {}]", name, bits);
+    if title.len() > FIELD_X {
+        format!("[{}]", name)
+    } else {
+        title
+    }
+}
+
+/// The type of the key as named by `ssh-keygen`, and its size.
+fn name_and_bits(key: &PublicKey) -> Option<(&'static str, usize)> {
+    Some(match key.key_data() {
+        KeyData::Rsa(rsa) => ("RSA", mpint_bits(&rsa.n)),
+        KeyData::Dsa(dsa) => ("DSA", mpint_bits(&dsa.p)),
+        KeyData::Ecdsa(ecdsa) => ("ECDSA", curve_bits(ecdsa)),
+        KeyData::Ed25519(_) => ("ED25519", 256),
+        KeyData::SkEcdsaSha2NistP256(_) => ("ECDSA-SK", 256),
+        KeyData::SkEd25519(_) => ("ED25519-SK", 256),
+        _ => return None,
+    })
+}
+
+fn mpint_bits(n: &Mpint) -> usize {
+    match n.as_positive_bytes() {
+        Some(bytes) => match bytes.iter().position(|b| *b != 0) {
+            Some(i) => {
+                (bytes.len() - i) * 8 - bytes.get(i).map_or(0, |b| b.leading_zeros() as usize)
+            }
+            None => 0,
+        },
+        None => 0,
+    }
+}
+
+fn curve_bits(key: &EcdsaPublicKey) -> usize {
+    match key.curve() {
+        EcdsaCurve::NistP256 => 256,
+        EcdsaCurve::NistP384 => 384,
+        EcdsaCurve::NistP521 => 521,
+    }
+}
+
+/// Draw the path of OpenSSH's "drunken bishop" walking according to
+/// the bits of `digest`, framed with `title` and `hash_name`.
+pub fn randomart(digest: &[u8], title: &str, hash_name: &str) -> String {
+    let max = AUGMENTATION.len() - 1;
+    let mut field = [[0usize; FIELD_Y]; FIELD_X];
+    let (mut x, mut y) = (FIELD_X / 2, FIELD_Y / 2);
+    for byte in digest {
+        let mut input = *byte;
+        for _ in 0..4 {
+            x = if input & 1 != 0 {
+                (x + 1).min(FIELD_X - 1)
+            } else {
+                x.saturating_sub(1)
+            };
+            y = if input & 2 != 0 {
+                (y + 1).min(FIELD_Y - 1)
+            } else {
+                y.saturating_sub(1)
+            };
+            if let Some(cell) = field.get_mut(x).and_then(|column| column.get_mut(y)) {
+                if *cell < max - 2 {
+                    *cell += 1
+                }
+            }
+            input >>= 2;
+        }
+    }
+    if let Some(start) = field
+        .get_mut(FIELD_X / 2)
+        .and_then(|column| column.get_mut(FIELD_Y / 2))
+    {
+        *start = max - 1
+    }
+    if let Some(end) = field.get_mut(x).and_then(|column| column.get_mut(y)) {
+        *end = max
+    }
+
+    let mut art = border(title);
+    art.push('\n');
+    for y in 0..FIELD_Y {
+        art.push('|');
+        for column in &field {
+            let visits = column.get(y).copied().unwrap_or(0).min(max);
+            art.push(AUGMENTATION.get(visits).map_or(' ', |c| *c as char));
+        }
+        art.push_str("|\n");
+    }
+    art.push_str(&border(&format!("[{}]", hash_name)));
+    art
+}
+
+/// A horizontal border with `label` in its middle.
+fn border(label: &str) -> String {
+    let label: String = label.chars().take(FIELD_X - 1).collect();
+    let left = (FIELD_X - label.len()) / 2;
+    let right = FIELD_X - left - label.len();
+    format!("+{}{}{}+", "-".repeat(left), label, "-".repeat(right))
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    const ED25519: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIJdD7y3aLq454yWBdwLWbieU1ebz9/cu7/QEXn9OIeZJ";
+
+    const RSA_1024: &str = "AAAAB3NzaC1yc2EAAAADAQABAAAAgQC+8XgRbsYIOXCNiXIX7RvleuBvYXLnglV6JDOLl/3Nl9KIHB0gNbkJvViveGQ15KwQyb4+7HHu6FrZP/GIarkSkm5g/J327bsvCVVHfP9FzJVtqdHTBt5OXdf7A9fShCvEn45tE+XTj9F0M7T1E87qnBycbJOXUmB3nAmCYvKI3Q==";
+
+    #[test]
+    fn test_fingerprints() {
+        let key = crate::parse_public_key_base64(ED25519).unwrap();
+        assert_eq!(
+            key.fingerprint_sha256(),
+            "SHA256:T7SvZ2cslqpPj6nKzitCBHHlpVF3r3MvLwmFL0fk0IE"
+        );
+        assert_eq!(
+            key.fingerprint_md5(),
+            "MD5:46:5d:0f:4b:7b:74:b6:5f:ce:80:b0:33:70:9e:33:96"
+        );
+        assert_eq!(
+            key.fingerprint_line(),
+            "256 SHA256:T7SvZ2cslqpPj6nKzitCBHHlpVF3r3MvLwmFL0fk0IE no comment (ED25519)"
+        );
+    }
+
+    // Compared with `ssh-keygen -lv` and `ssh-keygen -lv -E md5`.
+    #[test]
+    fn test_randomart() {
+        let key = crate::parse_public_key_base64(ED25519).unwrap();
+        assert_eq!(
+            key.fingerprint_randomart(),
+            "+--[ED25519 256]--+\n\
+             |....o.o . .o..   |\n\
+             |.. . + . .E.o    |\n\
+             | .  o     .=.    |\n\
+             |  .      ..o+    |\n\
+             | .      S =+.    |\n\
+             |  .      oo+o.   |\n\
+             | .        o+o+.  |\n\
+             |  . .o   . =Oo+  |\n\
+             |   . o*oo+*=.=.  |\n\
+             +----[SHA256]-----+"
+        );
+        assert_eq!(
+            key.fingerprint_randomart_md5(),
+            "+--[ED25519 256]--+\n\
+             |        . o + . o|\n\
+             |         = B B o.|\n\
+             |        . E + +..|\n\
+             |       . . = . +o|\n\
+             |        S       +|\n\
+             |       .         |\n\
+             |                 |\n\
+             |                 |\n\
+             |                 |\n\
+             +------[MD5]------+"
+        );
+
+        let key = crate::parse_public_key_base64(RSA_1024).unwrap();
+        assert_eq!(
+            key.fingerprint_randomart(),
+            "+---[RSA 1024]----+\n\
+             |        ......oo |\n\
+             |  .      ... .. o|\n\
+             |   o   ..o+    ..|\n\
+             |  . o . Eo o  .  |\n\
+             | . * + .So+. .   |\n\
+             |  + = .o.**..    |\n\
+             |   .  . ooOoo.   |\n\
+             |       ..+ *..o  |\n\
+             |      ...o+.oo . |\n\
+             +----[SHA256]-----+"
+        );
+    }
+}
@@ -1,4 +1,7 @@
-use ssh_key::PrivateKey;
+use std::io::Write;
+
+use rand::RngCore;
+use ssh_key::{Cipher, Kdf, LineEnding, PrivateKey};
 
 use crate::Error;
 
@@ -15,3 +18,27 @@ pub fn decode_openssh(secret: &[u8], password: Option<&str>) -> Result<PrivateKe
     }
     Ok(pk)
 }
+
+/// Encode a secret key in the OpenSSH format.
+pub fn encode_openssh_pem<W: Write>(key: &PrivateKey, mut w: W) -> Result<(), Error> {
+    w.write_all(key.to_openssh(LineEnding::LF)?.as_bytes())?;
+    Ok(())
+}
+
+/// Encode a secret key in the OpenSSH format, encrypted with
+/// aes256-ctr and a key derived from the password with `rounds` rounds
+/// of bcrypt-pbkdf, as done by `ssh-keygen` (which uses 16 rounds by
+/// default).
+pub fn encode_openssh_pem_encrypted<W: Write>(
+    key: &PrivateKey,
+    pass: &[u8],
+    rounds: u32,
+    w: W,
+) -> Result<(), Error> {
+    let mut rng = rand::thread_rng();
+    let mut salt = vec![0; 16];
+    rng.fill_bytes(&mut salt);
+    let kdf = Kdf::Bcrypt { salt, rounds };
+    let encrypted = key.encrypt_with(Cipher::Aes256Ctr, kdf, rng.next_u32(), pass)?;
+    encode_openssh_pem(&encrypted, w)
+}
@@ -0,0 +1,29 @@
+use std::convert::TryInto;
+
+use pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey};
+use ssh_key::private::{KeypairData, RsaKeypair};
+use ssh_key::PrivateKey;
+
+use crate::Error;
+
+/// Decode an RSA private key in the PKCS#1 DER format.
+pub fn decode_pkcs1_der(secret: &[u8]) -> Result<PrivateKey, Error> {
+    Ok(decode_rsa_keypair(secret)?.into())
+}
+
+pub(super) fn decode_rsa_keypair(secret: &[u8]) -> Result<RsaKeypair, Error> {
+    Ok(rsa::RsaPrivateKey::from_pkcs1_der(secret)?.try_into()?)
+}
+
+/// Encode an RSA private key in the PKCS#1 DER format.
+pub fn encode_pkcs1_der(key: &PrivateKey) -> Result<Vec<u8>, Error> {
+    let KeypairData::Rsa(ref pair) = key.key_data() else {
+        let kt = key.algorithm().as_str().to_string();
+        return Err(Error::UnsupportedKeyType {
+            key_type_raw: kt.as_bytes().into(),
+            key_type_string: kt,
+        });
+    };
+    let sk = super::pkcs8::rsa_private_key(pair)?;
+    Ok(sk.to_pkcs1_der()?.as_bytes().to_vec())
+}
@@ -11,26 +11,60 @@ pub fn decode_pkcs5(
     password: Option<&str>,
     enc: Encryption,
 ) -> Result<PrivateKey, Error> {
+    super::pkcs1::decode_rsa_keypair(&decrypt_pkcs5(secret, password, enc)?).map(Into::into)
+}
+
+/// Decipher the DER contents of a PEM block encrypted by OpenSSL (with a
+/// `DEK-Info` header) using the supplied password.
+pub fn decrypt_pkcs5(
+    secret: &[u8],
+    password: Option<&str>,
+    enc: Encryption,
+) -> Result<Vec<u8>, Error> {
     use aes::cipher::{BlockDecryptMut, KeyIvInit};
     use block_padding::Pkcs7;
 
     if let Some(pass) = password {
+        let mut dec = secret.to_vec();
         let sec = match enc {
             Encryption::Aes128Cbc(ref iv) => {
-                let mut c = md5::Context::new();
-                c.consume(pass.as_bytes());
-                c.consume(&iv[..8]);
-                let md5 = c.compute();
-
+                let key = evp_bytes_to_key::<16>(pass.as_bytes(), iv);
                 #[allow(clippy::unwrap_used)] // AES parameters are static
-                let c = cbc::Decryptor::<Aes128>::new_from_slices(&md5.0, &iv[..]).unwrap();
-                let mut dec = secret.to_vec();
+                let c = cbc::Decryptor::<Aes128>::new_from_slices(&key, &iv[..]).unwrap();
+                c.decrypt_padded_mut::<Pkcs7>(&mut dec)?.to_vec()
+            }
+            Encryption::Aes256Cbc(ref iv) => {
+                let key = evp_bytes_to_key::<32>(pass.as_bytes(), iv);
+                #[allow(clippy::unwrap_used)] // AES parameters are static
+                let c = cbc::Decryptor::<Aes256>::new_from_slices(&key, &iv[..]).unwrap();
                 c.decrypt_padded_mut::<Pkcs7>(&mut dec)?.to_vec()
             }
-            Encryption::Aes256Cbc(_) => unimplemented!(),
         };
-        super::decode_rsa_pkcs1_der(&sec).map(Into::into)
+        Ok(sec)
     } else {
         Err(Error::KeyIsEncrypted)
     }
 }
+
+/// OpenSSL's `EVP_BytesToKey` with MD5 and a single iteration, salted
+/// with the first 8 bytes of the IV.
+fn evp_bytes_to_key<const N: usize>(pass: &[u8], iv: &[u8; 16]) -> [u8; N] {
+    let mut key = [0; N];
+    let mut filled = 0;
+    let mut previous: Option<[u8; 16]> = None;
+    while filled < N {
+        let mut c = md5::Context::new();
+        if let Some(ref previous) = previous {
+            c.consume(previous);
+        }
+        c.consume(pass);
+        c.consume(&iv[..8]);
+        let digest = c.compute().0;
+        for (k, d) in key.iter_mut().skip(filled).zip(digest.iter()) {
+            *k = *d;
+        }
+        filled += digest.len();
+        previous = Some(digest);
+    }
+    key
+}
@@ -15,7 +15,10 @@ pub fn decode_pkcs8(
     password: Option<&[u8]>,
 ) -> Result<ssh_key::PrivateKey, Error> {
     let doc = SecretDocument::try_from(ciphertext)?;
-    let doc = if let Some(password) = password {
+    let doc = if doc.decode_msg::<pkcs8::EncryptedPrivateKeyInfo>().is_ok() {
+        let Some(password) = password else {
+            return Err(Error::KeyIsEncrypted);
+        };
         doc.decode_msg::<pkcs8::EncryptedPrivateKeyInfo>()?
             .decrypt(password)?
     } else {
@@ -47,39 +50,32 @@ fn pkcs8_pki_into_keypair_data(pki: PrivateKeyInfo<'_>) -> Result<KeypairData, E
             )?;
             Ok(KeypairData::Rsa(pk.try_into()?))
         }
-        sec1::ALGORITHM_OID => {
-            let sk = &sec1::EcPrivateKey::try_from(pki.private_key)?;
-            dbg!(sk.private_key);
-            dbg!(sk.public_key);
-            dbg!(sk.parameters);
-            dbg!(sk.parameters.and_then(|x| x.named_curve()));
-            Ok(KeypairData::Ecdsa(
-                match pki.algorithm.parameters_oid()? {
-                    NistP256::OID => {
-                        let sk = p256::SecretKey::try_from(pki)?;
-                        EcdsaKeypair::NistP256 {
-                            public: sk.public_key().into(),
-                            private: sk.into(),
-                        }
+        sec1::ALGORITHM_OID => Ok(KeypairData::Ecdsa(
+            match pki.algorithm.parameters_oid()? {
+                NistP256::OID => {
+                    let sk = p256::SecretKey::try_from(pki)?;
+                    EcdsaKeypair::NistP256 {
+                        public: sk.public_key().into(),
+                        private: sk.into(),
                     }
-                    NistP384::OID => {
-                        let sk = p384::SecretKey::try_from(pki)?;
-                        EcdsaKeypair::NistP384 {
-                            public: sk.public_key().into(),
-                            private: sk.into(),
-                        }
+                }
+                NistP384::OID => {
+                    let sk = p384::SecretKey::try_from(pki)?;
+                    EcdsaKeypair::NistP384 {
+                        public: sk.public_key().into(),
+                        private: sk.into(),
                     }
-                    NistP521::OID => {
-                        let sk = p521::SecretKey::try_from(pki)?;
-                        EcdsaKeypair::NistP521 {
-                            public: sk.public_key().into(),
-                            private: sk.into(),
-                        }
+                }
+                NistP521::OID => {
+                    let sk = p521::SecretKey::try_from(pki)?;
+                    EcdsaKeypair::NistP521 {
+                        public: sk.public_key().into(),
+                        private: sk.into(),
                     }
-                    oid => return Err(Error::UnknownAlgorithm(oid)),
-                },
-            ))
-        }
+                }
+                oid => return Err(Error::UnknownAlgorithm(oid)),
+            },
+        )),
         oid => Err(Error::UnknownAlgorithm(oid)),
     }
 }
@@ -115,19 +111,7 @@ pub fn encode_pkcs8(key: &ssh_key::PrivateKey) -> Result<Vec<u8>, Error> {
             let sk: ed25519_dalek::SigningKey = pair.try_into()?;
             sk.to_pkcs8_der()?
         }
-        ssh_key::private::KeypairData::Rsa(ref pair) => {
-            // TODO: Implementation in ssh-key 0.6.7 is broken (fixed in 0.7.0-pre)
-            let sk = rsa::RsaPrivateKey::from_components(
-                rsa::BigUint::try_from(&pair.public.n)?,
-                rsa::BigUint::try_from(&pair.public.e)?,
-                rsa::BigUint::try_from(&pair.private.d)?,
-                vec![
-                    rsa::BigUint::try_from(&pair.private.p)?,
-                    rsa::BigUint::try_from(&pair.private.q)?,
-                ],
-            )?;
-            sk.to_pkcs8_der()?
-        }
+        ssh_key::private::KeypairData::Rsa(ref pair) => rsa_private_key(pair)?.to_pkcs8_der()?,
         ssh_key::private::KeypairData::Ecdsa(ref pair) => match pair {
             EcdsaKeypair::NistP256 { private, .. } => {
                 let sk = p256::SecretKey::from_bytes(private.as_slice().into())?;
@@ -155,3 +139,18 @@ pub fn encode_pkcs8(key: &ssh_key::PrivateKey) -> Result<Vec<u8>, Error> {
     .to_vec();
     Ok(v)
 }
+
+pub(crate) fn rsa_private_key(
+    pair: &ssh_key::private::RsaKeypair,
+) -> Result<rsa::RsaPrivateKey, Error> {
+    // TODO: Implementation in ssh-key 0.6.7 is broken (fixed in 0.7.0-pre)
+    Ok(rsa::RsaPrivateKey::from_components(
+        rsa::BigUint::try_from(&pair.public.n)?,
+        rsa::BigUint::try_from(&pair.public.e)?,
+        rsa::BigUint::try_from(&pair.private.d)?,
+        vec![
+            rsa::BigUint::try_from(&pair.private.p)?,
+            rsa::BigUint::try_from(&pair.private.q)?,
+        ],
+    )?)
+}
@@ -0,0 +1,342 @@
+//! PuTTY's private key format (PPK), versions 2 and 3.
+
+use std::convert::TryFrom;
+use std::io::Write;
+
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use aes::Aes256;
+use block_padding::NoPadding;
+use data_encoding::{BASE64, HEXLOWER, HEXLOWER_PERMISSIVE};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use ssh_encoding::{Decode, Encode};
+use ssh_key::private::{
+    EcdsaKeypair, Ed25519Keypair, Ed25519PrivateKey, KeypairData, RsaKeypair, RsaPrivateKey,
+};
+use ssh_key::public::{EcdsaPublicKey, KeyData};
+use ssh_key::{Mpint, PrivateKey};
+
+use crate::helpers::EncodedExt;
+use crate::Error;
+
+pub(super) const PPK_HEADER: &str = "PuTTY-User-Key-File-";
+
+const MAC_KEY_V2: &[u8] = b"putty-private-key-file-mac-key";
+const AES256_CBC: &str = "aes256-cbc";
+const NONE: &str = "none";
+const LINE_LENGTH: usize = 64;
+
+/// Argon2 parameters of the PPK files written by [`encode_ppk`],
+/// PuTTY's defaults.
+const ARGON2_MEMORY: u32 = 8192;
+const ARGON2_PASSES: u32 = 13;
+const ARGON2_PARALLELISM: u32 = 1;
+
+struct Argon2Params {
+    algorithm: argon2::Algorithm,
+    memory: u32,
+    passes: u32,
+    parallelism: u32,
+    salt: Vec<u8>,
+}
+
+/// Decode a secret key in PuTTY's PPK format (version 2 or 3),
+/// deciphering it with the supplied password if needed.
+pub fn decode_ppk(secret: &str, password: Option<&str>) -> Result<PrivateKey, Error> {
+    let mut lines = secret.lines();
+
+    let (version, algorithm) = {
+        let line = lines.next().ok_or(Error::CouldNotReadKey)?;
+        let (version, algorithm) = line
+            .strip_prefix(PPK_HEADER)
+            .and_then(|l| l.split_once(": "))
+            .ok_or(Error::CouldNotReadKey)?;
+        let version = match version {
+            "2" => 2,
+            "3" => 3,
+            _ => return Err(Error::CouldNotReadKey),
+        };
+        (version, algorithm)
+    };
+    let encryption = header(&mut lines, "Encryption")?;
+    let comment = header(&mut lines, "Comment")?;
+    let public = blob(&mut lines, "Public-Lines")?;
+    let argon2 = if version == 3 && encryption != NONE {
+        Some(Argon2Params {
+            algorithm: match header(&mut lines, "Key-Derivation")? {
+                "Argon2d" => argon2::Algorithm::Argon2d,
+                "Argon2i" => argon2::Algorithm::Argon2i,
+                "Argon2id" => argon2::Algorithm::Argon2id,
+                _ => return Err(Error::CouldNotReadKey),
+            },
+            memory: number(header(&mut lines, "Argon2-Memory")?)?,
+            passes: number(header(&mut lines, "Argon2-Passes")?)?,
+            parallelism: number(header(&mut lines, "Argon2-Parallelism")?)?,
+            salt: HEXLOWER_PERMISSIVE.decode(header(&mut lines, "Argon2-Salt")?.as_bytes())?,
+        })
+    } else {
+        None
+    };
+    let mut private = blob(&mut lines, "Private-Lines")?;
+    let mac = HEXLOWER_PERMISSIVE.decode(header(&mut lines, "Private-MAC")?.as_bytes())?;
+
+    let mac_key = match encryption {
+        NONE => {
+            if version == 2 {
+                Sha1::digest(MAC_KEY_V2).to_vec()
+            } else {
+                Vec::new()
+            }
+        }
+        AES256_CBC => {
+            let password = password.ok_or(Error::KeyIsEncrypted)?.as_bytes();
+            let (key, iv, mac_key) = if let Some(ref argon2) = argon2 {
+                let mut out = [0; 80];
+                argon2_hash(argon2, password, &mut out)?;
+                let (key, rest) = out.split_at(32);
+                let (iv, mac_key) = rest.split_at(16);
+                (key.to_vec(), iv.to_vec(), mac_key.to_vec())
+            } else {
+                let mut key = Vec::with_capacity(40);
+                for i in 0u32..2 {
+                    let mut h = Sha1::new();
+                    h.update(i.to_be_bytes());
+                    h.update(password);
+                    key.extend_from_slice(&h.finalize());
+                }
+                key.truncate(32);
+                let mut h = Sha1::new();
+                h.update(MAC_KEY_V2);
+                h.update(password);
+                (key, vec![0; 16], h.finalize().to_vec())
+            };
+            cbc::Decryptor::<Aes256>::new_from_slices(&key, &iv)
+                .map_err(|_| Error::InvalidParameters)?
+                .decrypt_padded_mut::<NoPadding>(&mut private)?;
+            mac_key
+        }
+        _ => return Err(Error::CouldNotReadKey),
+    };
+
+    let mac_data = mac_data(algorithm, encryption, comment, &public, &private)?;
+    let valid = if version == 2 {
+        let mut hmac =
+            Hmac::<Sha1>::new_from_slice(&mac_key).map_err(|_| Error::InvalidParameters)?;
+        hmac.update(&mac_data);
+        hmac.verify_slice(&mac).is_ok()
+    } else {
+        let mut hmac =
+            Hmac::<Sha256>::new_from_slice(&mac_key).map_err(|_| Error::InvalidParameters)?;
+        hmac.update(&mac_data);
+        hmac.verify_slice(&mac).is_ok()
+    };
+    if !valid {
+        // Wrong password, or modified file.
+        return Err(Error::KeyIsCorrupt);
+    }
+
+    Ok(PrivateKey::new(keypair(&public, &private)?, comment)?)
+}
+
+/// Encode a secret key in PuTTY's PPK format version 3, encrypting it
+/// with the supplied password if any.
+pub fn encode_ppk<W: Write>(
+    key: &PrivateKey,
+    password: Option<&str>,
+    mut w: W,
+) -> Result<(), Error> {
+    let algorithm = key.algorithm();
+    let algorithm = algorithm.as_str();
+    let comment = key.comment();
+    let public = key.public_key().key_data().encoded()?;
+    let mut private = private_blob(key)?;
+
+    let (encryption, argon2, mac_key) = if let Some(password) = password {
+        let mut rng = rand::thread_rng();
+        let mut salt = vec![0; 16];
+        rng.fill_bytes(&mut salt);
+        // The private blob must be a whole number of blocks.
+        let mut padding = vec![0; (16 - private.len() % 16) % 16];
+        rng.fill_bytes(&mut padding);
+        private.extend_from_slice(&padding);
+
+        let argon2 = Argon2Params {
+            algorithm: argon2::Algorithm::Argon2id,
+            memory: ARGON2_MEMORY,
+            passes: ARGON2_PASSES,
+            parallelism: ARGON2_PARALLELISM,
+            salt,
+        };
+        let mut out = [0; 80];
+        argon2_hash(&argon2, password.as_bytes(), &mut out)?;
+        let (key, rest) = out.split_at(32);
+        let (iv, mac_key) = rest.split_at(16);
+        (
+            AES256_CBC,
+            Some((argon2, key.to_vec(), iv.to_vec())),
+            mac_key.to_vec(),
+        )
+    } else {
+        (NONE, None, Vec::new())
+    };
+
+    let mut hmac =
+        Hmac::<Sha256>::new_from_slice(&mac_key).map_err(|_| Error::InvalidParameters)?;
+    hmac.update(&mac_data(
+        algorithm, encryption, comment, &public, &private,
+    )?);
+    let mac = hmac.finalize().into_bytes();
+
+    writeln!(w, "{PPK_HEADER}3: {algorithm}")?;
+    writeln!(w, "Encryption: {encryption}")?;
+    writeln!(w, "Comment: {comment}")?;
+    write_blob(&mut w, "Public-Lines", &public)?;
+    if let Some((argon2, key, iv)) = argon2 {
+        let len = private.len();
+        cbc::Encryptor::<Aes256>::new_from_slices(&key, &iv)
+            .map_err(|_| Error::InvalidParameters)?
+            .encrypt_padded_mut::<NoPadding>(&mut private, len)?;
+        writeln!(w, "Key-Derivation: Argon2id")?;
+        writeln!(w, "Argon2-Memory: {}", argon2.memory)?;
+        writeln!(w, "Argon2-Passes: {}", argon2.passes)?;
+        writeln!(w, "Argon2-Parallelism: {}", argon2.parallelism)?;
+        writeln!(w, "Argon2-Salt: {}", HEXLOWER.encode(&argon2.salt))?;
+    }
+    write_blob(&mut w, "Private-Lines", &private)?;
+    writeln!(w, "Private-MAC: {}", HEXLOWER.encode(&mac))?;
+    Ok(())
+}
+
+fn header<'a, I: Iterator<Item = &'a str>>(lines: &mut I, name: &str) -> Result<&'a str, Error> {
+    lines
+        .next()
+        .and_then(|l| l.strip_prefix(name))
+        .and_then(|l| l.strip_prefix(':'))
+        .map(|l| l.strip_prefix(' ').unwrap_or(l))
+        .ok_or(Error::CouldNotReadKey)
+}
+
+fn number(n: &str) -> Result<u32, Error> {
+    n.parse().map_err(|_| Error::CouldNotReadKey)
+}
+
+fn blob<'a, I: Iterator<Item = &'a str>>(lines: &mut I, name: &str) -> Result<Vec<u8>, Error> {
+    let n: usize = header(lines, name)?
+        .parse()
+        .map_err(|_| Error::CouldNotReadKey)?;
+    let mut b64 = String::new();
+    for _ in 0..n {
+        b64.push_str(lines.next().ok_or(Error::CouldNotReadKey)?);
+    }
+    Ok(BASE64.decode(b64.as_bytes())?)
+}
+
+fn write_blob<W: Write>(w: &mut W, name: &str, blob: &[u8]) -> Result<(), Error> {
+    let b64 = BASE64.encode(blob);
+    let lines: Vec<&[u8]> = b64.as_bytes().chunks(LINE_LENGTH).collect();
+    writeln!(w, "{name}: {}", lines.len())?;
+    for line in lines {
+        w.write_all(line)?;
+        w.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+fn argon2_hash(params: &Argon2Params, password: &[u8], out: &mut [u8]) -> Result<(), Error> {
+    let argon2_params = argon2::Params::new(
+        params.memory,
+        params.passes,
+        params.parallelism,
+        Some(out.len()),
+    )
+    .map_err(|_| Error::InvalidParameters)?;
+    argon2::Argon2::new(params.algorithm, argon2::Version::V0x13, argon2_params)
+        .hash_password_into(password, &params.salt, out)
+        .map_err(|_| Error::InvalidParameters)
+}
+
+fn mac_data(
+    algorithm: &str,
+    encryption: &str,
+    comment: &str,
+    public: &[u8],
+    private: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    algorithm.encode(&mut data)?;
+    encryption.encode(&mut data)?;
+    comment.encode(&mut data)?;
+    public.encode(&mut data)?;
+    private.encode(&mut data)?;
+    Ok(data)
+}
+
+fn unsupported(algorithm: &str) -> Error {
+    Error::UnsupportedKeyType {
+        key_type_string: algorithm.to_string(),
+        key_type_raw: algorithm.as_bytes().to_vec(),
+    }
+}
+
+fn keypair(public: &[u8], mut private: &[u8]) -> Result<KeypairData, Error> {
+    let public = KeyData::decode(&mut &public[..])?;
+    Ok(match public {
+        KeyData::Ed25519(public) => {
+            let bytes = Vec::<u8>::decode(&mut private)?;
+            let bytes = <[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| Error::KeyIsCorrupt)?;
+            KeypairData::Ed25519(Ed25519Keypair {
+                public,
+                private: Ed25519PrivateKey::from_bytes(&bytes),
+            })
+        }
+        KeyData::Rsa(public) => {
+            let d = Mpint::decode(&mut private)?;
+            let p = Mpint::decode(&mut private)?;
+            let q = Mpint::decode(&mut private)?;
+            let iqmp = Mpint::decode(&mut private)?;
+            KeypairData::Rsa(RsaKeypair {
+                public,
+                private: RsaPrivateKey { d, iqmp, p, q },
+            })
+        }
+        KeyData::Ecdsa(public) => {
+            let scalar = Mpint::decode(&mut private)?;
+            let scalar = scalar.as_positive_bytes().ok_or(Error::KeyIsCorrupt)?;
+            KeypairData::Ecdsa(match public {
+                EcdsaPublicKey::NistP256(public) => EcdsaKeypair::NistP256 {
+                    public,
+                    private: p256::SecretKey::from_slice(scalar)?.into(),
+                },
+                EcdsaPublicKey::NistP384(public) => EcdsaKeypair::NistP384 {
+                    public,
+                    private: p384::SecretKey::from_slice(scalar)?.into(),
+                },
+                EcdsaPublicKey::NistP521(public) => EcdsaKeypair::NistP521 {
+                    public,
+                    private: p521::SecretKey::from_slice(scalar)?.into(),
+                },
+            })
+        }
+        other => return Err(unsupported(other.algorithm().as_str())),
+    })
+}
+
+fn private_blob(key: &PrivateKey) -> Result<Vec<u8>, Error> {
+    let mut blob = Vec::new();
+    match key.key_data() {
+        KeypairData::Ed25519(pair) => pair.private.to_bytes().as_slice().encode(&mut blob)?,
+        KeypairData::Rsa(pair) => {
+            pair.private.d.encode(&mut blob)?;
+            pair.private.p.encode(&mut blob)?;
+            pair.private.q.encode(&mut blob)?;
+            pair.private.iqmp.encode(&mut blob)?;
+        }
+        KeypairData::Ecdsa(pair) => {
+            Mpint::from_positive_bytes(pair.private_key_bytes())?.encode(&mut blob)?
+        }
+        _ => return Err(unsupported(key.algorithm().as_str())),
+    }
+    Ok(blob)
+}
@@ -0,0 +1,76 @@
+use std::convert::{TryFrom, TryInto};
+
+use p256::NistP256;
+use p384::NistP384;
+use p521::NistP521;
+use pkcs8::AssociatedOid;
+use sec1::der::Encode;
+use sec1::{EcParameters, EcPrivateKey};
+use ssh_key::private::{EcdsaKeypair, KeypairData};
+use ssh_key::PrivateKey;
+
+use crate::Error;
+
+/// Decode an ECDSA private key in the SEC1 DER format, as written by
+/// `openssl ec`.
+pub fn decode_sec1_der(secret: &[u8]) -> Result<PrivateKey, Error> {
+    let curve = EcPrivateKey::try_from(secret)?
+        .parameters
+        .and_then(|p| p.named_curve())
+        .ok_or(Error::CouldNotReadKey)?;
+    let pair = match curve {
+        NistP256::OID => {
+            let sk = p256::SecretKey::from_sec1_der(secret)?;
+            EcdsaKeypair::NistP256 {
+                public: sk.public_key().into(),
+                private: sk.into(),
+            }
+        }
+        NistP384::OID => {
+            let sk = p384::SecretKey::from_sec1_der(secret)?;
+            EcdsaKeypair::NistP384 {
+                public: sk.public_key().into(),
+                private: sk.into(),
+            }
+        }
+        NistP521::OID => {
+            let sk = p521::SecretKey::from_sec1_der(secret)?;
+            EcdsaKeypair::NistP521 {
+                public: sk.public_key().into(),
+                private: sk.into(),
+            }
+        }
+        oid => return Err(Error::UnknownAlgorithm(oid)),
+    };
+    Ok(KeypairData::Ecdsa(pair).try_into()?)
+}
+
+/// Encode an ECDSA private key in the SEC1 DER format.
+pub fn encode_sec1_der(key: &PrivateKey) -> Result<Vec<u8>, Error> {
+    let (der, curve) = match key.key_data() {
+        KeypairData::Ecdsa(EcdsaKeypair::NistP256 { private, .. }) => {
+            let der = p256::SecretKey::from_bytes(private.as_slice().into())?.to_sec1_der()?;
+            (der, NistP256::OID)
+        }
+        KeypairData::Ecdsa(EcdsaKeypair::NistP384 { private, .. }) => {
+            let der = p384::SecretKey::from_bytes(private.as_slice().into())?.to_sec1_der()?;
+            (der, NistP384::OID)
+        }
+        KeypairData::Ecdsa(EcdsaKeypair::NistP521 { private, .. }) => {
+            let der = p521::SecretKey::from_bytes(private.as_slice().into())?.to_sec1_der()?;
+            (der, NistP521::OID)
+        }
+        _ => {
+            let kt = key.algorithm().as_str().to_string();
+            return Err(Error::UnsupportedKeyType {
+                key_type_raw: kt.as_bytes().into(),
+                key_type_string: kt,
+            });
+        }
+    };
+    // The curve isn't written by `to_sec1_der`, but is needed to
+    // decode the key.
+    let mut ec = EcPrivateKey::try_from(der.as_slice())?;
+    ec.parameters = Some(EcParameters::NamedCurve(curve));
+    Ok(ec.to_der()?)
+}
@@ -0,0 +1,221 @@
+//! Generating key pairs and writing them to files, the equivalent of
+//! `ssh-keygen -t`.
+//!
+//! ```no_run
+//! use russh_keys::fingerprint::PublicKeyFingerprint;
+//! use russh_keys::generate::KeyGenerator;
+//!
+//! let key = KeyGenerator::ed25519()
+//!     .comment("root@server")
+//!     .generate()
+//!     .unwrap();
+//! russh_keys::generate::write_key_pair(&key, "/etc/ssh/ssh_host_ed25519_key", None).unwrap();
+//! println!("{}", key.public_key().fingerprint_line());
+//! ```
+
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+
+use ssh_key::private::{KeypairData, RsaKeypair};
+use ssh_key::{Algorithm, EcdsaCurve, LineEnding, PrivateKey};
+
+use crate::{encode_openssh_pem, encode_openssh_pem_encrypted, Error};
+
+/// The size of RSA keys generated by default, as with `ssh-keygen`.
+pub const DEFAULT_RSA_BITS: usize = 3072;
+
+/// The rounds of bcrypt-pbkdf used by `ssh-keygen` to derive the key
+/// protecting private keys from their passphrase.
+pub const DEFAULT_KDF_ROUNDS: u32 = 16;
+
+/// Builder for new private keys.
+#[derive(Debug, Clone)]
+pub struct KeyGenerator {
+    algorithm: Algorithm,
+    rsa_bits: usize,
+    comment: String,
+}
+
+impl KeyGenerator {
+    /// Generate keys of type `algorithm`. Only Ed25519, RSA and ECDSA
+    /// keys can be generated.
+    pub fn new(algorithm: Algorithm) -> Self {
+        KeyGenerator {
+            algorithm,
+            rsa_bits: DEFAULT_RSA_BITS,
+            comment: String::new(),
+        }
+    }
+
+    pub fn ed25519() -> Self {
+        Self::new(Algorithm::Ed25519)
+    }
+
+    /// RSA keys of `bits` bits, between 2048 and 16384.
+    pub fn rsa(bits: usize) -> Self {
+        Self::new(Algorithm::Rsa { hash: None }).rsa_bits(bits)
+    }
+
+    pub fn ecdsa(curve: EcdsaCurve) -> Self {
+        Self::new(Algorithm::Ecdsa { curve })
+    }
+
+    /// The size of RSA keys, ignored for other types.
+    pub fn rsa_bits(mut self, bits: usize) -> Self {
+        self.rsa_bits = bits;
+        self
+    }
+
+    /// The comment of the key, usually `user@host`, written after the
+    /// public key in `.pub` files.
+    pub fn comment<S: Into<String>>(mut self, comment: S) -> Self {
+        self.comment = comment.into();
+        self
+    }
+
+    /// Generate a key, using the random number generator of the
+    /// operating system.
+    pub fn generate(&self) -> Result<PrivateKey, Error> {
+        let mut rng = crate::key::safe_rng();
+        let mut key = match self.algorithm {
+            Algorithm::Ed25519 | Algorithm::Ecdsa { .. } => {
+                PrivateKey::random(&mut rng, self.algorithm.clone())?
+            }
+            Algorithm::Rsa { .. } => {
+                if !(2048..=16384).contains(&self.rsa_bits) {
+                    return Err(Error::InvalidParameters);
+                }
+                let keypair = RsaKeypair::random(&mut rng, self.rsa_bits)?;
+                PrivateKey::new(KeypairData::from(keypair), "")?
+            }
+            ref algorithm => {
+                return Err(Error::UnsupportedKeyType {
+                    key_type_string: algorithm.as_str().to_string(),
+                    key_type_raw: algorithm.as_str().as_bytes().to_vec(),
+                })
+            }
+        };
+        key.set_comment(self.comment.clone());
+        Ok(key)
+    }
+}
+
+/// The path of the public key of the private key at `path`, with
+/// `.pub` appended.
+pub fn public_key_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut path = path.as_ref().as_os_str().to_owned();
+    path.push(".pub");
+    PathBuf::from(path)
+}
+
+/// Write `key` to `path` in the OpenSSH format, encrypted if a
+/// `passphrase` is given, and its public key to `path` followed by
+/// `.pub`, as `ssh-keygen` does. Existing files are replaced. On Unix,
+/// the private key is only readable by its owner.
+pub fn write_key_pair<P: AsRef<Path>>(
+    key: &PrivateKey,
+    path: P,
+    passphrase: Option<&str>,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // The mode is only used when creating the file.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    match passphrase {
+        Some(passphrase) => {
+            encode_openssh_pem_encrypted(key, passphrase.as_bytes(), DEFAULT_KDF_ROUNDS, &mut file)?
+        }
+        None => encode_openssh_pem(key, &mut file)?,
+    }
+    file.sync_all()?;
+
+    crate::save_public_key(key.public_key(), public_key_path(path))
+}
+
+/// The private key in the OpenSSH format, encrypted if a `passphrase`
+/// is given.
+pub fn private_key_openssh(
+    key: &PrivateKey,
+    passphrase: Option<&str>,
+) -> Result<zeroize::Zeroizing<String>, Error> {
+    match passphrase {
+        Some(passphrase) => {
+            let mut pem = Vec::new();
+            encode_openssh_pem_encrypted(key, passphrase.as_bytes(), DEFAULT_KDF_ROUNDS, &mut pem)?;
+            Ok(zeroize::Zeroizing::new(String::from_utf8(pem)?))
+        }
+        None => Ok(key.to_openssh(LineEnding::LF)?),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+    use crate::fingerprint::PublicKeyFingerprint;
+
+    #[test]
+    fn test_generate_and_write() {
+        let dir = tempdir::TempDir::new("russh").unwrap();
+        for generator in [
+            KeyGenerator::ed25519(),
+            KeyGenerator::ecdsa(EcdsaCurve::NistP256),
+            KeyGenerator::ecdsa(EcdsaCurve::NistP384),
+            KeyGenerator::ecdsa(EcdsaCurve::NistP521),
+        ] {
+            let key = generator.comment("alice@example.com").generate().unwrap();
+            let path = dir.path().join("id");
+            write_key_pair(&key, &path, None).unwrap();
+            assert_eq!(crate::load_secret_key(&path, None).unwrap(), key);
+
+            let public = std::fs::read_to_string(public_key_path(&path)).unwrap();
+            assert!(public.ends_with(" alice@example.com\n"));
+            let public = crate::load_public_key(public_key_path(&path)).unwrap();
+            assert_eq!(public, *key.public_key());
+            assert!(key
+                .public_key()
+                .fingerprint_line()
+                .contains(" alice@example.com ("));
+        }
+    }
+
+    #[test]
+    fn test_write_encrypted() {
+        let dir = tempdir::TempDir::new("russh").unwrap();
+        let path = dir.path().join("id_ed25519");
+        let key = KeyGenerator::ed25519().generate().unwrap();
+        write_key_pair(&key, &path, Some("passphrase")).unwrap();
+        assert!(matches!(
+            crate::load_secret_key(&path, None),
+            Err(Error::KeyIsEncrypted)
+        ));
+        assert_eq!(
+            crate::load_secret_key(&path, Some("passphrase")).unwrap(),
+            key
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_rsa_bits() {
+        assert!(matches!(
+            KeyGenerator::rsa(1024).generate(),
+            Err(Error::InvalidParameters)
+        ));
+        assert!(KeyGenerator::new(Algorithm::Dsa).generate().is_err());
+    }
+}
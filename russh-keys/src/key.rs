@@ -12,9 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //
-use ssh_encoding::Decode;
+use std::convert::TryFrom;
+
+use async_trait::async_trait;
+use der::oid::AssociatedOid;
+use rsa::pkcs1v15;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use signature::{SignatureEncoding, Signer, Verifier};
+use ssh_encoding::{CheckedSum, Decode, Encode, Reader, Writer};
+use ssh_key::private::KeypairData;
 use ssh_key::public::KeyData;
-use ssh_key::{Algorithm, EcdsaCurve, HashAlg, PublicKey};
+use ssh_key::{Algorithm, EcdsaCurve, HashAlg, PrivateKey, PublicKey, Signature};
 
 use crate::Error;
 
@@ -41,6 +50,178 @@ pub fn parse_public_key(mut p: &[u8]) -> Result<PublicKey, Error> {
     Ok(ssh_key::public::KeyData::decode(&mut p)?.into())
 }
 
+/// A signature as sent on the wire. Unlike [`Signature`], this can
+/// also be an `ssh-rsa` (SHA-1) signature, which `ssh-key` refuses to
+/// represent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SshSignature {
+    /// An `ssh-rsa` signature: the PKCS#1 v1.5 signature of the SHA-1
+    /// hash of the data.
+    SshRsa(Vec<u8>),
+    /// Any other signature.
+    Other(Signature),
+}
+
+impl SshSignature {
+    /// Build a signature from its algorithm and raw bytes, as
+    /// [`Signature::new`] does.
+    pub fn new(algorithm: Algorithm, data: Vec<u8>) -> Result<Self, ssh_key::Error> {
+        match algorithm {
+            Algorithm::Rsa { hash: None } => Ok(SshSignature::SshRsa(data)),
+            algorithm => Ok(SshSignature::Other(Signature::new(algorithm, data)?)),
+        }
+    }
+
+    /// The algorithm of the signature.
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            SshSignature::SshRsa(_) => Algorithm::Rsa { hash: None },
+            SshSignature::Other(signature) => signature.algorithm(),
+        }
+    }
+
+    /// The raw signature.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            SshSignature::SshRsa(data) => data,
+            SshSignature::Other(signature) => signature.as_bytes(),
+        }
+    }
+}
+
+impl From<Signature> for SshSignature {
+    fn from(signature: Signature) -> Self {
+        SshSignature::Other(signature)
+    }
+}
+
+impl Encode for SshSignature {
+    fn encoded_len(&self) -> Result<usize, ssh_encoding::Error> {
+        match self {
+            SshSignature::SshRsa(data) => [
+                Algorithm::Rsa { hash: None }.encoded_len()?,
+                data.encoded_len()?,
+            ]
+            .checked_sum(),
+            SshSignature::Other(signature) => Encode::encoded_len(signature),
+        }
+    }
+
+    fn encode(&self, writer: &mut impl Writer) -> Result<(), ssh_encoding::Error> {
+        match self {
+            SshSignature::SshRsa(data) => {
+                Algorithm::Rsa { hash: None }.encode(writer)?;
+                data.encode(writer)
+            }
+            SshSignature::Other(signature) => Encode::encode(signature, writer),
+        }
+    }
+}
+
+impl Decode for SshSignature {
+    type Error = ssh_key::Error;
+
+    fn decode(reader: &mut impl Reader) -> Result<Self, ssh_key::Error> {
+        let algorithm = Algorithm::decode(reader)?;
+        let mut data = Vec::decode(reader)?;
+        // Like `Signature::decode`, keep the flags and counter of
+        // security key signatures after the signature itself.
+        if algorithm == Algorithm::SkEd25519 || algorithm == Algorithm::SkEcdsaSha2NistP256 {
+            let flags = u8::decode(reader)?;
+            let counter = u32::decode(reader)?;
+            data.push(flags);
+            data.extend(counter.to_be_bytes());
+        }
+        SshSignature::new(algorithm, data)
+    }
+}
+
+/// Sign `data` with `key`. For RSA keys, `hash_alg` selects between
+/// `ssh-rsa` (`None`, SHA-1), `rsa-sha2-256` and `rsa-sha2-512`; it is
+/// ignored for other keys.
+pub fn sign_with_hash_alg(
+    key: &PrivateKey,
+    hash_alg: Option<HashAlg>,
+    data: &[u8],
+) -> Result<SshSignature, Error> {
+    let KeypairData::Rsa(keypair) = key.key_data() else {
+        let signature: Signature =
+            Signer::try_sign(key, data).map_err(|_| Error::InvalidSignature)?;
+        return Ok(signature.into());
+    };
+    let private = crate::format::pkcs8::rsa_private_key(keypair)?;
+    let signature = match hash_alg {
+        None => return Ok(SshSignature::SshRsa(rsa_sign::<Sha1>(private, data)?)),
+        Some(HashAlg::Sha256) => rsa_sign::<Sha256>(private, data)?,
+        Some(HashAlg::Sha512) => rsa_sign::<Sha512>(private, data)?,
+        Some(hash_alg) => {
+            return Err(Error::UnknownSignatureType {
+                sig_type: hash_alg.as_str().into(),
+            })
+        }
+    };
+    Ok(Signature::new(Algorithm::Rsa { hash: hash_alg }, signature)?.into())
+}
+
+/// A private key that signs data, possibly without its secret ever
+/// being loaded in memory, for instance in an HSM, a cloud KMS or a
+/// TPM. [`PrivateKey`] implements this by signing in memory.
+#[async_trait]
+pub trait KeySigner: Send + Sync {
+    /// The public key of this signer.
+    fn public_key(&self) -> &PublicKey;
+
+    /// Sign `data`. `hash_alg` is the hash to use with RSA keys, as in
+    /// [`sign_with_hash_alg`], and is ignored by other keys.
+    async fn sign(&self, hash_alg: Option<HashAlg>, data: &[u8]) -> Result<SshSignature, Error>;
+}
+
+#[async_trait]
+impl KeySigner for PrivateKey {
+    fn public_key(&self) -> &PublicKey {
+        PrivateKey::public_key(self)
+    }
+
+    async fn sign(&self, hash_alg: Option<HashAlg>, data: &[u8]) -> Result<SshSignature, Error> {
+        sign_with_hash_alg(self, hash_alg, data)
+    }
+}
+
+fn rsa_sign<D: Digest + AssociatedOid>(
+    key: rsa::RsaPrivateKey,
+    data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let signature = pkcs1v15::SigningKey::<D>::new(key)
+        .try_sign(data)
+        .map_err(|_| Error::InvalidSignature)?;
+    Ok(signature.to_vec())
+}
+
+/// Check `signature` of `data` by `key`. Unlike the [`Verifier`]
+/// implementation of [`PublicKey`], this also accepts `ssh-rsa`
+/// (SHA-1) signatures, which callers should only allow if they
+/// negotiated that algorithm.
+pub fn verify_signature(
+    key: &PublicKey,
+    data: &[u8],
+    signature: &SshSignature,
+) -> Result<(), Error> {
+    match (key.key_data(), signature) {
+        (KeyData::Rsa(public), SshSignature::SshRsa(signature)) => {
+            let public = rsa::RsaPublicKey::try_from(public)?;
+            let signature = pkcs1v15::Signature::try_from(&signature[..])
+                .map_err(|_| Error::InvalidSignature)?;
+            pkcs1v15::VerifyingKey::<Sha1>::new(public)
+                .verify(data, &signature)
+                .map_err(|_| Error::InvalidSignature)
+        }
+        (_, SshSignature::SshRsa(_)) => Err(Error::InvalidSignature),
+        (_, SshSignature::Other(signature)) => {
+            Verifier::verify(key, data, signature).map_err(|_| Error::InvalidSignature)
+        }
+    }
+}
+
 /// Obtain a cryptographic-safe random number generator.
 pub fn safe_rng() -> impl rand::CryptoRng + rand::RngCore {
     rand::thread_rng()
@@ -106,7 +106,7 @@ pub fn known_host_keys_path<P: AsRef<Path>>(
             let key = s.next();
             if let (Some(h), Some(k)) = (hosts, key) {
                 debug!("{:?} {:?}", h, k);
-                if match_hostname(&host_port, h) {
+                if match_hostname_exact(&host_port, h) {
                     matches.push((line, parse_public_key_base64(k)?));
                 }
             }
@@ -117,26 +117,414 @@ pub fn known_host_keys_path<P: AsRef<Path>>(
     Ok(matches)
 }
 
+/// Whether the hashed host pattern `entry` (`|1|salt|hash`) is `host`.
+fn match_hashed(host: &str, entry: &str) -> bool {
+    let mut parts = entry.split('|').skip(2);
+    let Some(Ok(salt)) = parts.next().map(|p| BASE64_MIME.decode(p.as_bytes())) else {
+        return false;
+    };
+    let Some(Ok(hash)) = parts.next().map(|p| BASE64_MIME.decode(p.as_bytes())) else {
+        return false;
+    };
+    match Hmac::<Sha1>::new_from_slice(&salt) {
+        Ok(hmac) => hmac.chain_update(host).verify_slice(&hash).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Whether `host` is one of the comma-separated hosts of `pattern`,
+/// compared exactly, as done by [`check_known_hosts`] and
+/// [`known_host_keys`].
+fn match_hostname_exact(host: &str, pattern: &str) -> bool {
+    pattern.split(',').any(|entry| {
+        if entry.starts_with("|1|") {
+            match_hashed(host, entry)
+        } else {
+            host == entry
+        }
+    })
+}
+
+/// Whether `host` matches the comma-separated patterns of `pattern`,
+/// with wildcards and negations as in OpenSSH.
 fn match_hostname(host: &str, pattern: &str) -> bool {
+    let mut matched = false;
     for entry in pattern.split(',') {
         if entry.starts_with("|1|") {
-            let mut parts = entry.split('|').skip(2);
-            let Some(Ok(salt)) = parts.next().map(|p| BASE64_MIME.decode(p.as_bytes())) else {
-                continue;
-            };
-            let Some(Ok(hash)) = parts.next().map(|p| BASE64_MIME.decode(p.as_bytes())) else {
-                continue;
-            };
-            if let Ok(hmac) = Hmac::<Sha1>::new_from_slice(&salt) {
-                if hmac.chain_update(host).verify_slice(&hash).is_ok() {
-                    return true;
+            if match_hashed(host, entry) {
+                matched = true;
+            }
+        } else if let Some(negated) = entry.strip_prefix('!') {
+            // A negated pattern matching excludes the host, whatever
+            // the other patterns say.
+            if match_wildcard(negated.as_bytes(), host.as_bytes()) {
+                return false;
+            }
+        } else if match_wildcard(entry.as_bytes(), host.as_bytes()) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+/// Match `text` against a pattern where `*` matches any sequence and
+/// `?` any single character, ignoring ASCII case.
+pub(crate) fn match_wildcard(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The position of the last `*` in the pattern, and of the text
+    // it was matched up to.
+    let mut star = None;
+    while t < text.len() {
+        match (pattern.get(p), text.get(t)) {
+            (Some(b'*'), _) => {
+                star = Some((p, t));
+                p += 1;
+            }
+            (Some(a), Some(b)) if *a == b'?' || a.eq_ignore_ascii_case(b) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the last `*` match one more character.
+                Some((sp, st)) => {
+                    star = Some((sp, st + 1));
+                    p = sp + 1;
+                    t = st + 1;
                 }
+                None => return false,
+            },
+        }
+    }
+    pattern
+        .get(p..)
+        .map_or(false, |rest| rest.iter().all(|c| *c == b'*'))
+}
+
+fn host_port(host: &str, port: u16) -> Cow<'_, str> {
+    if port == 22 {
+        Cow::Borrowed(host)
+    } else {
+        Cow::Owned(format!("[{}]:{}", host, port))
+    }
+}
+
+/// A marker at the start of a known_hosts line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Marker {
+    /// `@cert-authority`: the key is a CA trusted to sign host
+    /// certificates for the hosts of the line.
+    CertAuthority,
+    /// `@revoked`: the key must never be accepted.
+    Revoked,
+}
+
+/// A line of a known_hosts file recording a key.
+#[derive(Debug, Clone)]
+pub struct KnownHostEntry {
+    /// Line number in the file, starting at 1.
+    pub line: usize,
+    pub marker: Option<Marker>,
+    /// The comma-separated host patterns, possibly hashed.
+    pub hosts: String,
+    pub key: ssh_key::PublicKey,
+    pub comment: Option<String>,
+}
+
+impl KnownHostEntry {
+    /// Whether this line applies to `host` on `port`.
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        match_hostname(&host_port(host, port), &self.hosts)
+    }
+
+    fn parse(line: usize, l: &str) -> Option<Self> {
+        let mut fields = l.split_whitespace();
+        let mut hosts = fields.next()?;
+        let marker = match hosts {
+            "@cert-authority" => Some(Marker::CertAuthority),
+            "@revoked" => Some(Marker::Revoked),
+            _ => None,
+        };
+        if marker.is_some() {
+            hosts = fields.next()?;
+        }
+        let _algorithm = fields.next()?;
+        let key = crate::parse_public_key_base64(fields.next()?).ok()?;
+        let comment = fields.collect::<Vec<_>>().join(" ");
+        Some(KnownHostEntry {
+            line,
+            marker,
+            hosts: hosts.to_string(),
+            key,
+            comment: if comment.is_empty() {
+                None
+            } else {
+                Some(comment)
+            },
+        })
+    }
+
+    fn to_line(&self) -> Result<String, Error> {
+        let mut line = String::new();
+        match self.marker {
+            Some(Marker::CertAuthority) => line.push_str("@cert-authority "),
+            Some(Marker::Revoked) => line.push_str("@revoked "),
+            None => {}
+        }
+        line.push_str(&self.hosts);
+        line.push(' ');
+        line.push_str(&self.key.to_openssh()?);
+        if let Some(ref comment) = self.comment {
+            line.push(' ');
+            line.push_str(comment);
+        }
+        Ok(line)
+    }
+}
+
+/// The result of looking up a host key in a [`KnownHosts`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KnownHostStatus {
+    /// The key is recorded for this host, at this line.
+    Known { line: usize },
+    /// The key is marked as `@revoked`.
+    Revoked { line: usize },
+    /// Another key of the same algorithm is recorded for this host.
+    Changed { line: usize },
+    /// There is no key of this algorithm for this host.
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+enum Line {
+    Entry(KnownHostEntry),
+    /// Comments, blank and unparseable lines, kept when rewriting.
+    Other(String),
+}
+
+/// An in-memory known_hosts file, which can be queried, modified and
+/// written back, keeping comments and the lines it doesn't understand.
+#[derive(Debug, Clone, Default)]
+pub struct KnownHosts {
+    path: Option<PathBuf>,
+    lines: Vec<Line>,
+}
+
+impl KnownHosts {
+    /// Load the user's known_hosts file. A missing file is empty.
+    pub fn load_default() -> Result<Self, Error> {
+        Self::load(known_hosts_path()?)
+    }
+
+    /// Load a known_hosts file. A missing file is empty, and will be
+    /// created by [`KnownHosts::save`].
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut known_hosts = match std::fs::read_to_string(path.as_ref()) {
+            Ok(s) => Self::parse(&s),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e.into()),
+        };
+        known_hosts.path = Some(path.as_ref().to_path_buf());
+        Ok(known_hosts)
+    }
+
+    /// Parse the contents of a known_hosts file.
+    pub fn parse(s: &str) -> Self {
+        let lines = s
+            .lines()
+            .enumerate()
+            .map(|(i, l)| {
+                let trimmed = l.trim();
+                if trimmed.is_empty() || trimmed.starts_with('#') {
+                    return Line::Other(l.to_string());
+                }
+                match KnownHostEntry::parse(i + 1, trimmed) {
+                    Some(entry) => Line::Entry(entry),
+                    None => {
+                        debug!("Unparseable known_hosts line {}: {:?}", i + 1, l);
+                        Line::Other(l.to_string())
+                    }
+                }
+            })
+            .collect();
+        KnownHosts { path: None, lines }
+    }
+
+    /// The file this was loaded from.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// All the entries of this file.
+    pub fn entries(&self) -> impl Iterator<Item = &KnownHostEntry> {
+        self.lines.iter().filter_map(|l| match l {
+            Line::Entry(e) => Some(e),
+            Line::Other(_) => None,
+        })
+    }
+
+    /// The keys recorded for a host (without marker).
+    pub fn host_keys(&self, host: &str, port: u16) -> Vec<&KnownHostEntry> {
+        self.entries()
+            .filter(|e| e.marker.is_none() && e.matches(host, port))
+            .collect()
+    }
+
+    /// The certificate authorities trusted for a host.
+    pub fn cert_authorities(&self, host: &str, port: u16) -> Vec<&ssh_key::PublicKey> {
+        self.entries()
+            .filter(|e| e.marker == Some(Marker::CertAuthority) && e.matches(host, port))
+            .map(|e| &e.key)
+            .collect()
+    }
+
+    /// Check a host certificate against the `@cert-authority` lines
+    /// matching the host.
+    pub fn check_host_certificate(
+        &self,
+        host: &str,
+        port: u16,
+        cert: &ssh_key::Certificate,
+    ) -> Result<(), Error> {
+        let cas: Vec<ssh_key::PublicKey> = self
+            .cert_authorities(host, port)
+            .into_iter()
+            .cloned()
+            .collect();
+        crate::certificate::validate_host_certificate(cert, &cas, host)
+    }
+
+    /// Look up a host's key. Revocations take precedence over
+    /// everything else.
+    pub fn check(&self, host: &str, port: u16, key: &ssh_key::PublicKey) -> KnownHostStatus {
+        if let Some(e) = self.entries().find(|e| {
+            e.marker == Some(Marker::Revoked)
+                && e.matches(host, port)
+                && e.key.key_data() == key.key_data()
+        }) {
+            return KnownHostStatus::Revoked { line: e.line };
+        }
+        let mut status = KnownHostStatus::Unknown;
+        for e in self.host_keys(host, port) {
+            if e.key.key_data() == key.key_data() {
+                return KnownHostStatus::Known { line: e.line };
+            } else if e.key.algorithm() == key.algorithm() && status == KnownHostStatus::Unknown {
+                status = KnownHostStatus::Changed { line: e.line }
             }
-        } else if host == entry {
-            return true;
         }
+        status
+    }
+
+    /// Add a key for a host. If `hash` is true, the host name is
+    /// hashed like with OpenSSH's `HashKnownHosts`.
+    pub fn add(
+        &mut self,
+        host: &str,
+        port: u16,
+        key: &ssh_key::PublicKey,
+        hash: bool,
+    ) -> Result<(), Error> {
+        let host_port = host_port(host, port);
+        let hosts = if hash {
+            hash_hostname(&host_port)?
+        } else {
+            host_port.into_owned()
+        };
+        let line = self.lines.len() + 1;
+        self.lines.push(Line::Entry(KnownHostEntry {
+            line,
+            marker: None,
+            hosts,
+            key: ssh_key::PublicKey::from(key.key_data().clone()),
+            comment: None,
+        }));
+        Ok(())
+    }
+
+    /// Remove the keys of a host, returning the number of lines
+    /// removed. Lines listing several hosts are removed as well.
+    /// Marked lines are left untouched.
+    pub fn remove(&mut self, host: &str, port: u16) -> usize {
+        let before = self.lines.len();
+        self.lines.retain(|l| match l {
+            Line::Entry(e) => e.marker.is_some() || !e.matches(host, port),
+            Line::Other(_) => true,
+        });
+        self.renumber();
+        before - self.lines.len()
     }
-    false
+
+    /// Remove a single key of a host, returning whether it was found.
+    pub fn remove_key(&mut self, host: &str, port: u16, key: &ssh_key::PublicKey) -> bool {
+        let before = self.lines.len();
+        self.lines.retain(|l| match l {
+            Line::Entry(e) => {
+                e.marker.is_some() || !e.matches(host, port) || e.key.key_data() != key.key_data()
+            }
+            Line::Other(_) => true,
+        });
+        self.renumber();
+        before != self.lines.len()
+    }
+
+    fn renumber(&mut self) {
+        for (i, l) in self.lines.iter_mut().enumerate() {
+            if let Line::Entry(e) = l {
+                e.line = i + 1
+            }
+        }
+    }
+
+    /// Write the file back to where it was loaded from.
+    pub fn save(&self) -> Result<(), Error> {
+        let path = self.path.as_ref().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "known_hosts has no path")
+        })?;
+        self.save_to(path)
+    }
+
+    /// Write the file to `path`, replacing it atomically.
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        {
+            let mut file = std::io::BufWriter::new(File::create(&tmp)?);
+            file.write_all(self.contents()?.as_bytes())?;
+            file.flush()?;
+        }
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// The contents of the file.
+    pub fn contents(&self) -> Result<String, Error> {
+        let mut s = String::new();
+        for l in &self.lines {
+            match l {
+                Line::Entry(e) => s.push_str(&e.to_line()?),
+                Line::Other(o) => s.push_str(o),
+            }
+            s.push('\n');
+        }
+        Ok(s)
+    }
+}
+
+/// Hash a host name like OpenSSH's `HashKnownHosts`.
+pub fn hash_hostname(host: &str) -> Result<String, Error> {
+    use rand::RngCore;
+    let mut salt = [0; 20];
+    crate::key::safe_rng().fill_bytes(&mut salt);
+    let hmac = Hmac::<Sha1>::new_from_slice(&salt).map_err(|_| Error::InvalidParameters)?;
+    let hash = hmac.chain_update(host).finalize().into_bytes();
+    Ok(format!(
+        "|1|{}|{}",
+        data_encoding::BASE64.encode(&salt),
+        data_encoding::BASE64.encode(&hash)
+    ))
 }
 
 /// Record a host's public key into the user's known_hosts file.
@@ -186,6 +574,8 @@ pub fn learn_known_hosts_path<P: AsRef<Path>>(
 
 #[cfg(test)]
 mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
     use std::fs::File;
 
     use super::*;
@@ -240,4 +630,121 @@ mod test {
         .unwrap();
         assert!(check_known_hosts_path(host, port, &hostkey, &path).is_err());
     }
+
+    #[test]
+    fn test_check_known_hosts_exact() {
+        env_logger::try_init().unwrap_or(());
+        let dir = tempdir::TempDir::new("russh").unwrap();
+        let path = dir.path().join("known_hosts");
+        std::fs::write(
+            &path,
+            "*.pijul.org,!evil.pijul.org,Nest.pijul.org ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA6rWI3G1sz07DnfFlrouTcysQlj2P+jpNSOEWD9OJ3X\n",
+        )
+        .unwrap();
+        let hostkey = parse_public_key_base64(
+            "AAAAC3NzaC1lZDI1NTE5AAAAIA6rWI3G1sz07DnfFlrouTcysQlj2P+jpNSOEWD9OJ3X",
+        )
+        .unwrap();
+        // Patterns are compared as plain host names, case-sensitively.
+        assert!(!check_known_hosts_path("a.pijul.org", 22, &hostkey, &path).unwrap());
+        assert!(!check_known_hosts_path("nest.pijul.org", 22, &hostkey, &path).unwrap());
+        assert!(check_known_hosts_path("Nest.pijul.org", 22, &hostkey, &path).unwrap());
+        assert!(check_known_hosts_path("*.pijul.org", 22, &hostkey, &path).unwrap());
+        assert!(check_known_hosts_path("!evil.pijul.org", 22, &hostkey, &path).unwrap());
+        assert!(known_host_keys_path("evil.pijul.org", 22, &path)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_match_wildcard() {
+        assert!(match_wildcard(b"*.pijul.org", b"nest.pijul.org"));
+        assert!(match_wildcard(b"*.PIJUL.org", b"nest.pijul.org"));
+        assert!(match_wildcard(b"nest.pijul.or?", b"nest.pijul.org"));
+        assert!(match_wildcard(b"*", b""));
+        assert!(match_wildcard(b"a*b*c", b"aXbYbZc"));
+        assert!(!match_wildcard(b"*.pijul.org", b"pijul.org"));
+        assert!(!match_wildcard(b"?", b""));
+        assert!(!match_wildcard(b"a*b", b"aXbY"));
+        // Many stars don't make matching exponential.
+        let pattern = "*a".repeat(30) + "b";
+        assert!(!match_wildcard(
+            pattern.as_bytes(),
+            "a".repeat(100).as_bytes()
+        ));
+    }
+
+    #[test]
+    fn test_known_hosts_type() {
+        env_logger::try_init().unwrap_or(());
+        let dir = tempdir::TempDir::new("russh").unwrap();
+        let path = dir.path().join("known_hosts");
+        std::fs::write(
+            &path,
+            "# comment\n\
+             [localhost]:13265 ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIJdD7y3aLq454yWBdwLWbieU1ebz9/cu7/QEXn9OIeZJ\n\
+             *.pijul.org,!evil.pijul.org ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA6rWI3G1sz07DnfFlrouTcysQlj2P+jpNSOEWD9OJ3X\n\
+             @revoked * ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILIG2T/B0l0gaqj3puu510tu9N1OkQ4znY3LYuEm5zCF\n\
+             @cert-authority *.example.com ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA6rWI3G2sz07DnfFlrouTcysQlj2P+jpNSOEWD9OJ3X ca\n",
+        )
+        .unwrap();
+        let mut known_hosts = KnownHosts::load(&path).unwrap();
+        let localhost = parse_public_key_base64(
+            "AAAAC3NzaC1lZDI1NTE5AAAAIJdD7y3aLq454yWBdwLWbieU1ebz9/cu7/QEXn9OIeZJ",
+        )
+        .unwrap();
+        let pijul = parse_public_key_base64(
+            "AAAAC3NzaC1lZDI1NTE5AAAAIA6rWI3G1sz07DnfFlrouTcysQlj2P+jpNSOEWD9OJ3X",
+        )
+        .unwrap();
+        let revoked = parse_public_key_base64(
+            "AAAAC3NzaC1lZDI1NTE5AAAAILIG2T/B0l0gaqj3puu510tu9N1OkQ4znY3LYuEm5zCF",
+        )
+        .unwrap();
+
+        assert_eq!(
+            known_hosts.check("localhost", 13265, &localhost),
+            KnownHostStatus::Known { line: 2 }
+        );
+        assert_eq!(
+            known_hosts.check("localhost", 22, &localhost),
+            KnownHostStatus::Unknown
+        );
+        assert_eq!(
+            known_hosts.check("nest.pijul.org", 22, &pijul),
+            KnownHostStatus::Known { line: 3 }
+        );
+        assert_eq!(
+            known_hosts.check("nest.pijul.org", 22, &localhost),
+            KnownHostStatus::Changed { line: 3 }
+        );
+        assert_eq!(
+            known_hosts.check("evil.pijul.org", 22, &pijul),
+            KnownHostStatus::Unknown
+        );
+        assert_eq!(
+            known_hosts.check("localhost", 13265, &revoked),
+            KnownHostStatus::Revoked { line: 4 }
+        );
+        assert_eq!(known_hosts.cert_authorities("a.example.com", 22).len(), 1);
+
+        known_hosts
+            .add("example.org", 2222, &localhost, true)
+            .unwrap();
+        assert_eq!(known_hosts.remove("localhost", 13265), 1);
+        known_hosts.save().unwrap();
+
+        let known_hosts = KnownHosts::load(&path).unwrap();
+        assert_eq!(
+            known_hosts.check("example.org", 2222, &localhost),
+            KnownHostStatus::Known { line: 5 }
+        );
+        assert_eq!(
+            known_hosts.check("localhost", 13265, &localhost),
+            KnownHostStatus::Unknown
+        );
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .starts_with("# comment\n"));
+    }
 }
@@ -0,0 +1,271 @@
+//! Keys stored in PKCS#11 modules, such as smartcards and HSMs.
+//!
+//! The private part of these keys never leaves the token. A
+//! [`Pkcs11Provider`] loads the module (for instance
+//! `/usr/lib/softhsm/libsofthsm2.so` or `opensc-pkcs11.so`), and
+//! [`Pkcs11Provider::keys`] logs into a slot and lists the keys it
+//! holds, as [`Pkcs11Key`]s implementing [`KeySigner`], usable as host
+//! keys or for client authentication.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), russh_keys::Error> {
+//! use russh_keys::pkcs11::Pkcs11Provider;
+//!
+//! let provider = Pkcs11Provider::new("/usr/lib/softhsm/libsofthsm2.so")?;
+//! let slot = provider.slots()?[0];
+//! for key in provider.keys(slot, Some("1234"))? {
+//!     println!("{}", key.label());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use cryptoki::context::{CInitializeArgs, Pkcs11};
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle};
+use cryptoki::session::{Session, UserType};
+use cryptoki::types::AuthPin;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use ssh_encoding::{Encode, Mpint};
+use ssh_key::public::{EcdsaPublicKey, Ed25519PublicKey, KeyData, RsaPublicKey};
+use ssh_key::{Algorithm, EcdsaCurve, HashAlg, PublicKey, Signature};
+
+use crate::key::{KeySigner, SshSignature};
+use crate::Error;
+
+pub use cryptoki::slot::Slot;
+
+/// A loaded and initialized PKCS#11 module.
+pub struct Pkcs11Provider {
+    pkcs11: Pkcs11,
+}
+
+impl std::fmt::Debug for Pkcs11Provider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pkcs11Provider").finish_non_exhaustive()
+    }
+}
+
+impl Pkcs11Provider {
+    /// Load the PKCS#11 module at `module` and initialize it.
+    pub fn new<P: AsRef<Path>>(module: P) -> Result<Self, Error> {
+        let pkcs11 = Pkcs11::new(module.as_ref())?;
+        pkcs11.initialize(CInitializeArgs::OsThreads)?;
+        Ok(Pkcs11Provider { pkcs11 })
+    }
+
+    /// The slots that currently hold a token.
+    pub fn slots(&self) -> Result<Vec<Slot>, Error> {
+        Ok(self.pkcs11.get_slots_with_token()?)
+    }
+
+    /// Open a session on `slot`, log in with `pin` if given, and list
+    /// the private keys of the token. Keys of unsupported types, or
+    /// without a matching public key object, are skipped.
+    pub fn keys(&self, slot: Slot, pin: Option<&str>) -> Result<Vec<Pkcs11Key>, Error> {
+        let session = self.pkcs11.open_ro_session(slot)?;
+        if let Some(pin) = pin {
+            session.login(UserType::User, Some(&AuthPin::new(pin.into())))?;
+        }
+
+        let mut keys = Vec::new();
+        for handle in session.find_objects(&[Attribute::Class(ObjectClass::PRIVATE_KEY)])? {
+            let mut id = Vec::new();
+            let mut label = String::new();
+            let mut key_type = None;
+            for attr in session.get_attributes(
+                handle,
+                &[
+                    AttributeType::Id,
+                    AttributeType::Label,
+                    AttributeType::KeyType,
+                ],
+            )? {
+                match attr {
+                    Attribute::Id(i) => id = i,
+                    Attribute::Label(l) => label = String::from_utf8_lossy(&l).into_owned(),
+                    Attribute::KeyType(t) => key_type = Some(t),
+                    _ => {}
+                }
+            }
+            let Some(key_type) = key_type else {
+                continue;
+            };
+            match public_key(&session, &id, key_type) {
+                Ok(Some(key_data)) => keys.push((handle, PublicKey::new(key_data, &label), label)),
+                Ok(None) => {}
+                Err(e) => log::debug!("skipping PKCS#11 key {:?}: {:?}", label, e),
+            }
+        }
+
+        let session = Arc::new(Mutex::new(session));
+        Ok(keys
+            .into_iter()
+            .map(|(handle, public, label)| Pkcs11Key {
+                session: session.clone(),
+                handle,
+                public,
+                label,
+            })
+            .collect())
+    }
+
+    /// Find the key labelled `label` on `slot`, logging in with `pin`.
+    pub fn key(&self, slot: Slot, pin: Option<&str>, label: &str) -> Result<Pkcs11Key, Error> {
+        self.keys(slot, pin)?
+            .into_iter()
+            .find(|k| k.label == label)
+            .ok_or(Error::CouldNotReadKey)
+    }
+}
+
+/// Read the public key object with id `id` from the token.
+fn public_key(session: &Session, id: &[u8], key_type: KeyType) -> Result<Option<KeyData>, Error> {
+    let Some(handle) = session
+        .find_objects(&[
+            Attribute::Class(ObjectClass::PUBLIC_KEY),
+            Attribute::Id(id.to_vec()),
+        ])?
+        .into_iter()
+        .next()
+    else {
+        return Ok(None);
+    };
+
+    if key_type == KeyType::RSA {
+        let mut n = Vec::new();
+        let mut e = Vec::new();
+        for attr in session.get_attributes(
+            handle,
+            &[AttributeType::Modulus, AttributeType::PublicExponent],
+        )? {
+            match attr {
+                Attribute::Modulus(m) => n = m,
+                Attribute::PublicExponent(p) => e = p,
+                _ => {}
+            }
+        }
+        return Ok(Some(KeyData::Rsa(RsaPublicKey {
+            e: Mpint::from_positive_bytes(&e)?,
+            n: Mpint::from_positive_bytes(&n)?,
+        })));
+    }
+
+    if key_type != KeyType::EC && key_type != KeyType::EC_EDWARDS {
+        return Ok(None);
+    }
+    let mut point = Vec::new();
+    for attr in session.get_attributes(handle, &[AttributeType::EcPoint])? {
+        if let Attribute::EcPoint(p) = attr {
+            point = p
+        }
+    }
+    // CKA_EC_POINT is a DER OCTET STRING, but some modules return the
+    // raw point.
+    let point = match <der::asn1::OctetString as der::Decode>::from_der(&point) {
+        Ok(p) => p.into_bytes(),
+        Err(_) => point,
+    };
+    if key_type == KeyType::EC {
+        Ok(Some(KeyData::Ecdsa(EcdsaPublicKey::from_sec1_bytes(
+            &point,
+        )?)))
+    } else {
+        Ok(Some(KeyData::Ed25519(Ed25519PublicKey::try_from(
+            &point[..],
+        )?)))
+    }
+}
+
+/// A private key held in a PKCS#11 token.
+///
+/// Signing calls into the module synchronously, and keys from the same
+/// [`Pkcs11Provider::keys`] call share a session, so that only one
+/// signature is made at a time.
+pub struct Pkcs11Key {
+    session: Arc<Mutex<Session>>,
+    handle: ObjectHandle,
+    public: PublicKey,
+    label: String,
+}
+
+impl std::fmt::Debug for Pkcs11Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pkcs11Key")
+            .field("label", &self.label)
+            .field("public", &self.public)
+            .finish()
+    }
+}
+
+impl Pkcs11Key {
+    /// The `CKA_LABEL` of the key.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    fn sign_raw(&self, mechanism: &Mechanism, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let session = self
+            .session
+            .lock()
+            .map_err(|_| Error::SecurityKey("PKCS#11 session poisoned".into()))?;
+        Ok(session.sign(mechanism, self.handle, data)?)
+    }
+}
+
+#[async_trait]
+impl KeySigner for Pkcs11Key {
+    fn public_key(&self) -> &PublicKey {
+        &self.public
+    }
+
+    async fn sign(&self, hash_alg: Option<HashAlg>, data: &[u8]) -> Result<SshSignature, Error> {
+        match self.public.key_data() {
+            KeyData::Rsa(_) => {
+                let mechanism = match hash_alg {
+                    None => Mechanism::Sha1RsaPkcs,
+                    Some(HashAlg::Sha256) => Mechanism::Sha256RsaPkcs,
+                    Some(HashAlg::Sha512) => Mechanism::Sha512RsaPkcs,
+                    Some(_) => {
+                        return Err(Error::UnsupportedKeyType {
+                            key_type_string: "rsa".into(),
+                            key_type_raw: Vec::new(),
+                        })
+                    }
+                };
+                Ok(SshSignature::new(
+                    Algorithm::Rsa { hash: hash_alg },
+                    self.sign_raw(&mechanism, data)?,
+                )?)
+            }
+            KeyData::Ecdsa(public) => {
+                let curve = public.curve();
+                let digest = match curve {
+                    EcdsaCurve::NistP256 => Sha256::digest(data).to_vec(),
+                    EcdsaCurve::NistP384 => Sha384::digest(data).to_vec(),
+                    EcdsaCurve::NistP521 => Sha512::digest(data).to_vec(),
+                };
+                // CKM_ECDSA returns r || s, each half of the output.
+                let raw = self.sign_raw(&Mechanism::Ecdsa, &digest)?;
+                let (r, s) = raw.split_at(raw.len() / 2);
+                let mut sig = Vec::new();
+                Mpint::from_positive_bytes(r)?.encode(&mut sig)?;
+                Mpint::from_positive_bytes(s)?.encode(&mut sig)?;
+                Ok(Signature::new(Algorithm::Ecdsa { curve }, sig)?.into())
+            }
+            KeyData::Ed25519(_) => Ok(Signature::new(
+                Algorithm::Ed25519,
+                self.sign_raw(&Mechanism::Eddsa, data)?,
+            )?
+            .into()),
+            _ => Err(Error::UnsupportedKeyType {
+                key_type_string: self.public.algorithm().as_str().into(),
+                key_type_raw: Vec::new(),
+            }),
+        }
+    }
+}
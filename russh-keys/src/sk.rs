@@ -0,0 +1,420 @@
+//! FIDO2/U2F security keys (`sk-ssh-ed25519@openssh.com` and
+//! `sk-ecdsa-sha2-nistp256@openssh.com`).
+//!
+//! The private part of these keys never leaves the authenticator: the
+//! key file only contains a handle to the credential, and signatures
+//! are made by an [`SkProvider`]. With the `libfido2` feature,
+//! [`SkMiddleware`] loads an OpenSSH `SecurityKeyProvider` library
+//! (such as the standalone `libsk-libfido2.so`) to talk to the device.
+
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+use ssh_encoding::{Decode, Encode};
+use ssh_key::private::KeypairData;
+use ssh_key::public::KeyData;
+use ssh_key::{Algorithm, Mpint, PrivateKey, PublicKey};
+
+use crate::Error;
+
+/// Flag set by the authenticator if the user touched it.
+pub const SK_USER_PRESENCE_REQD: u8 = 0x01;
+/// Flag set by the authenticator if the user was verified (PIN,
+/// biometrics).
+pub const SK_USER_VERIFICATION_REQD: u8 = 0x04;
+
+/// A signature made by a security key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkSignResponse {
+    pub flags: u8,
+    pub counter: u32,
+    /// The Ed25519 signature, or the `r` part of the ECDSA signature,
+    /// as big-endian bytes.
+    pub sig_r: Vec<u8>,
+    /// The `s` part of the ECDSA signature (empty for Ed25519).
+    pub sig_s: Vec<u8>,
+}
+
+/// Something that can sign with a security key credential.
+///
+/// This may block for a long time, until the user touches the key.
+pub trait SkProvider: Send + Sync {
+    /// Sign `message` with the credential `key_handle`, registered for
+    /// `application`. `algorithm` is one of the sk algorithms, and
+    /// `flags` are the flags of the key file. The authenticator must
+    /// use SHA-256(`message`) as the client data hash.
+    fn sign(
+        &self,
+        algorithm: &Algorithm,
+        message: &[u8],
+        application: &str,
+        key_handle: &[u8],
+        flags: u8,
+    ) -> Result<SkSignResponse, Error>;
+}
+
+/// A security key, as loaded from an `id_ed25519_sk` or `id_ecdsa_sk`
+/// file, and a provider to sign with it.
+pub struct SkSigner<P: SkProvider> {
+    public: PublicKey,
+    application: String,
+    key_handle: Vec<u8>,
+    flags: u8,
+    provider: Arc<P>,
+}
+
+impl<P: SkProvider> Clone for SkSigner<P> {
+    fn clone(&self) -> Self {
+        SkSigner {
+            public: self.public.clone(),
+            application: self.application.clone(),
+            key_handle: self.key_handle.clone(),
+            flags: self.flags,
+            provider: self.provider.clone(),
+        }
+    }
+}
+
+impl<P: SkProvider> std::fmt::Debug for SkSigner<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SkSigner")
+            .field("public", &self.public)
+            .field("application", &self.application)
+            .field("flags", &self.flags)
+            .finish()
+    }
+}
+
+impl<P: SkProvider> SkSigner<P> {
+    pub fn new(key: &PrivateKey, provider: P) -> Result<Self, Error> {
+        let (application, key_handle, flags) = match key.key_data() {
+            KeypairData::SkEd25519(k) => (k.public().application(), k.key_handle(), k.flags()),
+            KeypairData::SkEcdsaSha2NistP256(k) => {
+                (k.public().application(), k.key_handle(), k.flags())
+            }
+            _ => {
+                let algorithm = key.algorithm();
+                return Err(Error::UnsupportedKeyType {
+                    key_type_string: algorithm.as_str().to_string(),
+                    key_type_raw: algorithm.as_str().as_bytes().to_vec(),
+                });
+            }
+        };
+        Ok(SkSigner {
+            public: key.public_key().clone(),
+            application: application.to_string(),
+            key_handle: key_handle.to_vec(),
+            flags,
+            provider: Arc::new(provider),
+        })
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public
+    }
+
+    /// Sign `message`, returning the SSH encoding of the signature.
+    /// Like [`SkProvider::sign`], this may block.
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        let algorithm = self.public.algorithm();
+        let response = self.provider.sign(
+            &algorithm,
+            message,
+            &self.application,
+            &self.key_handle,
+            self.flags,
+        )?;
+        let mut sig = Vec::new();
+        algorithm.as_str().encode(&mut sig)?;
+        if let Algorithm::SkEd25519 = algorithm {
+            response.sig_r.encode(&mut sig)?;
+        } else {
+            let mut inner = Vec::new();
+            Mpint::from_positive_bytes(&response.sig_r)?.encode(&mut inner)?;
+            Mpint::from_positive_bytes(&response.sig_s)?.encode(&mut inner)?;
+            inner.encode(&mut sig)?;
+        }
+        response.flags.encode(&mut sig)?;
+        response.counter.encode(&mut sig)?;
+        Ok(sig)
+    }
+}
+
+/// The flags and counter of a verified security key signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SkSignatureInfo {
+    pub flags: u8,
+    pub counter: u32,
+}
+
+impl SkSignatureInfo {
+    /// Whether the user touched the key.
+    pub fn user_present(&self) -> bool {
+        self.flags & SK_USER_PRESENCE_REQD != 0
+    }
+
+    pub fn user_verified(&self) -> bool {
+        self.flags & SK_USER_VERIFICATION_REQD != 0
+    }
+}
+
+/// Whether `key` is a security key.
+pub fn is_sk_key(key: &PublicKey) -> bool {
+    matches!(
+        key.key_data(),
+        KeyData::SkEd25519(_) | KeyData::SkEcdsaSha2NistP256(_)
+    )
+}
+
+/// Verify the SSH encoding of a security key signature of `message`.
+/// The flags must still be checked by the caller, as OpenSSH refuses
+/// signatures without [`SK_USER_PRESENCE_REQD`] by default.
+pub fn verify(
+    key: &PublicKey,
+    message: &[u8],
+    mut signature: &[u8],
+) -> Result<SkSignatureInfo, Error> {
+    let algorithm = String::decode(&mut signature)?;
+    if algorithm != key.algorithm().as_str() {
+        return Err(Error::InvalidSignature);
+    }
+    let sig = Vec::<u8>::decode(&mut signature)?;
+    let flags = u8::decode(&mut signature)?;
+    let counter = u32::decode(&mut signature)?;
+    match key.key_data() {
+        KeyData::SkEd25519(pk) => {
+            let signed = signed_data(pk.application(), flags, counter, message);
+            let vk = ed25519_dalek::VerifyingKey::from_bytes(&pk.public_key().0)?;
+            let sig = ed25519_dalek::Signature::from_slice(&sig)?;
+            vk.verify_strict(&signed, &sig)?;
+        }
+        KeyData::SkEcdsaSha2NistP256(pk) => {
+            use p256::ecdsa::signature::Verifier;
+            let signed = signed_data(pk.application(), flags, counter, message);
+            let vk = p256::ecdsa::VerifyingKey::from_sec1_bytes(pk.ec_point().as_bytes())
+                .map_err(|_| Error::KeyIsCorrupt)?;
+            let mut r = &sig[..];
+            let sig_r = field_bytes(&Mpint::decode(&mut r)?)?;
+            let sig_s = field_bytes(&Mpint::decode(&mut r)?)?;
+            let sig = p256::ecdsa::Signature::from_scalars(sig_r, sig_s)
+                .map_err(|_| Error::InvalidSignature)?;
+            vk.verify(&signed, &sig)
+                .map_err(|_| Error::InvalidSignature)?;
+        }
+        _ => return Err(Error::InvalidSignature),
+    }
+    Ok(SkSignatureInfo { flags, counter })
+}
+
+/// What the authenticator actually signs.
+fn signed_data(application: &str, flags: u8, counter: u32, message: &[u8]) -> Vec<u8> {
+    let mut signed = Vec::with_capacity(69);
+    signed.extend_from_slice(&Sha256::digest(application.as_bytes()));
+    signed.push(flags);
+    signed.extend_from_slice(&counter.to_be_bytes());
+    signed.extend_from_slice(&Sha256::digest(message));
+    signed
+}
+
+fn field_bytes(m: &Mpint) -> Result<p256::FieldBytes, Error> {
+    let bytes = m.as_positive_bytes().ok_or(Error::InvalidSignature)?;
+    let mut field = p256::FieldBytes::default();
+    let offset = field
+        .len()
+        .checked_sub(bytes.len())
+        .ok_or(Error::InvalidSignature)?;
+    #[allow(clippy::indexing_slicing)] // length checked
+    field[offset..].copy_from_slice(bytes);
+    Ok(field)
+}
+
+#[cfg(feature = "libfido2")]
+pub use middleware::SkMiddleware;
+
+#[cfg(feature = "libfido2")]
+mod middleware {
+    use std::ffi::CString;
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::path::Path;
+
+    use ssh_key::Algorithm;
+
+    use super::{SkProvider, SkSignResponse};
+    use crate::Error;
+
+    /// The API version of OpenSSH's `sk-api.h` this is written for.
+    const SSH_SK_VERSION_MAJOR: u32 = 0x000a0000;
+    const SSH_SK_VERSION_MAJOR_MASK: u32 = 0xffff0000;
+    const SSH_SK_ECDSA: u32 = 0x00;
+    const SSH_SK_ED25519: u32 = 0x01;
+
+    #[repr(C)]
+    struct SkSignResponseC {
+        flags: u8,
+        counter: u32,
+        sig_r: *mut u8,
+        sig_r_len: usize,
+        sig_s: *mut u8,
+        sig_s_len: usize,
+    }
+
+    type SkApiVersion = unsafe extern "C" fn() -> u32;
+    type SkSign = unsafe extern "C" fn(
+        alg: u32,
+        data: *const u8,
+        data_len: usize,
+        application: *const c_char,
+        key_handle: *const u8,
+        key_handle_len: usize,
+        flags: u8,
+        pin: *const c_char,
+        options: *mut *mut c_void,
+        sign_response: *mut *mut SkSignResponseC,
+    ) -> c_int;
+
+    /// An OpenSSH `SecurityKeyProvider` library, such as
+    /// `libsk-libfido2.so`.
+    #[derive(Debug)]
+    pub struct SkMiddleware {
+        library: libloading::Library,
+        pin: Option<CString>,
+    }
+
+    impl SkMiddleware {
+        /// Load the library at `path`, checking its API version.
+        pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+            let library = unsafe { libloading::Library::new(path.as_ref())? };
+            let version = unsafe { library.get::<SkApiVersion>(b"sk_api_version\0")?() };
+            if version & SSH_SK_VERSION_MAJOR_MASK != SSH_SK_VERSION_MAJOR {
+                return Err(Error::SecurityKey(format!(
+                    "unsupported middleware API version {:#x}",
+                    version
+                )));
+            }
+            Ok(SkMiddleware { library, pin: None })
+        }
+
+        /// The PIN to pass to the device, for keys requiring user
+        /// verification.
+        pub fn with_pin(mut self, pin: &str) -> Result<Self, Error> {
+            self.pin = Some(CString::new(pin).map_err(|_| Error::InvalidParameters)?);
+            Ok(self)
+        }
+    }
+
+    impl SkProvider for SkMiddleware {
+        fn sign(
+            &self,
+            algorithm: &Algorithm,
+            message: &[u8],
+            application: &str,
+            key_handle: &[u8],
+            flags: u8,
+        ) -> Result<SkSignResponse, Error> {
+            let alg = match algorithm {
+                Algorithm::SkEd25519 => SSH_SK_ED25519,
+                Algorithm::SkEcdsaSha2NistP256 => SSH_SK_ECDSA,
+                _ => return Err(Error::InvalidParameters),
+            };
+            let application = CString::new(application).map_err(|_| Error::InvalidParameters)?;
+            let pin = self
+                .pin
+                .as_ref()
+                .map(|p| p.as_ptr())
+                .unwrap_or(std::ptr::null());
+            let mut response: *mut SkSignResponseC = std::ptr::null_mut();
+            let sk_sign = unsafe { self.library.get::<SkSign>(b"sk_sign\0")? };
+            let ret = unsafe {
+                sk_sign(
+                    alg,
+                    message.as_ptr(),
+                    message.len(),
+                    application.as_ptr(),
+                    key_handle.as_ptr(),
+                    key_handle.len(),
+                    flags,
+                    pin,
+                    std::ptr::null_mut(),
+                    &mut response,
+                )
+            };
+            if ret != 0 || response.is_null() {
+                return Err(Error::SecurityKey(format!("sk_sign failed ({})", ret)));
+            }
+            // SAFETY: the middleware returned a valid response, which is
+            // ours to free.
+            unsafe {
+                let r = &*response;
+                let copy = |p: *mut u8, len: usize| {
+                    if p.is_null() {
+                        Vec::new()
+                    } else {
+                        std::slice::from_raw_parts(p, len).to_vec()
+                    }
+                };
+                let result = SkSignResponse {
+                    flags: r.flags,
+                    counter: r.counter,
+                    sig_r: copy(r.sig_r, r.sig_r_len),
+                    sig_s: copy(r.sig_s, r.sig_s_len),
+                };
+                libc::free(r.sig_r as *mut c_void);
+                libc::free(r.sig_s as *mut c_void);
+                libc::free(response as *mut c_void);
+                Ok(result)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use ed25519_dalek::Signer;
+    use ssh_key::public::SkEd25519;
+
+    use super::*;
+
+    /// A software authenticator, holding the Ed25519 key itself.
+    struct SoftKey(ed25519_dalek::SigningKey);
+
+    impl SkProvider for SoftKey {
+        fn sign(
+            &self,
+            _: &Algorithm,
+            message: &[u8],
+            application: &str,
+            _: &[u8],
+            flags: u8,
+        ) -> Result<SkSignResponse, Error> {
+            let signed = signed_data(application, flags, 7, message);
+            Ok(SkSignResponse {
+                flags,
+                counter: 7,
+                sig_r: self.0.sign(&signed).to_bytes().to_vec(),
+                sig_s: Vec::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_sk_ed25519() {
+        let signing_key = ed25519_dalek::SigningKey::generate(&mut rand::thread_rng());
+        let public = SkEd25519::new(
+            ssh_key::public::Ed25519PublicKey(signing_key.verifying_key().to_bytes()),
+            "ssh:",
+        );
+        let private =
+            ssh_key::private::SkEd25519::new(public, SK_USER_PRESENCE_REQD, vec![1, 2, 3]).unwrap();
+        let key = PrivateKey::new(KeypairData::SkEd25519(private), "").unwrap();
+        let signer = SkSigner::new(&key, SoftKey(signing_key)).unwrap();
+        assert!(is_sk_key(signer.public_key()));
+
+        let sig = signer.sign(b"message").unwrap();
+        let info = verify(signer.public_key(), b"message", &sig).unwrap();
+        assert!(info.user_present());
+        assert_eq!(info.counter, 7);
+        assert!(verify(signer.public_key(), b"other message", &sig).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//! Resolving host names and connecting to them without blocking the
+//! runtime. All the addresses of a host are tried, IPv6 and IPv4
+//! alternately, with staggered connection attempts as described in
+//! RFC 8305 ("Happy Eyeballs").
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod proxy;
+#[cfg(not(target_arch = "wasm32"))]
+mod tcp;
+#[cfg(not(target_arch = "wasm32"))]
+pub use tcp::*;
+
+/// The address families to connect with, as in the `AddressFamily`
+/// option of OpenSSH.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    /// IPv4 only.
+    Inet,
+    /// IPv6 only.
+    Inet6,
+}
+
+impl AddressFamily {
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::Inet => addr.is_ipv4(),
+            AddressFamily::Inet6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Alternate the address families, starting with the family of the
+/// first address, and otherwise keeping the order of the resolver
+/// (RFC 8305, section 4).
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_is_ipv6 = first.is_ipv6();
+    let (first_family, other_family): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+    let mut first_family = first_family.into_iter();
+    let mut other_family = other_family.into_iter();
+    let mut result = Vec::new();
+    loop {
+        match (first_family.next(), other_family.next()) {
+            (None, None) => return result,
+            (a, b) => {
+                result.extend(a);
+                result.extend(b);
+            }
+        }
+    }
+}
+
+/// The type of service (IPv4) or traffic class (IPv6) of connections,
+/// as in the `IPQoS` option of OpenSSH: `interactive` once a terminal
+/// is requested, and `bulk` otherwise. `None` leaves the system's
+/// default.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IpQos {
+    pub interactive: Option<u8>,
+    pub bulk: Option<u8>,
+}
+
+/// The names of DSCP classes and legacy ToS values accepted by `IPQoS`.
+const IP_QOS_NAMES: &[(&str, u8)] = &[
+    ("af11", 0x28),
+    ("af12", 0x30),
+    ("af13", 0x38),
+    ("af21", 0x48),
+    ("af22", 0x50),
+    ("af23", 0x58),
+    ("af31", 0x68),
+    ("af32", 0x70),
+    ("af33", 0x78),
+    ("af41", 0x88),
+    ("af42", 0x90),
+    ("af43", 0x98),
+    ("cs0", 0x00),
+    ("cs1", 0x20),
+    ("cs2", 0x40),
+    ("cs3", 0x60),
+    ("cs4", 0x80),
+    ("cs5", 0xa0),
+    ("cs6", 0xc0),
+    ("cs7", 0xe0),
+    ("ef", 0xb8),
+    ("le", 0x04),
+    ("lowdelay", 0x10),
+    ("throughput", 0x08),
+    ("reliability", 0x04),
+];
+
+impl IpQos {
+    /// Parse an `IPQoS` value: one class for all connections, or the
+    /// interactive and bulk classes, such as `lowdelay throughput`.
+    /// Classes are names such as `af21` or `cs1`, numbers, or `none`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut classes = value.split_whitespace().map(Self::parse_class);
+        let interactive = classes.next()??;
+        let bulk = match classes.next() {
+            Some(bulk) => bulk?,
+            None => interactive,
+        };
+        if classes.next().is_some() {
+            return None;
+        }
+        Some(IpQos { interactive, bulk })
+    }
+
+    fn parse_class(class: &str) -> Option<Option<u8>> {
+        let class = class.to_lowercase();
+        if class == "none" {
+            return Some(None);
+        }
+        if let Some((_, value)) = IP_QOS_NAMES.iter().find(|(name, _)| *name == class) {
+            return Some(Some(*value));
+        }
+        let value = match class.strip_prefix("0x") {
+            Some(hex) => u8::from_str_radix(hex, 16),
+            None => class.parse(),
+        };
+        value.ok().map(Some)
+    }
+
+    /// The class of a connection in the given mode.
+    pub fn class(&self, interactive: bool) -> Option<u8> {
+        if interactive {
+            self.interactive
+        } else {
+            self.bulk
+        }
+    }
+}
+
+/// Options of TCP connections, made or accepted. The defaults leave
+/// the system's defaults.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm (`TCP_NODELAY`). It is also disabled
+    /// once a terminal is requested, like OpenSSH does.
+    pub nodelay: bool,
+    /// Send TCP keepalives (`SO_KEEPALIVE`), like OpenSSH's
+    /// `TCPKeepAlive`.
+    pub keepalive: bool,
+    /// The idle time before the first TCP keepalive, instead of the
+    /// system's.
+    pub keepalive_time: Option<Duration>,
+    pub ip_qos: IpQos,
+    /// Only use this network interface (`SO_BINDTODEVICE`, Linux and
+    /// Android only). Usually requires privileges.
+    pub bind_device: Option<String>,
+}
+
+/// The protocol spoken by a [`ProxyConnector`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// An HTTP proxy, with the `CONNECT` method.
+    Http,
+    /// A SOCKS5 proxy (RFC 1928), with username and password
+    /// authentication (RFC 1929) if credentials are given.
+    Socks5,
+}
+
+impl ProxyKind {
+    fn default_port(&self) -> u16 {
+        match self {
+            ProxyKind::Http => 8080,
+            ProxyKind::Socks5 => 1080,
+        }
+    }
+}
+
+/// An HTTP or SOCKS5 proxy to tunnel TCP connections through. Host
+/// names are sent to the proxy, which resolves them.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProxyConnector {
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+    /// The user name and password to authenticate to the proxy with:
+    /// basic authentication for HTTP proxies.
+    pub credentials: Option<(String, String)>,
+}
+
+impl fmt::Debug for ProxyConnector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyConnector")
+            .field("kind", &self.kind)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("user", &self.credentials.as_ref().map(|(user, _)| user))
+            .finish()
+    }
+}
+
+impl ProxyConnector {
+    pub fn new(kind: ProxyKind, host: &str, port: u16) -> Self {
+        ProxyConnector {
+            kind,
+            host: host.to_string(),
+            port,
+            credentials: None,
+        }
+    }
+
+    pub fn with_credentials(mut self, user: &str, password: &str) -> Self {
+        self.credentials = Some((user.to_string(), password.to_string()));
+        self
+    }
+
+    /// Parse a proxy URL, `http://[user:password@]host[:port]` or
+    /// `socks5://[user:password@]host[:port]` (`socks5h://` is
+    /// accepted too). The default ports are 8080 and 1080, and IPv6
+    /// addresses are written in brackets.
+    pub fn parse(url: &str) -> Option<Self> {
+        let (scheme, rest) = url.split_once("://")?;
+        let kind = match scheme.to_lowercase().as_str() {
+            "http" => ProxyKind::Http,
+            "socks5" | "socks5h" => ProxyKind::Socks5,
+            _ => return None,
+        };
+        let rest = rest.strip_suffix('/').unwrap_or(rest);
+        let (credentials, host_port) = match rest.rsplit_once('@') {
+            Some((credentials, host_port)) => {
+                let (user, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+                (Some((user.to_string(), password.to_string())), host_port)
+            }
+            None => (None, rest),
+        };
+        let (host, port) = if let Some(rest) = host_port.strip_prefix('[') {
+            let (host, port) = rest.split_once(']')?;
+            (host, port.strip_prefix(':'))
+        } else {
+            match host_port.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (host_port, None),
+            }
+        };
+        if host.is_empty() {
+            return None;
+        }
+        let port = match port {
+            Some(port) => port.parse().ok()?,
+            None => kind.default_port(),
+        };
+        Some(ProxyConnector {
+            kind,
+            host: host.to_string(),
+            port,
+            credentials,
+        })
+    }
+}
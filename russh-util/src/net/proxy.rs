@@ -0,0 +1,231 @@
+use std::io;
+use std::net::{IpAddr, Ipv6Addr};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use super::{
+    connect_with_options, resolve, AddressFamily, ProxyConnector, ProxyKind, SocketOptions,
+};
+
+/// The longest response header accepted from an HTTP proxy.
+const MAX_HTTP_RESPONSE: usize = 8192;
+
+const SOCKS5: u8 = 5;
+const CONNECT: u8 = 1;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const USERNAME_PASSWORD_VERSION: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+impl ProxyConnector {
+    /// Connect to the proxy, from `bind` if given, and ask it for a
+    /// tunnel to `host` and `port`.
+    pub async fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        bind: Option<IpAddr>,
+        options: &SocketOptions,
+    ) -> io::Result<TcpStream> {
+        let addrs = resolve((self.host.as_str(), self.port), AddressFamily::Any).await?;
+        let mut stream = connect_with_options(&addrs, bind, options).await?;
+        self.handshake(&mut stream, host, port).await?;
+        Ok(stream)
+    }
+
+    /// Ask the proxy at the other end of `stream` for a tunnel to
+    /// `host` and `port`. Once this returns, `stream` is connected to
+    /// the destination.
+    pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        host: &str,
+        port: u16,
+    ) -> io::Result<()> {
+        match self.kind {
+            ProxyKind::Http => self.http_connect(stream, host, port).await,
+            ProxyKind::Socks5 => self.socks5_connect(stream, host, port).await,
+        }
+    }
+
+    async fn http_connect<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        host: &str,
+        port: u16,
+    ) -> io::Result<()> {
+        let authority = if host.parse::<Ipv6Addr>().is_ok() {
+            format!("[{host}]:{port}")
+        } else {
+            format!("{host}:{port}")
+        };
+        let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+        if let Some((user, password)) = &self.credentials {
+            let token = data_encoding::BASE64.encode(format!("{user}:{password}").as_bytes());
+            request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+
+        // Read one byte at a time, not to consume the beginning of the
+        // tunnelled stream.
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_HTTP_RESPONSE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "HTTP proxy response too long",
+                ));
+            }
+            response.push(stream.read_u8().await?);
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        let mut status = status_line.split_whitespace();
+        match (status.next(), status.next()) {
+            (Some(version), Some(code))
+                if version.starts_with("HTTP/") && code.starts_with('2') =>
+            {
+                Ok(())
+            }
+            (_, Some("407")) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("HTTP proxy authentication failed: {status_line}"),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("HTTP proxy refused the connection: {status_line}"),
+            )),
+        }
+    }
+
+    async fn socks5_connect<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        host: &str,
+        port: u16,
+    ) -> io::Result<()> {
+        if self.credentials.is_some() {
+            stream
+                .write_all(&[SOCKS5, 2, NO_AUTHENTICATION, USERNAME_PASSWORD])
+                .await?;
+        } else {
+            stream.write_all(&[SOCKS5, 1, NO_AUTHENTICATION]).await?;
+        }
+        stream.flush().await?;
+        if stream.read_u8().await? != SOCKS5 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a SOCKS5 proxy",
+            ));
+        }
+        match (stream.read_u8().await?, &self.credentials) {
+            (NO_AUTHENTICATION, _) => {}
+            (USERNAME_PASSWORD, Some((user, password))) => {
+                let mut request = vec![USERNAME_PASSWORD_VERSION];
+                push_short(&mut request, user.as_bytes())?;
+                push_short(&mut request, password.as_bytes())?;
+                stream.write_all(&request).await?;
+                stream.flush().await?;
+                let _version = stream.read_u8().await?;
+                if stream.read_u8().await? != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "SOCKS5 proxy authentication failed",
+                    ));
+                }
+            }
+            (NO_ACCEPTABLE_METHODS, _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "no authentication method accepted by the SOCKS5 proxy",
+                ))
+            }
+            (method, _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected SOCKS5 authentication method {method}"),
+                ))
+            }
+        }
+
+        let mut request = vec![SOCKS5, CONNECT, 0];
+        match host.parse() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(IPV4);
+                request.extend(ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(IPV6);
+                request.extend(ip.octets());
+            }
+            Err(_) => {
+                request.push(DOMAIN_NAME);
+                push_short(&mut request, host.as_bytes())?;
+            }
+        }
+        request.extend(port.to_be_bytes());
+        stream.write_all(&request).await?;
+        stream.flush().await?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        let [_version, status, _reserved, address_type] = reply;
+        if status != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!(
+                    "SOCKS5 proxy refused the connection: {}",
+                    socks5_status(status)
+                ),
+            ));
+        }
+        // Skip the address the proxy connected from.
+        let address_len = match address_type {
+            IPV4 => 4,
+            IPV6 => 16,
+            DOMAIN_NAME => stream.read_u8().await?.into(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected SOCKS5 address type {address_type}"),
+                ))
+            }
+        };
+        let mut address = vec![0; address_len + 2];
+        stream.read_exact(&mut address).await?;
+        Ok(())
+    }
+}
+
+/// Push a string prefixed with its length on one byte.
+fn push_short(buffer: &mut Vec<u8>, s: &[u8]) -> io::Result<()> {
+    let len = u8::try_from(s.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "SOCKS5 fields are limited to 255 bytes",
+        )
+    })?;
+    buffer.push(len);
+    buffer.extend_from_slice(s);
+    Ok(())
+}
+
+fn socks5_status(status: u8) -> &'static str {
+    match status {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
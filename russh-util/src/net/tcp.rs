@@ -0,0 +1,212 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use socket2::{SockRef, Socket, TcpKeepalive};
+use tokio::net::{TcpSocket, TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use super::{interleave, AddressFamily, SocketOptions};
+
+/// How long to wait for a connection attempt before starting the next
+/// one, the value recommended by RFC 8305.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Resolve `host` with the system resolver, on a blocking thread, and
+/// return its addresses of `family` in the order they should be
+/// tried, see [`interleave`].
+pub async fn resolve<A: ToSocketAddrs>(
+    host: A,
+    family: AddressFamily,
+) -> io::Result<Vec<SocketAddr>> {
+    let addrs = tokio::net::lookup_host(host)
+        .await?
+        .filter(|addr| family.matches(addr))
+        .collect();
+    Ok(interleave(addrs))
+}
+
+/// Connect to the first of `addrs` that answers. A new attempt is
+/// started every [`CONNECTION_ATTEMPT_DELAY`] or as soon as the
+/// previous one fails, without cancelling the pending ones, and the
+/// first connection established wins.
+///
+/// With a `bind` address, connections are made from that address, and
+/// only the addresses of its family are tried.
+pub async fn connect(addrs: &[SocketAddr], bind: Option<IpAddr>) -> io::Result<TcpStream> {
+    connect_with_options(addrs, bind, &SocketOptions::default()).await
+}
+
+/// Like [`connect`], with `options` applied to the connection.
+pub async fn connect_with_options(
+    addrs: &[SocketAddr],
+    bind: Option<IpAddr>,
+    options: &SocketOptions,
+) -> io::Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = addrs
+        .iter()
+        .filter(|addr| bind.map_or(true, |bind| bind.is_ipv6() == addr.is_ipv6()))
+        .copied()
+        .collect();
+    let (sender, mut receiver) = mpsc::channel(addrs.len().max(1));
+    let mut addrs = addrs.into_iter();
+    let mut attempts = Vec::new();
+    let mut running = 0;
+    let mut last_error = None;
+    let result = loop {
+        if running == 0 {
+            let Some(addr) = addrs.next() else {
+                break Err(last_error.unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "no address to connect to")
+                }));
+            };
+            attempts.push(attempt(addr, bind, options, sender.clone()));
+            running += 1;
+        }
+        tokio::select! {
+            Some(result) = receiver.recv() => {
+                running -= 1;
+                match result {
+                    Ok(stream) => break Ok(stream),
+                    Err(e) => {
+                        last_error = Some(e);
+                        if let Some(addr) = addrs.next() {
+                            attempts.push(attempt(addr, bind, options, sender.clone()));
+                            running += 1;
+                        }
+                    }
+                }
+            }
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY) => {
+                if let Some(addr) = addrs.next() {
+                    attempts.push(attempt(addr, bind, options, sender.clone()));
+                    running += 1;
+                }
+            }
+        }
+    };
+    for attempt in attempts {
+        attempt.abort()
+    }
+    let stream = result?;
+    options.apply(&stream)?;
+    Ok(stream)
+}
+
+fn attempt(
+    addr: SocketAddr,
+    bind: Option<IpAddr>,
+    options: &SocketOptions,
+    sender: mpsc::Sender<io::Result<TcpStream>>,
+) -> JoinHandle<()> {
+    let device = options.bind_device.clone();
+    tokio::spawn(async move {
+        let result = async {
+            let socket = if addr.is_ipv6() {
+                TcpSocket::new_v6()?
+            } else {
+                TcpSocket::new_v4()?
+            };
+            if let Some(device) = device {
+                bind_device(&socket, &device)?
+            }
+            if let Some(bind) = bind {
+                socket.bind(SocketAddr::new(bind, 0))?
+            }
+            socket.connect(addr).await
+        }
+        .await;
+        let _ = sender.send(result).await;
+    })
+}
+
+/// Restrict `socket` to the network interface `device`, before it is
+/// bound or connected.
+pub fn bind_device(socket: &TcpSocket, device: &str) -> io::Result<()> {
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    {
+        SockRef::from(socket).bind_device(Some(device.as_bytes()))
+    }
+    #[cfg(not(any(target_os = "android", target_os = "linux")))]
+    {
+        let _ = (socket, device);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "binding to a device is not supported on this system",
+        ))
+    }
+}
+
+/// Set the type of service or traffic class of `socket`.
+fn set_class(socket: &Socket, class: u8) -> io::Result<()> {
+    let is_ipv6 = socket.local_addr()?.is_ipv6();
+    #[cfg(any(target_os = "android", target_os = "linux", target_os = "macos"))]
+    if is_ipv6 {
+        return socket.set_tclass_v6(class.into());
+    }
+    #[cfg(not(any(
+        target_os = "fuchsia",
+        target_os = "redox",
+        target_os = "solaris",
+        target_os = "illumos"
+    )))]
+    if !is_ipv6 {
+        return socket.set_tos(class.into());
+    }
+    let _ = (is_ipv6, class);
+    Ok(())
+}
+
+impl SocketOptions {
+    /// Apply these options to a connection, in the bulk mode of
+    /// [`SocketOptions::ip_qos`]. `bind_device` is only applied to the
+    /// connections made by [`connect_with_options`].
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        if self.nodelay {
+            socket.set_nodelay(true)?
+        }
+        if self.keepalive {
+            match self.keepalive_time {
+                Some(time) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?,
+                None => socket.set_keepalive(true)?,
+            }
+        }
+        if let Some(class) = self.ip_qos.bulk {
+            set_class(&socket, class)?
+        }
+        Ok(())
+    }
+}
+
+/// A handle on the socket of a connection, to switch it to the
+/// interactive mode once a terminal is requested.
+#[derive(Debug)]
+pub struct SocketControl {
+    socket: Socket,
+    options: SocketOptions,
+}
+
+impl SocketControl {
+    pub fn new(stream: &TcpStream, options: SocketOptions) -> io::Result<Self> {
+        Ok(SocketControl {
+            socket: SockRef::from(stream).try_clone()?,
+            options,
+        })
+    }
+
+    /// Switch to the `interactive` class of [`SocketOptions::ip_qos`]
+    /// and disable Nagle's algorithm, or back to the `bulk` class.
+    pub fn set_interactive(&self, interactive: bool) -> io::Result<()> {
+        if interactive {
+            self.socket.set_nodelay(true)?
+        } else if !self.options.nodelay {
+            self.socket.set_nodelay(false)?
+        }
+        match self.options.ip_qos.class(interactive) {
+            Some(class) => set_class(&self.socket, class),
+            None => Ok(()),
+        }
+    }
+}
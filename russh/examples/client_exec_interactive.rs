@@ -115,7 +115,7 @@ impl Session {
                 .authenticate_publickey(user, Arc::new(key_pair))
                 .await?;
 
-            if !auth_res {
+            if !auth_res.success() {
                 anyhow::bail!("Authentication (with publickey) failed");
             }
         } else {
@@ -123,7 +123,7 @@ impl Session {
                 .authenticate_openssh_cert(user, Arc::new(key_pair), openssh_cert.unwrap())
                 .await?;
 
-            if !auth_res {
+            if !auth_res.success() {
                 anyhow::bail!("Authentication (with publickey+cert) failed");
             }
         }
@@ -94,7 +94,7 @@ impl Session {
             .authenticate_publickey(user, Arc::new(key_pair))
             .await?;
 
-        if !auth_res {
+        if !auth_res.success() {
             anyhow::bail!("Authentication failed");
         }
 
@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use async_trait::async_trait;
 use rand_core::OsRng;
 use russh::keys::*;
 use russh::server::{Msg, Server as _, Session};
@@ -65,7 +64,6 @@ impl server::Server for Server {
     }
 }
 
-#[async_trait]
 impl server::Handler for Server {
     type Error = russh::Error;
 
@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use async_trait::async_trait;
 use rand_core::OsRng;
 use ratatui::backend::CrosstermBackend;
 use ratatui::layout::Rect;
@@ -126,7 +125,6 @@ impl Server for AppServer {
     }
 }
 
-#[async_trait]
 impl Handler for AppServer {
     type Error = anyhow::Error;
 
@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use async_trait::async_trait;
 use rand_core::OsRng;
 use ratatui::backend::CrosstermBackend;
 use ratatui::layout::Rect;
@@ -128,7 +127,6 @@ impl Server for AppServer {
     }
 }
 
-#[async_trait]
 impl Handler for AppServer {
     type Error = anyhow::Error;
 
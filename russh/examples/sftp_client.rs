@@ -48,6 +48,7 @@ async fn main() {
         .authenticate_password("root", "password")
         .await
         .unwrap()
+        .success()
     {
         let channel = session.channel_open_session().await.unwrap();
         channel.request_subsystem(true, "sftp").await.unwrap();
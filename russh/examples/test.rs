@@ -1,7 +1,6 @@
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
-use async_trait::async_trait;
 use log::debug;
 use rand_core::OsRng;
 use russh::keys::*;
@@ -47,7 +46,6 @@ impl server::Server for Server {
     }
 }
 
-#[async_trait]
 impl server::Handler for Server {
     type Error = anyhow::Error;
 
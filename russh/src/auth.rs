@@ -13,16 +13,18 @@
 // limitations under the License.
 //
 
+use std::collections::VecDeque;
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use bitflags::bitflags;
-use russh_keys::helpers::NameList;
+use russh_keys::helpers::{EncodedExt, NameList};
+use ssh_encoding::Encode;
 use ssh_key::{Certificate, PrivateKey};
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::CryptoVec;
+use crate::{CryptoVec, SecretBuffer};
 
 bitflags! {
     /// Set of authentication methods, represented by bit flags.
@@ -42,6 +44,9 @@ bitflags! {
         /// challenge, where the "challenge" can be a password prompt,
         /// a bytestring to sign with a smartcard, or something else).
         const KEYBOARD_INTERACTIVE = 16;
+        /// The SSH `gssapi-with-mic` method (GSSAPI, usually
+        /// Kerberos, see [RFC 4462](https://tools.ietf.org/html/rfc4462)).
+        const GSSAPI_WITH_MIC = 32;
     }
 }
 
@@ -79,11 +84,59 @@ impl<R: AsyncRead + AsyncWrite + Unpin + Send + 'static> Signer
     }
 }
 
+#[async_trait]
+impl<P: russh_keys::sk::SkProvider + 'static> Signer for russh_keys::sk::SkSigner<P> {
+    type Error = AgentAuthError;
+
+    async fn auth_publickey_sign(
+        &mut self,
+        _key: &ssh_key::PublicKey,
+        mut to_sign: CryptoVec,
+    ) -> Result<CryptoVec, Self::Error> {
+        // Signing waits for the user to touch the key, or to enter
+        // their PIN, so don't block the runtime.
+        #[cfg(not(target_arch = "wasm32"))]
+        let signature = {
+            let signer = self.clone();
+            let message = to_sign.to_vec();
+            tokio::task::spawn_blocking(move || signer.sign(&message))
+                .await
+                .map_err(|e| russh_keys::Error::IO(std::io::Error::other(e)))??
+        };
+        #[cfg(target_arch = "wasm32")]
+        let signature = self.sign(&to_sign)?;
+        signature
+            .encode(&mut to_sign)
+            .map_err(russh_keys::Error::from)?;
+        Ok(to_sign)
+    }
+}
+
+#[async_trait]
+impl<K: russh_keys::key::KeySigner + ?Sized> Signer for Arc<K> {
+    type Error = AgentAuthError;
+
+    async fn auth_publickey_sign(
+        &mut self,
+        _key: &ssh_key::PublicKey,
+        mut to_sign: CryptoVec,
+    ) -> Result<CryptoVec, Self::Error> {
+        // Like agents, sign with the key's own algorithm.
+        let signature = self.sign(None, &to_sign).await?;
+        signature
+            .encoded()
+            .map_err(russh_keys::Error::from)?
+            .encode(&mut to_sign)
+            .map_err(russh_keys::Error::from)?;
+        Ok(to_sign)
+    }
+}
+
 #[derive(Debug)]
 pub enum Method {
     None,
     Password {
-        password: String,
+        password: SecretBuffer,
     },
     PublicKey {
         key: Arc<PrivateKey>,
@@ -98,7 +151,17 @@ pub enum Method {
     KeyboardInteractive {
         submethods: String,
     },
-    // Hostbased,
+    #[cfg(feature = "gssapi")]
+    GssapiWithMic {
+        // In a mutex, so that `client::Msg` stays `Sync` with contexts
+        // that aren't.
+        context: std::sync::Mutex<Box<dyn crate::gssapi::ClientContext>>,
+    },
+    Hostbased {
+        key: Arc<PrivateKey>,
+        client_host: String,
+        client_user: String,
+    },
 }
 
 impl From<MethodSet> for &'static str {
@@ -109,6 +172,7 @@ impl From<MethodSet> for &'static str {
             MethodSet::PUBLICKEY => "publickey",
             MethodSet::HOSTBASED => "hostbased",
             MethodSet::KEYBOARD_INTERACTIVE => "keyboard-interactive",
+            MethodSet::GSSAPI_WITH_MIC => "gssapi-with-mic",
             _ => "",
         }
     }
@@ -134,6 +198,7 @@ impl MethodSet {
             "publickey" => Some(MethodSet::PUBLICKEY),
             "hostbased" => Some(MethodSet::HOSTBASED),
             "keyboard-interactive" => Some(MethodSet::KEYBOARD_INTERACTIVE),
+            "gssapi-with-mic" => Some(MethodSet::GSSAPI_WITH_MIC),
             _ => None,
         }
     }
@@ -148,6 +213,13 @@ pub struct AuthRequest {
     pub current: Option<CurrentRequest>,
     #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
     pub rejection_count: usize,
+    /// Steps left after the current one when the server requires
+    /// several methods, `None` if a single method is enough.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub next_methods: Option<VecDeque<MethodSet>>,
+    /// User who completed the previous steps.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    pub partial_user: Option<String>,
 }
 
 #[doc(hidden)]
@@ -164,5 +236,15 @@ pub enum CurrentRequest {
     KeyboardInteractive {
         #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
         submethods: String,
+        /// Number of prompts in the last info request sent, if the
+        /// client still has to answer it.
+        #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+        pending_prompts: Option<usize>,
+    },
+    #[cfg(all(feature = "gssapi", not(target_arch = "wasm32")))]
+    GssapiWithMic {
+        context: Box<dyn crate::gssapi::ServerContext>,
+        user: String,
+        established: bool,
     },
 }
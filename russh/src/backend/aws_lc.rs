@@ -0,0 +1,99 @@
+use aws_lc_rs::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM, AES_256_GCM};
+use aws_lc_rs::agreement::{self, PrivateKey, UnparsedPublicKey, X25519};
+use zeroize::Zeroizing;
+
+use super::{CryptoBackend, GCM_NONCE_LEN, GCM_TAG_LEN};
+use crate::Error;
+
+pub(super) const BACKEND: CryptoBackend = CryptoBackend::AwsLc;
+
+/// AES-GCM with a 128 or 256 bits key.
+pub(crate) struct AesGcm(LessSafeKey);
+
+impl AesGcm {
+    pub(crate) fn new(key: &[u8]) -> Self {
+        let algorithm = if key.len() == 16 {
+            &AES_128_GCM
+        } else {
+            &AES_256_GCM
+        };
+        // The length of the key is the one of the algorithm.
+        #[allow(clippy::unwrap_used)]
+        AesGcm(LessSafeKey::new(UnboundKey::new(algorithm, key).unwrap()))
+    }
+
+    /// Encrypt `in_out`, and write the tag to `tag`.
+    pub(crate) fn seal(
+        &self,
+        nonce: &[u8; GCM_NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+        tag: &mut [u8; GCM_TAG_LEN],
+    ) {
+        // Encryption only fails for buffers longer than 64 GiB.
+        #[allow(clippy::unwrap_used)]
+        let tag_out = self
+            .0
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(*nonce),
+                Aad::from(aad),
+                in_out,
+            )
+            .unwrap();
+        tag.copy_from_slice(tag_out.as_ref())
+    }
+
+    /// Decrypt `in_out`, if `tag` is correct.
+    pub(crate) fn open(
+        &self,
+        nonce: &[u8; GCM_NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), Error> {
+        // aws-lc-rs wants the tag after the ciphertext.
+        let mut buffer = Zeroizing::new(Vec::with_capacity(in_out.len() + tag.len()));
+        buffer.extend_from_slice(in_out);
+        buffer.extend_from_slice(tag);
+        let plaintext = self
+            .0
+            .open_in_place(
+                Nonce::assume_unique_for_key(*nonce),
+                Aad::from(aad),
+                &mut buffer,
+            )
+            .map_err(|_| Error::DecryptionError)?;
+        in_out.copy_from_slice(plaintext);
+        Ok(())
+    }
+}
+
+/// The X25519 public key of `secret`.
+pub(crate) fn x25519_public(secret: &[u8; 32]) -> Result<[u8; 32], Error> {
+    let private = PrivateKey::from_private_key(&X25519, secret).map_err(|_| Error::Kex)?;
+    let public = private.compute_public_key().map_err(|_| Error::Kex)?;
+    let mut out = [0; 32];
+    if public.as_ref().len() != out.len() {
+        return Err(Error::Kex);
+    }
+    out.copy_from_slice(public.as_ref());
+    Ok(out)
+}
+
+/// The X25519 shared secret of `secret` and `public`.
+pub(crate) fn x25519(secret: &[u8; 32], public: &[u8]) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let private = PrivateKey::from_private_key(&X25519, secret).map_err(|_| Error::Kex)?;
+    agreement::agree(
+        &private,
+        &UnparsedPublicKey::new(&X25519, public),
+        Error::Kex,
+        |shared| {
+            let mut out = Zeroizing::new([0; 32]);
+            if shared.len() != out.len() {
+                return Err(Error::Kex);
+            }
+            out.copy_from_slice(shared);
+            Ok(out)
+        },
+    )
+}
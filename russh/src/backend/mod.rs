@@ -0,0 +1,100 @@
+//! The implementation of AES-GCM (`aes128-gcm@openssh.com` and
+//! `aes256-gcm@openssh.com`) and X25519 (`curve25519-sha256`, and the
+//! classical half of the hybrid key exchanges), selected with cargo
+//! features:
+//!
+//! - RustCrypto, in pure Rust, by default,
+//! - [aws-lc-rs](https://github.com/aws/aws-lc-rs), with the
+//!   `aws-lc-rs` feature,
+//! - OpenSSL, with the `openssl` feature. If both features are
+//!   enabled, aws-lc-rs is used.
+//!
+//! Only these two primitives go through the backend: the other
+//! ciphers, MACs and key exchanges, and the signatures of
+//! `russh-keys`, always use RustCrypto, which remains a dependency
+//! whatever the backend.
+
+#[cfg(not(any(feature = "aws-lc-rs", feature = "openssl")))]
+mod rustcrypto;
+#[cfg(not(any(feature = "aws-lc-rs", feature = "openssl")))]
+pub(crate) use self::rustcrypto::*;
+
+#[cfg(feature = "aws-lc-rs")]
+mod aws_lc;
+#[cfg(feature = "aws-lc-rs")]
+pub(crate) use self::aws_lc::*;
+
+#[cfg(all(feature = "openssl", not(feature = "aws-lc-rs")))]
+mod openssl;
+#[cfg(all(feature = "openssl", not(feature = "aws-lc-rs")))]
+pub(crate) use self::openssl::*;
+
+/// The length of AES-GCM nonces.
+pub(crate) const GCM_NONCE_LEN: usize = 12;
+/// The length of AES-GCM tags.
+pub(crate) const GCM_TAG_LEN: usize = 16;
+
+/// The implementation of AES-GCM and X25519. Everything else always
+/// uses RustCrypto.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CryptoBackend {
+    /// RustCrypto, the default.
+    RustCrypto,
+    /// aws-lc-rs, with the `aws-lc-rs` feature.
+    AwsLc,
+    /// OpenSSL, with the `openssl` feature.
+    OpenSsl,
+}
+
+/// The implementation of AES-GCM and X25519 russh was built with.
+pub fn crypto_backend() -> CryptoBackend {
+    BACKEND
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    // Test vector of RFC 7748, section 6.1.
+    #[test]
+    fn test_x25519() {
+        let alice: [u8; 32] =
+            hex_literal::hex!("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let bob: [u8; 32] =
+            hex_literal::hex!("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        let alice_public = x25519_public(&alice).unwrap();
+        assert_eq!(
+            alice_public,
+            hex_literal::hex!("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a")
+        );
+        let bob_public = x25519_public(&bob).unwrap();
+        let shared =
+            hex_literal::hex!("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        assert_eq!(*x25519(&alice, &bob_public).unwrap(), shared);
+        assert_eq!(*x25519(&bob, &alice_public).unwrap(), shared);
+    }
+
+    #[test]
+    fn test_aes_gcm() {
+        for key_len in [16, 32] {
+            let key = vec![7; key_len];
+            let nonce = [1; GCM_NONCE_LEN];
+            let sealing = AesGcm::new(&key);
+            let opening = AesGcm::new(&key);
+            let mut data = *b"some data";
+            let mut tag = [0; GCM_TAG_LEN];
+            sealing.seal(&nonce, b"aad", &mut data, &mut tag);
+            assert_ne!(&data, b"some data");
+            let encrypted = data;
+            opening.open(&nonce, b"aad", &mut data, &tag).unwrap();
+            assert_eq!(&data, b"some data");
+
+            data = encrypted;
+            tag[0] ^= 1;
+            assert!(opening.open(&nonce, b"aad", &mut data, &tag).is_err());
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use openssl::derive::Deriver;
+use openssl::pkey::{Id, PKey};
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use zeroize::Zeroizing;
+
+use super::{CryptoBackend, GCM_NONCE_LEN, GCM_TAG_LEN};
+use crate::Error;
+
+pub(super) const BACKEND: CryptoBackend = CryptoBackend::OpenSsl;
+
+/// AES-GCM with a 128 or 256 bits key.
+pub(crate) struct AesGcm {
+    cipher: Cipher,
+    key: Zeroizing<Vec<u8>>,
+}
+
+impl AesGcm {
+    pub(crate) fn new(key: &[u8]) -> Self {
+        AesGcm {
+            cipher: if key.len() == 16 {
+                Cipher::aes_128_gcm()
+            } else {
+                Cipher::aes_256_gcm()
+            },
+            key: Zeroizing::new(key.to_vec()),
+        }
+    }
+
+    /// Encrypt `in_out`, and write the tag to `tag`.
+    pub(crate) fn seal(
+        &self,
+        nonce: &[u8; GCM_NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+        tag: &mut [u8; GCM_TAG_LEN],
+    ) {
+        // Encryption only fails if OpenSSL is out of memory.
+        #[allow(clippy::unwrap_used)]
+        let ciphertext =
+            encrypt_aead(self.cipher, &self.key, Some(nonce), aad, in_out, tag).unwrap();
+        in_out.copy_from_slice(&ciphertext)
+    }
+
+    /// Decrypt `in_out`, if `tag` is correct.
+    pub(crate) fn open(
+        &self,
+        nonce: &[u8; GCM_NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), Error> {
+        let plaintext = Zeroizing::new(
+            decrypt_aead(self.cipher, &self.key, Some(nonce), aad, in_out, tag)
+                .map_err(|_| Error::DecryptionError)?,
+        );
+        in_out.copy_from_slice(&plaintext);
+        Ok(())
+    }
+}
+
+/// The X25519 public key of `secret`.
+pub(crate) fn x25519_public(secret: &[u8; 32]) -> Result<[u8; 32], Error> {
+    let private = PKey::private_key_from_raw_bytes(secret, Id::X25519).map_err(|_| Error::Kex)?;
+    let public = private.raw_public_key().map_err(|_| Error::Kex)?;
+    let mut out = [0; 32];
+    if public.len() != out.len() {
+        return Err(Error::Kex);
+    }
+    out.copy_from_slice(&public);
+    Ok(out)
+}
+
+/// The X25519 shared secret of `secret` and `public`.
+pub(crate) fn x25519(secret: &[u8; 32], public: &[u8]) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let private = PKey::private_key_from_raw_bytes(secret, Id::X25519).map_err(|_| Error::Kex)?;
+    let public = PKey::public_key_from_raw_bytes(public, Id::X25519).map_err(|_| Error::Kex)?;
+    let mut deriver = Deriver::new(&private).map_err(|_| Error::Kex)?;
+    deriver.set_peer(&public).map_err(|_| Error::Kex)?;
+    let shared = Zeroizing::new(deriver.derive_to_vec().map_err(|_| Error::Kex)?);
+    let mut out = Zeroizing::new([0; 32]);
+    if shared.len() != out.len() {
+        return Err(Error::Kex);
+    }
+    out.copy_from_slice(&shared);
+    Ok(out)
+}
@@ -0,0 +1,79 @@
+use aes_gcm::{AeadInPlace, Aes128Gcm, Aes256Gcm, KeyInit};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use generic_array::GenericArray;
+use zeroize::Zeroizing;
+
+use super::{CryptoBackend, GCM_NONCE_LEN, GCM_TAG_LEN};
+use crate::Error;
+
+pub(super) const BACKEND: CryptoBackend = CryptoBackend::RustCrypto;
+
+/// AES-GCM with a 128 or 256 bits key.
+pub(crate) enum AesGcm {
+    Aes128(Box<Aes128Gcm>),
+    Aes256(Box<Aes256Gcm>),
+}
+
+impl AesGcm {
+    pub(crate) fn new(key: &[u8]) -> Self {
+        if key.len() == 16 {
+            AesGcm::Aes128(Box::new(Aes128Gcm::new(GenericArray::from_slice(key))))
+        } else {
+            AesGcm::Aes256(Box::new(Aes256Gcm::new(GenericArray::from_slice(key))))
+        }
+    }
+
+    /// Encrypt `in_out`, and write the tag to `tag`.
+    pub(crate) fn seal(
+        &self,
+        nonce: &[u8; GCM_NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+        tag: &mut [u8; GCM_TAG_LEN],
+    ) {
+        let nonce = GenericArray::from_slice(nonce);
+        // Encryption only fails for buffers longer than 64 GiB.
+        #[allow(clippy::unwrap_used)]
+        let tag_out = match self {
+            AesGcm::Aes128(c) => c.encrypt_in_place_detached(nonce, aad, in_out),
+            AesGcm::Aes256(c) => c.encrypt_in_place_detached(nonce, aad, in_out),
+        }
+        .unwrap();
+        tag.copy_from_slice(&tag_out)
+    }
+
+    /// Decrypt `in_out`, if `tag` is correct.
+    pub(crate) fn open(
+        &self,
+        nonce: &[u8; GCM_NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), Error> {
+        if tag.len() != GCM_TAG_LEN {
+            return Err(Error::DecryptionError);
+        }
+        let nonce = GenericArray::from_slice(nonce);
+        let tag = GenericArray::from_slice(tag);
+        match self {
+            AesGcm::Aes128(c) => c.decrypt_in_place_detached(nonce, aad, in_out, tag),
+            AesGcm::Aes256(c) => c.decrypt_in_place_detached(nonce, aad, in_out, tag),
+        }
+        .map_err(|_| Error::DecryptionError)
+    }
+}
+
+/// The X25519 public key of `secret`.
+pub(crate) fn x25519_public(secret: &[u8; 32]) -> Result<[u8; 32], Error> {
+    Ok(MontgomeryPoint::mul_base_clamped(*secret).0)
+}
+
+/// The X25519 shared secret of `secret` and `public`.
+pub(crate) fn x25519(secret: &[u8; 32], public: &[u8]) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let mut point = MontgomeryPoint([0; 32]);
+    if public.len() != point.0.len() {
+        return Err(Error::Kex);
+    }
+    point.0.copy_from_slice(public);
+    Ok(Zeroizing::new(point.mul_clamped(*secret).0))
+}
@@ -25,6 +25,17 @@ impl PublicKeyOrCertificate {
             )?)),
         }
     }
+
+    /// The signature algorithm requested by a public key algorithm name,
+    /// which for RSA keys includes the hash.
+    pub fn signature_algorithm(&self, pubkey_algo: &str) -> Option<Algorithm> {
+        match self {
+            PublicKeyOrCertificate::PublicKey(_) => Algorithm::new(pubkey_algo).ok(),
+            PublicKeyOrCertificate::Certificate(_) => {
+                Algorithm::new_certificate_ext(pubkey_algo).ok()
+            }
+        }
+    }
 }
 
 trait AlgorithmExt {
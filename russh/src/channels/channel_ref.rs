@@ -1,16 +1,18 @@
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 
 use tokio::sync::mpsc::UnboundedSender;
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex, Notify};
 
-use crate::ChannelMsg;
+use crate::{ChannelMsg, CryptoVec};
 
 /// A handle to the [`super::Channel`]'s to be able to transmit messages
 /// to it and update it's `window_size`.
 #[derive(Debug)]
 pub struct ChannelRef {
     pub(super) sender: UnboundedSender<ChannelMsg>,
-    pub(super) window_size: Arc<Mutex<u32>>,
+    pub(super) window_size: WindowSizeRef,
 }
 
 impl ChannelRef {
@@ -21,9 +23,55 @@ impl ChannelRef {
         }
     }
 
-    pub fn window_size(&self) -> &Arc<Mutex<u32>> {
+    pub fn window_size(&self) -> &WindowSizeRef {
         &self.window_size
     }
+
+    /// Hand data received on the channel to its [`super::Channel`],
+    /// where it counts as unread until a reader takes it.
+    pub(crate) fn send_data(&self, data: &[u8], ext: Option<u32>) {
+        let data = CryptoVec::from_slice(data);
+        let msg = match ext {
+            None => ChannelMsg::Data { data },
+            Some(ext) => ChannelMsg::ExtendedData {
+                ext: ext.into(),
+                data,
+            },
+        };
+        let len = msg_len(&msg);
+        self.window_size.received(len);
+        if self.sender.send(msg).is_err() {
+            self.window_size.read(len)
+        }
+    }
+
+    /// The largest window to give the other side for the data it sends
+    /// to fit in `buffer_size`, with the data not read yet. `waker` is
+    /// notified when the channel is read. `None` if the window isn't
+    /// limited, which is also the case when the channel isn't read any
+    /// more.
+    pub(crate) fn window_limit(
+        &self,
+        buffer_size: Option<u32>,
+        waker: &Arc<Notify>,
+    ) -> Option<u32> {
+        let buffer_size = buffer_size?;
+        if self.sender.is_closed() {
+            return None;
+        }
+        self.window_size.set_reader_waker(waker);
+        Some(buffer_size.saturating_sub(self.window_size.unread()))
+    }
+}
+
+/// The bytes of data in `msg`, which count in the channel's window.
+pub(crate) fn msg_len(msg: &ChannelMsg) -> u32 {
+    match msg {
+        ChannelMsg::Data { data } | ChannelMsg::ExtendedData { data, .. } => {
+            u32::try_from(data.len()).unwrap_or(u32::MAX)
+        }
+        _ => 0,
+    }
 }
 
 impl std::ops::Deref for ChannelRef {
@@ -33,3 +81,135 @@ impl std::ops::Deref for ChannelRef {
         &self.sender
     }
 }
+
+/// The remote window of a channel, shared between the session and the
+/// writers of the [`super::Channel`], which wait for it to change when
+/// they can't send anything. Also counts the data received and not
+/// read from the channel yet.
+#[derive(Debug, Clone)]
+pub struct WindowSizeRef {
+    value: Arc<Mutex<u32>>,
+    state: Arc<WindowState>,
+    changes: watch::Receiver<()>,
+}
+
+#[derive(Debug)]
+struct WindowState {
+    /// Bytes of the channel queued in the session, waiting for window
+    /// space or for a key exchange to finish.
+    queued: AtomicU32,
+    /// 0 if writers are only limited by the window.
+    high_water_mark: AtomicU32,
+    changed: watch::Sender<()>,
+    /// Bytes handed to the channel and not read yet.
+    unread: AtomicU32,
+    /// Wakes the session up when the channel is read, to reopen its
+    /// window.
+    reader_waker: std::sync::Mutex<Option<Arc<Notify>>>,
+}
+
+impl Default for WindowSizeRef {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl WindowSizeRef {
+    pub(crate) fn new(value: u32) -> Self {
+        let (changed, changes) = watch::channel(());
+        Self {
+            value: Arc::new(Mutex::new(value)),
+            state: Arc::new(WindowState {
+                queued: AtomicU32::new(0),
+                high_water_mark: AtomicU32::new(0),
+                changed,
+                unread: AtomicU32::new(0),
+                reader_waker: Default::default(),
+            }),
+            changes,
+        }
+    }
+
+    pub(crate) fn value(&self) -> &Arc<Mutex<u32>> {
+        &self.value
+    }
+
+    /// Set the window size, after the other side adjusted it.
+    pub(crate) async fn update(&self, value: u32) {
+        *self.value.lock().await = value;
+        self.notify()
+    }
+
+    /// Record how many bytes the session still has to send.
+    pub(crate) fn set_queued(&self, queued: u32) {
+        if self.state.queued.swap(queued, Ordering::Relaxed) != queued {
+            self.notify()
+        }
+    }
+
+    pub(crate) fn set_high_water_mark(&self, high_water_mark: Option<u32>) {
+        self.state
+            .high_water_mark
+            .store(high_water_mark.unwrap_or(0), Ordering::Relaxed);
+        self.notify()
+    }
+
+    /// How much more can be handed to the session before reaching the
+    /// high-water mark.
+    pub(crate) fn below_high_water_mark(&self) -> u32 {
+        match self.state.high_water_mark.load(Ordering::Relaxed) {
+            0 => u32::MAX,
+            mark => mark.saturating_sub(self.state.queued.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Record that `len` bytes were handed to the channel.
+    pub(crate) fn received(&self, len: u32) {
+        let _ = self
+            .state
+            .unread
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |unread| {
+                Some(unread.saturating_add(len))
+            });
+    }
+
+    /// Record that `len` bytes were read from the channel.
+    pub(crate) fn read(&self, len: u32) {
+        if len == 0 {
+            return;
+        }
+        let _ = self
+            .state
+            .unread
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |unread| {
+                Some(unread.saturating_sub(len))
+            });
+        if let Ok(waker) = self.state.reader_waker.lock() {
+            if let Some(ref waker) = *waker {
+                waker.notify_one()
+            }
+        }
+    }
+
+    pub(crate) fn unread(&self) -> u32 {
+        self.state.unread.load(Ordering::Relaxed)
+    }
+
+    fn set_reader_waker(&self, waker: &Arc<Notify>) {
+        if let Ok(mut reader_waker) = self.state.reader_waker.lock() {
+            if reader_waker.is_none() {
+                *reader_waker = Some(waker.clone())
+            }
+        }
+    }
+
+    /// Changes of the window, the queue or the high-water mark.
+    pub(crate) fn changes(&self) -> watch::Receiver<()> {
+        self.changes.clone()
+    }
+
+    fn notify(&self) {
+        // Can't fail, `self.changes` is a receiver.
+        let _ = self.state.changed.send(());
+    }
+}
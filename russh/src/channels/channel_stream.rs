@@ -1,40 +1,208 @@
+use std::collections::VecDeque;
 use std::io;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
 
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use super::io::{ChannelRx, ChannelTx};
-use super::{ChannelId, ChannelMsg};
+use super::io::ChannelTx;
+use super::{Channel, ChannelId, ChannelMsg, ExtendedDataType};
+use crate::CryptoVec;
 
-/// AsyncRead/AsyncWrite wrapper for SSH Channels
+/// AsyncRead/AsyncWrite wrapper for SSH Channels.
+///
+/// Reads return the channel's data until the other side sends EOF or
+/// closes the channel, and [`shutdown`](tokio::io::AsyncWriteExt::shutdown)
+/// sends EOF, leaving the reading half open. Standard error is
+/// discarded, unless the stream was created with
+/// [`Channel::into_stream_with_stderr`].
 pub struct ChannelStream<S>
 where
     S: From<(ChannelId, ChannelMsg)> + 'static,
 {
     tx: ChannelTx<S>,
-    rx: ChannelRx<'static, S>,
+    rx: Arc<Mutex<Demux<S>>>,
+}
+
+/// The standard error of a [`ChannelStream`], returned by
+/// [`Channel::into_stream_with_stderr`]. It can be read independently
+/// of the stream, and ends at the same time.
+pub struct ChannelStderr<S>
+where
+    S: From<(ChannelId, ChannelMsg)> + 'static,
+{
+    rx: Arc<Mutex<Demux<S>>>,
+}
+
+/// Dispatches the messages of a channel between its standard output
+/// and standard error, whichever is being read.
+struct Demux<S>
+where
+    S: From<(ChannelId, ChannelMsg)>,
+{
+    channel: Channel<S>,
+    eof: bool,
+    stdout: Pending,
+    /// `None` if nobody reads standard error.
+    stderr: Option<Pending>,
+}
+
+/// Data received for one reader, but not read yet.
+#[derive(Default)]
+struct Pending {
+    data: VecDeque<CryptoVec>,
+    /// Position in the first buffer of `data`.
+    pos: usize,
+    waker: Option<Waker>,
+}
+
+impl Pending {
+    fn push(&mut self, data: CryptoVec) {
+        // An empty read would look like EOF.
+        if !data.is_empty() {
+            self.data.push_back(data);
+            self.wake()
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake()
+        }
+    }
+
+    /// Copy as much data as possible to `buf`, returning `false` if
+    /// there was nothing to copy.
+    fn read(&mut self, buf: &mut ReadBuf<'_>) -> bool {
+        let Some(data) = self.data.front() else {
+            return false;
+        };
+        let rest = data.get(self.pos..).unwrap_or(&[]);
+        let readable = buf.remaining().min(rest.len());
+        buf.put_slice(rest.get(..readable).unwrap_or(&[]));
+        self.pos += readable;
+        if self.pos >= data.len() {
+            self.data.pop_front();
+            self.pos = 0;
+        }
+        true
+    }
+}
+
+impl<S> Demux<S>
+where
+    S: From<(ChannelId, ChannelMsg)> + 'static + Send + Sync,
+{
+    fn pending(&mut self, stderr: bool) -> Option<&mut Pending> {
+        if stderr {
+            self.stderr.as_mut()
+        } else {
+            Some(&mut self.stdout)
+        }
+    }
+
+    fn poll_read(
+        &mut self,
+        stderr: bool,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if let Some(pending) = self.pending(stderr) {
+                if pending.read(buf) {
+                    return Poll::Ready(Ok(()));
+                }
+            }
+            if self.eof {
+                return Poll::Ready(Ok(()));
+            }
+            let msg = match self.channel.poll_recv(cx) {
+                Poll::Ready(msg) => msg,
+                Poll::Pending => {
+                    // Only the last task polling the receiver is woken up
+                    // by new messages, it then wakes the other reader up
+                    // when it receives data for it.
+                    if let Some(pending) = self.pending(stderr) {
+                        pending.waker = Some(cx.waker().clone());
+                    }
+                    return Poll::Pending;
+                }
+            };
+            match msg {
+                Some(ChannelMsg::Data { data }) => self.stdout.push(data),
+                Some(ChannelMsg::ExtendedData { data, ext }) if ext == ExtendedDataType::STDERR => {
+                    if let Some(ref mut pending) = self.stderr {
+                        pending.push(data)
+                    }
+                }
+                Some(ChannelMsg::Eof) | Some(ChannelMsg::Close) | None => {
+                    self.eof = true;
+                    self.channel.receiver.close();
+                    self.stdout.wake();
+                    if let Some(ref mut pending) = self.stderr {
+                        pending.wake()
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+fn poll_read_demux<S>(
+    rx: &Mutex<Demux<S>>,
+    stderr: bool,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+) -> Poll<io::Result<()>>
+where
+    S: From<(ChannelId, ChannelMsg)> + 'static + Send + Sync,
+{
+    match rx.lock() {
+        Ok(mut demux) => demux.poll_read(stderr, cx, buf),
+        Err(_) => Poll::Ready(Err(io::Error::other("channel reader poisoned"))),
+    }
 }
 
 impl<S> ChannelStream<S>
 where
     S: From<(ChannelId, ChannelMsg)>,
 {
-    pub(super) fn new(tx: ChannelTx<S>, rx: ChannelRx<'static, S>) -> Self {
-        Self { tx, rx }
+    pub(super) fn new(tx: ChannelTx<S>, channel: Channel<S>) -> Self {
+        Self {
+            tx,
+            rx: Arc::new(Mutex::new(Demux {
+                channel,
+                eof: false,
+                stdout: Pending::default(),
+                stderr: None,
+            })),
+        }
+    }
+
+    pub(super) fn with_stderr(tx: ChannelTx<S>, channel: Channel<S>) -> (Self, ChannelStderr<S>) {
+        let stream = Self::new(tx, channel);
+        if let Ok(mut demux) = stream.rx.lock() {
+            demux.stderr = Some(Pending::default())
+        }
+        let stderr = ChannelStderr {
+            rx: stream.rx.clone(),
+        };
+        (stream, stderr)
     }
 }
 
 impl<S> AsyncRead for ChannelStream<S>
 where
-    S: From<(ChannelId, ChannelMsg)>,
+    S: From<(ChannelId, ChannelMsg)> + 'static + Send + Sync,
 {
     fn poll_read(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
+        buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
-        Pin::new(&mut self.rx).poll_read(cx, buf)
+        poll_read_demux(&self.rx, false, cx, buf)
     }
 }
 
@@ -61,3 +229,28 @@ where
         Pin::new(&mut self.tx).poll_shutdown(cx)
     }
 }
+
+impl<S> AsyncRead for ChannelStderr<S>
+where
+    S: From<(ChannelId, ChannelMsg)> + 'static + Send + Sync,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        poll_read_demux(&self.rx, true, cx, buf)
+    }
+}
+
+impl<S> Drop for ChannelStderr<S>
+where
+    S: From<(ChannelId, ChannelMsg)>,
+{
+    fn drop(&mut self) {
+        // Stop buffering standard error.
+        if let Ok(mut demux) = self.rx.lock() {
+            demux.stderr = None
+        }
+    }
+}
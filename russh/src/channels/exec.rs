@@ -0,0 +1,167 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+
+use super::{Channel, ChannelMsg, ExtendedDataType};
+use crate::{ChannelId, CryptoVec, Error, Sig};
+
+/// How a remote command was killed by a signal.
+#[derive(Debug, Clone)]
+pub struct ExitSignal {
+    pub signal_name: Sig,
+    pub core_dumped: bool,
+    pub error_message: String,
+}
+
+/// The output of a command run with [`Channel::exec_collect`].
+#[derive(Debug, Clone, Default)]
+pub struct CommandOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// `None` if the server didn't send one, for instance because the
+    /// command was killed by a signal.
+    pub exit_status: Option<u32>,
+    pub exit_signal: Option<ExitSignal>,
+}
+
+impl CommandOutput {
+    /// Whether the command exited with status 0.
+    pub fn success(&self) -> bool {
+        self.exit_status == Some(0)
+    }
+}
+
+/// Something a remote command did, see [`Channel::exec_stream`].
+#[derive(Debug, Clone)]
+pub enum CommandEvent {
+    Stdout(CryptoVec),
+    Stderr(CryptoVec),
+    ExitStatus(u32),
+    ExitSignal(ExitSignal),
+}
+
+/// The output of a remote command, as a [`Stream`] of
+/// [`CommandEvent`]s, ending when the channel is closed.
+pub struct CommandStream<S: From<(ChannelId, ChannelMsg)>> {
+    channel: Channel<S>,
+    /// Events received before the reply to the exec request.
+    pending: VecDeque<CommandEvent>,
+    closed: bool,
+}
+
+impl<S: From<(ChannelId, ChannelMsg)>> std::fmt::Debug for CommandStream<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CommandStream")
+            .field("channel", &self.channel.id)
+            .finish()
+    }
+}
+
+impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> CommandStream<S> {
+    /// The channel, to send input to the command.
+    pub fn channel(&self) -> &Channel<S> {
+        &self.channel
+    }
+
+    /// The next event, or `None` once the channel is closed.
+    pub async fn next(&mut self) -> Option<CommandEvent> {
+        futures::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+/// What a channel message means for a command: `Some(None)` when the
+/// channel is closed.
+fn command_event(msg: Option<ChannelMsg>) -> Option<Option<CommandEvent>> {
+    match msg {
+        Some(ChannelMsg::Data { data }) => Some(Some(CommandEvent::Stdout(data))),
+        Some(ChannelMsg::ExtendedData {
+            data,
+            ext: ExtendedDataType::STDERR,
+        }) => Some(Some(CommandEvent::Stderr(data))),
+        Some(ChannelMsg::ExitStatus { exit_status }) => {
+            Some(Some(CommandEvent::ExitStatus(exit_status)))
+        }
+        Some(ChannelMsg::ExitSignal {
+            signal_name,
+            core_dumped,
+            error_message,
+            ..
+        }) => Some(Some(CommandEvent::ExitSignal(ExitSignal {
+            signal_name,
+            core_dumped,
+            error_message,
+        }))),
+        Some(ChannelMsg::Close) | None => Some(None),
+        Some(_) => None,
+    }
+}
+
+impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Stream for CommandStream<S> {
+    type Item = CommandEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Poll::Ready(Some(event));
+        }
+        while !self.closed {
+            match command_event(futures::ready!(self.channel.poll_recv(cx))) {
+                Some(Some(event)) => return Poll::Ready(Some(event)),
+                Some(None) => self.closed = true,
+                None => {}
+            }
+        }
+        Poll::Ready(None)
+    }
+}
+
+impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
+    /// Run `command`, and return its events as they come. Fails if the
+    /// server refuses to run it.
+    pub async fn exec_stream<A: Into<Vec<u8>>>(
+        mut self,
+        command: A,
+    ) -> Result<CommandStream<S>, Error> {
+        self.exec(true, command).await?;
+        let mut pending = VecDeque::new();
+        let mut closed = false;
+        loop {
+            match self.wait().await {
+                Some(ChannelMsg::Success) => break,
+                Some(ChannelMsg::Failure) => return Err(Error::RequestDenied),
+                msg => match command_event(msg) {
+                    Some(Some(event)) => pending.push_back(event),
+                    Some(None) => {
+                        closed = true;
+                        break;
+                    }
+                    None => {}
+                },
+            }
+        }
+        Ok(CommandStream {
+            channel: self,
+            pending,
+            closed,
+        })
+    }
+
+    /// Run `command`, wait until it exits and the channel is closed,
+    /// and return everything it printed, with its exit status.
+    pub async fn exec_collect<A: Into<Vec<u8>>>(self, command: A) -> Result<CommandOutput, Error> {
+        let mut events = self.exec_stream(command).await?;
+        // Nothing more will be sent to the command.
+        events.channel.eof().await?;
+        let mut output = CommandOutput::default();
+        while let Some(event) = events.next().await {
+            match event {
+                CommandEvent::Stdout(data) => output.stdout.extend_from_slice(&data),
+                CommandEvent::Stderr(data) => output.stderr.extend_from_slice(&data),
+                CommandEvent::ExitStatus(status) => output.exit_status = Some(status),
+                CommandEvent::ExitSignal(signal) => output.exit_signal = Some(signal),
+            }
+        }
+        Ok(output)
+    }
+}
@@ -5,6 +5,8 @@ use std::task::{ready, Context, Poll};
 use tokio::io::AsyncRead;
 
 use super::{ChannelAsMut, ChannelMsg};
+use crate::channels::channel_ref::msg_len;
+use crate::channels::ExtendedDataType;
 use crate::ChannelId;
 
 #[derive(Debug)]
@@ -15,20 +17,69 @@ where
     channel: ChannelAsMut<'i, S>,
     buffer: Option<(ChannelMsg, usize)>,
 
-    ext: Option<u32>,
+    ext: Option<ExtendedDataType>,
 }
 
 impl<'i, S> ChannelRx<'i, S>
 where
     S: From<(ChannelId, ChannelMsg)>,
 {
-    pub fn new(channel: impl Into<ChannelAsMut<'i, S>>, ext: Option<u32>) -> Self {
+    pub fn new(channel: impl Into<ChannelAsMut<'i, S>>, ext: Option<ExtendedDataType>) -> Self {
         Self {
             channel: channel.into(),
             buffer: None,
             ext,
         }
     }
+
+    /// Receive the next message of the stream read, keeping the other
+    /// ones in the channel for its other readers.
+    fn poll_next_msg(&mut self, cx: &mut Context<'_>) -> Poll<Option<ChannelMsg>> {
+        let ext = self.ext;
+        let kept = self
+            .channel
+            .as_mut()
+            .pending
+            .iter()
+            .position(|msg| is_read(msg, ext));
+        if let Some(i) = kept {
+            let pending = &mut self.channel.as_mut().pending;
+            // The end of the channel is also kept for the other readers.
+            if let Some(ChannelMsg::Eof | ChannelMsg::Close) = pending.get(i) {
+                return Poll::Ready(Some(ChannelMsg::Eof));
+            }
+            let msg = pending.remove(i);
+            if let Some(ref msg) = msg {
+                self.channel.as_mut().window_size.read(msg_len(msg))
+            }
+            return Poll::Ready(msg);
+        }
+        loop {
+            match ready!(self.channel.as_mut().receiver.poll_recv(cx)) {
+                Some(msg @ (ChannelMsg::Eof | ChannelMsg::Close)) => {
+                    self.channel.as_mut().pending.push_back(msg);
+                    return Poll::Ready(Some(ChannelMsg::Eof));
+                }
+                Some(msg) if is_read(&msg, ext) => {
+                    self.channel.as_mut().window_size.read(msg_len(&msg));
+                    return Poll::Ready(Some(msg));
+                }
+                Some(msg) => self.channel.as_mut().pending.push_back(msg),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// Whether `msg` is data of the stream `ext`, or the end of the
+/// channel.
+fn is_read(msg: &ChannelMsg, ext: Option<ExtendedDataType>) -> bool {
+    match (msg, ext) {
+        (ChannelMsg::Data { .. }, None) => true,
+        (ChannelMsg::ExtendedData { ext, .. }, Some(target)) => *ext == target,
+        (ChannelMsg::Eof | ChannelMsg::Close, _) => true,
+        _ => false,
+    }
 }
 
 impl<'i, S> AsyncRead for ChannelRx<'i, S>
@@ -42,14 +93,14 @@ where
     ) -> Poll<io::Result<()>> {
         let (msg, mut idx) = match self.buffer.take() {
             Some(msg) => msg,
-            None => match ready!(self.channel.as_mut().receiver.poll_recv(cx)) {
+            None => match ready!(self.poll_next_msg(cx)) {
                 Some(msg) => (msg, 0),
                 None => return Poll::Ready(Ok(())),
             },
         };
 
-        match (&msg, self.ext) {
-            (ChannelMsg::Data { data }, None) => {
+        match &msg {
+            ChannelMsg::Data { data } | ChannelMsg::ExtendedData { data, .. } => {
                 let readable = buf.remaining().min(data.len() - idx);
 
                 // Clamped to maximum `buf.remaining()` and `data.len() - idx` with `.min`
@@ -63,29 +114,7 @@ where
 
                 Poll::Ready(Ok(()))
             }
-            (ChannelMsg::ExtendedData { data, ext }, Some(target)) if *ext == target => {
-                let readable = buf.remaining().min(data.len() - idx);
-
-                // Clamped to maximum `buf.remaining()` and `data.len() - idx` with `.min`
-                #[allow(clippy::indexing_slicing)]
-                buf.put_slice(&data[idx..idx + readable]);
-                idx += readable;
-
-                if idx != data.len() {
-                    self.buffer = Some((msg, idx));
-                }
-
-                Poll::Ready(Ok(()))
-            }
-            (ChannelMsg::Eof, _) => {
-                self.channel.as_mut().receiver.close();
-
-                Poll::Ready(Ok(()))
-            }
-            _ => {
-                cx.waker().wake_by_ref();
-                Poll::Pending
-            }
+            _ => Poll::Ready(Ok(())),
         }
     }
 }
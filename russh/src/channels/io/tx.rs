@@ -1,15 +1,16 @@
 use std::io;
 use std::pin::Pin;
-use std::sync::Arc;
 use std::task::{ready, Context, Poll};
 
 use futures::FutureExt;
 use tokio::io::AsyncWrite;
 use tokio::sync::mpsc::error::SendError;
 use tokio::sync::mpsc::{self, OwnedPermit};
-use tokio::sync::{Mutex, OwnedMutexGuard};
+use tokio::sync::{watch, OwnedMutexGuard};
 
+use super::super::WindowSizeRef;
 use super::ChannelMsg;
+use crate::channels::ExtendedDataType;
 use crate::{ChannelId, CryptoVec};
 
 type BoxedThreadsafeFuture<T> = Pin<Box<dyn Sync + Send + std::future::Future<Output = T>>>;
@@ -22,9 +23,11 @@ pub struct ChannelTx<S> {
     id: ChannelId,
 
     window_size_fut: Option<BoxedThreadsafeFuture<OwnedMutexGuard<u32>>>,
-    window_size: Arc<Mutex<u32>>,
+    window_size: WindowSizeRef,
+    changes: watch::Receiver<()>,
+    changed_fut: Option<BoxedThreadsafeFuture<()>>,
     max_packet_size: u32,
-    ext: Option<u32>,
+    ext: Option<ExtendedDataType>,
 }
 
 impl<S> ChannelTx<S>
@@ -34,14 +37,16 @@ where
     pub fn new(
         sender: mpsc::Sender<S>,
         id: ChannelId,
-        window_size: Arc<Mutex<u32>>,
+        window_size: WindowSizeRef,
         max_packet_size: u32,
-        ext: Option<u32>,
+        ext: Option<ExtendedDataType>,
     ) -> Self {
         Self {
             sender,
             send_fut: None,
             id,
+            changes: window_size.changes(),
+            changed_fut: None,
             window_size,
             window_size_fut: None,
             max_packet_size,
@@ -50,21 +55,36 @@ where
     }
 
     fn poll_mk_msg(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<(ChannelMsg, usize)> {
-        let window_size = self.window_size.clone();
-        let window_size_fut = self
-            .window_size_fut
-            .get_or_insert_with(|| Box::pin(window_size.lock_owned()));
-        let mut window_size = ready!(window_size_fut.poll_unpin(cx));
-        self.window_size_fut.take();
-
-        let writable = (self.max_packet_size)
-            .min(*window_size)
-            .min(buf.len() as u32) as usize;
-        if writable == 0 {
-            // TODO fix this busywait
-            cx.waker().wake_by_ref();
-            return Poll::Pending;
-        }
+        let (mut window_size, writable) = loop {
+            if let Some(changed_fut) = self.changed_fut.as_mut() {
+                ready!(changed_fut.poll_unpin(cx));
+                self.changed_fut = None;
+            }
+            // Any change from now on wakes us up.
+            self.changes.borrow_and_update();
+
+            let window_size = self.window_size.value().clone();
+            let window_size_fut = self
+                .window_size_fut
+                .get_or_insert_with(|| Box::pin(window_size.lock_owned()));
+            let window_size = ready!(window_size_fut.poll_unpin(cx));
+            self.window_size_fut.take();
+
+            let writable = (self.max_packet_size)
+                .min(*window_size)
+                .min(self.window_size.below_high_water_mark())
+                .min(buf.len() as u32) as usize;
+            if writable > 0 {
+                break (window_size, writable);
+            }
+            // Wait for the other side to adjust the window, or for the
+            // session to send what is queued.
+            drop(window_size);
+            let mut changes = self.changes.clone();
+            self.changed_fut = Some(Box::pin(async move {
+                let _ = changes.changed().await;
+            }));
+        };
         let mut data = CryptoVec::new_zeroed(writable);
         #[allow(clippy::indexing_slicing)] // Clamped to maximum `buf.len()` with `.min`
         data.copy_from_slice(&buf[..writable]);
@@ -116,6 +136,9 @@ where
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
+        if buf.is_empty() && self.send_fut.is_none() {
+            return Poll::Ready(Ok(0));
+        }
         let send_fut = if let Some(x) = self.send_fut.as_mut() {
             x
         } else {
@@ -134,12 +157,60 @@ where
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), io::Error>> {
-        let send_fut = if let Some(x) = self.send_fut.as_mut() {
-            x
-        } else {
-            self.activate(ChannelMsg::Eof, 0)
-        };
-        let r = ready!(send_fut.as_mut().poll_unpin(cx)).map(|(p, _, _)| (p, ChannelMsg::Eof, 0));
-        Poll::Ready(self.handle_write_result(r).map(drop))
+        // A write in progress is completed before sending EOF.
+        loop {
+            let send_fut = if let Some(x) = self.send_fut.as_mut() {
+                x
+            } else {
+                self.activate(ChannelMsg::Eof, 0)
+            };
+            let r = ready!(send_fut.as_mut().poll_unpin(cx));
+            let eof = matches!(r, Ok((_, ChannelMsg::Eof, _)));
+            self.handle_write_result(r)?;
+            if eof {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_waits_for_window() {
+        let (sender, mut receiver) = mpsc::channel::<(ChannelId, ChannelMsg)>(10);
+        let window_size = WindowSizeRef::new(0);
+        let mut tx = ChannelTx::new(sender, ChannelId(0), window_size.clone(), 4, None);
+        let write = tokio::spawn(async move { tx.write_all(b"hello").await.unwrap() });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(receiver.try_recv().is_err());
+        window_size.update(3).await;
+        match receiver.recv().await.unwrap() {
+            (_, ChannelMsg::Data { data }) => assert_eq!(&data[..], b"hel"),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        // The window is open, but too much is queued.
+        window_size.set_high_water_mark(Some(2));
+        window_size.set_queued(2);
+        window_size.update(10).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(receiver.try_recv().is_err());
+
+        window_size.set_queued(0);
+        match receiver.recv().await.unwrap() {
+            (_, ChannelMsg::Data { data }) => assert_eq!(&data[..], b"lo"),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        write.await.unwrap();
     }
 }
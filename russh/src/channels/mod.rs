@@ -1,18 +1,22 @@
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::task::{ready, Context, Poll};
 
+use futures::{Stream, StreamExt};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::mpsc::{Sender, UnboundedReceiver};
-use tokio::sync::Mutex;
 
-use crate::{ChannelId, ChannelOpenFailure, CryptoVec, Error, Pty, Sig};
+use crate::{ChannelId, ChannelOpenFailure, CryptoVec, Error, Pty, Sig, TerminalSize};
 
 pub mod io;
 
 mod channel_ref;
-pub use channel_ref::ChannelRef;
+pub use channel_ref::{ChannelRef, WindowSizeRef};
 
 mod channel_stream;
-pub use channel_stream::ChannelStream;
+pub use channel_stream::{ChannelStderr, ChannelStream};
+
+mod exec;
+pub use exec::{CommandEvent, CommandOutput, CommandStream, ExitSignal};
 
 #[derive(Debug)]
 #[non_exhaustive]
@@ -28,7 +32,7 @@ pub enum ChannelMsg {
     },
     ExtendedData {
         data: CryptoVec,
-        ext: u32,
+        ext: ExtendedDataType,
     },
     Eof,
     Close,
@@ -55,6 +59,12 @@ pub enum ChannelMsg {
     Signal {
         signal: Sig,
     },
+    /// (client only) A break of `length` milliseconds on the serial
+    /// line or terminal (RFC 4335).
+    Break {
+        want_reply: bool,
+        length: u32,
+    },
     /// (client only)
     RequestSubsystem {
         want_reply: bool,
@@ -112,6 +122,91 @@ pub enum ChannelMsg {
     OpenFailure(ChannelOpenFailure),
 }
 
+/// The type of the data of a [`ChannelMsg::ExtendedData`], see
+/// [RFC4254](https://tools.ietf.org/html/rfc4254#section-5.2). The only
+/// type defined by the RFC is [`ExtendedDataType::STDERR`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ExtendedDataType(pub u32);
+
+impl ExtendedDataType {
+    /// Standard error.
+    pub const STDERR: ExtendedDataType = ExtendedDataType(1);
+}
+
+impl From<u32> for ExtendedDataType {
+    fn from(code: u32) -> Self {
+        ExtendedDataType(code)
+    }
+}
+
+impl From<ExtendedDataType> for u32 {
+    fn from(ext: ExtendedDataType) -> Self {
+        ext.0
+    }
+}
+
+/// Sends window changes for a channel, see [`Channel::window_resizer`].
+pub struct WindowResizer<S: From<(ChannelId, ChannelMsg)>> {
+    id: ChannelId,
+    sender: Sender<S>,
+}
+
+impl<S: From<(ChannelId, ChannelMsg)>> Clone for WindowResizer<S> {
+    fn clone(&self) -> Self {
+        WindowResizer {
+            id: self.id,
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<S: From<(ChannelId, ChannelMsg)>> std::fmt::Debug for WindowResizer<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WindowResizer")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl<S: From<(ChannelId, ChannelMsg)>> WindowResizer<S> {
+    /// Inform the server that the size of the terminal has changed.
+    pub async fn resize(&self, size: TerminalSize) -> Result<(), Error> {
+        self.sender
+            .send(
+                (
+                    self.id,
+                    ChannelMsg::WindowChange {
+                        col_width: size.col_width,
+                        row_height: size.row_height,
+                        pix_width: size.pix_width,
+                        pix_height: size.pix_height,
+                    },
+                )
+                    .into(),
+            )
+            .await
+            .map_err(|_| Error::SendError)
+    }
+
+    /// Send the sizes produced by a terminal size watcher, such as a
+    /// stream of sizes read on each `SIGWINCH`, skipping the ones that
+    /// did not change. This returns when `sizes` ends, or when the
+    /// session is closed.
+    pub async fn follow<W: Stream<Item = TerminalSize> + Unpin>(
+        &self,
+        mut sizes: W,
+    ) -> Result<(), Error> {
+        let mut last = None;
+        while let Some(size) = sizes.next().await {
+            if last != Some(size) {
+                self.resize(size).await?;
+                last = Some(size);
+            }
+        }
+        Ok(())
+    }
+}
+
 /// A handle to a session channel.
 ///
 /// Allows you to read and write from a channel without borrowing the session
@@ -120,7 +215,10 @@ pub struct Channel<Send: From<(ChannelId, ChannelMsg)>> {
     pub(crate) sender: Sender<Send>,
     pub(crate) receiver: UnboundedReceiver<ChannelMsg>,
     pub(crate) max_packet_size: u32,
-    pub(crate) window_size: Arc<Mutex<u32>>,
+    pub(crate) window_size: WindowSizeRef,
+    /// Messages received by a reader of one stream, and kept for the
+    /// readers of the other streams.
+    pub(crate) pending: VecDeque<ChannelMsg>,
 }
 
 impl<T: From<(ChannelId, ChannelMsg)>> std::fmt::Debug for Channel<T> {
@@ -137,7 +235,7 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
         window_size: u32,
     ) -> (Self, ChannelRef) {
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-        let window_size = Arc::new(Mutex::new(window_size));
+        let window_size = WindowSizeRef::new(window_size);
 
         (
             Self {
@@ -146,6 +244,7 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
                 receiver: rx,
                 max_packet_size,
                 window_size: window_size.clone(),
+                pending: VecDeque::new(),
             },
             ChannelRef {
                 sender: tx,
@@ -157,7 +256,18 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
     /// Returns the min between the maximum packet size and the
     /// remaining window size in the channel.
     pub async fn writable_packet_size(&self) -> usize {
-        self.max_packet_size.min(*self.window_size.lock().await) as usize
+        self.max_packet_size
+            .min(*self.window_size.value().lock().await) as usize
+    }
+
+    /// Limit the amount of data written to this channel, but not yet
+    /// sent by the session, to `high_water_mark` bytes. Writes through
+    /// [`Channel::data`] or the channel's [`AsyncWrite`] adapters wait
+    /// for the session to send the data, in addition to waiting for
+    /// the other side's window. By default (`None`), writes only wait
+    /// for the window.
+    pub fn set_write_high_water_mark(&self, high_water_mark: Option<u32>) {
+        self.window_size.set_high_water_mark(high_water_mark)
     }
 
     pub fn id(&self) -> ChannelId {
@@ -165,6 +275,7 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
     }
 
     /// Request a pseudo-terminal with the given characteristics.
+    /// `terminal_modes` is usually a [`crate::PtyModes`].
     #[allow(clippy::too_many_arguments)] // length checked
     pub async fn request_pty(
         &self,
@@ -204,11 +315,20 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
         .await
     }
 
-    /// Signal a remote process.
+    /// Signal a remote process, such as [`Sig::INT`] when Ctrl-C is
+    /// pressed without a terminal.
     pub async fn signal(&self, signal: Sig) -> Result<(), Error> {
         self.send_msg(ChannelMsg::Signal { signal }).await
     }
 
+    /// Send a break of `length` milliseconds, for instance to a serial
+    /// console (RFC 4335). With `want_reply`, the server replies with
+    /// [`ChannelMsg::Success`] if it performed the break.
+    pub async fn send_break(&self, want_reply: bool, length: u32) -> Result<(), Error> {
+        self.send_msg(ChannelMsg::Break { want_reply, length })
+            .await
+    }
+
     /// Request the start of a subsystem with the given name.
     pub async fn request_subsystem<A: Into<String>>(
         &self,
@@ -259,6 +379,23 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
         .await
     }
 
+    /// Set several remote environment variables, usually right before
+    /// requesting a shell or a command, for instance the ones returned
+    /// by `russh_config::Config::environment`. With `want_reply`, the
+    /// server answers each of them with [`ChannelMsg::Success`] or
+    /// [`ChannelMsg::Failure`], in order.
+    pub async fn set_envs<I, A, B>(&self, want_reply: bool, variables: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (A, B)>,
+        A: Into<String>,
+        B: Into<String>,
+    {
+        for (name, value) in variables {
+            self.set_env(want_reply, name, value).await?;
+        }
+        Ok(())
+    }
+
     /// Inform the server that our window size has changed.
     pub async fn window_change(
         &self,
@@ -276,12 +413,24 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
         .await
     }
 
+    /// A handle to send window changes for this channel, for instance
+    /// from a task watching the size of the local terminal, while the
+    /// channel itself is used elsewhere.
+    pub fn window_resizer(&self) -> WindowResizer<S> {
+        WindowResizer {
+            id: self.id,
+            sender: self.sender.clone(),
+        }
+    }
+
     /// Inform the server that we will accept agent forwarding channels
     pub async fn agent_forward(&self, want_reply: bool) -> Result<(), Error> {
         self.send_msg(ChannelMsg::AgentForward { want_reply }).await
     }
 
-    /// Send data to a channel.
+    /// Send data to a channel. This waits for the other side to open
+    /// its window when it is full, so that `data` is never read faster
+    /// than it can be sent.
     pub async fn data<R: tokio::io::AsyncRead + Unpin>(&self, data: R) -> Result<(), Error> {
         self.send_data(None, data).await
     }
@@ -294,15 +443,15 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
         ext: u32,
         data: R,
     ) -> Result<(), Error> {
-        self.send_data(Some(ext), data).await
+        self.send_data(Some(ExtendedDataType(ext)), data).await
     }
 
     async fn send_data<R: tokio::io::AsyncRead + Unpin>(
         &self,
-        ext: Option<u32>,
+        ext: Option<ExtendedDataType>,
         mut data: R,
     ) -> Result<(), Error> {
-        let mut tx = self.make_writer_ext(ext);
+        let mut tx = self.make_tx(ext);
 
         tokio::io::copy(&mut data, &mut tx).await?;
 
@@ -327,26 +476,53 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
 
     /// Awaits an incoming [`ChannelMsg`], this method returns [`None`] if the channel has been closed.
     pub async fn wait(&mut self) -> Option<ChannelMsg> {
-        self.receiver.recv().await
+        futures::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Receive the next message, starting with the ones kept by
+    /// readers.
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<ChannelMsg>> {
+        let msg = match self.pending.pop_front() {
+            Some(msg) => Some(msg),
+            None => ready!(self.receiver.poll_recv(cx)),
+        };
+        if let Some(ref msg) = msg {
+            self.window_size.read(channel_ref::msg_len(msg))
+        }
+        Poll::Ready(msg)
     }
 
     /// Consume the [`Channel`] to produce a bidirectionnal stream,
     /// sending and receiving [`ChannelMsg::Data`] as `AsyncRead` + `AsyncWrite`.
+    ///
+    /// Shutting the stream down sends EOF, and reads end when the other
+    /// side sends EOF or closes the channel.
     pub fn into_stream(self) -> ChannelStream<S> {
-        ChannelStream::new(
-            io::ChannelTx::new(
-                self.sender.clone(),
-                self.id,
-                self.window_size.clone(),
-                self.max_packet_size,
-                None,
-            ),
-            io::ChannelRx::new(self, None),
+        ChannelStream::new(self.make_tx(None), self)
+    }
+
+    /// Like [`Channel::into_stream`], but standard error
+    /// ([`ChannelMsg::ExtendedData`] with [`ExtendedDataType::STDERR`])
+    /// is also returned, as a separate reader.
+    pub fn into_stream_with_stderr(self) -> (ChannelStream<S>, ChannelStderr<S>) {
+        ChannelStream::with_stderr(self.make_tx(None), self)
+    }
+
+    fn make_tx(&self, ext: Option<ExtendedDataType>) -> io::ChannelTx<S> {
+        io::ChannelTx::new(
+            self.sender.clone(),
+            self.id,
+            self.window_size.clone(),
+            self.max_packet_size,
+            ext,
         )
     }
 
     /// Make a reader for the [`Channel`] to receive [`ChannelMsg::Data`]
     /// through the `AsyncRead` trait.
+    ///
+    /// The other messages received meanwhile, including standard
+    /// error, are kept for the other readers and [`Channel::wait`].
     pub fn make_reader(&mut self) -> impl AsyncRead + '_ {
         self.make_reader_ext(None)
     }
@@ -354,7 +530,14 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
     /// Make a reader for the [`Channel`] to receive [`ChannelMsg::Data`] or [`ChannelMsg::ExtendedData`]
     /// depending on the `ext` parameter, through the `AsyncRead` trait.
     pub fn make_reader_ext(&mut self, ext: Option<u32>) -> impl AsyncRead + '_ {
-        io::ChannelRx::new(self, ext)
+        io::ChannelRx::new(self, ext.map(ExtendedDataType))
+    }
+
+    /// Make a reader for the standard error of the [`Channel`], like
+    /// [`Channel::make_reader`] for standard output. To read both at
+    /// the same time, use [`Channel::into_stream_with_stderr`].
+    pub fn stderr(&mut self) -> impl AsyncRead + '_ {
+        io::ChannelRx::new(self, Some(ExtendedDataType::STDERR))
     }
 
     /// Make a writer for the [`Channel`] to send [`ChannelMsg::Data`]
@@ -366,12 +549,13 @@ impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> Channel<S> {
     /// Make a writer for the [`Channel`] to send [`ChannelMsg::Data`] or [`ChannelMsg::ExtendedData`]
     /// depending on the `ext` parameter, through the `AsyncWrite` trait.
     pub fn make_writer_ext(&self, ext: Option<u32>) -> impl AsyncWrite {
-        io::ChannelTx::new(
-            self.sender.clone(),
-            self.id,
-            self.window_size.clone(),
-            self.max_packet_size,
-            ext,
-        )
+        self.make_tx(ext.map(ExtendedDataType))
+    }
+
+    /// Make a writer for the standard error of the [`Channel`]. It
+    /// shares the window of the channel with the writers of standard
+    /// output, and waits for it to open in the same way.
+    pub fn stderr_writer(&self) -> impl AsyncWrite {
+        self.make_tx(Some(ExtendedDataType::STDERR))
     }
 }
@@ -17,27 +17,23 @@
 
 use std::convert::TryInto;
 
-use aes_gcm::{AeadCore, AeadInPlace, Aes256Gcm, KeyInit, KeySizeUser};
-use digest::typenum::Unsigned;
-use generic_array::GenericArray;
 use rand::RngCore;
 
 use super::super::Error;
+use crate::backend::{AesGcm, GCM_NONCE_LEN, GCM_TAG_LEN};
 use crate::mac::MacAlgorithm;
 
-pub struct GcmCipher {}
-
-type KeySize = <Aes256Gcm as KeySizeUser>::KeySize;
-type NonceSize = <Aes256Gcm as AeadCore>::NonceSize;
-type TagSize = <Aes256Gcm as AeadCore>::TagSize;
+/// `aes128-gcm@openssh.com` or `aes256-gcm@openssh.com`, with a key
+/// of `.0` bytes.
+pub struct GcmCipher(pub usize);
 
 impl super::Cipher for GcmCipher {
     fn key_len(&self) -> usize {
-        Aes256Gcm::key_size()
+        self.0
     }
 
     fn nonce_len(&self) -> usize {
-        GenericArray::<u8, NonceSize>::default().len()
+        GCM_NONCE_LEN
     }
 
     fn make_opening_key(
@@ -47,13 +43,11 @@ impl super::Cipher for GcmCipher {
         _: &[u8],
         _: &dyn MacAlgorithm,
     ) -> Box<dyn super::OpeningKey + Send> {
-        let mut key = GenericArray::<u8, KeySize>::default();
-        key.clone_from_slice(k);
-        let mut nonce = GenericArray::<u8, NonceSize>::default();
+        let mut nonce = [0; GCM_NONCE_LEN];
         nonce.clone_from_slice(n);
         Box::new(OpeningKey {
             nonce,
-            cipher: Aes256Gcm::new(&key),
+            cipher: AesGcm::new(k),
         })
     }
 
@@ -64,28 +58,26 @@ impl super::Cipher for GcmCipher {
         _: &[u8],
         _: &dyn MacAlgorithm,
     ) -> Box<dyn super::SealingKey + Send> {
-        let mut key = GenericArray::<u8, KeySize>::default();
-        key.clone_from_slice(k);
-        let mut nonce = GenericArray::<u8, NonceSize>::default();
+        let mut nonce = [0; GCM_NONCE_LEN];
         nonce.clone_from_slice(n);
         Box::new(SealingKey {
             nonce,
-            cipher: Aes256Gcm::new(&key),
+            cipher: AesGcm::new(k),
         })
     }
 }
 
 pub struct OpeningKey {
-    nonce: GenericArray<u8, NonceSize>,
-    cipher: Aes256Gcm,
+    nonce: [u8; GCM_NONCE_LEN],
+    cipher: AesGcm,
 }
 
 pub struct SealingKey {
-    nonce: GenericArray<u8, NonceSize>,
-    cipher: Aes256Gcm,
+    nonce: [u8; GCM_NONCE_LEN],
+    cipher: AesGcm,
 }
 
-fn inc_nonce(nonce: &mut GenericArray<u8, NonceSize>) {
+fn inc_nonce(nonce: &mut [u8; GCM_NONCE_LEN]) {
     let mut carry = 1;
     #[allow(clippy::indexing_slicing)] // length checked
     for i in (0..nonce.len()).rev() {
@@ -107,7 +99,7 @@ impl super::OpeningKey for OpeningKey {
     }
 
     fn tag_len(&self) -> usize {
-        TagSize::to_usize()
+        GCM_TAG_LEN
     }
 
     fn open<'a>(
@@ -122,23 +114,13 @@ impl super::OpeningKey for OpeningKey {
         #[allow(clippy::indexing_slicing)] // length checked
         packet_length.clone_from_slice(&ciphertext_in_plaintext_out[..super::PACKET_LENGTH_LEN]);
 
-        let mut buffer = vec![0; ciphertext_in_plaintext_out.len() - super::PACKET_LENGTH_LEN];
-
-        #[allow(clippy::indexing_slicing)] // length checked
-        buffer.copy_from_slice(&ciphertext_in_plaintext_out[super::PACKET_LENGTH_LEN..]);
-
-        let mut tag_buf = GenericArray::<u8, TagSize>::default();
-        tag_buf.clone_from_slice(tag);
-
         #[allow(clippy::indexing_slicing)]
-        self.cipher
-            .decrypt_in_place_detached(
-                &self.nonce,
-                &packet_length,
-                &mut ciphertext_in_plaintext_out[super::PACKET_LENGTH_LEN..],
-                &tag_buf,
-            )
-            .map_err(|_| Error::DecryptionError)?;
+        self.cipher.open(
+            &self.nonce,
+            &packet_length,
+            &mut ciphertext_in_plaintext_out[super::PACKET_LENGTH_LEN..],
+            tag,
+        )?;
 
         inc_nonce(&mut self.nonce);
 
@@ -168,7 +150,7 @@ impl super::SealingKey for SealingKey {
     }
 
     fn tag_len(&self) -> usize {
-        TagSize::to_usize()
+        GCM_TAG_LEN
     }
 
     fn seal(
@@ -182,15 +164,14 @@ impl super::SealingKey for SealingKey {
         #[allow(clippy::indexing_slicing)] // length checked
         packet_length.clone_from_slice(&plaintext_in_ciphertext_out[..super::PACKET_LENGTH_LEN]);
 
-        #[allow(clippy::indexing_slicing, clippy::unwrap_used)]
-        let tag_out = self
-            .cipher
-            .encrypt_in_place_detached(
-                &self.nonce,
-                &packet_length,
-                &mut plaintext_in_ciphertext_out[super::PACKET_LENGTH_LEN..],
-            )
-            .unwrap();
+        let mut tag_out = [0; GCM_TAG_LEN];
+        #[allow(clippy::indexing_slicing)]
+        self.cipher.seal(
+            &self.nonce,
+            &packet_length,
+            &mut plaintext_in_ciphertext_out[super::PACKET_LENGTH_LEN..],
+            &mut tag_out,
+        );
 
         inc_nonce(&mut self.nonce);
         tag.clone_from_slice(&tag_out)
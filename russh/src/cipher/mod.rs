@@ -23,20 +23,24 @@ use std::num::Wrapping;
 
 use aes::{Aes128, Aes192, Aes256};
 use byteorder::{BigEndian, ByteOrder};
+#[cfg(feature = "legacy-ciphers")]
 use cbc::CbcWrapper;
 use ctr::Ctr128BE;
 use delegate::delegate;
+#[cfg(feature = "legacy-ciphers")]
 use des::TdesEde3;
-use log::debug;
 use once_cell::sync::Lazy;
 use ssh_encoding::Encode;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
+use crate::logging::debug;
 use crate::mac::MacAlgorithm;
+use crate::observer::{observe, Direction};
 use crate::sshbuffer::SSHBuffer;
 use crate::Error;
 
 pub(crate) mod block;
+#[cfg(feature = "legacy-ciphers")]
 pub(crate) mod cbc;
 pub(crate) mod chacha20poly1305;
 pub(crate) mod clear;
@@ -73,20 +77,26 @@ pub(crate) trait Cipher {
 
 /// `clear`
 pub const CLEAR: Name = Name("clear");
+#[cfg(feature = "legacy-ciphers")]
 /// `3des-cbc`
 pub const TRIPLE_DES_CBC: Name = Name("3des-cbc");
 /// `aes128-ctr`
 pub const AES_128_CTR: Name = Name("aes128-ctr");
 /// `aes192-ctr`
 pub const AES_192_CTR: Name = Name("aes192-ctr");
+#[cfg(feature = "legacy-ciphers")]
 /// `aes128-cbc`
 pub const AES_128_CBC: Name = Name("aes128-cbc");
+#[cfg(feature = "legacy-ciphers")]
 /// `aes192-cbc`
 pub const AES_192_CBC: Name = Name("aes192-cbc");
+#[cfg(feature = "legacy-ciphers")]
 /// `aes256-cbc`
 pub const AES_256_CBC: Name = Name("aes256-cbc");
 /// `aes256-ctr`
 pub const AES_256_CTR: Name = Name("aes256-ctr");
+/// `aes128-gcm@openssh.com`
+pub const AES_128_GCM: Name = Name("aes128-gcm@openssh.com");
 /// `aes256-gcm@openssh.com`
 pub const AES_256_GCM: Name = Name("aes256-gcm@openssh.com");
 /// `chacha20-poly1305@openssh.com`
@@ -95,26 +105,36 @@ pub const CHACHA20_POLY1305: Name = Name("chacha20-poly1305@openssh.com");
 pub const NONE: Name = Name("none");
 
 static _CLEAR: Clear = Clear {};
+#[cfg(feature = "legacy-ciphers")]
 static _3DES_CBC: SshBlockCipher<CbcWrapper<TdesEde3>> = SshBlockCipher(PhantomData);
 static _AES_128_CTR: SshBlockCipher<Ctr128BE<Aes128>> = SshBlockCipher(PhantomData);
 static _AES_192_CTR: SshBlockCipher<Ctr128BE<Aes192>> = SshBlockCipher(PhantomData);
 static _AES_256_CTR: SshBlockCipher<Ctr128BE<Aes256>> = SshBlockCipher(PhantomData);
-static _AES_256_GCM: GcmCipher = GcmCipher {};
+static _AES_128_GCM: GcmCipher = GcmCipher(16);
+static _AES_256_GCM: GcmCipher = GcmCipher(32);
+#[cfg(feature = "legacy-ciphers")]
 static _AES_128_CBC: SshBlockCipher<CbcWrapper<Aes128>> = SshBlockCipher(PhantomData);
+#[cfg(feature = "legacy-ciphers")]
 static _AES_192_CBC: SshBlockCipher<CbcWrapper<Aes192>> = SshBlockCipher(PhantomData);
+#[cfg(feature = "legacy-ciphers")]
 static _AES_256_CBC: SshBlockCipher<CbcWrapper<Aes256>> = SshBlockCipher(PhantomData);
 static _CHACHA20_POLY1305: SshChacha20Poly1305Cipher = SshChacha20Poly1305Cipher {};
 
 pub static ALL_CIPHERS: &[&Name] = &[
     &CLEAR,
     &NONE,
+    #[cfg(feature = "legacy-ciphers")]
     &TRIPLE_DES_CBC,
     &AES_128_CTR,
     &AES_192_CTR,
     &AES_256_CTR,
+    &AES_128_GCM,
     &AES_256_GCM,
+    #[cfg(feature = "legacy-ciphers")]
     &AES_128_CBC,
+    #[cfg(feature = "legacy-ciphers")]
     &AES_192_CBC,
+    #[cfg(feature = "legacy-ciphers")]
     &AES_256_CBC,
     &CHACHA20_POLY1305,
 ];
@@ -124,13 +144,18 @@ pub(crate) static CIPHERS: Lazy<HashMap<&'static Name, &(dyn Cipher + Send + Syn
         let mut h: HashMap<&'static Name, &(dyn Cipher + Send + Sync)> = HashMap::new();
         h.insert(&CLEAR, &_CLEAR);
         h.insert(&NONE, &_CLEAR);
+        #[cfg(feature = "legacy-ciphers")]
         h.insert(&TRIPLE_DES_CBC, &_3DES_CBC);
         h.insert(&AES_128_CTR, &_AES_128_CTR);
         h.insert(&AES_192_CTR, &_AES_192_CTR);
         h.insert(&AES_256_CTR, &_AES_256_CTR);
+        h.insert(&AES_128_GCM, &_AES_128_GCM);
         h.insert(&AES_256_GCM, &_AES_256_GCM);
+        #[cfg(feature = "legacy-ciphers")]
         h.insert(&AES_128_CBC, &_AES_128_CBC);
+        #[cfg(feature = "legacy-ciphers")]
         h.insert(&AES_192_CBC, &_AES_192_CBC);
+        #[cfg(feature = "legacy-ciphers")]
         h.insert(&AES_256_CBC, &_AES_256_CBC);
         h.insert(&CHACHA20_POLY1305, &_CHACHA20_POLY1305);
         assert_eq!(h.len(), ALL_CIPHERS.len());
@@ -208,6 +233,12 @@ pub(crate) trait SealingKey {
         // The variables `payload`, `packet_length` and `padding_length` refer
         // to the protocol fields of the same names.
         debug!("writing, seqn = {:?}", buffer.seqn.0);
+        observe(
+            &buffer.observer,
+            Direction::Outbound,
+            buffer.seqn.0,
+            payload,
+        );
 
         let padding_length = self.padding_length(payload);
         debug!("padding length {:?}", padding_length);
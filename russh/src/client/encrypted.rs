@@ -17,19 +17,21 @@ use std::convert::TryInto;
 use std::num::Wrapping;
 
 use bytes::Bytes;
-use log::{debug, error, info, trace, warn};
 use russh_keys::helpers::{map_err, EncodedExt};
 use ssh_encoding::{Decode, Encode};
+use ssh_key::{Algorithm, HashAlg};
 
 use crate::cert::PublicKeyOrCertificate;
-use crate::client::{Handler, Msg, Prompt, Reply, Session};
+use crate::client::remote_forward::find_remote_forward;
+use crate::client::{ForwardedTcpIp, Handler, Msg, Prompt, Reply, Session, SessionEvent};
 use crate::keys::key::parse_public_key;
-use crate::negotiation::{Named, Select};
-use crate::parsing::{ChannelOpenConfirmation, ChannelType, OpenChannelMessage};
+use crate::logging::{debug, error, info, trace, warn};
+use crate::negotiation::Select;
+use crate::parsing::{decode_slice, ChannelOpenConfirmation, ChannelType, OpenChannelMessage};
 use crate::session::{Encrypted, EncryptedState, GlobalRequestResponse, Kex, KexInit};
 use crate::{
     auth, msg, negotiation, Channel, ChannelId, ChannelMsg, ChannelOpenFailure, ChannelParams,
-    CryptoVec, Sig,
+    CryptoVec, Extensions, SecretBuffer, Sig,
 };
 
 thread_local! {
@@ -64,7 +66,9 @@ impl Session {
                         negotiation::Client::read_kex(
                             buf,
                             &self.common.config.as_ref().preferred,
+                            self.common.config.algorithm_policy.as_ref(),
                             None,
+                            &self.common.config.compression,
                         )?,
                         &enc.session_id,
                     ))
@@ -102,12 +106,20 @@ impl Session {
                         kexdhdone.names.ignore_guessed = false;
                         enc.rekey = Some(Kex::DhDone(kexdhdone));
                         Ok(())
-                    } else if buf.first() == Some(&msg::KEX_ECDH_REPLY) {
+                    } else if kexdhdone.client_negotiate(
+                        &mut *self.common.cipher.local_to_remote,
+                        buf,
+                        &mut self.common.write_buffer,
+                    )? {
+                        enc.rekey = Some(Kex::DhDone(kexdhdone));
+                        self.flush()?;
+                        Ok(())
+                    } else if buf.first() == Some(&kexdhdone.kex.reply_msg()) {
                         // We've sent ECDH_INIT, waiting for ECDH_REPLY
 
                         #[allow(clippy::indexing_slicing)] // length checked
                         let kex = kexdhdone
-                            .server_key_check(true, client, &mut &buf[1..])
+                            .server_key_check(true, None, None, client, &mut &buf[1..])
                             .await?;
 
                         enc.rekey = Some(Kex::Keys(kex));
@@ -139,6 +151,7 @@ impl Session {
                     self.pending_reads = pending;
                     self.pending_len = 0;
                     self.common.newkeys(newkeys);
+                    self.report_negotiated(client).await?;
                     self.flush()?;
 
                     if self.common.strict_kex {
@@ -150,7 +163,12 @@ impl Session {
                 Some(Kex::Init(k)) => {
                     enc.rekey = Some(Kex::Init(k));
                     self.pending_len += buf.len() as u32;
-                    if self.pending_len > 2 * self.target_window_size {
+                    if self.pending_len
+                        > self
+                            .target_window_size
+                            .max(self.common.config.max_window_size)
+                            .saturating_mul(2)
+                    {
                         return Err(crate::Error::Pending.into());
                     }
                     self.pending_reads.push(CryptoVec::from_slice(buf));
@@ -194,9 +212,12 @@ impl Session {
                                                 current: Some(
                                                     auth::CurrentRequest::KeyboardInteractive {
                                                         submethods: submethods.to_string(),
+                                                        pending_prompts: None,
                                                     },
                                                 ),
                                                 rejection_count: 0,
+                                                next_methods: None,
+                                                partial_user: None,
                                             }
                                         }
                                         _ => auth::AuthRequest {
@@ -204,11 +225,18 @@ impl Session {
                                             partial_success: false,
                                             current: None,
                                             rejection_count: 0,
+                                            next_methods: None,
+                                            partial_user: None,
                                         },
                                     };
                                     let len = enc.write.len();
+                                    let hash_alg = rsa_hash_alg(self.server_sig_algs.as_deref());
                                     #[allow(clippy::indexing_slicing)] // length checked
-                                    if enc.write_auth_request(&self.common.auth_user, meth)? {
+                                    if enc.write_auth_request(
+                                        &self.common.auth_user,
+                                        meth,
+                                        hash_alg,
+                                    )? {
                                         debug!("enc: {:?}", &enc.write[len..]);
                                         enc.state = EncryptedState::WaitingAuthRequest(auth_request)
                                     }
@@ -218,7 +246,7 @@ impl Session {
                             }
                         }
                         Some((&msg::EXT_INFO, r)) => {
-                            return self.handle_ext_info(client, r);
+                            return self.handle_ext_info(client, r).await;
                         }
                         other => {
                             debug!("unknown message: {other:?}");
@@ -234,12 +262,15 @@ impl Session {
                                 .send(Reply::AuthSuccess)
                                 .map_err(|_| crate::Error::SendError)?;
                             enc.state = EncryptedState::InitCompression;
-                            enc.server_compression.init_decompress(&mut enc.decompress);
+                            enc.server_compression
+                                .start_decompress(true, &mut enc.decompress);
                             return Ok(());
                         }
                         Some((&msg::USERAUTH_BANNER, mut r)) => {
                             let banner = map_err!(String::decode(&mut r))?;
-                            client.auth_banner(&banner, self).await?;
+                            // Be lenient with servers omitting the language tag.
+                            let language_tag = String::decode(&mut r).unwrap_or_default();
+                            client.auth_banner(&banner, &language_tag, self).await?;
                             return Ok(());
                         }
                         Some((&msg::USERAUTH_FAILURE, mut r)) => {
@@ -253,10 +284,14 @@ impl Session {
                                     auth_request.methods |= m
                                 }
                             }
+                            let partial_success = map_err!(u8::decode(&mut r))? != 0;
                             let no_more_methods = auth_request.methods.is_empty();
                             self.common.auth_method = None;
                             self.sender
-                                .send(Reply::AuthFailure)
+                                .send(Reply::AuthFailure {
+                                    remaining_methods: auth_request.methods,
+                                    partial_success,
+                                })
                                 .map_err(|_| crate::Error::SendError)?;
 
                             // If no other authentication method is allowed by the server, give up.
@@ -264,6 +299,35 @@ impl Session {
                                 return Err(crate::Error::NoAuthMethod.into());
                             }
                         }
+                        #[cfg(feature = "gssapi")]
+                        Some((&msg::USERAUTH_GSSAPI_RESPONSE, mut r))
+                            if matches!(
+                                self.common.auth_method,
+                                Some(auth::Method::GssapiWithMic { .. })
+                            ) =>
+                        {
+                            let mechanism = map_err!(Bytes::decode(&mut r))?;
+                            debug!("gssapi mechanism: {:?}", mechanism);
+                            return Ok(self.gssapi_step(|context| context.init(&mechanism))?);
+                        }
+                        #[cfg(feature = "gssapi")]
+                        Some((&msg::USERAUTH_GSSAPI_TOKEN, mut r)) => {
+                            let token = map_err!(Bytes::decode(&mut r))?;
+                            return Ok(self.gssapi_step(|context| context.step(&token))?);
+                        }
+                        #[cfg(feature = "gssapi")]
+                        Some((&msg::USERAUTH_GSSAPI_ERROR, mut r)) => {
+                            let major = map_err!(u32::decode(&mut r))?;
+                            let minor = map_err!(u32::decode(&mut r))?;
+                            let message = map_err!(String::decode(&mut r))?;
+                            debug!("gssapi error {major}/{minor}: {message}");
+                            return Ok(());
+                        }
+                        #[cfg(feature = "gssapi")]
+                        Some((&msg::USERAUTH_GSSAPI_ERRTOK, _)) => {
+                            debug!("gssapi error token");
+                            return Ok(());
+                        }
                         Some((&msg::USERAUTH_INFO_REQUEST_OR_USERAUTH_PK_OK, mut r)) => {
                             if let Some(auth::CurrentRequest::PublicKey {
                                 ref mut sent_pk_ok,
@@ -314,6 +378,7 @@ impl Session {
                                         Some(Msg::AuthInfoResponse { responses }) => {
                                             break responses
                                         }
+                                        None => return Err(crate::Error::Disconnect.into()),
                                         _ => {}
                                     }
                                 };
@@ -329,6 +394,7 @@ impl Session {
                                     enc.client_send_signature(
                                         &self.common.auth_user,
                                         &auth_method,
+                                        rsa_hash_alg(self.server_sig_algs.as_deref()),
                                         &mut self.common.buffer,
                                     )?
                                 }
@@ -337,15 +403,19 @@ impl Session {
                                     enc.client_send_signature(
                                         &self.common.auth_user,
                                         &auth_method,
+                                        rsa_hash_alg(self.server_sig_algs.as_deref()),
                                         &mut self.common.buffer,
                                     )?
                                 }
                                 Some(auth::Method::FuturePublicKey { key }) => {
                                     debug!("public key");
                                     self.common.buffer.clear();
+                                    // Agents and other signers sign with the
+                                    // key's own algorithm.
                                     let i = enc.client_make_to_sign(
                                         &self.common.auth_user,
                                         &PublicKeyOrCertificate::PublicKey(key.clone()),
+                                        None,
                                         &mut self.common.buffer,
                                     )?;
                                     let len = self.common.buffer.len();
@@ -375,7 +445,7 @@ impl Session {
                             }
                         }
                         Some((&msg::EXT_INFO, r)) => {
-                            return self.handle_ext_info(client, r);
+                            return self.handle_ext_info(client, r).await;
                         }
                         other => {
                             debug!("unknown message: {other:?}");
@@ -394,8 +464,82 @@ impl Session {
         }
     }
 
-    fn handle_ext_info<H: Handler>(&mut self, _client: &mut H, r: &[u8]) -> Result<(), H::Error> {
+    async fn handle_ext_info<H: Handler>(
+        &mut self,
+        client: &mut H,
+        mut r: &[u8],
+    ) -> Result<(), H::Error> {
         debug!("Received EXT_INFO: {:?}", r);
+        let extensions = Extensions::parse(&mut r)?;
+        if let Some(value) = extensions.get(crate::extensions::SERVER_SIG_ALGS) {
+            let algs = String::from_utf8_lossy(value);
+            self.server_sig_algs = Some(
+                algs.split(',')
+                    .filter_map(|alg| Algorithm::new(alg).ok())
+                    .collect(),
+            );
+            debug!("server-sig-algs: {:?}", self.server_sig_algs);
+        }
+        self.server_extensions.extend(&extensions);
+        client.ext_info(&extensions, self).await
+    }
+
+    /// Advance the GSSAPI context of the current authentication
+    /// request, sending its token and, once established, its MIC.
+    #[cfg(feature = "gssapi")]
+    fn gssapi_step(
+        &mut self,
+        step: impl FnOnce(
+            &mut dyn crate::gssapi::ClientContext,
+        ) -> Result<crate::gssapi::Step, crate::Error>,
+    ) -> Result<(), crate::Error> {
+        let (Some(auth::Method::GssapiWithMic { context }), Some(enc)) =
+            (&mut self.common.auth_method, &mut self.common.encrypted)
+        else {
+            return Err(crate::Error::Inconsistent);
+        };
+        let context = context.get_mut().map_err(|_| crate::Error::Inconsistent)?;
+        let auth_user = &self.common.auth_user;
+        let result = step(context.as_mut()).and_then(|step| {
+            let mic = if step.complete {
+                let data = crate::gssapi::mic_data(&enc.session_id, auth_user)?;
+                Some(context.get_mic(&data)?)
+            } else {
+                None
+            };
+            Ok((step.token, mic))
+        });
+        let (token, mic) = match result {
+            Ok(x) => x,
+            Err(e) => {
+                // Let the application try another method.
+                debug!("gssapi context failed: {e}");
+                let remaining_methods = match enc.state {
+                    EncryptedState::WaitingAuthRequest(ref auth_request) => auth_request.methods,
+                    _ => auth::MethodSet::all(),
+                };
+                self.common.auth_method = None;
+                self.sender
+                    .send(Reply::AuthFailure {
+                        remaining_methods,
+                        partial_success: false,
+                    })
+                    .map_err(|_| crate::Error::SendError)?;
+                return Ok(());
+            }
+        };
+        if let Some(token) = token {
+            push_packet!(enc.write, {
+                msg::USERAUTH_GSSAPI_TOKEN.encode(&mut enc.write)?;
+                token.encode(&mut enc.write)?;
+            });
+        }
+        if let Some(mic) = mic {
+            push_packet!(enc.write, {
+                msg::USERAUTH_GSSAPI_MIC.encode(&mut enc.write)?;
+                mic.encode(&mut enc.write)?;
+            });
+        }
         Ok(())
     }
 
@@ -412,7 +556,9 @@ impl Session {
 
                 if let Some(ref mut enc) = self.common.encrypted {
                     if let Some(parameters) = enc.channels.get_mut(&local_id) {
-                        parameters.confirm(&msg);
+                        if let Some(rtt) = parameters.confirm(&msg) {
+                            enc.observe_rtt(rtt);
+                        }
                     } else {
                         // We've not requested this channel, close connection.
                         return Err(crate::Error::Inconsistent.into());
@@ -422,13 +568,17 @@ impl Session {
                 };
 
                 if let Some(channel) = self.channels.get(&local_id) {
-                    channel
-                        .send(ChannelMsg::Open {
-                            id: local_id,
-                            max_packet_size: msg.maximum_packet_size,
-                            window_size: msg.initial_window_size,
-                        })
-                        .unwrap_or(());
+                    let opened = channel.send(ChannelMsg::Open {
+                        id: local_id,
+                        max_packet_size: msg.maximum_packet_size,
+                        window_size: msg.initial_window_size,
+                    });
+                    if opened.is_err() {
+                        // Nobody waits for this channel anymore.
+                        debug!("closing abandoned channel {local_id:?}");
+                        self.channels.remove(&local_id);
+                        self.close(local_id)?;
+                    }
                 } else {
                     error!("no channel for id {local_id:?}");
                 }
@@ -476,8 +626,6 @@ impl Session {
                     let _ = sender.send(ChannelMsg::OpenFailure(reason_code));
                 }
 
-                let _ = self.sender.send(Reply::ChannelOpenFailure);
-
                 client
                     .channel_open_failure(channel_num, reason_code, &descr, &language, self)
                     .await
@@ -485,51 +633,48 @@ impl Session {
             Some((&msg::CHANNEL_DATA, mut r)) => {
                 trace!("channel_data");
                 let channel_num = map_err!(ChannelId::decode(&mut r))?;
-                let data = map_err!(Bytes::decode(&mut r))?;
-                let target = self.common.config.window_size;
+                let data = decode_slice(&mut r)?;
+                if let Some(chan) = self.channels.get(&channel_num) {
+                    chan.send_data(data, None);
+                }
+
+                let max_window_size = self.common.config.max_window_size;
+                let window_limit = self.window_limit(channel_num);
                 if let Some(ref mut enc) = self.common.encrypted {
-                    if enc.adjust_window_size(channel_num, &data, target)? {
+                    if enc.adjust_window_size(channel_num, data, max_window_size, window_limit)? {
                         let next_window =
-                            client.adjust_window(channel_num, self.target_window_size);
+                            client.adjust_window(channel_num, enc.target_window_size(channel_num));
                         if next_window > 0 {
-                            self.target_window_size = next_window
+                            enc.set_target_window_size(channel_num, next_window)
                         }
                     }
                 }
 
-                if let Some(chan) = self.channels.get(&channel_num) {
-                    let _ = chan.send(ChannelMsg::Data {
-                        data: CryptoVec::from_slice(&data),
-                    });
-                }
-
-                client.data(channel_num, &data, self).await
+                client.data(channel_num, data, self).await
             }
             Some((&msg::CHANNEL_EXTENDED_DATA, mut r)) => {
                 debug!("channel_extended_data");
                 let channel_num = map_err!(ChannelId::decode(&mut r))?;
                 let extended_code = map_err!(u32::decode(&mut r))?;
-                let data = map_err!(Bytes::decode(&mut r))?;
-                let target = self.common.config.window_size;
+                let data = decode_slice(&mut r)?;
+                if let Some(chan) = self.channels.get(&channel_num) {
+                    chan.send_data(data, Some(extended_code));
+                }
+
+                let max_window_size = self.common.config.max_window_size;
+                let window_limit = self.window_limit(channel_num);
                 if let Some(ref mut enc) = self.common.encrypted {
-                    if enc.adjust_window_size(channel_num, &data, target)? {
+                    if enc.adjust_window_size(channel_num, data, max_window_size, window_limit)? {
                         let next_window =
-                            client.adjust_window(channel_num, self.target_window_size);
+                            client.adjust_window(channel_num, enc.target_window_size(channel_num));
                         if next_window > 0 {
-                            self.target_window_size = next_window
+                            enc.set_target_window_size(channel_num, next_window)
                         }
                     }
                 }
 
-                if let Some(chan) = self.channels.get(&channel_num) {
-                    let _ = chan.send(ChannelMsg::ExtendedData {
-                        ext: extended_code,
-                        data: CryptoVec::from_slice(&data),
-                    });
-                }
-
                 client
-                    .extended_data(channel_num, extended_code, &data, self)
+                    .extended_data(channel_num, extended_code, data, self)
                     .await
             }
             Some((&msg::CHANNEL_REQUEST, mut r)) => {
@@ -630,7 +775,7 @@ impl Session {
                     new_size -= enc.flush_pending(channel_num)? as u32;
                 }
                 if let Some(chan) = self.channels.get(&channel_num) {
-                    *chan.window_size().lock().await = new_size;
+                    chan.window_size().update(new_size).await;
 
                     let _ = chan.send(ChannelMsg::WindowAdjusted { new_size });
                 }
@@ -673,7 +818,11 @@ impl Session {
                     } else {
                         warn!("Unhandled global request: {req:?} {wants_reply:?}",);
                         self.common.wants_reply = false;
-                        push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE))
+                        push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE));
+                        self.events.send(SessionEvent::GlobalRequest {
+                            name: req,
+                            want_reply: wants_reply == 1,
+                        });
                     }
                 }
                 self.common.received_data = false;
@@ -703,6 +852,9 @@ impl Session {
                         sender_channel: id,
                         recipient_window_size: msg.recipient_window_size,
                         sender_window_size: self.common.config.window_size,
+                        target_window_size: self.common.config.window_size,
+                        window_adjusted_at: None,
+                        open_sent_at: None,
                         recipient_maximum_packet_size: msg.recipient_maximum_packet_size,
                         sender_maximum_packet_size: self.common.config.maximum_packet_size,
                         confirmed: true,
@@ -710,6 +862,9 @@ impl Session {
                         pending_data: std::collections::VecDeque::new(),
                         pending_eof: false,
                         pending_close: false,
+                        inbound_rate: None,
+                        outbound_rate: None,
+                        data_at: russh_util::time::Instant::now(),
                     };
 
                     let confirm = || {
@@ -761,7 +916,28 @@ impl Session {
                         }
                         ChannelType::ForwardedTcpIp(d) => {
                             confirm()?;
-                            let channel = self.accept_server_initiated_channel(id, &msg);
+                            let mut channel = self.accept_server_initiated_channel(id, &msg);
+                            if let Some((key, connections)) = find_remote_forward(
+                                &self.remote_forwards,
+                                &d.host_to_connect,
+                                d.port_to_connect,
+                            ) {
+                                match connections.send(ForwardedTcpIp {
+                                    channel,
+                                    connected_address: d.host_to_connect.clone(),
+                                    connected_port: d.port_to_connect,
+                                    originator_address: d.originator_address.clone(),
+                                    originator_port: d.originator_port,
+                                }) {
+                                    Ok(()) => return Ok(()),
+                                    Err(e) => {
+                                        // The RemoteForward was dropped.
+                                        let key = key.clone();
+                                        self.remote_forwards.remove(&key);
+                                        channel = e.0.channel;
+                                    }
+                                }
+                            }
                             client
                                 .server_channel_open_forwarded_tcpip(
                                     channel,
@@ -773,6 +949,14 @@ impl Session {
                                 )
                                 .await?
                         }
+                        ChannelType::DirectStreamLocal(_) => {
+                            debug!("refusing direct-streamlocal channel from the server");
+                            msg.unknown_type(&mut enc.write)?;
+                        }
+                        ChannelType::Tun { .. } => {
+                            debug!("refusing tun channel from the server");
+                            msg.unknown_type(&mut enc.write)?;
+                        }
                         ChannelType::ForwardedStreamLocal(d) => {
                             confirm()?;
                             let channel = self.accept_server_initiated_channel(id, &msg);
@@ -828,6 +1012,29 @@ impl Session {
                         };
                         let _ = return_channel.send(result);
                     }
+                    Some(GlobalRequestResponse::RemoteForward {
+                        reply_channel,
+                        address,
+                        port,
+                        connections,
+                    }) => {
+                        // If a specific port was requested, the reply has no data
+                        let port = if r.is_empty() {
+                            Some(port)
+                        } else {
+                            match u32::decode(&mut r) {
+                                Ok(port) => Some(port),
+                                Err(e) => {
+                                    error!("Error parsing port for TcpIpForward request: {e:?}");
+                                    None
+                                }
+                            }
+                        };
+                        if let Some(port) = port {
+                            self.remote_forwards.insert((address, port), connections);
+                        }
+                        let _ = reply_channel.send(port);
+                    }
                     Some(GlobalRequestResponse::CancelTcpIpForward(return_channel)) => {
                         let _ = return_channel.send(true);
                     }
@@ -837,6 +1044,11 @@ impl Session {
                     Some(GlobalRequestResponse::CancelStreamLocalForward(return_channel)) => {
                         let _ = return_channel.send(true);
                     }
+                    Some(GlobalRequestResponse::HostKeysProve(keys)) => {
+                        if self.check_host_keys_proof(&keys, &mut r)? {
+                            return client.openssh_ext_host_keys_proven(keys, self).await;
+                        }
+                    }
                     None => {
                         error!("Received global request failure for unknown request!")
                     }
@@ -852,6 +1064,9 @@ impl Session {
                     Some(GlobalRequestResponse::TcpIpForward(return_channel)) => {
                         let _ = return_channel.send(None);
                     }
+                    Some(GlobalRequestResponse::RemoteForward { reply_channel, .. }) => {
+                        let _ = reply_channel.send(None);
+                    }
                     Some(GlobalRequestResponse::CancelTcpIpForward(return_channel)) => {
                         let _ = return_channel.send(false);
                     }
@@ -861,6 +1076,9 @@ impl Session {
                     Some(GlobalRequestResponse::CancelStreamLocalForward(return_channel)) => {
                         let _ = return_channel.send(false);
                     }
+                    Some(GlobalRequestResponse::HostKeysProve(keys)) => {
+                        debug!("server refused to prove host keys {:?}", keys);
+                    }
                     None => {
                         error!("Received global request failure for unknown request!")
                     }
@@ -891,6 +1109,34 @@ impl Session {
         channel
     }
 
+    /// Check the signatures of a `hostkeys-prove-00@openssh.com` reply,
+    /// which come in the same order as the keys of the request.
+    fn check_host_keys_proof(
+        &self,
+        keys: &[ssh_key::PublicKey],
+        r: &mut &[u8],
+    ) -> Result<bool, crate::Error> {
+        let Some(ref enc) = self.common.encrypted else {
+            return Ok(false);
+        };
+        for key in keys {
+            let Ok(sig) = Bytes::decode(r) else {
+                debug!("missing host key proof for {:?}", key);
+                return Ok(false);
+            };
+            let Ok(sig) = ssh_key::Signature::decode(&mut &sig[..]) else {
+                debug!("invalid host key proof for {:?}", key);
+                return Ok(false);
+            };
+            let data = crate::session::host_keys_prove_data(&enc.session_id, key)?;
+            if signature::Verifier::verify(key, &data, &sig).is_err() {
+                warn!("wrong host key proof for {:?}", key);
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
     pub(crate) fn write_auth_request_if_needed(
         &mut self,
         user: &str,
@@ -906,11 +1152,11 @@ impl Session {
                 } => {
                     debug!("sending ssh-userauth service requset");
                     if !*sent {
-                        let p = b"\x05\0\0\0\x0Cssh-userauth";
-                        self.common
-                            .cipher
-                            .local_to_remote
-                            .write(p, &mut self.common.write_buffer);
+                        // Queued with the other packets, so that it's
+                        // compressed if `zlib` was negotiated.
+                        push_packet!(enc.write, {
+                            enc.write.extend(b"\x05\0\0\0\x0Cssh-userauth");
+                        });
                         *sent = true
                     }
                     accepted
@@ -922,7 +1168,7 @@ impl Session {
                 is_waiting
             );
             if is_waiting {
-                enc.write_auth_request(user, &meth)?;
+                enc.write_auth_request(user, &meth, rsa_hash_alg(self.server_sig_algs.as_deref()))?;
             }
         }
         self.common.auth_user.clear();
@@ -937,6 +1183,7 @@ impl Encrypted {
         &mut self,
         user: &str,
         auth_method: &auth::Method,
+        hash_alg: Option<HashAlg>,
     ) -> Result<bool, crate::Error> {
         // The server is waiting for our USERAUTH_REQUEST.
         Ok(push_packet!(self.write, {
@@ -954,7 +1201,7 @@ impl Encrypted {
                     "ssh-connection".encode(&mut self.write)?;
                     "password".encode(&mut self.write)?;
                     0u8.encode(&mut self.write)?;
-                    password.encode(&mut self.write)?;
+                    password.expose_secret().encode(&mut self.write)?;
                     true
                 }
                 auth::Method::PublicKey { ref key } => {
@@ -963,8 +1210,9 @@ impl Encrypted {
                     "publickey".encode(&mut self.write)?;
                     self.write.push(0); // This is a probe
 
-                    debug!("write_auth_request: key - {:?}", key.algorithm());
-                    key.algorithm().as_str().encode(&mut self.write)?;
+                    let algorithm = with_hash_alg(key.algorithm(), hash_alg);
+                    debug!("write_auth_request: key - {:?}", algorithm);
+                    algorithm.as_str().encode(&mut self.write)?;
                     key.public_key().to_bytes()?.encode(&mut self.write)?;
                     true
                 }
@@ -974,10 +1222,9 @@ impl Encrypted {
                     "publickey".encode(&mut self.write)?;
                     self.write.push(0); // This is a probe
 
-                    debug!("write_auth_request: cert - {:?}", cert.algorithm());
-                    cert.algorithm()
-                        .to_certificate_type()
-                        .encode(&mut self.write)?;
+                    let algorithm = with_hash_alg(cert.algorithm(), hash_alg);
+                    debug!("write_auth_request: cert - {:?}", algorithm);
+                    algorithm.to_certificate_type().encode(&mut self.write)?;
                     cert.to_bytes()?.as_slice().encode(&mut self.write)?;
                     true
                 }
@@ -992,6 +1239,48 @@ impl Encrypted {
                     key.to_bytes()?.as_slice().encode(&mut self.write)?;
                     true
                 }
+                #[cfg(feature = "gssapi")]
+                auth::Method::GssapiWithMic { ref context } => {
+                    user.encode(&mut self.write)?;
+                    "ssh-connection".encode(&mut self.write)?;
+                    "gssapi-with-mic".encode(&mut self.write)?;
+                    let mechanisms = context
+                        .lock()
+                        .map_err(|_| crate::Error::Inconsistent)?
+                        .mechanisms();
+                    (mechanisms.len() as u32).encode(&mut self.write)?;
+                    for mechanism in mechanisms {
+                        mechanism.encode(&mut self.write)?;
+                    }
+                    true
+                }
+                auth::Method::Hostbased {
+                    ref key,
+                    ref client_host,
+                    ref client_user,
+                } => {
+                    // Unlike public key authentication, there is no
+                    // probe: the request is signed right away.
+                    let algorithm = with_hash_alg(key.algorithm(), hash_alg);
+                    debug!("write_auth_request: hostbased - {:?}", algorithm);
+                    let mut to_sign = CryptoVec::new();
+                    self.session_id.as_ref().encode(&mut to_sign)?;
+                    let i0 = to_sign.len();
+                    to_sign.push(msg::USERAUTH_REQUEST);
+                    user.encode(&mut to_sign)?;
+                    "ssh-connection".encode(&mut to_sign)?;
+                    "hostbased".encode(&mut to_sign)?;
+                    algorithm.as_str().encode(&mut to_sign)?;
+                    key.public_key().to_bytes()?.encode(&mut to_sign)?;
+                    client_host.encode(&mut to_sign)?;
+                    client_user.encode(&mut to_sign)?;
+                    let signature = russh_keys::key::sign_with_hash_alg(key, hash_alg, &to_sign)?;
+
+                    #[allow(clippy::indexing_slicing)] // length checked
+                    self.write.extend(&to_sign[i0 + 1..]);
+                    signature.encoded()?.encode(&mut self.write)?;
+                    true
+                }
                 auth::Method::KeyboardInteractive { ref submethods } => {
                     debug!("Keyboard Iinteractive");
                     user.as_bytes().encode(&mut self.write)?;
@@ -1009,6 +1298,7 @@ impl Encrypted {
         &mut self,
         user: &str,
         key: &PublicKeyOrCertificate,
+        hash_alg: Option<HashAlg>,
         buffer: &mut CryptoVec,
     ) -> Result<usize, crate::Error> {
         buffer.clear();
@@ -1023,11 +1313,15 @@ impl Encrypted {
 
         match key {
             PublicKeyOrCertificate::Certificate(cert) => {
-                cert.name().as_ref().encode(buffer)?;
+                with_hash_alg(cert.algorithm(), hash_alg)
+                    .to_certificate_type()
+                    .encode(buffer)?;
                 cert.to_bytes()?.encode(buffer)?;
             }
             PublicKeyOrCertificate::PublicKey(key) => {
-                key.name().as_ref().encode(buffer)?;
+                with_hash_alg(key.algorithm(), hash_alg)
+                    .as_str()
+                    .encode(buffer)?;
                 key.to_bytes()?.encode(buffer)?;
             }
         }
@@ -1038,6 +1332,7 @@ impl Encrypted {
         &mut self,
         user: &str,
         method: &auth::Method,
+        hash_alg: Option<HashAlg>,
         buffer: &mut CryptoVec,
     ) -> Result<(), crate::Error> {
         match method {
@@ -1045,11 +1340,12 @@ impl Encrypted {
                 let i0 = self.client_make_to_sign(
                     user,
                     &PublicKeyOrCertificate::PublicKey(key.public_key().clone()),
+                    hash_alg,
                     buffer,
                 )?;
 
                 // Extend with self-signature.
-                let signature = signature::Signer::try_sign(&**key, buffer)?;
+                let signature = russh_keys::key::sign_with_hash_alg(key, hash_alg, buffer)?;
                 signature.encoded()?.encode(&mut *buffer)?;
 
                 push_packet!(self.write, {
@@ -1061,11 +1357,12 @@ impl Encrypted {
                 let i0 = self.client_make_to_sign(
                     user,
                     &PublicKeyOrCertificate::Certificate(cert.clone()),
+                    hash_alg,
                     buffer,
                 )?;
 
                 // Extend with self-signature.
-                let signature = signature::Signer::try_sign(&**key, buffer)?;
+                let signature = russh_keys::key::sign_with_hash_alg(key, hash_alg, buffer)?;
                 signature.encoded()?.encode(&mut *buffer)?;
 
                 push_packet!(self.write, {
@@ -1078,15 +1375,36 @@ impl Encrypted {
         Ok(())
     }
 
-    fn client_send_auth_response(&mut self, responses: &[String]) -> Result<(), crate::Error> {
+    fn client_send_auth_response(
+        &mut self,
+        responses: &[SecretBuffer],
+    ) -> Result<(), crate::Error> {
         push_packet!(self.write, {
             msg::USERAUTH_INFO_RESPONSE.encode(&mut self.write)?;
             (responses.len().try_into().unwrap_or(0) as u32).encode(&mut self.write)?; // number of responses
 
             for r in responses {
-                r.encode(&mut self.write)?; // write the reponses
+                r.expose_secret().encode(&mut self.write)?; // write the reponses
             }
         });
         Ok(())
     }
 }
+
+/// The hash to use with RSA keys in public key authentication: the
+/// strongest one listed in the server's `server-sig-algs`, or `ssh-rsa`
+/// if the server didn't send the extension (RFC 8332, section 3.3).
+fn rsa_hash_alg(server_sig_algs: Option<&[Algorithm]>) -> Option<HashAlg> {
+    let server_sig_algs = server_sig_algs?;
+    [HashAlg::Sha512, HashAlg::Sha256]
+        .iter()
+        .copied()
+        .find(|hash| server_sig_algs.contains(&Algorithm::Rsa { hash: Some(*hash) }))
+}
+
+fn with_hash_alg(algorithm: Algorithm, hash_alg: Option<HashAlg>) -> Algorithm {
+    match algorithm {
+        Algorithm::Rsa { .. } => Algorithm::Rsa { hash: hash_alg },
+        algorithm => algorithm,
+    }
+}
@@ -0,0 +1,161 @@
+//! The events of a session, as a stream, for applications that would
+//! rather `select!` on them than implement [`Handler`] methods.
+//!
+//! ```no_run
+//! # async fn f(config: std::sync::Arc<russh::client::Config>) -> Result<(), russh::Error> {
+//! use futures::StreamExt;
+//! use russh::client::{self, DefaultHandler, SessionEvent};
+//!
+//! // The server key is checked by `config.host_key_verifier`.
+//! let handle = client::connect(config, ("localhost", 22), DefaultHandler).await?;
+//! let mut events = handle.events();
+//! while let Some(event) = events.next().await {
+//!     match event {
+//!         SessionEvent::Banner { banner, .. } => println!("{banner}"),
+//!         SessionEvent::Disconnected(info) => println!("disconnected: {info:?}"),
+//!         _ => {}
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use super::{Handler, Msg};
+use crate::{Channel, RemoteDisconnectInfo};
+
+/// What happened in a session, received from [`Handle::events`](super::Handle::events).
+///
+/// Events are only produced by the default methods of [`Handler`], so
+/// a handler overriding [`Handler::auth_banner`], for instance, gets
+/// the banners instead of the stream.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SessionEvent {
+    /// An authentication banner, usually meant to be shown to the user.
+    Banner {
+        banner: String,
+        language_tag: String,
+    },
+    /// A global request this client doesn't handle. It was refused.
+    GlobalRequest { name: String, want_reply: bool },
+    /// A channel opened by the server.
+    ChannelOpen {
+        channel: Channel<Msg>,
+        kind: ChannelOpenKind,
+    },
+    /// The end of the session, with the disconnect message of the
+    /// server if it sent one. This is the last event.
+    Disconnected(Option<RemoteDisconnectInfo>),
+}
+
+/// The type of a channel opened by the server, and its parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ChannelOpenKind {
+    Session,
+    DirectTcpIp {
+        host_to_connect: String,
+        port_to_connect: u32,
+        originator_address: String,
+        originator_port: u32,
+    },
+    ForwardedTcpIp {
+        connected_address: String,
+        connected_port: u32,
+        originator_address: String,
+        originator_port: u32,
+    },
+    ForwardedStreamLocal {
+        socket_path: String,
+    },
+    X11 {
+        originator_address: String,
+        originator_port: u32,
+    },
+    AgentForward,
+}
+
+/// A stream of [`SessionEvent`]s, which ends after
+/// [`SessionEvent::Disconnected`].
+#[derive(Debug)]
+pub struct Events {
+    receiver: UnboundedReceiver<SessionEvent>,
+}
+
+impl Events {
+    /// The next event, or `None` once the session ended.
+    pub async fn recv(&mut self) -> Option<SessionEvent> {
+        self.receiver.recv().await
+    }
+}
+
+impl futures::Stream for Events {
+    type Item = SessionEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Subscriber {
+    sender: Option<UnboundedSender<SessionEvent>>,
+    closed: bool,
+}
+
+/// Where the session sends its events, shared with the [`Handle`](super::Handle).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EventSender(Arc<Mutex<Subscriber>>);
+
+impl EventSender {
+    /// Start a new stream of events, ending the previous one.
+    pub(crate) fn subscribe(&self) -> Events {
+        let (sender, receiver) = unbounded_channel();
+        if let Ok(mut subscriber) = self.0.lock() {
+            if !subscriber.closed {
+                subscriber.sender = Some(sender)
+            }
+        }
+        Events { receiver }
+    }
+
+    /// Send `event` if someone is listening, else drop it.
+    pub(crate) fn send(&self, event: SessionEvent) {
+        if let Ok(mut subscriber) = self.0.lock() {
+            if let Some(ref sender) = subscriber.sender {
+                if sender.send(event).is_err() {
+                    subscriber.sender = None
+                }
+            }
+        }
+    }
+
+    /// Send the last event, and end the stream.
+    pub(crate) fn disconnected(&self, info: Option<RemoteDisconnectInfo>) {
+        self.send(SessionEvent::Disconnected(info));
+        if let Ok(mut subscriber) = self.0.lock() {
+            subscriber.sender = None;
+            subscriber.closed = true;
+        }
+    }
+}
+
+/// A handler leaving everything to the default methods of [`Handler`]:
+/// server keys are accepted or rejected by
+/// [`Config::host_key_verifier`](super::Config::host_key_verifier), and
+/// rejected if there is none, and the events go to
+/// [`Handle::events`](super::Handle::events).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultHandler;
+
+#[async_trait]
+impl Handler for DefaultHandler {
+    type Error = crate::Error;
+}
@@ -0,0 +1,150 @@
+//! Local port forwarding, the equivalent of `ssh -L`, and dynamic
+//! forwarding through a local SOCKS proxy, like `ssh -D`.
+
+use std::net::SocketAddr;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+
+use super::{open_direct_tcpip, socks, Handle, Handler, Msg};
+use crate::logging::debug;
+
+/// A running local port forward, returned by
+/// [`Handle::forward_local`] and [`Handle::socks5_listen`]. The
+/// listener is closed when this is dropped or
+/// [stopped](LocalForward::stop); connections already forwarded keep
+/// going until either side closes them.
+#[derive(Debug)]
+pub struct LocalForward {
+    local_addr: SocketAddr,
+    stop: oneshot::Sender<()>,
+}
+
+impl LocalForward {
+    /// The address the listener is bound to, useful when binding to
+    /// port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stop accepting new connections.
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+    }
+}
+
+impl<H: Handler> Handle<H> {
+    /// Listen on `bind_addr:bind_port`, and forward each accepted
+    /// connection to `target_host:target_port` through a new
+    /// `direct-tcpip` channel, until the returned [`LocalForward`] is
+    /// stopped or the session is closed.
+    pub async fn forward_local<T: Into<String>>(
+        &self,
+        bind_addr: &str,
+        bind_port: u16,
+        target_host: T,
+        target_port: u32,
+    ) -> Result<LocalForward, crate::Error> {
+        let listener = TcpListener::bind((bind_addr, bind_port)).await?;
+        let local_addr = listener.local_addr()?;
+        let (stop, stopped) = oneshot::channel();
+        russh_util::runtime::spawn(accept_loop(
+            listener,
+            self.sender.clone(),
+            Some((target_host.into(), target_port)),
+            stopped,
+        ));
+        Ok(LocalForward { local_addr, stop })
+    }
+
+    /// Listen on `addr` for SOCKS5 and SOCKS4a clients, and open a
+    /// `direct-tcpip` channel to the destination of each CONNECT
+    /// request. Host names are resolved by the server.
+    pub async fn socks5_listen<A: tokio::net::ToSocketAddrs>(
+        &self,
+        addr: A,
+    ) -> Result<LocalForward, crate::Error> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (stop, stopped) = oneshot::channel();
+        russh_util::runtime::spawn(accept_loop(listener, self.sender.clone(), None, stopped));
+        Ok(LocalForward { local_addr, stop })
+    }
+}
+
+/// Accept connections until stopped, forwarding them to `target`, or
+/// to the destination of their SOCKS request if `target` is `None`.
+async fn accept_loop(
+    listener: TcpListener,
+    sender: Sender<Msg>,
+    target: Option<(String, u32)>,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    loop {
+        let (socket, peer) = tokio::select! {
+            _ = &mut stopped => break,
+            _ = sender.closed() => break,
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    debug!("local forward: accept failed: {:?}", e);
+                    continue;
+                }
+            },
+        };
+        russh_util::runtime::spawn(forward_connection(
+            socket,
+            peer,
+            sender.clone(),
+            target.clone(),
+        ));
+    }
+    debug!("local forward on {:?} stopped", listener.local_addr());
+}
+
+async fn forward_connection(
+    mut socket: TcpStream,
+    peer: SocketAddr,
+    sender: Sender<Msg>,
+    target: Option<(String, u32)>,
+) {
+    let (socks, target_host, target_port) = match target {
+        Some((host, port)) => (None, host, port),
+        None => match socks::read_request(&mut socket).await {
+            Ok((version, host, port)) => (Some(version), host, port.into()),
+            Err(e) => {
+                debug!("SOCKS request from {}: {:?}", peer, e);
+                return;
+            }
+        },
+    };
+    let channel = open_direct_tcpip(
+        sender,
+        target_host.clone(),
+        target_port,
+        peer.ip().to_string(),
+        peer.port().into(),
+    )
+    .await;
+    if let Some(version) = socks {
+        if let Err(e) = socks::reply(&mut socket, version, channel.is_ok()).await {
+            debug!("SOCKS reply to {}: {:?}", peer, e);
+            return;
+        }
+    }
+    let channel = match channel {
+        Ok(channel) => channel,
+        Err(e) => {
+            debug!(
+                "local forward to {}:{} failed: {:?}",
+                target_host, target_port, e
+            );
+            return;
+        }
+    };
+    let mut stream = channel.into_stream();
+    if let Err(e) = tokio::io::copy_bidirectional(&mut socket, &mut stream).await {
+        debug!("local forward from {}: {:?}", peer, e);
+    }
+}
@@ -1,12 +1,12 @@
-use log::{debug, trace};
-
 use crate::cipher::SealingKey;
 use crate::client::Config;
 use crate::kex::KEXES;
+use crate::logging::{debug, trace};
 use crate::negotiation;
 use crate::negotiation::Select;
 use crate::session::{KexDhDone, KexInit};
 use crate::sshbuffer::SSHBuffer;
+use crate::CryptoVec;
 
 impl KexInit {
     pub fn client_parse(
@@ -21,7 +21,13 @@ impl KexInit {
             // read algorithms from packet.
             debug!("extending {:?}", &self.exchange.server_kex_init[..]);
             self.exchange.server_kex_init.extend(buf);
-            negotiation::Client::read_kex(buf, &config.preferred, None)?
+            negotiation::Client::read_kex(
+                buf,
+                &config.preferred,
+                config.algorithm_policy.as_ref(),
+                None,
+                &config.compression,
+            )?
         };
         debug!("algo = {:?}", algo);
         debug!("write = {:?}", &write_buffer.buffer[..]);
@@ -69,9 +75,37 @@ impl KexInit {
         write_buffer: &mut SSHBuffer,
     ) -> Result<(), crate::Error> {
         self.exchange.client_kex_init.clear();
-        negotiation::write_kex(&config.preferred, &mut self.exchange.client_kex_init, None)?;
+        negotiation::write_kex(
+            &config.preferred,
+            config.algorithm_policy.as_ref(),
+            &mut self.exchange.client_kex_init,
+            None,
+            &config.compression,
+        )?;
         self.sent = true;
         cipher.write(&self.exchange.client_kex_init, write_buffer);
         Ok(())
     }
 }
+
+impl KexDhDone {
+    /// Let the key exchange answer messages the server sends before its
+    /// reply, such as the group of a group exchange. Returns `true` if
+    /// `buf` was such a message.
+    pub fn client_negotiate(
+        &mut self,
+        cipher: &mut dyn SealingKey,
+        buf: &[u8],
+        write_buffer: &mut SSHBuffer,
+    ) -> Result<bool, crate::Error> {
+        let mut out = CryptoVec::new();
+        if !self
+            .kex
+            .client_negotiate(buf, &mut self.exchange.client_ephemeral, &mut out)?
+        {
+            return Ok(false);
+        }
+        cipher.write(&out, write_buffer);
+        Ok(true)
+    }
+}
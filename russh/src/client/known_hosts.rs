@@ -0,0 +1,147 @@
+//! A [`HostKeyVerifier`] backed by known_hosts files.
+
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use russh_keys::known_hosts::{
+    learn_known_hosts, learn_known_hosts_path, KnownHostStatus, KnownHosts,
+};
+use ssh_key::PublicKey;
+
+use super::server_key::{HostKeyVerifier, KnownHostsStatus, ServerKeyCheck, ServerKeyVerdict};
+use crate::logging::debug;
+
+/// Checks server keys against known_hosts files, in the format of
+/// OpenSSH. Known keys are accepted, revoked keys rejected, and
+/// changed keys refused with [`ServerKeyVerdict::Mismatch`]. What
+/// happens to unknown keys depends on [`KnownHostsVerifier::unknown_keys`].
+///
+/// Keys accepted with [`ServerKeyVerdict::AcceptAndRecord`] are added
+/// to the first file.
+#[derive(Debug, Clone, Default)]
+pub struct KnownHostsVerifier {
+    files: Vec<PathBuf>,
+    unknown: Option<ServerKeyVerdict>,
+    host: Option<(String, u16)>,
+}
+
+impl KnownHostsVerifier {
+    /// Use the user's known_hosts file, and leave unknown keys to the
+    /// next verifiers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `path` instead of the user's known_hosts file. Several files
+    /// can be added, the first one being where keys are recorded.
+    pub fn file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.files.push(path.into());
+        self
+    }
+
+    /// Decide unknown keys with `verdict`, such as
+    /// [`ServerKeyVerdict::AcceptAndRecord`] for the `accept-new` policy
+    /// of OpenSSH, or leave them to the next verifiers with `None`.
+    pub fn unknown_keys(mut self, verdict: Option<ServerKeyVerdict>) -> Self {
+        self.unknown = verdict;
+        self
+    }
+
+    /// Look keys up for `host` on `port`, rather than for the host of
+    /// the connection, which is only known when connecting with
+    /// `connect_with_config`.
+    pub fn with_host<H: Into<String>>(mut self, host: H, port: u16) -> Self {
+        self.host = Some((host.into(), port));
+        self
+    }
+
+    /// The status of `key` in the files. Revocations take precedence
+    /// over everything else, and keys known in any file over changed
+    /// keys.
+    pub fn status(
+        &self,
+        host: &str,
+        port: u16,
+        key: &PublicKey,
+    ) -> Result<KnownHostsStatus, crate::Error> {
+        let known_hosts = if self.files.is_empty() {
+            vec![KnownHosts::load_default()?]
+        } else {
+            self.files
+                .iter()
+                .map(KnownHosts::load)
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        let mut status = KnownHostsStatus::Unknown;
+        for known_hosts in &known_hosts {
+            let path = known_hosts
+                .path()
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+            match known_hosts.check(host, port, key) {
+                KnownHostStatus::Revoked { line } => {
+                    return Ok(KnownHostsStatus::Revoked { path, line })
+                }
+                KnownHostStatus::Known { line } => {
+                    if !matches!(status, KnownHostsStatus::Known { .. }) {
+                        status = KnownHostsStatus::Known { path, line }
+                    }
+                }
+                KnownHostStatus::Changed { line } => {
+                    if status == KnownHostsStatus::Unknown {
+                        status = KnownHostsStatus::Changed { path, line }
+                    }
+                }
+                KnownHostStatus::Unknown => {}
+            }
+        }
+        Ok(status)
+    }
+
+    /// The verdict about a key of this status.
+    pub fn verdict(&self, status: &KnownHostsStatus) -> Option<ServerKeyVerdict> {
+        match status {
+            KnownHostsStatus::Unknown => self.unknown.clone(),
+            status => ServerKeyVerdict::from_known_hosts(status),
+        }
+    }
+
+    /// Add `key` to the first file.
+    pub fn record_key(&self, host: &str, port: u16, key: &PublicKey) -> Result<(), crate::Error> {
+        debug!("Learning host key for {:?}", host);
+        match self.files.first() {
+            Some(file) => learn_known_hosts_path(host, port, key, file)?,
+            None => learn_known_hosts(host, port, key)?,
+        }
+        Ok(())
+    }
+
+    fn host<'a>(&'a self, check: &ServerKeyCheck<'a>) -> Option<(&'a str, u16)> {
+        match self.host {
+            Some((ref host, port)) => Some((host.as_str(), port)),
+            None => check.host,
+        }
+    }
+}
+
+#[async_trait]
+impl HostKeyVerifier for KnownHostsVerifier {
+    async fn verify(
+        &self,
+        check: &ServerKeyCheck<'_>,
+    ) -> Result<Option<ServerKeyVerdict>, crate::Error> {
+        let Some((host, port)) = self.host(check) else {
+            debug!("known_hosts: unknown host name");
+            return Ok(None);
+        };
+        let status = self.status(host, port, check.key)?;
+        Ok(self.verdict(&status))
+    }
+
+    async fn record(&self, check: &ServerKeyCheck<'_>) -> Result<(), crate::Error> {
+        match self.host(check) {
+            Some((host, port)) => self.record_key(host, port, check.key),
+            None => Ok(()),
+        }
+    }
+}
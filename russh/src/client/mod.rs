@@ -36,7 +36,6 @@
 
 use std::cell::RefCell;
 use std::collections::{HashMap, VecDeque};
-use std::convert::TryInto;
 use std::num::Wrapping;
 use std::pin::Pin;
 use std::sync::Arc;
@@ -45,35 +44,73 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use futures::task::{Context, Poll};
 use futures::Future;
-use log::{debug, error, info, trace};
+use russh_keys::key::SshSignature;
 use russh_keys::map_err;
-use signature::Verifier;
 use ssh_encoding::{Decode, Encode, Reader};
-use ssh_key::{Certificate, PrivateKey, PublicKey, Signature};
+use ssh_key::{Algorithm, Certificate, PrivateKey, PublicKey};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::pin;
 use tokio::sync::mpsc::{
     channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender,
 };
-use tokio::sync::{oneshot, Mutex};
+use tokio::sync::{oneshot, Notify};
 
-use crate::channels::{Channel, ChannelMsg, ChannelRef};
+use crate::channels::{Channel, ChannelMsg, ChannelRef, WindowSizeRef};
 use crate::cipher::{self, clear, CipherPair, OpeningKey};
 use crate::keys::key::parse_public_key;
+use crate::logging::{self, debug, error, info, trace, Instrument};
+use crate::negotiation::SharedNegotiated;
+use crate::observer::{observe, Direction, PacketObserver};
 use crate::session::{
     CommonSession, EncryptedState, Exchange, GlobalRequestResponse, Kex, KexDhDone, KexInit,
     NewKeys,
 };
 use crate::ssh_read::SshRead;
 use crate::sshbuffer::{SSHBuffer, SshId};
+use crate::tun::TunMode;
 use crate::{
     auth, msg, negotiation, strict_kex_violation, ChannelId, ChannelOpenFailure, CryptoVec,
-    Disconnect, Limits, Sig,
+    Disconnect, Extensions, Limits, MethodSet, Negotiated, SecretBuffer, Sig,
 };
 
 mod encrypted;
+mod events;
+#[cfg(not(target_arch = "wasm32"))]
+mod forward;
 mod kex;
+#[cfg(not(target_arch = "wasm32"))]
+mod known_hosts;
+#[cfg(unix)]
+mod mux;
+mod reconnect;
+mod remote_forward;
+mod server_key;
 mod session;
+#[cfg(not(target_arch = "wasm32"))]
+mod socks;
+#[cfg(feature = "russh-config")]
+mod ssh_config;
+pub mod sshfp;
+#[cfg(not(target_arch = "wasm32"))]
+mod x11;
+pub use crate::RemoteDisconnectInfo;
+pub use events::{ChannelOpenKind, DefaultHandler, Events, SessionEvent};
+#[cfg(not(target_arch = "wasm32"))]
+pub use forward::LocalForward;
+#[cfg(not(target_arch = "wasm32"))]
+pub use known_hosts::KnownHostsVerifier;
+#[cfg(unix)]
+pub use mux::{connect_mux, MuxClient, MuxMaster, MuxSession, MuxSessionRequest};
+pub use reconnect::{ReconnectPolicy, ReconnectingClient};
+pub use remote_forward::{ForwardedTcpIp, RemoteForward};
+pub use server_key::{
+    HostKeyVerifier, HostKeyVerifiers, KnownHostsStatus, PinnedKeys, ServerKeyCheck,
+    ServerKeyVerdict,
+};
+#[cfg(feature = "russh-config")]
+pub use ssh_config::connect_with_config;
+#[cfg(not(target_arch = "wasm32"))]
+pub use x11::X11Forwarding;
 
 /// Actual client session's state.
 ///
@@ -91,10 +128,66 @@ pub struct Session {
     pending_len: u32,
     inbound_channel_sender: Sender<Msg>,
     inbound_channel_receiver: Receiver<Msg>,
+    /// Notified when channels limited by
+    /// [`Config::channel_buffer_size`] are read.
+    inbound_read: Arc<Notify>,
     open_global_requests: VecDeque<GlobalRequestResponse>,
+    server_key_precheck: Option<ServerKeyPrecheck>,
+    server_sig_algs: Option<Vec<Algorithm>>,
+    server_extensions: Extensions,
+    remote_forwards: remote_forward::RemoteForwards,
+    negotiated: SharedNegotiated,
+    events: events::EventSender,
+    /// The TCP socket of the connection, when made by [`connect`] or
+    /// `connect_with_config`.
+    #[cfg(not(target_arch = "wasm32"))]
+    socket: Option<russh_util::net::SocketControl>,
+}
+
+/// What is known about a connection besides its stream, when it is
+/// made by [`connect`] or `connect_with_config`.
+#[derive(Default)]
+pub(crate) struct ConnectContext {
+    pub(crate) server_key_precheck: Option<ServerKeyPrecheck>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) socket: Option<russh_util::net::SocketControl>,
 }
 
-const STRICT_KEX_MSG_ORDER: &[u8] = &[msg::KEXINIT, msg::KEX_ECDH_REPLY, msg::NEWKEYS];
+/// The known_hosts lookup of [`connect_with_config`], performed
+/// before [`Config::host_key_verifier`] and
+/// [`Handler::verify_server_key`] are asked.
+#[cfg_attr(not(feature = "russh-config"), allow(dead_code))]
+pub(crate) struct ServerKeyPrecheck {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    /// The status of a key, and the verdict if it doesn't need to be
+    /// asked.
+    pub(crate) lookup: Box<KeyLookup>,
+    /// Add a key to the known_hosts file.
+    pub(crate) record: Box<KeyRecord>,
+}
+
+type KeyLookup = dyn Fn(&PublicKey) -> Result<(KnownHostsStatus, Option<ServerKeyVerdict>), crate::Error>
+    + Send
+    + Sync;
+
+type KeyRecord = dyn Fn(&PublicKey) -> Result<(), crate::Error> + Send + Sync;
+
+impl std::fmt::Debug for ServerKeyPrecheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("ServerKeyPrecheck")
+    }
+}
+
+/// Messages allowed at each sequence number of the initial key
+/// exchange in strict mode. Group exchanges take one more round trip,
+/// and `KEX_DH_GEX_GROUP` has the same number as `KEX_ECDH_REPLY`.
+const STRICT_KEX_MSG_ORDER: &[&[u8]] = &[
+    &[msg::KEXINIT],
+    &[msg::KEX_ECDH_REPLY],
+    &[msg::NEWKEYS, msg::KEX_DH_GEX_REPLY],
+    &[msg::NEWKEYS],
+];
 
 impl Drop for Session {
     fn drop(&mut self) {
@@ -106,8 +199,10 @@ impl Drop for Session {
 #[allow(clippy::large_enum_variant)]
 enum Reply {
     AuthSuccess,
-    AuthFailure,
-    ChannelOpenFailure,
+    AuthFailure {
+        remaining_methods: MethodSet,
+        partial_success: bool,
+    },
     SignRequest {
         key: ssh_key::PublicKey,
         data: CryptoVec,
@@ -126,7 +221,7 @@ pub enum Msg {
         method: auth::Method,
     },
     AuthInfoResponse {
-        responses: Vec<String>,
+        responses: Vec<SecretBuffer>,
     },
     Signed {
         data: CryptoVec,
@@ -150,6 +245,11 @@ pub enum Msg {
         socket_path: String,
         channel_ref: ChannelRef,
     },
+    ChannelOpenTun {
+        mode: TunMode,
+        unit: u32,
+        channel_ref: ChannelRef,
+    },
     TcpIpForward {
         /// Provide a channel for the reply result to request a reply from the server
         reply_channel: Option<oneshot::Sender<Option<u32>>>,
@@ -162,6 +262,14 @@ pub enum Msg {
         address: String,
         port: u32,
     },
+    /// A `tcpip-forward` whose connections are sent to `connections`
+    /// instead of the handler, see [`Handle::forward_remote`].
+    RemoteForward {
+        reply_channel: oneshot::Sender<Option<u32>>,
+        address: String,
+        port: u32,
+        connections: UnboundedSender<ForwardedTcpIp>,
+    },
     StreamLocalForward {
         /// Provide a channel for the reply result to request a reply from the server
         reply_channel: Option<oneshot::Sender<bool>>,
@@ -172,6 +280,7 @@ pub enum Msg {
         reply_channel: Option<oneshot::Sender<bool>>,
         socket_path: String,
     },
+    NoMoreSessions,
     Close {
         id: ChannelId,
     },
@@ -192,6 +301,10 @@ impl From<(ChannelId, ChannelMsg)> for Msg {
 #[derive(Debug)]
 pub enum KeyboardInteractiveAuthResponse {
     Success,
+    /// The server requires another method, see [`AuthResult::Partial`].
+    Partial {
+        remaining: MethodSet,
+    },
     Failure,
     InfoRequest {
         name: String,
@@ -200,19 +313,48 @@ pub enum KeyboardInteractiveAuthResponse {
     },
 }
 
-#[derive(Debug)]
+/// The outcome of an authentication attempt, along with what the
+/// server allows next if it failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthResult {
+    /// The user is authenticated.
+    Success,
+    /// The attempt succeeded, but the server requires the user to
+    /// authenticate with one of the `remaining` methods as well.
+    Partial { remaining: MethodSet },
+    /// The attempt failed.
+    Failure {
+        /// Methods that can continue authentication.
+        remaining_methods: MethodSet,
+    },
+}
+
+impl AuthResult {
+    /// Whether the user is authenticated.
+    pub fn success(&self) -> bool {
+        matches!(self, AuthResult::Success)
+    }
+
+    fn from_failure(remaining_methods: MethodSet, partial_success: bool) -> Self {
+        if partial_success {
+            AuthResult::Partial {
+                remaining: remaining_methods,
+            }
+        } else {
+            AuthResult::Failure { remaining_methods }
+        }
+    }
+}
+
+/// A prompt in a keyboard-interactive info request.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Prompt {
+    /// Text to show to the user.
     pub prompt: String,
+    /// Whether the user's answer may be echoed back to the terminal.
     pub echo: bool,
 }
 
-#[derive(Debug)]
-pub struct RemoteDisconnectInfo {
-    pub reason_code: crate::Disconnect,
-    pub message: String,
-    pub lang_tag: String,
-}
-
 #[derive(Debug)]
 pub enum DisconnectReason<E: From<crate::Error> + Send> {
     ReceivedDisconnect(RemoteDisconnectInfo),
@@ -225,6 +367,8 @@ pub struct Handle<H: Handler> {
     sender: Sender<Msg>,
     receiver: UnboundedReceiver<Reply>,
     join: russh_util::runtime::JoinHandle<Result<(), H::Error>>,
+    negotiated: SharedNegotiated,
+    events: events::EventSender,
 }
 
 impl<H: Handler> Drop for Handle<H> {
@@ -234,16 +378,32 @@ impl<H: Handler> Drop for Handle<H> {
 }
 
 impl<H: Handler> Handle<H> {
+    /// The algorithms negotiated by the latest key exchange, and the
+    /// session id.
+    pub fn negotiated(&self) -> Option<Negotiated> {
+        self.negotiated.get()
+    }
+
+    /// The events of the session left to the default methods of the
+    /// handler: authentication banners, unhandled global requests,
+    /// channels opened by the server, and the end of the session.
+    /// Events are only kept from this call on, and calling it again
+    /// ends the previous stream.
+    pub fn events(&self) -> Events {
+        self.events.subscribe()
+    }
+
     pub fn is_closed(&self) -> bool {
         self.sender.is_closed()
     }
 
-    /// Perform no authentication. This is useful for testing, but should not be
-    /// used in most other circumstances.
+    /// Perform no authentication. Servers rarely accept this, but the
+    /// failure tells which methods they allow, which is useful to pick
+    /// the next method to try instead of guessing.
     pub async fn authenticate_none<U: Into<String>>(
         &mut self,
         user: U,
-    ) -> Result<bool, crate::Error> {
+    ) -> Result<AuthResult, crate::Error> {
         let user = user.into();
         self.sender
             .send(Msg::Authenticate {
@@ -260,13 +420,13 @@ impl<H: Handler> Handle<H> {
         &mut self,
         user: U,
         password: P,
-    ) -> Result<bool, crate::Error> {
+    ) -> Result<AuthResult, crate::Error> {
         let user = user.into();
         self.sender
             .send(Msg::Authenticate {
                 user,
                 method: auth::Method::Password {
-                    password: password.into(),
+                    password: SecretBuffer::from(password.into()),
                 },
             })
             .await
@@ -308,7 +468,9 @@ impl<H: Handler> Handle<H> {
         responses: Vec<String>,
     ) -> Result<KeyboardInteractiveAuthResponse, crate::Error> {
         self.sender
-            .send(Msg::AuthInfoResponse { responses })
+            .send(Msg::AuthInfoResponse {
+                responses: responses.into_iter().map(SecretBuffer::from).collect(),
+            })
             .await
             .map_err(|_| crate::Error::SendError)?;
         self.wait_recv_keyboard_interactive_reply().await
@@ -320,7 +482,17 @@ impl<H: Handler> Handle<H> {
         loop {
             match self.receiver.recv().await {
                 Some(Reply::AuthSuccess) => return Ok(KeyboardInteractiveAuthResponse::Success),
-                Some(Reply::AuthFailure) => return Ok(KeyboardInteractiveAuthResponse::Failure),
+                Some(Reply::AuthFailure {
+                    remaining_methods,
+                    partial_success: true,
+                }) => {
+                    return Ok(KeyboardInteractiveAuthResponse::Partial {
+                        remaining: remaining_methods,
+                    })
+                }
+                Some(Reply::AuthFailure { .. }) => {
+                    return Ok(KeyboardInteractiveAuthResponse::Failure)
+                }
                 Some(Reply::AuthInfoRequest {
                     name,
                     instructions,
@@ -332,17 +504,71 @@ impl<H: Handler> Handle<H> {
                         prompts,
                     });
                 }
+                None => return Ok(KeyboardInteractiveAuthResponse::Failure),
                 _ => {}
             }
         }
     }
 
-    async fn wait_recv_reply(&mut self) -> Result<bool, crate::Error> {
+    /// Perform Keyboard-Interactive based SSH authentication, calling
+    /// `respond` with the name, instructions and prompts of every info
+    /// request sent by the server, for as many rounds as the server
+    /// needs (for instance a password followed by a one-time code).
+    ///
+    /// `respond` must return one answer per prompt, in order.
+    pub async fn authenticate_keyboard_interactive<U, S, F, Fut>(
+        &mut self,
+        user: U,
+        submethods: S,
+        mut respond: F,
+    ) -> Result<AuthResult, crate::Error>
+    where
+        U: Into<String>,
+        S: Into<Option<String>>,
+        F: FnMut(String, String, Vec<Prompt>) -> Fut,
+        Fut: Future<Output = Vec<String>>,
+    {
+        let mut reply = self
+            .authenticate_keyboard_interactive_start(user, submethods)
+            .await?;
+        loop {
+            match reply {
+                KeyboardInteractiveAuthResponse::Success => return Ok(AuthResult::Success),
+                KeyboardInteractiveAuthResponse::Partial { remaining } => {
+                    return Ok(AuthResult::Partial { remaining })
+                }
+                KeyboardInteractiveAuthResponse::Failure => {
+                    return Ok(AuthResult::Failure {
+                        remaining_methods: MethodSet::empty(),
+                    })
+                }
+                KeyboardInteractiveAuthResponse::InfoRequest {
+                    name,
+                    instructions,
+                    prompts,
+                } => {
+                    let responses = respond(name, instructions, prompts).await;
+                    reply = self
+                        .authenticate_keyboard_interactive_respond(responses)
+                        .await?;
+                }
+            }
+        }
+    }
+
+    async fn wait_recv_reply(&mut self) -> Result<AuthResult, crate::Error> {
         loop {
             match self.receiver.recv().await {
-                Some(Reply::AuthSuccess) => return Ok(true),
-                Some(Reply::AuthFailure) => return Ok(false),
-                None => return Ok(false),
+                Some(Reply::AuthSuccess) => return Ok(AuthResult::Success),
+                Some(Reply::AuthFailure {
+                    remaining_methods,
+                    partial_success,
+                }) => return Ok(AuthResult::from_failure(remaining_methods, partial_success)),
+                None => {
+                    return Ok(AuthResult::Failure {
+                        remaining_methods: MethodSet::empty(),
+                    })
+                }
                 _ => {}
             }
         }
@@ -353,7 +579,7 @@ impl<H: Handler> Handle<H> {
         &mut self,
         user: U,
         key: Arc<PrivateKey>,
-    ) -> Result<bool, crate::Error> {
+    ) -> Result<AuthResult, crate::Error> {
         let user = user.into();
         self.sender
             .send(Msg::Authenticate {
@@ -371,7 +597,7 @@ impl<H: Handler> Handle<H> {
         user: U,
         key: Arc<PrivateKey>,
         cert: Certificate,
-    ) -> Result<bool, crate::Error> {
+    ) -> Result<AuthResult, crate::Error> {
         let user = user.into();
         self.sender
             .send(Msg::Authenticate {
@@ -383,15 +609,43 @@ impl<H: Handler> Handle<H> {
         self.wait_recv_reply().await
     }
 
+    /// Perform host-based SSH authentication (RFC 4252, section 9),
+    /// signing the request with `host_key`, the private key of the
+    /// local host. `client_host` is the fully qualified name of the
+    /// local host, and `client_user` the name of the user on it.
+    pub async fn authenticate_hostbased<U: Into<String>, C: Into<String>, L: Into<String>>(
+        &mut self,
+        user: U,
+        host_key: Arc<PrivateKey>,
+        client_host: C,
+        client_user: L,
+    ) -> Result<AuthResult, crate::Error> {
+        let user = user.into();
+        self.sender
+            .send(Msg::Authenticate {
+                user,
+                method: auth::Method::Hostbased {
+                    key: host_key,
+                    client_host: client_host.into(),
+                    client_user: client_user.into(),
+                },
+            })
+            .await
+            .map_err(|_| crate::Error::SendError)?;
+        self.wait_recv_reply().await
+    }
+
     /// Authenticate using a custom method that implements the
-    /// [`Signer`][auth::Signer] trait. Currently, this crate only provides an
-    /// implementation for an [SSH agent][russh_keys::agent::client::AgentClient].
+    /// [`Signer`][auth::Signer] trait. This crate provides implementations
+    /// for an [SSH agent][russh_keys::agent::client::AgentClient], for
+    /// [security keys][russh_keys::sk::SkSigner], and for keys held
+    /// elsewhere behind an `Arc<dyn `[`KeySigner`][russh_keys::key::KeySigner]`>`.
     pub async fn authenticate_publickey_with<U: Into<String>, S: auth::Signer>(
         &mut self,
         user: U,
         key: ssh_key::PublicKey,
         signer: &mut S,
-    ) -> Result<bool, S::Error> {
+    ) -> Result<AuthResult, S::Error> {
         let user = user.into();
         if self
             .sender
@@ -407,8 +661,11 @@ impl<H: Handler> Handle<H> {
         loop {
             let reply = self.receiver.recv().await;
             match reply {
-                Some(Reply::AuthSuccess) => return Ok(true),
-                Some(Reply::AuthFailure) => return Ok(false),
+                Some(Reply::AuthSuccess) => return Ok(AuthResult::Success),
+                Some(Reply::AuthFailure {
+                    remaining_methods,
+                    partial_success,
+                }) => return Ok(AuthResult::from_failure(remaining_methods, partial_success)),
                 Some(Reply::SignRequest { key, data }) => {
                     let data = signer.auth_publickey_sign(&key, data).await;
                     let data = match data {
@@ -419,64 +676,51 @@ impl<H: Handler> Handle<H> {
                         return Err((crate::SendError {}).into());
                     }
                 }
-                None => return Ok(false),
-                _ => {}
-            }
-        }
-    }
-
-    /// Wait for confirmation that a channel is open
-    async fn wait_channel_confirmation(
-        &self,
-        mut receiver: UnboundedReceiver<ChannelMsg>,
-        window_size_ref: Arc<Mutex<u32>>,
-    ) -> Result<Channel<Msg>, crate::Error> {
-        loop {
-            match receiver.recv().await {
-                Some(ChannelMsg::Open {
-                    id,
-                    max_packet_size,
-                    window_size,
-                }) => {
-                    *window_size_ref.lock().await = window_size;
-
-                    return Ok(Channel {
-                        id,
-                        sender: self.sender.clone(),
-                        receiver,
-                        max_packet_size,
-                        window_size: window_size_ref,
-                    });
-                }
-                Some(ChannelMsg::OpenFailure(reason)) => {
-                    return Err(crate::Error::ChannelOpenFailure(reason));
-                }
                 None => {
-                    return Err(crate::Error::Disconnect);
-                }
-                msg => {
-                    debug!("msg = {:?}", msg);
+                    return Ok(AuthResult::Failure {
+                        remaining_methods: MethodSet::empty(),
+                    })
                 }
+                _ => {}
             }
         }
     }
 
-    /// Request a session channel (the most basic type of
-    /// channel). This function returns `Some(..)` immediately if the
-    /// connection is authenticated, but the channel only becomes
-    /// usable when it's confirmed by the server, as indicated by the
-    /// `confirmed` field of the corresponding `Channel`.
-    pub async fn channel_open_session(&self) -> Result<Channel<Msg>, crate::Error> {
-        let (sender, receiver) = unbounded_channel();
-        let channel_ref = ChannelRef::new(sender);
-        let window_size_ref = channel_ref.window_size().clone();
-
+    /// Perform `gssapi-with-mic` authentication, usually with Kerberos,
+    /// using a security context provided by the application.
+    #[cfg(feature = "gssapi")]
+    pub async fn authenticate_gssapi_with_mic<
+        U: Into<String>,
+        C: crate::gssapi::ClientContext + 'static,
+    >(
+        &mut self,
+        user: U,
+        context: C,
+    ) -> Result<AuthResult, crate::Error> {
+        let user = user.into();
         self.sender
-            .send(Msg::ChannelOpenSession { channel_ref })
+            .send(Msg::Authenticate {
+                user,
+                method: auth::Method::GssapiWithMic {
+                    context: std::sync::Mutex::new(Box::new(context)),
+                },
+            })
             .await
             .map_err(|_| crate::Error::SendError)?;
-        self.wait_channel_confirmation(receiver, window_size_ref)
-            .await
+        self.wait_recv_reply().await
+    }
+
+    /// Wait for confirmation that a channel is open
+    /// Request a session channel (the most basic type of channel), and
+    /// wait for the server to confirm it.
+    ///
+    /// Like the other `channel_open_*` methods, this can be called
+    /// several times concurrently, on the same [`Handle`] or its
+    /// clones: each call waits for the answer to its own request, in
+    /// whichever order the server answers them. If the call is
+    /// cancelled, the channel is closed when it is confirmed.
+    pub async fn channel_open_session(&self) -> Result<Channel<Msg>, crate::Error> {
+        open_session(self.sender.clone()).await
     }
 
     /// Request an X11 channel, on which the X11 protocol may be tunneled.
@@ -485,20 +729,13 @@ impl<H: Handler> Handle<H> {
         originator_address: A,
         originator_port: u32,
     ) -> Result<Channel<Msg>, crate::Error> {
-        let (sender, receiver) = unbounded_channel();
-        let channel_ref = ChannelRef::new(sender);
-        let window_size_ref = channel_ref.window_size().clone();
-
-        self.sender
-            .send(Msg::ChannelOpenX11 {
-                originator_address: originator_address.into(),
-                originator_port,
-                channel_ref,
-            })
-            .await
-            .map_err(|_| crate::Error::SendError)?;
-        self.wait_channel_confirmation(receiver, window_size_ref)
-            .await
+        let originator_address = originator_address.into();
+        open_channel(self.sender.clone(), |channel_ref| Msg::ChannelOpenX11 {
+            originator_address,
+            originator_port,
+            channel_ref,
+        })
+        .await
     }
 
     /// Open a TCP/IP forwarding channel. This is usually done when a
@@ -516,41 +753,49 @@ impl<H: Handler> Handle<H> {
         originator_address: B,
         originator_port: u32,
     ) -> Result<Channel<Msg>, crate::Error> {
-        let (sender, receiver) = unbounded_channel();
-        let channel_ref = ChannelRef::new(sender);
-        let window_size_ref = channel_ref.window_size().clone();
-
-        self.sender
-            .send(Msg::ChannelOpenDirectTcpIp {
-                host_to_connect: host_to_connect.into(),
-                port_to_connect,
-                originator_address: originator_address.into(),
-                originator_port,
-                channel_ref,
-            })
-            .await
-            .map_err(|_| crate::Error::SendError)?;
-        self.wait_channel_confirmation(receiver, window_size_ref)
-            .await
-    }
-
+        open_direct_tcpip(
+            self.sender.clone(),
+            host_to_connect.into(),
+            port_to_connect,
+            originator_address.into(),
+            originator_port,
+        )
+        .await
+    }
+
+    /// Open a `direct-streamlocal@openssh.com` channel, to the UNIX
+    /// socket at `socket_path` on the server, such as
+    /// `/var/run/docker.sock`.
     pub async fn channel_open_direct_streamlocal<S: Into<String>>(
         &self,
         socket_path: S,
     ) -> Result<Channel<Msg>, crate::Error> {
-        let (sender, receiver) = unbounded_channel();
-        let channel_ref = ChannelRef::new(sender);
-        let window_size_ref = channel_ref.window_size().clone();
-
-        self.sender
-            .send(Msg::ChannelOpenDirectStreamLocal {
-                socket_path: socket_path.into(),
+        let socket_path = socket_path.into();
+        open_channel(self.sender.clone(), |channel_ref| {
+            Msg::ChannelOpenDirectStreamLocal {
+                socket_path,
                 channel_ref,
-            })
-            .await
-            .map_err(|_| crate::Error::SendError)?;
-        self.wait_channel_confirmation(receiver, window_size_ref)
-            .await
+            }
+        })
+        .await
+    }
+
+    /// Open a `tun@openssh.com` channel, to forward IP packets or
+    /// Ethernet frames to the tunnel device `unit` on the server (or
+    /// any free one, with [`TUN_ANY_UNIT`](crate::tun::TUN_ANY_UNIT)).
+    /// Wrap it in a [`TunChannel`](crate::tun::TunChannel) to exchange
+    /// packets.
+    pub async fn channel_open_tun(
+        &self,
+        mode: TunMode,
+        unit: u32,
+    ) -> Result<Channel<Msg>, crate::Error> {
+        open_channel(self.sender.clone(), |channel_ref| Msg::ChannelOpenTun {
+            mode,
+            unit,
+            channel_ref,
+        })
+        .await
     }
 
     /// Requests the server to open a TCP/IP forward channel
@@ -607,7 +852,9 @@ impl<H: Handler> Handle<H> {
         }
     }
 
-    // Requests the server to open a UDS forward channel
+    /// Requests the server to listen on the UNIX socket `socket_path`.
+    /// Connections to it cause calls to
+    /// [`Handler::server_channel_open_forwarded_streamlocal`].
     pub async fn streamlocal_forward<A: Into<String>>(
         &mut self,
         socket_path: A,
@@ -631,7 +878,7 @@ impl<H: Handler> Handle<H> {
         }
     }
 
-    // Requests the server to close a UDS forward channel
+    /// Requests the server to stop listening on `socket_path`.
     pub async fn cancel_streamlocal_forward<A: Into<String>>(
         &self,
         socket_path: A,
@@ -655,6 +902,16 @@ impl<H: Handler> Handle<H> {
         }
     }
 
+    /// Tell the server that no more session channels will be opened
+    /// on this connection, see [`Session::no_more_sessions`]. Opening
+    /// other types of channels, such as forwardings, is still allowed.
+    pub async fn no_more_sessions(&self) -> Result<(), crate::Error> {
+        self.sender
+            .send(Msg::NoMoreSessions)
+            .await
+            .map_err(|_| crate::Error::SendError)
+    }
+
     /// Sends a disconnect message.
     pub async fn disconnect(
         &self,
@@ -688,6 +945,89 @@ impl<H: Handler> Handle<H> {
     }
 }
 
+async fn wait_channel_confirmation(
+    sender: Sender<Msg>,
+    mut receiver: UnboundedReceiver<ChannelMsg>,
+    window_size_ref: WindowSizeRef,
+) -> Result<Channel<Msg>, crate::Error> {
+    loop {
+        match receiver.recv().await {
+            Some(ChannelMsg::Open {
+                id,
+                max_packet_size,
+                window_size,
+            }) => {
+                window_size_ref.update(window_size).await;
+
+                return Ok(Channel {
+                    id,
+                    sender,
+                    receiver,
+                    max_packet_size,
+                    window_size: window_size_ref,
+                    pending: Default::default(),
+                });
+            }
+            Some(ChannelMsg::OpenFailure(reason)) => {
+                return Err(crate::Error::ChannelOpenFailure(reason));
+            }
+            None => {
+                return Err(crate::Error::Disconnect);
+            }
+            msg => {
+                debug!("msg = {:?}", msg);
+            }
+        }
+    }
+}
+
+/// Send the request returned by `open` for a new channel through
+/// `sender`, and wait for its confirmation. Requests are matched with
+/// their answers by the channel's id, so that any number of them can
+/// be in flight at the same time.
+async fn open_channel<F: FnOnce(ChannelRef) -> Msg>(
+    sender: Sender<Msg>,
+    open: F,
+) -> Result<Channel<Msg>, crate::Error> {
+    let (channel_sender, receiver) = unbounded_channel();
+    let channel_ref = ChannelRef::new(channel_sender);
+    let window_size_ref = channel_ref.window_size().clone();
+
+    sender
+        .send(open(channel_ref))
+        .await
+        .map_err(|_| crate::Error::SendError)?;
+    wait_channel_confirmation(sender, receiver, window_size_ref).await
+}
+
+/// Open a session channel through `sender`, like
+/// [`Handle::channel_open_session`].
+async fn open_session(sender: Sender<Msg>) -> Result<Channel<Msg>, crate::Error> {
+    open_channel(sender, |channel_ref| Msg::ChannelOpenSession {
+        channel_ref,
+    })
+    .await
+}
+
+/// Open a `direct-tcpip` channel through `sender`, without borrowing
+/// the [`Handle`], so that forwarding tasks can open channels.
+async fn open_direct_tcpip(
+    sender: Sender<Msg>,
+    host_to_connect: String,
+    port_to_connect: u32,
+    originator_address: String,
+    originator_port: u32,
+) -> Result<Channel<Msg>, crate::Error> {
+    open_channel(sender, |channel_ref| Msg::ChannelOpenDirectTcpIp {
+        host_to_connect,
+        port_to_connect,
+        originator_address,
+        originator_port,
+        channel_ref,
+    })
+    .await
+}
+
 impl<H: Handler> Future for Handle<H> {
     type Output = Result<(), H::Error>;
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
@@ -709,26 +1049,119 @@ impl<H: Handler> Future for Handle<H> {
 /// commands, etc. The future will resolve to an error if the connection fails.
 /// This function creates a connection to the `addr` specified using a
 /// [`tokio::net::TcpStream`] and then calls [`connect_stream`] under the hood.
+///
+/// Host names are resolved without blocking the runtime, and all their
+/// addresses are tried, IPv6 and IPv4 alternately, with staggered
+/// attempts as described in RFC 8305 (see [`russh_util::net::connect`]).
+///
+/// With a [`Config::proxy`], the connection goes through the proxy, to
+/// each of the addresses in turn. To have the proxy resolve host names,
+/// connect with [`russh_util::net::ProxyConnector::connect`] and
+/// [`connect_stream`] instead.
 #[cfg(not(target_arch = "wasm32"))]
 pub async fn connect<H: Handler + Send + 'static, A: tokio::net::ToSocketAddrs>(
     config: Arc<Config>,
     addrs: A,
     handler: H,
 ) -> Result<Handle<H>, H::Error> {
-    use russh_keys::map_err;
+    use russh_util::net::{AddressFamily, SocketControl};
+
+    let options = &config.socket_options;
+    let connecting = async {
+        let addrs = russh_util::net::resolve(addrs, AddressFamily::Any).await?;
+        let Some(ref proxy) = config.proxy else {
+            return russh_util::net::connect_with_options(&addrs, None, options).await;
+        };
+        let mut last_error = None;
+        for addr in addrs {
+            match proxy
+                .connect(&addr.ip().to_string(), addr.port(), None, options)
+                .await
+            {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!("Could not connect to {:?} through the proxy: {:?}", addr, e);
+                    last_error = Some(e)
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "no address to connect to")
+        }))
+    };
+    let socket = match config.connection_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, connecting)
+            .await
+            .map_err(|_| crate::Error::ConnectionTimeout)?,
+        None => connecting.await,
+    }
+    .map_err(crate::Error::from)?;
+    let context = ConnectContext {
+        socket: SocketControl::new(&socket, options.clone()).ok(),
+        ..Default::default()
+    };
+    connect_stream_with_context(config, socket, handler, context).await
+}
 
-    let socket = map_err!(tokio::net::TcpStream::connect(addrs).await)?;
-    connect_stream(config, socket, handler).await
+/// Proxy an agent forwarding channel opened by the server (see
+/// [`Handler::server_channel_open_agent_forward`]) to the local agent at
+/// `SSH_AUTH_SOCK`, until either side closes it.
+#[cfg(unix)]
+pub async fn forward_agent_channel(channel: Channel<Msg>) -> Result<(), crate::Error> {
+    let path =
+        std::env::var("SSH_AUTH_SOCK").map_err(|_| russh_keys::Error::EnvVar("SSH_AUTH_SOCK"))?;
+    let mut agent = tokio::net::UnixStream::connect(path).await?;
+    let mut stream = channel.into_stream();
+    tokio::io::copy_bidirectional(&mut stream, &mut agent).await?;
+    Ok(())
 }
 
 /// Connect a stream to a server. This stream must implement
 /// [`tokio::io::AsyncRead`] and [`tokio::io::AsyncWrite`], as well as [`Unpin`]
 /// and [`Send`]. Typically, you may prefer to use [`connect`], which uses a
 /// [`tokio::net::TcpStream`] and then calls this function under the hood.
+/// See [`crate::transport`] about other transports.
+///
+/// The exchange of versions and the first key exchange must complete
+/// within [`Config::handshake_timeout`], or this fails with
+/// [`crate::Error::KexTimeout`].
 pub async fn connect_stream<H, R>(
+    config: Arc<Config>,
+    stream: R,
+    handler: H,
+) -> Result<Handle<H>, H::Error>
+where
+    H: Handler + Send + 'static,
+    R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    connect_stream_with_context(config, stream, handler, ConnectContext::default()).await
+}
+
+pub(crate) async fn connect_stream_with_context<H, R>(
+    config: Arc<Config>,
+    stream: R,
+    handler: H,
+    context: ConnectContext,
+) -> Result<Handle<H>, H::Error>
+where
+    H: Handler + Send + 'static,
+    R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match config.handshake_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, handshake(config, stream, handler, context))
+            .await
+            .unwrap_or_else(|_| Err(crate::Error::KexTimeout.into())),
+        None => handshake(config, stream, handler, context).await,
+    }
+}
+
+/// Exchange versions, start the session and wait for the end of the
+/// first key exchange.
+async fn handshake<H, R>(
     config: Arc<Config>,
     mut stream: R,
     handler: H,
+    context: ConnectContext,
 ) -> Result<Handle<H>, H::Error>
 where
     H: Handler + Send + 'static,
@@ -736,6 +1169,7 @@ where
 {
     // Writing SSH id.
     let mut write_buffer = SSHBuffer::new();
+    write_buffer.observer = config.packet_observer.clone();
     write_buffer.send_ssh_id(&config.as_ref().client_id);
     map_err!(stream.write_all(&write_buffer.buffer).await)?;
 
@@ -775,9 +1209,21 @@ where
         session_receiver,
         session_sender,
     );
+    session.server_key_precheck = context.server_key_precheck;
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        session.socket = context.socket;
+    }
     session.read_ssh_id(sshid)?;
+    let negotiated = session.negotiated.clone();
+    let events = session.events.clone();
     let (kex_done_signal, kex_done_signal_rx) = oneshot::channel();
-    let join = russh_util::runtime::spawn(session.run(stream, handler, Some(kex_done_signal)));
+    let span = logging::connection_span("client", logging::next_connection_id());
+    let join = russh_util::runtime::spawn(
+        session
+            .run(stream, handler, Some(kex_done_signal))
+            .instrument(span),
+    );
 
     if kex_done_signal_rx.await.is_err() {
         // kex_done_signal Sender is dropped when the session
@@ -790,6 +1236,8 @@ where
         sender: handle_sender,
         receiver: handle_receiver,
         join,
+        negotiated,
+        events,
     })
 }
 
@@ -818,10 +1266,19 @@ impl Session {
             target_window_size,
             inbound_channel_sender,
             inbound_channel_receiver,
+            inbound_read: Arc::new(Notify::new()),
             channels: HashMap::new(),
             pending_reads: Vec::new(),
             pending_len: 0,
             open_global_requests: VecDeque::new(),
+            server_key_precheck: None,
+            server_sig_algs: None,
+            server_extensions: Extensions::new(),
+            remote_forwards: HashMap::new(),
+            negotiated: SharedNegotiated::default(),
+            events: Default::default(),
+            #[cfg(not(target_arch = "wasm32"))]
+            socket: None,
         }
     }
 
@@ -845,7 +1302,13 @@ impl Session {
         self.inbound_channel_receiver.close();
         map_err!(stream_write.shutdown().await)?;
         match result {
+            Ok(v) if kex_done_signal.is_some() => {
+                // Disconnected before the end of the first key
+                // exchange, tell `connect_stream` why.
+                Err(crate::Error::RemoteDisconnect(v).into())
+            }
             Ok(v) => {
+                self.events.disconnected(Some(v.clone()));
                 handler
                     .disconnected(DisconnectReason::ReceivedDisconnect(v))
                     .await?;
@@ -861,6 +1324,7 @@ impl Session {
                     // The kex signal has been consumed, so no one is
                     // awaiting the result of this coroutine
                     // We're better off passing the error into the Handler
+                    self.events.disconnected(None);
                     handler.disconnected(DisconnectReason::Error(e)).await?;
                     Err(H::Error::from(crate::Error::Disconnect))
                 }
@@ -904,6 +1368,9 @@ impl Session {
             crate::future_or_pending(self.common.config.inactivity_timeout, tokio::time::sleep);
         pin!(inactivity_timer);
 
+        let rekey_timer = tokio::time::sleep(self.common.config.limits.rekey_time_limit);
+        pin!(rekey_timer);
+
         let reading = start_reading(stream_read, buffer, opening_cipher);
         pin!(reading);
 
@@ -939,12 +1406,20 @@ impl Session {
                         &buffer.buffer[5..]
                     };
                     if !buf.is_empty() {
+                        observe(
+                            &self.common.config.packet_observer,
+                            Direction::Inbound,
+                            buffer.seqn.0.wrapping_sub(1),
+                            buf,
+                        );
                         #[allow(clippy::indexing_slicing)] // length checked
                         if buf[0] == crate::msg::DISCONNECT {
                             result = self.process_disconnect(&buf[1..]);
                         } else {
                             self.common.received_data = true;
-                            reply(self, handler, kex_done_signal, &mut buffer.seqn, buf).await?;
+                            reply(self, handler, kex_done_signal, &mut buffer.seqn, buf)
+                                .instrument(logging::packet_span(buf))
+                                .await?;
                         }
                     }
 
@@ -952,7 +1427,7 @@ impl Session {
                     reading.set(start_reading(stream_read, buffer, opening_cipher));
                 }
                 () = &mut keepalive_timer => {
-                    if self.common.config.keepalive_max != 0 && self.common.alive_timeouts > self.common.config.keepalive_max {
+                    if self.common.config.keepalive_max != 0 && self.common.alive_timeouts >= self.common.config.keepalive_max {
                         debug!("Timeout, server not responding to keepalives");
                         return Err(crate::Error::KeepaliveTimeout.into());
                     }
@@ -964,6 +1439,17 @@ impl Session {
                     debug!("timeout");
                     return Err(crate::Error::InactivityTimeout.into());
                 }
+                () = &mut rekey_timer => {
+                    let wait = self.time_to_rekey();
+                    let next = if wait.is_zero() {
+                        debug!("rekey time limit reached");
+                        self.request_rekey()?;
+                        self.common.config.limits.rekey_time_limit
+                    } else {
+                        wait
+                    };
+                    rekey_timer.as_mut().reset(tokio::time::Instant::now() + next);
+                }
                 msg = self.receiver.recv(), if !self.is_rekeying() => {
                     match msg {
                         Some(msg) => self.handle_msg(msg)?,
@@ -981,6 +1467,9 @@ impl Session {
                         }
                     }
                 }
+                () = self.inbound_read.notified(), if !self.is_rekeying() => {
+                    self.reopen_windows()?;
+                }
                 msg = self.inbound_channel_receiver.recv(), if !self.is_rekeying() => {
                     match msg {
                         Some(msg) => self.handle_msg(msg)?,
@@ -1013,9 +1502,11 @@ impl Session {
             self.common.write_buffer.buffer.clear();
             if let Some(ref mut enc) = self.common.encrypted {
                 if let EncryptedState::InitCompression = enc.state {
-                    enc.client_compression.init_compress(&mut enc.compress);
+                    enc.client_compression
+                        .start_compress(true, &mut enc.compress);
                     enc.state = EncryptedState::Authenticated;
                 }
+                enc.update_queued(&self.channels);
             }
 
             if self.common.received_data {
@@ -1049,19 +1540,10 @@ impl Session {
 
     fn process_disconnect<E: From<crate::Error> + Send>(
         &mut self,
-        mut r: &[u8],
+        r: &[u8],
     ) -> Result<RemoteDisconnectInfo, E> {
         self.common.disconnected = true;
-
-        let reason_code = map_err!(u32::decode(&mut r))?.try_into()?;
-        let message = map_err!(String::decode(&mut r))?;
-        let lang_tag = map_err!(String::decode(&mut r))?;
-
-        Ok(RemoteDisconnectInfo {
-            reason_code,
-            message,
-            lang_tag,
-        })
+        Ok(RemoteDisconnectInfo::parse(r)?)
     }
 
     fn handle_msg(&mut self, msg: Msg) -> Result<(), crate::Error> {
@@ -1105,11 +1587,25 @@ impl Session {
                 let id = self.channel_open_direct_streamlocal(&socket_path)?;
                 self.channels.insert(id, channel_ref);
             }
+            Msg::ChannelOpenTun {
+                mode,
+                unit,
+                channel_ref,
+            } => {
+                let id = self.channel_open_tun(mode, unit)?;
+                self.channels.insert(id, channel_ref);
+            }
             Msg::TcpIpForward {
                 reply_channel,
                 address,
                 port,
             } => self.tcpip_forward(reply_channel, &address, port)?,
+            Msg::RemoteForward {
+                reply_channel,
+                address,
+                port,
+                connections,
+            } => self.remote_forward(reply_channel, address, port, connections)?,
             Msg::CancelTcpIpForward {
                 reply_channel,
                 address,
@@ -1123,6 +1619,7 @@ impl Session {
                 reply_channel,
                 socket_path,
             } => self.cancel_streamlocal_forward(reply_channel, &socket_path)?,
+            Msg::NoMoreSessions => self.no_more_sessions()?,
             Msg::Disconnect {
                 reason,
                 description,
@@ -1133,7 +1630,7 @@ impl Session {
                 self.eof(id)?;
             }
             Msg::Channel(id, ChannelMsg::ExtendedData { data, ext }) => {
-                self.extended_data(id, ext, data)?;
+                self.extended_data(id, ext.into(), data)?;
             }
             Msg::Channel(
                 id,
@@ -1201,6 +1698,9 @@ impl Session {
                 },
             ) => self.exec(id, want_reply, &command)?,
             Msg::Channel(id, ChannelMsg::Signal { signal }) => self.signal(id, signal)?,
+            Msg::Channel(id, ChannelMsg::Break { want_reply, length }) => {
+                self.send_break(id, want_reply, length)?
+            }
             Msg::Channel(id, ChannelMsg::RequestSubsystem { want_reply, name }) => {
                 self.request_subsystem(want_reply, id, &name)?
             }
@@ -1217,6 +1717,30 @@ impl Session {
         Ok(())
     }
 
+    /// The window limit of `channel` from
+    /// [`Config::channel_buffer_size`], if its reader is behind.
+    fn window_limit(&self, channel: ChannelId) -> Option<u32> {
+        self.channels
+            .get(&channel)?
+            .window_limit(self.common.config.channel_buffer_size, &self.inbound_read)
+    }
+
+    /// Reopen the windows of the channels whose readers caught up.
+    fn reopen_windows(&mut self) -> Result<(), crate::Error> {
+        let max_window_size = self.common.config.max_window_size;
+        let limits: Vec<_> = self
+            .channels
+            .keys()
+            .filter_map(|&id| Some((id, self.window_limit(id)?)))
+            .collect();
+        if let Some(ref mut enc) = self.common.encrypted {
+            for (id, limit) in limits {
+                enc.adjust_window_size(id, &[], max_window_size, Some(limit))?;
+            }
+        }
+        Ok(())
+    }
+
     fn is_rekeying(&self) -> bool {
         if let Some(ref enc) = self.common.encrypted {
             enc.rekey.is_some()
@@ -1252,29 +1776,52 @@ impl Session {
     /// Flush the temporary cleartext buffer into the encryption
     /// buffer. This does *not* flush to the socket.
     fn flush(&mut self) -> Result<(), crate::Error> {
-        if let Some(ref mut enc) = self.common.encrypted {
-            if enc.flush(
+        let needs_rekey = if let Some(ref mut enc) = self.common.encrypted {
+            enc.flush(
                 &self.common.config.as_ref().limits,
                 &mut *self.common.cipher.local_to_remote,
                 &mut self.common.write_buffer,
-            )? {
-                info!("Re-exchanging keys");
-                if enc.rekey.is_none() {
-                    if let Some(exchange) = enc.exchange.take() {
-                        let mut kexinit = KexInit::initiate_rekey(exchange, &enc.session_id);
-                        kexinit.client_write(
-                            self.common.config.as_ref(),
-                            &mut *self.common.cipher.local_to_remote,
-                            &mut self.common.write_buffer,
-                        )?;
-                        enc.rekey = Some(Kex::Init(kexinit))
-                    }
+            )?
+        } else {
+            false
+        };
+        if needs_rekey {
+            self.initiate_rekey()?;
+        }
+        Ok(())
+    }
+
+    /// Send a `KEXINIT`, unless a key exchange is already running.
+    /// Pending packets must have been flushed before.
+    fn initiate_rekey(&mut self) -> Result<(), crate::Error> {
+        if let Some(ref mut enc) = self.common.encrypted {
+            if enc.rekey.is_none() {
+                if let Some(exchange) = enc.exchange.take() {
+                    info!("Re-exchanging keys");
+                    let mut kexinit = KexInit::initiate_rekey(exchange, &enc.session_id);
+                    kexinit.client_write(
+                        self.common.config.as_ref(),
+                        &mut *self.common.cipher.local_to_remote,
+                        &mut self.common.write_buffer,
+                    )?;
+                    enc.rekey = Some(Kex::Init(kexinit))
                 }
             }
         }
         Ok(())
     }
 
+    /// Time until the keys have to be re-exchanged because of
+    /// [`Limits::rekey_time_limit`].
+    fn time_to_rekey(&self) -> std::time::Duration {
+        let limit = self.common.config.limits.rekey_time_limit;
+        match self.common.encrypted {
+            Some(ref enc) => limit
+                .saturating_sub(russh_util::time::Instant::now().duration_since(enc.last_rekey)),
+            None => limit,
+        }
+    }
+
     /// Send a `ChannelMsg` from the background handler to the client.
     pub fn send_channel_msg(&self, channel: ChannelId, msg: ChannelMsg) -> bool {
         if let Some(chan) = self.channels.get(&channel) {
@@ -1294,6 +1841,8 @@ impl KexDhDone {
     async fn server_key_check<H: Handler, R: Reader>(
         mut self,
         rekey: bool,
+        precheck: Option<&ServerKeyPrecheck>,
+        verifier: Option<&dyn HostKeyVerifier>,
         handler: &mut H,
         r: &mut R,
     ) -> Result<NewKeys, H::Error> {
@@ -1301,9 +1850,36 @@ impl KexDhDone {
         let pubkey = map_err!(parse_public_key(&pubkey))?;
         debug!("server_public_Key: {:?}", pubkey);
         if !rekey {
-            let check = handler.check_server_key(&pubkey).await?;
-            if !check {
-                return Err(crate::Error::UnknownKey.into());
+            let (known_hosts, mut verdict) = match precheck {
+                Some(precheck) => (precheck.lookup)(&pubkey)?,
+                None => (KnownHostsStatus::NotChecked, None),
+            };
+            let check = ServerKeyCheck {
+                host: precheck.map(|p| (p.host.as_str(), p.port)),
+                key: &pubkey,
+                known_hosts,
+            };
+            if verdict.is_none() {
+                if let Some(verifier) = verifier {
+                    verdict = verifier.verify(&check).await?
+                }
+            }
+            let verdict = match verdict {
+                Some(verdict) => verdict,
+                None => handler.verify_server_key(&check).await?,
+            };
+            debug!("server key verdict: {:?}", verdict);
+            match verdict {
+                ServerKeyVerdict::Accept => {}
+                ServerKeyVerdict::AcceptAndRecord => match (precheck, verifier) {
+                    (Some(precheck), _) => (precheck.record)(&pubkey)?,
+                    (None, Some(verifier)) => verifier.record(&check).await?,
+                    (None, None) => debug!("no known_hosts file to record the server key"),
+                },
+                ServerKeyVerdict::Reject => return Err(crate::Error::UnknownKey.into()),
+                ServerKeyVerdict::Mismatch { line, .. } => {
+                    return Err(crate::Error::KeyChanged { line }.into())
+                }
             }
         }
         HASH_BUFFER.with(|buffer| {
@@ -1326,19 +1902,28 @@ impl KexDhDone {
                         .compute_exchange_hash(&pubkey_vec, &self.exchange, &mut buffer)?;
 
                 debug!("exchange hash: {:?}", hash);
-                let signature = {
+                let (sig_type, signature) = {
                     let mut r = &signature[..];
                     let sig_type = map_err!(String::decode(&mut r))?;
                     debug!("sig_type: {:?}", sig_type);
-                    map_err!(Bytes::decode(&mut r))?
+                    (sig_type, map_err!(Bytes::decode(&mut r))?)
                 };
 
+                // The signature must use the negotiated algorithm, which
+                // for RSA keys also determines the hash.
+                let algorithm =
+                    Algorithm::new(&sig_type).map_err(|_| crate::Error::WrongServerSig)?;
+                if algorithm != self.names.key {
+                    debug!("unexpected signature type {sig_type}");
+                    return Err(crate::Error::WrongServerSig.into());
+                }
+
                 debug!("signature: {:?}", signature);
-                let signature = Signature::new(pubkey.algorithm(), signature).map_err(|e| {
+                let signature = SshSignature::new(algorithm, signature.to_vec()).map_err(|e| {
                     debug!("signature ctor failed: {e:?}");
                     crate::Error::WrongServerSig
                 })?;
-                if Verifier::verify(&pubkey, hash.as_ref(), &signature).is_err() {
+                if russh_keys::key::verify_signature(&pubkey, hash.as_ref(), &signature).is_err() {
                     debug!("wrong server sig");
                     return Err(crate::Error::WrongServerSig.into());
                 }
@@ -1361,10 +1946,12 @@ async fn reply<H: Handler>(
     if let Some(message_type) = buf.first() {
         if session.common.strict_kex && session.common.encrypted.is_none() {
             let seqno = seqn.0 - 1; // was incremented after read()
-            if let Some(expected) = STRICT_KEX_MSG_ORDER.get(seqno as usize) {
-                if message_type != expected {
-                    return Err(strict_kex_violation(*message_type, seqno as usize).into());
-                }
+
+            // Nothing but the key exchange is allowed before the
+            // first NEWKEYS, not even `IGNORE` messages.
+            match STRICT_KEX_MSG_ORDER.get(seqno as usize) {
+                Some(expected) if expected.contains(message_type) => {}
+                _ => return Err(strict_kex_violation(*message_type, seqno as usize).into()),
             }
         }
 
@@ -1395,7 +1982,9 @@ async fn reply<H: Handler>(
                     session.common.encrypted(
                         initial_encrypted_state(session),
                         done.compute_keys(CryptoVec::new(), false)?,
+                        false,
                     );
+                    session.report_negotiated(handler).await?;
 
                     if let Some(sender) = kex_done_signal.take() {
                         sender.send(()).unwrap_or(());
@@ -1412,12 +2001,27 @@ async fn reply<H: Handler>(
                 kexdhdone.names.ignore_guessed = false;
                 session.common.kex = Some(Kex::DhDone(kexdhdone));
                 Ok(())
-            } else if buf.first() == Some(&msg::KEX_ECDH_REPLY) {
+            } else if kexdhdone.client_negotiate(
+                &mut *session.common.cipher.local_to_remote,
+                buf,
+                &mut session.common.write_buffer,
+            )? {
+                session.common.kex = Some(Kex::DhDone(kexdhdone));
+                session.flush()?;
+                Ok(())
+            } else if buf.first() == Some(&kexdhdone.kex.reply_msg()) {
                 // We've sent ECDH_INIT, waiting for ECDH_REPLY
 
+                let config = session.common.config.clone();
                 #[allow(clippy::indexing_slicing)] // length checked
                 let kex = kexdhdone
-                    .server_key_check(false, handler, &mut &buf[1..])
+                    .server_key_check(
+                        false,
+                        session.server_key_precheck.as_ref(),
+                        config.host_key_verifier.as_deref(),
+                        handler,
+                        &mut &buf[1..],
+                    )
                     .await?;
 
                 session.common.strict_kex = session.common.strict_kex || kex.names.strict_kex;
@@ -1440,12 +2044,14 @@ async fn reply<H: Handler>(
             if buf.first() != Some(&msg::NEWKEYS) {
                 return Err(crate::Error::Kex.into());
             }
+            session
+                .common
+                .encrypted(initial_encrypted_state(session), newkeys, false);
+            session.report_negotiated(handler).await?;
             if let Some(sender) = kex_done_signal.take() {
                 sender.send(()).unwrap_or(());
             }
-            session
-                .common
-                .encrypted(initial_encrypted_state(session), newkeys);
+            session.maybe_send_ext_info()?;
             // Ok, NEWKEYS received, now encrypted.
             if session.common.strict_kex {
                 *seqn = Wrapping(0);
@@ -1480,18 +2086,62 @@ pub struct Config {
     pub limits: Limits,
     /// The initial size of a channel (used for flow control).
     pub window_size: u32,
+    /// The largest a channel's window can grow to. When the other side
+    /// sends data faster than our window adjustments reach it, which
+    /// happens on links with a large bandwidth-delay product, the
+    /// window is doubled, like OpenSSH does. Set it to `window_size` to
+    /// keep windows constant.
+    pub max_window_size: u32,
+    /// The most data of a channel received and not read from its
+    /// [`Channel`](crate::Channel) yet. When a reader falls behind, the
+    /// window of its channel is kept to what fits in the rest of this
+    /// buffer, so that the other side stops sending, and we stop
+    /// reading from the connection, until the reader catches up. The
+    /// data kept for a channel is then bounded by the larger of
+    /// `window_size` and this. `None` (the default) adjusts windows as data arrives, which is
+    /// what handlers reading data in their `data` method need.
+    pub channel_buffer_size: Option<u32>,
     /// The maximal size of a single packet.
     pub maximum_packet_size: u32,
     /// Lists of preferred algorithms.
     pub preferred: negotiation::Preferred,
+    /// Restricts the algorithms of `preferred` that can be negotiated,
+    /// for instance to [`AlgorithmPolicy::FIPS`](crate::AlgorithmPolicy::FIPS).
+    pub algorithm_policy: Option<crate::AlgorithmPolicy>,
+    /// Directions in which compression is offered, and its level, if
+    /// `preferred` allows compression.
+    pub compression: crate::compression::CompressionConfig,
+    /// Extensions sent to servers that support RFC 8308.
+    pub extensions: Extensions,
     /// Time after which the connection is garbage-collected.
     pub inactivity_timeout: Option<std::time::Duration>,
+    /// How long [`connect`] may take to resolve the host and establish
+    /// the TCP connection, before failing with
+    /// [`crate::Error::ConnectionTimeout`].
+    pub connection_timeout: Option<std::time::Duration>,
+    /// How long the exchange of versions and the first key exchange
+    /// may take, before failing with [`crate::Error::KexTimeout`].
+    pub handshake_timeout: Option<std::time::Duration>,
     /// If nothing is received from the server for this amount of time, send a keepalive message.
     pub keepalive_interval: Option<std::time::Duration>,
-    /// If this many keepalives have been sent without reply, close the connection.
+    /// If this many keepalives have been sent without reply, close the
+    /// connection at the next interval, like OpenSSH's `*AliveCountMax`.
+    /// `0` sends keepalives forever.
     pub keepalive_max: usize,
     /// Whether to expect and wait for an authentication call.
     pub anonymous: bool,
+    /// Sees every message received or sent, to debug protocol issues.
+    pub packet_observer: Option<Arc<dyn PacketObserver>>,
+    /// Options of the TCP connections made by [`connect`].
+    pub socket_options: russh_util::net::SocketOptions,
+    /// An HTTP or SOCKS5 proxy for the connections made by [`connect`].
+    pub proxy: Option<russh_util::net::ProxyConnector>,
+    /// Decides about server keys before asking
+    /// [`Handler::verify_server_key`], such as a [`KnownHostsVerifier`],
+    /// [`PinnedKeys`] or a [`sshfp::SshfpVerifier`], or several of them
+    /// with [`HostKeyVerifiers`]. With `connect_with_config`, this is
+    /// only asked about keys unknown to the known_hosts files.
+    pub host_key_verifier: Option<Arc<dyn HostKeyVerifier>>,
 }
 
 impl Default for Config {
@@ -1504,12 +2154,23 @@ impl Default for Config {
             )),
             limits: Limits::default(),
             window_size: 2097152,
+            max_window_size: 16777216,
+            channel_buffer_size: None,
             maximum_packet_size: 32768,
             preferred: Default::default(),
+            algorithm_policy: None,
+            compression: Default::default(),
+            extensions: Extensions::new(),
             inactivity_timeout: None,
+            connection_timeout: None,
+            handshake_timeout: None,
             keepalive_interval: None,
             keepalive_max: 3,
             anonymous: false,
+            packet_observer: None,
+            socket_options: Default::default(),
+            proxy: None,
+            host_key_verifier: None,
         }
     }
 }
@@ -1526,11 +2187,28 @@ pub trait Handler: Sized + Send {
     /// Called when the server sends us an authentication banner. This
     /// is usually meant to be shown to the user, see
     /// [RFC4252](https://tools.ietf.org/html/rfc4252#section-5.4) for
-    /// more details.
-    #[allow(unused_variables)]
+    /// more details. `language_tag` is usually empty. The default
+    /// implementation sends it to [`Handle::events`].
     async fn auth_banner(
         &mut self,
         banner: &str,
+        language_tag: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.events.send(SessionEvent::Banner {
+            banner: banner.to_string(),
+            language_tag: language_tag.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Called when the server sends its extensions (RFC 8308), after
+    /// the key exchange and possibly again after authentication. They
+    /// are also available from [`Session::server_extensions`].
+    #[allow(unused_variables)]
+    async fn ext_info(
+        &mut self,
+        extensions: &Extensions,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
         Ok(())
@@ -1539,6 +2217,9 @@ pub trait Handler: Sized + Send {
     /// Called to check the server's public key. This is a very important
     /// step to help prevent man-in-the-middle attacks. The default
     /// implementation rejects all keys.
+    ///
+    /// This is only called by the default implementation of
+    /// [`Handler::verify_server_key`].
     #[allow(unused_variables)]
     async fn check_server_key(
         &mut self,
@@ -1547,6 +2228,36 @@ pub trait Handler: Sized + Send {
         Ok(false)
     }
 
+    /// Called to verify the server's public key during the first key
+    /// exchange, with the result of the known_hosts lookup of
+    /// `connect_with_config`. Keys decided by the known_hosts files
+    /// and `StrictHostKeyChecking` aren't passed to this method, which
+    /// is where interactive clients ask the user about unknown keys.
+    ///
+    /// The default implementation accepts or rejects the key according
+    /// to [`Handler::check_server_key`].
+    async fn verify_server_key(
+        &mut self,
+        check: &ServerKeyCheck<'_>,
+    ) -> Result<ServerKeyVerdict, Self::Error> {
+        if self.check_server_key(check.key).await? {
+            Ok(ServerKeyVerdict::Accept)
+        } else {
+            Ok(ServerKeyVerdict::Reject)
+        }
+    }
+
+    /// Called when a key exchange completed, the first one before
+    /// [`connect`] returns, then after each key re-exchange.
+    #[allow(unused_variables)]
+    async fn negotiated(
+        &mut self,
+        negotiated: &Negotiated,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
     /// Called when the server confirmed our request to open a
     /// channel. A channel can only be written to after receiving this
     /// message (this library panics otherwise).
@@ -1615,7 +2326,7 @@ pub trait Handler: Sized + Send {
     }
 
     /// Called when the server opens a channel for a new remote port forwarding connection
-    #[allow(unused_variables)]
+    /// The default implementation sends it to [`Handle::events`].
     async fn server_channel_open_forwarded_tcpip(
         &mut self,
         channel: Channel<Msg>,
@@ -1625,27 +2336,48 @@ pub trait Handler: Sized + Send {
         originator_port: u32,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        session.events.send(SessionEvent::ChannelOpen {
+            channel,
+            kind: ChannelOpenKind::ForwardedTcpIp {
+                connected_address: connected_address.to_string(),
+                connected_port,
+                originator_address: originator_address.to_string(),
+                originator_port,
+            },
+        });
         Ok(())
     }
 
-    // Called when the server opens a channel for a new remote UDS forwarding connection
-    #[allow(unused_variables)]
+    /// Called when the server opens a channel for a new remote UDS forwarding connection
+    /// The default implementation sends it to [`Handle::events`].
     async fn server_channel_open_forwarded_streamlocal(
         &mut self,
         channel: Channel<Msg>,
         socket_path: &str,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        session.events.send(SessionEvent::ChannelOpen {
+            channel,
+            kind: ChannelOpenKind::ForwardedStreamLocal {
+                socket_path: socket_path.to_string(),
+            },
+        });
         Ok(())
     }
 
-    /// Called when the server opens an agent forwarding channel
-    #[allow(unused_variables)]
+    /// Called when the server opens an agent forwarding channel, after
+    /// agent forwarding was requested with [`Channel::agent_forward`].
+    /// On Unix, [`forward_agent_channel`] connects it to the local agent.
+    /// The default implementation sends it to [`Handle::events`].
     async fn server_channel_open_agent_forward(
         &mut self,
         channel: Channel<Msg>,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        session.events.send(SessionEvent::ChannelOpen {
+            channel,
+            kind: ChannelOpenKind::AgentForward,
+        });
         Ok(())
     }
 
@@ -1673,17 +2405,21 @@ pub trait Handler: Sized + Send {
     }
 
     /// Called when the server opens a session channel.
-    #[allow(unused_variables)]
+    /// The default implementation sends it to [`Handle::events`].
     async fn server_channel_open_session(
         &mut self,
         channel: Channel<Msg>,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        session.events.send(SessionEvent::ChannelOpen {
+            channel,
+            kind: ChannelOpenKind::Session,
+        });
         Ok(())
     }
 
     /// Called when the server opens a direct tcp/ip channel.
-    #[allow(unused_variables)]
+    /// The default implementation sends it to [`Handle::events`].
     async fn server_channel_open_direct_tcpip(
         &mut self,
         channel: Channel<Msg>,
@@ -1693,11 +2429,20 @@ pub trait Handler: Sized + Send {
         originator_port: u32,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        session.events.send(SessionEvent::ChannelOpen {
+            channel,
+            kind: ChannelOpenKind::DirectTcpIp {
+                host_to_connect: host_to_connect.to_string(),
+                port_to_connect,
+                originator_address: originator_address.to_string(),
+                originator_port,
+            },
+        });
         Ok(())
     }
 
     /// Called when the server opens an X11 channel.
-    #[allow(unused_variables)]
+    /// The default implementation sends it to [`Handle::events`].
     async fn server_channel_open_x11(
         &mut self,
         channel: Channel<Msg>,
@@ -1705,6 +2450,13 @@ pub trait Handler: Sized + Send {
         originator_port: u32,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        session.events.send(SessionEvent::ChannelOpen {
+            channel,
+            kind: ChannelOpenKind::X11 {
+                originator_address: originator_address.to_string(),
+                originator_port,
+            },
+        });
         Ok(())
     }
 
@@ -1790,14 +2542,21 @@ pub trait Handler: Sized + Send {
         Ok(())
     }
 
-    /// Called when this client adjusts the network window. Return the
-    /// next target window and maximum packet size.
+    /// Called when this client adjusts the window of `channel`, with
+    /// the window it targets, after scaling it up to
+    /// [`Config::max_window_size`]. Return the next target window for
+    /// this channel.
     #[allow(unused_variables)]
     fn adjust_window(&mut self, channel: ChannelId, window: u32) -> u32 {
         window
     }
 
-    /// Called when the server signals success.
+    /// Called when the server announces all its host keys after
+    /// authentication (OpenSSH's `hostkeys-00@openssh.com`
+    /// extension). To learn new keys, for instance when the server
+    /// is rotating its keys, ask the server to prove it owns them
+    /// with [`Session::request_host_keys_proof`]: they are then
+    /// passed to [`Handler::openssh_ext_host_keys_proven`].
     #[allow(unused_variables)]
     async fn openssh_ext_host_keys_announced(
         &mut self,
@@ -1808,6 +2567,19 @@ pub trait Handler: Sized + Send {
         Ok(())
     }
 
+    /// Called when the server has proven that it owns the keys
+    /// passed to [`Session::request_host_keys_proof`]. This is where
+    /// known_hosts should be updated.
+    #[allow(unused_variables)]
+    async fn openssh_ext_host_keys_proven(
+        &mut self,
+        keys: Vec<PublicKey>,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        debug!("openssh_ext_host_keys_proven: {:?}", keys);
+        Ok(())
+    }
+
     /// Called when the server sent a disconnect message
     ///
     /// If reason is an Error, this function should re-return the error so the join can also evaluate it
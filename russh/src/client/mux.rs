@@ -0,0 +1,620 @@
+//! Connection sharing with the OpenSSH multiplexing protocol
+//! (`ControlMaster` and `ControlPath`, see `PROTOCOL.mux` in OpenSSH).
+//!
+//! [`Handle::mux_listen`] makes a session available on a control
+//! socket, so that `ssh -S path`, `scp -o ControlPath=path` or
+//! [`connect_mux`] can run commands over it without authenticating
+//! again. Conversely, [`connect_mux`] attaches to an existing master,
+//! such as an `ssh -M -S path` process.
+//!
+//! Sessions and `ssh -W` stdio forwarding are supported; port
+//! forwarding requests are refused.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use ssh_encoding::{Decode, Encode};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Interest};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+
+use super::{open_direct_tcpip, open_session, Handle, Handler, Msg};
+use crate::logging::debug;
+use crate::{ChannelMsg, Disconnect, ExtendedDataType};
+
+const MUX_VERSION: u32 = 4;
+
+const MUX_MSG_HELLO: u32 = 0x0000_0001;
+const MUX_C_NEW_SESSION: u32 = 0x1000_0002;
+const MUX_C_ALIVE_CHECK: u32 = 0x1000_0004;
+const MUX_C_TERMINATE: u32 = 0x1000_0005;
+const MUX_C_NEW_STDIO_FWD: u32 = 0x1000_0008;
+const MUX_C_STOP_LISTENING: u32 = 0x1000_0009;
+const MUX_S_OK: u32 = 0x8000_0001;
+const MUX_S_PERMISSION_DENIED: u32 = 0x8000_0002;
+const MUX_S_FAILURE: u32 = 0x8000_0003;
+const MUX_S_EXIT_MESSAGE: u32 = 0x8000_0004;
+const MUX_S_ALIVE: u32 = 0x8000_0005;
+const MUX_S_SESSION_OPENED: u32 = 0x8000_0006;
+const MUX_S_TTY_ALLOC_FAIL: u32 = 0x8000_0008;
+
+/// Larger packets are refused, as OpenSSH does.
+const MAX_PACKET_LEN: u32 = 256 * 1024;
+
+/// A session to open through a multiplexing master.
+#[derive(Debug, Clone, Default)]
+pub struct MuxSessionRequest {
+    /// The command to run, or the subsystem to start. A shell is
+    /// started if this is empty.
+    pub command: String,
+    pub subsystem: bool,
+    pub want_tty: bool,
+    /// Value of `TERM`, if `want_tty` is set.
+    pub term: String,
+    pub want_x11: bool,
+    pub want_agent: bool,
+    /// Environment variables, as `NAME=value`.
+    pub env: Vec<String>,
+}
+
+/// A control socket served by [`Handle::mux_listen`]. The socket is
+/// closed and removed when this is dropped.
+#[derive(Debug)]
+pub struct MuxMaster {
+    path: PathBuf,
+    _stop: oneshot::Sender<()>,
+}
+
+impl MuxMaster {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for MuxMaster {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl<H: Handler> Handle<H> {
+    /// Listen on the UNIX socket `path` for multiplexing clients,
+    /// which can then open sessions on this connection.
+    pub async fn mux_listen<P: AsRef<Path>>(&self, path: P) -> Result<MuxMaster, crate::Error> {
+        let path = path.as_ref().to_path_buf();
+        let listener = UnixListener::bind(&path)?;
+        let (stop, mut stopped) = oneshot::channel();
+        let sender = self.sender.clone();
+        russh_util::runtime::spawn(async move {
+            let (stop_listening, mut stop_listening_recv) = tokio::sync::mpsc::channel(1);
+            loop {
+                let stream = tokio::select! {
+                    _ = &mut stopped => break,
+                    _ = stop_listening_recv.recv() => break,
+                    _ = sender.closed() => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            debug!("mux: accept failed: {:?}", e);
+                            continue;
+                        }
+                    },
+                };
+                let sender = sender.clone();
+                let stop_listening = stop_listening.clone();
+                russh_util::runtime::spawn(async move {
+                    if let Err(e) = serve_mux_client(stream, sender, stop_listening).await {
+                        debug!("mux client: {:?}", e);
+                    }
+                });
+            }
+        });
+        Ok(MuxMaster { path, _stop: stop })
+    }
+}
+
+/// Attach to the multiplexing master listening on `path`.
+pub async fn connect_mux<P: AsRef<Path>>(path: P) -> Result<MuxClient, crate::Error> {
+    MuxClient::connect(path).await
+}
+
+/// A connection to a multiplexing master.
+#[derive(Debug)]
+pub struct MuxClient {
+    stream: UnixStream,
+    request_id: u32,
+}
+
+impl MuxClient {
+    /// Connect to the master listening on `path`, and exchange hello
+    /// messages.
+    pub async fn connect<P: AsRef<Path>>(path: P) -> Result<Self, crate::Error> {
+        let mut stream = UnixStream::connect(path).await?;
+        hello(&mut stream).await?;
+        Ok(MuxClient {
+            stream,
+            request_id: 0,
+        })
+    }
+
+    fn next_request_id(&mut self) -> u32 {
+        self.request_id = self.request_id.wrapping_add(1);
+        self.request_id
+    }
+
+    /// Check that the master is alive (`ssh -O check`), and return its
+    /// process id.
+    pub async fn alive_check(&mut self) -> Result<u32, crate::Error> {
+        let id = self.next_request_id();
+        let mut p = Vec::new();
+        MUX_C_ALIVE_CHECK.encode(&mut p)?;
+        id.encode(&mut p)?;
+        write_packet(&mut self.stream, &p).await?;
+        let reply = self.read_reply(id).await?;
+        expect(&reply, MUX_S_ALIVE, |r| Ok(u32::decode(r)?))
+    }
+
+    /// Ask the master to close its connection (`ssh -O exit`).
+    pub async fn terminate(&mut self) -> Result<(), crate::Error> {
+        self.simple_request(MUX_C_TERMINATE).await
+    }
+
+    /// Ask the master to stop accepting multiplexing clients, while
+    /// keeping the existing ones (`ssh -O stop`).
+    pub async fn stop_listening(&mut self) -> Result<(), crate::Error> {
+        self.simple_request(MUX_C_STOP_LISTENING).await
+    }
+
+    async fn simple_request(&mut self, typ: u32) -> Result<(), crate::Error> {
+        let id = self.next_request_id();
+        let mut p = Vec::new();
+        typ.encode(&mut p)?;
+        id.encode(&mut p)?;
+        write_packet(&mut self.stream, &p).await?;
+        let reply = self.read_reply(id).await?;
+        expect(&reply, MUX_S_OK, |_| Ok(()))
+    }
+
+    /// Open a session on the master. Its standard input, output and
+    /// error are passed to the master, which copies them to and from
+    /// the channel.
+    pub async fn new_session<I: AsRawFd, O: AsRawFd, E: AsRawFd>(
+        mut self,
+        request: &MuxSessionRequest,
+        stdin: &I,
+        stdout: &O,
+        stderr: &E,
+    ) -> Result<MuxSession, crate::Error> {
+        let id = self.next_request_id();
+        let mut p = Vec::new();
+        MUX_C_NEW_SESSION.encode(&mut p)?;
+        id.encode(&mut p)?;
+        "".encode(&mut p)?; // reserved
+        u32::from(request.want_tty).encode(&mut p)?;
+        u32::from(request.want_x11).encode(&mut p)?;
+        u32::from(request.want_agent).encode(&mut p)?;
+        u32::from(request.subsystem).encode(&mut p)?;
+        0xffff_ffffu32.encode(&mut p)?; // no escape character
+        request.term.encode(&mut p)?;
+        request.command.encode(&mut p)?;
+        for var in request.env.iter() {
+            var.encode(&mut p)?;
+        }
+        write_packet(&mut self.stream, &p).await?;
+        for fd in [stdin.as_raw_fd(), stdout.as_raw_fd(), stderr.as_raw_fd()] {
+            send_fd(&self.stream, fd).await?;
+        }
+        let reply = self.read_reply(id).await?;
+        let session_id = expect(&reply, MUX_S_SESSION_OPENED, |r| Ok(u32::decode(r)?))?;
+        Ok(MuxSession {
+            stream: self.stream,
+            session_id,
+        })
+    }
+
+    /// Read the reply to request `id`.
+    async fn read_reply(&mut self, id: u32) -> Result<Vec<u8>, crate::Error> {
+        let reply = read_packet(&mut self.stream).await?;
+        let mut r = &reply[..];
+        let _typ = u32::decode(&mut r)?;
+        if u32::decode(&mut r)? != id {
+            return Err(crate::Error::Mux("unexpected reply".into()));
+        }
+        Ok(reply)
+    }
+}
+
+/// Check the type of `reply`, and parse what follows the request id
+/// with `f`.
+fn expect<T, F: FnOnce(&mut &[u8]) -> Result<T, crate::Error>>(
+    reply: &[u8],
+    typ: u32,
+    f: F,
+) -> Result<T, crate::Error> {
+    let mut r = reply;
+    let reply_typ = u32::decode(&mut r)?;
+    let _id = u32::decode(&mut r)?;
+    match reply_typ {
+        t if t == typ => f(&mut r),
+        MUX_S_PERMISSION_DENIED | MUX_S_FAILURE => Err(crate::Error::Mux(String::decode(&mut r)?)),
+        t => Err(crate::Error::Mux(format!("unexpected reply {:#x}", t))),
+    }
+}
+
+/// A session opened with [`MuxClient::new_session`].
+#[derive(Debug)]
+pub struct MuxSession {
+    stream: UnixStream,
+    session_id: u32,
+}
+
+impl MuxSession {
+    /// The id of the session on the master.
+    pub fn id(&self) -> u32 {
+        self.session_id
+    }
+
+    /// Wait until the remote command exits, and return its exit
+    /// status.
+    pub async fn wait(mut self) -> Result<u32, crate::Error> {
+        loop {
+            let packet = read_packet(&mut self.stream).await?;
+            let mut r = &packet[..];
+            match u32::decode(&mut r)? {
+                MUX_S_EXIT_MESSAGE => {
+                    let _session_id = u32::decode(&mut r)?;
+                    return Ok(u32::decode(&mut r)?);
+                }
+                MUX_S_TTY_ALLOC_FAIL => debug!("mux: the master could not allocate a tty"),
+                t => debug!("mux: unexpected message {:#x}", t),
+            }
+        }
+    }
+}
+
+/// Serve one multiplexing client, until it closes the connection or
+/// its session ends.
+async fn serve_mux_client(
+    mut stream: UnixStream,
+    sender: Sender<Msg>,
+    stop_listening: tokio::sync::mpsc::Sender<()>,
+) -> Result<(), crate::Error> {
+    hello(&mut stream).await?;
+    let mut session_id = 0u32;
+    loop {
+        let packet = match read_packet(&mut stream).await {
+            Ok(packet) => packet,
+            Err(crate::Error::IO(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut r = &packet[..];
+        let typ = u32::decode(&mut r)?;
+        let id = u32::decode(&mut r)?;
+        match typ {
+            MUX_C_ALIVE_CHECK => {
+                let mut p = Vec::new();
+                MUX_S_ALIVE.encode(&mut p)?;
+                id.encode(&mut p)?;
+                std::process::id().encode(&mut p)?;
+                write_packet(&mut stream, &p).await?;
+            }
+            MUX_C_TERMINATE => {
+                write_ok(&mut stream, id).await?;
+                sender
+                    .send(Msg::Disconnect {
+                        reason: Disconnect::ByApplication,
+                        description: "terminated by multiplexing client".into(),
+                        language_tag: "".into(),
+                    })
+                    .await
+                    .map_err(|_| crate::Error::SendError)?;
+            }
+            MUX_C_STOP_LISTENING => {
+                let _ = stop_listening.try_send(());
+                write_ok(&mut stream, id).await?;
+            }
+            MUX_C_NEW_SESSION => {
+                let _reserved = String::decode(&mut r)?;
+                // OpenSSH sends these flags as 32-bit integers.
+                let want_tty = u32::decode(&mut r)? != 0;
+                let _want_x11 = u32::decode(&mut r)? != 0;
+                let _want_agent = u32::decode(&mut r)? != 0;
+                let subsystem = u32::decode(&mut r)? != 0;
+                let _escape_char = u32::decode(&mut r)?;
+                let term = String::decode(&mut r)?;
+                let command = String::decode(&mut r)?;
+                let mut env = Vec::new();
+                while !r.is_empty() {
+                    env.push(String::decode(&mut r)?);
+                }
+                let stdin = recv_fd(&stream).await?;
+                let stdout = recv_fd(&stream).await?;
+                let stderr = recv_fd(&stream).await?;
+
+                let channel = match open_session(sender.clone()).await {
+                    Ok(channel) => channel,
+                    Err(e) => return write_failure(&mut stream, id, &e.to_string()).await,
+                };
+                if want_tty {
+                    let (cols, rows, pix_width, pix_height) =
+                        window_size(stdin.as_raw_fd()).unwrap_or((80, 24, 0, 0));
+                    channel
+                        .request_pty(false, &term, cols, rows, pix_width, pix_height, &[])
+                        .await?;
+                }
+                for (name, value) in env.iter().filter_map(|var| var.split_once('=')) {
+                    channel.set_env(false, name, value).await?;
+                }
+                if subsystem {
+                    channel.request_subsystem(false, command).await?;
+                } else if command.is_empty() {
+                    channel.request_shell(false).await?;
+                } else {
+                    channel.exec(false, command).await?;
+                }
+
+                session_id += 1;
+                let mut p = Vec::new();
+                MUX_S_SESSION_OPENED.encode(&mut p)?;
+                id.encode(&mut p)?;
+                session_id.encode(&mut p)?;
+                write_packet(&mut stream, &p).await?;
+
+                let exit_status = pump(channel, stdin, stdout, Some(stderr)).await?;
+                let mut p = Vec::new();
+                MUX_S_EXIT_MESSAGE.encode(&mut p)?;
+                session_id.encode(&mut p)?;
+                exit_status.encode(&mut p)?;
+                write_packet(&mut stream, &p).await?;
+                return Ok(());
+            }
+            MUX_C_NEW_STDIO_FWD => {
+                let _reserved = String::decode(&mut r)?;
+                let host = String::decode(&mut r)?;
+                let port = u32::decode(&mut r)?;
+                let stdin = recv_fd(&stream).await?;
+                let stdout = recv_fd(&stream).await?;
+                let channel = match open_direct_tcpip(
+                    sender.clone(),
+                    host,
+                    port,
+                    "127.0.0.1".into(),
+                    0,
+                )
+                .await
+                {
+                    Ok(channel) => channel,
+                    Err(e) => return write_failure(&mut stream, id, &e.to_string()).await,
+                };
+                session_id += 1;
+                let mut p = Vec::new();
+                MUX_S_SESSION_OPENED.encode(&mut p)?;
+                id.encode(&mut p)?;
+                session_id.encode(&mut p)?;
+                write_packet(&mut stream, &p).await?;
+                pump(channel, stdin, stdout, None).await?;
+                return Ok(());
+            }
+            _ => {
+                debug!("mux: unsupported request {:#x}", typ);
+                write_failure(&mut stream, id, "unsupported request").await?;
+            }
+        }
+    }
+}
+
+/// Copy `stdin` to the channel, and the channel to `stdout` and
+/// `stderr`, until the channel is closed. Returns the exit status.
+async fn pump(
+    mut channel: crate::Channel<Msg>,
+    stdin: OwnedFd,
+    stdout: OwnedFd,
+    stderr: Option<OwnedFd>,
+) -> Result<u32, crate::Error> {
+    let mut stdin = tokio::fs::File::from_std(std::fs::File::from(stdin));
+    let mut stdout = tokio::fs::File::from_std(std::fs::File::from(stdout));
+    let mut stderr = stderr.map(|fd| tokio::fs::File::from_std(std::fs::File::from(fd)));
+    let writer = channel.make_writer();
+    let stdin_task = russh_util::runtime::spawn(async move {
+        tokio::pin!(writer);
+        let _ = tokio::io::copy(&mut stdin, &mut writer).await;
+        let _ = writer.shutdown().await;
+    });
+
+    // Like OpenSSH, report 255 if the command didn't exit normally.
+    let mut exit_status = 255;
+    while let Some(msg) = channel.wait().await {
+        match msg {
+            ChannelMsg::Data { data } => {
+                stdout.write_all(&data).await?;
+                stdout.flush().await?;
+            }
+            ChannelMsg::ExtendedData {
+                data,
+                ext: ExtendedDataType::STDERR,
+            } => {
+                if let Some(ref mut stderr) = stderr {
+                    stderr.write_all(&data).await?;
+                    stderr.flush().await?;
+                }
+            }
+            ChannelMsg::ExitStatus { exit_status: s } => exit_status = s,
+            ChannelMsg::Eof => stdout.shutdown().await?,
+            ChannelMsg::Close => break,
+            _ => {}
+        }
+    }
+    drop(stdin_task);
+    Ok(exit_status)
+}
+
+/// Send our hello, and check the other side's.
+async fn hello<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<(), crate::Error> {
+    let mut p = Vec::new();
+    MUX_MSG_HELLO.encode(&mut p)?;
+    MUX_VERSION.encode(&mut p)?;
+    write_packet(stream, &p).await?;
+
+    let packet = read_packet(stream).await?;
+    let mut r = &packet[..];
+    if u32::decode(&mut r)? != MUX_MSG_HELLO || u32::decode(&mut r)? != MUX_VERSION {
+        return Err(crate::Error::Mux("unsupported protocol".into()));
+    }
+    // Extensions are ignored.
+    Ok(())
+}
+
+async fn write_ok<S: AsyncWrite + Unpin>(stream: &mut S, id: u32) -> Result<(), crate::Error> {
+    let mut p = Vec::new();
+    MUX_S_OK.encode(&mut p)?;
+    id.encode(&mut p)?;
+    write_packet(stream, &p).await
+}
+
+async fn write_failure<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    id: u32,
+    reason: &str,
+) -> Result<(), crate::Error> {
+    let mut p = Vec::new();
+    MUX_S_FAILURE.encode(&mut p)?;
+    id.encode(&mut p)?;
+    reason.encode(&mut p)?;
+    write_packet(stream, &p).await
+}
+
+async fn read_packet<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>, crate::Error> {
+    let len = stream.read_u32().await?;
+    if len > MAX_PACKET_LEN {
+        return Err(crate::Error::Mux("packet too long".into()));
+    }
+    let mut packet = vec![0; len as usize];
+    stream.read_exact(&mut packet).await?;
+    Ok(packet)
+}
+
+async fn write_packet<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    payload: &[u8],
+) -> Result<(), crate::Error> {
+    stream.write_u32(payload.len() as u32).await?;
+    stream.write_all(payload).await?;
+    Ok(())
+}
+
+/// The size of the terminal `fd`, as (columns, rows, width, height).
+fn window_size(fd: RawFd) -> Option<(u32, u32, u32, u32)> {
+    // SAFETY: TIOCGWINSZ only writes a winsize.
+    let ws = unsafe {
+        let mut ws: libc::winsize = std::mem::zeroed();
+        if libc::ioctl(fd, libc::TIOCGWINSZ, &mut ws) != 0 {
+            return None;
+        }
+        ws
+    };
+    Some((
+        ws.ws_col.into(),
+        ws.ws_row.into(),
+        ws.ws_xpixel.into(),
+        ws.ws_ypixel.into(),
+    ))
+}
+
+/// Control message buffer, aligned for `cmsghdr`, large enough for one
+/// file descriptor.
+#[repr(C)]
+union CmsgBuffer {
+    _align: libc::cmsghdr,
+    buf: [u8; 64],
+}
+
+/// Pass `fd` over `stream`, with one byte of data, as OpenSSH does.
+async fn send_fd(stream: &UnixStream, fd: RawFd) -> io::Result<()> {
+    loop {
+        stream.writable().await?;
+        match stream.try_io(Interest::WRITABLE, || send_fd_raw(stream.as_raw_fd(), fd)) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            r => return r,
+        }
+    }
+}
+
+/// Receive a file descriptor passed over `stream`.
+async fn recv_fd(stream: &UnixStream) -> io::Result<OwnedFd> {
+    loop {
+        stream.readable().await?;
+        match stream.try_io(Interest::READABLE, || recv_fd_raw(stream.as_raw_fd())) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            r => return r,
+        }
+    }
+}
+
+fn send_fd_raw(socket: RawFd, fd: RawFd) -> io::Result<()> {
+    let mut byte = [0u8; 1];
+    let mut control = CmsgBuffer { buf: [0; 64] };
+    // SAFETY: the control buffer is aligned and large enough for one
+    // descriptor, and all pointers outlive the call.
+    unsafe {
+        let mut iov = libc::iovec {
+            iov_base: byte.as_mut_ptr().cast(),
+            iov_len: 1,
+        };
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.buf.as_mut_ptr().cast();
+        msg.msg_controllen = libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null() {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>(), fd);
+        if libc::sendmsg(socket, &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn recv_fd_raw(socket: RawFd) -> io::Result<OwnedFd> {
+    let mut byte = [0u8; 1];
+    let mut control = CmsgBuffer { buf: [0; 64] };
+    // SAFETY: as in `send_fd_raw`; the received descriptor is owned by
+    // us once recvmsg returns.
+    unsafe {
+        let mut iov = libc::iovec {
+            iov_base: byte.as_mut_ptr().cast(),
+            iov_len: 1,
+        };
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.buf.as_mut_ptr().cast();
+        msg.msg_controllen = std::mem::size_of::<CmsgBuffer>() as _;
+        let n = libc::recvmsg(socket, &mut msg, 0);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected a file descriptor",
+            ));
+        }
+        let fd = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>());
+        Ok(OwnedFd::from_raw_fd(fd))
+    }
+}
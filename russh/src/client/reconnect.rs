@@ -0,0 +1,202 @@
+//! Long-lived clients that survive the loss of their connection.
+
+use std::future::Future;
+use std::time::Duration;
+
+use super::{Handle, Handler};
+use crate::logging::{debug, warn};
+
+/// How [`ReconnectingClient`] retries failed connections: after each
+/// failure, it waits for a delay starting at `initial_delay` and
+/// doubling up to `max_delay`.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Give up after this many failed attempts in a row, or never.
+    pub max_attempts: Option<usize>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// The delay before the attempt following `failures` failures.
+    pub fn delay(&self, failures: usize) -> Duration {
+        let factor = 1u32.checked_shl(failures.saturating_sub(1) as u32);
+        factor
+            .and_then(|f| self.initial_delay.checked_mul(f))
+            .map_or(self.max_delay, |d| d.min(self.max_delay))
+    }
+}
+
+/// A session that is re-established whenever it is lost.
+///
+/// `connect` opens a new authenticated session, usually by calling
+/// [`connect`](super::connect) and authenticating the handle, and
+/// creating a new handler each time. It is called again, with the
+/// delays of the [`ReconnectPolicy`], when the session is found
+/// closed, and the remote forwards requested with
+/// [`ReconnectingClient::tcpip_forward`] are requested again on the new
+/// session.
+///
+/// ```no_run
+/// # #[cfg(not(target_arch = "wasm32"))]
+/// # async fn f() -> Result<(), russh::Error> {
+/// use std::sync::Arc;
+///
+/// use russh::client::{self, ReconnectPolicy, ReconnectingClient};
+///
+/// struct Client;
+///
+/// #[async_trait::async_trait]
+/// impl client::Handler for Client {
+///     type Error = russh::Error;
+/// }
+///
+/// let config = Arc::new(client::Config::default());
+/// let mut client = ReconnectingClient::connect(
+///     move || {
+///         let config = config.clone();
+///         async move {
+///             let mut handle = client::connect(config, ("example.com", 22), Client).await?;
+///             if !handle
+///                 .authenticate_password("user", "password")
+///                 .await?
+///                 .success()
+///             {
+///                 return Err(russh::Error::NotAuthenticated);
+///             }
+///             Ok(handle)
+///         }
+///     },
+///     ReconnectPolicy::default(),
+/// )
+/// .await?;
+/// client.tcpip_forward("127.0.0.1", 8080).await?;
+/// let _channel = client.handle().await?.channel_open_session().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ReconnectingClient<H: Handler, F> {
+    connect: F,
+    policy: ReconnectPolicy,
+    handle: Option<Handle<H>>,
+    remote_forwards: Vec<(String, u32)>,
+    reconnects: usize,
+}
+
+impl<H, F, Fut> ReconnectingClient<H, F>
+where
+    H: Handler,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<Handle<H>, H::Error>>,
+{
+    /// Open the first session, retrying according to `policy`.
+    pub async fn connect(connect: F, policy: ReconnectPolicy) -> Result<Self, H::Error> {
+        let mut client = ReconnectingClient {
+            connect,
+            policy,
+            handle: None,
+            remote_forwards: Vec::new(),
+            reconnects: 0,
+        };
+        client.handle = Some(client.open().await?);
+        Ok(client)
+    }
+
+    /// The current session, re-established first if it was closed.
+    pub async fn handle(&mut self) -> Result<&mut Handle<H>, H::Error> {
+        match self.handle {
+            Some(ref handle) if !handle.is_closed() => {}
+            _ => {
+                debug!("session closed, reconnecting");
+                self.reconnect().await?
+            }
+        }
+        match self.handle {
+            Some(ref mut handle) => Ok(handle),
+            None => Err(crate::Error::Disconnect.into()),
+        }
+    }
+
+    /// Whether the current session is open.
+    pub fn is_connected(&self) -> bool {
+        self.handle.as_ref().is_some_and(|h| !h.is_closed())
+    }
+
+    /// How many times the session was re-established.
+    pub fn reconnects(&self) -> usize {
+        self.reconnects
+    }
+
+    /// Replace the current session with a new one, even if it is still
+    /// open, and request the remote forwards on it.
+    pub async fn reconnect(&mut self) -> Result<(), H::Error> {
+        self.handle = None;
+        let mut handle = self.open().await?;
+        for (address, port) in &self.remote_forwards {
+            if let Err(e) = handle.tcpip_forward(address.clone(), *port).await {
+                warn!("could not forward {}:{} again: {:?}", address, port, e);
+            }
+        }
+        self.handle = Some(handle);
+        self.reconnects += 1;
+        Ok(())
+    }
+
+    /// Ask the server to listen on `address` and `port`, on this session
+    /// and the next ones, see [`Handle::tcpip_forward`]. If port 0 is
+    /// requested, the next sessions request the port the server chose.
+    pub async fn tcpip_forward<A: Into<String>>(
+        &mut self,
+        address: A,
+        port: u32,
+    ) -> Result<u32, H::Error> {
+        let address = address.into();
+        let chosen = self
+            .handle()
+            .await?
+            .tcpip_forward(address.clone(), port)
+            .await?;
+        let port = if port == 0 { chosen } else { port };
+        self.remote_forwards.push((address, port));
+        Ok(chosen)
+    }
+
+    /// Stop listening on `address` and `port`, and forget about it.
+    pub async fn cancel_tcpip_forward(&mut self, address: &str, port: u32) -> Result<(), H::Error> {
+        self.remote_forwards
+            .retain(|(a, p)| (a.as_str(), *p) != (address, port));
+        self.handle()
+            .await?
+            .cancel_tcpip_forward(address, port)
+            .await?;
+        Ok(())
+    }
+
+    async fn open(&self) -> Result<Handle<H>, H::Error> {
+        let mut failures = 0;
+        loop {
+            match (self.connect)().await {
+                Ok(handle) => return Ok(handle),
+                Err(e) => {
+                    failures += 1;
+                    if self.policy.max_attempts.is_some_and(|max| failures >= max) {
+                        return Err(e);
+                    }
+                    let delay = self.policy.delay(failures);
+                    debug!("connection failed ({:?}), retrying in {:?}", e, delay);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+}
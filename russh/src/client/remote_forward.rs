@@ -0,0 +1,145 @@
+//! Remote port forwarding, the equivalent of `ssh -R`.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+use tokio::sync::mpsc::{unbounded_channel, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+
+use super::{Handle, Handler, Msg};
+use crate::channels::Channel;
+use crate::logging::error;
+
+/// A connection to a [`RemoteForward`], opened by the server.
+#[derive(Debug)]
+pub struct ForwardedTcpIp {
+    pub channel: Channel<Msg>,
+    /// The address the connection was made to on the server.
+    pub connected_address: String,
+    pub connected_port: u32,
+    /// The peer connecting to the server.
+    pub originator_address: String,
+    pub originator_port: u32,
+}
+
+/// The remote forwards of a session, by address and port bound on the
+/// server.
+pub(super) type RemoteForwards = HashMap<(String, u32), UnboundedSender<ForwardedTcpIp>>;
+
+/// Find the forward a `forwarded-tcpip` channel belongs to. Servers
+/// may report the bound address in another form than requested (for
+/// instance `localhost` as `127.0.0.1`), so fall back to the port.
+pub(super) fn find_remote_forward<'a>(
+    forwards: &'a RemoteForwards,
+    address: &str,
+    port: u32,
+) -> Option<(&'a (String, u32), &'a UnboundedSender<ForwardedTcpIp>)> {
+    forwards
+        .get_key_value(&(address.to_string(), port))
+        .or_else(|| forwards.iter().find(|((_, p), _)| *p == port))
+}
+
+/// An active remote forward, returned by [`Handle::forward_remote`].
+///
+/// Connections to it are a [`Stream`] of [`ForwardedTcpIp`]. They
+/// are not passed to
+/// [`Handler::server_channel_open_forwarded_tcpip`](super::Handler::server_channel_open_forwarded_tcpip)
+/// while this is alive. Dropping it without calling
+/// [`cancel`](RemoteForward::cancel) leaves the server listening, and
+/// further connections go to the handler again.
+#[derive(Debug)]
+pub struct RemoteForward {
+    address: String,
+    port: u32,
+    sender: Sender<Msg>,
+    connections: UnboundedReceiver<ForwardedTcpIp>,
+}
+
+impl RemoteForward {
+    /// The address the server listens on.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// The port the server listens on, which is the one it chose if
+    /// port 0 was requested.
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    /// Wait for the next connection. Returns `None` once the session
+    /// is closed.
+    pub async fn accept(&mut self) -> Option<ForwardedTcpIp> {
+        self.connections.recv().await
+    }
+
+    /// Ask the server to stop listening (`cancel-tcpip-forward`).
+    pub async fn cancel(self) -> Result<(), crate::Error> {
+        let (reply_send, reply_recv) = oneshot::channel();
+        self.sender
+            .send(Msg::CancelTcpIpForward {
+                reply_channel: Some(reply_send),
+                address: self.address.clone(),
+                port: self.port,
+            })
+            .await
+            .map_err(|_| crate::Error::SendError)?;
+
+        match reply_recv.await {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(crate::Error::RequestDenied),
+            Err(e) => {
+                error!("Unable to receive CancelTcpIpForward result: {e:?}");
+                Err(crate::Error::Disconnect)
+            }
+        }
+    }
+}
+
+impl Stream for RemoteForward {
+    type Item = ForwardedTcpIp;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.connections.poll_recv(cx)
+    }
+}
+
+impl<H: Handler> Handle<H> {
+    /// Ask the server to listen on `address:port` and forward the
+    /// connections it accepts (`tcpip-forward`). If `port` is 0, the
+    /// server chooses one, available from [`RemoteForward::port`].
+    pub async fn forward_remote<A: Into<String>>(
+        &self,
+        address: A,
+        port: u32,
+    ) -> Result<RemoteForward, crate::Error> {
+        let address = address.into();
+        let (connections_send, connections) = unbounded_channel();
+        let (reply_send, reply_recv) = oneshot::channel();
+        self.sender
+            .send(Msg::RemoteForward {
+                reply_channel: reply_send,
+                address: address.clone(),
+                port,
+                connections: connections_send,
+            })
+            .await
+            .map_err(|_| crate::Error::SendError)?;
+
+        match reply_recv.await {
+            Ok(Some(port)) => Ok(RemoteForward {
+                address,
+                port,
+                sender: self.sender.clone(),
+                connections,
+            }),
+            Ok(None) => Err(crate::Error::RequestDenied),
+            Err(e) => {
+                error!("Unable to receive TcpIpForward result: {e:?}");
+                Err(crate::Error::Disconnect)
+            }
+        }
+    }
+}
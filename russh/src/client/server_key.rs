@@ -0,0 +1,244 @@
+//! Verification of the server's host key, see
+//! [`Handler::verify_server_key`](super::Handler::verify_server_key).
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use ssh_key::{Fingerprint, HashAlg, PublicKey};
+
+/// What the known_hosts files say about a server key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KnownHostsStatus {
+    /// No known_hosts file was looked up, which is the case of
+    /// connections not made with `connect_with_config`.
+    NotChecked,
+    /// No key of this algorithm is recorded for the host.
+    Unknown,
+    /// The key is recorded for the host at `line` of `path`.
+    Known { path: PathBuf, line: usize },
+    /// Another key of the same algorithm is recorded for the host at
+    /// `line` of `path`, which could mean a man-in-the-middle attack.
+    Changed { path: PathBuf, line: usize },
+    /// The key is marked as `@revoked` at `line` of `path`.
+    Revoked { path: PathBuf, line: usize },
+}
+
+/// A server key to verify, with what is known about it.
+#[derive(Debug)]
+pub struct ServerKeyCheck<'a> {
+    /// The host name and port, when connecting with `connect_with_config`.
+    pub host: Option<(&'a str, u16)>,
+    pub key: &'a PublicKey,
+    pub known_hosts: KnownHostsStatus,
+}
+
+impl ServerKeyCheck<'_> {
+    /// The fingerprint of the key, displayed as `SHA256:...` like
+    /// OpenSSH does. See [`russh_keys::fingerprint`] for the other
+    /// formats and randomart.
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.key.fingerprint(HashAlg::Sha256)
+    }
+}
+
+/// The decision about a server key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerKeyVerdict {
+    Accept,
+    /// Accept the key, and add it to the first known_hosts file of the
+    /// connection. Connections not made with `connect_with_config`
+    /// have none, and only accept the key.
+    AcceptAndRecord,
+    /// Refuse the key, failing with [`crate::Error::UnknownKey`].
+    Reject,
+    /// Refuse the key because it differs from the one recorded at
+    /// `line` of `path`, failing with [`crate::Error::KeyChanged`].
+    Mismatch {
+        path: PathBuf,
+        line: usize,
+    },
+}
+
+impl ServerKeyVerdict {
+    /// The verdict of the known_hosts files alone: `None` for unknown
+    /// keys, which depend on the policy of the connection.
+    pub fn from_known_hosts(status: &KnownHostsStatus) -> Option<Self> {
+        match status {
+            KnownHostsStatus::Known { .. } => Some(ServerKeyVerdict::Accept),
+            KnownHostsStatus::Changed { path, line } => Some(ServerKeyVerdict::Mismatch {
+                path: path.clone(),
+                line: *line,
+            }),
+            KnownHostsStatus::Revoked { .. } => Some(ServerKeyVerdict::Reject),
+            KnownHostsStatus::Unknown | KnownHostsStatus::NotChecked => None,
+        }
+    }
+}
+
+/// A check of server keys, consulted after the known_hosts files of
+/// `connect_with_config` and before
+/// [`Handler::verify_server_key`](super::Handler::verify_server_key),
+/// see [`Config::host_key_verifier`](super::Config::host_key_verifier).
+/// Several verifiers are combined with [`HostKeyVerifiers`].
+#[async_trait]
+pub trait HostKeyVerifier: Send + Sync {
+    /// Decide about a key, or return `None` to leave the decision to
+    /// the next verifiers or to the handler.
+    async fn verify(
+        &self,
+        check: &ServerKeyCheck<'_>,
+    ) -> Result<Option<ServerKeyVerdict>, crate::Error>;
+
+    /// Remember a key accepted with
+    /// [`ServerKeyVerdict::AcceptAndRecord`]. Does nothing by default.
+    #[allow(unused_variables)]
+    async fn record(&self, check: &ServerKeyCheck<'_>) -> Result<(), crate::Error> {
+        Ok(())
+    }
+}
+
+impl fmt::Debug for dyn HostKeyVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HostKeyVerifier")
+    }
+}
+
+/// Verifiers asked in order, until one of them decides. Keys accepted
+/// with [`ServerKeyVerdict::AcceptAndRecord`] are recorded by all of
+/// them.
+///
+/// ```
+/// # #[cfg(not(target_arch = "wasm32"))]
+/// # fn f() {
+/// use std::sync::Arc;
+///
+/// use russh::client::{HostKeyVerifiers, KnownHostsVerifier, PinnedKeys, ServerKeyVerdict};
+///
+/// let pinned = Arc::new(PinnedKeys::new());
+/// let verifier = HostKeyVerifiers::new()
+///     .with(pinned.clone())
+///     .with(Arc::new(
+///         KnownHostsVerifier::new().unknown_keys(Some(ServerKeyVerdict::Reject)),
+///     ));
+/// let config = russh::client::Config {
+///     host_key_verifier: Some(Arc::new(verifier)),
+///     ..Default::default()
+/// };
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct HostKeyVerifiers(Vec<Arc<dyn HostKeyVerifier>>);
+
+impl HostKeyVerifiers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask `verifier` after the current ones.
+    pub fn with(mut self, verifier: Arc<dyn HostKeyVerifier>) -> Self {
+        self.0.push(verifier);
+        self
+    }
+}
+
+#[async_trait]
+impl HostKeyVerifier for HostKeyVerifiers {
+    async fn verify(
+        &self,
+        check: &ServerKeyCheck<'_>,
+    ) -> Result<Option<ServerKeyVerdict>, crate::Error> {
+        for verifier in &self.0 {
+            if let Some(verdict) = verifier.verify(check).await? {
+                return Ok(Some(verdict));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn record(&self, check: &ServerKeyCheck<'_>) -> Result<(), crate::Error> {
+        for verifier in &self.0 {
+            verifier.record(check).await?
+        }
+        Ok(())
+    }
+}
+
+/// Server keys kept in memory, for instance keys provisioned with an
+/// application, or keys the user accepted during this run. Pinned
+/// keys are accepted, and other keys of hosts with pinned keys are
+/// rejected. Recording pins the key for its host.
+#[derive(Debug, Default)]
+pub struct PinnedKeys {
+    keys: Mutex<Vec<PinnedKey>>,
+}
+
+/// A key, and the host and port it is pinned for, or `None` for all
+/// hosts.
+type PinnedKey = (Option<(String, u16)>, PublicKey);
+
+impl PinnedKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept `key` for `host` on `port`.
+    pub fn pin<H: Into<String>>(&self, host: H, port: u16, key: PublicKey) {
+        self.push(Some((host.into(), port)), key)
+    }
+
+    /// Accept `key` for all hosts, which is useful for connections
+    /// whose host isn't known, such as those of [`connect`](super::connect).
+    pub fn pin_any(&self, key: PublicKey) {
+        self.push(None, key)
+    }
+
+    /// Forget the keys pinned for `host` on `port`.
+    pub fn unpin(&self, host: &str, port: u16) {
+        if let Ok(mut keys) = self.keys.lock() {
+            keys.retain(|(h, _)| h.as_ref().map(|(h, p)| (h.as_str(), *p)) != Some((host, port)))
+        }
+    }
+
+    fn push(&self, host: Option<(String, u16)>, key: PublicKey) {
+        if let Ok(mut keys) = self.keys.lock() {
+            keys.push((host, key))
+        }
+    }
+}
+
+#[async_trait]
+impl HostKeyVerifier for PinnedKeys {
+    async fn verify(
+        &self,
+        check: &ServerKeyCheck<'_>,
+    ) -> Result<Option<ServerKeyVerdict>, crate::Error> {
+        let Ok(keys) = self.keys.lock() else {
+            return Ok(None);
+        };
+        let mut host_pinned = false;
+        for (host, key) in keys.iter() {
+            let for_host = host.as_ref().map(|(h, p)| (h.as_str(), *p));
+            if for_host.is_some() && for_host != check.host {
+                continue;
+            }
+            if key.key_data() == check.key.key_data() {
+                return Ok(Some(ServerKeyVerdict::Accept));
+            }
+            host_pinned |= for_host.is_some();
+        }
+        if host_pinned {
+            Ok(Some(ServerKeyVerdict::Reject))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn record(&self, check: &ServerKeyCheck<'_>) -> Result<(), crate::Error> {
+        if let Some((host, port)) = check.host {
+            self.pin(host, port, check.key.clone())
+        }
+        Ok(())
+    }
+}
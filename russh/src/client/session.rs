@@ -1,11 +1,15 @@
-use log::error;
 use russh_keys::map_err;
 use ssh_encoding::Encode;
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::oneshot;
 
-use crate::client::Session;
+use crate::client::{ForwardedTcpIp, Handler, Session};
+use crate::extensions::{self, Extensions};
+use crate::kex::EXTENSION_SUPPORT_AS_SERVER;
+use crate::logging::error;
 use crate::session::EncryptedState;
-use crate::{msg, ChannelId, CryptoVec, Disconnect, Pty, Sig};
+use crate::tun::TunMode;
+use crate::{msg, ChannelId, CryptoVec, Disconnect, Negotiated, Pty, Sig};
 
 impl Session {
     fn channel_open_generic<F>(
@@ -100,6 +104,30 @@ impl Session {
         })
     }
 
+    pub fn channel_open_tun(
+        &mut self,
+        mode: TunMode,
+        unit: u32,
+    ) -> Result<ChannelId, crate::Error> {
+        self.channel_open_generic(b"tun@openssh.com", |write| {
+            u32::from(mode).encode(write)?;
+            unit.encode(write)?;
+            Ok(())
+        })
+    }
+
+    /// Switch the TCP connection to the interactive mode of
+    /// [`Config::socket_options`](super::Config::socket_options), once
+    /// a terminal is requested.
+    fn set_interactive(&self) {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(ref socket) = self.socket {
+            if let Err(e) = socket.set_interactive(true) {
+                crate::logging::debug!("Could not switch the socket to interactive mode: {:?}", e)
+            }
+        }
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn request_pty(
         &mut self,
@@ -112,6 +140,7 @@ impl Session {
         pix_height: u32,
         terminal_modes: &[(Pty, u32)],
     ) -> Result<(), crate::Error> {
+        self.set_interactive();
         if let Some(ref mut enc) = self.common.encrypted {
             if let Some(channel) = enc.channels.get(&channel) {
                 push_packet!(enc.write, {
@@ -247,6 +276,26 @@ impl Session {
         Ok(())
     }
 
+    pub fn send_break(
+        &mut self,
+        channel: ChannelId,
+        want_reply: bool,
+        length: u32,
+    ) -> Result<(), crate::Error> {
+        if let Some(ref mut enc) = self.common.encrypted {
+            if let Some(channel) = enc.channels.get(&channel) {
+                push_packet!(enc.write, {
+                    msg::CHANNEL_REQUEST.encode(&mut enc.write)?;
+                    channel.recipient_channel.encode(&mut enc.write)?;
+                    "break".encode(&mut enc.write)?;
+                    (want_reply as u8).encode(&mut enc.write)?;
+                    length.encode(&mut enc.write)?;
+                });
+            }
+        }
+        Ok(())
+    }
+
     pub fn request_subsystem(
         &mut self,
         want_reply: bool,
@@ -322,6 +371,43 @@ impl Session {
         Ok(())
     }
 
+    /// Requests a TCP/IP forwarding whose connections are sent to
+    /// `connections`, see [`Handle::forward_remote`](super::Handle::forward_remote).
+    pub(crate) fn remote_forward(
+        &mut self,
+        reply_channel: oneshot::Sender<Option<u32>>,
+        address: String,
+        port: u32,
+        connections: UnboundedSender<ForwardedTcpIp>,
+    ) -> Result<(), crate::Error> {
+        if let Some(ref mut enc) = self.common.encrypted {
+            push_packet!(enc.write, {
+                msg::GLOBAL_REQUEST.encode(&mut enc.write)?;
+                "tcpip-forward".encode(&mut enc.write)?;
+                1u8.encode(&mut enc.write)?;
+                address.encode(&mut enc.write)?;
+                port.encode(&mut enc.write)?;
+            });
+            self.open_global_requests.push_back(
+                crate::session::GlobalRequestResponse::RemoteForward {
+                    reply_channel,
+                    address,
+                    port,
+                    connections,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// The address and port of the remote forwards registered with
+    /// [`Handle::forward_remote`](super::Handle::forward_remote).
+    pub fn remote_forwards(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.remote_forwards
+            .keys()
+            .map(|(address, port)| (address.as_str(), *port))
+    }
+
     /// Requests cancellation of TCP/IP forwarding from the server
     ///
     /// If `reply_channel` is not None, sets want_reply and returns the server's response via the channel,
@@ -332,6 +418,7 @@ impl Session {
         address: &str,
         port: u32,
     ) -> Result<(), crate::Error> {
+        self.remote_forwards.remove(&(address.to_string(), port));
         if let Some(ref mut enc) = self.common.encrypted {
             let want_reply = reply_channel.is_some();
             if let Some(reply_channel) = reply_channel {
@@ -402,6 +489,58 @@ impl Session {
         Ok(())
     }
 
+    /// Tell the server that no more session channels will be opened
+    /// on this connection (OpenSSH's `no-more-sessions@openssh.com`),
+    /// so that it refuses them, should a hijacked connection try to.
+    pub fn no_more_sessions(&mut self) -> Result<(), crate::Error> {
+        if let Some(ref mut enc) = self.common.encrypted {
+            push_packet!(enc.write, {
+                msg::GLOBAL_REQUEST.encode(&mut enc.write)?;
+                "no-more-sessions@openssh.com".encode(&mut enc.write)?;
+                0u8.encode(&mut enc.write)?;
+            });
+        }
+        Ok(())
+    }
+
+    /// Ask the server to prove that it owns the private parts of
+    /// `keys`, usually the new keys among those passed to
+    /// [`Handler::openssh_ext_host_keys_announced`](super::Handler::openssh_ext_host_keys_announced).
+    /// If the server replies with valid signatures for all of them,
+    /// [`Handler::openssh_ext_host_keys_proven`](super::Handler::openssh_ext_host_keys_proven)
+    /// is called.
+    pub fn request_host_keys_proof(
+        &mut self,
+        keys: Vec<ssh_key::PublicKey>,
+    ) -> Result<(), crate::Error> {
+        if let Some(ref mut enc) = self.common.encrypted {
+            let mut blobs = Vec::with_capacity(keys.len());
+            for key in &keys {
+                blobs.push(key.to_bytes()?)
+            }
+            self.open_global_requests
+                .push_back(crate::session::GlobalRequestResponse::HostKeysProve(keys));
+            push_packet!(enc.write, {
+                msg::GLOBAL_REQUEST.encode(&mut enc.write)?;
+                "hostkeys-prove-00@openssh.com".encode(&mut enc.write)?;
+                1u8.encode(&mut enc.write)?;
+                for blob in &blobs {
+                    blob.encode(&mut enc.write)?;
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Start a key re-exchange now, unless one is already running.
+    /// Keys are also re-exchanged automatically according to
+    /// [`Config::limits`](super::Config::limits).
+    pub fn request_rekey(&mut self) -> Result<(), crate::Error> {
+        // Packets queued before the KEXINIT must be sent with the old keys.
+        self.flush()?;
+        self.initiate_rekey()
+    }
+
     pub fn send_keepalive(&mut self, want_reply: bool) -> Result<(), crate::Error> {
         self.open_global_requests
             .push_back(crate::session::GlobalRequestResponse::Keepalive);
@@ -507,4 +646,59 @@ impl Session {
     pub fn remote_sshid(&self) -> &[u8] {
         &self.common.remote_sshid
     }
+
+    /// The algorithms negotiated by the latest key exchange, and the
+    /// session id, or `None` before the first key exchange completed.
+    pub fn negotiated(&self) -> Option<&Negotiated> {
+        self.common.encrypted.as_ref().map(|enc| &enc.negotiated)
+    }
+
+    /// Share the algorithms of a completed key exchange with the
+    /// handle of this session, and tell the handler.
+    pub(crate) async fn report_negotiated<H: Handler>(
+        &mut self,
+        handler: &mut H,
+    ) -> Result<(), H::Error> {
+        let Some(negotiated) = self
+            .common
+            .encrypted
+            .as_ref()
+            .map(|enc| enc.negotiated.clone())
+        else {
+            return Ok(());
+        };
+        self.negotiated.set(negotiated.clone());
+        handler.negotiated(&negotiated, self).await
+    }
+
+    /// The signature algorithms accepted by the server for public key
+    /// authentication, from its `server-sig-algs` extension (RFC 8308),
+    /// or `None` if it didn't send one.
+    pub fn server_sig_algs(&self) -> Option<&[ssh_key::Algorithm]> {
+        self.server_sig_algs.as_deref()
+    }
+
+    /// The extensions the server sent in its `SSH_MSG_EXT_INFO`
+    /// messages (RFC 8308), if any.
+    pub fn server_extensions(&self) -> &Extensions {
+        &self.server_extensions
+    }
+
+    /// Send [`Config::extensions`](crate::client::Config::extensions)
+    /// if the server supports them. This
+    /// must be the first message after the initial NEWKEYS.
+    pub(crate) fn maybe_send_ext_info(&mut self) -> Result<(), crate::Error> {
+        if self.common.config.extensions.is_empty() {
+            return Ok(());
+        }
+        if let Some(ref mut enc) = self.common.encrypted {
+            let supported = enc.exchange.as_ref().is_some_and(|e| {
+                extensions::peer_supports(&e.server_kex_init, &EXTENSION_SUPPORT_AS_SERVER)
+            });
+            if supported {
+                self.common.config.extensions.write_packet(&mut enc.write)?;
+            }
+        }
+        Ok(())
+    }
 }
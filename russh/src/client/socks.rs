@@ -0,0 +1,224 @@
+//! The server side of SOCKS5 and SOCKS4a, for dynamic port forwarding
+//! (the equivalent of `ssh -D`). Only CONNECT without authentication
+//! is supported.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::logging::debug;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Version {
+    V4,
+    V5,
+}
+
+const CONNECT: u8 = 1;
+const NO_AUTHENTICATION: u8 = 0;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+const SOCKS5_SUCCEEDED: u8 = 0;
+const SOCKS5_GENERAL_FAILURE: u8 = 1;
+const SOCKS5_COMMAND_NOT_SUPPORTED: u8 = 7;
+const SOCKS5_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+const SOCKS4_GRANTED: u8 = 0x5a;
+const SOCKS4_REJECTED: u8 = 0x5b;
+
+/// Read the handshake and request of a SOCKS client, and return the
+/// destination host and port. Requests we don't support (other
+/// commands, such as UDP ASSOCIATE, or authentication methods) are
+/// answered with an error.
+pub(super) async fn read_request<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> Result<(Version, String, u16), crate::Error> {
+    match stream.read_u8().await? {
+        4 => {
+            let command = stream.read_u8().await?;
+            let port = stream.read_u16().await?;
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            // The user id is ignored.
+            read_nul_terminated(stream).await?;
+            if command != CONNECT {
+                reply(stream, Version::V4, false).await?;
+                return Err(crate::Error::Socks);
+            }
+            // SOCKS4a: 0.0.0.x, with x != 0, means a host name follows.
+            let host = if let [0, 0, 0, x] = ip {
+                if x != 0 {
+                    String::from_utf8(read_nul_terminated(stream).await?)
+                        .map_err(|_| crate::Error::Socks)?
+                } else {
+                    Ipv4Addr::from(ip).to_string()
+                }
+            } else {
+                Ipv4Addr::from(ip).to_string()
+            };
+            Ok((Version::V4, host, port))
+        }
+        5 => {
+            let n = stream.read_u8().await?;
+            let mut methods = vec![0; n as usize];
+            stream.read_exact(&mut methods).await?;
+            if !methods.contains(&NO_AUTHENTICATION) {
+                stream.write_all(&[5, NO_ACCEPTABLE_METHODS]).await?;
+                return Err(crate::Error::Socks);
+            }
+            stream.write_all(&[5, NO_AUTHENTICATION]).await?;
+
+            let mut header = [0; 4];
+            stream.read_exact(&mut header).await?;
+            let [version, command, _, address_type] = header;
+            if version != 5 {
+                return Err(crate::Error::Socks);
+            }
+            let host = match address_type {
+                1 => {
+                    let mut ip = [0; 4];
+                    stream.read_exact(&mut ip).await?;
+                    Ipv4Addr::from(ip).to_string()
+                }
+                3 => {
+                    let len = stream.read_u8().await?;
+                    let mut name = vec![0; len as usize];
+                    stream.read_exact(&mut name).await?;
+                    String::from_utf8(name).map_err(|_| crate::Error::Socks)?
+                }
+                4 => {
+                    let mut ip = [0; 16];
+                    stream.read_exact(&mut ip).await?;
+                    Ipv6Addr::from(ip).to_string()
+                }
+                _ => {
+                    socks5_reply(stream, SOCKS5_ADDRESS_TYPE_NOT_SUPPORTED).await?;
+                    return Err(crate::Error::Socks);
+                }
+            };
+            let port = stream.read_u16().await?;
+            if command != CONNECT {
+                debug!("unsupported SOCKS5 command {}", command);
+                socks5_reply(stream, SOCKS5_COMMAND_NOT_SUPPORTED).await?;
+                return Err(crate::Error::Socks);
+            }
+            Ok((Version::V5, host, port))
+        }
+        _ => Err(crate::Error::Socks),
+    }
+}
+
+/// Tell the client whether the connection succeeded.
+pub(super) async fn reply<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    version: Version,
+    success: bool,
+) -> Result<(), crate::Error> {
+    match version {
+        Version::V4 => {
+            let status = if success {
+                SOCKS4_GRANTED
+            } else {
+                SOCKS4_REJECTED
+            };
+            stream.write_all(&[0, status, 0, 0, 0, 0, 0, 0]).await?;
+            Ok(())
+        }
+        Version::V5 => {
+            let status = if success {
+                SOCKS5_SUCCEEDED
+            } else {
+                SOCKS5_GENERAL_FAILURE
+            };
+            socks5_reply(stream, status).await
+        }
+    }
+}
+
+/// A SOCKS5 reply, with an unspecified bound address.
+async fn socks5_reply<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    status: u8,
+) -> Result<(), crate::Error> {
+    stream
+        .write_all(&[5, status, 0, 1, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+async fn read_nul_terminated<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Vec<u8>, crate::Error> {
+    let mut s = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(s),
+            _ if s.len() >= 255 => return Err(crate::Error::Socks),
+            b => s.push(b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_socks5_domain() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&[5, 2, 2, 0]).await.unwrap();
+        client.write_all(&[5, 1, 0, 3, 11]).await.unwrap();
+        client.write_all(b"example.com").await.unwrap();
+        client.write_all(&443u16.to_be_bytes()).await.unwrap();
+
+        let (version, host, port) = read_request(&mut server).await.unwrap();
+        assert_eq!(version, Version::V5);
+        assert_eq!(host, "example.com");
+        assert_eq!(port, 443);
+        reply(&mut server, version, true).await.unwrap();
+
+        let mut answer = [0; 12];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer, [5, 0, 5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_socks5_udp_associate() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        client
+            .write_all(&[5, 3, 0, 1, 127, 0, 0, 1, 0, 53])
+            .await
+            .unwrap();
+        assert!(matches!(
+            read_request(&mut server).await,
+            Err(crate::Error::Socks)
+        ));
+
+        let mut answer = [0; 12];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(
+            answer.get(2..4),
+            Some(&[5, SOCKS5_COMMAND_NOT_SUPPORTED][..])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_socks4a() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&[4, 1, 0, 80, 0, 0, 0, 1]).await.unwrap();
+        client.write_all(b"user\0example.org\0").await.unwrap();
+
+        let (version, host, port) = read_request(&mut server).await.unwrap();
+        assert_eq!(version, Version::V4);
+        assert_eq!(host, "example.org");
+        assert_eq!(port, 80);
+        reply(&mut server, version, false).await.unwrap();
+
+        let mut answer = [0; 8];
+        client.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer.get(1), Some(&SOCKS4_REJECTED));
+    }
+}
@@ -0,0 +1,366 @@
+//! Connecting to a host as described by the user's `~/.ssh/config`,
+//! using the `russh-config` crate.
+
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use russh_config::{AlgorithmList, StrictHostKeyChecking};
+use russh_util::net::SocketControl;
+use ssh_key::Algorithm;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{
+    connect_stream_with_context, Config, ConnectContext, Handle, Handler, KnownHostsVerifier,
+    ServerKeyPrecheck, ServerKeyVerdict,
+};
+use crate::logging::debug;
+use crate::{cipher, kex, mac, Preferred};
+
+/// Connect to the host described by `ssh_config` (as returned by
+/// [`russh_config::parse_home`] for instance), and authenticate with
+/// its identity files:
+///
+/// - the connection goes through the `ProxyJump` hosts or the
+///   `ProxyCommand` if there is one, and directly to `HostName` and
+///   `Port` otherwise, with the `BindAddress`, `AddressFamily`,
+///   `IPQoS` and `TCPKeepAlive` options instead of
+///   [`Config::socket_options`].
+/// - the server key is checked against the `UserKnownHostsFile`s
+///   according to `StrictHostKeyChecking`, see
+///   [`KnownHostsVerifier::from_ssh_config`]. Only in the `ask` mode
+///   are unknown keys passed on to [`Config::host_key_verifier`] and
+///   [`Handler::verify_server_key`], and keys they accept with
+///   [`ServerKeyVerdict::AcceptAndRecord`] are added to the first file.
+/// - each `IdentityFile` is tried in order as `User`, or the default
+///   keys of OpenSSH if there are none. Keys that can't be loaded,
+///   such as encrypted keys, are skipped.
+///
+/// Returns [`crate::Error::NoAuthMethod`] if none of the identities
+/// was accepted by the server.
+///
+/// The algorithm options are not applied to `config`, use
+/// [`Preferred::with_ssh_config`] for that.
+pub async fn connect_with_config<H: Handler + Send + 'static>(
+    config: Arc<Config>,
+    ssh_config: &russh_config::Config,
+    handler: H,
+) -> Result<Handle<H>, H::Error> {
+    let (stream, socket) = open_transport(&config, ssh_config).await?;
+    let context = ConnectContext {
+        server_key_precheck: Some(known_hosts_precheck(ssh_config)),
+        socket,
+    };
+    let mut handle = connect_stream_with_context(config, stream, handler, context).await?;
+    if authenticate_identities(&mut handle, ssh_config).await? {
+        Ok(handle)
+    } else {
+        Err(crate::Error::NoAuthMethod.into())
+    }
+}
+
+trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// Open the stream the SSH session to `ssh_config` runs on. For each
+/// `ProxyJump` hop, this is a `direct-tcpip` channel opened by the
+/// previous hop, which is kept alive by the channel.
+///
+/// There is nobody to ask about unknown jump host keys, so they are
+/// refused in the `ask` mode of `StrictHostKeyChecking`.
+///
+/// The socket is returned with the stream when connecting directly.
+async fn open_transport(
+    config: &Arc<Config>,
+    ssh_config: &russh_config::Config,
+) -> Result<(Box<dyn Transport>, Option<SocketControl>), crate::Error> {
+    let jumps = ssh_config.proxy_jump_hosts();
+    let mut jumps = jumps.iter();
+    let Some(first) = jumps.next() else {
+        let stream = ssh_config.stream().await?;
+        let socket = match stream {
+            russh_config::Stream::Tcp(ref tcp) => {
+                SocketControl::new(tcp, ssh_config.socket_options()).ok()
+            }
+            _ => None,
+        };
+        return Ok((Box::new(stream), socket));
+    };
+    let mut hop = first.config()?;
+    let mut stream: Box<dyn Transport> = Box::new(hop.stream().await?);
+    let next_hops = jumps
+        .map(|jump| jump.config())
+        .chain(std::iter::once(Ok(ssh_config.clone())));
+    for next in next_hops {
+        let next = next?;
+        debug!("Jumping from {:?} to {:?}", hop.host_name, next.host_name);
+        let context = ConnectContext {
+            server_key_precheck: Some(known_hosts_precheck(&hop)),
+            ..Default::default()
+        };
+        let mut handle =
+            connect_stream_with_context(config.clone(), stream, JumpHandler, context).await?;
+        if !authenticate_identities(&mut handle, &hop).await? {
+            return Err(crate::Error::NoAuthMethod);
+        }
+        let channel = handle
+            .channel_open_direct_tcpip(next.host_name.clone(), next.port.into(), "127.0.0.1", 0)
+            .await?;
+        stream = Box::new(channel.into_stream());
+        hop = next;
+    }
+    Ok((stream, None))
+}
+
+struct JumpHandler;
+
+#[async_trait]
+impl Handler for JumpHandler {
+    type Error = crate::Error;
+}
+
+async fn authenticate_identities<H: Handler>(
+    handle: &mut Handle<H>,
+    ssh_config: &russh_config::Config,
+) -> Result<bool, crate::Error> {
+    for path in ssh_config.identities() {
+        let key = match russh_keys::load_secret_key(&path, None) {
+            Ok(key) => key,
+            Err(e) => {
+                debug!("Skipping identity file {:?}: {:?}", path, e);
+                continue;
+            }
+        };
+        if handle
+            .authenticate_publickey(&ssh_config.user, Arc::new(key))
+            .await?
+            .success()
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn known_hosts_precheck(ssh_config: &russh_config::Config) -> ServerKeyPrecheck {
+    let host = ssh_config.host_name.clone();
+    let port = ssh_config.port;
+    let verifier = Arc::new(KnownHostsVerifier::from_ssh_config(ssh_config));
+    let (lookup, lookup_host) = (verifier.clone(), host.clone());
+    let record_host = host.clone();
+    ServerKeyPrecheck {
+        host,
+        port,
+        lookup: Box::new(move |pubkey| {
+            let status = lookup.status(&lookup_host, port, pubkey)?;
+            let verdict = lookup.verdict(&status);
+            Ok((status, verdict))
+        }),
+        record: Box::new(move |pubkey| verifier.record_key(&record_host, port, pubkey)),
+    }
+}
+
+impl KnownHostsVerifier {
+    /// A verifier of the `UserKnownHostsFile`s of `ssh_config`, or the
+    /// user's known_hosts file if there are none, deciding about
+    /// unknown keys according to `StrictHostKeyChecking`: they are left
+    /// to the next verifiers in the `ask` mode.
+    pub fn from_ssh_config(ssh_config: &russh_config::Config) -> Self {
+        let mut verifier = KnownHostsVerifier::new()
+            .with_host(ssh_config.host_name.clone(), ssh_config.port)
+            // Changed keys are refused, whatever the policy says.
+            .unknown_keys(match ssh_config.strict_host_key_checking {
+                StrictHostKeyChecking::Yes => Some(ServerKeyVerdict::Reject),
+                StrictHostKeyChecking::AcceptNew | StrictHostKeyChecking::No => {
+                    Some(ServerKeyVerdict::AcceptAndRecord)
+                }
+                StrictHostKeyChecking::Ask => None,
+            });
+        for file in &ssh_config.user_known_hosts_files {
+            verifier = verifier.file(file)
+        }
+        verifier
+    }
+}
+
+/// Pseudo-algorithms used to signal extensions, which aren't
+/// configurable in `KexAlgorithms`.
+const KEX_EXTENSIONS: &[kex::Name] = &[
+    kex::EXTENSION_SUPPORT_AS_CLIENT,
+    kex::EXTENSION_SUPPORT_AS_SERVER,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER,
+];
+
+impl Preferred {
+    /// Apply the `KexAlgorithms`, `HostKeyAlgorithms`, `Ciphers` and
+    /// `MACs` options of `ssh_config` to these lists, which play the
+    /// role of OpenSSH's defaults. Algorithms russh doesn't implement
+    /// are left out.
+    pub fn with_ssh_config(&self, ssh_config: &russh_config::Config) -> Preferred {
+        self.with_algorithm_lists(
+            ssh_config.kex_algorithms.as_ref(),
+            ssh_config.host_key_algorithms.as_ref(),
+            ssh_config.ciphers.as_ref(),
+            ssh_config.macs.as_ref(),
+        )
+    }
+
+    /// Apply algorithm lists written as in OpenSSH's configuration
+    /// files to these lists.
+    pub(crate) fn with_algorithm_lists(
+        &self,
+        kex_algorithms: Option<&AlgorithmList>,
+        host_key_algorithms: Option<&AlgorithmList>,
+        ciphers: Option<&AlgorithmList>,
+        macs: Option<&AlgorithmList>,
+    ) -> Preferred {
+        let mut preferred = self.clone();
+        if let Some(list) = kex_algorithms {
+            let current: Vec<kex::Name> = self
+                .kex
+                .iter()
+                .filter(|k| !KEX_EXTENSIONS.contains(*k))
+                .cloned()
+                .collect();
+            let supported: Vec<&str> = kex::ALL_KEX_ALGORITHMS
+                .iter()
+                .filter(|k| ***k != kex::NONE)
+                .map(|k| k.as_ref())
+                .collect();
+            let mut kex = apply_list(list, &current, &supported, |k| kex::Name::try_from(k).ok());
+            kex.extend(self.kex.iter().filter(|k| KEX_EXTENSIONS.contains(*k)));
+            preferred.kex = kex.into();
+        }
+        if let Some(list) = host_key_algorithms {
+            let default_keys = Preferred::DEFAULT.key;
+            let supported: Vec<&str> = default_keys.iter().map(|k| k.as_str()).collect();
+            preferred.key =
+                apply_list(list, &self.key, &supported, |k| Algorithm::new(k).ok()).into();
+        }
+        if let Some(list) = ciphers {
+            let supported: Vec<&str> = cipher::ALL_CIPHERS
+                .iter()
+                .filter(|c| ***c != cipher::NONE && ***c != cipher::CLEAR)
+                .map(|c| c.as_ref())
+                .collect();
+            preferred.cipher = apply_list(list, &self.cipher, &supported, |c| {
+                cipher::Name::try_from(c).ok()
+            })
+            .into();
+        }
+        if let Some(list) = macs {
+            let supported: Vec<&str> = mac::ALL_MAC_ALGORITHMS
+                .iter()
+                .filter(|m| ***m != mac::NONE)
+                .map(|m| m.as_ref())
+                .collect();
+            preferred.mac =
+                apply_list(list, &self.mac, &supported, |m| mac::Name::try_from(m).ok()).into();
+        }
+        preferred
+    }
+}
+
+fn apply_list<N: Clone + AsRef<str>, F: Fn(&str) -> Option<N>>(
+    list: &AlgorithmList,
+    current: &[N],
+    supported: &[&str],
+    from_name: F,
+) -> Vec<N> {
+    let current: Vec<&str> = current.iter().map(|n| n.as_ref()).collect();
+    list.apply(&current, supported)
+        .iter()
+        .filter_map(|name| {
+            let n = from_name(name);
+            if n.is_none() {
+                debug!("Unsupported algorithm {:?}", name);
+            }
+            n
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use std::borrow::Cow;
+
+    use ssh_key::{EcdsaCurve, HashAlg};
+
+    use super::*;
+
+    #[test]
+    fn test_with_ssh_config() {
+        let ssh_config = russh_config::parse(
+            "Host example
+                KexAlgorithms +diffie-hellman-group1-sha1
+                HostKeyAlgorithms -ssh-rsa,rsa-sha2-256
+                Ciphers -aes*-ctr
+                MACs ^hmac-sha1",
+            "example",
+        )
+        .unwrap();
+        let preferred = Preferred {
+            kex: Cow::Borrowed(&[
+                kex::CURVE25519,
+                kex::DH_G14_SHA256,
+                kex::EXTENSION_SUPPORT_AS_CLIENT,
+                kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+            ]),
+            ..Preferred::DEFAULT
+        }
+        .with_ssh_config(&ssh_config);
+
+        // Extensions stay at the end of the list.
+        assert_eq!(
+            &preferred.kex[..],
+            &[
+                kex::CURVE25519,
+                kex::DH_G14_SHA256,
+                kex::DH_G1_SHA1,
+                kex::EXTENSION_SUPPORT_AS_CLIENT,
+                kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+            ]
+        );
+        assert_eq!(
+            &preferred.key[..],
+            &[
+                Algorithm::Ed25519,
+                Algorithm::Ecdsa {
+                    curve: EcdsaCurve::NistP256,
+                },
+                Algorithm::Ecdsa {
+                    curve: EcdsaCurve::NistP384,
+                },
+                Algorithm::Ecdsa {
+                    curve: EcdsaCurve::NistP521,
+                },
+                Algorithm::Rsa {
+                    hash: Some(HashAlg::Sha512),
+                },
+            ]
+        );
+        assert_eq!(
+            &preferred.cipher[..],
+            &[
+                cipher::CHACHA20_POLY1305,
+                cipher::AES_256_GCM,
+                cipher::AES_128_GCM,
+            ]
+        );
+        assert_eq!(
+            &preferred.mac[..],
+            &[
+                mac::HMAC_SHA1,
+                mac::HMAC_SHA512_ETM,
+                mac::HMAC_SHA256_ETM,
+                mac::HMAC_SHA512,
+                mac::HMAC_SHA256,
+                mac::HMAC_SHA1_ETM,
+            ]
+        );
+        assert_eq!(preferred.compression, Preferred::DEFAULT.compression);
+    }
+}
@@ -0,0 +1,202 @@
+//! Verification of server keys against the SSHFP records of their
+//! host (RFC 4255), like OpenSSH's `VerifyHostKeyDNS`.
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use russh_keys::helpers::EncodedExt;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use ssh_key::public::KeyData;
+
+use super::server_key::{HostKeyVerifier, ServerKeyCheck, ServerKeyVerdict};
+use crate::logging::debug;
+
+/// An SSHFP resource record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshfpRecord {
+    /// The key algorithm: 1 for RSA, 2 for DSA, 3 for ECDSA, 4 for
+    /// Ed25519 (RFC 4255, 6594 and 7479).
+    pub algorithm: u8,
+    /// The hash of the fingerprint: 1 for SHA-1, 2 for SHA-256.
+    pub fingerprint_type: u8,
+    pub fingerprint: Vec<u8>,
+}
+
+/// The SSHFP records of a host name.
+#[derive(Debug, Clone, Default)]
+pub struct SshfpLookup {
+    pub records: Vec<SshfpRecord>,
+    /// Whether the answer was validated with DNSSEC, by the resolver
+    /// or by a trusted recursive server (the AD bit).
+    pub dnssec_validated: bool,
+}
+
+/// Looks SSHFP records up in the DNS, for instance with a resolver
+/// library that validates DNSSEC.
+#[async_trait]
+pub trait SshfpResolver: Send + Sync {
+    async fn lookup_sshfp(&self, host: &str) -> Result<SshfpLookup, crate::Error>;
+}
+
+/// A [`HostKeyVerifier`] checking server keys against the SSHFP
+/// records of their host. Only a record matching the key in a DNSSEC
+/// validated answer accepts the key: as in OpenSSH, other answers
+/// leave the decision to the next verifiers or to the handler.
+pub struct SshfpVerifier {
+    resolver: Arc<dyn SshfpResolver>,
+    host: Option<String>,
+}
+
+impl SshfpVerifier {
+    /// Look up the host of the connection, which is only known when
+    /// connecting with `connect_with_config`.
+    pub fn new(resolver: Arc<dyn SshfpResolver>) -> Self {
+        SshfpVerifier {
+            resolver,
+            host: None,
+        }
+    }
+
+    /// Look up `host` instead.
+    pub fn with_host<H: Into<String>>(mut self, host: H) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+}
+
+/// The SSHFP algorithm number of a key, if it can be published.
+fn sshfp_algorithm(key: &KeyData) -> Option<u8> {
+    match key {
+        KeyData::Rsa(_) => Some(1),
+        KeyData::Dsa(_) => Some(2),
+        KeyData::Ecdsa(_) => Some(3),
+        KeyData::Ed25519(_) => Some(4),
+        _ => None,
+    }
+}
+
+/// Whether `record` is the fingerprint of `blob`, `None` for hashes we
+/// don't know.
+fn matches(record: &SshfpRecord, blob: &[u8]) -> Option<bool> {
+    match record.fingerprint_type {
+        1 => Some(Sha1::digest(blob).as_slice() == record.fingerprint.as_slice()),
+        2 => Some(Sha256::digest(blob).as_slice() == record.fingerprint.as_slice()),
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl HostKeyVerifier for SshfpVerifier {
+    async fn verify(
+        &self,
+        check: &ServerKeyCheck<'_>,
+    ) -> Result<Option<ServerKeyVerdict>, crate::Error> {
+        let host = match (&self.host, check.host) {
+            (Some(host), _) => host.as_str(),
+            (None, Some((host, _))) => host,
+            (None, None) => {
+                debug!("sshfp: unknown host name");
+                return Ok(None);
+            }
+        };
+        let Some(algorithm) = sshfp_algorithm(check.key.key_data()) else {
+            return Ok(None);
+        };
+        let lookup = self.resolver.lookup_sshfp(host).await?;
+        let blob = check.key.key_data().encoded()?;
+        let mut found = false;
+        for record in lookup.records.iter().filter(|r| r.algorithm == algorithm) {
+            match matches(record, &blob) {
+                Some(true) => {
+                    if lookup.dnssec_validated {
+                        debug!("sshfp: matching fingerprint found for {:?}", host);
+                        return Ok(Some(ServerKeyVerdict::Accept));
+                    }
+                    debug!(
+                        "sshfp: matching fingerprint found for {:?}, but not validated",
+                        host
+                    );
+                    return Ok(None);
+                }
+                Some(false) => found = true,
+                None => {}
+            }
+        }
+        if found {
+            debug!("sshfp: the fingerprints of {:?} don't match its key", host);
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use hex_literal::hex;
+
+    use super::*;
+    use crate::client::KnownHostsStatus;
+
+    struct Resolver(SshfpLookup);
+
+    #[async_trait]
+    impl SshfpResolver for Resolver {
+        async fn lookup_sshfp(&self, host: &str) -> Result<SshfpLookup, crate::Error> {
+            assert_eq!(host, "example.com");
+            Ok(self.0.clone())
+        }
+    }
+
+    async fn verify(records: Vec<SshfpRecord>, dnssec_validated: bool) -> Option<ServerKeyVerdict> {
+        let key = russh_keys::parse_public_key_base64(
+            "AAAAC3NzaC1lZDI1NTE5AAAAIJdD7y3aLq454yWBdwLWbieU1ebz9/cu7/QEXn9OIeZJ",
+        )
+        .unwrap();
+        let verifier = SshfpVerifier::new(Arc::new(Resolver(SshfpLookup {
+            records,
+            dnssec_validated,
+        })));
+        verifier
+            .verify(&ServerKeyCheck {
+                host: Some(("example.com", 22)),
+                key: &key,
+                known_hosts: KnownHostsStatus::Unknown,
+            })
+            .await
+            .unwrap()
+    }
+
+    fn record(fingerprint_type: u8, fingerprint: &[u8]) -> SshfpRecord {
+        SshfpRecord {
+            algorithm: 4,
+            fingerprint_type,
+            fingerprint: fingerprint.to_vec(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sshfp() {
+        // As printed by `ssh-keygen -r example.com`.
+        let sha1 = record(1, &hex!("e12886e189f486551b8e741f7c8ea7f159d6ce11"));
+        let sha256 = record(
+            2,
+            &hex!("4fb4af67672c96aa4f8fa9cace2b420471e5a55177af732f2f09852f47e4d081"),
+        );
+        let other = record(2, &[0; 32]);
+
+        assert_eq!(
+            verify(vec![other.clone(), sha256.clone()], true).await,
+            Some(ServerKeyVerdict::Accept)
+        );
+        assert_eq!(
+            verify(vec![sha1.clone()], true).await,
+            Some(ServerKeyVerdict::Accept)
+        );
+        assert_eq!(verify(vec![sha1], false).await, None);
+        assert_eq!(verify(vec![sha256], false).await, None);
+        assert_eq!(verify(vec![other], true).await, None);
+        assert_eq!(verify(vec![], true).await, None);
+    }
+}
@@ -0,0 +1,231 @@
+//! Forwarding the X11 connections of remote programs to the local
+//! display.
+//!
+//! Like OpenSSH, the server only gets a fake authentication cookie,
+//! which is replaced with the real one in the connection setup of
+//! each forwarded connection.
+
+use std::convert::TryFrom;
+use std::process::Command;
+
+use rand::RngCore;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::Msg;
+use crate::channels::Channel;
+use crate::logging::debug;
+
+const MIT_MAGIC_COOKIE: &str = "MIT-MAGIC-COOKIE-1";
+
+/// The local display, and the cookies used to forward X11 connections
+/// to it.
+#[derive(Debug, Clone)]
+pub struct X11Forwarding {
+    /// `None` for a local display (Unix socket).
+    host: Option<String>,
+    display: u32,
+    screen: u32,
+    /// The cookie of the local X server, if `xauth` knows it.
+    real_cookie: Option<Vec<u8>>,
+    fake_cookie: Vec<u8>,
+}
+
+impl X11Forwarding {
+    /// Forward to the display in `$DISPLAY`, reading its cookie with
+    /// `xauth`.
+    pub fn from_env() -> Result<Self, crate::Error> {
+        let display = std::env::var("DISPLAY").map_err(|_| russh_keys::Error::EnvVar("DISPLAY"))?;
+        let real_cookie = xauth_cookie(&display);
+        if real_cookie.is_none() {
+            debug!("no xauth cookie for {:?}", display);
+        }
+        Self::new(&display, real_cookie)
+    }
+
+    /// Forward to `display` (such as `:0` or `localhost:10.0`), with
+    /// the MIT-MAGIC-COOKIE-1 of the X server, if any.
+    pub fn new(display: &str, real_cookie: Option<Vec<u8>>) -> Result<Self, crate::Error> {
+        let (host, number) = display
+            .rsplit_once(':')
+            .ok_or(crate::Error::InvalidDisplay)?;
+        let (number, screen) = number.split_once('.').unwrap_or((number, "0"));
+        let display = number.parse().map_err(|_| crate::Error::InvalidDisplay)?;
+        let screen = screen.parse().map_err(|_| crate::Error::InvalidDisplay)?;
+        let host = match host {
+            "" | "unix" => None,
+            host => Some(host.to_string()),
+        };
+        let mut fake_cookie = vec![0; 16];
+        rand::thread_rng().fill_bytes(&mut fake_cookie);
+        Ok(X11Forwarding {
+            host,
+            display,
+            screen,
+            real_cookie,
+            fake_cookie,
+        })
+    }
+
+    /// Ask the server to forward X11 connections on `channel`. Each
+    /// of them then causes a call to
+    /// [`Handler::server_channel_open_x11`](super::Handler::server_channel_open_x11),
+    /// which should call [`X11Forwarding::forward`].
+    pub async fn request(
+        &self,
+        channel: &Channel<Msg>,
+        single_connection: bool,
+    ) -> Result<(), crate::Error> {
+        channel
+            .request_x11(
+                true,
+                single_connection,
+                MIT_MAGIC_COOKIE,
+                hex(&self.fake_cookie),
+                self.screen,
+            )
+            .await
+    }
+
+    /// Connect an X11 channel opened by the server to the local
+    /// display, until either side closes it.
+    pub async fn forward(&self, channel: Channel<Msg>) -> Result<(), crate::Error> {
+        let mut stream = channel.into_stream();
+        let setup = self.read_setup(&mut stream).await?;
+        match self.host {
+            None => {
+                #[cfg(unix)]
+                {
+                    let path = format!("/tmp/.X11-unix/X{}", self.display);
+                    let mut x = tokio::net::UnixStream::connect(path).await?;
+                    x.write_all(&setup).await?;
+                    tokio::io::copy_bidirectional(&mut stream, &mut x).await?;
+                }
+                #[cfg(not(unix))]
+                return Err(crate::Error::InvalidDisplay);
+            }
+            Some(ref host) => {
+                let port =
+                    u16::try_from(6000 + self.display).map_err(|_| crate::Error::InvalidDisplay)?;
+                let mut x = tokio::net::TcpStream::connect((host.as_str(), port)).await?;
+                x.write_all(&setup).await?;
+                tokio::io::copy_bidirectional(&mut stream, &mut x).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the connection setup sent by the X client, check the fake
+    /// cookie and replace it with the real one.
+    async fn read_setup<R: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut R,
+    ) -> Result<Vec<u8>, crate::Error> {
+        let mut header = [0; 12];
+        stream.read_exact(&mut header).await?;
+        let read_u16 = |i: usize| match header.get(i..i + 2) {
+            Some(&[a, b]) if header.first() == Some(&b'B') => u16::from_be_bytes([a, b]),
+            Some(&[a, b]) => u16::from_le_bytes([a, b]),
+            _ => 0,
+        };
+        let name_len = read_u16(6) as usize;
+        let data_len = read_u16(8) as usize;
+        let mut name = vec![0; padded(name_len)];
+        stream.read_exact(&mut name).await?;
+        let mut data = vec![0; padded(data_len)];
+        stream.read_exact(&mut data).await?;
+        name.truncate(name_len);
+        data.truncate(data_len);
+        if name != MIT_MAGIC_COOKIE.as_bytes() || data != self.fake_cookie {
+            debug!("X11 connection with a wrong cookie");
+            return Err(crate::Error::X11Auth);
+        }
+
+        let (name, data) = match self.real_cookie {
+            Some(ref cookie) => (MIT_MAGIC_COOKIE.as_bytes(), cookie.as_slice()),
+            None => (&b""[..], &b""[..]),
+        };
+        let mut setup: Vec<u8> = header.iter().take(6).copied().collect();
+        let big_endian = header.first() == Some(&b'B');
+        for len in [name.len() as u16, data.len() as u16] {
+            if big_endian {
+                setup.extend_from_slice(&len.to_be_bytes())
+            } else {
+                setup.extend_from_slice(&len.to_le_bytes())
+            }
+        }
+        setup.extend_from_slice(&[0, 0]);
+        for s in [name, data] {
+            setup.extend_from_slice(s);
+            setup.resize(setup.len() + padded(s.len()) - s.len(), 0);
+        }
+        Ok(setup)
+    }
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The MIT-MAGIC-COOKIE-1 of `display`, as given by `xauth list`.
+fn xauth_cookie(display: &str) -> Option<Vec<u8>> {
+    let output = Command::new("xauth")
+        .arg("list")
+        .arg(display)
+        .output()
+        .ok()?;
+    let output = String::from_utf8(output.stdout).ok()?;
+    output.lines().find_map(|line| {
+        let mut fields = line.split_whitespace().skip(1);
+        if fields.next()? == MIT_MAGIC_COOKIE {
+            unhex(fields.next()?)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_x11_setup_cookie() {
+        let x11 = X11Forwarding::new("localhost:10.0", Some(vec![7; 16])).unwrap();
+        assert_eq!(x11.host.as_deref(), Some("localhost"));
+        assert_eq!(x11.display, 10);
+
+        let mut setup = vec![b'l', 0, 11, 0, 0, 0, 18, 0, 16, 0, 0, 0];
+        setup.extend_from_slice(MIT_MAGIC_COOKIE.as_bytes());
+        setup.extend_from_slice(&[0, 0]);
+        setup.extend_from_slice(&x11.fake_cookie);
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&setup).await.unwrap();
+        let forwarded = x11.read_setup(&mut server).await.unwrap();
+        assert_eq!(forwarded.len(), setup.len());
+        assert_eq!(forwarded.get(32..), Some(&[7; 16][..]));
+
+        let wrong = X11Forwarding::new(":0", None).unwrap();
+        client.write_all(&setup).await.unwrap();
+        assert!(matches!(
+            wrong.read_setup(&mut server).await,
+            Err(crate::Error::X11Auth)
+        ));
+    }
+}
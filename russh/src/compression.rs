@@ -7,7 +7,46 @@ use ssh_encoding::Encode;
 pub enum Compression {
     None,
     #[cfg(feature = "flate2")]
-    Zlib,
+    Zlib {
+        /// `zlib@openssh.com`: only start after authentication.
+        delayed: bool,
+        level: u32,
+    },
+}
+
+/// Compression settings, beyond the algorithms listed in
+/// [`Preferred::compression`](crate::Preferred::compression).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionConfig {
+    /// Offer to compress the data we send. Only `none` is offered for
+    /// that direction otherwise.
+    pub outgoing: bool,
+    /// Offer to receive compressed data.
+    pub incoming: bool,
+    /// zlib compression level of the data we send, from 0 (fastest)
+    /// to 9 (smallest).
+    pub level: u32,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            outgoing: true,
+            incoming: true,
+            level: 1,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// The algorithms to offer for one direction.
+    pub(crate) fn algorithms<'a>(&self, outgoing: bool, preferred: &'a [Name]) -> &'a [Name] {
+        if (outgoing && self.outgoing) || (!outgoing && self.incoming) {
+            preferred
+        } else {
+            &[NONE]
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -64,22 +103,58 @@ pub const ALL_COMPRESSION_ALGORITHMS: &[&Name] = &[
     &ZLIB_LEGACY,
 ];
 
+impl Compression {
+    /// The name of the algorithm.
+    pub fn name(&self) -> Name {
+        match self {
+            Compression::None => NONE,
+            #[cfg(feature = "flate2")]
+            Compression::Zlib { delayed: true, .. } => ZLIB_LEGACY,
+            #[cfg(feature = "flate2")]
+            Compression::Zlib { .. } => ZLIB,
+        }
+    }
+
+    /// Initialize the compressor right after the first key exchange
+    /// for `zlib`, or after authentication (if `authenticated`) for
+    /// `zlib@openssh.com`.
+    pub fn start_compress(&self, authenticated: bool, comp: &mut Compress) {
+        if self.is_delayed() == authenticated {
+            self.init_compress(comp)
+        }
+    }
+
+    /// Same as [`Compression::start_compress`], for the decompressor.
+    pub fn start_decompress(&self, authenticated: bool, comp: &mut Decompress) {
+        if self.is_delayed() == authenticated {
+            self.init_decompress(comp)
+        }
+    }
+}
+
 #[cfg(feature = "flate2")]
 impl Compression {
-    pub fn new(name: &Name) -> Self {
+    pub fn new(name: &Name, level: u32) -> Self {
         if name == &ZLIB || name == &ZLIB_LEGACY {
-            Compression::Zlib
+            Compression::Zlib {
+                delayed: name == &ZLIB_LEGACY,
+                level: level.min(9),
+            }
         } else {
             Compression::None
         }
     }
 
+    pub fn is_delayed(&self) -> bool {
+        matches!(self, Compression::Zlib { delayed: true, .. })
+    }
+
     pub fn init_compress(&self, comp: &mut Compress) {
-        if let Compression::Zlib = *self {
+        if let Compression::Zlib { level, .. } = *self {
             if let Compress::Zlib(ref mut c) = *comp {
                 c.reset()
             } else {
-                *comp = Compress::Zlib(flate2::Compress::new(flate2::Compression::fast(), true))
+                *comp = Compress::Zlib(flate2::Compress::new(flate2::Compression::new(level), true))
             }
         } else {
             *comp = Compress::None
@@ -87,7 +162,7 @@ impl Compression {
     }
 
     pub fn init_decompress(&self, comp: &mut Decompress) {
-        if let Compression::Zlib = *self {
+        if let Compression::Zlib { .. } = *self {
             if let Decompress::Zlib(ref mut c) = *comp {
                 c.reset(true)
             } else {
@@ -101,10 +176,14 @@ impl Compression {
 
 #[cfg(not(feature = "flate2"))]
 impl Compression {
-    pub fn new(_name: &Name) -> Self {
+    pub fn new(_name: &Name, _level: u32) -> Self {
         Compression::None
     }
 
+    pub fn is_delayed(&self) -> bool {
+        false
+    }
+
     pub fn init_compress(&self, _: &mut Compress) {}
 
     pub fn init_decompress(&self, _: &mut Decompress) {}
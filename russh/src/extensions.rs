@@ -0,0 +1,118 @@
+//! Extension negotiation, as defined by
+//! [RFC 8308](https://tools.ietf.org/html/rfc8308).
+//!
+//! Both sides announce their support in their key exchange
+//! algorithms (`ext-info-c` and `ext-info-s`), and can then send an
+//! `SSH_MSG_EXT_INFO` message listing their extensions. The server
+//! always sends `server-sig-algs`; other extensions can be added with
+//! the `extensions` field of [`client::Config`](crate::client::Config)
+//! and [`server::Config`](crate::server::Config).
+
+use russh_keys::helpers::map_err;
+use ssh_encoding::{Decode, Encode};
+
+use crate::negotiation::parse_kex_algo_list;
+use crate::{kex, msg, CryptoVec};
+
+/// `server-sig-algs`: signature algorithms accepted by the server.
+pub const SERVER_SIG_ALGS: &str = "server-sig-algs";
+/// `publickey-hostbound@openssh.com`: the server accepts public key
+/// authentication requests bound to its host key.
+pub const PUBLICKEY_HOSTBOUND: &str = "publickey-hostbound@openssh.com";
+/// `no-flow-control`: channel windows may be ignored.
+pub const NO_FLOW_CONTROL: &str = "no-flow-control";
+/// `delay-compression`: compression algorithms to use after
+/// authentication.
+pub const DELAY_COMPRESSION: &str = "delay-compression";
+
+/// A list of extensions, each with a name and a value, in the order
+/// they were inserted or received.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Extensions {
+    list: Vec<(String, Vec<u8>)>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an extension, replacing any extension with the same name.
+    pub fn insert<N: Into<String>, V: Into<Vec<u8>>>(&mut self, name: N, value: V) {
+        let name = name.into();
+        let value = value.into();
+        if let Some(ext) = self.list.iter_mut().find(|(n, _)| *n == name) {
+            ext.1 = value
+        } else {
+            self.list.push((name, value))
+        }
+    }
+
+    /// The value of the extension named `name`.
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.list
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.list.iter().map(|(n, v)| (n.as_str(), v.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    /// Merge the extensions of `other` into this list.
+    pub(crate) fn extend(&mut self, other: &Extensions) {
+        for (name, value) in other.iter() {
+            self.insert(name, value)
+        }
+    }
+
+    /// Parse the contents of an `SSH_MSG_EXT_INFO` message.
+    pub(crate) fn parse(r: &mut &[u8]) -> Result<Self, crate::Error> {
+        let n = map_err!(u32::decode(r))?;
+        let mut extensions = Extensions::new();
+        for _ in 0..n {
+            let name = map_err!(String::decode(r))?;
+            let value = map_err!(Vec::<u8>::decode(r))?;
+            extensions.insert(name, value);
+        }
+        Ok(extensions)
+    }
+
+    /// Write an `SSH_MSG_EXT_INFO` packet with these extensions.
+    pub(crate) fn write_packet(&self, write: &mut CryptoVec) -> Result<(), crate::Error> {
+        push_packet!(write, {
+            msg::EXT_INFO.encode(write)?;
+            (self.list.len() as u32).encode(write)?;
+            for (name, value) in self.list.iter() {
+                name.encode(write)?;
+                value.encode(write)?;
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Whether the peer's KEXINIT message `kex_init` lists `indicator`
+/// (`ext-info-c` or `ext-info-s`), announcing that it accepts
+/// `SSH_MSG_EXT_INFO`.
+pub(crate) fn peer_supports(kex_init: &[u8], indicator: &kex::Name) -> bool {
+    let Some(mut r) = kex_init.get(17..) else {
+        return false;
+    };
+    String::decode(&mut r)
+        .map(|kex| parse_kex_algo_list(&kex).contains(&indicator.as_ref()))
+        .unwrap_or(false)
+}
@@ -0,0 +1,94 @@
+//! The `gssapi-with-mic` authentication method ([RFC 4462, section
+//! 3](https://tools.ietf.org/html/rfc4462#section-3)), used to log in
+//! with Kerberos tickets.
+//!
+//! Russh does not link to a GSSAPI library itself: applications
+//! provide the security contexts, for instance on top of the
+//! `libgssapi` crate, by implementing [`ClientContext`] and
+//! [`ServerContext`].
+//!
+//! On the client, pass a [`ClientContext`] to
+//! [`Handle::authenticate_gssapi_with_mic`](crate::client::Handle::authenticate_gssapi_with_mic).
+//! On the server, return a [`ServerContext`] from
+//! [`Handler::auth_gssapi_context`](crate::server::Handler::auth_gssapi_context),
+//! and decide whether the authenticated principal may log in in
+//! [`Handler::auth_gssapi_with_mic`](crate::server::Handler::auth_gssapi_with_mic).
+
+use ssh_encoding::Encode;
+
+use crate::CryptoVec;
+
+/// The DER encoding of the Kerberos V5 mechanism OID,
+/// 1.2.840.113554.1.2.2.
+pub const KRB5_MECHANISM: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x12, 0x01, 0x02, 0x02,
+];
+
+/// The result of a step of the context establishment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Step {
+    /// The token to send to the other side, if any.
+    pub token: Option<Vec<u8>>,
+    /// Whether the context is established.
+    pub complete: bool,
+}
+
+/// The client side of a security context, as established by
+/// `GSS_Init_sec_context`.
+pub trait ClientContext: Send {
+    /// The mechanisms to offer to the server, as DER-encoded OIDs.
+    fn mechanisms(&self) -> Vec<Vec<u8>> {
+        vec![KRB5_MECHANISM.to_vec()]
+    }
+
+    /// Start establishing the context with the mechanism chosen by the
+    /// server, and return the first token.
+    fn init(&mut self, mechanism: &[u8]) -> Result<Step, crate::Error>;
+
+    /// Continue establishing the context with a token sent by the
+    /// server.
+    fn step(&mut self, token: &[u8]) -> Result<Step, crate::Error>;
+
+    /// Compute a message integrity code of `data` with the
+    /// established context (`GSS_GetMIC`).
+    fn get_mic(&mut self, data: &[u8]) -> Result<Vec<u8>, crate::Error>;
+}
+
+/// The server side of a security context, as established by
+/// `GSS_Accept_sec_context`.
+pub trait ServerContext: Send {
+    /// Continue establishing the context with a token sent by the
+    /// client.
+    fn accept(&mut self, token: &[u8]) -> Result<Step, crate::Error>;
+
+    /// Check the message integrity code sent by the client
+    /// (`GSS_VerifyMIC`).
+    fn verify_mic(&mut self, data: &[u8], mic: &[u8]) -> bool;
+
+    /// The name of the authenticated client, such as `user@REALM`, once
+    /// the context is established.
+    fn source_name(&self) -> Option<String>;
+}
+
+impl std::fmt::Debug for dyn ClientContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("ClientContext")
+    }
+}
+
+impl std::fmt::Debug for dyn ServerContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("ServerContext")
+    }
+}
+
+/// The data covered by the MIC, see section 3.5 of RFC 4462.
+pub(crate) fn mic_data(session_id: &[u8], user: &str) -> Result<CryptoVec, crate::Error> {
+    let mut data = CryptoVec::new();
+    session_id.encode(&mut data)?;
+    data.push(crate::msg::USERAUTH_REQUEST);
+    user.encode(&mut data)?;
+    "ssh-connection".encode(&mut data)?;
+    "gssapi-with-mic".encode(&mut data)?;
+    Ok(data)
+}
@@ -1,12 +1,11 @@
 use byteorder::{BigEndian, ByteOrder};
-use curve25519_dalek::constants::ED25519_BASEPOINT_TABLE;
-use curve25519_dalek::montgomery::MontgomeryPoint;
-use curve25519_dalek::scalar::Scalar;
-use log::debug;
 use ssh_encoding::Encode;
+use zeroize::{Zeroize, Zeroizing};
 
 use super::{compute_keys, KexAlgorithm, KexType};
+use crate::backend::{x25519, x25519_public};
 use crate::kex::encode_mpint;
+use crate::logging::debug;
 use crate::mac::{self};
 use crate::session::Exchange;
 use crate::{cipher, msg, CryptoVec};
@@ -24,8 +23,8 @@ impl KexType for Curve25519KexType {
 
 #[doc(hidden)]
 pub struct Curve25519Kex {
-    local_secret: Option<Scalar>,
-    shared_secret: Option<MontgomeryPoint>,
+    local_secret: Option<[u8; 32]>,
+    shared_secret: Option<Zeroizing<[u8; 32]>>,
 }
 
 impl std::fmt::Debug for Curve25519Kex {
@@ -37,6 +36,12 @@ impl std::fmt::Debug for Curve25519Kex {
     }
 }
 
+impl Drop for Curve25519Kex {
+    fn drop(&mut self) {
+        self.local_secret.zeroize();
+    }
+}
+
 // We used to support curve "NIST P-256" here, but the security of
 // that curve is controversial, see
 // http://safecurves.cr.yp.to/rigid.html
@@ -65,20 +70,17 @@ impl KexAlgorithm for Curve25519Kex {
                 return Err(crate::Error::Inconsistent);
             }
 
-            let mut pubkey = MontgomeryPoint([0; 32]);
             #[allow(clippy::indexing_slicing)] // length checked
-            pubkey.0.clone_from_slice(&payload[5..5 + 32]);
-            pubkey
+            &payload[5..5 + 32]
         };
 
-        let server_secret = Scalar::from_bytes_mod_order(rand::random::<[u8; 32]>());
-        let server_pubkey = (ED25519_BASEPOINT_TABLE * &server_secret).to_montgomery();
+        let server_secret = Zeroizing::new(rand::random::<[u8; 32]>());
+        let server_pubkey = x25519_public(&server_secret)?;
 
         // fill exchange.
         exchange.server_ephemeral.clear();
-        exchange.server_ephemeral.extend(&server_pubkey.0);
-        let shared = server_secret * client_pubkey;
-        self.shared_secret = Some(shared);
+        exchange.server_ephemeral.extend(&server_pubkey);
+        self.shared_secret = Some(x25519(&server_secret, client_pubkey)?);
         Ok(())
     }
 
@@ -88,26 +90,23 @@ impl KexAlgorithm for Curve25519Kex {
         client_ephemeral: &mut CryptoVec,
         buf: &mut CryptoVec,
     ) -> Result<(), crate::Error> {
-        let client_secret = Scalar::from_bytes_mod_order(rand::random::<[u8; 32]>());
-        let client_pubkey = (ED25519_BASEPOINT_TABLE * &client_secret).to_montgomery();
+        let client_secret = rand::random::<[u8; 32]>();
+        let client_pubkey = x25519_public(&client_secret)?;
 
         // fill exchange.
         client_ephemeral.clear();
-        client_ephemeral.extend(&client_pubkey.0);
+        client_ephemeral.extend(&client_pubkey);
 
         msg::KEX_ECDH_INIT.encode(buf)?;
-        client_pubkey.0.encode(buf)?;
+        client_pubkey.encode(buf)?;
 
         self.local_secret = Some(client_secret);
         Ok(())
     }
 
     fn compute_shared_secret(&mut self, remote_pubkey_: &[u8]) -> Result<(), crate::Error> {
-        let local_secret = self.local_secret.take().ok_or(crate::Error::KexInit)?;
-        let mut remote_pubkey = MontgomeryPoint([0; 32]);
-        remote_pubkey.0.clone_from_slice(remote_pubkey_);
-        let shared = local_secret * remote_pubkey;
-        self.shared_secret = Some(shared);
+        let local_secret = Zeroizing::new(self.local_secret.take().ok_or(crate::Error::KexInit)?);
+        self.shared_secret = Some(x25519(&local_secret, remote_pubkey_)?);
         Ok(())
     }
 
@@ -129,7 +128,7 @@ impl KexAlgorithm for Curve25519Kex {
         exchange.server_ephemeral.encode(buffer)?;
 
         if let Some(ref shared) = self.shared_secret {
-            encode_mpint(&shared.0, buffer)?;
+            encode_mpint(&shared[..], buffer)?;
         }
 
         use sha2::Digest;
@@ -151,7 +150,7 @@ impl KexAlgorithm for Curve25519Kex {
         is_server: bool,
     ) -> Result<super::cipher::CipherPair, crate::Error> {
         compute_keys::<sha2::Sha256>(
-            self.shared_secret.as_ref().map(|x| x.0.as_slice()),
+            self.shared_secret.as_ref().map(|x| &x[..]),
             session_id,
             exchange_hash,
             cipher,
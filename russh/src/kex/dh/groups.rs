@@ -99,6 +99,21 @@ impl DH {
         }
     }
 
+    /// A group with a prime that isn't known at compile time, as in
+    /// group exchanges.
+    pub fn from_prime(prime: &[u8], generator: usize) -> Self {
+        let prime_num = BigUint::from_bytes_be(prime);
+        let exp_size = prime_num.bits() / 8;
+        Self {
+            prime_num,
+            generator,
+            exp_size,
+            private_key: BigUint::default(),
+            public_key: BigUint::default(),
+            shared_secret: BigUint::default(),
+        }
+    }
+
     pub fn generate_private_key(&mut self, is_server: bool) -> BigUint {
         let q = (&self.prime_num - &BigUint::from(1u8)) / &BigUint::from(2u8);
         let mut rng = rand::thread_rng();
@@ -1,17 +1,19 @@
 mod groups;
+use std::borrow::Cow;
+use std::convert::TryFrom;
 use std::marker::PhantomData;
 
 use byteorder::{BigEndian, ByteOrder};
 use digest::Digest;
 use groups::DH;
-use log::debug;
 use num_bigint::BigUint;
 use sha1::Sha1;
 use sha2::{Sha256, Sha512};
-use ssh_encoding::Encode;
+use ssh_encoding::{Decode, Encode};
 
 use self::groups::{DhGroup, DH_GROUP1, DH_GROUP14, DH_GROUP16};
 use super::{compute_keys, KexAlgorithm, KexType};
+use crate::logging::debug;
 use crate::session::Exchange;
 use crate::{cipher, mac, msg, CryptoVec};
 
@@ -37,6 +39,22 @@ impl KexType for DhGroup14Sha256KexType {
     }
 }
 
+pub struct DhGexSha1KexType {}
+
+impl KexType for DhGexSha1KexType {
+    fn make(&self) -> Box<dyn KexAlgorithm + Send> {
+        Box::new(DhGexKex::<Sha1>::new()) as Box<dyn KexAlgorithm + Send>
+    }
+}
+
+pub struct DhGexSha256KexType {}
+
+impl KexType for DhGexSha256KexType {
+    fn make(&self) -> Box<dyn KexAlgorithm + Send> {
+        Box::new(DhGexKex::<Sha256>::new()) as Box<dyn KexAlgorithm + Send>
+    }
+}
+
 pub struct DhGroup16Sha512KexType {}
 
 impl KexType for DhGroup16Sha512KexType {
@@ -48,7 +66,7 @@ impl KexType for DhGroup16Sha512KexType {
 #[doc(hidden)]
 pub struct DhGroupKex<D: Digest> {
     dh: DH,
-    shared_secret: Option<Vec<u8>>,
+    shared_secret: Option<CryptoVec>,
     _digest: PhantomData<D>,
 }
 
@@ -84,6 +102,19 @@ fn biguint_to_mpint(biguint: &BigUint) -> Vec<u8> {
     mpint
 }
 
+/// Like [`biguint_to_mpint`], for the shared secret, without leaving
+/// copies of it in memory.
+fn secret_to_mpint(biguint: &BigUint) -> CryptoVec {
+    // Converting the vector to a `CryptoVec` zeroes it.
+    let bytes = CryptoVec::from(biguint.to_bytes_be());
+    let mut mpint = CryptoVec::new();
+    if bytes.first().is_some_and(|b| *b > 0x7f) {
+        mpint.push(0);
+    }
+    mpint.extend(&bytes);
+    mpint
+}
+
 impl<D: Digest> KexAlgorithm for DhGroupKex<D> {
     fn skip_exchange(&self) -> bool {
         false
@@ -133,7 +164,7 @@ impl<D: Digest> KexAlgorithm for DhGroupKex<D> {
         if !self.dh.validate_shared_secret(&shared) {
             return Err(crate::Error::Inconsistent);
         }
-        self.shared_secret = Some(biguint_to_mpint(&shared));
+        self.shared_secret = Some(secret_to_mpint(&shared));
         Ok(())
     }
 
@@ -172,7 +203,7 @@ impl<D: Digest> KexAlgorithm for DhGroupKex<D> {
         if !self.dh.validate_shared_secret(&shared) {
             return Err(crate::Error::Inconsistent);
         }
-        self.shared_secret = Some(biguint_to_mpint(&shared));
+        self.shared_secret = Some(secret_to_mpint(&shared));
         Ok(())
     }
 
@@ -194,7 +225,315 @@ impl<D: Digest> KexAlgorithm for DhGroupKex<D> {
         exchange.server_ephemeral.encode(buffer)?;
 
         if let Some(ref shared) = self.shared_secret {
-            shared.encode(buffer)?;
+            shared[..].encode(buffer)?;
+        }
+
+        let mut hasher = D::new();
+        hasher.update(&buffer);
+
+        let mut res = CryptoVec::new();
+        res.extend(hasher.finalize().as_slice());
+        Ok(res)
+    }
+
+    fn compute_keys(
+        &self,
+        session_id: &CryptoVec,
+        exchange_hash: &CryptoVec,
+        cipher: cipher::Name,
+        remote_to_local_mac: mac::Name,
+        local_to_remote_mac: mac::Name,
+        is_server: bool,
+    ) -> Result<super::cipher::CipherPair, crate::Error> {
+        compute_keys::<D>(
+            self.shared_secret.as_deref(),
+            session_id,
+            exchange_hash,
+            cipher,
+            remote_to_local_mac,
+            local_to_remote_mac,
+            is_server,
+        )
+    }
+}
+
+/// A group offered by servers for `diffie-hellman-group-exchange-*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GexGroup {
+    /// The safe prime, big-endian.
+    pub prime: Cow<'static, [u8]>,
+    pub generator: u32,
+}
+
+/// Smallest and largest group sizes accepted, like OpenSSH.
+const GEX_MIN_BITS: u32 = 2048;
+const GEX_MAX_BITS: u32 = 8192;
+/// Group size requested by clients.
+const GEX_PREFERRED_BITS: u32 = 4096;
+
+impl GexGroup {
+    /// The size of the prime in bits.
+    pub fn bits(&self) -> u32 {
+        BigUint::from_bytes_be(&self.prime).bits() as u32
+    }
+
+    /// The primes of RFC 3526 that are also used by the fixed group
+    /// key exchanges.
+    pub fn defaults() -> Vec<GexGroup> {
+        [&DH_GROUP14, &DH_GROUP16]
+            .iter()
+            .map(|group| GexGroup {
+                prime: Cow::Borrowed(group.prime),
+                generator: group.generator as u32,
+            })
+            .collect()
+    }
+
+    /// Parse groups in the format of OpenSSH's `moduli` file, as
+    /// generated by `ssh-keygen -M generate`. Lines that aren't
+    /// tested safe primes are skipped.
+    pub fn parse_moduli(moduli: &str) -> Vec<GexGroup> {
+        moduli
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let &[_time, typ, _tests, _tries, _size, generator, modulus] = fields.as_slice()
+                else {
+                    return None;
+                };
+                // Type 2 is a safe prime.
+                if typ != "2" {
+                    return None;
+                }
+                let prime = BigUint::parse_bytes(modulus.as_bytes(), 16)?;
+                Some(GexGroup {
+                    prime: Cow::Owned(prime.to_bytes_be()),
+                    generator: generator.parse().ok()?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// `diffie-hellman-group-exchange-*`, where the client requests a
+/// group size and the server picks the group (RFC 4419).
+#[doc(hidden)]
+pub struct DhGexKex<D: Digest> {
+    /// Minimum, preferred and maximum sizes requested.
+    request: (u32, u32, u32),
+    /// The group, as mpints.
+    prime: Vec<u8>,
+    generator: Vec<u8>,
+    dh: Option<DH>,
+    shared_secret: Option<CryptoVec>,
+    _digest: PhantomData<D>,
+}
+
+impl<D: Digest> DhGexKex<D> {
+    fn new() -> Self {
+        DhGexKex {
+            request: (GEX_MIN_BITS, GEX_PREFERRED_BITS, GEX_MAX_BITS),
+            prime: Vec::new(),
+            generator: Vec::new(),
+            dh: None,
+            shared_secret: None,
+            _digest: PhantomData,
+        }
+    }
+
+    fn set_group(&mut self, prime: &BigUint, generator: &BigUint) -> Result<&mut DH, crate::Error> {
+        let g = usize::try_from(generator).map_err(|_| crate::Error::Kex)?;
+        self.prime = biguint_to_mpint(prime);
+        self.generator = biguint_to_mpint(generator);
+        Ok(self.dh.insert(DH::from_prime(&prime.to_bytes_be(), g)))
+    }
+}
+
+impl<D: Digest> std::fmt::Debug for DhGexKex<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Algorithm {{ request: {:?}, local_secret: [hidden], shared_secret: [hidden] }}",
+            self.request
+        )
+    }
+}
+
+impl<D: Digest> KexAlgorithm for DhGexKex<D> {
+    fn skip_exchange(&self) -> bool {
+        false
+    }
+
+    fn init_msg(&self) -> u8 {
+        msg::KEX_DH_GEX_INIT
+    }
+
+    fn reply_msg(&self) -> u8 {
+        msg::KEX_DH_GEX_REPLY
+    }
+
+    fn server_negotiate(
+        &mut self,
+        payload: &[u8],
+        groups: &[GexGroup],
+        buf: &mut CryptoVec,
+    ) -> Result<bool, crate::Error> {
+        let Some((&msg::KEX_DH_GEX_REQUEST, mut r)) = payload.split_first() else {
+            return Ok(false);
+        };
+        if self.dh.is_some() {
+            return Err(crate::Error::Inconsistent);
+        }
+        let min = u32::decode(&mut r)?;
+        let n = u32::decode(&mut r)?;
+        let max = u32::decode(&mut r)?;
+        if min > n || n > max {
+            return Err(crate::Error::Kex);
+        }
+        self.request = (min, n, max);
+        let group = groups
+            .iter()
+            .filter(|g| (min..=max).contains(&g.bits()))
+            .min_by_key(|g| (i64::from(g.bits()) - i64::from(n)).abs())
+            .ok_or_else(|| {
+                debug!("no group between {} and {} bits", min, max);
+                crate::Error::Kex
+            })?;
+        debug!("group exchange: {} bits", group.bits());
+        let prime = BigUint::from_bytes_be(&group.prime);
+        self.set_group(&prime, &BigUint::from(group.generator))?;
+
+        msg::KEX_DH_GEX_GROUP.encode(buf)?;
+        self.prime.encode(buf)?;
+        self.generator.encode(buf)?;
+        Ok(true)
+    }
+
+    #[doc(hidden)]
+    fn server_dh(&mut self, exchange: &mut Exchange, payload: &[u8]) -> Result<(), crate::Error> {
+        let Some((&msg::KEX_DH_GEX_INIT, mut r)) = payload.split_first() else {
+            return Err(crate::Error::Inconsistent);
+        };
+        let client_pubkey = Vec::<u8>::decode(&mut r)?;
+        let dh = self.dh.as_mut().ok_or(crate::Error::Inconsistent)?;
+
+        dh.generate_private_key(true);
+        let server_pubkey = &dh.generate_public_key();
+        if !dh.validate_public_key(server_pubkey) {
+            return Err(crate::Error::Inconsistent);
+        }
+        exchange.server_ephemeral.clear();
+        exchange
+            .server_ephemeral
+            .extend(&biguint_to_mpint(server_pubkey));
+
+        let decoded_client_pubkey = DH::decode_public_key(&client_pubkey);
+        if !dh.validate_public_key(&decoded_client_pubkey) {
+            return Err(crate::Error::Inconsistent);
+        }
+        let shared = dh.compute_shared_secret(decoded_client_pubkey);
+        if !dh.validate_shared_secret(&shared) {
+            return Err(crate::Error::Inconsistent);
+        }
+        self.shared_secret = Some(secret_to_mpint(&shared));
+        Ok(())
+    }
+
+    #[doc(hidden)]
+    fn client_dh(
+        &mut self,
+        client_ephemeral: &mut CryptoVec,
+        buf: &mut CryptoVec,
+    ) -> Result<(), crate::Error> {
+        // The public key is only sent once the server has chosen the
+        // group, see `client_negotiate`.
+        client_ephemeral.clear();
+        let (min, n, max) = self.request;
+        msg::KEX_DH_GEX_REQUEST.encode(buf)?;
+        min.encode(buf)?;
+        n.encode(buf)?;
+        max.encode(buf)?;
+        Ok(())
+    }
+
+    fn client_negotiate(
+        &mut self,
+        payload: &[u8],
+        client_ephemeral: &mut CryptoVec,
+        buf: &mut CryptoVec,
+    ) -> Result<bool, crate::Error> {
+        // The group message has the same number as the reply of the
+        // fixed group exchanges, and is only expected once.
+        if self.dh.is_some() {
+            return Ok(false);
+        }
+        let Some((&msg::KEX_DH_GEX_GROUP, mut r)) = payload.split_first() else {
+            return Ok(false);
+        };
+        let prime = BigUint::from_bytes_be(&Vec::<u8>::decode(&mut r)?);
+        let generator = BigUint::from_bytes_be(&Vec::<u8>::decode(&mut r)?);
+        let bits = prime.bits() as u32;
+        let (min, _, max) = self.request;
+        if bits < min || bits > max {
+            debug!("server chose a {} bits group", bits);
+            return Err(crate::Error::Kex);
+        }
+        let dh = self.set_group(&prime, &generator)?;
+
+        dh.generate_private_key(false);
+        let client_pubkey = &dh.generate_public_key();
+        if !dh.validate_public_key(client_pubkey) {
+            return Err(crate::Error::Inconsistent);
+        }
+        let encoded_pubkey = biguint_to_mpint(client_pubkey);
+        client_ephemeral.clear();
+        client_ephemeral.extend(&encoded_pubkey);
+
+        msg::KEX_DH_GEX_INIT.encode(buf)?;
+        encoded_pubkey.encode(buf)?;
+        Ok(true)
+    }
+
+    fn compute_shared_secret(&mut self, remote_pubkey_: &[u8]) -> Result<(), crate::Error> {
+        let dh = self.dh.as_mut().ok_or(crate::Error::KexInit)?;
+        let remote_pubkey = DH::decode_public_key(remote_pubkey_);
+        if !dh.validate_public_key(&remote_pubkey) {
+            return Err(crate::Error::Inconsistent);
+        }
+        let shared = dh.compute_shared_secret(remote_pubkey);
+        if !dh.validate_shared_secret(&shared) {
+            return Err(crate::Error::Inconsistent);
+        }
+        self.shared_secret = Some(secret_to_mpint(&shared));
+        Ok(())
+    }
+
+    fn compute_exchange_hash(
+        &self,
+        key: &CryptoVec,
+        exchange: &Exchange,
+        buffer: &mut CryptoVec,
+    ) -> Result<CryptoVec, crate::Error> {
+        // See section 3 of RFC 4419.
+        buffer.clear();
+        exchange.client_id.encode(buffer)?;
+        exchange.server_id.encode(buffer)?;
+        exchange.client_kex_init.encode(buffer)?;
+        exchange.server_kex_init.encode(buffer)?;
+
+        buffer.extend(key);
+        let (min, n, max) = self.request;
+        min.encode(buffer)?;
+        n.encode(buffer)?;
+        max.encode(buffer)?;
+        self.prime.encode(buffer)?;
+        self.generator.encode(buffer)?;
+        exchange.client_ephemeral.encode(buffer)?;
+        exchange.server_ephemeral.encode(buffer)?;
+
+        if let Some(ref shared) = self.shared_secret {
+            shared[..].encode(buffer)?;
         }
 
         let mut hasher = D::new();
@@ -225,3 +564,64 @@ impl<D: Digest> KexAlgorithm for DhGroupKex<D> {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    #[test]
+    fn test_dh_gex() {
+        let mut client = DhGexKex::<Sha256>::new();
+        let mut server = DhGexKex::<Sha256>::new();
+        let mut exchange = Exchange::new();
+
+        let mut request = CryptoVec::new();
+        client
+            .client_dh(&mut exchange.client_ephemeral, &mut request)
+            .unwrap();
+        let mut group = CryptoVec::new();
+        assert!(server
+            .server_negotiate(&request, &GexGroup::defaults(), &mut group)
+            .unwrap());
+        // The 4096 bits group is closest to what the client prefers.
+        assert_eq!(server.prime.len(), 4096 / 8 + 1);
+
+        let mut init = CryptoVec::new();
+        assert!(client
+            .client_negotiate(&group, &mut exchange.client_ephemeral, &mut init)
+            .unwrap());
+        server.server_dh(&mut exchange, &init).unwrap();
+        client
+            .compute_shared_secret(&exchange.server_ephemeral)
+            .unwrap();
+        assert!(client.shared_secret.is_some());
+        assert_eq!(
+            client.shared_secret.as_deref(),
+            server.shared_secret.as_deref()
+        );
+
+        let key = CryptoVec::new();
+        let mut buffer = CryptoVec::new();
+        let client_hash = client
+            .compute_exchange_hash(&key, &exchange, &mut buffer)
+            .unwrap();
+        let server_hash = server
+            .compute_exchange_hash(&key, &exchange, &mut buffer)
+            .unwrap();
+        assert_eq!(&client_hash[..], &server_hash[..]);
+    }
+
+    #[test]
+    fn test_parse_moduli() {
+        let moduli = "# comment\n\
+            20230813000000 2 6 100 2047 2 FFFFFFFFFFFFFFFFC90FDAA2\n\
+            20230813000000 4 6 100 2047 2 FFFF\n";
+        let groups = GexGroup::parse_moduli(moduli);
+        assert_eq!(groups.len(), 1);
+        let group = groups.first().unwrap();
+        assert_eq!(group.generator, 2);
+        assert_eq!(group.prime.first(), Some(&0xff));
+    }
+}
@@ -6,7 +6,6 @@ use elliptic_curve::ecdh::{EphemeralSecret, SharedSecret};
 use elliptic_curve::point::PointCompression;
 use elliptic_curve::sec1::{FromEncodedPoint, ModulusSize, ToEncodedPoint};
 use elliptic_curve::{AffinePoint, Curve, CurveArithmetic, FieldBytesSize};
-use log::debug;
 use p256::NistP256;
 use p384::NistP384;
 use p521::NistP521;
@@ -15,6 +14,7 @@ use ssh_encoding::Encode;
 
 use super::encode_mpint;
 use crate::kex::{compute_keys, KexAlgorithm, KexType};
+use crate::logging::debug;
 use crate::mac::{self};
 use crate::session::Exchange;
 use crate::{cipher, msg, CryptoVec};
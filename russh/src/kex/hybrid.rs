@@ -0,0 +1,348 @@
+//! Hybrid key exchanges combining a post-quantum KEM with X25519,
+//! `mlkem768x25519-sha256` and `sntrup761x25519-sha512`.
+//!
+//! The client sends its KEM public key followed by its X25519 public
+//! key, and the server answers with a KEM ciphertext followed by its
+//! own X25519 public key. The shared secret is the hash of the KEM
+//! secret and of the X25519 secret, encoded as a string rather than
+//! an mpint.
+
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+
+use digest::Digest;
+use ssh_encoding::{Decode, Encode};
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, Zeroizing};
+
+use super::{compute_keys_encoded, KexAlgorithm, KexType};
+use crate::backend::x25519_public;
+use crate::logging::debug;
+use crate::mac::{self};
+use crate::session::Exchange;
+use crate::{cipher, msg, CryptoVec};
+
+const X25519_LEN: usize = 32;
+
+/// A key encapsulation mechanism.
+pub trait Kem {
+    const PUBLIC_KEY_LEN: usize;
+    const CIPHERTEXT_LEN: usize;
+    type SecretKey: Send;
+
+    /// Generate a key pair, returning the encoded public key.
+    fn generate() -> (Self::SecretKey, Vec<u8>);
+
+    /// Encapsulate a new secret to `public_key`, returning the
+    /// ciphertext and the secret.
+    fn encapsulate(public_key: &[u8]) -> Result<(Vec<u8>, CryptoVec), crate::Error>;
+
+    fn decapsulate(
+        secret_key: &Self::SecretKey,
+        ciphertext: &[u8],
+    ) -> Result<CryptoVec, crate::Error>;
+}
+
+pub struct MlKem768 {}
+
+impl Kem for MlKem768 {
+    const PUBLIC_KEY_LEN: usize = 1184;
+    const CIPHERTEXT_LEN: usize = 1088;
+    type SecretKey = <ml_kem::MlKem768 as ml_kem::KemCore>::DecapsulationKey;
+
+    fn generate() -> (Self::SecretKey, Vec<u8>) {
+        use ml_kem::{EncodedSizeUser, KemCore};
+        let (dk, ek) = ml_kem::MlKem768::generate(&mut rand::thread_rng());
+        (dk, ek.as_bytes().to_vec())
+    }
+
+    fn encapsulate(public_key: &[u8]) -> Result<(Vec<u8>, CryptoVec), crate::Error> {
+        use ml_kem::kem::Encapsulate;
+        use ml_kem::{EncodedSizeUser, KemCore};
+        let encoded = ml_kem::Encoded::<<ml_kem::MlKem768 as KemCore>::EncapsulationKey>::try_from(
+            public_key,
+        )
+        .map_err(|_| crate::Error::Kex)?;
+        let ek = <ml_kem::MlKem768 as KemCore>::EncapsulationKey::from_bytes(&encoded);
+        let (ct, ss) = ek
+            .encapsulate(&mut rand::thread_rng())
+            .map_err(|_| crate::Error::Kex)?;
+        Ok((ct.to_vec(), CryptoVec::from_slice(&ss)))
+    }
+
+    fn decapsulate(
+        secret_key: &Self::SecretKey,
+        ciphertext: &[u8],
+    ) -> Result<CryptoVec, crate::Error> {
+        use ml_kem::kem::Decapsulate;
+        let ct = ml_kem::Ciphertext::<ml_kem::MlKem768>::try_from(ciphertext)
+            .map_err(|_| crate::Error::Kex)?;
+        let ss = secret_key.decapsulate(&ct).map_err(|_| crate::Error::Kex)?;
+        Ok(CryptoVec::from_slice(&ss))
+    }
+}
+
+#[cfg(feature = "sntrup761")]
+pub struct Sntrup761 {}
+
+#[cfg(feature = "sntrup761")]
+impl Kem for Sntrup761 {
+    const PUBLIC_KEY_LEN: usize = 1158;
+    const CIPHERTEXT_LEN: usize = 1039;
+    type SecretKey = pqcrypto_ntruprime::sntrup761::SecretKey;
+
+    fn generate() -> (Self::SecretKey, Vec<u8>) {
+        use pqcrypto_traits::kem::PublicKey;
+        let (pk, sk) = pqcrypto_ntruprime::sntrup761::keypair();
+        (sk, pk.as_bytes().to_vec())
+    }
+
+    fn encapsulate(public_key: &[u8]) -> Result<(Vec<u8>, CryptoVec), crate::Error> {
+        use pqcrypto_traits::kem::{Ciphertext, PublicKey, SharedSecret};
+        let pk = pqcrypto_ntruprime::sntrup761::PublicKey::from_bytes(public_key)
+            .map_err(|_| crate::Error::Kex)?;
+        let (ss, ct) = pqcrypto_ntruprime::sntrup761::encapsulate(&pk);
+        Ok((ct.as_bytes().to_vec(), CryptoVec::from_slice(ss.as_bytes())))
+    }
+
+    fn decapsulate(
+        secret_key: &Self::SecretKey,
+        ciphertext: &[u8],
+    ) -> Result<CryptoVec, crate::Error> {
+        use pqcrypto_traits::kem::{Ciphertext, SharedSecret};
+        let ct = pqcrypto_ntruprime::sntrup761::Ciphertext::from_bytes(ciphertext)
+            .map_err(|_| crate::Error::Kex)?;
+        let ss = pqcrypto_ntruprime::sntrup761::decapsulate(&ct, secret_key);
+        Ok(CryptoVec::from_slice(ss.as_bytes()))
+    }
+}
+
+pub struct HybridKexType<K: Kem, D: Digest> {
+    _marker: PhantomData<(K, D)>,
+}
+
+impl<K: Kem, D: Digest> HybridKexType<K, D> {
+    pub const fn new() -> Self {
+        HybridKexType {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K: Kem + 'static, D: Digest + 'static> KexType for HybridKexType<K, D> {
+    fn make(&self) -> Box<dyn KexAlgorithm + Send> {
+        Box::new(HybridKex::<K, D> {
+            kem_secret: None,
+            x25519_secret: None,
+            shared_secret: None,
+            _marker: PhantomData,
+        }) as Box<dyn KexAlgorithm + Send>
+    }
+}
+
+#[doc(hidden)]
+pub struct HybridKex<K: Kem, D: Digest> {
+    kem_secret: Option<K::SecretKey>,
+    x25519_secret: Option<[u8; 32]>,
+    shared_secret: Option<CryptoVec>,
+    _marker: PhantomData<fn() -> D>,
+}
+
+impl<K: Kem, D: Digest> std::fmt::Debug for HybridKex<K, D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Algorithm {{ local_secret: [hidden], shared_secret: [hidden] }}",
+        )
+    }
+}
+
+/// X25519 with the check of OpenSSH against low order points.
+fn x25519(secret: &[u8; 32], public: &[u8]) -> Result<Zeroizing<[u8; 32]>, crate::Error> {
+    let shared = crate::backend::x25519(secret, public)?;
+    if bool::from(shared.ct_eq(&[0; 32])) {
+        return Err(crate::Error::Kex);
+    }
+    Ok(shared)
+}
+
+impl<K: Kem, D: Digest> Drop for HybridKex<K, D> {
+    fn drop(&mut self) {
+        self.x25519_secret.zeroize();
+    }
+}
+
+impl<K: Kem, D: Digest> HybridKex<K, D> {
+    fn combine(&mut self, kem_shared: &[u8], x25519_shared: &[u8]) {
+        let mut hasher = D::new();
+        hasher.update(kem_shared);
+        hasher.update(x25519_shared);
+        self.shared_secret = Some(CryptoVec::from_slice(&hasher.finalize()));
+    }
+}
+
+impl<K: Kem, D: Digest> KexAlgorithm for HybridKex<K, D> {
+    fn skip_exchange(&self) -> bool {
+        false
+    }
+
+    #[doc(hidden)]
+    fn server_dh(&mut self, exchange: &mut Exchange, payload: &[u8]) -> Result<(), crate::Error> {
+        debug!("server_dh");
+        let Some((&msg::KEX_ECDH_INIT, mut r)) = payload.split_first() else {
+            return Err(crate::Error::Inconsistent);
+        };
+        let client_init = Vec::<u8>::decode(&mut r)?;
+        if client_init.len() != K::PUBLIC_KEY_LEN + X25519_LEN {
+            return Err(crate::Error::Kex);
+        }
+        let (kem_public, client_x25519) = client_init.split_at(K::PUBLIC_KEY_LEN);
+
+        let (ciphertext, kem_shared) = K::encapsulate(kem_public)?;
+        let server_secret = Zeroizing::new(rand::random::<[u8; 32]>());
+        let server_public = x25519_public(&server_secret)?;
+        let x25519_shared = x25519(&server_secret, client_x25519)?;
+
+        exchange.server_ephemeral.clear();
+        exchange.server_ephemeral.extend(&ciphertext);
+        exchange.server_ephemeral.extend(&server_public);
+        self.combine(&kem_shared, &*x25519_shared);
+        Ok(())
+    }
+
+    #[doc(hidden)]
+    fn client_dh(
+        &mut self,
+        client_ephemeral: &mut CryptoVec,
+        buf: &mut CryptoVec,
+    ) -> Result<(), crate::Error> {
+        let (kem_secret, kem_public) = K::generate();
+        let client_secret = rand::random::<[u8; 32]>();
+        let client_public = x25519_public(&client_secret)?;
+
+        client_ephemeral.clear();
+        client_ephemeral.extend(&kem_public);
+        client_ephemeral.extend(&client_public);
+
+        msg::KEX_ECDH_INIT.encode(buf)?;
+        let client_init: &[u8] = client_ephemeral;
+        client_init.encode(buf)?;
+
+        self.kem_secret = Some(kem_secret);
+        self.x25519_secret = Some(client_secret);
+        Ok(())
+    }
+
+    fn compute_shared_secret(&mut self, remote_pubkey_: &[u8]) -> Result<(), crate::Error> {
+        let kem_secret = self.kem_secret.take().ok_or(crate::Error::KexInit)?;
+        let x25519_secret = Zeroizing::new(self.x25519_secret.take().ok_or(crate::Error::KexInit)?);
+        if remote_pubkey_.len() != K::CIPHERTEXT_LEN + X25519_LEN {
+            return Err(crate::Error::Kex);
+        }
+        let (ciphertext, x25519_public) = remote_pubkey_.split_at(K::CIPHERTEXT_LEN);
+        let kem_shared = K::decapsulate(&kem_secret, ciphertext)?;
+        let x25519_shared = x25519(&x25519_secret, x25519_public)?;
+        self.combine(&kem_shared, &*x25519_shared);
+        Ok(())
+    }
+
+    fn compute_exchange_hash(
+        &self,
+        key: &CryptoVec,
+        exchange: &Exchange,
+        buffer: &mut CryptoVec,
+    ) -> Result<CryptoVec, crate::Error> {
+        buffer.clear();
+        exchange.client_id.encode(buffer)?;
+        exchange.server_id.encode(buffer)?;
+        exchange.client_kex_init.encode(buffer)?;
+        exchange.server_kex_init.encode(buffer)?;
+
+        buffer.extend(key);
+        exchange.client_ephemeral.encode(buffer)?;
+        exchange.server_ephemeral.encode(buffer)?;
+
+        if let Some(ref shared) = self.shared_secret {
+            let shared: &[u8] = shared;
+            shared.encode(buffer)?;
+        }
+
+        let mut hasher = D::new();
+        hasher.update(&buffer);
+
+        let mut res = CryptoVec::new();
+        res.extend(hasher.finalize().as_slice());
+        Ok(res)
+    }
+
+    fn compute_keys(
+        &self,
+        session_id: &CryptoVec,
+        exchange_hash: &CryptoVec,
+        cipher: cipher::Name,
+        remote_to_local_mac: mac::Name,
+        local_to_remote_mac: mac::Name,
+        is_server: bool,
+    ) -> Result<super::cipher::CipherPair, crate::Error> {
+        let mut shared = CryptoVec::new();
+        if let Some(ref secret) = self.shared_secret {
+            let secret: &[u8] = secret;
+            secret.encode(&mut shared)?;
+        }
+        compute_keys_encoded::<D>(
+            self.shared_secret.as_ref().map(|_| &shared[..]),
+            session_id,
+            exchange_hash,
+            cipher,
+            remote_to_local_mac,
+            local_to_remote_mac,
+            is_server,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    fn roundtrip<K: Kem + 'static, D: Digest + 'static>() {
+        let kex_type = HybridKexType::<K, D>::new();
+        let mut client = kex_type.make();
+        let mut server = kex_type.make();
+
+        let mut client_ephemeral = CryptoVec::new();
+        let mut init = CryptoVec::new();
+        client.client_dh(&mut client_ephemeral, &mut init).unwrap();
+        assert_eq!(client_ephemeral.len(), K::PUBLIC_KEY_LEN + X25519_LEN);
+
+        let mut exchange = Exchange::new();
+        exchange.client_ephemeral.extend(&client_ephemeral);
+        server.server_dh(&mut exchange, &init).unwrap();
+        client
+            .compute_shared_secret(&exchange.server_ephemeral)
+            .unwrap();
+
+        let key = CryptoVec::new();
+        let mut buffer = CryptoVec::new();
+        let client_hash = client
+            .compute_exchange_hash(&key, &exchange, &mut buffer)
+            .unwrap();
+        let server_hash = server
+            .compute_exchange_hash(&key, &exchange, &mut buffer)
+            .unwrap();
+        assert_eq!(&client_hash[..], &server_hash[..]);
+    }
+
+    #[test]
+    fn test_mlkem768x25519() {
+        roundtrip::<MlKem768, sha2::Sha256>();
+    }
+
+    #[cfg(feature = "sntrup761")]
+    #[test]
+    fn test_sntrup761x25519() {
+        roundtrip::<Sntrup761, sha2::Sha512>();
+    }
+}
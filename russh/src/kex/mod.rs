@@ -18,27 +18,30 @@
 mod curve25519;
 mod dh;
 mod ecdh_nistp;
+mod hybrid;
 mod none;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt::Debug;
-use std::ops::DerefMut;
 
 use curve25519::Curve25519KexType;
 use delegate::delegate;
+pub use dh::GexGroup;
 use dh::{
-    DhGroup14Sha1KexType, DhGroup14Sha256KexType, DhGroup16Sha512KexType, DhGroup1Sha1KexType,
+    DhGexSha1KexType, DhGexSha256KexType, DhGroup14Sha1KexType, DhGroup14Sha256KexType,
+    DhGroup16Sha512KexType, DhGroup1Sha1KexType,
 };
 use digest::Digest;
 use ecdh_nistp::{EcdhNistP256KexType, EcdhNistP384KexType, EcdhNistP521KexType};
+use hybrid::{HybridKexType, MlKem768};
 use once_cell::sync::Lazy;
 use ssh_encoding::{Encode, Writer};
 
 use crate::cipher::CIPHERS;
 use crate::mac::{self, MACS};
 use crate::session::Exchange;
-use crate::{cipher, CryptoVec};
+use crate::{cipher, msg, CryptoVec};
 
 pub(crate) trait KexType {
     fn make(&self) -> Box<dyn KexAlgorithm + Send>;
@@ -64,6 +67,41 @@ pub(crate) trait KexAlgorithm {
 
     fn compute_shared_secret(&mut self, remote_pubkey_: &[u8]) -> Result<(), crate::Error>;
 
+    /// The message numbers of the init and reply messages, which are
+    /// different for group exchanges.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    fn init_msg(&self) -> u8 {
+        msg::KEX_ECDH_INIT
+    }
+
+    fn reply_msg(&self) -> u8 {
+        msg::KEX_ECDH_REPLY
+    }
+
+    /// For exchanges with an extra round trip, handle a message of the
+    /// server sent before the reply, and write the answer to `buf`.
+    /// Returns `false` if `payload` isn't such a message.
+    fn client_negotiate(
+        &mut self,
+        _payload: &[u8],
+        _client_ephemeral: &mut CryptoVec,
+        _buf: &mut CryptoVec,
+    ) -> Result<bool, crate::Error> {
+        Ok(false)
+    }
+
+    /// Server side of [`KexAlgorithm::client_negotiate`], for messages
+    /// of the client sent before the init message.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    fn server_negotiate(
+        &mut self,
+        _payload: &[u8],
+        _groups: &[GexGroup],
+        _buf: &mut CryptoVec,
+    ) -> Result<bool, crate::Error> {
+        Ok(false)
+    }
+
     fn compute_exchange_hash(
         &self,
         key: &CryptoVec,
@@ -104,6 +142,14 @@ impl TryFrom<&str> for Name {
     }
 }
 
+/// `mlkem768x25519-sha256`
+pub const MLKEM768X25519_SHA256: Name = Name("mlkem768x25519-sha256");
+/// `sntrup761x25519-sha512`
+#[cfg(feature = "sntrup761")]
+pub const SNTRUP761X25519_SHA512: Name = Name("sntrup761x25519-sha512");
+/// `sntrup761x25519-sha512@openssh.com`
+#[cfg(feature = "sntrup761")]
+pub const SNTRUP761X25519_SHA512_OPENSSH: Name = Name("sntrup761x25519-sha512@openssh.com");
 /// `curve25519-sha256`
 pub const CURVE25519: Name = Name("curve25519-sha256");
 /// `curve25519-sha256@libssh.org`
@@ -116,6 +162,10 @@ pub const DH_G14_SHA1: Name = Name("diffie-hellman-group14-sha1");
 pub const DH_G14_SHA256: Name = Name("diffie-hellman-group14-sha256");
 /// `diffie-hellman-group16-sha512`
 pub const DH_G16_SHA512: Name = Name("diffie-hellman-group16-sha512");
+/// `diffie-hellman-group-exchange-sha1`
+pub const DH_GEX_SHA1: Name = Name("diffie-hellman-group-exchange-sha1");
+/// `diffie-hellman-group-exchange-sha256`
+pub const DH_GEX_SHA256: Name = Name("diffie-hellman-group-exchange-sha256");
 /// `ecdh-sha2-nistp256`
 pub const ECDH_SHA2_NISTP256: Name = Name("ecdh-sha2-nistp256");
 /// `ecdh-sha2-nistp384`
@@ -138,18 +188,31 @@ const _DH_G1_SHA1: DhGroup1Sha1KexType = DhGroup1Sha1KexType {};
 const _DH_G14_SHA1: DhGroup14Sha1KexType = DhGroup14Sha1KexType {};
 const _DH_G14_SHA256: DhGroup14Sha256KexType = DhGroup14Sha256KexType {};
 const _DH_G16_SHA512: DhGroup16Sha512KexType = DhGroup16Sha512KexType {};
+const _DH_GEX_SHA1: DhGexSha1KexType = DhGexSha1KexType {};
+const _DH_GEX_SHA256: DhGexSha256KexType = DhGexSha256KexType {};
 const _ECDH_SHA2_NISTP256: EcdhNistP256KexType = EcdhNistP256KexType {};
 const _ECDH_SHA2_NISTP384: EcdhNistP384KexType = EcdhNistP384KexType {};
 const _ECDH_SHA2_NISTP521: EcdhNistP521KexType = EcdhNistP521KexType {};
 const _NONE: none::NoneKexType = none::NoneKexType {};
+const _MLKEM768X25519_SHA256: HybridKexType<MlKem768, sha2::Sha256> = HybridKexType::new();
+#[cfg(feature = "sntrup761")]
+const _SNTRUP761X25519_SHA512: HybridKexType<hybrid::Sntrup761, sha2::Sha512> =
+    HybridKexType::new();
 
 pub const ALL_KEX_ALGORITHMS: &[&Name] = &[
+    &MLKEM768X25519_SHA256,
+    #[cfg(feature = "sntrup761")]
+    &SNTRUP761X25519_SHA512,
+    #[cfg(feature = "sntrup761")]
+    &SNTRUP761X25519_SHA512_OPENSSH,
     &CURVE25519,
     &CURVE25519_PRE_RFC_8731,
     &DH_G1_SHA1,
     &DH_G14_SHA1,
     &DH_G14_SHA256,
     &DH_G16_SHA512,
+    &DH_GEX_SHA1,
+    &DH_GEX_SHA256,
     &ECDH_SHA2_NISTP256,
     &ECDH_SHA2_NISTP384,
     &ECDH_SHA2_NISTP521,
@@ -159,12 +222,19 @@ pub const ALL_KEX_ALGORITHMS: &[&Name] = &[
 pub(crate) static KEXES: Lazy<HashMap<&'static Name, &(dyn KexType + Send + Sync)>> =
     Lazy::new(|| {
         let mut h: HashMap<&'static Name, &(dyn KexType + Send + Sync)> = HashMap::new();
+        h.insert(&MLKEM768X25519_SHA256, &_MLKEM768X25519_SHA256);
+        #[cfg(feature = "sntrup761")]
+        h.insert(&SNTRUP761X25519_SHA512, &_SNTRUP761X25519_SHA512);
+        #[cfg(feature = "sntrup761")]
+        h.insert(&SNTRUP761X25519_SHA512_OPENSSH, &_SNTRUP761X25519_SHA512);
         h.insert(&CURVE25519, &_CURVE25519);
         h.insert(&CURVE25519_PRE_RFC_8731, &_CURVE25519);
         h.insert(&DH_G16_SHA512, &_DH_G16_SHA512);
         h.insert(&DH_G14_SHA256, &_DH_G14_SHA256);
         h.insert(&DH_G14_SHA1, &_DH_G14_SHA1);
         h.insert(&DH_G1_SHA1, &_DH_G1_SHA1);
+        h.insert(&DH_GEX_SHA1, &_DH_GEX_SHA1);
+        h.insert(&DH_GEX_SHA256, &_DH_GEX_SHA256);
         h.insert(&ECDH_SHA2_NISTP256, &_ECDH_SHA2_NISTP256);
         h.insert(&ECDH_SHA2_NISTP384, &_ECDH_SHA2_NISTP384);
         h.insert(&ECDH_SHA2_NISTP521, &_ECDH_SHA2_NISTP521);
@@ -188,6 +258,32 @@ pub(crate) fn compute_keys<D: Digest>(
     remote_to_local_mac: mac::Name,
     local_to_remote_mac: mac::Name,
     is_server: bool,
+) -> Result<super::cipher::CipherPair, crate::Error> {
+    let mut encoded = CryptoVec::new();
+    if let Some(shared) = shared_secret {
+        encode_mpint(shared, &mut encoded)?;
+    }
+    compute_keys_encoded::<D>(
+        shared_secret.map(|_| &encoded[..]),
+        session_id,
+        exchange_hash,
+        cipher,
+        remote_to_local_mac,
+        local_to_remote_mac,
+        is_server,
+    )
+}
+
+/// Like [`compute_keys`], with the shared secret already encoded, for
+/// key exchanges that don't encode it as an mpint.
+pub(crate) fn compute_keys_encoded<D: Digest>(
+    shared_secret: Option<&[u8]>,
+    session_id: &CryptoVec,
+    exchange_hash: &CryptoVec,
+    cipher: cipher::Name,
+    remote_to_local_mac: mac::Name,
+    local_to_remote_mac: mac::Name,
+    is_server: bool,
 ) -> Result<super::cipher::CipherPair, crate::Error> {
     let cipher = CIPHERS.get(&cipher).ok_or(crate::Error::UnknownAlgo)?;
     let remote_to_local_mac = MACS
@@ -208,7 +304,7 @@ pub(crate) fn compute_keys<D: Digest>(
                         key.clear();
 
                         if let Some(shared) = shared_secret {
-                            encode_mpint(shared, buffer.deref_mut())?;
+                            buffer.extend(shared);
                         }
 
                         buffer.extend(exchange_hash.as_ref());
@@ -225,7 +321,7 @@ pub(crate) fn compute_keys<D: Digest>(
                             // extend.
                             buffer.clear();
                             if let Some(shared) = shared_secret {
-                                encode_mpint(shared, buffer.deref_mut())?;
+                                buffer.extend(shared);
                             }
                             buffer.extend(exchange_hash.as_ref());
                             buffer.extend(key);
@@ -37,6 +37,8 @@
 //! `Stream::tcp_connect` or `Stream::proxy_command` methods of that
 //! crate. That crate is a very lightweight layer above Russh, only
 //! implementing for external commands the traits used for sockets.
+//! With the `russh-config` feature, `client::connect_with_config`
+//! also takes care of known_hosts checking and identity files.
 //!
 //! # The SSH protocol
 //!
@@ -92,17 +94,24 @@
 use std::convert::TryFrom;
 use std::fmt::{Debug, Display, Formatter};
 
-use log::debug;
 use parsing::ChannelOpenConfirmation;
-pub use russh_cryptovec::CryptoVec;
+pub use russh_cryptovec::{CryptoVec, SecretBuffer};
+use russh_keys::map_err;
 use ssh_encoding::{Decode, Encode};
 use thiserror::Error;
 
+use crate::logging::debug;
+
 #[cfg(test)]
 mod tests;
 
+mod logging;
+
 mod auth;
 
+mod backend;
+pub use backend::{crypto_backend, CryptoBackend};
+
 mod cert;
 /// Cipher names
 pub mod cipher;
@@ -121,11 +130,14 @@ mod negotiation;
 mod ssh_read;
 mod sshbuffer;
 
-pub use negotiation::Preferred;
+pub use negotiation::{AlgorithmLists, Negotiated, Preferred};
+
+mod policy;
+pub use policy::AlgorithmPolicy;
 
 mod pty;
 
-pub use pty::Pty;
+pub use pty::{Pty, PtyModes, TerminalSize};
 pub use sshbuffer::SshId;
 
 macro_rules! push_packet {
@@ -144,10 +156,21 @@ macro_rules! push_packet {
 }
 
 mod channels;
-pub use channels::{Channel, ChannelMsg, ChannelStream};
+pub use channels::{
+    Channel, ChannelMsg, ChannelStderr, ChannelStream, CommandEvent, CommandOutput, CommandStream,
+    ExitSignal, ExtendedDataType, WindowResizer,
+};
+
+pub mod extensions;
+
+/// Tracing of the messages of a connection.
+pub mod observer;
+pub use extensions::Extensions;
 
 mod parsing;
 mod session;
+mod throttle;
+pub use throttle::RateLimit;
 
 /// Server side of this library.
 #[cfg(not(target_arch = "wasm32"))]
@@ -156,7 +179,29 @@ pub mod server;
 /// Client side of this library.
 pub mod client;
 
-#[derive(Debug)]
+/// The SFTP file transfer protocol.
+#[cfg(not(target_arch = "wasm32"))]
+pub mod sftp;
+
+pub mod tun;
+
+/// NETCONF over SSH.
+pub mod netconf;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod transport;
+
+#[cfg(all(feature = "websocket", not(target_arch = "wasm32")))]
+pub mod websocket;
+
+/// File copies with the scp protocol.
+#[cfg(not(target_arch = "wasm32"))]
+pub mod scp;
+
+#[cfg(feature = "gssapi")]
+pub mod gssapi;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AlgorithmKind {
     Kex,
     Key,
@@ -165,6 +210,18 @@ pub enum AlgorithmKind {
     Mac,
 }
 
+impl Display for AlgorithmKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AlgorithmKind::Kex => "key exchange",
+            AlgorithmKind::Key => "host key",
+            AlgorithmKind::Cipher => "cipher",
+            AlgorithmKind::Compression => "compression",
+            AlgorithmKind::Mac => "MAC",
+        })
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     /// The key file could not be parsed.
@@ -179,11 +236,20 @@ pub enum Error {
     #[error("Unknown algorithm")]
     UnknownAlgo,
 
-    /// No common algorithm found during key exchange.
-    #[error("No common algorithm")]
+    /// No common algorithm of kind `kind` found during key exchange.
+    /// `ours` and `theirs` are all the algorithms offered by each side.
+    #[error("No common {kind} algorithm: ours are {}, theirs are {}", ours.get(*kind).join(","), theirs.get(*kind).join(","))]
     NoCommonAlgo {
         kind: AlgorithmKind,
-        ours: Vec<String>,
+        ours: Box<AlgorithmLists>,
+        theirs: Box<AlgorithmLists>,
+    },
+
+    /// The other side only offered algorithms that the
+    /// [`AlgorithmPolicy`] doesn't allow.
+    #[error("No {kind} algorithm offered by the remote side is allowed by the algorithm policy: {}", theirs.join(","))]
+    RefusedByPolicy {
+        kind: AlgorithmKind,
         theirs: Vec<String>,
     },
 
@@ -235,6 +301,10 @@ pub enum Error {
     #[error("Disconnected")]
     Disconnect,
 
+    /// The other side sent a disconnect message.
+    #[error("Disconnected by the remote side ({:?}): {}", .0.reason_code, .0.message)]
+    RemoteDisconnect(RemoteDisconnectInfo),
+
     /// No home directory found when trying to learn new host key.
     #[error("No home directory when saving host key")]
     NoHomeDir,
@@ -260,6 +330,16 @@ pub enum Error {
     #[error("Inactivity timeout")]
     InactivityTimeout,
 
+    /// The first key exchange took longer than the server allows, or
+    /// than the client's `handshake_timeout`.
+    #[error("Key exchange timeout")]
+    KexTimeout,
+
+    /// The client didn't authenticate within the server's login grace
+    /// time.
+    #[error("Login grace time exceeded")]
+    LoginTimeout,
+
     /// Missing authentication method.
     #[error("No authentication method")]
     NoAuthMethod,
@@ -276,6 +356,43 @@ pub enum Error {
     #[error("The request was rejected by the other party")]
     RequestDenied,
 
+    /// Invalid `DISPLAY` for X11 forwarding.
+    #[error("Invalid X11 display")]
+    InvalidDisplay,
+
+    /// A forwarded X11 connection didn't use the cookie we sent.
+    #[error("Wrong X11 authentication cookie")]
+    X11Auth,
+
+    /// An address range couldn't be parsed, see [`server::IpCidr`].
+    #[error("Invalid address range: {0}")]
+    InvalidAddressRange(String),
+
+    /// A local SOCKS client sent a request we can't handle.
+    #[error("Invalid or unsupported SOCKS request")]
+    Socks,
+
+    /// A connection multiplexing request failed, see [`client::MuxClient`].
+    #[error("Multiplexing: {0}")]
+    Mux(String),
+
+    /// An SFTP request failed.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("SFTP error {code:?}: {message}")]
+    Sftp {
+        code: sftp::StatusCode,
+        message: String,
+    },
+
+    /// The other side of an scp copy reported an error.
+    #[error("scp: {0}")]
+    Scp(String),
+
+    /// A GSSAPI security context failed.
+    #[cfg(feature = "gssapi")]
+    #[error("GSSAPI: {0}")]
+    Gssapi(String),
+
     #[error(transparent)]
     Keys(#[from] russh_keys::Error),
 
@@ -293,6 +410,10 @@ pub enum Error {
     #[cfg(feature = "flate2")]
     Decompress(#[from] flate2::DecompressError),
 
+    #[error(transparent)]
+    #[cfg(feature = "russh-config")]
+    Config(#[from] russh_config::Error),
+
     #[error(transparent)]
     Join(#[from] russh_util::runtime::JoinError),
 
@@ -336,6 +457,8 @@ pub struct SendError {}
 pub struct Limits {
     pub rekey_write_limit: usize,
     pub rekey_read_limit: usize,
+    /// Keys are re-exchanged after this time even if the connection
+    /// is idle, like OpenSSH's `RekeyLimit default 1h`.
     pub rekey_time_limit: std::time::Duration,
 }
 
@@ -369,7 +492,7 @@ pub use auth::{AgentAuthError, MethodSet, Signer};
 /// A reason for disconnection.
 #[allow(missing_docs)] // This should be relatively self-explanatory.
 #[allow(clippy::manual_non_exhaustive)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Disconnect {
     HostNotAllowedToConnect = 1,
     ProtocolError = 2,
@@ -414,13 +537,35 @@ impl TryFrom<u32> for Disconnect {
     }
 }
 
+/// The contents of an `SSH_MSG_DISCONNECT` sent by the other side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteDisconnectInfo {
+    pub reason_code: Disconnect,
+    pub message: String,
+    pub lang_tag: String,
+}
+
+impl RemoteDisconnectInfo {
+    /// Parse the payload of a disconnect message, after its type.
+    pub(crate) fn parse(mut r: &[u8]) -> Result<Self, Error> {
+        let reason_code = Disconnect::try_from(map_err!(u32::decode(&mut r))?)?;
+        let message = map_err!(String::decode(&mut r))?;
+        let lang_tag = map_err!(String::decode(&mut r))?;
+        Ok(RemoteDisconnectInfo {
+            reason_code,
+            message,
+            lang_tag,
+        })
+    }
+}
+
 /// The type of signals that can be sent to a remote process. If you
 /// plan to use custom signals, read [the
 /// RFC](https://tools.ietf.org/html/rfc4254#section-6.10) to
 /// understand the encoding.
 #[allow(missing_docs)]
 // This should be relatively self-explanatory.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Sig {
     ABRT,
     ALRM,
@@ -434,11 +579,14 @@ pub enum Sig {
     SEGV,
     TERM,
     USR1,
+    USR2,
     Custom(String),
 }
 
 impl Sig {
-    fn name(&self) -> &str {
+    /// The name of the signal in `signal` and `exit-signal` requests,
+    /// without the `SIG` prefix.
+    pub fn name(&self) -> &str {
         match *self {
             Sig::ABRT => "ABRT",
             Sig::ALRM => "ALRM",
@@ -452,10 +600,11 @@ impl Sig {
             Sig::SEGV => "SEGV",
             Sig::TERM => "TERM",
             Sig::USR1 => "USR1",
+            Sig::USR2 => "USR2",
             Sig::Custom(ref c) => c,
         }
     }
-    fn from_name(name: &str) -> Sig {
+    pub fn from_name(name: &str) -> Sig {
         match name {
             "ABRT" => Sig::ABRT,
             "ALRM" => Sig::ALRM,
@@ -469,6 +618,7 @@ impl Sig {
             "SEGV" => Sig::SEGV,
             "TERM" => Sig::TERM,
             "USR1" => Sig::USR1,
+            "USR2" => Sig::USR2,
             x => Sig::Custom(x.to_string()),
         }
     }
@@ -538,6 +688,13 @@ pub(crate) struct ChannelParams {
     sender_channel: ChannelId,
     recipient_window_size: u32,
     sender_window_size: u32,
+    /// The window we keep open for the other side, which grows when it
+    /// limits the throughput.
+    target_window_size: u32,
+    window_adjusted_at: Option<russh_util::time::Instant>,
+    /// When we asked to open the channel, to measure the round-trip
+    /// time.
+    open_sent_at: Option<russh_util::time::Instant>,
     recipient_maximum_packet_size: u32,
     sender_maximum_packet_size: u32,
     /// Has the other side confirmed the channel?
@@ -547,14 +704,25 @@ pub(crate) struct ChannelParams {
     pending_data: std::collections::VecDeque<(CryptoVec, Option<u32>, usize)>,
     pending_eof: bool,
     pending_close: bool,
+    /// The rate of the data received, which bounds the window.
+    inbound_rate: Option<throttle::TokenBucket>,
+    /// The rate of the data sent, beyond which it is queued.
+    outbound_rate: Option<throttle::TokenBucket>,
+    /// When data was last sent or received.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    data_at: russh_util::time::Instant,
 }
 
 impl ChannelParams {
-    pub fn confirm(&mut self, c: &ChannelOpenConfirmation) {
+    /// Returns the time it took the other side to confirm the channel.
+    pub fn confirm(&mut self, c: &ChannelOpenConfirmation) -> Option<std::time::Duration> {
         self.recipient_channel = c.sender_channel; // "sender" is the sender of the confirmation
         self.recipient_window_size = c.initial_window_size;
         self.recipient_maximum_packet_size = c.maximum_packet_size;
         self.confirmed = true;
+        self.open_sent_at
+            .take()
+            .map(|t| russh_util::time::Instant::now().duration_since(t))
     }
 }
 
@@ -0,0 +1,89 @@
+//! Logging, through the `log` crate, or through `tracing` with the
+//! `tracing` feature. With `tracing`, each connection runs in a span
+//! with its side and id, and the processing of each packet in a span
+//! for the key exchange, authentication or the channel it concerns.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) use log::{debug, error, info, trace, warn};
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{debug, error, info, trace, warn};
+
+#[cfg(feature = "tracing")]
+use std::convert::TryFrom;
+
+#[cfg(feature = "tracing")]
+use crate::msg;
+
+/// Identifies connections in logs, unique in the process.
+pub(crate) fn next_connection_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{Instrument, Span};
+
+/// Without `tracing`, spans don't exist.
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) trait Instrument: Sized {
+    fn instrument(self, _span: Span) -> Self {
+        self
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl<F: std::future::Future> Instrument for F {}
+
+/// The span of a whole connection, `side` being `"client"` or
+/// `"server"`.
+#[cfg(feature = "tracing")]
+pub(crate) fn connection_span(side: &'static str, id: u64) -> Span {
+    tracing::info_span!("ssh_connection", side, id)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn connection_span(_side: &'static str, _id: u64) -> Span {
+    Span
+}
+
+/// The span of a connection accepted by
+/// [`Server::run_on_socket`](crate::server::Server::run_on_socket).
+#[cfg(feature = "tracing")]
+pub(crate) fn peer_span(peer_addr: Option<SocketAddr>) -> Span {
+    tracing::info_span!("ssh_peer", addr = ?peer_addr)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn peer_span(_peer_addr: Option<SocketAddr>) -> Span {
+    Span
+}
+
+/// The span of the processing of an incoming packet, `buf` starting
+/// with the message number.
+#[cfg(feature = "tracing")]
+pub(crate) fn packet_span(buf: &[u8]) -> Span {
+    // Message number ranges of RFC 4250, section 4.1.
+    match buf.split_first() {
+        Some((20..=49, _)) => tracing::debug_span!("kex"),
+        Some((50..=79, _)) => tracing::debug_span!("auth"),
+        Some((msg::CHANNEL_OPEN_CONFIRMATION..=msg::CHANNEL_FAILURE, rest)) => {
+            match rest.get(..4).and_then(|id| <[u8; 4]>::try_from(id).ok()) {
+                Some(id) => tracing::debug_span!("channel", channel = u32::from_be_bytes(id)),
+                None => Span::none(),
+            }
+        }
+        _ => Span::none(),
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn packet_span(_buf: &[u8]) -> Span {
+    Span
+}
@@ -28,10 +28,12 @@ use ssh_encoding::Encode;
 use self::crypto::CryptoMacAlgorithm;
 use self::crypto_etm::CryptoEtmMacAlgorithm;
 use self::none::NoMacAlgorithm;
+use self::truncated::TruncatedMacAlgorithm;
 
 mod crypto;
 mod crypto_etm;
 mod none;
+mod truncated;
 
 pub(crate) trait MacAlgorithm {
     fn key_len(&self) -> usize;
@@ -73,12 +75,16 @@ impl TryFrom<&str> for Name {
 pub const NONE: Name = Name("none");
 /// `hmac-sha1`
 pub const HMAC_SHA1: Name = Name("hmac-sha1");
+/// `hmac-sha1-96`
+pub const HMAC_SHA1_96: Name = Name("hmac-sha1-96");
 /// `hmac-sha2-256`
 pub const HMAC_SHA256: Name = Name("hmac-sha2-256");
 /// `hmac-sha2-512`
 pub const HMAC_SHA512: Name = Name("hmac-sha2-512");
 /// `hmac-sha1-etm@openssh.com`
 pub const HMAC_SHA1_ETM: Name = Name("hmac-sha1-etm@openssh.com");
+/// `hmac-sha1-96-etm@openssh.com`
+pub const HMAC_SHA1_96_ETM: Name = Name("hmac-sha1-96-etm@openssh.com");
 /// `hmac-sha2-256-etm@openssh.com`
 pub const HMAC_SHA256_ETM: Name = Name("hmac-sha2-256-etm@openssh.com");
 /// `hmac-sha2-512-etm@openssh.com`
@@ -87,12 +93,14 @@ pub const HMAC_SHA512_ETM: Name = Name("hmac-sha2-512-etm@openssh.com");
 static _NONE: NoMacAlgorithm = NoMacAlgorithm {};
 static _HMAC_SHA1: CryptoMacAlgorithm<Hmac<Sha1>, U20> =
     CryptoMacAlgorithm(PhantomData, PhantomData);
+static _HMAC_SHA1_96: TruncatedMacAlgorithm = TruncatedMacAlgorithm(&_HMAC_SHA1, 12);
 static _HMAC_SHA256: CryptoMacAlgorithm<Hmac<Sha256>, U32> =
     CryptoMacAlgorithm(PhantomData, PhantomData);
 static _HMAC_SHA512: CryptoMacAlgorithm<Hmac<Sha512>, U64> =
     CryptoMacAlgorithm(PhantomData, PhantomData);
 static _HMAC_SHA1_ETM: CryptoEtmMacAlgorithm<Hmac<Sha1>, U20> =
     CryptoEtmMacAlgorithm(PhantomData, PhantomData);
+static _HMAC_SHA1_96_ETM: TruncatedMacAlgorithm = TruncatedMacAlgorithm(&_HMAC_SHA1_ETM, 12);
 static _HMAC_SHA256_ETM: CryptoEtmMacAlgorithm<Hmac<Sha256>, U32> =
     CryptoEtmMacAlgorithm(PhantomData, PhantomData);
 static _HMAC_SHA512_ETM: CryptoEtmMacAlgorithm<Hmac<Sha512>, U64> =
@@ -101,9 +109,11 @@ static _HMAC_SHA512_ETM: CryptoEtmMacAlgorithm<Hmac<Sha512>, U64> =
 pub const ALL_MAC_ALGORITHMS: &[&Name] = &[
     &NONE,
     &HMAC_SHA1,
+    &HMAC_SHA1_96,
     &HMAC_SHA256,
     &HMAC_SHA512,
     &HMAC_SHA1_ETM,
+    &HMAC_SHA1_96_ETM,
     &HMAC_SHA256_ETM,
     &HMAC_SHA512_ETM,
 ];
@@ -113,9 +123,11 @@ pub(crate) static MACS: Lazy<HashMap<&'static Name, &(dyn MacAlgorithm + Send +
         let mut h: HashMap<&'static Name, &(dyn MacAlgorithm + Send + Sync)> = HashMap::new();
         h.insert(&NONE, &_NONE);
         h.insert(&HMAC_SHA1, &_HMAC_SHA1);
+        h.insert(&HMAC_SHA1_96, &_HMAC_SHA1_96);
         h.insert(&HMAC_SHA256, &_HMAC_SHA256);
         h.insert(&HMAC_SHA512, &_HMAC_SHA512);
         h.insert(&HMAC_SHA1_ETM, &_HMAC_SHA1_ETM);
+        h.insert(&HMAC_SHA1_96_ETM, &_HMAC_SHA1_96_ETM);
         h.insert(&HMAC_SHA256_ETM, &_HMAC_SHA256_ETM);
         h.insert(&HMAC_SHA512_ETM, &_HMAC_SHA512_ETM);
         assert_eq!(h.len(), ALL_MAC_ALGORITHMS.len());
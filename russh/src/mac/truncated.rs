@@ -0,0 +1,53 @@
+use subtle::ConstantTimeEq;
+
+use super::{Mac, MacAlgorithm};
+
+/// A MAC algorithm whose output is truncated to its first `.1` bytes,
+/// such as `hmac-sha1-96`.
+pub struct TruncatedMacAlgorithm(pub &'static (dyn MacAlgorithm + Send + Sync), pub usize);
+
+impl MacAlgorithm for TruncatedMacAlgorithm {
+    fn key_len(&self) -> usize {
+        self.0.key_len()
+    }
+
+    fn make_mac(&self, mac_key: &[u8]) -> Box<dyn Mac + Send> {
+        Box::new(TruncatedMac {
+            mac: self.0.make_mac(mac_key),
+            len: self.1,
+        })
+    }
+}
+
+pub struct TruncatedMac {
+    mac: Box<dyn Mac + Send>,
+    len: usize,
+}
+
+impl TruncatedMac {
+    fn compute_full(&self, sequence_number: u32, payload: &[u8]) -> Vec<u8> {
+        let mut full = vec![0; self.mac.mac_len()];
+        self.mac.compute(sequence_number, payload, &mut full);
+        full
+    }
+}
+
+impl Mac for TruncatedMac {
+    fn is_etm(&self) -> bool {
+        self.mac.is_etm()
+    }
+
+    fn mac_len(&self) -> usize {
+        self.len
+    }
+
+    fn compute(&self, sequence_number: u32, payload: &[u8], output: &mut [u8]) {
+        let full = self.compute_full(sequence_number, payload);
+        output.clone_from_slice(full.get(..self.len).unwrap_or(&full));
+    }
+
+    fn verify(&self, sequence_number: u32, payload: &[u8], mac: &[u8]) -> bool {
+        let full = self.compute_full(sequence_number, payload);
+        full.get(..self.len).unwrap_or(&full).ct_eq(mac).into()
+    }
+}
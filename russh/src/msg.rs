@@ -34,6 +34,12 @@ pub const NEWKEYS: u8 = 21;
 pub const KEX_ECDH_INIT: u8 = 30;
 pub const KEX_ECDH_REPLY: u8 = 31;
 
+// https://tools.ietf.org/html/rfc4419#section-5
+pub const KEX_DH_GEX_GROUP: u8 = 31;
+pub const KEX_DH_GEX_INIT: u8 = 32;
+pub const KEX_DH_GEX_REPLY: u8 = 33;
+pub const KEX_DH_GEX_REQUEST: u8 = 34;
+
 // https://tools.ietf.org/html/rfc4250#section-4.1.2
 pub const USERAUTH_REQUEST: u8 = 50;
 pub const USERAUTH_FAILURE: u8 = 51;
@@ -42,6 +48,19 @@ pub const USERAUTH_BANNER: u8 = 53;
 
 pub const USERAUTH_INFO_RESPONSE: u8 = 61;
 
+#[cfg(feature = "gssapi")]
+pub use gssapi::*;
+
+#[cfg(feature = "gssapi")]
+mod gssapi {
+    // https://tools.ietf.org/html/rfc4462#section-3.9
+    pub const USERAUTH_GSSAPI_RESPONSE: u8 = 60;
+    pub const USERAUTH_GSSAPI_TOKEN: u8 = 61;
+    pub const USERAUTH_GSSAPI_ERROR: u8 = 64;
+    pub const USERAUTH_GSSAPI_ERRTOK: u8 = 65;
+    pub const USERAUTH_GSSAPI_MIC: u8 = 66;
+}
+
 // some numbers have same meaning
 pub const USERAUTH_INFO_REQUEST_OR_USERAUTH_PK_OK: u8 = 60;
 
@@ -13,18 +13,21 @@
 // limitations under the License.
 //
 use std::borrow::Cow;
+use std::fmt;
+use std::sync::{Arc, Mutex};
 
-use log::debug;
 use rand::RngCore;
 use russh_keys::helpers::NameList;
 use ssh_encoding::{Decode, Encode};
-use ssh_key::{Algorithm, Certificate, EcdsaCurve, HashAlg, PrivateKey, PublicKey};
+use ssh_key::{Algorithm, EcdsaCurve, HashAlg};
 
 use crate::cipher::CIPHERS;
+use crate::compression::CompressionConfig;
 use crate::kex::{EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT, EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER};
+use crate::logging::debug;
 #[cfg(not(target_arch = "wasm32"))]
 use crate::server::Config;
-use crate::{cipher, compression, kex, mac, msg, AlgorithmKind, CryptoVec, Error};
+use crate::{cipher, compression, kex, mac, msg, AlgorithmKind, AlgorithmPolicy, CryptoVec, Error};
 
 #[cfg(target_arch = "wasm32")]
 /// WASM-only stub
@@ -32,6 +35,13 @@ pub struct Config {
     keys: Vec<PrivateKey>,
 }
 
+#[cfg(target_arch = "wasm32")]
+impl Config {
+    fn host_keys(&self) -> impl Iterator<Item = &PrivateKey> {
+        self.keys.iter()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Names {
     pub kex: kex::Name,
@@ -45,6 +55,188 @@ pub struct Names {
     pub strict_kex: bool,
 }
 
+/// The algorithms of a connection, negotiated by its latest key
+/// exchange, and its session identifier.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub kex: kex::Name,
+    /// The algorithm of the server's host key signature.
+    pub host_key_algorithm: Algorithm,
+    pub cipher_client_to_server: cipher::Name,
+    pub cipher_server_to_client: cipher::Name,
+    /// `none` for ciphers with integrated authentication.
+    pub mac_client_to_server: mac::Name,
+    pub mac_server_to_client: mac::Name,
+    pub compression_client_to_server: compression::Name,
+    pub compression_server_to_client: compression::Name,
+    /// The exchange hash of the first key exchange, which stays the
+    /// same after key re-exchanges (RFC 4253, section 7.2).
+    pub session_id: Vec<u8>,
+}
+
+impl Negotiated {
+    pub(crate) fn new(names: &Names, session_id: &[u8]) -> Self {
+        Negotiated {
+            kex: names.kex,
+            host_key_algorithm: names.key.clone(),
+            cipher_client_to_server: names.cipher,
+            cipher_server_to_client: names.cipher,
+            mac_client_to_server: names.client_mac,
+            mac_server_to_client: names.server_mac,
+            compression_client_to_server: names.client_compression.name(),
+            compression_server_to_client: names.server_compression.name(),
+            session_id: session_id.to_vec(),
+        }
+    }
+}
+
+/// The latest [`Negotiated`] of a session, shared with its handles.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SharedNegotiated(Arc<Mutex<Option<Negotiated>>>);
+
+impl SharedNegotiated {
+    pub(crate) fn get(&self) -> Option<Negotiated> {
+        let negotiated = self.0.lock().ok()?;
+        negotiated.clone()
+    }
+
+    pub(crate) fn set(&self, negotiated: Negotiated) {
+        if let Ok(mut n) = self.0.lock() {
+            *n = Some(negotiated)
+        }
+    }
+}
+
+/// The algorithms offered by one side in its KEXINIT message, to tell
+/// why negotiation failed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AlgorithmLists {
+    pub kex: Vec<String>,
+    pub host_key: Vec<String>,
+    pub cipher_client_to_server: Vec<String>,
+    pub cipher_server_to_client: Vec<String>,
+    pub mac_client_to_server: Vec<String>,
+    pub mac_server_to_client: Vec<String>,
+    pub compression_client_to_server: Vec<String>,
+    pub compression_server_to_client: Vec<String>,
+}
+
+impl AlgorithmLists {
+    /// The lists we offer. `host_keys` are the types of the host keys
+    /// of servers, used to only offer algorithms we have keys for.
+    pub(crate) fn offered(
+        prefs: &Preferred,
+        is_server: bool,
+        host_keys: Option<&[Algorithm]>,
+        compression: &CompressionConfig,
+    ) -> Self {
+        // Only send the extension names meant for the other side.
+        let own_extensions = if is_server {
+            [
+                kex::EXTENSION_SUPPORT_AS_CLIENT,
+                kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+            ]
+        } else {
+            [
+                kex::EXTENSION_SUPPORT_AS_SERVER,
+                kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER,
+            ]
+        };
+        let kex = prefs
+            .kex
+            .iter()
+            .filter(|k| !own_extensions.contains(*k))
+            .map(|k| k.as_ref().to_owned())
+            .collect();
+        let host_key = match host_keys {
+            Some(host_keys) => prefs
+                .possible_host_key_algos_for_keys(host_keys)
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            None => prefs.key.iter().map(ToString::to_string).collect(),
+        };
+        let cipher: Vec<String> = prefs.cipher.iter().map(|c| c.as_ref().to_owned()).collect();
+        let mac: Vec<String> = prefs.mac.iter().map(|m| m.as_ref().to_owned()).collect();
+        let compression_names = |outgoing| {
+            compression
+                .algorithms(outgoing, &prefs.compression)
+                .iter()
+                .map(|c| c.as_ref().to_owned())
+                .collect()
+        };
+        AlgorithmLists {
+            kex,
+            host_key,
+            cipher_client_to_server: cipher.clone(),
+            cipher_server_to_client: cipher,
+            mac_client_to_server: mac.clone(),
+            mac_server_to_client: mac,
+            compression_client_to_server: compression_names(!is_server),
+            compression_server_to_client: compression_names(is_server),
+        }
+    }
+
+    /// The lists of a KEXINIT message.
+    pub(crate) fn parse(buffer: &[u8]) -> Result<Self, Error> {
+        let mut r = buffer.get(17..).ok_or(Error::Inconsistent)?;
+        let mut list = || -> Result<Vec<String>, Error> {
+            Ok(String::decode(&mut r)?
+                .split(',')
+                .filter(|a| !a.is_empty())
+                .map(str::to_owned)
+                .collect())
+        };
+        Ok(AlgorithmLists {
+            kex: list()?,
+            host_key: list()?,
+            cipher_client_to_server: list()?,
+            cipher_server_to_client: list()?,
+            mac_client_to_server: list()?,
+            mac_server_to_client: list()?,
+            compression_client_to_server: list()?,
+            compression_server_to_client: list()?,
+        })
+    }
+
+    /// The algorithms of `kind`, from client to server for ciphers,
+    /// MACs and compression.
+    pub fn get(&self, kind: AlgorithmKind) -> &[String] {
+        match kind {
+            AlgorithmKind::Kex => &self.kex,
+            AlgorithmKind::Key => &self.host_key,
+            AlgorithmKind::Cipher => &self.cipher_client_to_server,
+            AlgorithmKind::Mac => &self.mac_client_to_server,
+            AlgorithmKind::Compression => &self.compression_client_to_server,
+        }
+    }
+}
+
+/// One line per list, with the algorithms separated by commas.
+impl fmt::Display for AlgorithmLists {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, list) in [
+            ("kex", &self.kex),
+            ("host key", &self.host_key),
+            ("cipher client to server", &self.cipher_client_to_server),
+            ("cipher server to client", &self.cipher_server_to_client),
+            ("mac client to server", &self.mac_client_to_server),
+            ("mac server to client", &self.mac_server_to_client),
+            (
+                "compression client to server",
+                &self.compression_client_to_server,
+            ),
+            (
+                "compression server to client",
+                &self.compression_server_to_client,
+            ),
+        ] {
+            writeln!(f, "{}: {}", name, list.join(","))?;
+        }
+        Ok(())
+    }
+}
+
 /// Lists of preferred algorithms. This is normally hard-coded into implementations.
 #[derive(Debug, Clone)]
 pub struct Preferred {
@@ -63,19 +255,34 @@ pub struct Preferred {
 impl Preferred {
     pub(crate) fn possible_host_key_algos_for_keys(
         &self,
-        available_host_keys: &[PrivateKey],
+        available_host_keys: &[Algorithm],
     ) -> Vec<Algorithm> {
         self.key
             .iter()
-            .filter(|n| available_host_keys.iter().any(|k| k.algorithm() == **n))
+            .filter(|n| available_host_keys.iter().any(|k| can_sign_with(k, n)))
             .cloned()
             .collect::<Vec<_>>()
     }
 }
 
+/// Whether a key of type `key` can sign with `algorithm`: RSA keys can
+/// sign with any hash.
+pub(crate) fn can_sign_with(key: &Algorithm, algorithm: &Algorithm) -> bool {
+    match (key, algorithm) {
+        (Algorithm::Rsa { .. }, Algorithm::Rsa { .. }) => true,
+        _ => key == algorithm,
+    }
+}
+
 const SAFE_KEX_ORDER: &[kex::Name] = &[
+    kex::MLKEM768X25519_SHA256,
+    #[cfg(feature = "sntrup761")]
+    kex::SNTRUP761X25519_SHA512,
+    #[cfg(feature = "sntrup761")]
+    kex::SNTRUP761X25519_SHA512_OPENSSH,
     kex::CURVE25519,
     kex::CURVE25519_PRE_RFC_8731,
+    kex::DH_GEX_SHA256,
     kex::DH_G16_SHA512,
     kex::DH_G14_SHA256,
     kex::EXTENSION_SUPPORT_AS_CLIENT,
@@ -87,9 +294,52 @@ const SAFE_KEX_ORDER: &[kex::Name] = &[
 const CIPHER_ORDER: &[cipher::Name] = &[
     cipher::CHACHA20_POLY1305,
     cipher::AES_256_GCM,
+    cipher::AES_128_GCM,
+    cipher::AES_256_CTR,
+    cipher::AES_192_CTR,
+    cipher::AES_128_CTR,
+];
+
+/// [`SAFE_KEX_ORDER`], followed by NIST curves and SHA-1 key exchanges.
+const LEGACY_KEX_ORDER: &[kex::Name] = &[
+    kex::MLKEM768X25519_SHA256,
+    #[cfg(feature = "sntrup761")]
+    kex::SNTRUP761X25519_SHA512,
+    #[cfg(feature = "sntrup761")]
+    kex::SNTRUP761X25519_SHA512_OPENSSH,
+    kex::CURVE25519,
+    kex::CURVE25519_PRE_RFC_8731,
+    kex::DH_GEX_SHA256,
+    kex::DH_G16_SHA512,
+    kex::DH_G14_SHA256,
+    kex::ECDH_SHA2_NISTP256,
+    kex::ECDH_SHA2_NISTP384,
+    kex::ECDH_SHA2_NISTP521,
+    kex::DH_G14_SHA1,
+    kex::DH_GEX_SHA1,
+    kex::DH_G1_SHA1,
+    kex::EXTENSION_SUPPORT_AS_CLIENT,
+    kex::EXTENSION_SUPPORT_AS_SERVER,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER,
+];
+
+/// [`CIPHER_ORDER`], followed by CBC ciphers.
+const LEGACY_CIPHER_ORDER: &[cipher::Name] = &[
+    cipher::CHACHA20_POLY1305,
+    cipher::AES_256_GCM,
+    cipher::AES_128_GCM,
     cipher::AES_256_CTR,
     cipher::AES_192_CTR,
     cipher::AES_128_CTR,
+    #[cfg(feature = "legacy-ciphers")]
+    cipher::AES_256_CBC,
+    #[cfg(feature = "legacy-ciphers")]
+    cipher::AES_192_CBC,
+    #[cfg(feature = "legacy-ciphers")]
+    cipher::AES_128_CBC,
+    #[cfg(feature = "legacy-ciphers")]
+    cipher::TRIPLE_DES_CBC,
 ];
 
 const HMAC_ORDER: &[mac::Name] = &[
@@ -101,6 +351,18 @@ const HMAC_ORDER: &[mac::Name] = &[
     mac::HMAC_SHA1,
 ];
 
+/// [`HMAC_ORDER`], followed by truncated MACs.
+const LEGACY_HMAC_ORDER: &[mac::Name] = &[
+    mac::HMAC_SHA512_ETM,
+    mac::HMAC_SHA256_ETM,
+    mac::HMAC_SHA512,
+    mac::HMAC_SHA256,
+    mac::HMAC_SHA1_ETM,
+    mac::HMAC_SHA1,
+    mac::HMAC_SHA1_96_ETM,
+    mac::HMAC_SHA1_96,
+];
+
 const COMPRESSION_ORDER: &[compression::Name] = &[
     compression::NONE,
     #[cfg(feature = "flate2")]
@@ -109,6 +371,14 @@ const COMPRESSION_ORDER: &[compression::Name] = &[
     compression::ZLIB_LEGACY,
 ];
 
+const COMPRESSED_ORDER: &[compression::Name] = &[
+    #[cfg(feature = "flate2")]
+    compression::ZLIB,
+    #[cfg(feature = "flate2")]
+    compression::ZLIB_LEGACY,
+    compression::NONE,
+];
+
 impl Preferred {
     pub const DEFAULT: Preferred = Preferred {
         kex: Cow::Borrowed(SAFE_KEX_ORDER),
@@ -136,11 +406,25 @@ impl Preferred {
         compression: Cow::Borrowed(COMPRESSION_ORDER),
     };
 
+    /// The defaults, preferring compression.
     pub const COMPRESSED: Preferred = Preferred {
         kex: Cow::Borrowed(SAFE_KEX_ORDER),
         key: Preferred::DEFAULT.key,
         cipher: Cow::Borrowed(CIPHER_ORDER),
         mac: Cow::Borrowed(HMAC_ORDER),
+        compression: Cow::Borrowed(COMPRESSED_ORDER),
+    };
+
+    /// For old servers and network equipment that can't be upgraded:
+    /// the defaults, followed by NIST curve and SHA-1 key exchanges,
+    /// truncated MACs and, with the `legacy-ciphers` feature, CBC
+    /// ciphers. These are only used if the other side doesn't support
+    /// anything better.
+    pub const LEGACY_COMPAT: Preferred = Preferred {
+        kex: Cow::Borrowed(LEGACY_KEX_ORDER),
+        key: Preferred::DEFAULT.key,
+        cipher: Cow::Borrowed(LEGACY_CIPHER_ORDER),
+        mac: Cow::Borrowed(LEGACY_HMAC_ORDER),
         compression: Cow::Borrowed(COMPRESSION_ORDER),
     };
 }
@@ -151,36 +435,6 @@ impl Default for Preferred {
     }
 }
 
-/// Named algorithms.
-pub trait Named<'a> {
-    /// The name of this algorithm.
-    fn name(&'a self) -> impl AsRef<str> + 'a;
-}
-
-impl Named<'static> for () {
-    fn name(&'static self) -> impl AsRef<str> + 'static {
-        ""
-    }
-}
-
-impl<'a> Named<'a> for PublicKey {
-    fn name(&'a self) -> impl AsRef<str> + 'a {
-        self.algorithm()
-    }
-}
-
-impl<'a> Named<'a> for PrivateKey {
-    fn name(&'a self) -> impl AsRef<str> + 'a {
-        self.algorithm()
-    }
-}
-
-impl<'a> Named<'a> for Certificate {
-    fn name(&'a self) -> impl AsRef<str> + 'a {
-        self.algorithm().to_certificate_type()
-    }
-}
-
 pub(crate) fn parse_kex_algo_list(list: &str) -> Vec<&str> {
     list.split(',').collect()
 }
@@ -188,30 +442,76 @@ pub(crate) fn parse_kex_algo_list(list: &str) -> Vec<&str> {
 pub(crate) trait Select {
     fn is_server() -> bool;
 
-    fn select<S: AsRef<str> + Clone>(
-        a: &[S],
-        b: &[&str],
-        kind: AlgorithmKind,
-    ) -> Result<(bool, S), Error>;
+    /// The first algorithm of the client's list that is also in the
+    /// server's, from our list `ours` and the other side's `theirs`,
+    /// and whether it is the first choice of both sides.
+    fn select<S: AsRef<str> + Clone>(ours: &[S], theirs: &[&str]) -> Option<(bool, S)>;
 
     /// `available_host_keys`, if present, is used to limit the host key algorithms to the ones we have keys for.
+    /// With a `policy`, only the algorithms of `pref` it allows are chosen.
     fn read_kex(
         buffer: &[u8],
         pref: &Preferred,
-        available_host_keys: Option<&[PrivateKey]>,
+        policy: Option<&AlgorithmPolicy>,
+        available_host_keys: Option<&[Algorithm]>,
+        compression: &CompressionConfig,
+    ) -> Result<Names, Error> {
+        let Some(policy) = policy else {
+            return Self::read_kex_lists(buffer, pref, available_host_keys, compression);
+        };
+        match Self::read_kex_lists(
+            buffer,
+            &policy.restrict(pref),
+            available_host_keys,
+            compression,
+        ) {
+            // Tell whether the policy is what made negotiation fail.
+            Err(Error::NoCommonAlgo { kind, ours, theirs }) => {
+                match Self::read_kex_lists(buffer, pref, available_host_keys, compression) {
+                    Err(Error::NoCommonAlgo { kind: k, .. }) if k == kind => {
+                        Err(Error::NoCommonAlgo { kind, ours, theirs })
+                    }
+                    _ => {
+                        let theirs = theirs.get(kind).to_vec();
+                        debug!("{} algorithms refused by policy: {:?}", kind, theirs);
+                        Err(Error::RefusedByPolicy { kind, theirs })
+                    }
+                }
+            }
+            result => result,
+        }
+    }
+
+    fn read_kex_lists(
+        buffer: &[u8],
+        pref: &Preferred,
+        available_host_keys: Option<&[Algorithm]>,
+        compression: &CompressionConfig,
     ) -> Result<Names, Error> {
         let Some(mut r) = &buffer.get(17..) else {
             return Err(Error::Inconsistent);
         };
+        let no_common_algo = |kind: AlgorithmKind| {
+            let ours =
+                AlgorithmLists::offered(pref, Self::is_server(), available_host_keys, compression);
+            let theirs = AlgorithmLists::parse(buffer).unwrap_or_default();
+            debug!(
+                "no common {} algorithm, ours:\n{}theirs:\n{}",
+                kind, ours, theirs
+            );
+            Error::NoCommonAlgo {
+                kind,
+                ours: Box::new(ours),
+                theirs: Box::new(theirs),
+            }
+        };
 
         // Key exchange
 
         let kex_string = String::decode(&mut r)?;
-        let (kex_both_first, kex_algorithm) = Self::select(
-            &pref.kex,
-            &parse_kex_algo_list(&kex_string),
-            AlgorithmKind::Kex,
-        )?;
+        let (kex_both_first, kex_algorithm) =
+            Self::select(&pref.kex, &parse_kex_algo_list(&kex_string))
+                .ok_or_else(|| no_common_algo(AlgorithmKind::Kex))?;
 
         // Strict kex detection
 
@@ -227,9 +527,8 @@ pub(crate) trait Select {
                 EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER
             }],
             &parse_kex_algo_list(&kex_string),
-            AlgorithmKind::Kex,
         )
-        .is_ok();
+        .is_some();
         if strict_kex_requested && strict_kex_provided {
             debug!("strict kex enabled")
         }
@@ -245,17 +544,15 @@ pub(crate) trait Select {
         let (key_both_first, key_algorithm) = Self::select(
             &possible_host_key_algos[..],
             &parse_kex_algo_list(&key_string),
-            AlgorithmKind::Key,
-        )?;
+        )
+        .ok_or_else(|| no_common_algo(AlgorithmKind::Key))?;
 
         // Cipher
 
         let cipher_string = String::decode(&mut r)?;
-        let (_cipher_both_first, cipher) = Self::select(
-            &pref.cipher,
-            &parse_kex_algo_list(&cipher_string),
-            AlgorithmKind::Cipher,
-        )?;
+        let (_cipher_both_first, cipher) =
+            Self::select(&pref.cipher, &parse_kex_algo_list(&cipher_string))
+                .ok_or_else(|| no_common_algo(AlgorithmKind::Cipher))?;
         String::decode(&mut r)?; // cipher server-to-client.
         debug!("kex {}", line!());
 
@@ -263,34 +560,28 @@ pub(crate) trait Select {
 
         let need_mac = CIPHERS.get(&cipher).map(|x| x.needs_mac()).unwrap_or(false);
 
-        let client_mac = match Self::select(
-            &pref.mac,
-            &parse_kex_algo_list(&String::decode(&mut r)?),
-            AlgorithmKind::Mac,
-        ) {
-            Ok((_, m)) => m,
-            Err(e) => {
-                if need_mac {
-                    return Err(e);
-                } else {
-                    mac::NONE
+        let client_mac =
+            match Self::select(&pref.mac, &parse_kex_algo_list(&String::decode(&mut r)?)) {
+                Some((_, m)) => m,
+                None => {
+                    if need_mac {
+                        return Err(no_common_algo(AlgorithmKind::Mac));
+                    } else {
+                        mac::NONE
+                    }
                 }
-            }
-        };
-        let server_mac = match Self::select(
-            &pref.mac,
-            &parse_kex_algo_list(&String::decode(&mut r)?),
-            AlgorithmKind::Mac,
-        ) {
-            Ok((_, m)) => m,
-            Err(e) => {
-                if need_mac {
-                    return Err(e);
-                } else {
-                    mac::NONE
+            };
+        let server_mac =
+            match Self::select(&pref.mac, &parse_kex_algo_list(&String::decode(&mut r)?)) {
+                Some((_, m)) => m,
+                None => {
+                    if need_mac {
+                        return Err(no_common_algo(AlgorithmKind::Mac));
+                    } else {
+                        mac::NONE
+                    }
                 }
-            }
-        };
+            };
 
         // Compression
 
@@ -298,22 +589,24 @@ pub(crate) trait Select {
         // client-to-server compression.
         let client_compression = compression::Compression::new(
             &Self::select(
-                &pref.compression,
+                compression.algorithms(!Self::is_server(), &pref.compression),
                 &parse_kex_algo_list(&String::decode(&mut r)?),
-                AlgorithmKind::Compression,
-            )?
+            )
+            .ok_or_else(|| no_common_algo(AlgorithmKind::Compression))?
             .1,
+            compression.level,
         );
 
         debug!("kex {}", line!());
         // server-to-client compression.
         let server_compression = compression::Compression::new(
             &Self::select(
-                &pref.compression,
+                compression.algorithms(Self::is_server(), &pref.compression),
                 &parse_kex_algo_list(&String::decode(&mut r)?),
-                AlgorithmKind::Compression,
-            )?
+            )
+            .ok_or_else(|| no_common_algo(AlgorithmKind::Compression))?
             .1,
+            compression.level,
         );
         debug!("client_compression = {:?}", client_compression);
         String::decode(&mut r)?; // languages client-to-server
@@ -343,25 +636,17 @@ impl Select for Server {
         true
     }
 
-    fn select<S: AsRef<str> + Clone>(
-        server_list: &[S],
-        client_list: &[&str],
-        kind: AlgorithmKind,
-    ) -> Result<(bool, S), Error> {
+    fn select<S: AsRef<str> + Clone>(server_list: &[S], client_list: &[&str]) -> Option<(bool, S)> {
         let mut both_first_choice = true;
         for c in client_list {
             for s in server_list {
                 if c == &s.as_ref() {
-                    return Ok((both_first_choice, s.clone()));
+                    return Some((both_first_choice, s.clone()));
                 }
                 both_first_choice = false
             }
         }
-        Err(Error::NoCommonAlgo {
-            kind,
-            ours: server_list.iter().map(|x| x.as_ref().to_owned()).collect(),
-            theirs: client_list.iter().map(|x| (*x).to_owned()).collect(),
-        })
+        None
     }
 }
 
@@ -370,33 +655,47 @@ impl Select for Client {
         false
     }
 
-    fn select<S: AsRef<str> + Clone>(
-        client_list: &[S],
-        server_list: &[&str],
-        kind: AlgorithmKind,
-    ) -> Result<(bool, S), Error> {
+    fn select<S: AsRef<str> + Clone>(client_list: &[S], server_list: &[&str]) -> Option<(bool, S)> {
         let mut both_first_choice = true;
         for c in client_list {
             for s in server_list {
                 if s == &c.as_ref() {
-                    return Ok((both_first_choice, c.clone()));
+                    return Some((both_first_choice, c.clone()));
                 }
                 both_first_choice = false
             }
         }
-        Err(Error::NoCommonAlgo {
-            kind,
-            ours: client_list.iter().map(|x| x.as_ref().to_owned()).collect(),
-            theirs: server_list.iter().map(|x| (*x).to_owned()).collect(),
-        })
+        None
     }
 }
 
 pub fn write_kex(
     prefs: &Preferred,
+    policy: Option<&AlgorithmPolicy>,
     buf: &mut CryptoVec,
     server_config: Option<&Config>,
+    compression: &CompressionConfig,
 ) -> Result<(), Error> {
+    let restricted;
+    let prefs = match policy {
+        Some(policy) => {
+            restricted = policy.restrict(prefs);
+            &restricted
+        }
+        None => prefs,
+    };
+    // Only advertise host key algorithms that we have keys for.
+    let host_keys = server_config.map(|c| {
+        c.host_keys()
+            .map(|k| k.public_key().algorithm())
+            .collect::<Vec<_>>()
+    });
+    let lists = AlgorithmLists::offered(
+        prefs,
+        server_config.is_some(),
+        host_keys.as_deref(),
+        compression,
+    );
     // buf.clear();
     buf.push(msg::KEXINIT);
 
@@ -404,90 +703,19 @@ pub fn write_kex(
     rand::thread_rng().fill_bytes(&mut cookie);
 
     buf.extend(&cookie); // cookie
-    NameList(
-        prefs
-            .kex
-            .iter()
-            .filter(|k| {
-                !(if server_config.is_some() {
-                    [
-                        crate::kex::EXTENSION_SUPPORT_AS_CLIENT,
-                        crate::kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
-                    ]
-                } else {
-                    [
-                        crate::kex::EXTENSION_SUPPORT_AS_SERVER,
-                        crate::kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER,
-                    ]
-                })
-                .contains(*k)
-            })
-            .map(|x| x.as_ref().to_owned())
-            .collect(),
-    )
-    .encode(buf)?; // kex algo
-
-    if let Some(server_config) = server_config {
-        // Only advertise host key algorithms that we have keys for.
-        NameList(
-            prefs
-                .key
-                .iter()
-                .filter(|algo| server_config.keys.iter().any(|k| k.algorithm() == **algo))
-                .map(|x| x.to_string())
-                .collect(),
-        )
-        .encode(buf)?;
-    } else {
-        NameList(prefs.key.iter().map(ToString::to_string).collect()).encode(buf)?;
+    for list in [
+        lists.kex,
+        lists.host_key,
+        lists.cipher_client_to_server,
+        lists.cipher_server_to_client,
+        lists.mac_client_to_server,
+        lists.mac_server_to_client,
+        lists.compression_client_to_server,
+        lists.compression_server_to_client,
+    ] {
+        NameList(list).encode(buf)?;
     }
 
-    // cipher client to server
-    NameList(
-        prefs
-            .cipher
-            .iter()
-            .map(|x| x.as_ref().to_string())
-            .collect(),
-    )
-    .encode(buf)?;
-
-    // cipher server to client
-    NameList(
-        prefs
-            .cipher
-            .iter()
-            .map(|x| x.as_ref().to_string())
-            .collect(),
-    )
-    .encode(buf)?;
-
-    // mac client to server
-    NameList(prefs.mac.iter().map(|x| x.as_ref().to_string()).collect()).encode(buf)?;
-
-    // mac server to client
-    NameList(prefs.mac.iter().map(|x| x.as_ref().to_string()).collect()).encode(buf)?;
-
-    // compress client to server
-    NameList(
-        prefs
-            .compression
-            .iter()
-            .map(|x| x.as_ref().to_string())
-            .collect(),
-    )
-    .encode(buf)?;
-
-    // compress server to client
-    NameList(
-        prefs
-            .compression
-            .iter()
-            .map(|x| x.as_ref().to_string())
-            .collect(),
-    )
-    .encode(buf)?;
-
     Vec::<String>::new().encode(buf)?; // languages client to server
     Vec::<String>::new().encode(buf)?; // languages server to client
 
@@ -495,3 +723,53 @@ pub fn write_kex(
     buf.extend(&[0, 0, 0, 0]); // reserved
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    #[test]
+    fn test_no_common_algo() {
+        let client = Preferred {
+            cipher: Cow::Borrowed(&[cipher::AES_128_CTR]),
+            ..Preferred::DEFAULT
+        };
+        let mut kexinit = CryptoVec::new();
+        write_kex(&client, None, &mut kexinit, None, &Default::default()).unwrap();
+        let theirs = AlgorithmLists::parse(&kexinit).unwrap();
+        assert_eq!(theirs.cipher_client_to_server, vec!["aes128-ctr"]);
+        assert_eq!(
+            theirs,
+            AlgorithmLists::offered(&client, false, None, &Default::default())
+        );
+
+        let server = Preferred {
+            cipher: Cow::Borrowed(&[cipher::CHACHA20_POLY1305]),
+            ..Preferred::DEFAULT
+        };
+        let host_keys = [Algorithm::Ed25519];
+        match Server::read_kex(
+            &kexinit,
+            &server,
+            None,
+            Some(&host_keys),
+            &Default::default(),
+        ) {
+            Err(Error::NoCommonAlgo { kind, ours, theirs }) => {
+                assert_eq!(kind, AlgorithmKind::Cipher);
+                assert_eq!(ours.host_key, vec!["ssh-ed25519"]);
+                assert_eq!(ours.get(kind), ["chacha20-poly1305@openssh.com"]);
+                assert_eq!(theirs.get(kind), ["aes128-ctr"]);
+                assert!(theirs.kex.contains(&"ext-info-c".to_string()));
+                let e = Error::NoCommonAlgo { kind, ours, theirs };
+                assert_eq!(
+                    e.to_string(),
+                    "No common cipher algorithm: ours are chacha20-poly1305@openssh.com, theirs are aes128-ctr"
+                );
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
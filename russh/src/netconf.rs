@@ -0,0 +1,291 @@
+//! Clients of the `netconf` subsystem (RFC 6242).
+//!
+//! [`NetconfClient`] exchanges the `<hello>` messages with the server,
+//! and then sends and receives whole messages, framed with the
+//! `]]>]]>` end-of-message marker of NETCONF 1.0, or in chunks if both
+//! sides support NETCONF 1.1. The content of the messages is left to
+//! the application.
+//!
+//! ```no_run
+//! # async fn f(channel: russh::Channel<russh::client::Msg>) -> Result<(), russh::Error> {
+//! let mut netconf = russh::netconf::NetconfClient::from_channel(channel, &[]).await?;
+//! let reply = netconf.rpc("<get-config><source><running/></source></get-config>").await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::logging::debug;
+use crate::{Channel, ChannelId, ChannelMsg, ChannelStream, Error};
+
+/// The capability of NETCONF 1.0, with end-of-message framing.
+pub const BASE_1_0: &str = "urn:ietf:params:netconf:base:1.0";
+/// The capability of NETCONF 1.1, with chunked framing.
+pub const BASE_1_1: &str = "urn:ietf:params:netconf:base:1.1";
+/// The namespace of NETCONF messages.
+const NAMESPACE: &str = "urn:ietf:params:xml:ns:netconf:base:1.0";
+
+const END_OF_MESSAGE: &[u8] = b"]]>]]>";
+
+/// How messages are delimited on the channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Each message is followed by `]]>]]>` (NETCONF 1.0, and the
+    /// `<hello>` messages).
+    EndOfMessage,
+    /// Each message is sent in chunks prefixed with their size
+    /// (NETCONF 1.1).
+    Chunked,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A NETCONF session, on a channel where the `netconf` subsystem was
+/// started, or on any other stream.
+#[derive(Debug)]
+pub struct NetconfClient<S> {
+    stream: BufReader<S>,
+    framing: Framing,
+    server_hello: Vec<u8>,
+    next_message_id: u64,
+}
+
+impl<S> NetconfClient<ChannelStream<S>>
+where
+    S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static,
+{
+    /// Request the `netconf` subsystem on a session channel, and
+    /// exchange the `<hello>` messages, advertising `capabilities`
+    /// besides [`BASE_1_0`] and [`BASE_1_1`].
+    pub async fn from_channel(
+        mut channel: Channel<S>,
+        capabilities: &[&str],
+    ) -> Result<Self, Error> {
+        channel.request_subsystem(true, "netconf").await?;
+        loop {
+            match channel.wait().await {
+                Some(ChannelMsg::Success) => break,
+                Some(ChannelMsg::Failure) => return Err(Error::RequestDenied),
+                Some(msg) => debug!("netconf: ignoring {:?}", msg),
+                None => return Err(Error::HUP),
+            }
+        }
+        Self::new(channel.into_stream(), capabilities).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> NetconfClient<S> {
+    /// Exchange the `<hello>` messages on `stream`, advertising
+    /// `capabilities` besides [`BASE_1_0`] and [`BASE_1_1`]. Chunked
+    /// framing is used from then on if the server supports it.
+    pub async fn new(stream: S, capabilities: &[&str]) -> Result<Self, Error> {
+        let mut client = Self::with_framing(stream, Framing::EndOfMessage);
+        let mut hello = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<hello xmlns=\"{NAMESPACE}\"><capabilities>"
+        );
+        for capability in [BASE_1_0, BASE_1_1].iter().chain(capabilities) {
+            hello.push_str(&format!("<capability>{capability}</capability>"));
+        }
+        hello.push_str("</capabilities></hello>");
+        client.send(hello.as_bytes()).await?;
+
+        let server_hello = client.recv().await?.ok_or(Error::HUP)?;
+        if contains(&server_hello, BASE_1_1.as_bytes()) {
+            client.framing = Framing::Chunked
+        } else if !contains(&server_hello, BASE_1_0.as_bytes()) {
+            return Err(invalid_data("no common NETCONF version").into());
+        }
+        debug!("netconf: {:?} framing", client.framing);
+        client.server_hello = server_hello;
+        Ok(client)
+    }
+
+    /// Send and receive messages on `stream` with `framing`, without
+    /// exchanging `<hello>` messages.
+    pub fn with_framing(stream: S, framing: Framing) -> Self {
+        NetconfClient {
+            stream: BufReader::new(stream),
+            framing,
+            server_hello: Vec::new(),
+            next_message_id: 1,
+        }
+    }
+
+    /// The framing of the messages.
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// The `<hello>` message of the server, with its capabilities and
+    /// session id. Empty if the session was started with
+    /// [`NetconfClient::with_framing`].
+    pub fn server_hello(&self) -> &[u8] {
+        &self.server_hello
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    /// Send one message.
+    pub async fn send(&mut self, message: &[u8]) -> Result<(), Error> {
+        match self.framing {
+            Framing::EndOfMessage => {
+                self.stream.write_all(message).await?;
+                self.stream.write_all(END_OF_MESSAGE).await?;
+            }
+            Framing::Chunked => {
+                if message.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty message").into());
+                }
+                self.stream
+                    .write_all(format!("\n#{}\n", message.len()).as_bytes())
+                    .await?;
+                self.stream.write_all(message).await?;
+                self.stream.write_all(b"\n##\n").await?;
+            }
+        }
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Receive one message, or `None` if the server closed the stream
+    /// between two messages.
+    pub async fn recv(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        match self.framing {
+            Framing::EndOfMessage => self.recv_end_of_message().await,
+            Framing::Chunked => self.recv_chunked().await,
+        }
+    }
+
+    async fn recv_end_of_message(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let mut message = Vec::new();
+        while !message.ends_with(END_OF_MESSAGE) {
+            if self.stream.read_until(b'>', &mut message).await? == 0 {
+                if message.iter().all(u8::is_ascii_whitespace) {
+                    return Ok(None);
+                }
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+        message.truncate(message.len() - END_OF_MESSAGE.len());
+        Ok(Some(message))
+    }
+
+    async fn recv_chunked(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let mut message = Vec::new();
+        loop {
+            let mut header = Vec::new();
+            if self.stream.read_until(b'#', &mut header).await? == 0 && message.is_empty() {
+                return Ok(None);
+            }
+            if header != b"\n#" {
+                return Err(invalid_data("invalid NETCONF chunk header").into());
+            }
+            let mut size = Vec::new();
+            self.stream.read_until(b'\n', &mut size).await?;
+            if size == b"#\n" {
+                if message.is_empty() {
+                    return Err(invalid_data("NETCONF message without chunks").into());
+                }
+                return Ok(Some(message));
+            }
+            let size = size
+                .strip_suffix(b"\n")
+                .filter(|s| s.first().is_some_and(|c| (b'1'..=b'9').contains(c)))
+                .and_then(|s| std::str::from_utf8(s).ok())
+                .and_then(|s| s.parse::<u32>().ok())
+                .ok_or_else(|| invalid_data("invalid NETCONF chunk size"))?;
+            let start = message.len();
+            message.resize(start + size as usize, 0);
+            #[allow(clippy::indexing_slicing)] // length checked
+            self.stream.read_exact(&mut message[start..]).await?;
+        }
+    }
+
+    /// Send `operation` in an `<rpc>` with a new `message-id`, and
+    /// return the next message received, which should be its
+    /// `<rpc-reply>`.
+    pub async fn rpc(&mut self, operation: &str) -> Result<Vec<u8>, Error> {
+        let rpc = format!(
+            "<rpc message-id=\"{}\" xmlns=\"{NAMESPACE}\">{operation}</rpc>",
+            self.next_message_id
+        );
+        self.next_message_id += 1;
+        self.send(rpc.as_bytes()).await?;
+        self.recv().await?.ok_or(Error::HUP)
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    const SERVER_HELLO: &str = "<hello xmlns=\"urn:ietf:params:xml:ns:netconf:base:1.0\">\
+        <capabilities><capability>urn:ietf:params:netconf:base:1.1</capability></capabilities>\
+        <session-id>4</session-id></hello>";
+
+    #[tokio::test]
+    async fn test_netconf_hello_and_chunks() {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = async move {
+            let mut server = NetconfClient::with_framing(server, Framing::EndOfMessage);
+            let hello = server.recv().await.unwrap().unwrap();
+            assert!(contains(&hello, BASE_1_1.as_bytes()));
+            server.send(SERVER_HELLO.as_bytes()).await.unwrap();
+
+            let mut stream = server.into_inner();
+            let mut rpc = vec![0; 256];
+            let n = stream.read(&mut rpc).await.unwrap();
+            rpc.truncate(n);
+            assert!(rpc.starts_with(b"\n#"));
+            assert!(rpc.ends_with(b"<get/></rpc>\n##\n"));
+            // A reply in two chunks.
+            stream
+                .write_all(b"\n#10\n<rpc-reply\n#18\n><ok/></rpc-reply>\n##\n")
+                .await
+                .unwrap();
+        };
+        let client = async move {
+            let mut client = NetconfClient::new(client, &[]).await.unwrap();
+            assert_eq!(client.framing(), Framing::Chunked);
+            assert!(contains(client.server_hello(), b"<session-id>4"));
+            let reply = client.rpc("<get/>").await.unwrap();
+            assert_eq!(reply, b"<rpc-reply><ok/></rpc-reply>");
+            assert_eq!(client.recv().await.unwrap(), None);
+        };
+        tokio::join!(server, client);
+    }
+
+    #[tokio::test]
+    async fn test_netconf_end_of_message() {
+        let (a, b) = tokio::io::duplex(4096);
+        let mut a = NetconfClient::with_framing(a, Framing::EndOfMessage);
+        let mut b = NetconfClient::with_framing(b, Framing::EndOfMessage);
+        a.send(b"<rpc>]]></rpc>").await.unwrap();
+        a.send(b"<close-session/>").await.unwrap();
+        drop(a);
+        assert_eq!(b.recv().await.unwrap().unwrap(), b"<rpc>]]></rpc>");
+        assert_eq!(b.recv().await.unwrap().unwrap(), b"<close-session/>");
+        assert_eq!(b.recv().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_netconf_invalid_chunk() {
+        let (mut a, b) = tokio::io::duplex(4096);
+        let mut b = NetconfClient::with_framing(b, Framing::Chunked);
+        a.write_all(b"\n#012\n").await.unwrap();
+        assert!(b.recv().await.is_err());
+    }
+}
@@ -0,0 +1,295 @@
+//! Tracing of the SSH messages of a connection, to debug
+//! interoperability issues. Set a [`PacketObserver`] in
+//! [`client::Config::packet_observer`](crate::client::Config::packet_observer)
+//! or [`server::Config::packet_observer`](crate::server::Config::packet_observer),
+//! for instance a [`TextDump`].
+//!
+//! Observers see the messages in clear, including passwords and the
+//! data of channels: don't leave them enabled in production.
+
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use ssh_encoding::Decode;
+
+use crate::msg;
+
+// Only defined in `msg` for servers.
+const SERVICE_REQUEST: u8 = 5;
+
+/// Whether a message was received or sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// An SSH message, decrypted and decompressed.
+#[derive(Debug, Clone, Copy)]
+pub struct Packet<'a> {
+    pub direction: Direction,
+    /// The sequence number of the packet in its direction.
+    pub seqn: u32,
+    /// The payload, starting with the message number.
+    pub payload: &'a [u8],
+}
+
+impl Packet<'_> {
+    /// The message number, `None` for an empty payload.
+    pub fn msg_type(&self) -> Option<u8> {
+        self.payload.first().copied()
+    }
+
+    /// The name of the message in the RFCs, such as `SSH_MSG_KEXINIT`.
+    /// Some numbers are used by several messages, depending on the key
+    /// exchange or authentication method.
+    pub fn msg_name(&self) -> &'static str {
+        match self.msg_type() {
+            Some(t) => msg_name(t),
+            None => "(empty)",
+        }
+    }
+
+    /// A short description of the fields of the message that are
+    /// useful to follow a connection, such as channel numbers, request
+    /// names or authentication methods. Never includes secrets.
+    pub fn summary(&self) -> String {
+        let Some((&t, mut r)) = self.payload.split_first() else {
+            return String::new();
+        };
+        match t {
+            msg::DISCONNECT => {
+                let code = u32::decode(&mut r).unwrap_or(0);
+                let description = String::decode(&mut r).unwrap_or_default();
+                format!("reason {code}, {description:?}")
+            }
+            SERVICE_REQUEST | msg::SERVICE_ACCEPT | msg::GLOBAL_REQUEST => {
+                format!("{:?}", String::decode(&mut r).unwrap_or_default())
+            }
+            msg::USERAUTH_REQUEST => {
+                let user = String::decode(&mut r).unwrap_or_default();
+                let _service = String::decode(&mut r);
+                let method = String::decode(&mut r).unwrap_or_default();
+                format!("user {user:?}, method {method:?}")
+            }
+            msg::USERAUTH_FAILURE => {
+                let methods = String::decode(&mut r).unwrap_or_default();
+                let partial = u8::decode(&mut r).unwrap_or(0) != 0;
+                format!("methods {methods:?}, partial success {partial}")
+            }
+            msg::CHANNEL_OPEN => {
+                let typ = String::decode(&mut r).unwrap_or_default();
+                let sender = u32::decode(&mut r).unwrap_or(0);
+                format!("{typ:?}, sender channel {sender}")
+            }
+            msg::CHANNEL_OPEN_CONFIRMATION => {
+                let recipient = u32::decode(&mut r).unwrap_or(0);
+                let sender = u32::decode(&mut r).unwrap_or(0);
+                format!("channel {recipient}, sender channel {sender}")
+            }
+            msg::CHANNEL_DATA => {
+                let channel = u32::decode(&mut r).unwrap_or(0);
+                let len = u32::decode(&mut r).unwrap_or(0);
+                format!("channel {channel}, {len} bytes")
+            }
+            msg::CHANNEL_EXTENDED_DATA => {
+                let channel = u32::decode(&mut r).unwrap_or(0);
+                let ext = u32::decode(&mut r).unwrap_or(0);
+                let len = u32::decode(&mut r).unwrap_or(0);
+                format!("channel {channel}, type {ext}, {len} bytes")
+            }
+            msg::CHANNEL_WINDOW_ADJUST => {
+                let channel = u32::decode(&mut r).unwrap_or(0);
+                let amount = u32::decode(&mut r).unwrap_or(0);
+                format!("channel {channel}, {amount} bytes")
+            }
+            msg::CHANNEL_REQUEST => {
+                let channel = u32::decode(&mut r).unwrap_or(0);
+                let request = String::decode(&mut r).unwrap_or_default();
+                format!("channel {channel}, {request:?}")
+            }
+            msg::CHANNEL_OPEN_FAILURE
+            | msg::CHANNEL_EOF
+            | msg::CHANNEL_CLOSE
+            | msg::CHANNEL_SUCCESS
+            | msg::CHANNEL_FAILURE => {
+                format!("channel {}", u32::decode(&mut r).unwrap_or(0))
+            }
+            _ => String::new(),
+        }
+    }
+}
+
+fn msg_name(t: u8) -> &'static str {
+    match t {
+        msg::DISCONNECT => "SSH_MSG_DISCONNECT",
+        msg::IGNORE => "SSH_MSG_IGNORE",
+        msg::UNIMPLEMENTED => "SSH_MSG_UNIMPLEMENTED",
+        msg::DEBUG => "SSH_MSG_DEBUG",
+        SERVICE_REQUEST => "SSH_MSG_SERVICE_REQUEST",
+        msg::SERVICE_ACCEPT => "SSH_MSG_SERVICE_ACCEPT",
+        msg::EXT_INFO => "SSH_MSG_EXT_INFO",
+        msg::KEXINIT => "SSH_MSG_KEXINIT",
+        msg::NEWKEYS => "SSH_MSG_NEWKEYS",
+        msg::KEX_ECDH_INIT => "SSH_MSG_KEX_ECDH_INIT",
+        msg::KEX_ECDH_REPLY => "SSH_MSG_KEX_ECDH_REPLY/SSH_MSG_KEX_DH_GEX_GROUP",
+        msg::KEX_DH_GEX_INIT => "SSH_MSG_KEX_DH_GEX_INIT",
+        msg::KEX_DH_GEX_REPLY => "SSH_MSG_KEX_DH_GEX_REPLY",
+        msg::KEX_DH_GEX_REQUEST => "SSH_MSG_KEX_DH_GEX_REQUEST",
+        msg::USERAUTH_REQUEST => "SSH_MSG_USERAUTH_REQUEST",
+        msg::USERAUTH_FAILURE => "SSH_MSG_USERAUTH_FAILURE",
+        msg::USERAUTH_SUCCESS => "SSH_MSG_USERAUTH_SUCCESS",
+        msg::USERAUTH_BANNER => "SSH_MSG_USERAUTH_BANNER",
+        msg::USERAUTH_INFO_REQUEST_OR_USERAUTH_PK_OK => {
+            "SSH_MSG_USERAUTH_PK_OK/SSH_MSG_USERAUTH_INFO_REQUEST"
+        }
+        msg::USERAUTH_INFO_RESPONSE => "SSH_MSG_USERAUTH_INFO_RESPONSE",
+        msg::GLOBAL_REQUEST => "SSH_MSG_GLOBAL_REQUEST",
+        msg::REQUEST_SUCCESS => "SSH_MSG_REQUEST_SUCCESS",
+        msg::REQUEST_FAILURE => "SSH_MSG_REQUEST_FAILURE",
+        msg::CHANNEL_OPEN => "SSH_MSG_CHANNEL_OPEN",
+        msg::CHANNEL_OPEN_CONFIRMATION => "SSH_MSG_CHANNEL_OPEN_CONFIRMATION",
+        msg::CHANNEL_OPEN_FAILURE => "SSH_MSG_CHANNEL_OPEN_FAILURE",
+        msg::CHANNEL_WINDOW_ADJUST => "SSH_MSG_CHANNEL_WINDOW_ADJUST",
+        msg::CHANNEL_DATA => "SSH_MSG_CHANNEL_DATA",
+        msg::CHANNEL_EXTENDED_DATA => "SSH_MSG_CHANNEL_EXTENDED_DATA",
+        msg::CHANNEL_EOF => "SSH_MSG_CHANNEL_EOF",
+        msg::CHANNEL_CLOSE => "SSH_MSG_CHANNEL_CLOSE",
+        msg::CHANNEL_REQUEST => "SSH_MSG_CHANNEL_REQUEST",
+        msg::CHANNEL_SUCCESS => "SSH_MSG_CHANNEL_SUCCESS",
+        msg::CHANNEL_FAILURE => "SSH_MSG_CHANNEL_FAILURE",
+        _ => "unknown",
+    }
+}
+
+/// Receives the messages of a connection, in the order in which they
+/// are received or queued for sending. This is called from the
+/// session's event loop, and should return quickly.
+pub trait PacketObserver: Send + Sync {
+    fn observe(&self, packet: &Packet<'_>);
+}
+
+impl fmt::Debug for dyn PacketObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PacketObserver")
+    }
+}
+
+/// Notify `observer`, if any.
+pub(crate) fn observe(
+    observer: &Option<Arc<dyn PacketObserver>>,
+    direction: Direction,
+    seqn: u32,
+    payload: &[u8],
+) {
+    if let Some(ref observer) = observer {
+        observer.observe(&Packet {
+            direction,
+            seqn,
+            payload,
+        })
+    }
+}
+
+/// Writes messages as text: a comment line with the name and summary
+/// of each message, then its payload as a hex dump, preceded by `I`
+/// or `O` for its direction. This is the input format of Wireshark's
+/// `text2pcap -D`, and stays readable as is.
+///
+/// ```text
+/// # > 3 SSH_MSG_SERVICE_REQUEST (17 bytes) "ssh-userauth"
+/// O
+/// 000000 05 00 00 00 0c 73 73 68 2d 75 73 65 72 61 75 74
+/// 000010 68
+/// ```
+pub struct TextDump<W> {
+    out: Mutex<W>,
+}
+
+impl<W: Write + Send> TextDump<W> {
+    pub fn new(out: W) -> Self {
+        TextDump {
+            out: Mutex::new(out),
+        }
+    }
+
+    /// The writer, for instance to read the dump of a `Vec<u8>`.
+    pub fn into_inner(self) -> W {
+        match self.out.into_inner() {
+            Ok(out) => out,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl<W: Write + Send> PacketObserver for TextDump<W> {
+    fn observe(&self, packet: &Packet<'_>) {
+        let Ok(mut out) = self.out.lock() else {
+            return;
+        };
+        let _ = write_packet(&mut *out, packet);
+    }
+}
+
+fn write_packet<W: Write>(out: &mut W, packet: &Packet<'_>) -> std::io::Result<()> {
+    let (arrow, letter) = match packet.direction {
+        Direction::Inbound => ('<', 'I'),
+        Direction::Outbound => ('>', 'O'),
+    };
+    write!(
+        out,
+        "# {} {} {} ({} bytes)",
+        arrow,
+        packet.seqn,
+        packet.msg_name(),
+        packet.payload.len()
+    )?;
+    let summary = packet.summary();
+    if !summary.is_empty() {
+        write!(out, " {}", summary)?;
+    }
+    writeln!(out, "\n{}", letter)?;
+    for (i, line) in packet.payload.chunks(16).enumerate() {
+        write!(out, "{:06x}", i * 16)?;
+        for b in line {
+            write!(out, " {:02x}", b)?;
+        }
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    #[test]
+    fn test_text_dump() {
+        let dump = TextDump::new(Vec::new());
+        let mut payload = vec![SERVICE_REQUEST, 0, 0, 0, 12];
+        payload.extend(b"ssh-userauth");
+        dump.observe(&Packet {
+            direction: Direction::Outbound,
+            seqn: 3,
+            payload: &payload,
+        });
+        dump.observe(&Packet {
+            direction: Direction::Inbound,
+            seqn: 7,
+            payload: &[msg::CHANNEL_EOF, 0, 0, 0, 2],
+        });
+        assert_eq!(
+            String::from_utf8(dump.into_inner()).unwrap(),
+            "# > 3 SSH_MSG_SERVICE_REQUEST (17 bytes) \"ssh-userauth\"\n\
+             O\n\
+             000000 05 00 00 00 0c 73 73 68 2d 75 73 65 72 61 75 74\n\
+             000010 68\n\
+             # < 7 SSH_MSG_CHANNEL_EOF (5 bytes) channel 2\n\
+             I\n\
+             000000 60 00 00 00 02\n"
+        );
+    }
+}
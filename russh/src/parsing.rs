@@ -1,6 +1,9 @@
+use std::convert::TryFrom;
+
 use russh_keys::helpers::map_err;
 use ssh_encoding::{Decode, Encode, Reader};
 
+use crate::tun::TunMode;
 use crate::{msg, CryptoVec};
 
 #[derive(Debug)]
@@ -31,9 +34,20 @@ impl OpenChannelMessage {
             }
             "direct-tcpip" => ChannelType::DirectTcpip(TcpChannelInfo::decode(r)?),
             "forwarded-tcpip" => ChannelType::ForwardedTcpIp(TcpChannelInfo::decode(r)?),
+            "direct-streamlocal@openssh.com" => {
+                ChannelType::DirectStreamLocal(StreamLocalChannelInfo::decode(r)?)
+            }
             "forwarded-streamlocal@openssh.com" => {
                 ChannelType::ForwardedStreamLocal(StreamLocalChannelInfo::decode(r)?)
             }
+            "tun@openssh.com" => {
+                let mode = map_err!(u32::decode(r))?;
+                let unit = map_err!(u32::decode(r))?;
+                match TunMode::try_from(mode) {
+                    Ok(mode) => ChannelType::Tun { mode, unit },
+                    Err(_) => ChannelType::Unknown { typ: typ.clone() },
+                }
+            }
             "auth-agent@openssh.com" => ChannelType::AgentForward,
             _ => ChannelType::Unknown { typ },
         };
@@ -100,7 +114,12 @@ pub enum ChannelType {
     },
     DirectTcpip(TcpChannelInfo),
     ForwardedTcpIp(TcpChannelInfo),
+    DirectStreamLocal(StreamLocalChannelInfo),
     ForwardedStreamLocal(StreamLocalChannelInfo),
+    Tun {
+        mode: TunMode,
+        unit: u32,
+    },
     AgentForward,
     Unknown {
         typ: String,
@@ -172,3 +191,15 @@ impl Decode for ChannelOpenConfirmation {
         })
     }
 }
+
+/// Decode an SSH string, such as the payload of a `CHANNEL_DATA`
+/// message, without copying it.
+pub(crate) fn decode_slice<'a>(r: &mut &'a [u8]) -> Result<&'a [u8], crate::Error> {
+    let len = map_err!(u32::decode(r))? as usize;
+    if r.len() < len {
+        return Err(ssh_encoding::Error::Length.into());
+    }
+    let (s, rest) = r.split_at(len);
+    *r = rest;
+    Ok(s)
+}
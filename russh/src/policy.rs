@@ -0,0 +1,234 @@
+//! Restricting the algorithms that can be negotiated, whatever the
+//! [`Preferred`] lists of a configuration are.
+//!
+//! ```
+//! let mut config = russh::client::Config::default();
+//! config.preferred = russh::Preferred::LEGACY_COMPAT;
+//! config.algorithm_policy = Some(russh::AlgorithmPolicy::FIPS);
+//! ```
+
+use std::borrow::Cow;
+
+use ssh_key::{Algorithm, EcdsaCurve, HashAlg};
+
+use crate::negotiation::Preferred;
+use crate::{cipher, kex, mac};
+
+/// The key exchange names that only signal extensions, which policies
+/// don't restrict.
+const KEX_EXTENSIONS: &[kex::Name] = &[
+    kex::EXTENSION_SUPPORT_AS_CLIENT,
+    kex::EXTENSION_SUPPORT_AS_SERVER,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER,
+];
+
+/// The algorithms allowed in key exchanges, for both directions of
+/// the connection, and for the signatures of user authentication on
+/// servers.
+///
+/// A policy only removes algorithms from the [`Preferred`] lists, in
+/// their order. If the other side offers none of the remaining ones,
+/// negotiation fails with [`crate::Error::RefusedByPolicy`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlgorithmPolicy {
+    /// Allowed key exchange algorithms.
+    pub kex: Cow<'static, [kex::Name]>,
+    /// Allowed host & public key algorithms.
+    pub key: Cow<'static, [Algorithm]>,
+    /// Allowed symmetric ciphers.
+    pub cipher: Cow<'static, [cipher::Name]>,
+    /// Allowed MAC algorithms, for ciphers without integrated
+    /// authentication.
+    pub mac: Cow<'static, [mac::Name]>,
+}
+
+impl AlgorithmPolicy {
+    /// The algorithms approved by FIPS 140-3, as allowed by OpenSSH
+    /// in FIPS mode: NIST curves and finite field Diffie-Hellman with
+    /// SHA-2, ECDSA and RSA with SHA-2, AES, and HMAC with SHA-2.
+    ///
+    /// Since the default [`Preferred`] lists don't include NIST curve
+    /// key exchanges, use it with [`Preferred::LEGACY_COMPAT`] to allow
+    /// them. This only restricts the algorithms: the certification of
+    /// the cryptographic module depends on the backend, see
+    /// [`crate::CryptoBackend`].
+    pub const FIPS: AlgorithmPolicy = AlgorithmPolicy {
+        kex: Cow::Borrowed(&[
+            kex::ECDH_SHA2_NISTP256,
+            kex::ECDH_SHA2_NISTP384,
+            kex::ECDH_SHA2_NISTP521,
+            kex::DH_GEX_SHA256,
+            kex::DH_G16_SHA512,
+            kex::DH_G14_SHA256,
+        ]),
+        key: Cow::Borrowed(&[
+            Algorithm::Ecdsa {
+                curve: EcdsaCurve::NistP256,
+            },
+            Algorithm::Ecdsa {
+                curve: EcdsaCurve::NistP384,
+            },
+            Algorithm::Ecdsa {
+                curve: EcdsaCurve::NistP521,
+            },
+            Algorithm::Rsa {
+                hash: Some(HashAlg::Sha512),
+            },
+            Algorithm::Rsa {
+                hash: Some(HashAlg::Sha256),
+            },
+        ]),
+        cipher: Cow::Borrowed(&[
+            cipher::AES_256_GCM,
+            cipher::AES_128_GCM,
+            cipher::AES_256_CTR,
+            cipher::AES_192_CTR,
+            cipher::AES_128_CTR,
+        ]),
+        mac: Cow::Borrowed(&[
+            mac::HMAC_SHA512_ETM,
+            mac::HMAC_SHA256_ETM,
+            mac::HMAC_SHA512,
+            mac::HMAC_SHA256,
+        ]),
+    };
+
+    /// Only post-quantum and X25519 key exchanges, authenticated
+    /// ciphers, and signatures without SHA-1. MACs are never used with
+    /// these ciphers, but some implementations can't parse empty lists.
+    pub const MODERN: AlgorithmPolicy = AlgorithmPolicy {
+        kex: Cow::Borrowed(&[
+            kex::MLKEM768X25519_SHA256,
+            #[cfg(feature = "sntrup761")]
+            kex::SNTRUP761X25519_SHA512,
+            #[cfg(feature = "sntrup761")]
+            kex::SNTRUP761X25519_SHA512_OPENSSH,
+            kex::CURVE25519,
+            kex::CURVE25519_PRE_RFC_8731,
+        ]),
+        key: Cow::Borrowed(&[
+            Algorithm::Ed25519,
+            Algorithm::Ecdsa {
+                curve: EcdsaCurve::NistP256,
+            },
+            Algorithm::Ecdsa {
+                curve: EcdsaCurve::NistP384,
+            },
+            Algorithm::Ecdsa {
+                curve: EcdsaCurve::NistP521,
+            },
+            Algorithm::Rsa {
+                hash: Some(HashAlg::Sha512),
+            },
+            Algorithm::Rsa {
+                hash: Some(HashAlg::Sha256),
+            },
+        ]),
+        cipher: Cow::Borrowed(&[
+            cipher::CHACHA20_POLY1305,
+            cipher::AES_256_GCM,
+            cipher::AES_128_GCM,
+        ]),
+        mac: Cow::Borrowed(&[mac::HMAC_SHA512_ETM, mac::HMAC_SHA256_ETM]),
+    };
+
+    pub fn allows_kex(&self, kex: &kex::Name) -> bool {
+        self.kex.contains(kex) || KEX_EXTENSIONS.contains(kex)
+    }
+
+    pub fn allows_key(&self, key: &Algorithm) -> bool {
+        self.key.contains(key)
+    }
+
+    pub fn allows_cipher(&self, cipher: &cipher::Name) -> bool {
+        self.cipher.contains(cipher)
+    }
+
+    pub fn allows_mac(&self, mac: &mac::Name) -> bool {
+        self.mac.contains(mac)
+    }
+
+    /// The algorithms of `preferred` allowed by this policy, in the
+    /// same order. Compression isn't restricted.
+    pub fn restrict(&self, preferred: &Preferred) -> Preferred {
+        Preferred {
+            kex: preferred
+                .kex
+                .iter()
+                .filter(|k| self.allows_kex(k))
+                .cloned()
+                .collect::<Vec<_>>()
+                .into(),
+            key: preferred
+                .key
+                .iter()
+                .filter(|k| self.allows_key(k))
+                .cloned()
+                .collect::<Vec<_>>()
+                .into(),
+            cipher: preferred
+                .cipher
+                .iter()
+                .filter(|c| self.allows_cipher(c))
+                .cloned()
+                .collect::<Vec<_>>()
+                .into(),
+            mac: preferred
+                .mac
+                .iter()
+                .filter(|m| self.allows_mac(m))
+                .cloned()
+                .collect::<Vec<_>>()
+                .into(),
+            compression: preferred.compression.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    #[test]
+    fn test_restrict() {
+        let preferred = AlgorithmPolicy::FIPS.restrict(&Preferred::LEGACY_COMPAT);
+        assert_eq!(
+            &preferred.kex[..],
+            &[
+                kex::DH_GEX_SHA256,
+                kex::DH_G16_SHA512,
+                kex::DH_G14_SHA256,
+                kex::ECDH_SHA2_NISTP256,
+                kex::ECDH_SHA2_NISTP384,
+                kex::ECDH_SHA2_NISTP521,
+                kex::EXTENSION_SUPPORT_AS_CLIENT,
+                kex::EXTENSION_SUPPORT_AS_SERVER,
+                kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+                kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER,
+            ]
+        );
+        assert!(!preferred.key.contains(&Algorithm::Ed25519));
+        assert!(!preferred.key.contains(&Algorithm::Rsa { hash: None }));
+        assert!(!preferred.cipher.contains(&cipher::CHACHA20_POLY1305));
+        assert!(!preferred.mac.contains(&mac::HMAC_SHA1));
+
+        let preferred = AlgorithmPolicy::MODERN.restrict(&Preferred::DEFAULT);
+        assert_eq!(preferred.kex.first(), Some(&kex::MLKEM768X25519_SHA256));
+        assert!(!preferred.kex.contains(&kex::DH_G14_SHA256));
+        assert_eq!(
+            &preferred.cipher[..],
+            &[
+                cipher::CHACHA20_POLY1305,
+                cipher::AES_256_GCM,
+                cipher::AES_128_GCM
+            ]
+        );
+        assert_eq!(
+            &preferred.mac[..],
+            &[mac::HMAC_SHA512_ETM, mac::HMAC_SHA256_ETM]
+        );
+    }
+}
@@ -1,3 +1,9 @@
+use std::ops::Deref;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::logging::debug;
+
 #[allow(non_camel_case_types, missing_docs)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 /// Standard pseudo-terminal codes.
@@ -132,3 +138,184 @@ impl Pty {
         }
     }
 }
+
+/// The terminal modes sent with a `pty-req` (RFC 4254, section 8), in
+/// the order they were set. Each mode appears at most once.
+///
+/// ```
+/// use russh::{Pty, PtyModes};
+///
+/// let modes = PtyModes::new()
+///     .flag(Pty::ECHO, false)
+///     .flag(Pty::ICANON, true)
+///     .set(Pty::VINTR, 3)
+///     .speed(38400);
+/// assert_eq!(modes.get(Pty::TTY_OP_OSPEED), Some(38400));
+/// ```
+///
+/// This dereferences to a slice of modes, which is what
+/// `Channel::request_pty` takes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PtyModes {
+    modes: Vec<(Pty, u32)>,
+}
+
+/// Opcodes from 160 on are undefined, and stop the parsing of modes.
+const FIRST_UNDEFINED_OPCODE: u8 = 160;
+
+impl PtyModes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set `mode` to `value`, replacing its previous value.
+    pub fn set(mut self, mode: Pty, value: u32) -> Self {
+        self.insert(mode, value);
+        self
+    }
+
+    /// Set a flag, such as [`Pty::ECHO`] or [`Pty::ICANON`].
+    pub fn flag(self, mode: Pty, enabled: bool) -> Self {
+        self.set(mode, enabled.into())
+    }
+
+    /// Set both the input and output baud rates.
+    pub fn speed(self, baud: u32) -> Self {
+        self.set(Pty::TTY_OP_ISPEED, baud)
+            .set(Pty::TTY_OP_OSPEED, baud)
+    }
+
+    /// Set `mode` to `value`, and return its previous value.
+    /// [`Pty::TTY_OP_END`] is not a mode, and is ignored.
+    pub fn insert(&mut self, mode: Pty, value: u32) -> Option<u32> {
+        if mode == Pty::TTY_OP_END {
+            return None;
+        }
+        match self.modes.iter_mut().find(|(m, _)| *m == mode) {
+            Some((_, v)) => Some(std::mem::replace(v, value)),
+            None => {
+                self.modes.push((mode, value));
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, mode: Pty) -> Option<u32> {
+        let i = self.modes.iter().position(|(m, _)| *m == mode)?;
+        Some(self.modes.remove(i).1)
+    }
+
+    pub fn get(&self, mode: Pty) -> Option<u32> {
+        self.modes
+            .iter()
+            .find_map(|&(m, v)| if m == mode { Some(v) } else { None })
+    }
+
+    /// Whether a flag is set, or `None` if the client didn't send it.
+    pub fn is_set(&self, mode: Pty) -> Option<bool> {
+        self.get(mode).map(|v| v != 0)
+    }
+
+    /// Encode these modes as the string of a `pty-req`, terminated by
+    /// [`Pty::TTY_OP_END`].
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(5 * self.modes.len() + 1);
+        for &(mode, value) in &self.modes {
+            encoded.push(mode as u8);
+            encoded.extend_from_slice(&value.to_be_bytes());
+        }
+        encoded.push(Pty::TTY_OP_END as u8);
+        encoded
+    }
+
+    /// Decode the modes of a `pty-req`. Unknown opcodes are skipped,
+    /// and parsing stops at [`Pty::TTY_OP_END`], at an undefined opcode
+    /// (160 and above), or at a truncated mode.
+    pub fn decode(encoded: &[u8]) -> Self {
+        let mut modes = PtyModes::new();
+        let mut rest = encoded;
+        while let Some((&opcode, tail)) = rest.split_first() {
+            if opcode == Pty::TTY_OP_END as u8 || opcode >= FIRST_UNDEFINED_OPCODE {
+                break;
+            }
+            let (Some(value), Some(tail)) = (tail.get(..4), tail.get(4..)) else {
+                debug!("pty-req: truncated mode {:?}", opcode);
+                break;
+            };
+            match Pty::from_u8(opcode) {
+                Some(mode) => {
+                    modes.insert(mode, BigEndian::read_u32(value));
+                }
+                None => debug!("pty-req: unknown mode {:?}", opcode),
+            }
+            rest = tail;
+        }
+        modes
+    }
+}
+
+impl Deref for PtyModes {
+    type Target = [(Pty, u32)];
+    fn deref(&self) -> &Self::Target {
+        &self.modes
+    }
+}
+
+impl From<&[(Pty, u32)]> for PtyModes {
+    fn from(modes: &[(Pty, u32)]) -> Self {
+        modes.iter().copied().collect()
+    }
+}
+
+impl std::iter::FromIterator<(Pty, u32)> for PtyModes {
+    fn from_iter<I: IntoIterator<Item = (Pty, u32)>>(iter: I) -> Self {
+        let mut modes = PtyModes::new();
+        for (mode, value) in iter {
+            modes.insert(mode, value);
+        }
+        modes
+    }
+}
+
+/// The size of a terminal, in characters and in pixels (0 if unknown).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TerminalSize {
+    pub col_width: u32,
+    pub row_height: u32,
+    pub pix_width: u32,
+    pub pix_height: u32,
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let modes = PtyModes::new()
+            .flag(Pty::ECHO, true)
+            .set(Pty::VERASE, 0x7f)
+            .speed(9600)
+            .flag(Pty::ECHO, false);
+        assert_eq!(modes.len(), 4);
+        assert_eq!(modes.is_set(Pty::ECHO), Some(false));
+        assert_eq!(modes.is_set(Pty::ICANON), None);
+        let encoded = modes.encode();
+        assert_eq!(encoded.len(), 21);
+        assert_eq!(encoded.get(..5), Some(&[53, 0, 0, 0, 0][..]));
+        assert_eq!(PtyModes::decode(&encoded), modes);
+    }
+
+    #[test]
+    fn decode_unknown_and_truncated() {
+        // An unknown opcode (20), then ECHO, then a truncated ICANON.
+        let encoded = [20, 0, 0, 0, 1, 53, 0, 0, 0, 1, 51, 0, 0];
+        let modes = PtyModes::decode(&encoded);
+        assert_eq!(&modes[..], &[(Pty::ECHO, 1)]);
+        // Parsing stops at undefined opcodes.
+        let encoded = [160, 53, 0, 0, 0, 1];
+        assert!(PtyModes::decode(&encoded).is_empty());
+    }
+}
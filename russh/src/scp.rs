@@ -0,0 +1,402 @@
+//! Copying files with the scp protocol, for servers without an SFTP
+//! subsystem.
+//!
+//! The remote side runs `scp -t` (sink) when sending files, and
+//! `scp -f` (source) when receiving them. The wire protocol is the
+//! same in both directions: control lines such as `C0644 12 name`,
+//! `D0755 0 dir`, `E` and `T<mtime> 0 <atime> 0`, each acknowledged
+//! with a zero byte.
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::logging::{debug, warn};
+use crate::{Channel, ChannelId, ChannelMsg, Error};
+
+/// Options of [`send_file`] and [`recv_file`].
+#[derive(Debug, Clone, Default)]
+pub struct ScpOptions {
+    /// Copy directories recursively (`scp -r`).
+    pub recursive: bool,
+    /// Preserve the permissions and the modification and access times
+    /// (`scp -p`).
+    pub preserve: bool,
+}
+
+/// Copy the local file or directory `local` to `remote`, using a
+/// session channel.
+pub async fn send_file<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static>(
+    channel: Channel<S>,
+    local: impl AsRef<Path>,
+    remote: &str,
+    options: &ScpOptions,
+) -> Result<(), Error> {
+    let mut stream = start(channel, "-t", remote, options).await?;
+    source(&mut stream, local.as_ref(), options).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Copy the remote file or directory `remote` to `local`, using a
+/// session channel. If `local` is an existing directory, the copy is
+/// created inside it.
+pub async fn recv_file<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static>(
+    channel: Channel<S>,
+    remote: &str,
+    local: impl AsRef<Path>,
+    options: &ScpOptions,
+) -> Result<(), Error> {
+    let mut stream = start(channel, "-f", remote, options).await?;
+    sink(&mut stream, local.as_ref(), options).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Run the remote `scp` in `mode`.
+async fn start<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static>(
+    mut channel: Channel<S>,
+    mode: &str,
+    remote: &str,
+    options: &ScpOptions,
+) -> Result<crate::ChannelStream<S>, Error> {
+    let mut command = String::from("scp");
+    if options.recursive {
+        command.push_str(" -r")
+    }
+    if options.preserve {
+        command.push_str(" -p")
+    }
+    command.push(' ');
+    command.push_str(mode);
+    command.push_str(" -- ");
+    command.push_str(&shell_quote(remote));
+    debug!("scp: {}", command);
+    channel.exec(true, command).await?;
+    loop {
+        match channel.wait().await {
+            Some(ChannelMsg::Success) => break,
+            Some(ChannelMsg::Failure) => return Err(Error::RequestDenied),
+            Some(msg) => debug!("scp: ignoring {:?}", msg),
+            None => return Err(Error::HUP),
+        }
+    }
+    Ok(channel.into_stream())
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Read a control line, without the final `\n`.
+async fn read_line<R: AsyncRead + Unpin>(r: &mut R) -> Result<String, Error> {
+    let mut line = Vec::new();
+    loop {
+        match r.read_u8().await? {
+            b'\n' => break,
+            c => line.push(c),
+        }
+        if line.len() > 4096 {
+            return Err(Error::Scp("control line too long".to_string()));
+        }
+    }
+    String::from_utf8(line).map_err(|_| Error::Scp("invalid control line".to_string()))
+}
+
+/// Read the acknowledgement of the last message.
+async fn read_ack<R: AsyncRead + Unpin>(r: &mut R) -> Result<(), Error> {
+    match r.read_u8().await? {
+        0 => Ok(()),
+        1 | 2 => Err(Error::Scp(read_line(r).await?)),
+        _ => Err(Error::Inconsistent),
+    }
+}
+
+async fn write_line<W: AsyncWrite + Unpin>(w: &mut W, line: &str) -> Result<(), Error> {
+    w.write_all(line.as_bytes()).await?;
+    w.write_all(b"\n").await?;
+    w.flush().await?;
+    Ok(())
+}
+
+async fn ack<W: AsyncWrite + Unpin>(w: &mut W) -> Result<(), Error> {
+    w.write_all(&[0]).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+/// Report a fatal error to the other side, and return it.
+async fn fail<W: AsyncWrite + Unpin>(w: &mut W, message: String) -> Error {
+    let _ = w.write_all(&[2]).await;
+    let _ = write_line(w, &message).await;
+    Error::Scp(message)
+}
+
+fn mode(metadata: &std::fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        if metadata.is_dir() {
+            0o755
+        } else if metadata.permissions().readonly() {
+            0o444
+        } else {
+            0o644
+        }
+    }
+}
+
+fn unix_time(t: std::io::Result<std::time::SystemTime>) -> i64 {
+    t.ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+type BoxFuture<'a> = Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>>;
+
+/// Send `path` to a sink, which has to acknowledge its start first.
+pub(crate) async fn source<S: AsyncRead + AsyncWrite + Unpin + Send>(
+    stream: &mut S,
+    path: &Path,
+    options: &ScpOptions,
+) -> Result<(), Error> {
+    read_ack(stream).await?;
+    send_path(stream, path, options).await
+}
+
+fn send_path<'a, S: AsyncRead + AsyncWrite + Unpin + Send>(
+    stream: &'a mut S,
+    path: &'a Path,
+    options: &'a ScpOptions,
+) -> BoxFuture<'a> {
+    Box::pin(async move {
+        let metadata = tokio::fs::metadata(path).await?;
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            return Err(Error::Scp(format!("{}: invalid file name", path.display())));
+        };
+        if metadata.is_dir() && !options.recursive {
+            return Err(Error::Scp(format!("{}: is a directory", path.display())));
+        }
+        if options.preserve {
+            let line = format!(
+                "T{} 0 {} 0",
+                unix_time(metadata.modified()),
+                unix_time(metadata.accessed())
+            );
+            write_line(stream, &line).await?;
+            read_ack(stream).await?;
+        }
+        if metadata.is_dir() {
+            write_line(stream, &format!("D{:04o} 0 {}", mode(&metadata), name)).await?;
+            read_ack(stream).await?;
+            let mut dir = tokio::fs::read_dir(path).await?;
+            while let Some(entry) = dir.next_entry().await? {
+                let path = entry.path();
+                send_path(&mut *stream, &path, options).await?;
+            }
+            write_line(stream, "E").await?;
+            read_ack(stream).await
+        } else {
+            let len = metadata.len();
+            let line = format!("C{:04o} {} {}", mode(&metadata), len, name);
+            write_line(stream, &line).await?;
+            read_ack(stream).await?;
+            let mut file = tokio::fs::File::open(path).await?.take(len);
+            let n = tokio::io::copy(&mut file, stream).await?;
+            if n < len {
+                return Err(fail(stream, format!("{}: file shrank", path.display())).await);
+            }
+            ack(stream).await?;
+            read_ack(stream).await
+        }
+    })
+}
+
+/// Parse the `<mode> <size> <name>` of a `C` or `D` line.
+fn parse_entry(line: &str) -> Option<(u32, u64, &str)> {
+    let mut fields = line.splitn(3, ' ');
+    let mode = u32::from_str_radix(fields.next()?, 8).ok()?;
+    let size = fields.next()?.parse().ok()?;
+    let name = fields.next()?;
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return None;
+    }
+    Some((mode, size, name))
+}
+
+/// Parse the `<mtime> 0 <atime> 0` of a `T` line.
+fn parse_times(line: &str) -> Option<(i64, i64)> {
+    let mut fields = line.split(' ');
+    let mtime = fields.next()?.parse().ok()?;
+    fields.next()?;
+    let atime = fields.next()?.parse().ok()?;
+    Some((mtime, atime))
+}
+
+fn set_times(path: &Path, times: Option<(i64, i64)>) -> Result<(), Error> {
+    if let Some((mtime, atime)) = times {
+        filetime::set_file_times(
+            path,
+            filetime::FileTime::from_unix_time(atime, 0),
+            filetime::FileTime::from_unix_time(mtime, 0),
+        )?;
+    }
+    Ok(())
+}
+
+#[allow(unused_variables)]
+async fn set_mode(path: &Path, mode: u32) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777)).await?;
+    }
+    Ok(())
+}
+
+/// Receive files from a source into `target`.
+pub(crate) async fn sink<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    target: &Path,
+    options: &ScpOptions,
+) -> Result<(), Error> {
+    let target_is_dir = tokio::fs::metadata(target)
+        .await
+        .map(|m| m.is_dir())
+        .unwrap_or(false);
+    // The directories being received, with their times.
+    let mut dirs: Vec<(PathBuf, Option<(i64, i64)>)> = Vec::new();
+    let mut times = None;
+    ack(stream).await?;
+    loop {
+        let mut kind = [0];
+        if stream.read(&mut kind).await? == 0 {
+            return if dirs.is_empty() {
+                Ok(())
+            } else {
+                Err(Error::HUP)
+            };
+        }
+        let [kind] = kind;
+        let line = read_line(stream).await?;
+        match kind {
+            1 => {
+                warn!("scp: {}", line);
+                continue;
+            }
+            2 => return Err(Error::Scp(line)),
+            b'T' => {
+                let Some(t) = parse_times(&line) else {
+                    return Err(fail(stream, format!("invalid times: {:?}", line)).await);
+                };
+                times = Some(t);
+            }
+            b'E' => {
+                let Some((path, times)) = dirs.pop() else {
+                    return Err(fail(stream, "unexpected E".to_string()).await);
+                };
+                set_times(&path, times)?;
+            }
+            b'C' | b'D' => {
+                let Some((mode, size, name)) = parse_entry(&line) else {
+                    return Err(fail(stream, format!("invalid entry: {:?}", line)).await);
+                };
+                let path = match dirs.last() {
+                    Some((dir, _)) => dir.join(name),
+                    None if target_is_dir => target.join(name),
+                    None => target.to_path_buf(),
+                };
+                if kind == b'D' {
+                    if !options.recursive {
+                        return Err(fail(stream, format!("{}: is a directory", name)).await);
+                    }
+                    if !tokio::fs::metadata(&path)
+                        .await
+                        .map(|m| m.is_dir())
+                        .unwrap_or(false)
+                    {
+                        tokio::fs::create_dir(&path).await?;
+                    }
+                    if options.preserve {
+                        set_mode(&path, mode).await?;
+                    }
+                    dirs.push((path, times.take()));
+                } else {
+                    let mut file = tokio::fs::File::create(&path).await?;
+                    ack(stream).await?;
+                    let n = tokio::io::copy(&mut (&mut *stream).take(size), &mut file).await?;
+                    file.flush().await?;
+                    if n < size {
+                        return Err(Error::HUP);
+                    }
+                    read_ack(stream).await?;
+                    if options.preserve {
+                        set_mode(&path, mode).await?;
+                    }
+                    set_times(&path, times.take())?;
+                }
+            }
+            _ => return Err(Error::Inconsistent),
+        }
+        ack(stream).await?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_scp_source_sink() {
+        let base = std::env::temp_dir().join(format!("russh-scp-{}", std::process::id()));
+        let from = base.join("from");
+        std::fs::create_dir_all(from.join("sub")).unwrap();
+        std::fs::write(from.join("a"), b"first file").unwrap();
+        std::fs::write(from.join("sub/b"), b"").unwrap();
+        filetime::set_file_mtime(
+            from.join("a"),
+            filetime::FileTime::from_unix_time(1_000_000, 0),
+        )
+        .unwrap();
+        let to = base.join("to");
+        std::fs::create_dir_all(&to).unwrap();
+
+        let options = ScpOptions {
+            recursive: true,
+            preserve: true,
+        };
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        let send = async {
+            source(&mut a, &from, &options).await?;
+            // Close the stream, which ends the sink.
+            a.shutdown().await?;
+            Ok::<_, Error>(())
+        };
+        let (sent, received) = tokio::join!(send, sink(&mut b, &to, &options));
+        sent.unwrap();
+        received.unwrap();
+
+        assert_eq!(std::fs::read(to.join("from/a")).unwrap(), b"first file");
+        assert_eq!(std::fs::read(to.join("from/sub/b")).unwrap(), b"");
+        let mtime = std::fs::metadata(to.join("from/a")).unwrap().modified();
+        assert_eq!(unix_time(mtime), 1_000_000);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_scp_parse() {
+        assert_eq!(parse_entry("0644 12 a b"), Some((0o644, 12, "a b")));
+        assert_eq!(parse_entry("0644 12 ../x"), None);
+        assert_eq!(parse_times("1 0 2 0"), Some((1, 2)));
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+}
@@ -0,0 +1,177 @@
+//! Address ranges used to accept or refuse connections by source
+//! address, before any SSH traffic is exchanged.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+/// A range of IPv4 or IPv6 addresses in CIDR notation, such as
+/// `192.0.2.0/24` or `2001:db8::/32`. A single address is a range of
+/// one address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    /// The range of addresses sharing the first `prefix_len` bits of
+    /// `addr`, or `None` if `prefix_len` is longer than the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return None;
+        }
+        Some(IpCidr { addr, prefix_len })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Whether `addr` is in this range. IPv4 addresses mapped to IPv6
+    /// (`::ffff:192.0.2.1`), as seen on dual-stack listeners, match
+    /// IPv4 ranges.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, unmap(*addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => prefix_matches(
+                u32::from(net).into(),
+                u32::from(addr).into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(u128::from(net), u128::from(addr), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix_len` of the `bits` lowest bits of `a` and
+/// `b` are equal.
+fn prefix_matches(a: u128, b: u128, bits: u8, prefix_len: u8) -> bool {
+    let ignored = u32::from(bits.saturating_sub(prefix_len));
+    a.checked_shr(ignored).unwrap_or(0) == b.checked_shr(ignored).unwrap_or(0)
+}
+
+pub(super) fn unmap(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6),
+        },
+        v4 => v4,
+    }
+}
+
+impl From<IpAddr> for IpCidr {
+    fn from(addr: IpAddr) -> Self {
+        let prefix_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        IpCidr { addr, prefix_len }
+    }
+}
+
+impl From<Ipv4Addr> for IpCidr {
+    fn from(addr: Ipv4Addr) -> Self {
+        IpAddr::V4(addr).into()
+    }
+}
+
+impl From<Ipv6Addr> for IpCidr {
+    fn from(addr: Ipv6Addr) -> Self {
+        IpAddr::V6(addr).into()
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || crate::Error::InvalidAddressRange(s.to_string());
+        match s.split_once('/') {
+            Some((addr, len)) => {
+                let addr = addr.parse().map_err(|_| invalid())?;
+                let len = len.parse().map_err(|_| invalid())?;
+                IpCidr::new(addr, len).ok_or_else(invalid)
+            }
+            None => Ok(IpCidr::from(s.parse::<IpAddr>().map_err(|_| invalid())?)),
+        }
+    }
+}
+
+impl fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Whether `config` lets `peer_addr` connect: it must not be in
+/// `denied_addresses`, and must be in `allowed_addresses` unless that
+/// list is empty. Connections of unknown origin are only accepted if
+/// both lists are empty.
+pub(crate) fn is_allowed(config: &super::Config, peer_addr: Option<SocketAddr>) -> bool {
+    let Some(peer_addr) = peer_addr else {
+        return config.allowed_addresses.is_empty() && config.denied_addresses.is_empty();
+    };
+    let ip = peer_addr.ip();
+    !config.denied_addresses.iter().any(|net| net.contains(&ip))
+        && (config.allowed_addresses.is_empty()
+            || config.allowed_addresses.iter().any(|net| net.contains(&ip)))
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    #[test]
+    fn test_cidr_contains() {
+        let net: IpCidr = "192.0.2.0/24".parse().unwrap();
+        assert!(net.contains(&"192.0.2.77".parse().unwrap()));
+        assert!(!net.contains(&"192.0.3.1".parse().unwrap()));
+        assert!(net.contains(&"::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!net.contains(&"2001:db8::1".parse().unwrap()));
+
+        let net: IpCidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!net.contains(&"2001:db9::1".parse().unwrap()));
+
+        let any: IpCidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&"203.0.113.9".parse().unwrap()));
+
+        let one: IpCidr = "10.1.2.3".parse().unwrap();
+        assert_eq!(one.to_string(), "10.1.2.3/32");
+        assert!(one.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(!one.contains(&"10.1.2.4".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("example.com/8".parse::<IpCidr>().is_err());
+    }
+
+    #[test]
+    fn test_is_allowed() {
+        let mut config = super::super::Config::default();
+        let peer = |s: &str| Some(SocketAddr::new(s.parse().unwrap(), 2222));
+        assert!(is_allowed(&config, peer("198.51.100.1")));
+        assert!(is_allowed(&config, None));
+
+        config.allowed_addresses = vec!["198.51.100.0/24".parse().unwrap()];
+        config.denied_addresses = vec!["198.51.100.13".parse().unwrap()];
+        assert!(is_allowed(&config, peer("198.51.100.1")));
+        assert!(!is_allowed(&config, peer("198.51.100.13")));
+        assert!(!is_allowed(&config, peer("203.0.113.1")));
+        assert!(!is_allowed(&config, None));
+    }
+}
@@ -17,23 +17,22 @@ use std::cell::RefCell;
 use std::time::SystemTime;
 
 use auth::*;
-use byteorder::{BigEndian, ByteOrder};
 use bytes::Bytes;
 use cert::PublicKeyOrCertificate;
-use log::{debug, error, info, trace, warn};
 use negotiation::Select;
-use russh_keys::helpers::NameList;
+use russh_keys::helpers::{EncodedExt, NameList};
+use russh_keys::key::SshSignature;
 use russh_keys::map_err;
-use signature::Verifier;
 use ssh_encoding::{Decode, Encode, Reader};
-use ssh_key::{PublicKey, Signature};
+use ssh_key::{Algorithm, HashAlg, PublicKey};
 use tokio::time::Instant;
 use {msg, negotiation};
 
 use super::super::*;
 use super::*;
+use crate::logging::{debug, error, info, trace, warn};
 use crate::msg::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED;
-use crate::parsing::{ChannelOpenConfirmation, ChannelType, OpenChannelMessage};
+use crate::parsing::{decode_slice, ChannelOpenConfirmation, ChannelType, OpenChannelMessage};
 
 impl Session {
     /// Returns false iff a request was rejected.
@@ -70,7 +69,9 @@ impl Session {
                     negotiation::Server::read_kex(
                         buf,
                         &self.common.config.as_ref().preferred,
-                        Some(&self.common.config.as_ref().keys),
+                        self.common.config.algorithm_policy.as_ref(),
+                        Some(&self.common.config.host_key_algorithms()),
+                        &self.common.config.compression,
                     )?,
                     &enc.session_id,
                 );
@@ -90,12 +91,16 @@ impl Session {
 
         match enc.rekey.take() {
             Some(Kex::Dh(kexdh)) => {
-                enc.rekey = Some(kexdh.parse(
-                    self.common.config.as_ref(),
-                    &mut *self.common.cipher.local_to_remote,
-                    buf,
-                    &mut self.common.write_buffer,
-                )?);
+                enc.rekey = Some(
+                    kexdh
+                        .parse(
+                            self.common.config.as_ref(),
+                            &mut *self.common.cipher.local_to_remote,
+                            buf,
+                            &mut self.common.write_buffer,
+                        )
+                        .await?,
+                );
                 if let Some(Kex::Keys(_)) = enc.rekey {
                     // just sent NEWKEYS
                     self.common.maybe_reset_seqn();
@@ -119,6 +124,10 @@ impl Session {
                 self.pending_reads = pending;
                 self.pending_len = 0;
                 self.common.newkeys(newkeys);
+                if let Some(ref stats) = self.common.config.stats {
+                    stats.kex_completed(true)
+                }
+                self.report_negotiated(handler).await?;
                 if self.common.strict_kex {
                     *seqn = Wrapping(0);
                 }
@@ -135,7 +144,12 @@ impl Session {
                 enc.rekey = Some(Kex::Init(k));
 
                 self.pending_len += buf.len() as u32;
-                if self.pending_len > 2 * self.target_window_size {
+                if self.pending_len
+                    > self
+                        .target_window_size
+                        .max(self.common.config.max_window_size)
+                        .saturating_mul(2)
+                {
                     return Err(Error::Pending.into());
                 }
                 self.pending_reads.push(CryptoVec::from_slice(buf));
@@ -154,8 +168,74 @@ impl Session {
         handler: &mut H,
         buf: &[u8],
     ) -> Result<(), H::Error> {
-        let rejection_wait_until =
-            tokio::time::Instant::now() + self.common.config.auth_rejection_time;
+        let rejections = self.auth_rejections();
+        self.dispatch_packet(handler, buf).await?;
+        if self.auth_rejections() > rejections {
+            self.auth_rejected(handler).await?;
+        }
+        Ok(())
+    }
+
+    /// Number of authentication requests rejected so far.
+    fn auth_rejections(&self) -> usize {
+        match self.common.encrypted {
+            Some(Encrypted {
+                state: EncryptedState::WaitingAuthRequest(ref auth),
+                ..
+            }) => auth.rejection_count,
+            _ => 0,
+        }
+    }
+
+    /// Count a rejected authentication request, and disconnect once
+    /// [`Config::max_auth_attempts`] is reached. Like in OpenSSH, the
+    /// initial `none` request doesn't count.
+    async fn auth_rejected<H: Handler + Send>(&mut self, handler: &mut H) -> Result<(), H::Error> {
+        let (user, method) = self.last_auth_request.clone().unwrap_or_default();
+        if method == "none" && self.common.auth_attempts <= 1 {
+            return Ok(());
+        }
+        self.auth_failures += 1;
+        if let Some(ref stats) = self.common.config.stats {
+            stats.auth_failed()
+        }
+        debug!(
+            "authentication failure {} for {:?}",
+            self.auth_failures, user
+        );
+        handler.auth_failed(&user, &method, self).await?;
+        if self.auth_failures >= self.common.config.max_auth_attempts {
+            info!("too many authentication failures for {:?}", user);
+            self.disconnect(
+                Disconnect::NoMoreAuthMethodsAvailable,
+                "Too many authentication failures",
+                "",
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Start the `zlib@openssh.com` compression, which follows the
+    /// `USERAUTH_SUCCESS` message in both directions: that message,
+    /// already queued, is sent before the compressor starts.
+    fn start_delayed_compression(&mut self) -> Result<(), crate::Error> {
+        self.flush()?;
+        if let Some(ref mut enc) = self.common.encrypted {
+            enc.client_compression
+                .start_decompress(true, &mut enc.decompress);
+            enc.server_compression
+                .start_compress(true, &mut enc.compress);
+        }
+        Ok(())
+    }
+
+    async fn dispatch_packet<H: Handler + Send>(
+        &mut self,
+        handler: &mut H,
+        buf: &[u8],
+    ) -> Result<(), H::Error> {
+        let rejection_wait_until = tokio::time::Instant::now()
+            + self.common.config.auth_rejection_delay(self.auth_failures);
         let initial_none_rejection_wait_until = if self.common.auth_attempts == 0 {
             tokio::time::Instant::now()
                 + self
@@ -171,6 +251,12 @@ impl Session {
         let enc = self.common.encrypted.as_mut().unwrap();
         // If we've successfully read a packet.
         match (&mut enc.state, buf.split_first()) {
+            (EncryptedState::WaitingAuthServiceRequest { .. }, Some((&msg::EXT_INFO, mut r))) => {
+                let extensions = Extensions::parse(&mut r)?;
+                debug!("client extensions: {:?}", extensions);
+                self.client_extensions.extend(&extensions);
+                handler.ext_info(&extensions, self).await
+            }
             (
                 EncryptedState::WaitingAuthServiceRequest {
                     ref mut accepted, ..
@@ -180,9 +266,13 @@ impl Session {
                 let request = map_err!(String::decode(&mut r))?;
                 debug!("request: {:?}", request);
                 if request == "ssh-userauth" {
+                    let banner = handler.auth_banner().await?;
                     let auth_request = server_accept_service(
-                        self.common.config.as_ref().auth_banner,
+                        banner
+                            .as_deref()
+                            .or(self.common.config.as_ref().auth_banner),
                         self.common.config.as_ref().methods,
+                        &self.common.config.as_ref().required_methods,
                         &mut enc.write,
                     )?;
                     *accepted = true;
@@ -191,6 +281,7 @@ impl Session {
                 Ok(())
             }
             (EncryptedState::WaitingAuthRequest(_), Some((&msg::USERAUTH_REQUEST, mut r))) => {
+                self.last_auth_request = Some(auth_request_user_method(r)?);
                 enc.server_read_auth_request(
                     rejection_wait_until,
                     initial_none_rejection_wait_until,
@@ -198,15 +289,64 @@ impl Session {
                     buf,
                     &mut r,
                     &mut self.common.auth_user,
+                    &self.common.config.trusted_user_ca_keys,
+                    &self.common.config.key_algorithms(),
                 )
                 .await?;
                 self.common.auth_attempts += 1;
                 if let EncryptedState::InitCompression = enc.state {
-                    enc.client_compression.init_decompress(&mut enc.decompress);
+                    self.start_delayed_compression()?;
+                    if self.common.config.announce_host_keys {
+                        self.announce_host_keys()?;
+                    }
                     handler.auth_succeeded(self).await?;
                 }
                 Ok(())
             }
+            #[cfg(feature = "gssapi")]
+            (
+                EncryptedState::WaitingAuthRequest(ref mut auth),
+                Some((&msg::USERAUTH_GSSAPI_TOKEN, mut r)),
+            ) if matches!(auth.current, Some(CurrentRequest::GssapiWithMic { .. })) => {
+                read_gssapi_token(rejection_wait_until, &mut enc.write, auth, &mut r).await?;
+                Ok(())
+            }
+            #[cfg(feature = "gssapi")]
+            (
+                EncryptedState::WaitingAuthRequest(ref mut auth),
+                Some((&msg::USERAUTH_GSSAPI_MIC, mut r)),
+            ) => {
+                let resp = read_gssapi_mic(
+                    rejection_wait_until,
+                    handler,
+                    &mut enc.write,
+                    auth,
+                    &mut self.common.auth_user,
+                    &enc.session_id,
+                    &mut r,
+                )
+                .await?;
+                if resp {
+                    enc.state = EncryptedState::InitCompression;
+                    self.start_delayed_compression()?;
+                    if self.common.config.announce_host_keys {
+                        self.announce_host_keys()?;
+                    }
+                    handler.auth_succeeded(self).await
+                } else {
+                    Ok(())
+                }
+            }
+            #[cfg(feature = "gssapi")]
+            (
+                EncryptedState::WaitingAuthRequest(ref mut auth),
+                Some((&msg::USERAUTH_GSSAPI_ERRTOK, _)),
+            ) => {
+                // The client failed to process our last token.
+                debug!("gssapi error token");
+                reject_auth_request(rejection_wait_until, &mut enc.write, auth).await?;
+                Ok(())
+            }
             (
                 EncryptedState::WaitingAuthRequest(ref mut auth),
                 Some((&msg::USERAUTH_INFO_RESPONSE, mut r)),
@@ -216,20 +356,22 @@ impl Session {
                     handler,
                     &mut enc.write,
                     auth,
-                    &self.common.auth_user,
+                    &mut self.common.auth_user,
                     &mut r,
                 )
                 .await?;
                 if resp {
                     enc.state = EncryptedState::InitCompression;
-                    enc.client_compression.init_decompress(&mut enc.decompress);
+                    self.start_delayed_compression()?;
+                    if self.common.config.announce_host_keys {
+                        self.announce_host_keys()?;
+                    }
                     handler.auth_succeeded(self).await
                 } else {
                     Ok(())
                 }
             }
             (EncryptedState::InitCompression, Some((msg, mut r))) => {
-                enc.server_compression.init_compress(&mut enc.compress);
                 enc.state = EncryptedState::Authenticated;
                 self.server_read_authenticated(handler, *msg, &mut r).await
             }
@@ -241,9 +383,18 @@ impl Session {
     }
 }
 
+/// The user and method of a `USERAUTH_REQUEST`.
+fn auth_request_user_method(mut r: &[u8]) -> Result<(String, String), crate::Error> {
+    let user = map_err!(String::decode(&mut r))?;
+    map_err!(String::decode(&mut r))?;
+    let method = map_err!(String::decode(&mut r))?;
+    Ok((user, method))
+}
+
 fn server_accept_service(
     banner: Option<&str>,
     methods: MethodSet,
+    required_methods: &[MethodSet],
     buffer: &mut CryptoVec,
 ) -> Result<AuthRequest, crate::Error> {
     push_packet!(buffer, {
@@ -259,16 +410,23 @@ fn server_accept_service(
         })
     }
 
+    let (methods, next_methods) = match required_methods.split_first() {
+        Some((first, rest)) => (*first, Some(rest.iter().copied().collect())),
+        None => (methods, None),
+    };
     Ok(AuthRequest {
         methods,
         partial_success: false, // not used immediately anway.
         current: None,
         rejection_count: 0,
+        next_methods,
+        partial_user: None,
     })
 }
 
 impl Encrypted {
     /// Returns false iff the request was rejected.
+    #[allow(clippy::too_many_arguments)]
     async fn server_read_auth_request<H: Handler + Send>(
         &mut self,
         mut until: Instant,
@@ -277,6 +435,8 @@ impl Encrypted {
         original_packet: &[u8],
         r: &mut &[u8],
         auth_user: &mut String,
+        trusted_cas: &[PublicKey],
+        sig_algs: &[Algorithm],
     ) -> Result<(), H::Error> {
         // https://tools.ietf.org/html/rfc4252#section-5
         let user = map_err!(String::decode(r))?;
@@ -285,6 +445,38 @@ impl Encrypted {
         debug!("name: {user:?} {service_name:?} {method:?}",);
 
         if service_name == "ssh-connection" {
+            // With several required steps, only the methods of the
+            // current step are allowed, and the user cannot change.
+            let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state {
+                a
+            } else {
+                unreachable!()
+            };
+            if auth_request.next_methods.is_some() {
+                let allowed =
+                    MethodSet::from_str(&method).is_some_and(|m| auth_request.methods.contains(m));
+                let same_user = auth_request
+                    .partial_user
+                    .as_ref()
+                    .map_or(true, |u| *u == user);
+                if !allowed || !same_user {
+                    debug!("{method:?} for {user:?} not allowed at this step");
+                    let until = if method == "none" {
+                        initial_auth_until
+                    } else {
+                        until
+                    };
+                    reject_auth_request(until, &mut self.write, auth_request).await?;
+                    return Ok(());
+                }
+            }
+
+            #[cfg(feature = "gssapi")]
+            if method == "gssapi-with-mic" {
+                return self
+                    .server_read_auth_request_gssapi(until, handler, auth_user, &user, r)
+                    .await;
+            }
             if method == "password" {
                 let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state
                 {
@@ -298,8 +490,9 @@ impl Encrypted {
                 let password = map_err!(String::decode(r))?;
                 let auth = handler.auth_password(&user, &password).await?;
                 if let Auth::Accept = auth {
-                    server_auth_request_success(&mut self.write);
-                    self.state = EncryptedState::InitCompression;
+                    if server_auth_request_success(auth_request, auth_user, &mut self.write)? {
+                        self.state = EncryptedState::InitCompression;
+                    }
                 } else {
                     auth_user.clear();
                     if let Auth::Reject {
@@ -322,6 +515,19 @@ impl Encrypted {
                     auth_user,
                     &user,
                     r,
+                    trusted_cas,
+                    sig_algs,
+                )
+                .await
+            } else if method == "hostbased" {
+                self.server_read_auth_request_hostbased(
+                    until,
+                    handler,
+                    original_packet,
+                    auth_user,
+                    &user,
+                    r,
+                    sig_algs,
                 )
                 .await
             } else if method == "none" {
@@ -336,8 +542,11 @@ impl Encrypted {
 
                 let auth = handler.auth_none(&user).await?;
                 if let Auth::Accept = auth {
-                    server_auth_request_success(&mut self.write);
-                    self.state = EncryptedState::InitCompression;
+                    auth_user.clear();
+                    auth_user.push_str(&user);
+                    if server_auth_request_success(auth_request, auth_user, &mut self.write)? {
+                        self.state = EncryptedState::InitCompression;
+                    }
                 } else {
                     auth_user.clear();
                     if let Auth::Reject {
@@ -366,11 +575,20 @@ impl Encrypted {
                 debug!("{:?}", submethods);
                 auth_request.current = Some(CurrentRequest::KeyboardInteractive {
                     submethods: submethods.to_string(),
+                    pending_prompts: None,
                 });
                 let auth = handler
                     .auth_keyboard_interactive(&user, &submethods, None)
                     .await?;
-                if reply_userauth_info_response(until, auth_request, &mut self.write, auth).await? {
+                if reply_userauth_info_response(
+                    until,
+                    auth_request,
+                    auth_user,
+                    &mut self.write,
+                    auth,
+                )
+                .await?
+                {
                     self.state = EncryptedState::InitCompression
                 }
                 Ok(())
@@ -397,6 +615,7 @@ thread_local! {
 }
 
 impl Encrypted {
+    #[allow(clippy::too_many_arguments)]
     async fn server_read_auth_request_pk<H: Handler + Send>(
         &mut self,
         until: Instant,
@@ -405,6 +624,8 @@ impl Encrypted {
         auth_user: &mut String,
         user: &str,
         r: &mut &[u8],
+        trusted_cas: &[PublicKey],
+        sig_algs: &[Algorithm],
     ) -> Result<(), H::Error> {
         let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state {
             a
@@ -427,6 +648,18 @@ impl Encrypted {
                 let pubkey = match pk_or_cert {
                     PublicKeyOrCertificate::PublicKey(ref pk) => pk.clone(),
                     PublicKeyOrCertificate::Certificate(ref cert) => {
+                        if !trusted_cas.is_empty() {
+                            if let Err(e) = russh_keys::certificate::validate_user_certificate(
+                                cert,
+                                trusted_cas,
+                                user,
+                            ) {
+                                warn!("Certificate rejected: {}", e);
+                                reject_auth_request(until, &mut self.write, auth_request).await?;
+                                return Ok(());
+                            }
+                        }
+
                         // Validate certificate expiration
                         let now = SystemTime::now();
                         if now < cert.valid_after_time() || now > cert.valid_before_time() {
@@ -460,7 +693,37 @@ impl Encrypted {
 
                     let encoded_signature = map_err!(Vec::<u8>::decode(r))?;
 
-                    let sig = map_err!(Signature::decode(&mut encoded_signature.as_slice()))?;
+                    // Security key signatures also carry flags and a counter.
+                    let sig = if russh_keys::sk::is_sk_key(&pubkey) {
+                        None
+                    } else {
+                        Some(map_err!(SshSignature::decode(
+                            &mut encoded_signature.as_slice()
+                        ))?)
+                    };
+
+                    // The signature must match the requested algorithm,
+                    // and RSA hashes must be among the ones announced in
+                    // `server-sig-algs` (RFC 8332).
+                    if let Some(ref sig) = sig {
+                        let algorithm = pk_or_cert.signature_algorithm(&pubkey_algo);
+                        let allowed = match algorithm {
+                            Some(ref algorithm @ Algorithm::Rsa { .. }) => {
+                                sig_algs.contains(algorithm)
+                            }
+                            Some(_) => true,
+                            None => false,
+                        };
+                        if !allowed || algorithm != Some(sig.algorithm()) {
+                            debug!(
+                                "signature algorithm {:?} not allowed for {:?}",
+                                sig.algorithm(),
+                                pubkey_algo
+                            );
+                            reject_auth_request(until, &mut self.write, auth_request).await?;
+                            return Ok(());
+                        }
+                    }
 
                     // SAFETY: both original_packet and pos0 are coming
                     // from the same allocation (pos0 is derived from
@@ -491,7 +754,14 @@ impl Encrypted {
                             map_err!(session_id.encode(&mut *buf))?;
                             buf.extend(init);
 
-                            Ok(Verifier::verify(&pubkey, &buf, &sig).is_ok())
+                            Ok(match sig {
+                                Some(ref sig) => {
+                                    russh_keys::key::verify_signature(&pubkey, &buf, sig).is_ok()
+                                }
+                                // Like OpenSSH, require the user to touch the key.
+                                None => russh_keys::sk::verify(&pubkey, &buf, &encoded_signature)
+                                    .is_ok_and(|info| info.user_present()),
+                            })
                         })? {
                             debug!("signature verified");
                             let auth = match pk_or_cert {
@@ -504,8 +774,13 @@ impl Encrypted {
                             };
 
                             if auth == Auth::Accept {
-                                server_auth_request_success(&mut self.write);
-                                self.state = EncryptedState::InitCompression;
+                                if server_auth_request_success(
+                                    auth_request,
+                                    auth_user,
+                                    &mut self.write,
+                                )? {
+                                    self.state = EncryptedState::InitCompression;
+                                }
                             } else {
                                 if let Auth::Reject {
                                     proceed_with_methods: Some(proceed_with_methods),
@@ -576,6 +851,228 @@ impl Encrypted {
             },
         }
     }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn server_read_auth_request_hostbased<H: Handler + Send>(
+        &mut self,
+        until: Instant,
+        handler: &mut H,
+        original_packet: &[u8],
+        auth_user: &mut String,
+        user: &str,
+        r: &mut &[u8],
+        sig_algs: &[Algorithm],
+    ) -> Result<(), H::Error> {
+        let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state {
+            a
+        } else {
+            unreachable!()
+        };
+
+        // https://tools.ietf.org/html/rfc4252#section-9
+        let pubkey_algo = map_err!(String::decode(r))?;
+        let pubkey_key = map_err!(Bytes::decode(r))?;
+        let client_host = map_err!(String::decode(r))?;
+        let client_user = map_err!(String::decode(r))?;
+        let pos0 = r.as_ptr();
+        let encoded_signature = map_err!(Vec::<u8>::decode(r))?;
+
+        let pubkey = match PublicKeyOrCertificate::decode(&pubkey_algo, &pubkey_key) {
+            Ok(PublicKeyOrCertificate::PublicKey(pubkey)) => pubkey,
+            Ok(PublicKeyOrCertificate::Certificate(_)) => {
+                debug!("hostbased: host certificates are not supported");
+                reject_auth_request(until, &mut self.write, auth_request).await?;
+                return Ok(());
+            }
+            Err(e) => {
+                debug!("hostbased: public key error: {e}");
+                reject_auth_request(until, &mut self.write, auth_request).await?;
+                return Ok(());
+            }
+        };
+        let Ok(sig) = SshSignature::decode(&mut encoded_signature.as_slice()) else {
+            reject_auth_request(until, &mut self.write, auth_request).await?;
+            return Ok(());
+        };
+
+        let algorithm = Algorithm::new(&pubkey_algo).ok();
+        let allowed = match algorithm {
+            Some(ref algorithm @ Algorithm::Rsa { .. }) => sig_algs.contains(algorithm),
+            Some(_) => true,
+            None => false,
+        };
+        if !allowed || algorithm != Some(sig.algorithm()) {
+            debug!(
+                "signature algorithm {:?} not allowed for {:?}",
+                sig.algorithm(),
+                pubkey_algo
+            );
+            reject_auth_request(until, &mut self.write, auth_request).await?;
+            return Ok(());
+        }
+
+        // SAFETY: both original_packet and pos0 are coming from the
+        // same allocation (pos0 is derived from a slice of the
+        // original_packet)
+        let init = {
+            let init_len = unsafe { pos0.offset_from(original_packet.as_ptr()) };
+            #[allow(clippy::indexing_slicing)] // length checked
+            &original_packet[0..init_len as usize]
+        };
+        let session_id = self.session_id.as_ref();
+        let is_valid = SIGNATURE_BUFFER.with(|buf| {
+            let mut buf = buf.borrow_mut();
+            buf.clear();
+            map_err!(session_id.encode(&mut *buf))?;
+            buf.extend(init);
+            Ok::<_, crate::Error>(russh_keys::key::verify_signature(&pubkey, &buf, &sig).is_ok())
+        })?;
+        if !is_valid {
+            debug!("hostbased: signature wrong");
+            reject_auth_request(until, &mut self.write, auth_request).await?;
+            return Ok(());
+        }
+
+        let auth = handler
+            .auth_hostbased(user, &client_host, &client_user, &pubkey)
+            .await?;
+        if auth == Auth::Accept {
+            auth_user.clear();
+            auth_user.push_str(user);
+            if server_auth_request_success(auth_request, auth_user, &mut self.write)? {
+                self.state = EncryptedState::InitCompression;
+            }
+        } else {
+            if let Auth::Reject {
+                proceed_with_methods: Some(proceed_with_methods),
+            } = auth
+            {
+                auth_request.methods = proceed_with_methods;
+            }
+            auth_request.partial_success = false;
+            auth_user.clear();
+            reject_auth_request(until, &mut self.write, auth_request).await?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "gssapi")]
+    async fn server_read_auth_request_gssapi<H: Handler + Send>(
+        &mut self,
+        until: Instant,
+        handler: &mut H,
+        auth_user: &mut String,
+        user: &str,
+        r: &mut &[u8],
+    ) -> Result<(), H::Error> {
+        let auth_request = if let EncryptedState::WaitingAuthRequest(ref mut a) = self.state {
+            a
+        } else {
+            unreachable!()
+        };
+
+        // Use the first mechanism for which the handler has a context.
+        let n = map_err!(u32::decode(r))?;
+        let mut selected = None;
+        for _ in 0..n {
+            let mechanism = map_err!(Bytes::decode(r))?;
+            if selected.is_none() {
+                if let Some(context) = handler.auth_gssapi_context(user, &mechanism).await? {
+                    selected = Some((mechanism, context));
+                }
+            }
+        }
+        let Some((mechanism, context)) = selected else {
+            debug!("no supported gssapi mechanism");
+            reject_auth_request(until, &mut self.write, auth_request).await?;
+            return Ok(());
+        };
+
+        auth_user.clear();
+        auth_user.push_str(user);
+        push_packet!(self.write, {
+            self.write.push(msg::USERAUTH_GSSAPI_RESPONSE);
+            map_err!(mechanism.encode(&mut self.write))?;
+        });
+        auth_request.current = Some(CurrentRequest::GssapiWithMic {
+            context,
+            user: user.into(),
+            established: false,
+        });
+        Ok(())
+    }
+}
+
+/// Process a context token sent by the client.
+#[cfg(feature = "gssapi")]
+async fn read_gssapi_token<R: Reader>(
+    until: Instant,
+    write: &mut CryptoVec,
+    auth_request: &mut AuthRequest,
+    r: &mut R,
+) -> Result<(), Error> {
+    let Some(CurrentRequest::GssapiWithMic {
+        ref mut context,
+        ref mut established,
+        ..
+    }) = auth_request.current
+    else {
+        return reject_auth_request(until, write, auth_request).await;
+    };
+    let token = Bytes::decode(r)?;
+    match context.accept(&token) {
+        Ok(step) => {
+            if let Some(token) = step.token {
+                push_packet!(write, {
+                    write.push(msg::USERAUTH_GSSAPI_TOKEN);
+                    token.encode(write)?;
+                });
+            }
+            *established = step.complete;
+            Ok(())
+        }
+        Err(e) => {
+            debug!("gssapi context failed: {e}");
+            reject_auth_request(until, write, auth_request).await
+        }
+    }
+}
+
+/// Check the MIC sent by the client once the context is established,
+/// and ask the handler whether the principal may log in. Returns
+/// `true` if the user is authenticated.
+#[cfg(feature = "gssapi")]
+async fn read_gssapi_mic<H: Handler + Send, R: Reader>(
+    until: Instant,
+    handler: &mut H,
+    write: &mut CryptoVec,
+    auth_request: &mut AuthRequest,
+    auth_user: &mut String,
+    session_id: &[u8],
+    r: &mut R,
+) -> Result<bool, H::Error> {
+    let Some(CurrentRequest::GssapiWithMic {
+        ref mut context,
+        ref user,
+        established: true,
+    }) = auth_request.current
+    else {
+        reject_auth_request(until, write, auth_request).await?;
+        return Ok(false);
+    };
+    let mic = map_err!(Bytes::decode(r))?;
+    let data = crate::gssapi::mic_data(session_id, user)?;
+    let principal = match context.source_name() {
+        Some(principal) if context.verify_mic(&data, &mic) => principal,
+        _ => {
+            debug!("gssapi mic verification failed");
+            reject_auth_request(until, write, auth_request).await?;
+            return Ok(false);
+        }
+    };
+    let user = user.clone();
+    let auth = handler.auth_gssapi_with_mic(&user, &principal).await?;
+    Ok(reply_userauth_info_response(until, auth_request, auth_user, write, auth).await?)
 }
 
 async fn reject_auth_request(
@@ -596,10 +1093,36 @@ async fn reject_auth_request(
     Ok(())
 }
 
-fn server_auth_request_success(buffer: &mut CryptoVec) {
+/// Accept a step of the authentication of `auth_user`. Returns `true`
+/// if the user is authenticated, or `false` if
+/// [`Config::required_methods`] requires another step, in which case
+/// the client is told about its partial success.
+fn server_auth_request_success(
+    auth_request: &mut AuthRequest,
+    auth_user: &mut String,
+    buffer: &mut CryptoVec,
+) -> Result<bool, crate::Error> {
+    if let Some(methods) = auth_request
+        .next_methods
+        .as_mut()
+        .and_then(|next| next.pop_front())
+    {
+        debug!("partial success, next methods: {:?}", methods);
+        auth_request.methods = methods;
+        auth_request.current = None;
+        auth_request.partial_user = Some(std::mem::take(auth_user));
+        push_packet!(buffer, {
+            buffer.push(msg::USERAUTH_FAILURE);
+            NameList::from(methods).encode(buffer)?;
+            buffer.push(1);
+        });
+        return Ok(false);
+    }
+    info!("{:?} authenticated", auth_user);
     push_packet!(buffer, {
         buffer.push(msg::USERAUTH_SUCCESS);
-    })
+    });
+    Ok(true)
 }
 
 async fn read_userauth_info_response<H: Handler + Send, R: Reader>(
@@ -607,11 +1130,26 @@ async fn read_userauth_info_response<H: Handler + Send, R: Reader>(
     handler: &mut H,
     write: &mut CryptoVec,
     auth_request: &mut AuthRequest,
-    user: &str,
+    auth_user: &mut String,
     r: &mut R,
 ) -> Result<bool, H::Error> {
-    if let Some(CurrentRequest::KeyboardInteractive { ref submethods }) = auth_request.current {
+    let pending = match auth_request.current {
+        Some(CurrentRequest::KeyboardInteractive {
+            ref submethods,
+            ref mut pending_prompts,
+        }) => pending_prompts.take().map(|n| (submethods.clone(), n)),
+        _ => None,
+    };
+    if let Some((submethods, expected)) = pending {
         let n = map_err!(u32::decode(r))?;
+        if n as usize != expected {
+            debug!(
+                "expected {} keyboard-interactive responses, got {}",
+                expected, n
+            );
+            reject_auth_request(until, write, auth_request).await?;
+            return Ok(false);
+        }
 
         let mut responses = Vec::with_capacity(n as usize);
         for _ in 0..n {
@@ -619,9 +1157,13 @@ async fn read_userauth_info_response<H: Handler + Send, R: Reader>(
         }
 
         let auth = handler
-            .auth_keyboard_interactive(user, submethods, Some(Response(&mut responses.into_iter())))
+            .auth_keyboard_interactive(
+                auth_user,
+                &submethods,
+                Some(Response(&mut responses.into_iter())),
+            )
             .await?;
-        let resp = reply_userauth_info_response(until, auth_request, write, auth)
+        let resp = reply_userauth_info_response(until, auth_request, auth_user, write, auth)
             .await
             .map_err(H::Error::from)?;
         Ok(resp)
@@ -634,14 +1176,12 @@ async fn read_userauth_info_response<H: Handler + Send, R: Reader>(
 async fn reply_userauth_info_response(
     until: Instant,
     auth_request: &mut AuthRequest,
+    auth_user: &mut String,
     write: &mut CryptoVec,
     auth: Auth,
 ) -> Result<bool, Error> {
     match auth {
-        Auth::Accept => {
-            server_auth_request_success(write);
-            Ok(true)
-        }
+        Auth::Accept => server_auth_request_success(auth_request, auth_user, write),
         Auth::Reject {
             proceed_with_methods,
         } => {
@@ -657,6 +1197,13 @@ async fn reply_userauth_info_response(
             instructions,
             prompts,
         } => {
+            if let Some(CurrentRequest::KeyboardInteractive {
+                ref mut pending_prompts,
+                ..
+            }) = auth_request.current
+            {
+                *pending_prompts = Some(prompts.len());
+            }
             push_packet!(write, {
                 msg::USERAUTH_INFO_REQUEST.encode(write)?;
                 name.as_ref().encode(write)?;
@@ -676,11 +1223,11 @@ async fn reply_userauth_info_response(
 }
 
 impl Session {
-    async fn server_read_authenticated<H: Handler + Send, R: Reader>(
+    async fn server_read_authenticated<H: Handler + Send>(
         &mut self,
         handler: &mut H,
         msg: u8,
-        r: &mut R,
+        r: &mut &[u8],
     ) -> Result<(), H::Error> {
         match msg {
             msg::CHANNEL_OPEN => self
@@ -693,6 +1240,7 @@ impl Session {
                     enc.channels.remove(&channel_num);
                 }
                 self.channels.remove(&channel_num);
+                self.report_channel_closed(channel_num);
                 debug!("handler.channel_close {:?}", channel_num);
                 handler.channel_close(channel_num, self).await
             }
@@ -713,35 +1261,30 @@ impl Session {
                     Some(map_err!(u32::decode(r))?)
                 };
                 trace!("handler.data {:?} {:?}", ext, channel_num);
-                let data = map_err!(Bytes::decode(r))?;
-                let target = self.target_window_size;
+                let data = decode_slice(r)?;
+                if let Some(ref stats) = self.common.config.stats {
+                    stats.channel_data_received(channel_num, data.len())
+                }
+                if let Some(chan) = self.channels.get(&channel_num) {
+                    chan.send_data(data, ext);
+                }
+                let max_window_size = self.common.config.max_window_size;
+                let window_limit = self.window_limit(channel_num);
 
                 if let Some(ref mut enc) = self.common.encrypted {
-                    if enc.adjust_window_size(channel_num, &data, target)? {
-                        let window = handler.adjust_window(channel_num, self.target_window_size);
+                    if enc.adjust_window_size(channel_num, data, max_window_size, window_limit)? {
+                        let window =
+                            handler.adjust_window(channel_num, enc.target_window_size(channel_num));
                         if window > 0 {
-                            self.target_window_size = window
+                            enc.set_target_window_size(channel_num, window)
                         }
                     }
                 }
                 self.flush()?;
                 if let Some(ext) = ext {
-                    if let Some(chan) = self.channels.get(&channel_num) {
-                        chan.send(ChannelMsg::ExtendedData {
-                            ext,
-                            data: CryptoVec::from_slice(&data),
-                        })
-                        .unwrap_or(())
-                    }
-                    handler.extended_data(channel_num, ext, &data, self).await
+                    handler.extended_data(channel_num, ext, data, self).await
                 } else {
-                    if let Some(chan) = self.channels.get(&channel_num) {
-                        chan.send(ChannelMsg::Data {
-                            data: CryptoVec::from_slice(&data),
-                        })
-                        .unwrap_or(())
-                    }
-                    handler.data(channel_num, &data, self).await
+                    handler.data(channel_num, data, self).await
                 }
             }
 
@@ -758,10 +1301,10 @@ impl Session {
                     }
                 }
                 if let Some(ref mut enc) = self.common.encrypted {
-                    enc.flush_pending(channel_num)?;
+                    new_size -= enc.flush_pending(channel_num)? as u32;
                 }
                 if let Some(chan) = self.channels.get(&channel_num) {
-                    *chan.window_size().lock().await = new_size;
+                    chan.window_size().update(new_size).await;
 
                     chan.send(ChannelMsg::WindowAdjusted { new_size })
                         .unwrap_or(())
@@ -777,7 +1320,9 @@ impl Session {
 
                 if let Some(ref mut enc) = self.common.encrypted {
                     if let Some(parameters) = enc.channels.get_mut(&local_id) {
-                        parameters.confirm(&msg);
+                        if let Some(rtt) = parameters.confirm(&msg) {
+                            enc.observe_rtt(rtt);
+                        }
                     } else {
                         // We've not requested this channel, close connection.
                         return Err(Error::Inconsistent.into());
@@ -785,6 +1330,7 @@ impl Session {
                 } else {
                     return Err(Error::Inconsistent.into());
                 };
+                self.report_channel_opened(local_id);
 
                 if let Some(channel) = self.channels.get(&local_id) {
                     channel
@@ -818,37 +1364,13 @@ impl Session {
                 }
                 match req_type.as_str() {
                     "pty-req" => {
+                        self.set_interactive();
                         let term = map_err!(String::decode(r))?;
                         let col_width = map_err!(u32::decode(r))?;
                         let row_height = map_err!(u32::decode(r))?;
                         let pix_width = map_err!(u32::decode(r))?;
                         let pix_height = map_err!(u32::decode(r))?;
-                        let mut modes = [(Pty::TTY_OP_END, 0); 130];
-                        let mut i = 0;
-                        {
-                            let mode_string = map_err!(Bytes::decode(r))?;
-                            while 5 * i < mode_string.len() {
-                                #[allow(clippy::indexing_slicing)] // length checked
-                                let code = mode_string[5 * i];
-                                if code == 0 {
-                                    break;
-                                }
-                                #[allow(clippy::indexing_slicing)] // length checked
-                                let num = BigEndian::read_u32(&mode_string[5 * i + 1..]);
-                                debug!("code = {:?}", code);
-                                if let Some(code) = Pty::from_u8(code) {
-                                    #[allow(clippy::indexing_slicing)] // length checked
-                                    if i < 130 {
-                                        modes[i] = (code, num);
-                                    } else {
-                                        error!("pty-req: too many pty codes");
-                                    }
-                                } else {
-                                    info!("pty-req: unknown pty code {:?}", code);
-                                }
-                                i += 1
-                            }
-                        }
+                        let modes = PtyModes::decode(&map_err!(Bytes::decode(r))?);
 
                         if let Some(chan) = self.channels.get(&channel_num) {
                             let _ = chan.send(ChannelMsg::RequestPty {
@@ -858,12 +1380,11 @@ impl Session {
                                 row_height,
                                 pix_width,
                                 pix_height,
-                                terminal_modes: modes.into(),
+                                terminal_modes: modes.to_vec(),
                             });
                         }
 
                         debug!("handler.pty_request {:?}", channel_num);
-                        #[allow(clippy::indexing_slicing)] // `modes` length checked
                         handler
                             .pty_request(
                                 channel_num,
@@ -872,7 +1393,7 @@ impl Session {
                                 row_height,
                                 pix_width,
                                 pix_height,
-                                &modes[0..i],
+                                &modes,
                                 self,
                             )
                             .await
@@ -908,6 +1429,11 @@ impl Session {
                         let env_variable = map_err!(String::decode(r))?;
                         let env_value = map_err!(String::decode(r))?;
 
+                        if !self.common.config.accepts_env(&env_variable) {
+                            debug!("Refusing environment variable {:?}", env_variable);
+                            self.channel_failure(channel_num)?;
+                            return Ok(());
+                        }
                         if let Some(chan) = self.channels.get(&channel_num) {
                             let _ = chan.send(ChannelMsg::SetEnv {
                                 want_reply: true,
@@ -1003,6 +1529,22 @@ impl Session {
                         debug!("handler.signal {:?} {:?}", channel_num, signal);
                         handler.signal(channel_num, signal, self).await
                     }
+                    "break" => {
+                        let length = map_err!(u32::decode(r))?;
+                        if let Some(chan) = self.channels.get(&channel_num) {
+                            let _ = chan.send(ChannelMsg::Break {
+                                want_reply: true,
+                                length,
+                            });
+                        }
+                        debug!("handler.break_request {:?} {:?}", channel_num, length);
+                        if handler.break_request(channel_num, length, self).await? {
+                            self.channel_success(channel_num)?;
+                        } else {
+                            self.channel_failure(channel_num)?;
+                        }
+                        Ok(())
+                    }
                     x => {
                         warn!("unknown channel request {x}");
                         self.channel_failure(channel_num)?;
@@ -1050,6 +1592,49 @@ impl Session {
                         }
                         Ok(())
                     }
+                    "hostkeys-prove-00@openssh.com" => {
+                        let mut keys = Vec::new();
+                        while !r.is_finished() {
+                            keys.push(map_err!(Bytes::decode(r))?);
+                        }
+                        let proof = match self.common.encrypted {
+                            Some(ref enc) => {
+                                prove_host_keys(&self.common.config, &enc.session_id, &keys).await
+                            }
+                            None => Ok(None),
+                        };
+                        if let Some(ref mut enc) = self.common.encrypted {
+                            match proof {
+                                Ok(Some(signatures)) if self.common.wants_reply => {
+                                    push_packet!(enc.write, {
+                                        enc.write.push(msg::REQUEST_SUCCESS);
+                                        for sig in &signatures {
+                                            map_err!(sig.encode(&mut enc.write))?;
+                                        }
+                                    })
+                                }
+                                Ok(_) => {
+                                    push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE))
+                                }
+                                Err(e) => {
+                                    debug!("could not prove host keys: {:?}", e);
+                                    push_packet!(enc.write, enc.write.push(msg::REQUEST_FAILURE))
+                                }
+                            }
+                        }
+                        Ok(())
+                    }
+                    "no-more-sessions@openssh.com" => {
+                        debug!("handler.no_more_sessions");
+                        self.no_more_sessions = true;
+                        handler.no_more_sessions(self).await?;
+                        if self.common.wants_reply {
+                            if let Some(ref mut enc) = self.common.encrypted {
+                                push_packet!(enc.write, enc.write.push(msg::REQUEST_SUCCESS))
+                            }
+                        }
+                        Ok(())
+                    }
                     "streamlocal-forward@openssh.com" => {
                         let server_socket_path = map_err!(String::decode(r))?;
                         debug!("handler.streamlocal_forward {:?}", server_socket_path);
@@ -1180,7 +1765,7 @@ impl Session {
         } else {
             unreachable!()
         };
-        let channel_params = ChannelParams {
+        let mut channel_params = ChannelParams {
             recipient_channel: msg.recipient_channel,
 
             // "sender" is the local end, i.e. we're the sender, the remote is the recipient.
@@ -1188,6 +1773,9 @@ impl Session {
 
             recipient_window_size: msg.recipient_window_size,
             sender_window_size: self.common.config.window_size,
+            target_window_size: self.common.config.window_size,
+            window_adjusted_at: None,
+            open_sent_at: None,
             recipient_maximum_packet_size: msg.recipient_maximum_packet_size,
             sender_maximum_packet_size: self.common.config.maximum_packet_size,
             confirmed: true,
@@ -1195,7 +1783,11 @@ impl Session {
             pending_data: std::collections::VecDeque::new(),
             pending_eof: false,
             pending_close: false,
+            inbound_rate: None,
+            outbound_rate: None,
+            data_at: russh_util::time::Instant::now(),
         };
+        self.common.config.data_limits.apply(&mut channel_params);
 
         let (channel, reference) = Channel::new(
             sender_channel,
@@ -1206,10 +1798,24 @@ impl Session {
 
         match &msg.typ {
             ChannelType::Session => {
+                if self.no_more_sessions {
+                    debug!("refusing session channel after no-more-sessions");
+                    if let Some(ref mut enc) = self.common.encrypted {
+                        msg.fail(
+                            &mut enc.write,
+                            msg::SSH_OPEN_ADMINISTRATIVELY_PROHIBITED,
+                            b"No more sessions",
+                        )?;
+                    }
+                    return Ok(false);
+                }
                 let mut result = handler.channel_open_session(channel, self).await;
                 if let Ok(allowed) = &mut result {
                     self.channels.insert(sender_channel, reference);
                     self.finalize_channel_open(&msg, channel_params, *allowed)?;
+                    if *allowed && self.common.config.no_more_sessions {
+                        self.no_more_sessions = true;
+                    }
                 }
                 result
             }
@@ -1260,6 +1866,24 @@ impl Session {
                 }
                 result
             }
+            ChannelType::DirectStreamLocal(d) => {
+                let mut result = handler
+                    .channel_open_direct_streamlocal(channel, &d.socket_path, self)
+                    .await;
+                if let Ok(allowed) = &mut result {
+                    self.channels.insert(sender_channel, reference);
+                    self.finalize_channel_open(&msg, channel_params, *allowed)?;
+                }
+                result
+            }
+            ChannelType::Tun { mode, unit } => {
+                let mut result = handler.channel_open_tun(channel, *mode, *unit, self).await;
+                if let Ok(allowed) = &mut result {
+                    self.channels.insert(sender_channel, reference);
+                    self.finalize_channel_open(&msg, channel_params, *allowed)?;
+                }
+                result
+            }
             ChannelType::ForwardedStreamLocal(_) => {
                 if let Some(ref mut enc) = self.common.encrypted {
                     msg.fail(
@@ -1296,6 +1920,7 @@ impl Session {
         channel: ChannelParams,
         allowed: bool,
     ) -> Result<(), Error> {
+        let id = channel.sender_channel;
         if let Some(ref mut enc) = self.common.encrypted {
             if allowed {
                 open.confirm(
@@ -1313,6 +1938,34 @@ impl Session {
                 )?;
             }
         }
+        if allowed {
+            self.report_channel_opened(id);
+        }
         Ok(())
     }
 }
+
+/// Sign the `hostkeys-prove-00@openssh.com` data for each of the key
+/// blobs. Returns `None` if one of them isn't a key of ours.
+async fn prove_host_keys(
+    config: &Config,
+    session_id: &[u8],
+    blobs: &[Bytes],
+) -> Result<Option<Vec<Vec<u8>>>, crate::Error> {
+    let mut signatures = Vec::with_capacity(blobs.len());
+    for blob in blobs {
+        let pubkey = PublicKey::from_bytes(blob)?;
+        let Some(key) = config
+            .host_keys()
+            .find(|k| k.public_key().key_data() == pubkey.key_data())
+        else {
+            debug!("asked to prove unknown host key {:?}", pubkey);
+            return Ok(None);
+        };
+        let data = crate::session::host_keys_prove_data(session_id, &pubkey)?;
+        // RSA proofs use rsa-sha2-512, like OpenSSH.
+        let signature = key.sign(Some(HashAlg::Sha512), &data).await?;
+        signatures.push(signature.encoded()?);
+    }
+    Ok(Some(signatures))
+}
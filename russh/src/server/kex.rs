@@ -1,13 +1,14 @@
 use std::cell::RefCell;
 use std::ops::DerefMut;
 
-use log::debug;
 use russh_keys::helpers::EncodedExt;
 use ssh_encoding::Encode;
+use ssh_key::Algorithm;
 
 use super::*;
 use crate::cipher::SealingKey;
 use crate::kex::KEXES;
+use crate::logging::debug;
 use crate::negotiation::Select;
 use crate::{msg, negotiation};
 
@@ -27,22 +28,27 @@ impl KexInit {
             let algo = {
                 // read algorithms from packet.
                 self.exchange.client_kex_init.extend(buf);
-                super::negotiation::Server::read_kex(buf, &config.preferred, Some(&config.keys))?
+                super::negotiation::Server::read_kex(
+                    buf,
+                    &config.preferred,
+                    config.algorithm_policy.as_ref(),
+                    Some(&config.host_key_algorithms()),
+                    &config.compression,
+                )?
             };
             if !self.sent {
                 self.server_write(config, cipher, write_buffer)?
             }
-            let mut key = 0;
-            #[allow(clippy::indexing_slicing)] // length checked
-            while key < config.keys.len() && config.keys[key].algorithm() != algo.key {
-                key += 1
-            }
-            let next_kex = if key < config.keys.len() {
+            let key = config
+                .host_keys()
+                .position(|k| negotiation::can_sign_with(&k.public_key().algorithm(), &algo.key));
+            let next_kex = if let Some(key) = key {
                 Kex::Dh(KexDh {
                     exchange: self.exchange,
                     key,
                     names: algo,
                     session_id: self.session_id,
+                    kex: None,
                 })
             } else {
                 debug!("unknown key {:?}", algo.key);
@@ -64,8 +70,10 @@ impl KexInit {
         self.exchange.server_kex_init.clear();
         negotiation::write_kex(
             &config.preferred,
+            config.algorithm_policy.as_ref(),
             &mut self.exchange.server_kex_init,
             Some(config),
+            &config.compression,
         )?;
         debug!("server kex init: {:?}", &self.exchange.server_kex_init[..]);
         self.sent = true;
@@ -75,10 +83,10 @@ impl KexInit {
 }
 
 impl KexDh {
-    pub fn parse(
+    pub async fn parse(
         mut self,
         config: &Config,
-        cipher: &mut dyn SealingKey,
+        cipher: &mut (dyn SealingKey + Send),
         buf: &[u8],
         write_buffer: &mut SSHBuffer,
     ) -> Result<Kex, Error> {
@@ -88,16 +96,29 @@ impl KexDh {
             Ok(Kex::Dh(self))
         } else {
             // Else, process it.
-            let Some((&msg::KEX_ECDH_INIT, mut r)) = buf.split_first() else {
+            let mut kex = match self.kex.take() {
+                Some(kex) => kex,
+                None => KEXES.get(&self.names.kex).ok_or(Error::UnknownAlgo)?.make(),
+            };
+
+            let mut reply = CryptoVec::new();
+            if kex.server_negotiate(buf, &config.gex_groups, &mut reply)? {
+                cipher.write(&reply, write_buffer);
+                self.kex = Some(kex);
+                return Ok(Kex::Dh(self));
+            }
+
+            let Some((&typ, mut r)) = buf.split_first() else {
                 return Err(Error::Inconsistent);
             };
+            if typ != kex.init_msg() {
+                return Err(Error::Inconsistent);
+            }
 
             self.exchange
                 .client_ephemeral
                 .extend(&Bytes::decode(&mut r)?);
 
-            let mut kex = KEXES.get(&self.names.kex).ok_or(Error::UnknownAlgo)?.make();
-
             kex.server_dh(&mut self.exchange, buf)?;
 
             // Then, we fill the write buffer right away, so that we
@@ -109,17 +130,19 @@ impl KexDh {
                 names: self.names,
                 session_id: self.session_id,
             };
-            #[allow(clippy::indexing_slicing)] // key index checked
+            let host_key = config
+                .host_keys()
+                .nth(kexdhdone.key)
+                .ok_or(Error::UnknownKey)?;
+            let pubkey = host_key.public_key().to_bytes()?;
+
             let hash: Result<_, Error> = HASH_BUF.with(|buffer| {
                 let mut buffer = buffer.borrow_mut();
                 buffer.clear();
                 debug!("server kexdhdone.exchange = {:?}", kexdhdone.exchange);
 
                 let mut pubkey_vec = CryptoVec::new();
-                config.keys[kexdhdone.key]
-                    .public_key()
-                    .to_bytes()?
-                    .encode(&mut pubkey_vec)?;
+                pubkey.encode(&mut pubkey_vec)?;
 
                 let hash = kexdhdone.kex.compute_exchange_hash(
                     &pubkey_vec,
@@ -127,12 +150,25 @@ impl KexDh {
                     &mut buffer,
                 )?;
                 debug!("exchange hash: {:?}", hash);
+                Ok(hash)
+            });
+            let hash = hash?;
+
+            // Hash signature, possibly by a remote signer.
+            debug!("signing with key {:?}", kexdhdone.key);
+            debug!("hash: {:?}", hash);
+            debug!("key: {:?}", host_key.public_key());
+            let hash_alg = match kexdhdone.names.key {
+                Algorithm::Rsa { hash } => hash,
+                _ => None,
+            };
+            let signature = host_key.sign(hash_alg, &hash).await?;
+
+            HASH_BUF.with(|buffer| -> Result<(), Error> {
+                let mut buffer = buffer.borrow_mut();
                 buffer.clear();
-                buffer.push(msg::KEX_ECDH_REPLY);
-                config.keys[kexdhdone.key]
-                    .public_key()
-                    .to_bytes()?
-                    .encode(buffer.deref_mut())?;
+                buffer.push(kexdhdone.kex.reply_msg());
+                pubkey.encode(buffer.deref_mut())?;
 
                 // Server ephemeral
                 kexdhdone
@@ -140,20 +176,14 @@ impl KexDh {
                     .server_ephemeral
                     .encode(buffer.deref_mut())?;
 
-                // Hash signature
-                debug!("signing with key {:?}", kexdhdone.key);
-                debug!("hash: {:?}", hash);
-                debug!("key: {:?}", config.keys[kexdhdone.key]);
-
-                let signature = signature::Signer::try_sign(&config.keys[kexdhdone.key], &hash)?;
                 signature.encoded()?.encode(&mut *buffer)?;
 
                 cipher.write(&buffer, write_buffer);
                 cipher.write(&[msg::NEWKEYS], write_buffer);
-                Ok(hash)
-            });
+                Ok(())
+            })?;
 
-            Ok(Kex::Keys(kexdhdone.compute_keys(hash?, true)?))
+            Ok(Kex::Keys(kexdhdone.compute_keys(hash, true)?))
         }
     }
 }
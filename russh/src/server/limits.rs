@@ -0,0 +1,115 @@
+//! Connection limits of [`Server::run_on_socket`](super::Server::run_on_socket).
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ssh_encoding::Encode;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use super::Config;
+use crate::cipher::{clear, SealingKey};
+use crate::sshbuffer::SSHBuffer;
+use crate::{msg, CryptoVec, Disconnect};
+
+/// The connections currently open, overall and by source address.
+#[derive(Default, Clone)]
+pub(crate) struct ConnectionCounts(Arc<Mutex<Counts>>);
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionCounts {
+    /// The number of connections open, and from `peer_addr`.
+    pub(crate) fn get(&self, peer_addr: Option<SocketAddr>) -> (usize, usize) {
+        match self.0.lock() {
+            Ok(counts) => {
+                let from_peer = peer_addr
+                    .and_then(|addr| counts.by_ip.get(&super::address::unmap(addr.ip())))
+                    .copied()
+                    .unwrap_or(0);
+                (counts.total, from_peer)
+            }
+            Err(_) => (0, 0),
+        }
+    }
+
+    /// Count a new connection until the returned guard is dropped.
+    pub(crate) fn add(&self, peer_addr: Option<SocketAddr>) -> ConnectionGuard {
+        let ip = peer_addr.map(|addr| super::address::unmap(addr.ip()));
+        if let Ok(mut counts) = self.0.lock() {
+            counts.total += 1;
+            if let Some(ip) = ip {
+                *counts.by_ip.entry(ip).or_insert(0) += 1;
+            }
+        }
+        ConnectionGuard {
+            counts: self.clone(),
+            ip,
+        }
+    }
+}
+
+/// Counts a connection as open while alive.
+pub(crate) struct ConnectionGuard {
+    counts: ConnectionCounts,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Ok(mut counts) = self.counts.0.lock() {
+            counts.total = counts.total.saturating_sub(1);
+            if let Some(ip) = self.ip {
+                if let Some(n) = counts.by_ip.get_mut(&ip) {
+                    *n -= 1;
+                    if *n == 0 {
+                        counts.by_ip.remove(&ip);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Whether `open` connections, `open_from_peer` of which come from the
+/// same address as a new one, are within the limits of `config`.
+pub(crate) fn within_limits(config: &Config, open: usize, open_from_peer: usize) -> bool {
+    config.max_connections.map_or(true, |max| open < max)
+        && config
+            .max_connections_per_ip
+            .map_or(true, |max| open_from_peer < max)
+}
+
+/// Send our version string and a cleartext `SSH_MSG_DISCONNECT`
+/// (allowed before the first key exchange) to a client we won't
+/// serve, and close the connection.
+pub(crate) async fn refuse<S: AsyncRead + AsyncWrite + Unpin>(
+    config: &Config,
+    mut stream: S,
+    reason: Disconnect,
+    description: &str,
+) -> Result<(), crate::Error> {
+    let mut buffer = SSHBuffer::new();
+    buffer.send_ssh_id(&config.server_id);
+    let mut payload = CryptoVec::new();
+    msg::DISCONNECT.encode(&mut payload)?;
+    (reason as u32).encode(&mut payload)?;
+    description.encode(&mut payload)?;
+    "".encode(&mut payload)?;
+    clear::Key.write(&payload, &mut buffer);
+    stream.write_all(&buffer.buffer).await?;
+    stream.shutdown().await?;
+    // Closing with unread data would reset the connection, possibly
+    // before the client reads our message.
+    let _ = tokio::time::timeout(
+        Duration::from_secs(1),
+        tokio::io::copy(&mut stream, &mut tokio::io::sink()),
+    )
+    .await;
+    Ok(())
+}
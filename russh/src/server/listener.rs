@@ -0,0 +1,144 @@
+//! Sources of connections for [`Server::run_on_listener`](super::Server::run_on_listener).
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
+use russh_util::net::{SocketControl, SocketOptions};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+
+use crate::logging::debug;
+
+/// A connection accepted by a [`Listener`].
+#[derive(Debug)]
+pub struct Accepted<S> {
+    pub stream: S,
+    /// The address of the client, if the transport has one.
+    pub peer_addr: Option<SocketAddr>,
+    /// The TCP socket of the connection, to switch it to the
+    /// interactive mode when a terminal is requested.
+    pub socket: Option<SocketControl>,
+}
+
+/// Accepts the connections served by
+/// [`Server::run_on_listener`](super::Server::run_on_listener), such as
+/// a [`TcpListener`], a [`tokio::net::UnixListener`], or a listener
+/// whose connections go through a TLS or WebSocket handshake first,
+/// see [`Listener::upgrade`].
+///
+/// `accept` must be cancel safe: it is interrupted whenever the server
+/// has something else to do, and called again.
+#[async_trait]
+pub trait Listener: Send + Sync {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Wait for the next connection, applying `options` to it if it is
+    /// a TCP connection. Errors stop the server.
+    async fn accept(&self, options: &SocketOptions) -> io::Result<Accepted<Self::Stream>>;
+
+    /// Pass the accepted connections through `upgrade`, for instance
+    /// to accept TLS on them, before the SSH session starts. Upgrades
+    /// run concurrently, and failed ones are dropped.
+    fn upgrade<F, Fut, S>(self, upgrade: F) -> Upgrade<Self, F, S>
+    where
+        Self: Sized,
+        F: Fn(Self::Stream) -> Fut + Send + Sync,
+        Fut: Future<Output = io::Result<S>> + Send + 'static,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Upgrade {
+            listener: self,
+            upgrade,
+            pending: tokio::sync::Mutex::new(FuturesUnordered::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl Listener for TcpListener {
+    type Stream = tokio::net::TcpStream;
+
+    async fn accept(&self, options: &SocketOptions) -> io::Result<Accepted<Self::Stream>> {
+        let (stream, peer_addr) = TcpListener::accept(self).await?;
+        if let Err(e) = options.apply(&stream) {
+            debug!("Could not set the socket options: {:?}", e);
+        }
+        let socket = SocketControl::new(&stream, options.clone()).ok();
+        Ok(Accepted {
+            stream,
+            peer_addr: Some(peer_addr),
+            socket,
+        })
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl Listener for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+
+    async fn accept(&self, _: &SocketOptions) -> io::Result<Accepted<Self::Stream>> {
+        let (stream, _) = tokio::net::UnixListener::accept(self).await?;
+        Ok(Accepted {
+            stream,
+            peer_addr: None,
+            socket: None,
+        })
+    }
+}
+
+type Upgrading<S> = Pin<Box<dyn Future<Output = (io::Result<S>, Accepted<()>)> + Send>>;
+
+/// A [`Listener`] upgrading the connections of another one, see
+/// [`Listener::upgrade`].
+pub struct Upgrade<L, F, S> {
+    listener: L,
+    upgrade: F,
+    pending: tokio::sync::Mutex<FuturesUnordered<Upgrading<S>>>,
+}
+
+#[async_trait]
+impl<L, F, Fut, S> Listener for Upgrade<L, F, S>
+where
+    L: Listener,
+    F: Fn(L::Stream) -> Fut + Send + Sync,
+    Fut: Future<Output = io::Result<S>> + Send + 'static,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = S;
+
+    async fn accept(&self, options: &SocketOptions) -> io::Result<Accepted<S>> {
+        let mut pending = self.pending.lock().await;
+        loop {
+            tokio::select! {
+                accepted = self.listener.accept(options) => {
+                    let accepted = accepted?;
+                    let upgrading = (self.upgrade)(accepted.stream);
+                    let connection = Accepted {
+                        stream: (),
+                        peer_addr: accepted.peer_addr,
+                        socket: accepted.socket,
+                    };
+                    pending.push(Box::pin(async move { (upgrading.await, connection) }));
+                }
+                Some((result, connection)) = pending.next() => match result {
+                    Ok(stream) => {
+                        return Ok(Accepted {
+                            stream,
+                            peer_addr: connection.peer_addr,
+                            socket: connection.socket,
+                        })
+                    }
+                    Err(e) => debug!(
+                        "Upgrade of the connection from {:?} failed: {:?}",
+                        connection.peer_addr, e
+                    ),
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,666 @@
+//! Middleware around server handlers, for the concerns shared by all
+//! the handlers of an application, such as auditing or limiting
+//! authentications, counting channels, or changing the banner.
+//!
+//! A [`Middleware`] sees some of the events of a connection before or
+//! after the handler, and may answer for it. [`HandlerExt::with`] wraps
+//! a handler in a middleware, giving a [`Layered`] handler, which can be
+//! wrapped again: the outermost middleware runs first.
+//!
+//! ```ignore
+//! struct Audit;
+//!
+//! impl Middleware for Audit {
+//!     async fn after_auth(&mut self, user: &str, method: &str, auth: &Auth) {
+//!         log::info!("{method} authentication of {user}: {auth:?}");
+//!     }
+//! }
+//!
+//! // In `Server::new_client`:
+//! MyHandler::new(peer_addr).with(Audit).with(RootLogins::denied())
+//! ```
+
+use std::future::Future;
+
+use ssh_key::{Certificate, PublicKey};
+
+use super::{Auth, Handler, Msg, Response, Session};
+use crate::tun::TunMode;
+use crate::{Channel, ChannelId, Extensions, Negotiated, PtyModes, RemoteDisconnectInfo, Sig};
+
+/// Hooks called around the methods of a [`Handler`]. All of them do
+/// nothing by default.
+pub trait Middleware: Send {
+    /// Change the authentication banner returned by the handler.
+    fn auth_banner(
+        &mut self,
+        banner: Option<String>,
+    ) -> impl Future<Output = Option<String>> + Send {
+        async move { banner }
+    }
+
+    /// Called before the handler checks a request to authenticate
+    /// `user` with `method` (`"none"`, `"password"`, `"publickey"`,
+    /// `"hostbased"`, `"keyboard-interactive"` or `"gssapi-with-mic"`).
+    /// Return `Some` to answer without asking the handler.
+    #[allow(unused_variables)]
+    fn before_auth(
+        &mut self,
+        user: &str,
+        method: &str,
+    ) -> impl Future<Output = Option<Auth>> + Send {
+        async move { None }
+    }
+
+    /// Called with the answer to a request to authenticate `user` with
+    /// `method`, except for public keys accepted when they are offered,
+    /// which are then checked again with their signature.
+    #[allow(unused_variables)]
+    fn after_auth(
+        &mut self,
+        user: &str,
+        method: &str,
+        auth: &Auth,
+    ) -> impl Future<Output = ()> + Send {
+        async move {}
+    }
+
+    /// Called before the handler is asked about a channel of type
+    /// `channel_type` opened by the client, such as `"session"` or
+    /// `"direct-tcpip"`. Return `false` to refuse it.
+    #[allow(unused_variables)]
+    fn channel_open(
+        &mut self,
+        channel_type: &str,
+        session: &mut Session,
+    ) -> impl Future<Output = bool> + Send {
+        async move { true }
+    }
+
+    /// Called before the handler gets a request of type `request`,
+    /// such as `"exec"` or `"pty-req"`, on `channel`.
+    #[allow(unused_variables)]
+    fn channel_request(
+        &mut self,
+        channel: ChannelId,
+        request: &str,
+        session: &mut Session,
+    ) -> impl Future<Output = ()> + Send {
+        async move {}
+    }
+
+    /// Called when the client disconnects.
+    #[allow(unused_variables)]
+    fn disconnected(&mut self, info: &RemoteDisconnectInfo) -> impl Future<Output = ()> + Send {
+        async move {}
+    }
+}
+
+/// A handler wrapped in a middleware.
+#[derive(Debug, Clone)]
+pub struct Layered<M, H> {
+    middleware: M,
+    inner: H,
+}
+
+impl<M, H> Layered<M, H> {
+    pub fn new(middleware: M, inner: H) -> Self {
+        Layered { middleware, inner }
+    }
+
+    pub fn middleware(&self) -> &M {
+        &self.middleware
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+/// Wrapping handlers in middleware.
+pub trait HandlerExt: Handler + Sized {
+    /// Run `middleware` around this handler.
+    fn with<M: Middleware>(self, middleware: M) -> Layered<M, Self> {
+        Layered::new(middleware, self)
+    }
+}
+
+impl<H: Handler> HandlerExt for H {}
+
+/// Ask the middleware about an authentication request, then `check` if
+/// it doesn't answer.
+async fn auth<M, E, F>(middleware: &mut M, user: &str, method: &str, check: F) -> Result<Auth, E>
+where
+    M: Middleware,
+    F: Future<Output = Result<Auth, E>> + Send,
+{
+    let auth = match middleware.before_auth(user, method).await {
+        Some(auth) => auth,
+        None => check.await?,
+    };
+    middleware.after_auth(user, method, &auth).await;
+    Ok(auth)
+}
+
+impl<M: Middleware, H: Handler> Handler for Layered<M, H> {
+    type Error = H::Error;
+
+    async fn auth_banner(&mut self) -> Result<Option<String>, Self::Error> {
+        let banner = self.inner.auth_banner().await?;
+        Ok(self.middleware.auth_banner(banner).await)
+    }
+
+    async fn auth_none(&mut self, user: &str) -> Result<Auth, Self::Error> {
+        auth(
+            &mut self.middleware,
+            user,
+            "none",
+            self.inner.auth_none(user),
+        )
+        .await
+    }
+
+    async fn auth_failed(
+        &mut self,
+        user: &str,
+        method: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.auth_failed(user, method, session).await
+    }
+
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+        let check = self.inner.auth_password(user, password);
+        auth(&mut self.middleware, user, "password", check).await
+    }
+
+    async fn auth_publickey_offered(
+        &mut self,
+        user: &str,
+        public_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        if let Some(auth) = self.middleware.before_auth(user, "publickey").await {
+            self.middleware.after_auth(user, "publickey", &auth).await;
+            return Ok(auth);
+        }
+        let auth = self.inner.auth_publickey_offered(user, public_key).await?;
+        if auth != Auth::Accept {
+            self.middleware.after_auth(user, "publickey", &auth).await;
+        }
+        Ok(auth)
+    }
+
+    async fn auth_publickey(
+        &mut self,
+        user: &str,
+        public_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        let check = self.inner.auth_publickey(user, public_key);
+        auth(&mut self.middleware, user, "publickey", check).await
+    }
+
+    async fn auth_openssh_certificate(
+        &mut self,
+        user: &str,
+        certificate: &Certificate,
+    ) -> Result<Auth, Self::Error> {
+        let check = self.inner.auth_openssh_certificate(user, certificate);
+        auth(&mut self.middleware, user, "publickey", check).await
+    }
+
+    async fn auth_hostbased(
+        &mut self,
+        user: &str,
+        client_host: &str,
+        client_user: &str,
+        host_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        let check = self
+            .inner
+            .auth_hostbased(user, client_host, client_user, host_key);
+        auth(&mut self.middleware, user, "hostbased", check).await
+    }
+
+    async fn auth_keyboard_interactive(
+        &mut self,
+        user: &str,
+        submethods: &str,
+        response: Option<Response<'_>>,
+    ) -> Result<Auth, Self::Error> {
+        let check = self
+            .inner
+            .auth_keyboard_interactive(user, submethods, response);
+        auth(&mut self.middleware, user, "keyboard-interactive", check).await
+    }
+
+    #[cfg(feature = "gssapi")]
+    async fn auth_gssapi_context(
+        &mut self,
+        user: &str,
+        mechanism: &[u8],
+    ) -> Result<Option<Box<dyn crate::gssapi::ServerContext>>, Self::Error> {
+        self.inner.auth_gssapi_context(user, mechanism).await
+    }
+
+    #[cfg(feature = "gssapi")]
+    async fn auth_gssapi_with_mic(
+        &mut self,
+        user: &str,
+        principal: &str,
+    ) -> Result<Auth, Self::Error> {
+        let check = self.inner.auth_gssapi_with_mic(user, principal);
+        auth(&mut self.middleware, user, "gssapi-with-mic", check).await
+    }
+
+    async fn auth_succeeded(&mut self, session: &mut Session) -> Result<(), Self::Error> {
+        self.inner.auth_succeeded(session).await
+    }
+
+    async fn ext_info(
+        &mut self,
+        extensions: &Extensions,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.ext_info(extensions, session).await
+    }
+
+    async fn channel_close(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.channel_close(channel, session).await
+    }
+
+    async fn channel_eof(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.channel_eof(channel, session).await
+    }
+
+    async fn channel_idle(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.channel_idle(channel, session).await
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        if !self.middleware.channel_open("session", session).await {
+            return Ok(false);
+        }
+        self.inner.channel_open_session(channel, session).await
+    }
+
+    async fn channel_open_x11(
+        &mut self,
+        channel: Channel<Msg>,
+        originator_address: &str,
+        originator_port: u32,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        if !self.middleware.channel_open("x11", session).await {
+            return Ok(false);
+        }
+        self.inner
+            .channel_open_x11(channel, originator_address, originator_port, session)
+            .await
+    }
+
+    async fn channel_open_direct_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        host_to_connect: &str,
+        port_to_connect: u32,
+        originator_address: &str,
+        originator_port: u32,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        if !self.middleware.channel_open("direct-tcpip", session).await {
+            return Ok(false);
+        }
+        self.inner
+            .channel_open_direct_tcpip(
+                channel,
+                host_to_connect,
+                port_to_connect,
+                originator_address,
+                originator_port,
+                session,
+            )
+            .await
+    }
+
+    async fn channel_open_direct_streamlocal(
+        &mut self,
+        channel: Channel<Msg>,
+        socket_path: &str,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        if !self
+            .middleware
+            .channel_open("direct-streamlocal@openssh.com", session)
+            .await
+        {
+            return Ok(false);
+        }
+        self.inner
+            .channel_open_direct_streamlocal(channel, socket_path, session)
+            .await
+    }
+
+    async fn channel_open_tun(
+        &mut self,
+        channel: Channel<Msg>,
+        mode: TunMode,
+        unit: u32,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        if !self
+            .middleware
+            .channel_open("tun@openssh.com", session)
+            .await
+        {
+            return Ok(false);
+        }
+        self.inner
+            .channel_open_tun(channel, mode, unit, session)
+            .await
+    }
+
+    async fn channel_open_forwarded_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        host_to_connect: &str,
+        port_to_connect: u32,
+        originator_address: &str,
+        originator_port: u32,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        if !self
+            .middleware
+            .channel_open("forwarded-tcpip", session)
+            .await
+        {
+            return Ok(false);
+        }
+        self.inner
+            .channel_open_forwarded_tcpip(
+                channel,
+                host_to_connect,
+                port_to_connect,
+                originator_address,
+                originator_port,
+                session,
+            )
+            .await
+    }
+
+    async fn channel_open_confirmation(
+        &mut self,
+        id: ChannelId,
+        max_packet_size: u32,
+        window_size: u32,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner
+            .channel_open_confirmation(id, max_packet_size, window_size, session)
+            .await
+    }
+
+    async fn data(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.data(channel, data, session).await
+    }
+
+    async fn extended_data(
+        &mut self,
+        channel: ChannelId,
+        code: u32,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.extended_data(channel, code, data, session).await
+    }
+
+    async fn window_adjusted(
+        &mut self,
+        channel: ChannelId,
+        new_size: u32,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.window_adjusted(channel, new_size, session).await
+    }
+
+    fn adjust_window(&mut self, channel: ChannelId, current: u32) -> u32 {
+        self.inner.adjust_window(channel, current)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn pty_request(
+        &mut self,
+        channel: ChannelId,
+        term: &str,
+        col_width: u32,
+        row_height: u32,
+        pix_width: u32,
+        pix_height: u32,
+        modes: &PtyModes,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.middleware
+            .channel_request(channel, "pty-req", session)
+            .await;
+        self.inner
+            .pty_request(
+                channel, term, col_width, row_height, pix_width, pix_height, modes, session,
+            )
+            .await
+    }
+
+    async fn x11_request(
+        &mut self,
+        channel: ChannelId,
+        single_connection: bool,
+        x11_auth_protocol: &str,
+        x11_auth_cookie: &str,
+        x11_screen_number: u32,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.middleware
+            .channel_request(channel, "x11-req", session)
+            .await;
+        self.inner
+            .x11_request(
+                channel,
+                single_connection,
+                x11_auth_protocol,
+                x11_auth_cookie,
+                x11_screen_number,
+                session,
+            )
+            .await
+    }
+
+    async fn env_request(
+        &mut self,
+        channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.middleware
+            .channel_request(channel, "env", session)
+            .await;
+        self.inner
+            .env_request(channel, variable_name, variable_value, session)
+            .await
+    }
+
+    async fn shell_request(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.middleware
+            .channel_request(channel, "shell", session)
+            .await;
+        self.inner.shell_request(channel, session).await
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.middleware
+            .channel_request(channel, "exec", session)
+            .await;
+        self.inner.exec_request(channel, data, session).await
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.middleware
+            .channel_request(channel, "subsystem", session)
+            .await;
+        self.inner.subsystem_request(channel, name, session).await
+    }
+
+    async fn window_change_request(
+        &mut self,
+        channel: ChannelId,
+        col_width: u32,
+        row_height: u32,
+        pix_width: u32,
+        pix_height: u32,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.middleware
+            .channel_request(channel, "window-change", session)
+            .await;
+        self.inner
+            .window_change_request(
+                channel, col_width, row_height, pix_width, pix_height, session,
+            )
+            .await
+    }
+
+    async fn agent_request(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.middleware
+            .channel_request(channel, "auth-agent-req@openssh.com", session)
+            .await;
+        self.inner.agent_request(channel, session).await
+    }
+
+    async fn signal(
+        &mut self,
+        channel: ChannelId,
+        signal: Sig,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.middleware
+            .channel_request(channel, "signal", session)
+            .await;
+        self.inner.signal(channel, signal, session).await
+    }
+
+    async fn break_request(
+        &mut self,
+        channel: ChannelId,
+        length: u32,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.middleware
+            .channel_request(channel, "break", session)
+            .await;
+        self.inner.break_request(channel, length, session).await
+    }
+
+    async fn tcpip_forward(
+        &mut self,
+        address: &str,
+        port: &mut u32,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.inner.tcpip_forward(address, port, session).await
+    }
+
+    async fn cancel_tcpip_forward(
+        &mut self,
+        address: &str,
+        port: u32,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.inner
+            .cancel_tcpip_forward(address, port, session)
+            .await
+    }
+
+    async fn streamlocal_forward(
+        &mut self,
+        socket_path: &str,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.inner.streamlocal_forward(socket_path, session).await
+    }
+
+    async fn cancel_streamlocal_forward(
+        &mut self,
+        socket_path: &str,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.inner
+            .cancel_streamlocal_forward(socket_path, session)
+            .await
+    }
+
+    async fn disconnected(
+        &mut self,
+        info: &RemoteDisconnectInfo,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.middleware.disconnected(info).await;
+        self.inner.disconnected(info, session).await
+    }
+
+    async fn negotiated(
+        &mut self,
+        negotiated: &Negotiated,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        self.inner.negotiated(negotiated, session).await
+    }
+
+    async fn no_more_sessions(&mut self, session: &mut Session) -> Result<(), Self::Error> {
+        self.inner.no_more_sessions(session).await
+    }
+}
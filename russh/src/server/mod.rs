@@ -16,7 +16,8 @@
 //! # Writing servers
 //!
 //! There are two ways of accepting connections:
-//! * implement the [Server](server::Server) trait and let [run_on_socket](server::Server::run_on_socket)/[run_on_address](server::Server::run_on_address) handle everything
+//! * implement the [Server](server::Server) trait and let [run_on_socket](server::Server::run_on_socket)/[run_on_address](server::Server::run_on_address) handle everything,
+//!   or [run_on_listener](server::Server::run_on_listener) for other transports, see [`crate::transport`]
 //! * accept connections yourself and pass them to [run_stream](server::run_stream)
 //!
 //! In both cases, you'll first need to implement the [Handler](server::Handler) trait -
@@ -29,7 +30,7 @@
 //! * Serving `ratatui` based TUI app to clients: [per-client](https://github.com/warp-tech/russh/blob/main/russh/examples/ratatui_app.rs), [shared](https://github.com/warp-tech/russh/blob/main/russh/examples/ratatui_shared_app.rs)
 
 use std;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::num::Wrapping;
 use std::pin::Pin;
 use std::sync::Arc;
@@ -38,8 +39,9 @@ use std::task::{Context, Poll};
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::future::Future;
-use log::{debug, error};
+use russh_keys::key::KeySigner;
 use russh_keys::map_err;
+use russh_util::net::SocketControl;
 use russh_util::runtime::JoinHandle;
 use ssh_key::{Certificate, PrivateKey};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
@@ -47,15 +49,37 @@ use tokio::net::{TcpListener, ToSocketAddrs};
 use tokio::pin;
 
 use crate::cipher::{clear, CipherPair, OpeningKey};
+use crate::logging::{self, debug, error, Instrument};
+use crate::negotiation::SharedNegotiated;
+use crate::observer::PacketObserver;
 use crate::session::*;
 use crate::ssh_read::*;
 use crate::sshbuffer::*;
+use crate::tun::TunMode;
 use crate::*;
 
 mod kex;
 mod session;
 pub use self::session::*;
+mod address;
 mod encrypted;
+mod limits;
+mod listener;
+pub mod middleware;
+#[cfg(feature = "portable-pty")]
+pub mod shell;
+mod shutdown;
+#[cfg(all(feature = "russh-config", not(target_arch = "wasm32")))]
+mod sshd_config;
+mod stats;
+pub mod subsystem;
+pub use self::address::IpCidr;
+pub use self::listener::{Accepted, Listener, Upgrade};
+pub use self::shutdown::ShutdownHandle;
+#[cfg(all(feature = "russh-config", not(target_arch = "wasm32")))]
+pub use self::sshd_config::SshdPolicy;
+pub use self::stats::Stats;
+pub use crate::throttle::DataLimits;
 
 /// Configuration of a server.
 pub struct Config {
@@ -63,7 +87,15 @@ pub struct Config {
     pub server_id: SshId,
     /// Authentication methods proposed to the client.
     pub methods: auth::MethodSet,
-    /// The authentication banner, usually a warning message shown to the client.
+    /// Authentication steps the client must complete in order, like
+    /// sshd's `AuthenticationMethods`: for instance `[PUBLICKEY,
+    /// KEYBOARD_INTERACTIVE]` requires a key and then a password or
+    /// one-time code, and each step may allow several methods. Clients
+    /// are told about partial success after each step. If empty, one
+    /// of `methods` is enough.
+    pub required_methods: Vec<auth::MethodSet>,
+    /// The authentication banner, usually a warning message shown to
+    /// the client, unless [`Handler::auth_banner`] returns another one.
     pub auth_banner: Option<&'static str>,
     /// Authentication rejections must happen in constant time for
     /// security reasons. Russh does not handle this by default.
@@ -71,26 +103,134 @@ pub struct Config {
     /// Authentication rejection time override for the initial "none" auth attempt.
     /// OpenSSH clients will send an initial "none" auth to probe for authentication methods.
     pub auth_rejection_time_initial: Option<std::time::Duration>,
-    /// The server's keys. The first key pair in the client's preference order will be chosen.
+    /// If set, the rejection time doubles after each failed
+    /// authentication attempt on the connection, up to this duration,
+    /// to slow brute force attacks down.
+    pub auth_rejection_time_max: Option<std::time::Duration>,
+    /// The server's keys, for instance one of each type. For each
+    /// connection, the first key that can sign with the client's most
+    /// preferred host key algorithm is used, RSA keys signing with any
+    /// of `rsa-sha2-512`, `rsa-sha2-256` and `ssh-rsa`.
     pub keys: Vec<PrivateKey>,
+    /// Host keys kept outside of this process, for instance in an HSM,
+    /// a cloud KMS or a TPM. They are offered after `keys`.
+    pub key_signers: Vec<Arc<dyn KeySigner>>,
     /// The bytes and time limits before key re-exchange.
     pub limits: Limits,
+    /// The rates of the data of each connection and channel, and the
+    /// most data a connection may transfer.
+    pub data_limits: DataLimits,
     /// The initial size of a channel (used for flow control).
     pub window_size: u32,
+    /// The largest a channel's window can grow to. When the other side
+    /// sends data faster than our window adjustments reach it, which
+    /// happens on links with a large bandwidth-delay product, the
+    /// window is doubled, like OpenSSH does. Set it to `window_size` to
+    /// keep windows constant.
+    pub max_window_size: u32,
+    /// The most data of a channel received and not read from its
+    /// [`Channel`](crate::Channel) yet. When a reader falls behind, the
+    /// window of its channel is kept to what fits in the rest of this
+    /// buffer, so that the other side stops sending, and we stop
+    /// reading from the connection, until the reader catches up. The
+    /// data kept for a channel is then bounded by the larger of
+    /// `window_size` and this. `None` (the default) adjusts windows as data arrives, which is
+    /// what handlers reading data in their `data` method need.
+    pub channel_buffer_size: Option<u32>,
     /// The maximal size of a single packet.
     pub maximum_packet_size: u32,
     /// Internal event buffer size
     pub event_buffer_size: usize,
     /// Lists of preferred algorithms.
     pub preferred: Preferred,
-    /// Maximal number of allowed authentication attempts.
+    /// Restricts the algorithms of `preferred` that can be negotiated,
+    /// and the signature algorithms of user keys, for instance to
+    /// [`AlgorithmPolicy::FIPS`].
+    pub algorithm_policy: Option<AlgorithmPolicy>,
+    /// Directions in which compression is offered, and its level, if
+    /// `preferred` allows compression.
+    pub compression: crate::compression::CompressionConfig,
+    /// Extensions sent to clients that support RFC 8308, in addition
+    /// to `server-sig-algs`.
+    pub extensions: Extensions,
+    /// Maximal number of failed authentication attempts, after which
+    /// the client is disconnected, like sshd's `MaxAuthTries`. As in
+    /// OpenSSH, the initial `none` request of clients isn't counted.
     pub max_auth_attempts: usize,
     /// Time after which the connection is garbage-collected.
     pub inactivity_timeout: Option<std::time::Duration>,
+    /// Time after which a connection without any channel data, in
+    /// either direction, is disconnected. Unlike
+    /// `inactivity_timeout`, keepalives and other messages don't count,
+    /// so forgotten sessions are closed even if the client is alive.
+    pub idle_timeout: Option<std::time::Duration>,
+    /// Time after which a channel without any data, in either
+    /// direction, is closed, after [`Handler::channel_idle`] is called.
+    pub channel_idle_timeout: Option<std::time::Duration>,
+    /// Time allowed to the client to send its version string, or
+    /// `inactivity_timeout` if `None`.
+    pub version_exchange_timeout: Option<std::time::Duration>,
+    /// Time allowed to complete the first key exchange after the client
+    /// connected.
+    pub kex_timeout: Option<std::time::Duration>,
+    /// Time allowed to authenticate after the client connected, like
+    /// sshd's `LoginGraceTime`, after which the connection is closed.
+    pub login_grace_time: Option<std::time::Duration>,
     /// If nothing is received from the client for this amount of time, send a keepalive message.
     pub keepalive_interval: Option<std::time::Duration>,
-    /// If this many keepalives have been sent without reply, close the connection.
+    /// If this many keepalives have been sent without reply, close the
+    /// connection at the next interval, like OpenSSH's `*AliveCountMax`.
+    /// `0` sends keepalives forever.
     pub keepalive_max: usize,
+    /// Announce all the keys in `keys` to the client after authentication
+    /// (OpenSSH's `hostkeys-00@openssh.com`), so that clients can learn
+    /// new keys before the old ones are retired.
+    pub announce_host_keys: bool,
+    /// If not empty, connections accepted by [`Server::run_on_socket`]
+    /// are closed right away, before the version exchange, unless
+    /// they come from one of these ranges.
+    pub allowed_addresses: Vec<IpCidr>,
+    /// Connections from these ranges are closed right away by
+    /// [`Server::run_on_socket`], even if they are in
+    /// `allowed_addresses`.
+    pub denied_addresses: Vec<IpCidr>,
+    /// Maximal number of connections served at the same time by
+    /// [`Server::run_on_socket`]. Clients connecting beyond that are
+    /// disconnected with [`Disconnect::TooManyConnections`].
+    pub max_connections: Option<usize>,
+    /// Maximal number of connections from a single address served at
+    /// the same time by [`Server::run_on_socket`].
+    pub max_connections_per_ip: Option<usize>,
+    /// Refuse new session channels once one has been opened, as if
+    /// every client sent OpenSSH's `no-more-sessions@openssh.com`
+    /// after opening its first session. Clients that do send it are
+    /// always obeyed.
+    pub no_more_sessions: bool,
+    /// The names of the environment variables clients may set with
+    /// `env` requests, like sshd's `AcceptEnv`, where `*` and `?` are
+    /// wildcards. Other variables are refused before reaching
+    /// [`Handler::env_request`]. If `None`, all of them reach it.
+    pub accept_env: Option<Vec<String>>,
+    /// Certificate authorities trusted to sign user certificates, like
+    /// sshd's `TrustedUserCAKeys`. If not empty, certificates are only
+    /// passed to [`Handler::auth_openssh_certificate`] if they are signed
+    /// by one of these keys and valid for the user.
+    pub trusted_user_ca_keys: Vec<ssh_key::PublicKey>,
+    /// Groups offered to clients using `diffie-hellman-group-exchange-*`.
+    /// The group closest to the size requested by the client is chosen.
+    /// Defaults to the primes of RFC 3526, use
+    /// [`GexGroup::parse_moduli`](crate::kex::GexGroup::parse_moduli) to
+    /// load OpenSSH's `moduli` file instead.
+    pub gex_groups: Vec<crate::kex::GexGroup>,
+    /// Receives the activity of the sessions, for instance to export
+    /// metrics.
+    pub stats: Option<Arc<dyn Stats>>,
+    /// Sees every message received or sent, to debug protocol issues.
+    pub packet_observer: Option<Arc<dyn PacketObserver>>,
+    /// Options of the TCP connections accepted by
+    /// [`Server::run_on_socket`]. `bind_device` is applied to the
+    /// listener of [`Server::run_on_address`].
+    pub socket_options: russh_util::net::SocketOptions,
 }
 
 impl Default for Config {
@@ -102,49 +242,205 @@ impl Default for Config {
                 env!("CARGO_PKG_VERSION")
             )),
             methods: auth::MethodSet::all(),
+            required_methods: Vec::new(),
             auth_banner: None,
             auth_rejection_time: std::time::Duration::from_secs(1),
             auth_rejection_time_initial: None,
+            auth_rejection_time_max: None,
             keys: Vec::new(),
+            key_signers: Vec::new(),
             window_size: 2097152,
+            max_window_size: 16777216,
+            channel_buffer_size: None,
             maximum_packet_size: 32768,
             event_buffer_size: 10,
             limits: Limits::default(),
+            data_limits: DataLimits::default(),
             preferred: Default::default(),
+            algorithm_policy: None,
+            compression: Default::default(),
+            extensions: Extensions::new(),
             max_auth_attempts: 10,
             inactivity_timeout: Some(std::time::Duration::from_secs(600)),
+            idle_timeout: None,
+            channel_idle_timeout: None,
+            version_exchange_timeout: None,
+            kex_timeout: None,
+            login_grace_time: Some(std::time::Duration::from_secs(120)),
             keepalive_interval: None,
             keepalive_max: 3,
+            announce_host_keys: true,
+            allowed_addresses: Vec::new(),
+            denied_addresses: Vec::new(),
+            max_connections: None,
+            max_connections_per_ip: None,
+            no_more_sessions: false,
+            accept_env: None,
+            trusted_user_ca_keys: Vec::new(),
+            gex_groups: crate::kex::GexGroup::defaults(),
+            stats: None,
+            packet_observer: None,
+            socket_options: Default::default(),
         }
     }
 }
 
+impl Config {
+    /// Whether clients may set the environment variable `name`,
+    /// according to `accept_env`.
+    pub fn accepts_env(&self, name: &str) -> bool {
+        match self.accept_env {
+            None => true,
+            Some(ref patterns) => patterns
+                .iter()
+                .any(|p| match_wildcard(p.as_bytes(), name.as_bytes())),
+        }
+    }
+}
+
+/// Match `text` against a pattern where `*` matches any sequence of
+/// characters and `?` any single one.
+fn match_wildcard(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The position of the last `*` in the pattern, and of the text
+    // it was matched up to.
+    let mut star = None;
+    while t < text.len() {
+        match (pattern.get(p), text.get(t)) {
+            (Some(b'*'), _) => {
+                star = Some((p, t));
+                p += 1;
+            }
+            (Some(a), Some(b)) if *a == b'?' || a == b => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // Let the last `*` match one more character.
+                Some((sp, st)) => {
+                    star = Some((sp, st + 1));
+                    p = sp + 1;
+                    t = st + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern
+        .get(p..)
+        .is_some_and(|rest| rest.iter().all(|c| *c == b'*'))
+}
+
 impl Debug for Config {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // display everything except the private keys
         f.debug_struct("Config")
             .field("server_id", &self.server_id)
             .field("methods", &self.methods)
+            .field("required_methods", &self.required_methods)
             .field("auth_banner", &self.auth_banner)
             .field("auth_rejection_time", &self.auth_rejection_time)
             .field(
                 "auth_rejection_time_initial",
                 &self.auth_rejection_time_initial,
             )
+            .field("auth_rejection_time_max", &self.auth_rejection_time_max)
             .field("keys", &"***")
+            .field(
+                "key_signers",
+                &self
+                    .key_signers
+                    .iter()
+                    .map(|k| k.public_key())
+                    .collect::<Vec<_>>(),
+            )
             .field("window_size", &self.window_size)
+            .field("max_window_size", &self.max_window_size)
+            .field("channel_buffer_size", &self.channel_buffer_size)
             .field("maximum_packet_size", &self.maximum_packet_size)
             .field("event_buffer_size", &self.event_buffer_size)
             .field("limits", &self.limits)
+            .field("data_limits", &self.data_limits)
             .field("preferred", &self.preferred)
+            .field("algorithm_policy", &self.algorithm_policy)
+            .field("compression", &self.compression)
+            .field("extensions", &self.extensions)
             .field("max_auth_attempts", &self.max_auth_attempts)
             .field("inactivity_timeout", &self.inactivity_timeout)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("channel_idle_timeout", &self.channel_idle_timeout)
+            .field("version_exchange_timeout", &self.version_exchange_timeout)
+            .field("kex_timeout", &self.kex_timeout)
+            .field("login_grace_time", &self.login_grace_time)
             .field("keepalive_interval", &self.keepalive_interval)
             .field("keepalive_max", &self.keepalive_max)
+            .field("announce_host_keys", &self.announce_host_keys)
+            .field("allowed_addresses", &self.allowed_addresses)
+            .field("denied_addresses", &self.denied_addresses)
+            .field("max_connections", &self.max_connections)
+            .field("max_connections_per_ip", &self.max_connections_per_ip)
+            .field("no_more_sessions", &self.no_more_sessions)
+            .field("accept_env", &self.accept_env)
+            .field("trusted_user_ca_keys", &self.trusted_user_ca_keys)
+            .field(
+                "gex_groups",
+                &self.gex_groups.iter().map(|g| g.bits()).collect::<Vec<_>>(),
+            )
+            .field("stats", &self.stats.is_some())
+            .field("packet_observer", &self.packet_observer)
+            .field("socket_options", &self.socket_options)
             .finish()
     }
 }
 
+impl Config {
+    /// All the host keys, `keys` followed by `key_signers`.
+    pub(crate) fn host_keys(&self) -> impl Iterator<Item = &(dyn KeySigner + 'static)> {
+        self.keys
+            .iter()
+            .map(|k| k as &(dyn KeySigner + 'static))
+            .chain(self.key_signers.iter().map(|k| &**k))
+    }
+
+    /// The algorithms of the host keys.
+    pub(crate) fn host_key_algorithms(&self) -> Vec<ssh_key::Algorithm> {
+        self.host_keys()
+            .map(|k| k.public_key().algorithm())
+            .collect()
+    }
+
+    /// The public key algorithms of `preferred` allowed by the
+    /// `algorithm_policy`.
+    pub(crate) fn key_algorithms(&self) -> Cow<'_, [ssh_key::Algorithm]> {
+        match self.algorithm_policy {
+            Some(ref policy) => self
+                .preferred
+                .key
+                .iter()
+                .filter(|k| policy.allows_key(k))
+                .cloned()
+                .collect::<Vec<_>>()
+                .into(),
+            None => Cow::Borrowed(&self.preferred.key),
+        }
+    }
+
+    /// The time to wait before rejecting an authentication attempt,
+    /// after `failures` failed ones.
+    pub(crate) fn auth_rejection_delay(&self, failures: usize) -> std::time::Duration {
+        match self.auth_rejection_time_max {
+            Some(max) => {
+                let factor = 1u32.checked_shl(failures as u32).unwrap_or(u32::MAX);
+                self.auth_rejection_time
+                    .checked_mul(factor)
+                    .map_or(max, |t| t.min(max))
+                    .max(self.auth_rejection_time)
+            }
+            None => self.auth_rejection_time,
+        }
+    }
+}
+
 /// A client's response in a challenge-response authentication.
 ///
 /// You should iterate it to get `&[u8]` response slices.
@@ -187,30 +483,65 @@ pub enum Auth {
 
 /// Server handler. Each client will have their own handler.
 ///
-/// Note: this is an `async_trait`. Click `[source]` on the right to see actual async function definitions.
-#[async_trait]
-pub trait Handler: Sized {
+/// Implementations write the methods they override as `async fn`. The
+/// futures they return must be `Send`, which is why the methods are
+/// declared returning `impl Future + Send`.
+pub trait Handler: Send {
     type Error: From<crate::Error> + Send;
 
+    /// The authentication banner sent to this client before it
+    /// authenticates, for instance a legal notice depending on the
+    /// client's address. If `None`, [`Config::auth_banner`] is sent,
+    /// if any.
+    fn auth_banner(&mut self) -> impl Future<Output = Result<Option<String>, Self::Error>> + Send {
+        async move { Ok(None) }
+    }
+
     /// Check authentication using the "none" method. Russh makes
     /// sure rejection happens in time `config.auth_rejection_time`,
     /// except if this method takes more than that.
     #[allow(unused_variables)]
-    async fn auth_none(&mut self, user: &str) -> Result<Auth, Self::Error> {
-        Ok(Auth::Reject {
-            proceed_with_methods: None,
-        })
+    fn auth_none(&mut self, user: &str) -> impl Future<Output = Result<Auth, Self::Error>> + Send {
+        async move {
+            Ok(Auth::Reject {
+                proceed_with_methods: None,
+            })
+        }
+    }
+
+    /// Called after each failed authentication attempt, for instance
+    /// to log it for fail2ban-style tools, with the requested user and
+    /// method. The number of failures on this connection is
+    /// [`Session::auth_failures`], the client is disconnected when it
+    /// reaches [`Config::max_auth_attempts`].
+    #[allow(unused_variables)]
+    fn auth_failed(
+        &mut self,
+        user: &str,
+        method: &str,
+        session: &mut Session,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 
     /// Check authentication using the "password" method. Russh
     /// makes sure rejection happens in time
     /// `config.auth_rejection_time`, except if this method takes more
-    /// than that.
+    /// than that. Passwords kept in memory are best held in a
+    /// [`SecretBuffer`](crate::SecretBuffer), and compared with
+    /// [`SecretBuffer::eq_bytes`](crate::SecretBuffer::eq_bytes), in
+    /// constant time.
     #[allow(unused_variables)]
-    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
-        Ok(Auth::Reject {
-            proceed_with_methods: None,
-        })
+    fn auth_password(
+        &mut self,
+        user: &str,
+        password: &str,
+    ) -> impl Future<Output = Result<Auth, Self::Error>> + Send {
+        async move {
+            Ok(Auth::Reject {
+                proceed_with_methods: None,
+            })
+        }
     }
 
     /// Check authentication using the "publickey" method. This method
@@ -221,12 +552,12 @@ pub trait Handler: Sized {
     /// `config.auth_rejection_time`, except if this method takes more
     /// time than that.
     #[allow(unused_variables)]
-    async fn auth_publickey_offered(
+    fn auth_publickey_offered(
         &mut self,
         user: &str,
         public_key: &ssh_key::PublicKey,
-    ) -> Result<Auth, Self::Error> {
-        Ok(Auth::Accept)
+    ) -> impl Future<Output = Result<Auth, Self::Error>> + Send {
+        async move { Ok(Auth::Accept) }
     }
 
     /// Check authentication using the "publickey" method. This method
@@ -236,31 +567,59 @@ pub trait Handler: Sized {
     /// `config.auth_rejection_time`, except if this method takes more
     /// time than that.
     #[allow(unused_variables)]
-    async fn auth_publickey(
+    fn auth_publickey(
         &mut self,
         user: &str,
         public_key: &ssh_key::PublicKey,
-    ) -> Result<Auth, Self::Error> {
-        Ok(Auth::Reject {
-            proceed_with_methods: None,
-        })
+    ) -> impl Future<Output = Result<Auth, Self::Error>> + Send {
+        async move {
+            Ok(Auth::Reject {
+                proceed_with_methods: None,
+            })
+        }
     }
 
     /// Check authentication using an OpenSSH certificate. This method
     /// is called after the signature has been verified and key
-    /// ownership has been confirmed.
+    /// ownership has been confirmed. If [`Config::trusted_user_ca_keys`]
+    /// is set, the certificate has also been validated against it.
     /// Russh guarantees that rejection happens in constant time
     /// `config.auth_rejection_time`, except if this method takes more
     /// time than that.
     #[allow(unused_variables)]
-    async fn auth_openssh_certificate(
+    fn auth_openssh_certificate(
         &mut self,
         user: &str,
         certificate: &Certificate,
-    ) -> Result<Auth, Self::Error> {
-        Ok(Auth::Reject {
-            proceed_with_methods: None,
-        })
+    ) -> impl Future<Output = Result<Auth, Self::Error>> + Send {
+        async move {
+            Ok(Auth::Reject {
+                proceed_with_methods: None,
+            })
+        }
+    }
+
+    /// Check authentication using the "hostbased" method. This method
+    /// is called after the signature with `host_key` has been
+    /// verified. Implementations must check that `host_key` is indeed
+    /// the key of `client_host` (for instance from a known hosts file),
+    /// and that `client_user` on that host may log in as `user`.
+    /// Russh guarantees that rejection happens in constant time
+    /// `config.auth_rejection_time`, except if this method takes more
+    /// time than that.
+    #[allow(unused_variables)]
+    fn auth_hostbased(
+        &mut self,
+        user: &str,
+        client_host: &str,
+        client_user: &str,
+        host_key: &ssh_key::PublicKey,
+    ) -> impl Future<Output = Result<Auth, Self::Error>> + Send {
+        async move {
+            Ok(Auth::Reject {
+                proceed_with_methods: None,
+            })
+        }
     }
 
     /// Check authentication using the "keyboard-interactive"
@@ -268,71 +627,132 @@ pub trait Handler: Sized {
     /// `config.auth_rejection_time`, except if this method takes more
     /// than that.
     #[allow(unused_variables)]
-    async fn auth_keyboard_interactive(
+    fn auth_keyboard_interactive(
         &mut self,
         user: &str,
         submethods: &str,
-        response: Option<Response<'async_trait>>,
-    ) -> Result<Auth, Self::Error> {
-        Ok(Auth::Reject {
-            proceed_with_methods: None,
-        })
+        response: Option<Response<'_>>,
+    ) -> impl Future<Output = Result<Auth, Self::Error>> + Send {
+        async move {
+            Ok(Auth::Reject {
+                proceed_with_methods: None,
+            })
+        }
+    }
+
+    /// Start a "gssapi-with-mic" authentication of `user` with the
+    /// GSSAPI `mechanism`, a DER-encoded OID such as
+    /// [`KRB5_MECHANISM`](crate::gssapi::KRB5_MECHANISM). Return `None` if
+    /// the mechanism isn't supported.
+    #[cfg(feature = "gssapi")]
+    #[allow(unused_variables)]
+    fn auth_gssapi_context(
+        &mut self,
+        user: &str,
+        mechanism: &[u8],
+    ) -> impl Future<Output = Result<Option<Box<dyn crate::gssapi::ServerContext>>, Self::Error>> + Send
+    {
+        async move { Ok(None) }
+    }
+
+    /// Check whether the GSSAPI `principal` (such as `user@REALM`) may
+    /// log in as `user`, like Kerberos' `.k5login`. This is called after
+    /// the context is established and its MIC has been verified.
+    #[cfg(feature = "gssapi")]
+    #[allow(unused_variables)]
+    fn auth_gssapi_with_mic(
+        &mut self,
+        user: &str,
+        principal: &str,
+    ) -> impl Future<Output = Result<Auth, Self::Error>> + Send {
+        async move {
+            Ok(Auth::Reject {
+                proceed_with_methods: None,
+            })
+        }
     }
 
     /// Called when authentication succeeds for a session.
     #[allow(unused_variables)]
-    async fn auth_succeeded(&mut self, session: &mut Session) -> Result<(), Self::Error> {
-        Ok(())
+    fn auth_succeeded(
+        &mut self,
+        session: &mut Session,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
+    }
+
+    /// Called when the client sends its extensions (RFC 8308), right
+    /// after the key exchange. They are also available from
+    /// [`Session::client_extensions`].
+    #[allow(unused_variables)]
+    fn ext_info(
+        &mut self,
+        extensions: &Extensions,
+        session: &mut Session,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 
     /// Called when the client closes a channel.
     #[allow(unused_variables)]
-    async fn channel_close(
+    fn channel_close(
         &mut self,
         channel: ChannelId,
         session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        Ok(())
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 
     /// Called when the client sends EOF to a channel.
     #[allow(unused_variables)]
-    async fn channel_eof(
+    fn channel_eof(
         &mut self,
         channel: ChannelId,
         session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        Ok(())
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
+    }
+
+    /// Called when a channel had no data for
+    /// [`Config::channel_idle_timeout`], just before it is closed, to
+    /// tell the user for instance.
+    #[allow(unused_variables)]
+    fn channel_idle(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 
     /// Called when a new session channel is created.
     /// Return value indicates whether the channel request should be granted.
     #[allow(unused_variables)]
-    async fn channel_open_session(
+    fn channel_open_session(
         &mut self,
         channel: Channel<Msg>,
         session: &mut Session,
-    ) -> Result<bool, Self::Error> {
-        Ok(false)
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        async move { Ok(false) }
     }
 
     /// Called when a new X11 channel is created.
     /// Return value indicates whether the channel request should be granted.
     #[allow(unused_variables)]
-    async fn channel_open_x11(
+    fn channel_open_x11(
         &mut self,
         channel: Channel<Msg>,
         originator_address: &str,
         originator_port: u32,
         session: &mut Session,
-    ) -> Result<bool, Self::Error> {
-        Ok(false)
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        async move { Ok(false) }
     }
 
     /// Called when a new TCP/IP is created.
     /// Return value indicates whether the channel request should be granted.
     #[allow(unused_variables)]
-    async fn channel_open_direct_tcpip(
+    fn channel_open_direct_tcpip(
         &mut self,
         channel: Channel<Msg>,
         host_to_connect: &str,
@@ -340,14 +760,43 @@ pub trait Handler: Sized {
         originator_address: &str,
         originator_port: u32,
         session: &mut Session,
-    ) -> Result<bool, Self::Error> {
-        Ok(false)
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        async move { Ok(false) }
+    }
+
+    /// Called when the client opens a `direct-streamlocal@openssh.com`
+    /// channel, to connect to the UNIX socket at `socket_path`.
+    /// Return value indicates whether the channel request should be granted.
+    #[allow(unused_variables)]
+    fn channel_open_direct_streamlocal(
+        &mut self,
+        channel: Channel<Msg>,
+        socket_path: &str,
+        session: &mut Session,
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        async move { Ok(false) }
+    }
+
+    /// Called when the client opens a `tun@openssh.com` channel, to
+    /// forward packets to the tunnel device `unit` (or any, if it is
+    /// [`TUN_ANY_UNIT`](crate::tun::TUN_ANY_UNIT)). Wrap the channel in
+    /// a [`TunChannel`](crate::tun::TunChannel) to exchange packets.
+    /// Return value indicates whether the channel request should be granted.
+    #[allow(unused_variables)]
+    fn channel_open_tun(
+        &mut self,
+        channel: Channel<Msg>,
+        mode: TunMode,
+        unit: u32,
+        session: &mut Session,
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        async move { Ok(false) }
     }
 
     /// Called when a new forwarded connection comes in.
     /// <https://www.rfc-editor.org/rfc/rfc4254#section-7>
     #[allow(unused_variables)]
-    async fn channel_open_forwarded_tcpip(
+    fn channel_open_forwarded_tcpip(
         &mut self,
         channel: Channel<Msg>,
         host_to_connect: &str,
@@ -355,34 +804,34 @@ pub trait Handler: Sized {
         originator_address: &str,
         originator_port: u32,
         session: &mut Session,
-    ) -> Result<bool, Self::Error> {
-        Ok(false)
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        async move { Ok(false) }
     }
 
     /// Called when the client confirmed our request to open a
     /// channel. A channel can only be written to after receiving this
     /// message (this library panics otherwise).
     #[allow(unused_variables)]
-    async fn channel_open_confirmation(
+    fn channel_open_confirmation(
         &mut self,
         id: ChannelId,
         max_packet_size: u32,
         window_size: u32,
         session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        Ok(())
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 
     /// Called when a data packet is received. A response can be
     /// written to the `response` argument.
     #[allow(unused_variables)]
-    async fn data(
+    fn data(
         &mut self,
         channel: ChannelId,
         data: &[u8],
         session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        Ok(())
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 
     /// Called when an extended data packet is received. Code 1 means
@@ -390,37 +839,40 @@ pub trait Handler: Sized {
     /// defined (see
     /// [RFC4254](https://tools.ietf.org/html/rfc4254#section-5.2)).
     #[allow(unused_variables)]
-    async fn extended_data(
+    fn extended_data(
         &mut self,
         channel: ChannelId,
         code: u32,
         data: &[u8],
         session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        Ok(())
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 
     /// Called when the network window is adjusted, meaning that we
     /// can send more bytes.
     #[allow(unused_variables)]
-    async fn window_adjusted(
+    fn window_adjusted(
         &mut self,
         channel: ChannelId,
         new_size: u32,
         session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        Ok(())
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 
-    /// Called when this server adjusts the network window. Return the
-    /// next target window.
+    /// Called when this server adjusts the window of `channel`, with
+    /// the window it targets, after scaling it up to
+    /// [`Config::max_window_size`]. Return the next target window for
+    /// this channel.
     #[allow(unused_variables)]
     fn adjust_window(&mut self, channel: ChannelId, current: u32) -> u32 {
         current
     }
 
     /// The client requests a pseudo-terminal with the given
-    /// specifications.
+    /// specifications. `modes` are the terminal modes sent by the
+    /// client, such as `modes.is_set(Pty::ECHO)`.
     ///
     /// **Note:** Success or failure should be communicated to the client by calling
     /// `session.channel_success(channel)` or `session.channel_failure(channel)` respectively. For
@@ -435,7 +887,7 @@ pub trait Handler: Sized {
     ///     row_height: u32,
     ///     pix_width: u32,
     ///     pix_height: u32,
-    ///     modes: &[(Pty, u32)],
+    ///     modes: &PtyModes,
     ///     session: &mut Session,
     /// ) -> Result<(), Self::Error> {
     ///     session.channel_success(channel);
@@ -443,7 +895,7 @@ pub trait Handler: Sized {
     /// }
     /// ```
     #[allow(unused_variables, clippy::too_many_arguments)]
-    async fn pty_request(
+    fn pty_request(
         &mut self,
         channel: ChannelId,
         term: &str,
@@ -451,10 +903,10 @@ pub trait Handler: Sized {
         row_height: u32,
         pix_width: u32,
         pix_height: u32,
-        modes: &[(Pty, u32)],
+        modes: &PtyModes,
         session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        Ok(())
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 
     /// The client requests an X11 connection.
@@ -478,7 +930,7 @@ pub trait Handler: Sized {
     /// }
     /// ```
     #[allow(unused_variables)]
-    async fn x11_request(
+    fn x11_request(
         &mut self,
         channel: ChannelId,
         single_connection: bool,
@@ -486,13 +938,14 @@ pub trait Handler: Sized {
         x11_auth_cookie: &str,
         x11_screen_number: u32,
         session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        Ok(())
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 
     /// The client wants to set the given environment variable. Check
     /// these carefully, as it is dangerous to allow any variable
-    /// environment to be set.
+    /// environment to be set. Only the variables accepted by
+    /// [`Config::accept_env`] are passed here, the others are refused.
     ///
     /// **Note:** Success or failure should be communicated to the client by calling
     /// `session.channel_success(channel)` or `session.channel_failure(channel)` respectively. For
@@ -511,14 +964,14 @@ pub trait Handler: Sized {
     /// }
     /// ```
     #[allow(unused_variables)]
-    async fn env_request(
+    fn env_request(
         &mut self,
         channel: ChannelId,
         variable_name: &str,
         variable_value: &str,
         session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        Ok(())
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 
     /// The client requests a shell.
@@ -538,12 +991,12 @@ pub trait Handler: Sized {
     /// }
     /// ```
     #[allow(unused_variables)]
-    async fn shell_request(
+    fn shell_request(
         &mut self,
         channel: ChannelId,
         session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        Ok(())
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 
     /// The client sends a command to execute, to be passed to a
@@ -565,17 +1018,20 @@ pub trait Handler: Sized {
     /// }
     /// ```
     #[allow(unused_variables)]
-    async fn exec_request(
+    fn exec_request(
         &mut self,
         channel: ChannelId,
         data: &[u8],
         session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        Ok(())
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 
     /// The client asks to start the subsystem with the given name
-    /// (such as sftp).
+    /// (such as sftp). Servers with several subsystems can leave this
+    /// and serve their channels with a
+    /// [`SubsystemRegistry`](subsystem::SubsystemRegistry), which
+    /// replies to these requests.
     ///
     /// **Note:** Success or failure should be communicated to the client by calling
     /// `session.channel_success(channel)` or `session.channel_failure(channel)` respectively. For
@@ -593,13 +1049,13 @@ pub trait Handler: Sized {
     /// }
     /// ```
     #[allow(unused_variables)]
-    async fn subsystem_request(
+    fn subsystem_request(
         &mut self,
         channel: ChannelId,
         name: &str,
         session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        Ok(())
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 
     /// The client's pseudo-terminal window size has changed.
@@ -623,7 +1079,7 @@ pub trait Handler: Sized {
     /// }
     /// ```
     #[allow(unused_variables)]
-    async fn window_change_request(
+    fn window_change_request(
         &mut self,
         channel: ChannelId,
         col_width: u32,
@@ -631,8 +1087,8 @@ pub trait Handler: Sized {
         pix_width: u32,
         pix_height: u32,
         session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        Ok(())
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 
     /// The client requests OpenSSH agent forwarding
@@ -652,67 +1108,118 @@ pub trait Handler: Sized {
     /// }
     /// ```
     #[allow(unused_variables)]
-    async fn agent_request(
+    fn agent_request(
         &mut self,
         channel: ChannelId,
         session: &mut Session,
-    ) -> Result<bool, Self::Error> {
-        Ok(false)
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        async move { Ok(false) }
     }
 
     /// The client is sending a signal (usually to pass to the
     /// currently running process).
     #[allow(unused_variables)]
-    async fn signal(
+    fn signal(
         &mut self,
         channel: ChannelId,
         signal: Sig,
         session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        Ok(())
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
+    }
+
+    /// The client requests a break of `length` milliseconds (RFC
+    /// 4335), usually to pass to a serial console. Return whether the
+    /// break was performed, which is replied to the client if it asked.
+    #[allow(unused_variables)]
+    fn break_request(
+        &mut self,
+        channel: ChannelId,
+        length: u32,
+        session: &mut Session,
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        async move { Ok(false) }
     }
 
     /// Used for reverse-forwarding ports, see
     /// [RFC4254](https://tools.ietf.org/html/rfc4254#section-7).
     /// If `port` is 0, you should set it to the allocated port number.
     #[allow(unused_variables)]
-    async fn tcpip_forward(
+    fn tcpip_forward(
         &mut self,
         address: &str,
         port: &mut u32,
         session: &mut Session,
-    ) -> Result<bool, Self::Error> {
-        Ok(false)
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        async move { Ok(false) }
     }
 
     /// Used to stop the reverse-forwarding of a port, see
     /// [RFC4254](https://tools.ietf.org/html/rfc4254#section-7).
     #[allow(unused_variables)]
-    async fn cancel_tcpip_forward(
+    fn cancel_tcpip_forward(
         &mut self,
         address: &str,
         port: u32,
         session: &mut Session,
-    ) -> Result<bool, Self::Error> {
-        Ok(false)
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        async move { Ok(false) }
     }
 
+    /// Used to ask the server to listen on the UNIX socket
+    /// `socket_path` and open a `forwarded-streamlocal@openssh.com`
+    /// channel (see [`Handle::channel_open_forwarded_streamlocal`]) for
+    /// each connection to it.
     #[allow(unused_variables)]
-    async fn streamlocal_forward(
+    fn streamlocal_forward(
         &mut self,
         socket_path: &str,
         session: &mut Session,
-    ) -> Result<bool, Self::Error> {
-        Ok(false)
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        async move { Ok(false) }
     }
 
+    /// Used to stop a forwarding started by
+    /// [`Handler::streamlocal_forward`].
     #[allow(unused_variables)]
-    async fn cancel_streamlocal_forward(
+    fn cancel_streamlocal_forward(
         &mut self,
         socket_path: &str,
         session: &mut Session,
-    ) -> Result<bool, Self::Error> {
-        Ok(false)
+    ) -> impl Future<Output = Result<bool, Self::Error>> + Send {
+        async move { Ok(false) }
+    }
+
+    /// Called when the client sends a disconnect message, just before
+    /// the session ends.
+    #[allow(unused_variables)]
+    fn disconnected(
+        &mut self,
+        info: &RemoteDisconnectInfo,
+        session: &mut Session,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
+    }
+
+    /// Called when a key exchange completed, the first one before
+    /// authentication, then after each key re-exchange.
+    #[allow(unused_variables)]
+    fn negotiated(
+        &mut self,
+        negotiated: &Negotiated,
+        session: &mut Session,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
+    }
+
+    /// Called when the client sends `no-more-sessions@openssh.com`,
+    /// after which requests to open session channels are refused.
+    #[allow(unused_variables)]
+    fn no_more_sessions(
+        &mut self,
+        session: &mut Session,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { Ok(()) }
     }
 }
 
@@ -721,6 +1228,33 @@ pub trait Handler: Sized {
 pub trait Server {
     /// The type of handlers.
     type Handler: Handler + Send + 'static;
+    /// Called when a client connects to [`Server::run_on_socket`], if
+    /// its address is allowed by [`Config::allowed_addresses`] and
+    /// [`Config::denied_addresses`], before anything is sent to it.
+    /// Returning `false` closes the connection, which is much cheaper
+    /// than rejecting it later, for instance to drop scanners.
+    #[allow(unused_variables)]
+    fn accept_connection(&mut self, peer_addr: Option<std::net::SocketAddr>) -> bool {
+        true
+    }
+    /// Whether a new client connecting from `peer_addr` can be
+    /// served by [`Server::run_on_socket`], while `open` connections
+    /// are open, `open_from_peer` of which come from the same address.
+    /// Refused clients are disconnected with
+    /// [`Disconnect::TooManyConnections`]. By default, checks
+    /// [`Config::max_connections`] and
+    /// [`Config::max_connections_per_ip`].
+    #[allow(unused_variables)]
+    fn within_connection_limits(
+        &mut self,
+        config: &Config,
+        peer_addr: Option<std::net::SocketAddr>,
+        open: usize,
+        open_from_peer: usize,
+    ) -> bool {
+        limits::within_limits(config, open, open_from_peer)
+    }
+
     /// Called when a new client connects.
     fn new_client(&mut self, peer_addr: Option<std::net::SocketAddr>) -> Self::Handler;
     /// Called when an active connection fails.
@@ -732,6 +1266,43 @@ pub trait Server {
         &mut self,
         config: Arc<Config>,
         socket: &TcpListener,
+    ) -> Result<(), std::io::Error> {
+        self.run_on_socket_with_shutdown(config, socket, ShutdownHandle::new())
+            .await
+    }
+
+    /// Like [`Server::run_on_socket`], until a shutdown is requested
+    /// through `shutdown`. This returns when all the sessions have
+    /// ended, or have been aborted at the end of the shutdown timeout.
+    async fn run_on_socket_with_shutdown(
+        &mut self,
+        config: Arc<Config>,
+        socket: &TcpListener,
+        shutdown: ShutdownHandle,
+    ) -> Result<(), std::io::Error> {
+        self.run_on_listener_with_shutdown(config, socket, shutdown)
+            .await
+    }
+
+    /// Run a server on the connections of any [`Listener`], such as a
+    /// Unix socket, or TCP connections wrapped in TLS with
+    /// [`Listener::upgrade`].
+    async fn run_on_listener<L: Listener>(
+        &mut self,
+        config: Arc<Config>,
+        listener: &L,
+    ) -> Result<(), std::io::Error> {
+        self.run_on_listener_with_shutdown(config, listener, ShutdownHandle::new())
+            .await
+    }
+
+    /// Like [`Server::run_on_listener`], until a shutdown is requested
+    /// through `shutdown`, see [`Server::run_on_socket_with_shutdown`].
+    async fn run_on_listener_with_shutdown<L: Listener>(
+        &mut self,
+        config: Arc<Config>,
+        listener: &L,
+        shutdown: ShutdownHandle,
     ) -> Result<(), std::io::Error> {
         if config.maximum_packet_size > 65535 {
             error!(
@@ -741,39 +1312,105 @@ pub trait Server {
         }
 
         let (error_tx, mut error_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (done_tx, mut done_rx) = tokio::sync::mpsc::unbounded_channel();
+        let counts = limits::ConnectionCounts::default();
+        let mut sessions = HashMap::new();
+        let mut next_id = 0u64;
 
-        loop {
+        let request = loop {
             tokio::select! {
-                accept_result = socket.accept() => {
+                accept_result = listener.accept(&config.socket_options) => {
                     match accept_result {
-                        Ok((socket, _)) => {
+                        Ok(Accepted { stream: socket, peer_addr, socket: control }) => {
+                            if !address::is_allowed(&config, peer_addr)
+                                || !self.accept_connection(peer_addr)
+                            {
+                                debug!("Refused connection from {:?}", peer_addr);
+                                continue;
+                            }
+                            let (open, open_from_peer) = counts.get(peer_addr);
+                            if !self.within_connection_limits(&config, peer_addr, open, open_from_peer) {
+                                debug!("Too many connections, refusing {:?}", peer_addr);
+                                let config = config.clone();
+                                russh_util::runtime::spawn(async move {
+                                    let _ = limits::refuse(
+                                        &config,
+                                        socket,
+                                        Disconnect::TooManyConnections,
+                                        "Too many connections",
+                                    )
+                                    .await;
+                                });
+                                continue;
+                            }
+                            let guard = counts.add(peer_addr);
                             let config = config.clone();
-                            let  handler = self.new_client(socket.peer_addr().ok());
+                            let  handler = self.new_client(peer_addr);
                             let error_tx = error_tx.clone();
-                            russh_util::runtime::spawn(async move {
-                                let session = match run_stream(config, socket,  handler).await {
-                                    Ok(s) => s,
+                            let done_tx = done_tx.clone();
+                            let shutdown = shutdown.clone();
+                            let id = next_id;
+                            next_id += 1;
+                            let (abort, registration) = futures::future::AbortHandle::new_pair();
+                            sessions.insert(id, abort);
+                            russh_util::runtime::spawn(futures::future::Abortable::new(async move {
+                                let _guard = guard;
+                                // The session runs in this task, so that it's
+                                // aborted with it.
+                                let result = match start_session(config, socket, control).await {
+                                    Ok((session, stream, connected_at)) => {
+                                        let handle = session.handle();
+                                        let span = logging::connection_span("server", logging::next_connection_id());
+                                        let run = session.run(stream, handler, connected_at).instrument(span);
+                                        run_until_shutdown(run, handle, &shutdown).await
+                                    }
                                     Err(e) => {
                                         debug!("Connection setup failed");
-                                        let _ = error_tx.send(e);
-                                        return
+                                        Err(e.into())
                                     }
                                 };
-                                match session.await {
+                                match result {
                                     Ok(_) => debug!("Connection closed"),
                                     Err(e) => {
                                         debug!("Connection closed with error");
                                         let _ = error_tx.send(e);
                                     }
                                 }
-                            });
+                                let _ = done_tx.send(id);
+                            }.instrument(logging::peer_span(peer_addr)), registration));
                         }
-                        _ => break,
+                        _ => break None,
                     }
                 },
                 Some(error) = error_rx.recv() => {
                     self.handle_session_error(error);
                 }
+                Some(id) = done_rx.recv() => {
+                    sessions.remove(&id);
+                }
+                request = shutdown.requested() => break Some(request),
+            }
+        };
+
+        if let Some(request) = request {
+            debug!("Shutting down, {} sessions active", sessions.len());
+            let drain = async {
+                while !sessions.is_empty() {
+                    tokio::select! {
+                        Some(error) = error_rx.recv() => {
+                            self.handle_session_error(error);
+                        }
+                        Some(id) = done_rx.recv() => {
+                            sessions.remove(&id);
+                        }
+                    }
+                }
+            };
+            if tokio::time::timeout(request.timeout, drain).await.is_err() {
+                debug!("Aborting {} sessions", sessions.len());
+            }
+            for abort in sessions.values() {
+                abort.abort()
             }
         }
 
@@ -788,11 +1425,52 @@ pub trait Server {
         config: Arc<Config>,
         addrs: A,
     ) -> Result<(), std::io::Error> {
-        let socket = TcpListener::bind(addrs).await?;
+        let socket = match config.socket_options.bind_device {
+            Some(ref device) => bind_to_device(addrs, device).await?,
+            None => TcpListener::bind(addrs).await?,
+        };
         self.run_on_socket(config, &socket).await
     }
 }
 
+/// Listen on the first address of `addrs`, on the network interface
+/// `device` only.
+async fn bind_to_device<A: ToSocketAddrs>(addrs: A, device: &str) -> std::io::Result<TcpListener> {
+    let addr = tokio::net::lookup_host(addrs)
+        .await?
+        .next()
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address to listen on")
+        })?;
+    let socket = if addr.is_ipv6() {
+        tokio::net::TcpSocket::new_v6()?
+    } else {
+        tokio::net::TcpSocket::new_v4()?
+    };
+    socket.set_reuseaddr(true)?;
+    russh_util::net::bind_device(&socket, device)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Wait for `session` to end. If a shutdown is requested before, send
+/// it a disconnect message if requested, and keep waiting.
+async fn run_until_shutdown<F: Future<Output = Result<(), E>>, E>(
+    session: F,
+    handle: Handle,
+    shutdown: &ShutdownHandle,
+) -> Result<(), E> {
+    pin!(session);
+    let request = tokio::select! {
+        result = &mut session => return result,
+        request = shutdown.requested() => request,
+    };
+    if let Some((reason, description)) = request.disconnect {
+        let _ = handle.disconnect(reason, description, String::new()).await;
+    }
+    session.await
+}
+
 use std::cell::RefCell;
 thread_local! {
     static B1: RefCell<CryptoVec> = RefCell::new(CryptoVec::new());
@@ -841,13 +1519,55 @@ impl<H: Handler> Future for RunningSession<H> {
 /// Start a single connection in the background.
 pub async fn run_stream<H, R>(
     config: Arc<Config>,
-    mut stream: R,
+    stream: R,
     handler: H,
 ) -> Result<RunningSession<H>, H::Error>
 where
     H: Handler + Send + 'static,
     R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
+    run_stream_with_socket(config, stream, handler, None).await
+}
+
+/// Like [`run_stream`], with the socket of a TCP connection, to switch
+/// it to the interactive mode when a terminal is requested.
+async fn run_stream_with_socket<H, R>(
+    config: Arc<Config>,
+    stream: R,
+    handler: H,
+    socket: Option<SocketControl>,
+) -> Result<RunningSession<H>, H::Error>
+where
+    H: Handler + Send + 'static,
+    R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (session, stream, connected_at) = start_session(config, stream, socket).await?;
+    let handle = session.handle();
+    let span = logging::connection_span("server", logging::next_connection_id());
+    let join =
+        russh_util::runtime::spawn(session.run(stream, handler, connected_at).instrument(span));
+
+    Ok(RunningSession { handle, join })
+}
+
+/// Exchange the SSH ids, and allocate a session to run on `stream`.
+async fn start_session<R>(
+    config: Arc<Config>,
+    stream: R,
+    socket: Option<SocketControl>,
+) -> Result<
+    (
+        Session,
+        SshRead<crate::throttle::Throttled<R>>,
+        tokio::time::Instant,
+    ),
+    Error,
+>
+where
+    R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let connected_at = tokio::time::Instant::now();
+    let mut stream = crate::throttle::Throttled::new(stream, &config.data_limits);
     // Writing SSH id.
     let mut write_buffer = SSHBuffer::new();
     write_buffer.send_ssh_id(&config.as_ref().server_id);
@@ -857,27 +1577,40 @@ where
     let mut stream = SshRead::new(stream);
     let (sender, receiver) = tokio::sync::mpsc::channel(config.event_buffer_size);
     let common = read_ssh_id(config, &mut stream).await?;
-    let handle = server::session::Handle { sender };
+    let handle = server::session::Handle {
+        sender,
+        negotiated: SharedNegotiated::default(),
+    };
     let session = Session {
         target_window_size: common.config.window_size,
         common,
         receiver,
-        sender: handle.clone(),
+        sender: handle,
         pending_reads: Vec::new(),
         pending_len: 0,
         channels: HashMap::new(),
+        inbound_read: Arc::new(tokio::sync::Notify::new()),
         open_global_requests: VecDeque::new(),
+        client_extensions: Extensions::new(),
+        no_more_sessions: false,
+        auth_failures: 0,
+        last_auth_request: None,
+        open_channels: HashSet::new(),
+        transferred: 0,
+        data_at: russh_util::time::Instant::now(),
+        socket,
     };
-    let join = russh_util::runtime::spawn(session.run(stream, handler));
-
-    Ok(RunningSession { handle, join })
+    Ok((session, stream, connected_at))
 }
 
 async fn read_ssh_id<R: AsyncRead + Unpin>(
     config: Arc<Config>,
     read: &mut SshRead<R>,
 ) -> Result<CommonSession<Arc<Config>>, Error> {
-    let sshid = if let Some(t) = config.inactivity_timeout {
+    let sshid = if let Some(t) = config
+        .version_exchange_timeout
+        .or(config.inactivity_timeout)
+    {
         tokio::time::timeout(t, read.read_ssh_id()).await??
     } else {
         read.read_ssh_id().await?
@@ -899,6 +1632,7 @@ async fn read_ssh_id<R: AsyncRead + Unpin>(
         remote_to_local: Box::new(clear::Key),
     };
     let mut write_buffer = SSHBuffer::new();
+    write_buffer.observer = config.packet_observer.clone();
     kexinit.server_write(
         config.as_ref(),
         &mut *cipher.local_to_remote,
@@ -923,7 +1657,14 @@ async fn read_ssh_id<R: AsyncRead + Unpin>(
     })
 }
 
-const STRICT_KEX_MSG_ORDER: &[u8] = &[msg::KEXINIT, msg::KEX_ECDH_INIT, msg::NEWKEYS];
+/// Messages allowed at each sequence number of the initial key
+/// exchange in strict mode. Group exchanges take one more round trip.
+const STRICT_KEX_MSG_ORDER: &[&[u8]] = &[
+    &[msg::KEXINIT],
+    &[msg::KEX_ECDH_INIT, msg::KEX_DH_GEX_REQUEST],
+    &[msg::NEWKEYS, msg::KEX_DH_GEX_INIT],
+    &[msg::NEWKEYS],
+];
 
 async fn reply<H: Handler + Send>(
     session: &mut Session,
@@ -934,10 +1675,12 @@ async fn reply<H: Handler + Send>(
     if let Some(message_type) = buf.first() {
         if session.common.strict_kex && session.common.encrypted.is_none() {
             let seqno = seqn.0 - 1; // was incremented after read()
-            if let Some(expected) = STRICT_KEX_MSG_ORDER.get(seqno as usize) {
-                if message_type != expected {
-                    return Err(strict_kex_violation(*message_type, seqno as usize).into());
-                }
+
+            // Nothing but the key exchange is allowed before the
+            // first NEWKEYS, not even `IGNORE` messages.
+            match STRICT_KEX_MSG_ORDER.get(seqno as usize) {
+                Some(expected) if expected.contains(message_type) => {}
+                _ => return Err(strict_kex_violation(*message_type, seqno as usize).into()),
             }
         }
 
@@ -973,12 +1716,16 @@ async fn reply<H: Handler + Send>(
                 }
             }
             Some(Kex::Dh(kexdh)) => {
-                session.common.kex = Some(kexdh.parse(
-                    session.common.config.as_ref(),
-                    &mut *session.common.cipher.local_to_remote,
-                    buf,
-                    &mut session.common.write_buffer,
-                )?);
+                session.common.kex = Some(
+                    kexdh
+                        .parse(
+                            session.common.config.as_ref(),
+                            &mut *session.common.cipher.local_to_remote,
+                            buf,
+                            &mut session.common.write_buffer,
+                        )
+                        .await?,
+                );
                 if let Some(Kex::Keys(_)) = session.common.kex {
                     // just sent NEWKEYS
                     session.common.maybe_reset_seqn();
@@ -996,7 +1743,12 @@ async fn reply<H: Handler + Send>(
                         accepted: false,
                     },
                     newkeys,
+                    true,
                 );
+                if let Some(ref stats) = session.common.config.stats {
+                    stats.kex_completed(false)
+                }
+                session.report_negotiated(handler).await?;
                 session.maybe_send_ext_info()?;
                 if session.common.strict_kex {
                     *seqn = Wrapping(0);
@@ -1,18 +1,19 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
 
-use log::debug;
-use negotiation::parse_kex_algo_list;
-use russh_keys::helpers::NameList;
 use russh_keys::map_err;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::sync::mpsc::{unbounded_channel, Receiver, Sender, UnboundedReceiver};
-use tokio::sync::{oneshot, Mutex};
+use tokio::sync::{oneshot, Notify};
 
 use super::*;
-use crate::channels::{Channel, ChannelMsg, ChannelRef};
+use crate::channels::{Channel, ChannelMsg, ChannelRef, WindowSizeRef};
+use crate::extensions::{self, Extensions};
 use crate::kex::EXTENSION_SUPPORT_AS_CLIENT;
+use crate::logging::{self, debug, Instrument};
 use crate::msg;
+use crate::negotiation::SharedNegotiated;
+use crate::observer::{observe, Direction};
 
 /// A connected server session. This type is unique to a client.
 #[derive(Debug)]
@@ -24,7 +25,38 @@ pub struct Session {
     pub(crate) pending_reads: Vec<CryptoVec>,
     pub(crate) pending_len: u32,
     pub(crate) channels: HashMap<ChannelId, ChannelRef>,
+    /// Notified when channels limited by
+    /// [`Config::channel_buffer_size`] are read.
+    pub(crate) inbound_read: Arc<Notify>,
     pub(crate) open_global_requests: VecDeque<GlobalRequestResponse>,
+    pub(crate) client_extensions: Extensions,
+    /// Whether new session channels are refused.
+    pub(crate) no_more_sessions: bool,
+    /// Number of failed authentication attempts.
+    pub(crate) auth_failures: usize,
+    /// User and method of the last authentication request.
+    pub(crate) last_auth_request: Option<(String, String)>,
+    /// Channels reported as open to [`Config::stats`].
+    pub(crate) open_channels: HashSet<ChannelId>,
+    /// The bytes sent and received, checked against
+    /// [`DataLimits::max_bytes`](super::DataLimits::max_bytes).
+    pub(crate) transferred: u64,
+    /// When channel data was last sent or received, for
+    /// [`Config::idle_timeout`](super::Config::idle_timeout).
+    pub(crate) data_at: russh_util::time::Instant,
+    /// The TCP socket of the connection, when accepted by
+    /// [`Server::run_on_socket`](super::Server::run_on_socket).
+    pub(crate) socket: Option<russh_util::net::SocketControl>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(ref stats) = self.common.config.stats {
+            for id in self.open_channels.drain() {
+                stats.channel_closed(id)
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -89,9 +121,16 @@ impl From<(ChannelId, ChannelMsg)> for Msg {
 /// the request/response cycle.
 pub struct Handle {
     pub(crate) sender: Sender<Msg>,
+    pub(crate) negotiated: SharedNegotiated,
 }
 
 impl Handle {
+    /// The algorithms negotiated by the latest key exchange, and the
+    /// session id, or `None` before the first key exchange completed.
+    pub fn negotiated(&self) -> Option<Negotiated> {
+        self.negotiated.get()
+    }
+
     /// Send data to the session referenced by this handler.
     pub async fn data(&self, id: ChannelId, data: CryptoVec) -> Result<(), CryptoVec> {
         self.sender
@@ -111,7 +150,13 @@ impl Handle {
         data: CryptoVec,
     ) -> Result<(), CryptoVec> {
         self.sender
-            .send(Msg::Channel(id, ChannelMsg::ExtendedData { ext, data }))
+            .send(Msg::Channel(
+                id,
+                ChannelMsg::ExtendedData {
+                    ext: ext.into(),
+                    data,
+                },
+            ))
             .await
             .map_err(|e| match e.0 {
                 Msg::Channel(_, ChannelMsg::ExtendedData { data, .. }) => data,
@@ -229,6 +274,51 @@ impl Handle {
             .await
     }
 
+    /// Forward the X11 connections accepted on `listener` to the
+    /// client, each through a new X11 channel, after the client
+    /// requested X11 forwarding (see [`Handler::x11_request`]). The
+    /// listener is usually bound to `127.0.0.1:6000 + n`, and the
+    /// programs run for the client get `DISPLAY=localhost:n`, with the
+    /// cookie sent by the client added with `xauth`. This runs until
+    /// the listener or the connection fails.
+    pub async fn serve_x11(&self, listener: tokio::net::TcpListener) -> Result<(), Error> {
+        loop {
+            let (mut socket, addr) = listener.accept().await?;
+            let channel = self
+                .channel_open_x11(addr.ip().to_string(), addr.port().into())
+                .await?;
+            russh_util::runtime::spawn(async move {
+                let mut stream = channel.into_stream();
+                if let Err(e) = tokio::io::copy_bidirectional(&mut socket, &mut stream).await {
+                    debug!("X11 forwarding: {:?}", e);
+                }
+            });
+        }
+    }
+
+    /// Forward the connections accepted on `listener` to the client's
+    /// agent, each through a new agent channel. The listener is usually
+    /// bound to a path given as `SSH_AUTH_SOCK` to the programs run for
+    /// the client, after it requested agent forwarding (see
+    /// [`Handler::agent_request`]). This runs until the listener or the
+    /// connection fails.
+    #[cfg(unix)]
+    pub async fn serve_forwarded_agent(
+        &self,
+        listener: tokio::net::UnixListener,
+    ) -> Result<(), Error> {
+        loop {
+            let (mut socket, _) = listener.accept().await?;
+            let channel = self.channel_open_agent().await?;
+            russh_util::runtime::spawn(async move {
+                let mut stream = channel.into_stream();
+                if let Err(e) = tokio::io::copy_bidirectional(&mut socket, &mut stream).await {
+                    debug!("agent forwarding: {:?}", e);
+                }
+            });
+        }
+    }
+
     /// Request a session channel (the most basic type of
     /// channel). This function returns `Ok(..)` immediately if the
     /// connection is authenticated, but the channel only becomes
@@ -346,7 +436,7 @@ impl Handle {
     async fn wait_channel_confirmation(
         &self,
         mut receiver: UnboundedReceiver<ChannelMsg>,
-        window_size_ref: Arc<Mutex<u32>>,
+        window_size_ref: WindowSizeRef,
     ) -> Result<Channel<Msg>, Error> {
         loop {
             match receiver.recv().await {
@@ -355,7 +445,7 @@ impl Handle {
                     max_packet_size,
                     window_size,
                 }) => {
-                    *window_size_ref.lock().await = window_size;
+                    window_size_ref.update(window_size).await;
 
                     return Ok(Channel {
                         id,
@@ -363,6 +453,7 @@ impl Handle {
                         receiver,
                         max_packet_size,
                         window_size: window_size_ref,
+                        pending: Default::default(),
                     });
                 }
                 Some(ChannelMsg::OpenFailure(reason)) => {
@@ -420,6 +511,102 @@ impl Handle {
 }
 
 impl Session {
+    /// Switch the TCP connection to the interactive mode of
+    /// [`Config::socket_options`], once a terminal is requested.
+    pub(crate) fn set_interactive(&self) {
+        if let Some(ref socket) = self.socket {
+            if let Err(e) = socket.set_interactive(true) {
+                debug!("Could not switch the socket to interactive mode: {:?}", e)
+            }
+        }
+    }
+
+    /// The window limit of `channel` from
+    /// [`Config::channel_buffer_size`], if its reader is behind.
+    pub(crate) fn window_limit(&self, channel: ChannelId) -> Option<u32> {
+        self.channels
+            .get(&channel)?
+            .window_limit(self.common.config.channel_buffer_size, &self.inbound_read)
+    }
+
+    /// Reopen the windows of the channels whose readers caught up.
+    fn reopen_windows(&mut self) -> Result<(), crate::Error> {
+        let max_window_size = self.common.config.max_window_size;
+        let limits: Vec<_> = self
+            .channels
+            .keys()
+            .filter_map(|&id| Some((id, self.window_limit(id)?)))
+            .collect();
+        if let Some(ref mut enc) = self.common.encrypted {
+            for (id, limit) in limits {
+                enc.adjust_window_size(id, &[], max_window_size, Some(limit))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the data, and open the windows, that the rate limits of
+    /// [`DataLimits`](super::DataLimits) held back.
+    fn flush_throttled(&mut self) -> Result<(), crate::Error> {
+        let max_window_size = self.common.config.max_window_size;
+        let ids: Vec<_> = match self.common.encrypted {
+            Some(ref enc) => enc.channels.keys().copied().collect(),
+            None => return Ok(()),
+        };
+        for id in ids {
+            let limit = self.window_limit(id);
+            if let Some(ref mut enc) = self.common.encrypted {
+                enc.flush_pending(id)?;
+                enc.adjust_window_size(id, &[], max_window_size, limit)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The time until the session, or one of its channels, is idle for
+    /// its timeout.
+    fn idle_delay(&mut self) -> Option<std::time::Duration> {
+        let config = &self.common.config;
+        let (last, first_idle) = self.common.encrypted.as_ref().map_or((None, None), |enc| {
+            enc.idleness(config.channel_idle_timeout)
+        });
+        if let Some(last) = last {
+            self.data_at = self.data_at.max(last)
+        }
+        let session = config.idle_timeout.map(|timeout| {
+            timeout.saturating_sub(russh_util::time::Instant::now().duration_since(self.data_at))
+        });
+        session.into_iter().chain(first_idle).min()
+    }
+
+    /// Disconnect the session if it is idle, or else close its idle
+    /// channels.
+    async fn close_idle<H: Handler>(&mut self, handler: &mut H) -> Result<(), H::Error> {
+        if let Some(timeout) = self.common.config.idle_timeout {
+            if russh_util::time::Instant::now().duration_since(self.data_at) >= timeout {
+                debug!("idle timeout");
+                self.common
+                    .disconnect(crate::Disconnect::ByApplication, "Idle timeout", "")?;
+                return Ok(());
+            }
+        }
+        let idle = match (
+            self.common.config.channel_idle_timeout,
+            &self.common.encrypted,
+        ) {
+            (Some(timeout), Some(enc)) => enc.idle_channels(timeout),
+            _ => return Ok(()),
+        };
+        for id in idle {
+            debug!("channel {} idle, closing", id);
+            handler.channel_idle(id, self).await?;
+            if let Some(ref mut enc) = self.common.encrypted {
+                enc.close(id)?;
+            }
+        }
+        Ok(())
+    }
+
     pub(crate) fn is_rekeying(&self) -> bool {
         if let Some(ref enc) = self.common.encrypted {
             enc.rekey.is_some()
@@ -432,12 +619,14 @@ impl Session {
         mut self,
         mut stream: SshRead<R>,
         mut handler: H,
+        connected_at: tokio::time::Instant,
     ) -> Result<(), H::Error>
     where
         H: Handler + Send + 'static,
         R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
         self.flush()?;
+        self.report_bytes_sent();
         map_err!(stream.write_all(&self.common.write_buffer.buffer).await)?;
         self.common.write_buffer.buffer.clear();
 
@@ -456,6 +645,32 @@ impl Session {
             future_or_pending(self.common.config.inactivity_timeout, tokio::time::sleep);
         pin!(inactivity_timer);
 
+        let rekey_timer = tokio::time::sleep(self.common.config.limits.rekey_time_limit);
+        pin!(rekey_timer);
+
+        let kex_timer = future_or_pending(
+            self.common.config.kex_timeout.map(|t| connected_at + t),
+            tokio::time::sleep_until,
+        );
+        pin!(kex_timer);
+
+        let login_timer = future_or_pending(
+            self.common
+                .config
+                .login_grace_time
+                .map(|t| connected_at + t),
+            tokio::time::sleep_until,
+        );
+        pin!(login_timer);
+
+        let throttle_timer = tokio::time::sleep(std::time::Duration::ZERO);
+        pin!(throttle_timer);
+        let mut throttled = false;
+
+        let idle_timer = tokio::time::sleep(std::time::Duration::ZERO);
+        pin!(idle_timer);
+        let mut idle_timeouts = false;
+
         let reading = start_reading(stream_read, buffer, opening_cipher);
         pin!(reading);
         let mut is_reading = None;
@@ -468,7 +683,15 @@ impl Session {
             tokio::select! {
                 r = &mut reading => {
                     let (stream_read, mut buffer, mut opening_cipher) = match r {
-                        Ok((_, stream_read, buffer, opening_cipher)) => (stream_read, buffer, opening_cipher),
+                        Ok((n, stream_read, buffer, opening_cipher)) => {
+                            self.transferred += n as u64;
+                            if let Some(ref stats) = self.common.config.stats {
+                                if n > 0 {
+                                    stats.packet_received(n)
+                                }
+                            }
+                            (stream_read, buffer, opening_cipher)
+                        }
                         Err(e) => return Err(e.into())
                     };
                     if buffer.buffer.len() < 5 {
@@ -492,16 +715,27 @@ impl Session {
                         &buffer.buffer[5..]
                     };
                     if !buf.is_empty() {
+                        observe(
+                            &self.common.config.packet_observer,
+                            Direction::Inbound,
+                            buffer.seqn.0.wrapping_sub(1),
+                            buf,
+                        );
                         #[allow(clippy::indexing_slicing)] // length checked
                         if buf[0] == crate::msg::DISCONNECT {
                             debug!("break");
+                            #[allow(clippy::indexing_slicing)] // length checked
+                            match RemoteDisconnectInfo::parse(&buf[1..]) {
+                                Ok(info) => handler.disconnected(&info, &mut self).await?,
+                                Err(e) => debug!("invalid disconnect message: {:?}", e),
+                            }
                             is_reading = Some((stream_read, buffer, opening_cipher));
                             break;
                         } else {
                             self.common.received_data = true;
                             std::mem::swap(&mut opening_cipher, &mut self.common.cipher.remote_to_local);
                             // TODO it'd be cleaner to just pass cipher to reply()
-                            match reply(&mut self, &mut handler, &mut buffer.seqn, buf).await {
+                            match reply(&mut self, &mut handler, &mut buffer.seqn, buf).instrument(logging::packet_span(buf)).await {
                                 Ok(_) => {},
                                 Err(e) => return Err(e),
                             }
@@ -511,7 +745,7 @@ impl Session {
                     reading.set(start_reading(stream_read, buffer, opening_cipher));
                 }
                 () = &mut keepalive_timer => {
-                    if self.common.config.keepalive_max != 0 && self.common.alive_timeouts > self.common.config.keepalive_max {
+                    if self.common.config.keepalive_max != 0 && self.common.alive_timeouts >= self.common.config.keepalive_max {
                         debug!("Timeout, client not responding to keepalives");
                         return Err(crate::Error::KeepaliveTimeout.into());
                     }
@@ -523,13 +757,41 @@ impl Session {
                     debug!("timeout");
                     return Err(crate::Error::InactivityTimeout.into());
                 }
+                () = &mut kex_timer, if self.common.encrypted.is_none() => {
+                    debug!("first key exchange timed out");
+                    return Err(crate::Error::KexTimeout.into());
+                }
+                () = &mut login_timer, if !self.is_authenticated() => {
+                    debug!("login grace time exceeded");
+                    return Err(crate::Error::LoginTimeout.into());
+                }
+                () = &mut rekey_timer => {
+                    let wait = self.time_to_rekey();
+                    let next = if wait.is_zero() {
+                        debug!("rekey time limit reached");
+                        self.request_rekey()?;
+                        self.common.config.limits.rekey_time_limit
+                    } else {
+                        wait
+                    };
+                    rekey_timer.as_mut().reset(tokio::time::Instant::now() + next);
+                }
+                () = self.inbound_read.notified(), if !self.is_rekeying() => {
+                    self.reopen_windows()?;
+                }
+                () = &mut throttle_timer, if throttled && !self.is_rekeying() => {
+                    self.flush_throttled()?;
+                }
+                () = &mut idle_timer, if idle_timeouts && !self.is_rekeying() => {
+                    self.close_idle(&mut handler).await?;
+                }
                 msg = self.receiver.recv(), if !self.is_rekeying() => {
                     match msg {
                         Some(Msg::Channel(id, ChannelMsg::Data { data })) => {
                             self.data(id, data)?;
                         }
                         Some(Msg::Channel(id, ChannelMsg::ExtendedData { ext, data })) => {
-                            self.extended_data(id, ext, data)?;
+                            self.extended_data(id, ext.into(), data)?;
                         }
                         Some(Msg::Channel(id, ChannelMsg::Eof)) => {
                             self.eof(id)?;
@@ -599,13 +861,51 @@ impl Session {
                     }
                 }
             }
+            if let Some(max_bytes) = self.common.config.data_limits.max_bytes {
+                if self.transferred > max_bytes {
+                    debug!("data limit exceeded");
+                    self.common.disconnect(
+                        crate::Disconnect::ByApplication,
+                        "Data limit exceeded",
+                        "",
+                    )?;
+                }
+            }
             self.flush()?;
+            self.report_bytes_sent();
+            self.transferred += self.common.write_buffer.buffer.len() as u64;
             map_err!(
                 stream_write
                     .write_all(&self.common.write_buffer.buffer)
                     .await
             )?;
             self.common.write_buffer.buffer.clear();
+            if let Some(ref enc) = self.common.encrypted {
+                enc.update_queued(&self.channels);
+            }
+            throttled = match self
+                .common
+                .encrypted
+                .as_ref()
+                .and_then(|enc| enc.throttle_delay())
+            {
+                Some(delay) => {
+                    throttle_timer
+                        .as_mut()
+                        .reset(tokio::time::Instant::now() + delay);
+                    true
+                }
+                None => false,
+            };
+            idle_timeouts = match self.idle_delay() {
+                Some(delay) => {
+                    idle_timer
+                        .as_mut()
+                        .reset(tokio::time::Instant::now() + delay);
+                    true
+                }
+                None => false,
+            };
 
             if self.common.received_data {
                 // Reset the number of failed keepalive attempts. We don't
@@ -649,6 +949,55 @@ impl Session {
         Ok(())
     }
 
+    fn report_bytes_sent(&self) {
+        if let Some(ref stats) = self.common.config.stats {
+            let len = self.common.write_buffer.buffer.len();
+            if len > 0 {
+                stats.bytes_sent(len)
+            }
+        }
+    }
+
+    pub(crate) fn report_channel_opened(&mut self, id: ChannelId) {
+        if let Some(ref stats) = self.common.config.stats {
+            if self.open_channels.insert(id) {
+                stats.channel_opened(id)
+            }
+        }
+    }
+
+    pub(crate) fn report_channel_closed(&mut self, id: ChannelId) {
+        if let Some(ref stats) = self.common.config.stats {
+            if self.open_channels.remove(&id) {
+                stats.channel_closed(id)
+            }
+        }
+    }
+
+    /// Share the algorithms of a completed key exchange with the
+    /// handles of this session, and tell the handler.
+    pub(crate) async fn report_negotiated<H: Handler + Send>(
+        &mut self,
+        handler: &mut H,
+    ) -> Result<(), H::Error> {
+        let Some(negotiated) = self
+            .common
+            .encrypted
+            .as_ref()
+            .map(|enc| enc.negotiated.clone())
+        else {
+            return Ok(());
+        };
+        self.sender.negotiated.set(negotiated.clone());
+        handler.negotiated(&negotiated, self).await
+    }
+
+    /// The algorithms negotiated by the latest key exchange, and the
+    /// session id, or `None` before the first key exchange completed.
+    pub fn negotiated(&self) -> Option<&Negotiated> {
+        self.common.encrypted.as_ref().map(|enc| &enc.negotiated)
+    }
+
     /// Get a handle to this session.
     pub fn handle(&self) -> Handle {
         self.sender.clone()
@@ -685,15 +1034,35 @@ impl Session {
 
     /// Flush the session, i.e. encrypt the pending buffer.
     pub fn flush(&mut self) -> Result<(), Error> {
-        if let Some(ref mut enc) = self.common.encrypted {
-            if enc.flush(
+        let needs_rekey = if let Some(ref mut enc) = self.common.encrypted {
+            enc.flush(
                 &self.common.config.as_ref().limits,
                 &mut *self.common.cipher.local_to_remote,
                 &mut self.common.write_buffer,
-            )? && enc.rekey.is_none()
-            {
-                debug!("starting rekeying");
+            )?
+        } else {
+            false
+        };
+        if needs_rekey {
+            self.initiate_rekey()?;
+        }
+        Ok(())
+    }
+
+    /// Start a key re-exchange now, unless one is already running.
+    /// Keys are also re-exchanged automatically according to
+    /// [`Config::limits`](super::Config::limits).
+    pub fn request_rekey(&mut self) -> Result<(), Error> {
+        // Packets queued before the KEXINIT must be sent with the old keys.
+        self.flush()?;
+        self.initiate_rekey()
+    }
+
+    fn initiate_rekey(&mut self) -> Result<(), Error> {
+        if let Some(ref mut enc) = self.common.encrypted {
+            if enc.rekey.is_none() {
                 if let Some(exchange) = enc.exchange.take() {
+                    debug!("starting rekeying");
                     let mut kexinit = KexInit::initiate_rekey(exchange, &enc.session_id);
                     kexinit.server_write(
                         self.common.config.as_ref(),
@@ -707,6 +1076,16 @@ impl Session {
         Ok(())
     }
 
+    /// Time until the keys have to be re-exchanged because of
+    /// [`Limits::rekey_time_limit`](crate::Limits::rekey_time_limit).
+    fn time_to_rekey(&self) -> std::time::Duration {
+        let limit = self.common.config.limits.rekey_time_limit;
+        match self.common.encrypted {
+            Some(ref enc) => limit.saturating_sub(enc.last_rekey.elapsed()),
+            None => limit,
+        }
+    }
+
     pub fn flush_pending(&mut self, channel: ChannelId) -> Result<usize, Error> {
         if let Some(ref mut enc) = self.common.encrypted {
             enc.flush_pending(channel)
@@ -841,6 +1220,9 @@ impl Session {
     /// The number of bytes added to the "sending pipeline" (to be
     /// processed by the event loop) is returned.
     pub fn data(&mut self, channel: ChannelId, data: CryptoVec) -> Result<(), Error> {
+        if let Some(ref stats) = self.common.config.stats {
+            stats.channel_data_sent(channel, data.len())
+        }
         if let Some(ref mut enc) = self.common.encrypted {
             enc.data(channel, data)
         } else {
@@ -860,6 +1242,9 @@ impl Session {
         extended: u32,
         data: CryptoVec,
     ) -> Result<(), Error> {
+        if let Some(ref stats) = self.common.config.stats {
+            stats.channel_data_sent(channel, data.len())
+        }
         if let Some(ref mut enc) = self.common.encrypted {
             enc.extended_data(channel, extended, data)
         } else {
@@ -906,6 +1291,27 @@ impl Session {
         Ok(())
     }
 
+    /// Announce the server's host keys to the client
+    /// (`hostkeys-00@openssh.com`). This is done automatically after
+    /// authentication if [`Config::announce_host_keys`] is set.
+    pub fn announce_host_keys(&mut self) -> Result<(), Error> {
+        if let Some(ref mut enc) = self.common.encrypted {
+            let mut blobs = Vec::new();
+            for key in self.common.config.host_keys() {
+                blobs.push(key.public_key().to_bytes()?)
+            }
+            push_packet!(enc.write, {
+                msg::GLOBAL_REQUEST.encode(&mut enc.write)?;
+                "hostkeys-00@openssh.com".encode(&mut enc.write)?;
+                0u8.encode(&mut enc.write)?;
+                for blob in &blobs {
+                    blob.encode(&mut enc.write)?;
+                }
+            })
+        }
+        Ok(())
+    }
+
     /// Send the exit status of a program.
     pub fn exit_status_request(
         &mut self,
@@ -1030,6 +1436,16 @@ impl Session {
         self.channel_open_generic(b"auth-agent@openssh.com", |_| Ok(()))
     }
 
+    fn is_authenticated(&self) -> bool {
+        matches!(
+            self.common.encrypted,
+            Some(Encrypted {
+                state: EncryptedState::Authenticated | EncryptedState::InitCompression,
+                ..
+            })
+        )
+    }
+
     fn channel_open_generic<F>(&mut self, kind: &[u8], write_suffix: F) -> Result<ChannelId, Error>
     where
         F: FnOnce(&mut CryptoVec) -> Result<(), Error>,
@@ -1046,6 +1462,9 @@ impl Session {
                 self.common.config.window_size,
                 self.common.config.maximum_packet_size,
             );
+            if let Some(channel) = enc.channels.get_mut(&sender_channel) {
+                self.common.config.data_limits.apply(channel);
+            }
             push_packet!(enc.write, {
                 enc.write.push(msg::CHANNEL_OPEN);
                 kind.encode(&mut enc.write)?;
@@ -1144,44 +1563,40 @@ impl Session {
     pub(crate) fn maybe_send_ext_info(&mut self) -> Result<(), Error> {
         if let Some(ref mut enc) = self.common.encrypted {
             // If client sent a ext-info-c message in the kex list, it supports RFC 8308 extension negotiation.
-            let mut key_extension_client = false;
-            if let Some(e) = &enc.exchange {
-                let Some(mut r) = &e.client_kex_init.as_ref().get(17..) else {
-                    return Ok(());
-                };
-                if let Ok(kex_string) = String::decode(&mut r) {
-                    use super::negotiation::Select;
-                    key_extension_client = super::negotiation::Server::select(
-                        &[EXTENSION_SUPPORT_AS_CLIENT],
-                        &parse_kex_algo_list(&kex_string),
-                        AlgorithmKind::Kex,
-                    )
-                    .is_ok();
-                }
-            }
-
+            let key_extension_client = enc.exchange.as_ref().is_some_and(|e| {
+                extensions::peer_supports(&e.client_kex_init, &EXTENSION_SUPPORT_AS_CLIENT)
+            });
             if !key_extension_client {
                 debug!("RFC 8308 Extension Negotiation not supported by client");
                 return Ok(());
             }
 
-            push_packet!(enc.write, {
-                msg::EXT_INFO.encode(&mut enc.write)?;
-                1u32.encode(&mut enc.write)?;
-                "server-sig-algs".encode(&mut enc.write)?;
-
-                NameList(
-                    self.common
-                        .config
-                        .preferred
-                        .key
-                        .iter()
-                        .map(|x| x.to_string())
-                        .collect(),
-                )
-                .encode(&mut enc.write)?;
-            });
+            let mut ext = Extensions::new();
+            ext.insert(
+                extensions::SERVER_SIG_ALGS,
+                self.common
+                    .config
+                    .key_algorithms()
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            );
+            ext.extend(&self.common.config.extensions);
+            ext.write_packet(&mut enc.write)?;
         }
         Ok(())
     }
+
+    /// The extensions the client sent in its `SSH_MSG_EXT_INFO`
+    /// message (RFC 8308), if any.
+    pub fn client_extensions(&self) -> &Extensions {
+        &self.client_extensions
+    }
+
+    /// Number of failed authentication attempts on this connection,
+    /// not counting the client's initial `none` request.
+    pub fn auth_failures(&self) -> usize {
+        self.auth_failures
+    }
 }
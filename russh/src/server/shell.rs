@@ -0,0 +1,441 @@
+//! Shells and commands on pseudo-terminals, for the sessions of a
+//! shell server (`portable-pty` feature). Terminals are opened with
+//! `openpty` on Unix, and ConPTY on Windows.
+//!
+//! Accept the `pty-req`, `shell` and `exec` requests in the
+//! [`Handler`](super::Handler), and pass the session channels to
+//! [`serve`], which runs the requested shell or command:
+//!
+//! ```ignore
+//! async fn channel_open_session(
+//!     &mut self,
+//!     channel: Channel<Msg>,
+//!     session: &mut Session,
+//! ) -> Result<bool, Self::Error> {
+//!     tokio::spawn(shell::serve(channel, session.handle(), ShellConfig::default()));
+//!     Ok(true)
+//! }
+//!
+//! async fn shell_request(
+//!     &mut self,
+//!     channel: ChannelId,
+//!     session: &mut Session,
+//! ) -> Result<(), Self::Error> {
+//!     session.channel_success(channel)?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use portable_pty::{
+    native_pty_system, ChildKiller, CommandBuilder, ExitStatus, MasterPty, PtySize,
+};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, watch};
+
+use super::{Handle, Msg};
+use crate::logging::debug;
+use crate::{Channel, ChannelMsg, Sig, TerminalSize};
+
+/// The size of the terminal of a session without `pty-req`.
+const DEFAULT_SIZE: TerminalSize = TerminalSize {
+    col_width: 80,
+    row_height: 24,
+    pix_width: 0,
+    pix_height: 0,
+};
+
+fn pty_error<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+fn pty_size(size: TerminalSize) -> PtySize {
+    let clamp = |x: u32| u16::try_from(x).unwrap_or(u16::MAX);
+    PtySize {
+        rows: clamp(size.row_height),
+        cols: clamp(size.col_width),
+        pixel_width: clamp(size.pix_width),
+        pixel_height: clamp(size.pix_height),
+    }
+}
+
+/// How [`serve`] runs the requests of a session.
+#[derive(Debug, Clone, Default)]
+pub struct ShellConfig {
+    /// The shell run by `shell` requests, and which runs the commands
+    /// of `exec` requests with `-c` (`/C` on Windows). Defaults to
+    /// `$SHELL`, or `/bin/sh` (`cmd.exe` on Windows).
+    pub shell: Option<String>,
+    /// The working directory, instead of the server's.
+    pub cwd: Option<PathBuf>,
+    /// Variables added to the environment of the server, besides `TERM`.
+    pub env: Vec<(String, String)>,
+    /// The names of the variables clients may set with `env` requests,
+    /// like OpenSSH's `AcceptEnv` (without patterns). Others are
+    /// ignored.
+    pub accept_env: Vec<String>,
+}
+
+impl ShellConfig {
+    fn shell(&self) -> String {
+        if let Some(ref shell) = self.shell {
+            return shell.clone();
+        }
+        #[cfg(windows)]
+        {
+            "cmd.exe".to_string()
+        }
+        #[cfg(not(windows))]
+        {
+            std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string())
+        }
+    }
+
+    /// The command running `command` in the shell, or the shell itself.
+    pub fn command(&self, command: Option<&str>) -> CommandBuilder {
+        let mut builder = CommandBuilder::new(self.shell());
+        if let Some(command) = command {
+            builder.arg(if cfg!(windows) { "/C" } else { "-c" });
+            builder.arg(command);
+        }
+        if let Some(ref cwd) = self.cwd {
+            builder.cwd(cwd);
+        }
+        for (name, value) in &self.env {
+            builder.env(name, value);
+        }
+        builder
+    }
+}
+
+/// A process running on a pseudo-terminal.
+pub struct PtyProcess {
+    master: Box<dyn MasterPty + Send>,
+    killer: Mutex<Box<dyn ChildKiller + Send + Sync>>,
+    process_id: Option<u32>,
+    exit: watch::Receiver<Option<Result<ExitStatus, String>>>,
+}
+
+impl std::fmt::Debug for PtyProcess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PtyProcess")
+            .field("process_id", &self.process_id)
+            .finish()
+    }
+}
+
+impl PtyProcess {
+    /// Spawn `command` on a new pseudo-terminal of `size`, with `TERM`
+    /// set to `term`.
+    pub fn spawn(mut command: CommandBuilder, term: &str, size: TerminalSize) -> io::Result<Self> {
+        let pair = native_pty_system()
+            .openpty(pty_size(size))
+            .map_err(pty_error)?;
+        command.env("TERM", term);
+        let mut child = pair.slave.spawn_command(command).map_err(pty_error)?;
+        // Reads from the master end once the process and its children
+        // are done with the terminal, which requires closing our side.
+        drop(pair.slave);
+        let killer = child.clone_killer();
+        let process_id = child.process_id();
+        let (exited, exit) = watch::channel(None);
+        std::thread::spawn(move || {
+            let status = child.wait().map_err(|e| e.to_string());
+            let _ = exited.send(Some(status));
+        });
+        Ok(PtyProcess {
+            master: pair.master,
+            killer: Mutex::new(killer),
+            process_id,
+            exit,
+        })
+    }
+
+    pub fn process_id(&self) -> Option<u32> {
+        self.process_id
+    }
+
+    /// A blocking reader of the output of the terminal.
+    pub fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        self.master.try_clone_reader().map_err(pty_error)
+    }
+
+    /// A blocking writer to the input of the terminal. This can only be
+    /// taken once.
+    pub fn writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        self.master.take_writer().map_err(pty_error)
+    }
+
+    pub fn resize(&self, size: TerminalSize) -> io::Result<()> {
+        self.master.resize(pty_size(size)).map_err(pty_error)
+    }
+
+    pub fn kill(&self) -> io::Result<()> {
+        self.killer.lock().map_err(pty_error)?.kill()
+    }
+
+    /// Send `signal` to the process. On Windows, `KILL`, `TERM` and
+    /// `INT` kill the process, and other signals are not supported.
+    pub fn signal(&self, signal: &Sig) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            let (Some(number), Some(pid)) = (signal_number(signal), self.process_id) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("cannot send {:?}", signal),
+                ));
+            };
+            let pid = libc::pid_t::try_from(pid).map_err(pty_error)?;
+            // SAFETY: kill has no memory safety requirements.
+            if unsafe { libc::kill(pid, number) } == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            match signal {
+                Sig::KILL | Sig::TERM | Sig::INT => self.kill(),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("cannot send {:?}", signal),
+                )),
+            }
+        }
+    }
+
+    /// Wait for the process to exit.
+    pub async fn wait(&self) -> io::Result<ExitStatus> {
+        let mut exit = self.exit.clone();
+        loop {
+            if let Some(ref status) = *exit.borrow() {
+                return status.clone().map_err(pty_error);
+            }
+            exit.changed().await.map_err(pty_error)?;
+        }
+    }
+}
+
+#[cfg(unix)]
+const SIGNALS: [Sig; 13] = [
+    Sig::ABRT,
+    Sig::ALRM,
+    Sig::FPE,
+    Sig::HUP,
+    Sig::ILL,
+    Sig::INT,
+    Sig::KILL,
+    Sig::PIPE,
+    Sig::QUIT,
+    Sig::SEGV,
+    Sig::TERM,
+    Sig::USR1,
+    Sig::USR2,
+];
+
+#[cfg(unix)]
+fn signal_number(signal: &Sig) -> Option<libc::c_int> {
+    Some(match signal {
+        Sig::ABRT => libc::SIGABRT,
+        Sig::ALRM => libc::SIGALRM,
+        Sig::FPE => libc::SIGFPE,
+        Sig::HUP => libc::SIGHUP,
+        Sig::ILL => libc::SIGILL,
+        Sig::INT => libc::SIGINT,
+        Sig::KILL => libc::SIGKILL,
+        Sig::PIPE => libc::SIGPIPE,
+        Sig::QUIT => libc::SIGQUIT,
+        Sig::SEGV => libc::SIGSEGV,
+        Sig::TERM => libc::SIGTERM,
+        Sig::USR1 => libc::SIGUSR1,
+        Sig::USR2 => libc::SIGUSR2,
+        Sig::Custom(_) => return None,
+    })
+}
+
+/// The signal that killed a process, from the description of
+/// `strsignal` that portable-pty reports.
+fn exit_signal(description: &str) -> Sig {
+    #[cfg(unix)]
+    for signal in SIGNALS.iter() {
+        let Some(number) = signal_number(signal) else {
+            continue;
+        };
+        // SAFETY: strsignal returns a null or nul-terminated string,
+        // copied before another call.
+        let name = unsafe {
+            let name = libc::strsignal(number);
+            if name.is_null() {
+                continue;
+            }
+            std::ffi::CStr::from_ptr(name)
+                .to_string_lossy()
+                .into_owned()
+        };
+        if name == description {
+            return signal.clone();
+        }
+    }
+    Sig::Custom(description.to_string())
+}
+
+/// Serve a session channel: wait for its `shell` or `exec` request,
+/// run the shell or command on a pseudo-terminal of the size from the
+/// `pty-req` if any, and forward data, window changes and signals
+/// until the process exits. Its exit status or signal is then sent,
+/// and the channel closed. If the client closes the channel first, the
+/// process is killed.
+///
+/// Replying to the requests is left to the handler.
+pub async fn serve(
+    mut channel: Channel<Msg>,
+    handle: Handle,
+    config: ShellConfig,
+) -> io::Result<()> {
+    let id = channel.id();
+    let mut term = None;
+    let mut size = DEFAULT_SIZE;
+    let mut env = Vec::new();
+    let command = loop {
+        match channel.wait().await {
+            Some(ChannelMsg::RequestPty {
+                term: t,
+                col_width,
+                row_height,
+                pix_width,
+                pix_height,
+                ..
+            }) => {
+                term = Some(t);
+                size = TerminalSize {
+                    col_width,
+                    row_height,
+                    pix_width,
+                    pix_height,
+                };
+            }
+            Some(ChannelMsg::SetEnv {
+                variable_name,
+                variable_value,
+                ..
+            }) => {
+                if config.accept_env.contains(&variable_name) {
+                    env.push((variable_name, variable_value))
+                } else {
+                    debug!("Ignoring environment variable {:?}", variable_name)
+                }
+            }
+            Some(ChannelMsg::RequestShell { .. }) => break None,
+            Some(ChannelMsg::Exec { command, .. }) => {
+                break Some(String::from_utf8_lossy(&command).into_owned())
+            }
+            Some(ChannelMsg::Close) | None => return Ok(()),
+            Some(_) => {}
+        }
+    };
+
+    let mut builder = config.command(command.as_deref());
+    for (name, value) in env {
+        builder.env(name, value);
+    }
+    let process = PtyProcess::spawn(builder, term.as_deref().unwrap_or("dumb"), size)?;
+
+    let mut reader = process.reader()?;
+    let (output_sender, mut output) = mpsc::channel::<Vec<u8>>(16);
+    std::thread::spawn(move || loop {
+        let mut buf = vec![0; 32768];
+        match reader.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                buf.truncate(n);
+                if output_sender.blocking_send(buf).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut writer = process.writer()?;
+    let (input_sender, mut input) = mpsc::channel::<Vec<u8>>(16);
+    std::thread::spawn(move || {
+        while let Some(data) = input.blocking_recv() {
+            if writer
+                .write_all(&data)
+                .and_then(|_| writer.flush())
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    let mut input_sender = Some(input_sender);
+
+    let mut stdout = channel.make_writer();
+    let status = loop {
+        tokio::select! {
+            status = process.wait() => break status?,
+            Some(data) = output.recv() => stdout.write_all(&data).await?,
+            msg = channel.wait() => match msg {
+                Some(ChannelMsg::Data { data }) => {
+                    if let Some(ref input) = input_sender {
+                        let _ = input.send(data.to_vec()).await;
+                    }
+                }
+                Some(ChannelMsg::Eof) => input_sender = None,
+                Some(ChannelMsg::WindowChange {
+                    col_width,
+                    row_height,
+                    pix_width,
+                    pix_height,
+                }) => {
+                    let size = TerminalSize {
+                        col_width,
+                        row_height,
+                        pix_width,
+                        pix_height,
+                    };
+                    if let Err(e) = process.resize(size) {
+                        debug!("Could not resize the terminal: {:?}", e)
+                    }
+                }
+                Some(ChannelMsg::Signal { signal }) => {
+                    if let Err(e) = process.signal(&signal) {
+                        debug!("Could not send {:?}: {:?}", signal, e)
+                    }
+                }
+                Some(ChannelMsg::Close) | None => return process.kill(),
+                Some(_) => {}
+            },
+        }
+    };
+
+    // The rest of the output, until the terminal is closed.
+    while let Some(data) = output.recv().await {
+        stdout.write_all(&data).await?;
+    }
+    stdout.flush().await?;
+    match status.signal() {
+        Some(description) => {
+            let _ = handle
+                .exit_signal_request(
+                    id,
+                    exit_signal(description),
+                    false,
+                    description.to_string(),
+                    String::new(),
+                )
+                .await;
+        }
+        None => {
+            let _ = handle.exit_status_request(id, status.exit_code()).await;
+        }
+    }
+    let _ = handle.eof(id).await;
+    let _ = handle.close(id).await;
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Graceful shutdown of [`Server::run_on_socket_with_shutdown`](super::Server::run_on_socket_with_shutdown).
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::Disconnect;
+
+/// Stops a server started with
+/// [`Server::run_on_socket_with_shutdown`](super::Server::run_on_socket_with_shutdown).
+/// Clones of a handle stop the same server.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    sender: Arc<watch::Sender<Option<ShutdownRequest>>>,
+    receiver: watch::Receiver<Option<ShutdownRequest>>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ShutdownRequest {
+    /// Sent to active sessions, if any.
+    pub(crate) disconnect: Option<(Disconnect, String)>,
+    /// Time given to sessions to end before they are aborted.
+    pub(crate) timeout: Duration,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(None);
+        ShutdownHandle {
+            sender: Arc::new(sender),
+            receiver,
+        }
+    }
+
+    /// Stop accepting connections, and give active sessions `timeout`
+    /// to be closed by their clients, after which they are aborted.
+    pub fn shutdown(&self, timeout: Duration) {
+        self.request(ShutdownRequest {
+            disconnect: None,
+            timeout,
+        })
+    }
+
+    /// Stop accepting connections, and send a disconnect message to
+    /// all active sessions. Their pending data is flushed, and
+    /// sessions still running after `timeout` are aborted.
+    pub fn shutdown_with_disconnect<D: Into<String>>(
+        &self,
+        reason: Disconnect,
+        description: D,
+        timeout: Duration,
+    ) {
+        self.request(ShutdownRequest {
+            disconnect: Some((reason, description.into())),
+            timeout,
+        })
+    }
+
+    fn request(&self, request: ShutdownRequest) {
+        // Only the first request counts.
+        if self.receiver.borrow().is_none() {
+            let _ = self.sender.send(Some(request));
+        }
+    }
+
+    /// Wait until a shutdown is requested.
+    pub(crate) async fn requested(&self) -> ShutdownRequest {
+        let mut receiver = self.receiver.clone();
+        loop {
+            if let Some(ref request) = *receiver.borrow() {
+                return request.clone();
+            }
+            if receiver.changed().await.is_err() {
+                // Can't happen, since we hold a sender.
+                futures::future::pending::<()>().await
+            }
+        }
+    }
+}
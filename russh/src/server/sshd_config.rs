@@ -0,0 +1,200 @@
+//! Servers configured with OpenSSH's `sshd_config`, parsed by the
+//! `russh-config` crate.
+//!
+//! [`Config::from_sshd_config`] takes the options of the whole server,
+//! and [`SshdPolicy`] enforces the ones that depend on the user, and on
+//! the `Match` blocks that apply to the connection.
+//!
+//! ```ignore
+//! let sshd_config = Arc::new(russh_config::server::parse_default()?);
+//! let config = Arc::new(Config::from_sshd_config(&sshd_config)?);
+//!
+//! // In `Server::new_client`:
+//! MyHandler::new().with(SshdPolicy::new(sshd_config.clone(), peer_addr))
+//! ```
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use russh_config::server::{Connection, ServerConfig, Settings};
+
+use super::middleware::Middleware;
+use super::{Auth, Config, Session};
+use crate::logging::debug;
+use crate::{MethodSet, Preferred};
+
+impl Config {
+    /// A configuration with the `HostKey`s of `sshd_config`, its
+    /// authentication methods, `MaxAuthTries`, `LoginGraceTime`,
+    /// `ClientAlive*` options and algorithm lists, and the defaults of
+    /// russh for the rest.
+    ///
+    /// The options of `Match` blocks, `AllowUsers` and the like are
+    /// enforced by [`SshdPolicy`]. `Subsystem`s are left to the
+    /// application, see [`super::subsystem`].
+    pub fn from_sshd_config(sshd_config: &ServerConfig) -> Result<Config, crate::Error> {
+        let settings = &sshd_config.settings;
+        let mut keys = Vec::new();
+        for path in &sshd_config.host_keys {
+            keys.push(russh_keys::load_secret_key(path, None)?);
+        }
+        let mut methods = MethodSet::empty();
+        if settings.pubkey_authentication {
+            methods |= MethodSet::PUBLICKEY
+        }
+        if settings.password_authentication {
+            methods |= MethodSet::PASSWORD
+        }
+        if settings.kbd_interactive_authentication {
+            methods |= MethodSet::KEYBOARD_INTERACTIVE
+        }
+        let default = Config::default();
+        Ok(Config {
+            keys,
+            methods,
+            max_auth_attempts: settings.max_auth_tries,
+            login_grace_time: match sshd_config.login_grace_time {
+                0 => None,
+                t => Some(Duration::from_secs(t)),
+            },
+            keepalive_interval: match settings.client_alive_interval {
+                0 => None,
+                t => Some(Duration::from_secs(t)),
+            },
+            keepalive_max: settings.client_alive_count_max,
+            preferred: Preferred::default().with_algorithm_lists(
+                sshd_config.kex_algorithms.as_ref(),
+                None,
+                sshd_config.ciphers.as_ref(),
+                sshd_config.macs.as_ref(),
+            ),
+            ..default
+        })
+    }
+}
+
+/// A middleware refusing what `sshd_config` doesn't allow:
+///
+/// - users denied by `AllowUsers`, `DenyUsers`, `AllowGroups` and
+///   `DenyGroups`,
+/// - authentication methods turned off, and root logins refused by
+///   `PermitRootLogin`. Keys with forced commands aren't known here, so
+///   `forced-commands-only` refuses all root logins.
+/// - port forwarding channels, if `AllowTcpForwarding` is `no`.
+///
+/// The options of the `Match` blocks applying to the connection and to
+/// the user are used, once the user is known. The `Banner` is sent as
+/// the authentication banner.
+pub struct SshdPolicy {
+    sshd_config: Arc<ServerConfig>,
+    connection: Connection,
+    groups: Option<Arc<dyn Fn(&str) -> Vec<String> + Send + Sync>>,
+    settings: Option<Settings>,
+}
+
+impl fmt::Debug for SshdPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SshdPolicy")
+            .field("connection", &self.connection)
+            .field("settings", &self.settings)
+            .finish()
+    }
+}
+
+impl SshdPolicy {
+    /// The policy of `sshd_config` for a connection from `peer_addr`.
+    pub fn new(sshd_config: Arc<ServerConfig>, peer_addr: Option<SocketAddr>) -> Self {
+        SshdPolicy {
+            sshd_config,
+            connection: Connection {
+                address: peer_addr.map(|a| a.ip()),
+                ..Default::default()
+            },
+            groups: None,
+            settings: None,
+        }
+    }
+
+    /// The local address the client connected to, for the `Match
+    /// LocalAddress` and `Match LocalPort` criteria.
+    pub fn local_addr(mut self, local_addr: SocketAddr) -> Self {
+        self.connection.local_address = Some(local_addr.ip());
+        self.connection.local_port = Some(local_addr.port());
+        self
+    }
+
+    /// Look up the groups of users with `groups`, for `AllowGroups`,
+    /// `DenyGroups` and `Match Group`. Without it, users are in no
+    /// group.
+    pub fn groups<F: Fn(&str) -> Vec<String> + Send + Sync + 'static>(mut self, groups: F) -> Self {
+        self.groups = Some(Arc::new(groups));
+        self
+    }
+
+    /// The options for the last user who tried to authenticate, such
+    /// as their `ForceCommand`.
+    pub fn settings(&self) -> Option<&Settings> {
+        self.settings.as_ref()
+    }
+
+    /// Evaluate the `Match` blocks for `user`, unless already done.
+    fn update(&mut self, user: &str) {
+        if self.settings.is_some() && self.connection.user == user {
+            return;
+        }
+        self.connection.user = user.to_string();
+        self.connection.groups = self.groups.as_ref().map_or(Vec::new(), |g| g(user));
+        self.settings = Some(self.sshd_config.settings(&self.connection));
+    }
+}
+
+impl Middleware for SshdPolicy {
+    async fn auth_banner(&mut self, banner: Option<String>) -> Option<String> {
+        match self.sshd_config.settings.banner {
+            Some(ref path) => match tokio::fs::read_to_string(path).await {
+                Ok(banner) => Some(banner),
+                Err(e) => {
+                    debug!("Could not read banner {:?}: {:?}", path, e);
+                    banner
+                }
+            },
+            None => banner,
+        }
+    }
+
+    async fn before_auth(&mut self, user: &str, method: &str) -> Option<Auth> {
+        self.update(user);
+        let host = match (&self.connection.host, self.connection.address) {
+            (Some(host), _) => host.clone(),
+            (None, Some(address)) => address.to_string(),
+            (None, None) => String::new(),
+        };
+        let settings = self.settings.as_ref()?;
+        if !settings.allows_user(user, &self.connection.groups, &host)
+            || (method != "none" && !settings.allows_method(user, method, false))
+        {
+            debug!(
+                "{} authentication of {:?} refused by sshd_config",
+                method, user
+            );
+            return Some(Auth::Reject {
+                proceed_with_methods: None,
+            });
+        }
+        None
+    }
+
+    async fn channel_open(&mut self, channel_type: &str, _: &mut Session) -> bool {
+        match channel_type {
+            "direct-tcpip" | "direct-streamlocal@openssh.com" => {
+                self.settings
+                    .as_ref()
+                    .unwrap_or(&self.sshd_config.settings)
+                    .allow_tcp_forwarding
+            }
+            _ => true,
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Counters reported by the server sessions, see [`Config::stats`](super::Config::stats).
+
+use crate::ChannelId;
+
+/// Receives the activity of all the sessions of a server, for instance
+/// to export it as metrics. All methods do nothing by default.
+///
+/// Channel ids are only unique within a connection, and calls come
+/// from the tasks running the sessions, so implementations should be
+/// cheap, such as incrementing atomic counters.
+pub trait Stats: Send + Sync {
+    /// A packet of `len` bytes was received, including its length,
+    /// padding and MAC.
+    #[allow(unused_variables)]
+    fn packet_received(&self, len: usize) {}
+
+    /// `len` bytes of packets were written to the connection.
+    #[allow(unused_variables)]
+    fn bytes_sent(&self, len: usize) {}
+
+    /// `len` bytes of data or extended data were received on `channel`.
+    #[allow(unused_variables)]
+    fn channel_data_received(&self, channel: ChannelId, len: usize) {}
+
+    /// `len` bytes of data or extended data were queued for sending on
+    /// `channel`.
+    #[allow(unused_variables)]
+    fn channel_data_sent(&self, channel: ChannelId, len: usize) {}
+
+    /// A channel was opened, by the client or by the server.
+    #[allow(unused_variables)]
+    fn channel_opened(&self, channel: ChannelId) {}
+
+    /// A channel was closed, or the connection ended while it was open.
+    #[allow(unused_variables)]
+    fn channel_closed(&self, channel: ChannelId) {}
+
+    /// A key exchange completed, `rekey` being `false` for the first
+    /// one of a connection.
+    #[allow(unused_variables)]
+    fn kex_completed(&self, rekey: bool) {}
+
+    /// An authentication attempt failed (the initial `none` request of
+    /// clients isn't counted).
+    fn auth_failed(&self) {}
+}
@@ -0,0 +1,160 @@
+//! Subsystems of session channels, such as `sftp` or `netconf`, each
+//! served by its own handler.
+//!
+//! A [`SubsystemRegistry`] maps subsystem names to factories, which
+//! take the channel and return the future serving it. Pass the session
+//! channels to [`SubsystemRegistry::serve`], which waits for their
+//! `subsystem` request, and replies to it: with a failure if the
+//! subsystem is unknown or already serving as many channels as
+//! allowed, else with a success before running the factory's future.
+//!
+//! ```ignore
+//! let mut subsystems = SubsystemRegistry::new();
+//! subsystems
+//!     .register("sftp", |channel| async move {
+//!         russh_sftp::server::run(channel.into_stream(), SftpSession::default()).await;
+//!         Ok(())
+//!     })
+//!     .limit("sftp", 16);
+//!
+//! // In the handler, with the default `subsystem_request`:
+//! async fn channel_open_session(
+//!     &mut self,
+//!     channel: Channel<Msg>,
+//!     _: &mut Session,
+//! ) -> Result<bool, Self::Error> {
+//!     let subsystems = self.subsystems.clone();
+//!     tokio::spawn(async move { subsystems.serve(channel).await });
+//!     Ok(true)
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+
+use super::Msg;
+use crate::logging::debug;
+use crate::{Channel, ChannelMsg};
+
+type SubsystemFuture = Pin<Box<dyn Future<Output = io::Result<()>> + Send>>;
+type Factory = dyn Fn(Channel<Msg>) -> SubsystemFuture + Send + Sync;
+
+struct Subsystem {
+    factory: Arc<Factory>,
+    /// The channels this subsystem may still serve.
+    slots: Option<Arc<Semaphore>>,
+}
+
+/// Handlers of subsystems, by name. Clones share their concurrency
+/// limits, so that a registry cloned for each connection limits the
+/// channels of the whole server.
+#[derive(Clone, Default)]
+pub struct SubsystemRegistry {
+    subsystems: HashMap<String, Arc<Subsystem>>,
+}
+
+impl std::fmt::Debug for SubsystemRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SubsystemRegistry")
+            .field("subsystems", &self.subsystems.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl SubsystemRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve subsystem `name` with the futures returned by `factory`,
+    /// each one reading and writing the channel it is given. The
+    /// channel is closed when the future returns.
+    pub fn register<F, Fut>(&mut self, name: impl Into<String>, factory: F) -> &mut Self
+    where
+        F: Fn(Channel<Msg>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        let factory: Arc<Factory> = Arc::new(move |channel| Box::pin(factory(channel)));
+        self.subsystems.insert(
+            name.into(),
+            Arc::new(Subsystem {
+                factory,
+                slots: None,
+            }),
+        );
+        self
+    }
+
+    /// Serve at most `max` channels at the same time with subsystem
+    /// `name`, which must be registered. Other requests are refused.
+    pub fn limit(&mut self, name: &str, max: usize) -> &mut Self {
+        if let Some(subsystem) = self.subsystems.get_mut(name) {
+            let factory = subsystem.factory.clone();
+            *subsystem = Arc::new(Subsystem {
+                factory,
+                slots: Some(Arc::new(Semaphore::new(max))),
+            });
+        }
+        self
+    }
+
+    /// Whether subsystem `name` is registered.
+    pub fn contains(&self, name: &str) -> bool {
+        self.subsystems.contains_key(name)
+    }
+
+    /// Wait for the `subsystem` request of a session channel, and
+    /// [`start`](Self::start) the subsystem. Messages received before
+    /// are ignored, and this returns if the channel is closed first.
+    pub async fn serve(&self, mut channel: Channel<Msg>) -> io::Result<()> {
+        loop {
+            match channel.wait().await {
+                Some(ChannelMsg::RequestSubsystem { name, .. }) => {
+                    return self.start(channel, &name).await
+                }
+                Some(ChannelMsg::Close) | None => return Ok(()),
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// Reply to the `subsystem` request for `name` on `channel`, and
+    /// serve it until its handler returns.
+    pub async fn start(&self, channel: Channel<Msg>, name: &str) -> io::Result<()> {
+        let id = channel.id();
+        let sender = channel.sender.clone();
+        let reply = |msg| {
+            let sender = sender.clone();
+            async move {
+                sender
+                    .send(Msg::Channel(id, msg))
+                    .await
+                    .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "session closed"))
+            }
+        };
+
+        let Some(subsystem) = self.subsystems.get(name) else {
+            debug!("Unknown subsystem {:?}", name);
+            return reply(ChannelMsg::Failure).await;
+        };
+        let _slot = match subsystem.slots {
+            None => None,
+            Some(ref slots) => match slots.clone().try_acquire_owned() {
+                Ok(slot) => Some(slot),
+                Err(_) => {
+                    debug!("Too many channels for subsystem {:?}", name);
+                    return reply(ChannelMsg::Failure).await;
+                }
+            },
+        };
+        reply(ChannelMsg::Success).await?;
+        let result = (subsystem.factory)(channel).await;
+        reply(ChannelMsg::Close).await?;
+        result
+    }
+}
@@ -14,19 +14,23 @@
 //
 
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt::{Debug, Formatter};
 use std::num::Wrapping;
 
 use byteorder::{BigEndian, ByteOrder};
-use log::{debug, trace};
 use ssh_encoding::Encode;
 use tokio::sync::oneshot;
 
+use crate::channels::ChannelRef;
 use crate::cipher::SealingKey;
 use crate::kex::KexAlgorithm;
+use crate::logging::{debug, trace};
+use crate::observer::{observe, Direction};
 use crate::sshbuffer::SSHBuffer;
 use crate::{
     auth, cipher, mac, msg, negotiation, ChannelId, ChannelParams, CryptoVec, Disconnect, Limits,
+    Negotiated,
 };
 
 #[derive(Debug)]
@@ -51,6 +55,10 @@ pub(crate) struct Encrypted {
     pub compress: crate::compression::Compress,
     pub decompress: crate::compression::Decompress,
     pub compress_buffer: CryptoVec,
+    /// The shortest round-trip time observed, used to scale channel
+    /// windows.
+    pub rtt: Option<std::time::Duration>,
+    pub negotiated: Negotiated,
 }
 
 #[derive(Debug)]
@@ -108,13 +116,20 @@ impl<C> CommonSession<C> {
             enc.key = newkeys.key;
             enc.client_mac = newkeys.names.client_mac;
             enc.server_mac = newkeys.names.server_mac;
+            // Compression isn't renegotiated.
+            enc.negotiated = Negotiated {
+                compression_client_to_server: enc.negotiated.compression_client_to_server,
+                compression_server_to_client: enc.negotiated.compression_server_to_client,
+                ..Negotiated::new(&newkeys.names, &enc.session_id)
+            };
             self.cipher = newkeys.cipher;
             self.strict_kex = self.strict_kex || newkeys.names.strict_kex;
         }
     }
 
-    pub fn encrypted(&mut self, state: EncryptedState, newkeys: NewKeys) {
-        self.encrypted = Some(Encrypted {
+    pub fn encrypted(&mut self, state: EncryptedState, newkeys: NewKeys, is_server: bool) {
+        let negotiated = Negotiated::new(&newkeys.names, &newkeys.session_id);
+        let mut enc = Encrypted {
             exchange: Some(newkeys.exchange),
             kex: newkeys.kex,
             key: newkeys.key,
@@ -133,7 +148,17 @@ impl<C> CommonSession<C> {
             compress: crate::compression::Compress::None,
             compress_buffer: CryptoVec::new(),
             decompress: crate::compression::Decompress::None,
-        });
+            rtt: None,
+            negotiated,
+        };
+        let (outgoing, incoming) = if is_server {
+            (&enc.server_compression, &enc.client_compression)
+        } else {
+            (&enc.client_compression, &enc.server_compression)
+        };
+        outgoing.start_compress(false, &mut enc.compress);
+        incoming.start_decompress(false, &mut enc.decompress);
+        self.encrypted = Some(enc);
         self.cipher = newkeys.cipher;
         self.strict_kex = newkeys.names.strict_kex;
     }
@@ -226,40 +251,109 @@ impl Encrypted {
         }
     }
 
+    /// Account for `data` received on `channel`, and adjust its window
+    /// if more than half of it is used, without opening it beyond
+    /// `window_limit`. Returns whether the window was adjusted.
     pub fn adjust_window_size(
         &mut self,
         channel: ChannelId,
         data: &[u8],
-        target: u32,
+        max_window_size: u32,
+        window_limit: Option<u32>,
     ) -> Result<bool, crate::Error> {
         if let Some(channel) = self.channels.get_mut(&channel) {
             trace!(
                 "adjust_window_size, channel = {}, size = {},",
                 channel.sender_channel,
-                target
+                channel.target_window_size
             );
             // Ignore extra data.
             // https://tools.ietf.org/html/rfc4254#section-5.2
             if data.len() as u32 <= channel.sender_window_size {
                 channel.sender_window_size -= data.len() as u32;
             }
-            if channel.sender_window_size < target / 2 {
+            if !data.is_empty() {
+                channel.data_at = russh_util::time::Instant::now();
+            }
+            let window_limit = match channel.inbound_rate {
+                Some(ref mut rate) => {
+                    // Open the window no further than the rate allows.
+                    rate.take(data.len());
+                    let tokens = u32::try_from(rate.available()).unwrap_or(u32::MAX);
+                    let limit = channel.sender_window_size.saturating_add(tokens);
+                    Some(window_limit.map_or(limit, |l| l.min(limit)))
+                }
+                None => window_limit,
+            };
+            let target = channel.target_window_size;
+            let limited = window_limit.is_some_and(|limit| limit < target);
+            let window = window_limit.map_or(target, |limit| limit.min(target));
+            if channel.sender_window_size < target / 2 && window > channel.sender_window_size {
                 debug!(
-                    "sender_window_size {:?}, target {:?}",
-                    channel.sender_window_size, target
+                    "sender_window_size {:?}, target {:?}, limit {:?}",
+                    channel.sender_window_size, target, window_limit
                 );
+                if limited {
+                    // The reader of the channel is behind: open the
+                    // window no more than it can take.
+                    push_packet!(self.write, {
+                        self.write.push(msg::CHANNEL_WINDOW_ADJUST);
+                        channel.recipient_channel.encode(&mut self.write)?;
+                        (window - channel.sender_window_size).encode(&mut self.write)?;
+                    });
+                    channel.sender_window_size = window;
+                    return Ok(true);
+                }
+                let now = russh_util::time::Instant::now();
+                if let (Some(rtt), Some(last)) = (self.rtt, channel.window_adjusted_at) {
+                    // If the other side used this part of the window in
+                    // less than two round trips, its adjustments don't
+                    // arrive in time, and the window limits the
+                    // throughput.
+                    let used = f64::from(target - channel.sender_window_size) / f64::from(target);
+                    let elapsed = now.duration_since(last).as_secs_f64();
+                    if elapsed < 2. * rtt.as_secs_f64() * used {
+                        channel.target_window_size =
+                            target.saturating_mul(2).min(max_window_size).max(target);
+                        debug!(
+                            "growing window of channel {} to {}",
+                            channel.sender_channel, channel.target_window_size
+                        );
+                    }
+                }
+                channel.window_adjusted_at = Some(now);
                 push_packet!(self.write, {
                     self.write.push(msg::CHANNEL_WINDOW_ADJUST);
                     channel.recipient_channel.encode(&mut self.write)?;
-                    (target - channel.sender_window_size).encode(&mut self.write)?;
+                    (channel.target_window_size - channel.sender_window_size)
+                        .encode(&mut self.write)?;
                 });
-                channel.sender_window_size = target;
+                channel.sender_window_size = channel.target_window_size;
                 return Ok(true);
             }
         }
         Ok(false)
     }
 
+    pub fn target_window_size(&self, channel: ChannelId) -> u32 {
+        self.channels
+            .get(&channel)
+            .map_or(0, |channel| channel.target_window_size)
+    }
+
+    /// Change the window we keep open for the other side on `channel`,
+    /// which takes effect at the next adjustment.
+    pub fn set_target_window_size(&mut self, channel: ChannelId, target: u32) {
+        if let Some(channel) = self.channels.get_mut(&channel) {
+            channel.target_window_size = target
+        }
+    }
+
+    /// Record a round-trip time measurement.
+    pub fn observe_rtt(&mut self, rtt: std::time::Duration) {
+        self.rtt = Some(self.rtt.map_or(rtt, |r| r.min(rtt)));
+    }
+
     fn flush_channel(
         write: &mut CryptoVec,
         channel: &mut ChannelParams,
@@ -321,12 +415,97 @@ impl Encrypted {
         Ok(())
     }
 
+    /// How long until the rate limits of a channel let through the
+    /// data they hold back, in either direction.
+    pub(crate) fn throttle_delay(&self) -> Option<std::time::Duration> {
+        self.channels
+            .values()
+            .flat_map(|channel| {
+                let outbound = channel.outbound_rate.as_ref().and_then(|rate| {
+                    let queued: usize = channel
+                        .pending_data
+                        .iter()
+                        .map(|(buf, _, from)| buf.len() - from)
+                        .sum();
+                    let next = channel
+                        .recipient_window_size
+                        .min(channel.recipient_maximum_packet_size);
+                    match (queued as u64).min(u64::from(next)) {
+                        0 => None,
+                        next => rate.delay(next),
+                    }
+                });
+                let inbound = channel
+                    .inbound_rate
+                    .as_ref()
+                    .filter(|_| channel.sender_window_size < channel.target_window_size / 2)
+                    .and_then(|rate| {
+                        rate.delay(u64::from(
+                            channel.target_window_size - channel.sender_window_size,
+                        ))
+                    });
+                outbound.into_iter().chain(inbound)
+            })
+            .min()
+    }
+
+    /// The channels that neither sent nor received data for `timeout`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn idle_channels(&self, timeout: std::time::Duration) -> Vec<ChannelId> {
+        let now = russh_util::time::Instant::now();
+        self.channels
+            .values()
+            .filter(|channel| channel.confirmed && !channel.pending_close)
+            .filter(|channel| now.duration_since(channel.data_at) >= timeout)
+            .map(|channel| channel.sender_channel)
+            .collect()
+    }
+
+    /// When data was last sent or received on a channel, and the time
+    /// until the first channel is idle for `channel_timeout`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn idleness(
+        &self,
+        channel_timeout: Option<std::time::Duration>,
+    ) -> (
+        Option<russh_util::time::Instant>,
+        Option<std::time::Duration>,
+    ) {
+        let now = russh_util::time::Instant::now();
+        let last = self.channels.values().map(|channel| channel.data_at).max();
+        let first_idle = channel_timeout.and_then(|timeout| {
+            self.channels
+                .values()
+                .filter(|channel| channel.confirmed && !channel.pending_close)
+                .map(|channel| timeout.saturating_sub(now.duration_since(channel.data_at)))
+                .min()
+        });
+        (last, first_idle)
+    }
+
     fn has_pending_data_mut(&mut self, channel: ChannelId) -> Option<&mut ChannelParams> {
         self.channels
             .get_mut(&channel)
             .filter(|c| !c.pending_data.is_empty())
     }
 
+    /// Tell the writers of each channel how much of their data is still
+    /// queued.
+    pub(crate) fn update_queued(&self, channels: &HashMap<ChannelId, ChannelRef>) {
+        for (id, channel_ref) in channels {
+            let queued: usize = self.channels.get(id).map_or(0, |channel| {
+                channel
+                    .pending_data
+                    .iter()
+                    .map(|(buf, _, from)| buf.len() - from)
+                    .sum()
+            });
+            channel_ref
+                .window_size()
+                .set_queued(u32::try_from(queued).unwrap_or(u32::MAX));
+        }
+    }
+
     pub fn has_pending_data(&self, channel: ChannelId) -> bool {
         if let Some(channel) = self.channels.get(&channel) {
             !channel.pending_data.is_empty()
@@ -355,6 +534,15 @@ impl Encrypted {
             #[allow(clippy::indexing_slicing)] // length checked
             &buf0[from..]
         };
+        if let Some(ref rate) = channel.outbound_rate {
+            let available = usize::try_from(rate.available()).unwrap_or(usize::MAX);
+            if buf.len() > available {
+                #[allow(clippy::indexing_slicing)] // length checked
+                {
+                    buf = &buf[..available]
+                }
+            }
+        }
         let buf_len = buf.len();
 
         while !buf.is_empty() {
@@ -387,6 +575,12 @@ impl Encrypted {
             }
         }
         trace!("buf.len() = {:?}, buf_len = {:?}", buf.len(), buf_len);
+        if let Some(ref mut rate) = channel.outbound_rate {
+            rate.take(buf_len)
+        }
+        if buf_len > 0 {
+            channel.data_at = russh_util::time::Instant::now();
+        }
         Ok(buf_len)
     }
 
@@ -415,7 +609,7 @@ impl Encrypted {
     ) -> Result<(), crate::Error> {
         if let Some(channel) = self.channels.get_mut(&channel) {
             assert!(channel.confirmed);
-            if !channel.pending_data.is_empty() {
+            if !channel.pending_data.is_empty() || self.rekey.is_some() {
                 channel.pending_data.push_back((buf0, Some(ext), 0));
                 return Ok(());
             }
@@ -446,7 +640,16 @@ impl Encrypted {
                 let packet = self
                     .compress
                     .compress(to_write, &mut self.compress_buffer)?;
+                // Observe the payload before compression.
+                let observer = write_buffer.observer.take();
+                observe(
+                    &observer,
+                    Direction::Outbound,
+                    write_buffer.seqn.0,
+                    to_write,
+                );
                 cipher.write(packet, write_buffer);
+                write_buffer.observer = observer;
                 self.write_cursor += 4 + len
             }
         }
@@ -484,6 +687,9 @@ impl Encrypted {
                     recipient_channel: 0,
                     sender_channel: ChannelId(self.last_channel_id.0),
                     sender_window_size: window_size,
+                    target_window_size: window_size,
+                    window_adjusted_at: None,
+                    open_sent_at: Some(russh_util::time::Instant::now()),
                     recipient_window_size: 0,
                     sender_maximum_packet_size: maxpacket,
                     recipient_maximum_packet_size: 0,
@@ -492,6 +698,9 @@ impl Encrypted {
                     pending_data: std::collections::VecDeque::new(),
                     pending_eof: false,
                     pending_close: false,
+                    inbound_rate: None,
+                    outbound_rate: None,
+                    data_at: russh_util::time::Instant::now(),
                 });
                 return ChannelId(self.last_channel_id.0);
             }
@@ -593,6 +802,9 @@ pub(crate) struct KexDh {
     pub names: negotiation::Names,
     pub key: usize,
     pub session_id: Option<CryptoVec>,
+    /// The algorithm, once a message of a multi-step exchange (such
+    /// as a group exchange request) has been received.
+    pub kex: Option<Box<dyn KexAlgorithm + Send>>,
 }
 
 pub(crate) struct KexDhDone {
@@ -664,7 +876,30 @@ pub(crate) enum GlobalRequestResponse {
     TcpIpForward(oneshot::Sender<Option<u32>>),
     /// request was for CancelTcpIpForward, sends true for success or false for failure
     CancelTcpIpForward(oneshot::Sender<bool>),
+    /// request was for a client's RemoteForward, which is registered
+    /// with the bound port on success
+    RemoteForward {
+        reply_channel: oneshot::Sender<Option<u32>>,
+        address: String,
+        port: u32,
+        connections: tokio::sync::mpsc::UnboundedSender<crate::client::ForwardedTcpIp>,
+    },
     /// request was for StreamLocalForward, sends true for success or false for failure
     StreamLocalForward(oneshot::Sender<bool>),
     CancelStreamLocalForward(oneshot::Sender<bool>),
+    /// request was for HostKeysProve, the signatures are checked against these keys
+    HostKeysProve(Vec<ssh_key::PublicKey>),
+}
+
+/// The data signed by the server to prove it owns a host key, in
+/// reply to a `hostkeys-prove-00@openssh.com` request.
+pub(crate) fn host_keys_prove_data(
+    session_id: &[u8],
+    key: &ssh_key::PublicKey,
+) -> Result<CryptoVec, crate::Error> {
+    let mut data = CryptoVec::new();
+    "hostkeys-prove-00@openssh.com".encode(&mut data)?;
+    session_id.encode(&mut data)?;
+    key.to_bytes()?.encode(&mut data)?;
+    Ok(data)
 }
@@ -0,0 +1,701 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use ssh_encoding::{Decode, Encode};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{oneshot, Mutex};
+
+use super::*;
+use crate::logging::debug;
+use crate::{Channel, ChannelId, ChannelMsg};
+
+type Pending = HashMap<u32, oneshot::Sender<(u8, Vec<u8>)>>;
+
+struct Inner {
+    writer: Mutex<Box<dyn AsyncWrite + Send + Unpin>>,
+    pending: Arc<std::sync::Mutex<Pending>>,
+    next_id: AtomicU32,
+}
+
+/// An SFTP client. Requests can be sent concurrently from clones of
+/// the client, which share the same channel.
+///
+/// ```no_run
+/// # async fn f(channel: russh::Channel<russh::client::Msg>) -> Result<(), russh::Error> {
+/// use tokio::io::AsyncReadExt;
+///
+/// let sftp = russh::sftp::SftpClient::from_channel(channel).await?;
+/// let mut file = sftp.open("/etc/hostname", russh::sftp::OpenFlags::READ, &Default::default()).await?;
+/// let mut hostname = String::new();
+/// file.read_to_string(&mut hostname).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct SftpClient {
+    inner: Arc<Inner>,
+    version: u32,
+    extensions: Arc<Vec<(String, String)>>,
+}
+
+impl std::fmt::Debug for SftpClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SftpClient")
+            .field("version", &self.version)
+            .field("extensions", &self.extensions)
+            .finish()
+    }
+}
+
+impl SftpClient {
+    /// Request the `sftp` subsystem on a session channel, and start
+    /// the protocol on it.
+    pub async fn from_channel<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static>(
+        mut channel: Channel<S>,
+    ) -> Result<Self, crate::Error> {
+        channel.request_subsystem(true, "sftp").await?;
+        loop {
+            match channel.wait().await {
+                Some(ChannelMsg::Success) => break,
+                Some(ChannelMsg::Failure) => return Err(crate::Error::RequestDenied),
+                Some(msg) => debug!("sftp: ignoring {:?}", msg),
+                None => return Err(crate::Error::HUP),
+            }
+        }
+        Self::new(channel.into_stream()).await
+    }
+
+    /// Start the protocol on `stream`, which is usually a channel where
+    /// the `sftp` subsystem was started.
+    pub async fn new<S: AsyncRead + AsyncWrite + Send + 'static>(
+        stream: S,
+    ) -> Result<Self, crate::Error> {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let mut init = Vec::new();
+        VERSION.encode(&mut init)?;
+        writer.write_all(&frame(packet::INIT, &init)).await?;
+        writer.flush().await?;
+
+        let version = read_packet(&mut reader).await?.ok_or(crate::Error::HUP)?;
+        let (typ, mut r) = version.split_first().ok_or(crate::Error::Inconsistent)?;
+        if *typ != packet::VERSION {
+            return Err(crate::Error::Inconsistent);
+        }
+        let version = u32::decode(&mut r)?;
+        let mut extensions = Vec::new();
+        while !r.is_empty() {
+            extensions.push((String::decode(&mut r)?, String::decode(&mut r)?));
+        }
+        debug!("sftp version {}, extensions {:?}", version, extensions);
+
+        let pending = Arc::new(std::sync::Mutex::new(Pending::new()));
+        let pending_ = pending.clone();
+        russh_util::runtime::spawn(async move {
+            loop {
+                let packet = match read_packet(&mut reader).await {
+                    Ok(Some(packet)) => packet,
+                    Ok(None) => break,
+                    Err(e) => {
+                        debug!("sftp: {:?}", e);
+                        break;
+                    }
+                };
+                let Some((typ, mut r)) = packet.split_first() else {
+                    break;
+                };
+                let Ok(id) = u32::decode(&mut r) else {
+                    break;
+                };
+                let sender = pending_.lock().ok().and_then(|mut p| p.remove(&id));
+                if let Some(sender) = sender {
+                    let _ = sender.send((*typ, r.to_vec()));
+                } else {
+                    debug!("sftp: reply to unknown request {}", id);
+                }
+            }
+            // Fail all the pending requests.
+            if let Ok(mut pending) = pending_.lock() {
+                pending.clear()
+            }
+        });
+
+        Ok(SftpClient {
+            inner: Arc::new(Inner {
+                writer: Mutex::new(Box::new(writer)),
+                pending,
+                next_id: AtomicU32::new(0),
+            }),
+            version,
+            extensions: Arc::new(extensions),
+        })
+    }
+
+    /// The protocol version of the server.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// The extensions announced by the server, such as
+    /// `posix-rename@openssh.com`.
+    pub fn extensions(&self) -> &[(String, String)] {
+        &self.extensions
+    }
+
+    /// Send a request and wait for its reply.
+    async fn request(&self, typ: u8, body: &[u8]) -> Result<(u8, Vec<u8>), crate::Error> {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.inner
+            .pending
+            .lock()
+            .map_err(|_| crate::Error::Inconsistent)?
+            .insert(id, sender);
+        let mut packet = Vec::with_capacity(body.len() + 4);
+        id.encode(&mut packet)?;
+        packet.extend_from_slice(body);
+        {
+            let mut writer = self.inner.writer.lock().await;
+            writer.write_all(&frame(typ, &packet)).await?;
+            writer.flush().await?;
+        }
+        receiver.await.map_err(|_| crate::Error::HUP)
+    }
+
+    /// Send a request whose reply is a status.
+    async fn request_status(&self, typ: u8, body: &[u8]) -> Result<(), crate::Error> {
+        let (typ, reply) = self.request(typ, body).await?;
+        expect(packet::STATUS, typ, &reply).map(|_| ())
+    }
+
+    async fn request_handle(&self, typ: u8, body: &[u8]) -> Result<Vec<u8>, crate::Error> {
+        let (typ, reply) = self.request(typ, body).await?;
+        let mut r = expect(packet::HANDLE, typ, &reply)?;
+        Ok(Vec::<u8>::decode(&mut r)?)
+    }
+
+    async fn request_attrs(&self, typ: u8, body: &[u8]) -> Result<FileAttributes, crate::Error> {
+        let (typ, reply) = self.request(typ, body).await?;
+        let mut r = expect(packet::ATTRS, typ, &reply)?;
+        FileAttributes::decode(&mut r)
+    }
+
+    async fn request_names(&self, typ: u8, body: &[u8]) -> Result<Vec<DirEntry>, crate::Error> {
+        let (typ, reply) = self.request(typ, body).await?;
+        decode_names(typ, &reply)
+    }
+
+    async fn request_name(&self, typ: u8, body: &[u8]) -> Result<String, crate::Error> {
+        self.request_names(typ, body)
+            .await?
+            .into_iter()
+            .next()
+            .map(|e| e.filename)
+            .ok_or(crate::Error::Inconsistent)
+    }
+
+    /// Open a file.
+    pub async fn open(
+        &self,
+        path: &str,
+        flags: OpenFlags,
+        attrs: &FileAttributes,
+    ) -> Result<File, crate::Error> {
+        let mut body = Vec::new();
+        path.encode(&mut body)?;
+        flags.bits().encode(&mut body)?;
+        attrs.encode(&mut body)?;
+        let handle = self.request_handle(packet::OPEN, &body).await?;
+        Ok(File::new(
+            self.clone(),
+            handle,
+            flags.contains(OpenFlags::APPEND),
+        ))
+    }
+
+    /// Create a file, or truncate it, for writing.
+    pub async fn create(&self, path: &str) -> Result<File, crate::Error> {
+        self.open(
+            path,
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+            &FileAttributes::default(),
+        )
+        .await
+    }
+
+    async fn close_handle(&self, handle: &[u8]) -> Result<(), crate::Error> {
+        let mut body = Vec::new();
+        handle.encode(&mut body)?;
+        self.request_status(packet::CLOSE, &body).await
+    }
+
+    /// Read at most `len` bytes at `offset`, returning an empty vector
+    /// at the end of the file.
+    async fn read_at(&self, handle: &[u8], offset: u64, len: u32) -> Result<Vec<u8>, crate::Error> {
+        let mut body = Vec::new();
+        handle.encode(&mut body)?;
+        offset.encode(&mut body)?;
+        len.encode(&mut body)?;
+        let (typ, reply) = self.request(packet::READ, &body).await?;
+        if typ == packet::STATUS && status(&reply)?.0 == StatusCode::Eof {
+            return Ok(Vec::new());
+        }
+        let mut r = expect(packet::DATA, typ, &reply)?;
+        Ok(Vec::<u8>::decode(&mut r)?)
+    }
+
+    async fn write_at(&self, handle: &[u8], offset: u64, data: &[u8]) -> Result<(), crate::Error> {
+        let mut body = Vec::new();
+        handle.encode(&mut body)?;
+        offset.encode(&mut body)?;
+        data.encode(&mut body)?;
+        self.request_status(packet::WRITE, &body).await
+    }
+
+    /// The attributes of a file, following symbolic links.
+    pub async fn stat(&self, path: &str) -> Result<FileAttributes, crate::Error> {
+        self.path_request_attrs(packet::STAT, path).await
+    }
+
+    /// The attributes of a file, without following symbolic links.
+    pub async fn lstat(&self, path: &str) -> Result<FileAttributes, crate::Error> {
+        self.path_request_attrs(packet::LSTAT, path).await
+    }
+
+    async fn path_request_attrs(
+        &self,
+        typ: u8,
+        path: &str,
+    ) -> Result<FileAttributes, crate::Error> {
+        let mut body = Vec::new();
+        path.encode(&mut body)?;
+        self.request_attrs(typ, &body).await
+    }
+
+    /// Change the attributes of a file.
+    pub async fn setstat(&self, path: &str, attrs: &FileAttributes) -> Result<(), crate::Error> {
+        let mut body = Vec::new();
+        path.encode(&mut body)?;
+        attrs.encode(&mut body)?;
+        self.request_status(packet::SETSTAT, &body).await
+    }
+
+    /// List the entries of a directory, including `.` and `..`.
+    pub async fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, crate::Error> {
+        let mut body = Vec::new();
+        path.encode(&mut body)?;
+        let handle = self.request_handle(packet::OPENDIR, &body).await?;
+        let mut handle_body = Vec::new();
+        handle.encode(&mut handle_body)?;
+        let mut entries = Vec::new();
+        let result = loop {
+            let (typ, reply) = match self.request(packet::READDIR, &handle_body).await {
+                Ok(reply) => reply,
+                Err(e) => break Err(e),
+            };
+            if typ == packet::STATUS {
+                match status(&reply) {
+                    Ok((StatusCode::Eof, _)) => break Ok(()),
+                    Ok((code, message)) => break Err(crate::Error::Sftp { code, message }),
+                    Err(e) => break Err(e),
+                }
+            }
+            match decode_names(typ, &reply) {
+                Ok(mut e) => entries.append(&mut e),
+                Err(e) => break Err(e),
+            }
+        };
+        self.close_handle(&handle).await?;
+        result.map(|_| entries)
+    }
+
+    /// Remove a file.
+    pub async fn remove(&self, path: &str) -> Result<(), crate::Error> {
+        let mut body = Vec::new();
+        path.encode(&mut body)?;
+        self.request_status(packet::REMOVE, &body).await
+    }
+
+    pub async fn mkdir(&self, path: &str, attrs: &FileAttributes) -> Result<(), crate::Error> {
+        let mut body = Vec::new();
+        path.encode(&mut body)?;
+        attrs.encode(&mut body)?;
+        self.request_status(packet::MKDIR, &body).await
+    }
+
+    pub async fn rmdir(&self, path: &str) -> Result<(), crate::Error> {
+        let mut body = Vec::new();
+        path.encode(&mut body)?;
+        self.request_status(packet::RMDIR, &body).await
+    }
+
+    /// Rename a file. Many servers refuse to overwrite an existing file.
+    pub async fn rename(&self, from: &str, to: &str) -> Result<(), crate::Error> {
+        let mut body = Vec::new();
+        from.encode(&mut body)?;
+        to.encode(&mut body)?;
+        self.request_status(packet::RENAME, &body).await
+    }
+
+    /// Create a symbolic link at `link` pointing to `target`.
+    pub async fn symlink(&self, target: &str, link: &str) -> Result<(), crate::Error> {
+        let mut body = Vec::new();
+        // OpenSSH has the arguments in the opposite order of the
+        // draft, and everybody followed it.
+        target.encode(&mut body)?;
+        link.encode(&mut body)?;
+        self.request_status(packet::SYMLINK, &body).await
+    }
+
+    pub async fn readlink(&self, path: &str) -> Result<String, crate::Error> {
+        let mut body = Vec::new();
+        path.encode(&mut body)?;
+        self.request_name(packet::READLINK, &body).await
+    }
+
+    /// The canonical absolute path of `path`. `"."` gives the current
+    /// directory of the server.
+    pub async fn canonicalize(&self, path: &str) -> Result<String, crate::Error> {
+        let mut body = Vec::new();
+        path.encode(&mut body)?;
+        self.request_name(packet::REALPATH, &body).await
+    }
+}
+
+fn decode_names(typ: u8, reply: &[u8]) -> Result<Vec<DirEntry>, crate::Error> {
+    let mut r = expect(packet::NAME, typ, reply)?;
+    let n = u32::decode(&mut r)?;
+    let mut entries = Vec::new();
+    for _ in 0..n {
+        entries.push(DirEntry {
+            filename: String::decode(&mut r)?,
+            longname: String::decode(&mut r)?,
+            attrs: FileAttributes::decode(&mut r)?,
+        })
+    }
+    Ok(entries)
+}
+
+/// Check the type of a reply, turning error statuses into errors.
+fn expect(expected: u8, typ: u8, reply: &[u8]) -> Result<&[u8], crate::Error> {
+    if typ == packet::STATUS {
+        let (code, message) = status(reply)?;
+        return match code {
+            StatusCode::Ok if expected == packet::STATUS => Ok(reply),
+            StatusCode::Ok => Err(crate::Error::Inconsistent),
+            code => Err(crate::Error::Sftp { code, message }),
+        };
+    }
+    if typ == expected {
+        Ok(reply)
+    } else {
+        Err(crate::Error::Inconsistent)
+    }
+}
+
+fn status(mut reply: &[u8]) -> Result<(StatusCode, String), crate::Error> {
+    let code = StatusCode::from(u32::decode(&mut reply)?);
+    // Some old servers don't send the message.
+    let message = String::decode(&mut reply).unwrap_or_default();
+    Ok((code, message))
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = Result<T, crate::Error>> + Send>>;
+
+/// An open file. Reads and writes start at the current offset, which
+/// advances like with a local file.
+///
+/// Close the file with [`File::close`] to get errors reported by the
+/// server; otherwise it is closed in the background when dropped.
+pub struct File {
+    client: SftpClient,
+    handle: Arc<Vec<u8>>,
+    offset: u64,
+    append: bool,
+    closed: bool,
+    read: Option<BoxFuture<Vec<u8>>>,
+    write: Option<BoxFuture<usize>>,
+    seek: Option<u64>,
+}
+
+impl std::fmt::Debug for File {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("File")
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+impl File {
+    fn new(client: SftpClient, handle: Vec<u8>, append: bool) -> Self {
+        File {
+            client,
+            handle: Arc::new(handle),
+            offset: 0,
+            append,
+            closed: false,
+            read: None,
+            write: None,
+            seek: None,
+        }
+    }
+
+    /// The attributes of the open file.
+    pub async fn metadata(&self) -> Result<FileAttributes, crate::Error> {
+        let mut body = Vec::new();
+        self.handle.encode(&mut body)?;
+        self.client.request_attrs(packet::FSTAT, &body).await
+    }
+
+    /// Change the attributes of the open file, for instance its size.
+    pub async fn set_metadata(&self, attrs: &FileAttributes) -> Result<(), crate::Error> {
+        let mut body = Vec::new();
+        self.handle.encode(&mut body)?;
+        attrs.encode(&mut body)?;
+        self.client.request_status(packet::FSETSTAT, &body).await
+    }
+
+    /// Close the file.
+    pub async fn close(mut self) -> Result<(), crate::Error> {
+        self.closed = true;
+        self.client.close_handle(&self.handle).await
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        if !self.closed {
+            let client = self.client.clone();
+            let handle = self.handle.clone();
+            russh_util::runtime::spawn(async move {
+                if let Err(e) = client.close_handle(&handle).await {
+                    debug!("sftp: closing file: {:?}", e);
+                }
+            });
+        }
+    }
+}
+
+fn io_error(e: crate::Error) -> io::Error {
+    match e {
+        crate::Error::IO(e) => e,
+        crate::Error::Sftp { code, message } => io::Error::new(code.io_error_kind(), message),
+        e => io::Error::other(e),
+    }
+}
+
+impl AsyncRead for File {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let client = this.client.clone();
+        let handle = this.handle.clone();
+        let offset = this.offset;
+        let read = this.read.get_or_insert_with(|| {
+            let len = buf.remaining().min(MAX_DATA_SIZE) as u32;
+            Box::pin(async move { client.read_at(&handle, offset, len).await })
+        });
+        let result = match read.as_mut().poll(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(result) => result,
+        };
+        this.read = None;
+        let data = result.map_err(io_error)?;
+        // The request was sized for a previous buffer, which may have
+        // been larger.
+        let n = data.len().min(buf.remaining());
+        buf.put_slice(data.get(..n).unwrap_or_default());
+        this.offset += n as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for File {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let client = this.client.clone();
+        let handle = this.handle.clone();
+        let offset = this.offset;
+        let write = this.write.get_or_insert_with(|| {
+            let data = buf
+                .get(..buf.len().min(MAX_DATA_SIZE))
+                .unwrap_or_default()
+                .to_vec();
+            Box::pin(async move {
+                client.write_at(&handle, offset, &data).await?;
+                Ok(data.len())
+            })
+        });
+        let result = match write.as_mut().poll(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(result) => result,
+        };
+        this.write = None;
+        let n = result.map_err(io_error)?;
+        // In append mode, the server ignores the offset.
+        if !this.append {
+            this.offset += n as u64;
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Writes are acknowledged by the server before they complete.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for File {
+    fn start_seek(mut self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        let offset = match position {
+            io::SeekFrom::Start(o) => Some(o),
+            io::SeekFrom::Current(d) if d >= 0 => self.offset.checked_add(d as u64),
+            io::SeekFrom::Current(d) => self.offset.checked_sub(d.unsigned_abs()),
+            io::SeekFrom::End(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "seeking from the end requires the file size, see File::metadata",
+                ))
+            }
+        };
+        let offset =
+            offset.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek"))?;
+        self.seek = Some(offset);
+        Ok(())
+    }
+
+    fn poll_complete(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        if let Some(offset) = self.seek.take() {
+            self.offset = offset;
+            // Pending reads were made at the old offset.
+            self.read = None;
+        }
+        Poll::Ready(Ok(self.offset))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sftp_client_stat() {
+        let (client, mut server) = tokio::io::duplex(65536);
+        russh_util::runtime::spawn(async move {
+            let init = read_packet(&mut server).await.unwrap().unwrap();
+            assert_eq!(
+                Some(&init[..]),
+                frame(packet::INIT, &VERSION.to_be_bytes()).get(4..)
+            );
+            server
+                .write_all(&frame(packet::VERSION, &VERSION.to_be_bytes()))
+                .await
+                .unwrap();
+            while let Some(request) = read_packet(&mut server).await.unwrap() {
+                let (typ, mut r) = request.split_first().unwrap();
+                let id = u32::decode(&mut r).unwrap();
+                let path = String::decode(&mut r).unwrap();
+                assert_eq!(*typ, packet::STAT);
+                let mut reply = Vec::new();
+                id.encode(&mut reply).unwrap();
+                if path == "/file" {
+                    FileAttributes {
+                        size: Some(42),
+                        permissions: Some(0o100644),
+                        ..Default::default()
+                    }
+                    .encode(&mut reply)
+                    .unwrap();
+                    server
+                        .write_all(&frame(packet::ATTRS, &reply))
+                        .await
+                        .unwrap();
+                } else {
+                    u32::from(StatusCode::NoSuchFile)
+                        .encode(&mut reply)
+                        .unwrap();
+                    "no such file".encode(&mut reply).unwrap();
+                    "".encode(&mut reply).unwrap();
+                    server
+                        .write_all(&frame(packet::STATUS, &reply))
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+
+        let sftp = SftpClient::new(client).await.unwrap();
+        assert_eq!(sftp.version(), VERSION);
+        let attrs = sftp.stat("/file").await.unwrap();
+        assert_eq!(attrs.size, Some(42));
+        assert!(attrs.is_file());
+        assert!(matches!(
+            sftp.stat("/missing").await,
+            Err(crate::Error::Sftp {
+                code: StatusCode::NoSuchFile,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sftp_client_roundtrip() {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let dir = std::env::temp_dir().join(format!("russh-sftp-client-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (client, server) = tokio::io::duplex(65536);
+        russh_util::runtime::spawn(serve(server, FsHandler::new(&dir)));
+        let sftp = SftpClient::new(client).await.unwrap();
+
+        // Large enough to need several requests each way.
+        let data: Vec<u8> = (0..3 * MAX_DATA_SIZE + 17).map(|i| i as u8).collect();
+        let mut file = sftp.create("/data").await.unwrap();
+        file.write_all(&data).await.unwrap();
+        file.close().await.unwrap();
+        assert_eq!(std::fs::read(dir.join("data")).unwrap(), data);
+
+        let mut file = sftp
+            .open("/data", OpenFlags::READ, &FileAttributes::default())
+            .await
+            .unwrap();
+        let mut read = Vec::new();
+        file.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, data);
+        file.seek(io::SeekFrom::Start(MAX_DATA_SIZE as u64 + 1))
+            .await
+            .unwrap();
+        let mut byte = [0];
+        file.read_exact(&mut byte).await.unwrap();
+        assert_eq!(Some(&byte[0]), data.get(MAX_DATA_SIZE + 1));
+        file.close().await.unwrap();
+
+        // Failures of requests answered with a status are reported.
+        assert!(matches!(
+            sftp.remove("/missing").await,
+            Err(crate::Error::Sftp {
+                code: StatusCode::NoSuchFile,
+                ..
+            })
+        ));
+        sftp.remove("/data").await.unwrap();
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
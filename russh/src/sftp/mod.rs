@@ -0,0 +1,280 @@
+//! SFTP version 3, as specified in
+//! [draft-ietf-secsh-filexfer-02](https://datatracker.ietf.org/doc/html/draft-ietf-secsh-filexfer-02)
+//! and implemented by OpenSSH.
+//!
+//! [`SftpClient`] speaks SFTP over the `sftp` subsystem of a channel,
+//! and [`serve`] answers it with an [`SftpHandler`], such as the
+//! filesystem-backed [`FsHandler`].
+
+use ssh_encoding::{Decode, Encode};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+mod client;
+pub use client::{File, SftpClient};
+mod server;
+pub use server::{serve, FsHandler, SftpHandler};
+
+/// The protocol version implemented here.
+pub const VERSION: u32 = 3;
+
+/// Packets larger than this are refused, like OpenSSH does.
+pub(crate) const MAX_PACKET_SIZE: usize = 256 * 1024;
+
+/// Largest amount of data read or written in a single request.
+pub(crate) const MAX_DATA_SIZE: usize = 32768;
+
+pub(crate) mod packet {
+    pub const INIT: u8 = 1;
+    pub const VERSION: u8 = 2;
+    pub const OPEN: u8 = 3;
+    pub const CLOSE: u8 = 4;
+    pub const READ: u8 = 5;
+    pub const WRITE: u8 = 6;
+    pub const LSTAT: u8 = 7;
+    pub const FSTAT: u8 = 8;
+    pub const SETSTAT: u8 = 9;
+    pub const FSETSTAT: u8 = 10;
+    pub const OPENDIR: u8 = 11;
+    pub const READDIR: u8 = 12;
+    pub const REMOVE: u8 = 13;
+    pub const MKDIR: u8 = 14;
+    pub const RMDIR: u8 = 15;
+    pub const REALPATH: u8 = 16;
+    pub const STAT: u8 = 17;
+    pub const RENAME: u8 = 18;
+    pub const READLINK: u8 = 19;
+    pub const SYMLINK: u8 = 20;
+    pub const STATUS: u8 = 101;
+    pub const HANDLE: u8 = 102;
+    pub const DATA: u8 = 103;
+    pub const NAME: u8 = 104;
+    pub const ATTRS: u8 = 105;
+}
+
+/// Status codes of `SSH_FXP_STATUS` replies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusCode {
+    Ok,
+    Eof,
+    NoSuchFile,
+    PermissionDenied,
+    Failure,
+    BadMessage,
+    NoConnection,
+    ConnectionLost,
+    OpUnsupported,
+    Other(u32),
+}
+
+impl From<u32> for StatusCode {
+    fn from(code: u32) -> Self {
+        match code {
+            0 => StatusCode::Ok,
+            1 => StatusCode::Eof,
+            2 => StatusCode::NoSuchFile,
+            3 => StatusCode::PermissionDenied,
+            4 => StatusCode::Failure,
+            5 => StatusCode::BadMessage,
+            6 => StatusCode::NoConnection,
+            7 => StatusCode::ConnectionLost,
+            8 => StatusCode::OpUnsupported,
+            code => StatusCode::Other(code),
+        }
+    }
+}
+
+impl From<StatusCode> for u32 {
+    fn from(code: StatusCode) -> Self {
+        match code {
+            StatusCode::Ok => 0,
+            StatusCode::Eof => 1,
+            StatusCode::NoSuchFile => 2,
+            StatusCode::PermissionDenied => 3,
+            StatusCode::Failure => 4,
+            StatusCode::BadMessage => 5,
+            StatusCode::NoConnection => 6,
+            StatusCode::ConnectionLost => 7,
+            StatusCode::OpUnsupported => 8,
+            StatusCode::Other(code) => code,
+        }
+    }
+}
+
+impl StatusCode {
+    /// The closest `std::io::ErrorKind`.
+    pub fn io_error_kind(&self) -> std::io::ErrorKind {
+        use std::io::ErrorKind;
+        match self {
+            StatusCode::Eof => ErrorKind::UnexpectedEof,
+            StatusCode::NoSuchFile => ErrorKind::NotFound,
+            StatusCode::PermissionDenied => ErrorKind::PermissionDenied,
+            StatusCode::NoConnection => ErrorKind::NotConnected,
+            StatusCode::ConnectionLost => ErrorKind::ConnectionAborted,
+            StatusCode::OpUnsupported => ErrorKind::Unsupported,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+bitflags::bitflags! {
+    /// Flags of `SSH_FXP_OPEN`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct OpenFlags: u32 {
+        const READ = 0x01;
+        const WRITE = 0x02;
+        const APPEND = 0x04;
+        const CREATE = 0x08;
+        const TRUNCATE = 0x10;
+        const EXCLUDE = 0x20;
+    }
+}
+
+const ATTR_SIZE: u32 = 0x01;
+const ATTR_UIDGID: u32 = 0x02;
+const ATTR_PERMISSIONS: u32 = 0x04;
+const ATTR_ACMODTIME: u32 = 0x08;
+const ATTR_EXTENDED: u32 = 0x8000_0000;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// The attributes of a file. Absent fields are left unchanged by
+/// `setstat`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    pub size: Option<u64>,
+    /// The owner and group, which are only sent together.
+    pub uid_gid: Option<(u32, u32)>,
+    /// The Unix mode, including the file type bits.
+    pub permissions: Option<u32>,
+    /// The access and modification times, in seconds since the epoch,
+    /// which are only sent together.
+    pub atime_mtime: Option<(u32, u32)>,
+    pub extended: Vec<(String, String)>,
+}
+
+impl FileAttributes {
+    pub fn is_dir(&self) -> bool {
+        self.file_type() == Some(S_IFDIR)
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type() == Some(S_IFREG)
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type() == Some(S_IFLNK)
+    }
+
+    fn file_type(&self) -> Option<u32> {
+        self.permissions.map(|p| p & S_IFMT)
+    }
+
+    pub(crate) fn encode(&self, w: &mut Vec<u8>) -> Result<(), crate::Error> {
+        let mut flags = 0;
+        if self.size.is_some() {
+            flags |= ATTR_SIZE
+        }
+        if self.uid_gid.is_some() {
+            flags |= ATTR_UIDGID
+        }
+        if self.permissions.is_some() {
+            flags |= ATTR_PERMISSIONS
+        }
+        if self.atime_mtime.is_some() {
+            flags |= ATTR_ACMODTIME
+        }
+        if !self.extended.is_empty() {
+            flags |= ATTR_EXTENDED
+        }
+        flags.encode(w)?;
+        if let Some(size) = self.size {
+            size.encode(w)?;
+        }
+        if let Some((uid, gid)) = self.uid_gid {
+            uid.encode(w)?;
+            gid.encode(w)?;
+        }
+        if let Some(permissions) = self.permissions {
+            permissions.encode(w)?;
+        }
+        if let Some((atime, mtime)) = self.atime_mtime {
+            atime.encode(w)?;
+            mtime.encode(w)?;
+        }
+        if !self.extended.is_empty() {
+            (self.extended.len() as u32).encode(w)?;
+            for (name, value) in &self.extended {
+                name.encode(w)?;
+                value.encode(w)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn decode(r: &mut &[u8]) -> Result<Self, crate::Error> {
+        let flags = u32::decode(r)?;
+        let mut attrs = FileAttributes::default();
+        if flags & ATTR_SIZE != 0 {
+            attrs.size = Some(u64::decode(r)?);
+        }
+        if flags & ATTR_UIDGID != 0 {
+            attrs.uid_gid = Some((u32::decode(r)?, u32::decode(r)?));
+        }
+        if flags & ATTR_PERMISSIONS != 0 {
+            attrs.permissions = Some(u32::decode(r)?);
+        }
+        if flags & ATTR_ACMODTIME != 0 {
+            attrs.atime_mtime = Some((u32::decode(r)?, u32::decode(r)?));
+        }
+        if flags & ATTR_EXTENDED != 0 {
+            let n = u32::decode(r)?;
+            for _ in 0..n {
+                attrs
+                    .extended
+                    .push((String::decode(r)?, String::decode(r)?));
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+/// An entry of a directory listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub filename: String,
+    /// The `ls -l` style description of the file, sent by the server.
+    pub longname: String,
+    pub attrs: FileAttributes,
+}
+
+/// Read a length-prefixed packet, returning `None` at the end of the
+/// stream.
+pub(crate) async fn read_packet<R: AsyncRead + Unpin>(
+    r: &mut R,
+) -> Result<Option<Vec<u8>>, crate::Error> {
+    let mut len = [0; 4];
+    match r.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 || len > MAX_PACKET_SIZE {
+        return Err(crate::Error::PacketSize(len));
+    }
+    let mut packet = vec![0; len];
+    r.read_exact(&mut packet).await?;
+    Ok(Some(packet))
+}
+
+/// Frame a packet of type `typ`.
+pub(crate) fn frame(typ: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(body.len() + 5);
+    packet.extend_from_slice(&(body.len() as u32 + 1).to_be_bytes());
+    packet.push(typ);
+    packet.extend_from_slice(body);
+    packet
+}
@@ -0,0 +1,915 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+
+use async_trait::async_trait;
+use ssh_encoding::{Decode, Encode};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use super::*;
+use crate::logging::debug;
+
+/// The callbacks of an SFTP server, one for each request of the
+/// protocol. Requests that aren't implemented fail with
+/// [`StatusCode::OpUnsupported`].
+///
+/// Handles are opaque strings chosen by the handler. Reading past the
+/// end of a file, or past the last entry of a directory, should fail
+/// with [`StatusCode::Eof`].
+///
+/// Note: this is an `async_trait`. Click `[source]` on the right to see actual async function definitions.
+#[async_trait]
+pub trait SftpHandler: Send {
+    /// Called with the version and the extensions of the client. The
+    /// extensions returned are announced to the client.
+    #[allow(unused_variables)]
+    async fn init(
+        &mut self,
+        version: u32,
+        extensions: Vec<(String, String)>,
+    ) -> Result<Vec<(String, String)>, StatusCode> {
+        Ok(Vec::new())
+    }
+
+    #[allow(unused_variables)]
+    async fn open(
+        &mut self,
+        path: String,
+        flags: OpenFlags,
+        attrs: FileAttributes,
+    ) -> Result<String, StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    /// Close a file or directory handle.
+    #[allow(unused_variables)]
+    async fn close(&mut self, handle: String) -> Result<(), StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    /// Read at most `len` bytes at `offset`.
+    #[allow(unused_variables)]
+    async fn read(&mut self, handle: String, offset: u64, len: u32) -> Result<Vec<u8>, StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    #[allow(unused_variables)]
+    async fn write(
+        &mut self,
+        handle: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<(), StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    /// The attributes of `path`, without following symbolic links.
+    #[allow(unused_variables)]
+    async fn lstat(&mut self, path: String) -> Result<FileAttributes, StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    /// The attributes of an open file.
+    #[allow(unused_variables)]
+    async fn fstat(&mut self, handle: String) -> Result<FileAttributes, StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    #[allow(unused_variables)]
+    async fn setstat(&mut self, path: String, attrs: FileAttributes) -> Result<(), StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    #[allow(unused_variables)]
+    async fn fsetstat(&mut self, handle: String, attrs: FileAttributes) -> Result<(), StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    #[allow(unused_variables)]
+    async fn opendir(&mut self, path: String) -> Result<String, StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    /// The next entries of a directory opened with `opendir`.
+    #[allow(unused_variables)]
+    async fn readdir(&mut self, handle: String) -> Result<Vec<DirEntry>, StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    #[allow(unused_variables)]
+    async fn remove(&mut self, path: String) -> Result<(), StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    #[allow(unused_variables)]
+    async fn mkdir(&mut self, path: String, attrs: FileAttributes) -> Result<(), StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    #[allow(unused_variables)]
+    async fn rmdir(&mut self, path: String) -> Result<(), StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    /// The canonical absolute form of `path`.
+    #[allow(unused_variables)]
+    async fn realpath(&mut self, path: String) -> Result<String, StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    /// The attributes of `path`, following symbolic links.
+    #[allow(unused_variables)]
+    async fn stat(&mut self, path: String) -> Result<FileAttributes, StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    #[allow(unused_variables)]
+    async fn rename(&mut self, from: String, to: String) -> Result<(), StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    #[allow(unused_variables)]
+    async fn readlink(&mut self, path: String) -> Result<String, StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+
+    /// Create a symbolic link at `link` pointing to `target`.
+    #[allow(unused_variables)]
+    async fn symlink(&mut self, target: String, link: String) -> Result<(), StatusCode> {
+        Err(StatusCode::OpUnsupported)
+    }
+}
+
+/// Serve SFTP on `stream` until the client closes it. On a server,
+/// `stream` is usually the [`Channel::into_stream`](crate::Channel::into_stream)
+/// of a channel where the client requested the `sftp` subsystem:
+///
+/// ```no_run
+/// # async fn f(channel: russh::Channel<russh::server::Msg>) -> Result<(), russh::Error> {
+/// let fs = russh::sftp::FsHandler::new("/srv/sftp");
+/// russh::sftp::serve(channel.into_stream(), fs).await
+/// # }
+/// ```
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin, H: SftpHandler>(
+    mut stream: S,
+    mut handler: H,
+) -> Result<(), crate::Error> {
+    let Some(init) = read_packet(&mut stream).await? else {
+        return Ok(());
+    };
+    let (typ, mut r) = init.split_first().ok_or(crate::Error::Inconsistent)?;
+    if *typ != packet::INIT {
+        return Err(crate::Error::Inconsistent);
+    }
+    let version = u32::decode(&mut r)?;
+    let mut extensions = Vec::new();
+    while !r.is_empty() {
+        extensions.push((String::decode(&mut r)?, String::decode(&mut r)?));
+    }
+    debug!(
+        "sftp client version {}, extensions {:?}",
+        version, extensions
+    );
+    let mut reply = Vec::new();
+    VERSION.encode(&mut reply)?;
+    if let Ok(extensions) = handler.init(version, extensions).await {
+        for (name, data) in extensions {
+            name.encode(&mut reply)?;
+            data.encode(&mut reply)?;
+        }
+    }
+    stream.write_all(&frame(packet::VERSION, &reply)).await?;
+    stream.flush().await?;
+
+    while let Some(request) = read_packet(&mut stream).await? {
+        let (typ, mut r) = request.split_first().ok_or(crate::Error::Inconsistent)?;
+        let id = u32::decode(&mut r)?;
+        let mut reply = Vec::new();
+        id.encode(&mut reply)?;
+        let typ = match handle_request(&mut handler, *typ, r, &mut reply).await {
+            Ok(typ) => typ,
+            Err(code) => {
+                reply.truncate(4);
+                encode_status(code, &mut reply)?;
+                packet::STATUS
+            }
+        };
+        stream.write_all(&frame(typ, &reply)).await?;
+        stream.flush().await?;
+    }
+    Ok(())
+}
+
+fn encode_status(code: StatusCode, w: &mut Vec<u8>) -> Result<(), crate::Error> {
+    let message = match code {
+        StatusCode::Ok => "Success",
+        StatusCode::Eof => "End of file",
+        StatusCode::NoSuchFile => "No such file",
+        StatusCode::PermissionDenied => "Permission denied",
+        StatusCode::BadMessage => "Bad message",
+        StatusCode::OpUnsupported => "Operation unsupported",
+        _ => "Failure",
+    };
+    u32::from(code).encode(w)?;
+    message.encode(w)?;
+    "en".encode(w)?;
+    Ok(())
+}
+
+/// Call the handler for one request, writing the body of the reply
+/// after the request id and returning its type. Requests answered by
+/// a status, including successful ones, return the status as an error.
+fn encode_err<E>(_: E) -> StatusCode {
+    StatusCode::Failure
+}
+
+async fn handle_request<H: SftpHandler>(
+    handler: &mut H,
+    typ: u8,
+    mut r: &[u8],
+    w: &mut Vec<u8>,
+) -> Result<u8, StatusCode> {
+    let r = &mut r;
+    let string = |r: &mut &[u8]| String::decode(r).map_err(|_| StatusCode::BadMessage);
+    let attrs = |r: &mut &[u8]| FileAttributes::decode(r).map_err(|_| StatusCode::BadMessage);
+    let reply_type = match typ {
+        packet::OPEN => {
+            let path = string(r)?;
+            let flags = u32::decode(r).map_err(|_| StatusCode::BadMessage)?;
+            let attrs = attrs(r)?;
+            let handle = handler
+                .open(path, OpenFlags::from_bits_truncate(flags), attrs)
+                .await?;
+            handle.encode(w).map_err(encode_err)?;
+            packet::HANDLE
+        }
+        packet::CLOSE => {
+            handler.close(string(r)?).await?;
+            return Err(StatusCode::Ok);
+        }
+        packet::READ => {
+            let handle = string(r)?;
+            let offset = u64::decode(r).map_err(|_| StatusCode::BadMessage)?;
+            let len = u32::decode(r).map_err(|_| StatusCode::BadMessage)?;
+            let len = len.min(MAX_DATA_SIZE as u32);
+            let data = handler.read(handle, offset, len).await?;
+            data.encode(w).map_err(encode_err)?;
+            packet::DATA
+        }
+        packet::WRITE => {
+            let handle = string(r)?;
+            let offset = u64::decode(r).map_err(|_| StatusCode::BadMessage)?;
+            let data = Vec::<u8>::decode(r).map_err(|_| StatusCode::BadMessage)?;
+            handler.write(handle, offset, data).await?;
+            return Err(StatusCode::Ok);
+        }
+        packet::LSTAT | packet::STAT | packet::FSTAT => {
+            let arg = string(r)?;
+            let attrs = match typ {
+                packet::LSTAT => handler.lstat(arg).await?,
+                packet::STAT => handler.stat(arg).await?,
+                _ => handler.fstat(arg).await?,
+            };
+            attrs.encode(w).map_err(encode_err)?;
+            packet::ATTRS
+        }
+        packet::SETSTAT => {
+            let path = string(r)?;
+            handler.setstat(path, attrs(r)?).await?;
+            return Err(StatusCode::Ok);
+        }
+        packet::FSETSTAT => {
+            let handle = string(r)?;
+            handler.fsetstat(handle, attrs(r)?).await?;
+            return Err(StatusCode::Ok);
+        }
+        packet::OPENDIR => {
+            let handle = handler.opendir(string(r)?).await?;
+            handle.encode(w).map_err(encode_err)?;
+            packet::HANDLE
+        }
+        packet::READDIR => {
+            let entries = handler.readdir(string(r)?).await?;
+            encode_names(&entries, w).map_err(encode_err)?;
+            packet::NAME
+        }
+        packet::REMOVE => {
+            handler.remove(string(r)?).await?;
+            return Err(StatusCode::Ok);
+        }
+        packet::MKDIR => {
+            let path = string(r)?;
+            handler.mkdir(path, attrs(r)?).await?;
+            return Err(StatusCode::Ok);
+        }
+        packet::RMDIR => {
+            handler.rmdir(string(r)?).await?;
+            return Err(StatusCode::Ok);
+        }
+        packet::REALPATH | packet::READLINK => {
+            let path = string(r)?;
+            let path = if typ == packet::REALPATH {
+                handler.realpath(path).await?
+            } else {
+                handler.readlink(path).await?
+            };
+            let entry = DirEntry {
+                longname: path.clone(),
+                filename: path,
+                attrs: FileAttributes::default(),
+            };
+            encode_names(&[entry], w).map_err(encode_err)?;
+            packet::NAME
+        }
+        packet::RENAME => {
+            let from = string(r)?;
+            handler.rename(from, string(r)?).await?;
+            return Err(StatusCode::Ok);
+        }
+        packet::SYMLINK => {
+            // In the OpenSSH order, see `SftpClient::symlink`.
+            let target = string(r)?;
+            handler.symlink(target, string(r)?).await?;
+            return Err(StatusCode::Ok);
+        }
+        typ => {
+            debug!("sftp: unsupported request {}", typ);
+            return Err(StatusCode::OpUnsupported);
+        }
+    };
+    Ok(reply_type)
+}
+
+fn encode_names(entries: &[DirEntry], w: &mut Vec<u8>) -> Result<(), crate::Error> {
+    (entries.len() as u32).encode(w)?;
+    for entry in entries {
+        entry.filename.encode(w)?;
+        entry.longname.encode(w)?;
+        entry.attrs.encode(w)?;
+    }
+    Ok(())
+}
+
+enum Handle {
+    File(tokio::fs::File),
+    /// The entries not yet sent.
+    Dir(Vec<DirEntry>),
+}
+
+/// An [`SftpHandler`] serving a directory of the local filesystem,
+/// which appears as `/` to clients. Paths can't escape the directory,
+/// neither with `..` nor with symbolic links: links are only followed
+/// if they resolve inside it, and clients can only create relative
+/// links that stay inside it.
+pub struct FsHandler {
+    root: PathBuf,
+    handles: HashMap<String, Handle>,
+    next_handle: u64,
+}
+
+impl std::fmt::Debug for FsHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FsHandler")
+            .field("root", &self.root)
+            .field("handles", &self.handles.len())
+            .finish()
+    }
+}
+
+/// Directory entries sent in a single `SSH_FXP_NAME` reply.
+const READDIR_BATCH: usize = 100;
+
+impl FsHandler {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        FsHandler {
+            root: root.as_ref().to_path_buf(),
+            handles: HashMap::new(),
+            next_handle: 0,
+        }
+    }
+
+    /// The path of the client as an absolute path below `/`, with
+    /// `.` and `..` resolved.
+    fn normalize(path: &str) -> PathBuf {
+        let mut normalized = PathBuf::from("/");
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(c) => normalized.push(c),
+                Component::ParentDir => {
+                    normalized.pop();
+                }
+                _ => {}
+            }
+        }
+        normalized
+    }
+
+    async fn canonical_root(&self) -> Result<PathBuf, StatusCode> {
+        tokio::fs::canonicalize(&self.root).await.map_err(status)
+    }
+
+    /// The local path of the client's `path`, without following a
+    /// symbolic link in its last component. The directory containing
+    /// it is resolved, and must be inside the root.
+    async fn local_path_nofollow(&self, path: &str) -> Result<PathBuf, StatusCode> {
+        let root = self.canonical_root().await?;
+        let normalized = Self::normalize(path);
+        let Some(name) = normalized.file_name() else {
+            return Ok(root);
+        };
+        let mut parent = root.clone();
+        parent.extend(normalized.components().skip(1));
+        parent.pop();
+        let parent = tokio::fs::canonicalize(&parent).await.map_err(status)?;
+        if !parent.starts_with(&root) {
+            return Err(StatusCode::PermissionDenied);
+        }
+        Ok(parent.join(name))
+    }
+
+    /// The local path of the client's `path`, following symbolic
+    /// links, which must resolve inside the root. Paths that don't
+    /// exist yet are returned unresolved, so that they can be created.
+    async fn local_path(&self, path: &str) -> Result<PathBuf, StatusCode> {
+        let local = self.local_path_nofollow(path).await?;
+        match tokio::fs::canonicalize(&local).await {
+            Ok(resolved) if resolved.starts_with(self.canonical_root().await?) => Ok(resolved),
+            Ok(_) => Err(StatusCode::PermissionDenied),
+            // A dangling link could point anywhere.
+            Err(_) if tokio::fs::symlink_metadata(&local).await.is_ok() => {
+                Err(StatusCode::PermissionDenied)
+            }
+            Err(_) => Ok(local),
+        }
+    }
+
+    /// The depth below the root of the directory containing `local`, a
+    /// path returned by [`FsHandler::local_path_nofollow`].
+    async fn depth(&self, local: &Path) -> Result<usize, StatusCode> {
+        let root = self.canonical_root().await?;
+        local
+            .parent()
+            .and_then(|parent| parent.strip_prefix(&root).ok())
+            .map(|parent| parent.components().count())
+            .ok_or(StatusCode::PermissionDenied)
+    }
+
+    fn add_handle(&mut self, handle: Handle) -> String {
+        let name = self.next_handle.to_string();
+        self.next_handle += 1;
+        self.handles.insert(name.clone(), handle);
+        name
+    }
+
+    fn file(&mut self, handle: &str) -> Result<&mut tokio::fs::File, StatusCode> {
+        match self.handles.get_mut(handle) {
+            Some(Handle::File(file)) => Ok(file),
+            _ => Err(StatusCode::Failure),
+        }
+    }
+}
+
+/// Whether the relative path `target`, resolved from a directory
+/// `depth` levels below the root, stays inside the root.
+fn stays_inside(mut depth: usize, target: &Path) -> bool {
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return false,
+        }
+    }
+    true
+}
+
+fn status(e: std::io::Error) -> StatusCode {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => StatusCode::NoSuchFile,
+        std::io::ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+        std::io::ErrorKind::UnexpectedEof => StatusCode::Eof,
+        _ => StatusCode::Failure,
+    }
+}
+
+impl From<&std::fs::Metadata> for FileAttributes {
+    fn from(metadata: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            FileAttributes {
+                size: Some(metadata.size()),
+                uid_gid: Some((metadata.uid(), metadata.gid())),
+                permissions: Some(metadata.mode()),
+                atime_mtime: Some((metadata.atime() as u32, metadata.mtime() as u32)),
+                extended: Vec::new(),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let file_type = if metadata.is_dir() {
+                S_IFDIR | 0o755
+            } else if metadata.is_symlink() {
+                S_IFLNK | 0o777
+            } else if metadata.permissions().readonly() {
+                S_IFREG | 0o444
+            } else {
+                S_IFREG | 0o644
+            };
+            let secs = |t: std::io::Result<std::time::SystemTime>| {
+                t.ok()
+                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs() as u32)
+                    .unwrap_or(0)
+            };
+            FileAttributes {
+                size: Some(metadata.len()),
+                uid_gid: None,
+                permissions: Some(file_type),
+                atime_mtime: Some((secs(metadata.accessed()), secs(metadata.modified()))),
+                extended: Vec::new(),
+            }
+        }
+    }
+}
+
+/// An `ls -l` style line, as OpenSSH sends in directory listings.
+fn longname(filename: &str, attrs: &FileAttributes) -> String {
+    let mode = attrs.permissions.unwrap_or(0);
+    let kind = if attrs.is_dir() {
+        'd'
+    } else if attrs.is_symlink() {
+        'l'
+    } else {
+        '-'
+    };
+    let mut perms = String::with_capacity(10);
+    perms.push(kind);
+    for (i, c) in "rwxrwxrwx".chars().enumerate() {
+        perms.push(if mode & (0o400 >> i) != 0 { c } else { '-' });
+    }
+    let (uid, gid) = attrs.uid_gid.unwrap_or((0, 0));
+    format!(
+        "{} 1 {:<8} {:<8} {:>8} {}",
+        perms,
+        uid,
+        gid,
+        attrs.size.unwrap_or(0),
+        filename
+    )
+}
+
+async fn set_attributes(path: &Path, attrs: &FileAttributes) -> Result<(), StatusCode> {
+    if let Some(size) = attrs.size {
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .await
+            .map_err(status)?;
+        file.set_len(size).await.map_err(status)?;
+    }
+    #[cfg(unix)]
+    if let Some(mode) = attrs.permissions {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))
+            .await
+            .map_err(status)?;
+    }
+    if attrs.uid_gid.is_some() || attrs.atime_mtime.is_some() {
+        debug!("sftp: ignoring owner and times of {:?}", path);
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl SftpHandler for FsHandler {
+    async fn open(
+        &mut self,
+        path: String,
+        flags: OpenFlags,
+        attrs: FileAttributes,
+    ) -> Result<String, StatusCode> {
+        let mut options = tokio::fs::OpenOptions::new();
+        options
+            .read(flags.contains(OpenFlags::READ))
+            .write(flags.contains(OpenFlags::WRITE))
+            .append(flags.contains(OpenFlags::APPEND))
+            .truncate(flags.contains(OpenFlags::TRUNCATE));
+        if flags.contains(OpenFlags::EXCLUDE) {
+            options.create_new(true);
+        } else {
+            options.create(flags.contains(OpenFlags::CREATE));
+        }
+        #[cfg(unix)]
+        if let Some(mode) = attrs.permissions {
+            options.mode(mode & 0o7777);
+        }
+        #[cfg(not(unix))]
+        let _ = attrs;
+        let file = options
+            .open(self.local_path(&path).await?)
+            .await
+            .map_err(status)?;
+        Ok(self.add_handle(Handle::File(file)))
+    }
+
+    async fn close(&mut self, handle: String) -> Result<(), StatusCode> {
+        match self.handles.remove(&handle) {
+            Some(Handle::File(mut file)) => file.flush().await.map_err(status),
+            Some(Handle::Dir(_)) => Ok(()),
+            None => Err(StatusCode::Failure),
+        }
+    }
+
+    async fn read(&mut self, handle: String, offset: u64, len: u32) -> Result<Vec<u8>, StatusCode> {
+        let file = self.file(&handle)?;
+        file.seek(SeekFrom::Start(offset)).await.map_err(status)?;
+        let mut data = vec![0; len as usize];
+        let mut n = 0;
+        while n < data.len() {
+            let Some(buf) = data.get_mut(n..) else { break };
+            match file.read(buf).await.map_err(status)? {
+                0 => break,
+                k => n += k,
+            }
+        }
+        if n == 0 && len > 0 {
+            return Err(StatusCode::Eof);
+        }
+        data.truncate(n);
+        Ok(data)
+    }
+
+    async fn write(
+        &mut self,
+        handle: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<(), StatusCode> {
+        let file = self.file(&handle)?;
+        file.seek(SeekFrom::Start(offset)).await.map_err(status)?;
+        file.write_all(&data).await.map_err(status)
+    }
+
+    async fn lstat(&mut self, path: String) -> Result<FileAttributes, StatusCode> {
+        let metadata = tokio::fs::symlink_metadata(self.local_path_nofollow(&path).await?)
+            .await
+            .map_err(status)?;
+        Ok((&metadata).into())
+    }
+
+    async fn fstat(&mut self, handle: String) -> Result<FileAttributes, StatusCode> {
+        let metadata = self.file(&handle)?.metadata().await.map_err(status)?;
+        Ok((&metadata).into())
+    }
+
+    async fn setstat(&mut self, path: String, attrs: FileAttributes) -> Result<(), StatusCode> {
+        set_attributes(&self.local_path(&path).await?, &attrs).await
+    }
+
+    async fn fsetstat(&mut self, handle: String, attrs: FileAttributes) -> Result<(), StatusCode> {
+        let file = self.file(&handle)?;
+        if let Some(size) = attrs.size {
+            file.set_len(size).await.map_err(status)?;
+        }
+        #[cfg(unix)]
+        if let Some(mode) = attrs.permissions {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(mode & 0o7777))
+                .await
+                .map_err(status)?;
+        }
+        Ok(())
+    }
+
+    async fn opendir(&mut self, path: String) -> Result<String, StatusCode> {
+        let mut dir = tokio::fs::read_dir(self.local_path(&path).await?)
+            .await
+            .map_err(status)?;
+        let mut entries = Vec::new();
+        while let Some(entry) = dir.next_entry().await.map_err(status)? {
+            let Ok(metadata) = tokio::fs::symlink_metadata(entry.path()).await else {
+                continue;
+            };
+            let filename = entry.file_name().to_string_lossy().into_owned();
+            let attrs = FileAttributes::from(&metadata);
+            entries.push(DirEntry {
+                longname: longname(&filename, &attrs),
+                filename,
+                attrs,
+            })
+        }
+        // Entries are sent from the end.
+        entries.reverse();
+        Ok(self.add_handle(Handle::Dir(entries)))
+    }
+
+    async fn readdir(&mut self, handle: String) -> Result<Vec<DirEntry>, StatusCode> {
+        let Some(Handle::Dir(entries)) = self.handles.get_mut(&handle) else {
+            return Err(StatusCode::Failure);
+        };
+        if entries.is_empty() {
+            return Err(StatusCode::Eof);
+        }
+        let n = entries.len().saturating_sub(READDIR_BATCH);
+        Ok(entries.drain(n..).rev().collect())
+    }
+
+    async fn remove(&mut self, path: String) -> Result<(), StatusCode> {
+        tokio::fs::remove_file(self.local_path_nofollow(&path).await?)
+            .await
+            .map_err(status)
+    }
+
+    async fn mkdir(&mut self, path: String, attrs: FileAttributes) -> Result<(), StatusCode> {
+        let path = self.local_path_nofollow(&path).await?;
+        tokio::fs::create_dir(&path).await.map_err(status)?;
+        set_attributes(&path, &attrs).await
+    }
+
+    async fn rmdir(&mut self, path: String) -> Result<(), StatusCode> {
+        tokio::fs::remove_dir(self.local_path_nofollow(&path).await?)
+            .await
+            .map_err(status)
+    }
+
+    async fn realpath(&mut self, path: String) -> Result<String, StatusCode> {
+        Ok(Self::normalize(&path).to_string_lossy().into_owned())
+    }
+
+    async fn stat(&mut self, path: String) -> Result<FileAttributes, StatusCode> {
+        let metadata = tokio::fs::metadata(self.local_path(&path).await?)
+            .await
+            .map_err(status)?;
+        Ok((&metadata).into())
+    }
+
+    async fn rename(&mut self, from: String, to: String) -> Result<(), StatusCode> {
+        let to = self.local_path_nofollow(&to).await?;
+        // Like OpenSSH, refuse to overwrite an existing file.
+        if tokio::fs::symlink_metadata(&to).await.is_ok() {
+            return Err(StatusCode::Failure);
+        }
+        tokio::fs::rename(self.local_path_nofollow(&from).await?, to)
+            .await
+            .map_err(status)
+    }
+
+    async fn readlink(&mut self, path: String) -> Result<String, StatusCode> {
+        let local = self.local_path_nofollow(&path).await?;
+        let target = tokio::fs::read_link(&local).await.map_err(status)?;
+        // Don't reveal paths of the host: absolute targets are shown
+        // relative to the root, and targets outside of it are refused.
+        if target.is_absolute() {
+            let root = self.canonical_root().await?;
+            let inside = target
+                .strip_prefix(&root)
+                .or_else(|_| target.strip_prefix(&self.root))
+                .map_err(|_| StatusCode::PermissionDenied)?;
+            if !stays_inside(0, inside) {
+                return Err(StatusCode::PermissionDenied);
+            }
+            Ok(Path::new("/").join(inside).to_string_lossy().into_owned())
+        } else if stays_inside(self.depth(&local).await?, &target) {
+            Ok(target.to_string_lossy().into_owned())
+        } else {
+            Err(StatusCode::PermissionDenied)
+        }
+    }
+
+    #[cfg(unix)]
+    async fn symlink(&mut self, target: String, link: String) -> Result<(), StatusCode> {
+        // Absolute targets would be resolved on the host, outside of
+        // the root.
+        let local = self.local_path_nofollow(&link).await?;
+        if !stays_inside(self.depth(&local).await?, Path::new(&target)) {
+            return Err(StatusCode::PermissionDenied);
+        }
+        tokio::fs::symlink(target, local).await.map_err(status)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::sftp::SftpClient;
+
+    #[test]
+    fn test_sftp_normalize() {
+        assert_eq!(
+            FsHandler::normalize("../../etc/passwd"),
+            Path::new("/etc/passwd")
+        );
+        assert_eq!(FsHandler::normalize("a/./b/../c"), Path::new("/a/c"));
+        assert!(stays_inside(1, Path::new("../a/./b")));
+        assert!(!stays_inside(1, Path::new("../../a")));
+        assert!(!stays_inside(3, Path::new("/etc/passwd")));
+    }
+
+    #[tokio::test]
+    async fn test_sftp_fs_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("russh-sftp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (client, server) = tokio::io::duplex(65536);
+        russh_util::runtime::spawn(serve(server, FsHandler::new(&dir)));
+        let sftp = SftpClient::new(client).await.unwrap();
+
+        sftp.mkdir("/sub", &FileAttributes::default())
+            .await
+            .unwrap();
+        let mut file = sftp.create("/sub/hello").await.unwrap();
+        file.write_all(b"hello, world").await.unwrap();
+        file.close().await.unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("sub/hello")).unwrap(),
+            b"hello, world"
+        );
+
+        let mut file = sftp
+            .open("/sub/hello", OpenFlags::READ, &FileAttributes::default())
+            .await
+            .unwrap();
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).await.unwrap();
+        assert_eq!(contents, "hello, world");
+        assert_eq!(file.metadata().await.unwrap().size, Some(12));
+        file.close().await.unwrap();
+
+        sftp.rename("/sub/hello", "/sub/bye").await.unwrap();
+        let entries = sftp.read_dir("/sub").await.unwrap();
+        assert_eq!(entries.len(), 1);
+        let entry = entries.first().unwrap();
+        assert_eq!(entry.filename, "bye");
+        assert!(entry.attrs.is_file());
+
+        assert_eq!(sftp.canonicalize("/sub/../sub/.").await.unwrap(), "/sub");
+        assert!(matches!(
+            sftp.stat("/missing").await,
+            Err(crate::Error::Sftp {
+                code: StatusCode::NoSuchFile,
+                ..
+            })
+        ));
+        sftp.remove("/sub/bye").await.unwrap();
+        sftp.rmdir("/sub").await.unwrap();
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    fn denied<T>(r: Result<T, crate::Error>) -> bool {
+        matches!(
+            r,
+            Err(crate::Error::Sftp {
+                code: StatusCode::PermissionDenied,
+                ..
+            })
+        )
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sftp_fs_symlinks_stay_inside() {
+        let base = std::env::temp_dir().join(format!("russh-sftp-links-{}", std::process::id()));
+        let dir = base.join("root");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(base.join("secret"), b"secret").unwrap();
+        std::os::unix::fs::symlink(&base, dir.join("out")).unwrap();
+        std::os::unix::fs::symlink(base.join("secret"), dir.join("abs")).unwrap();
+        std::os::unix::fs::symlink(base.join("missing"), dir.join("dangling")).unwrap();
+        let (client, server) = tokio::io::duplex(65536);
+        russh_util::runtime::spawn(serve(server, FsHandler::new(&dir)));
+        let sftp = SftpClient::new(client).await.unwrap();
+
+        // Links created on the host can't be followed outside.
+        assert!(denied(sftp.stat("/out/secret").await));
+        assert!(denied(sftp.read_dir("/out").await));
+        assert!(denied(
+            sftp.open("/abs", OpenFlags::READ, &FileAttributes::default())
+                .await
+        ));
+        assert!(denied(sftp.create("/dangling").await));
+        assert!(!base.join("missing").exists());
+        // and don't reveal where they point.
+        assert!(denied(sftp.readlink("/out").await));
+        assert!(denied(sftp.readlink("/abs").await));
+        // The links themselves can still be listed and removed.
+        assert!(sftp.lstat("/out").await.unwrap().is_symlink());
+        sftp.remove("/dangling").await.unwrap();
+
+        // Clients can't create links pointing outside.
+        assert!(denied(sftp.symlink("/etc/passwd", "/passwd").await));
+        assert!(denied(sftp.symlink("../../secret", "/sub/secret").await));
+        assert!(denied(sftp.symlink("../secret", "/secret").await));
+
+        sftp.symlink("../sub", "/sub/self").await.unwrap();
+        assert_eq!(sftp.readlink("/sub/self").await.unwrap(), "../sub");
+        assert!(sftp.stat("/sub/self").await.unwrap().is_dir());
+        std::os::unix::fs::symlink(dir.join("sub"), dir.join("inside")).unwrap();
+        assert_eq!(sftp.readlink("/inside").await.unwrap(), "/sub");
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
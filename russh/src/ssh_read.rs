@@ -1,9 +1,9 @@
 use std::pin::Pin;
 
 use futures::task::*;
-use log::debug;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
 
+use crate::logging::debug;
 use crate::{CryptoVec, Error};
 
 /// The buffer to read the identification string (first line in the
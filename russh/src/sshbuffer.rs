@@ -14,8 +14,10 @@
 //
 
 use std::num::Wrapping;
+use std::sync::Arc;
 
 use super::*;
+use crate::observer::PacketObserver;
 
 /// The SSH client/server identification string.
 #[derive(Debug)]
@@ -70,6 +72,8 @@ pub struct SSHBuffer {
     // Sequence numbers are on 32 bits and wrap.
     // https://tools.ietf.org/html/rfc4253#section-6.4
     pub seqn: Wrapping<u32>,
+    /// Sees the payloads written with [`SealingKey::write`](crate::cipher::SealingKey::write).
+    pub observer: Option<Arc<dyn PacketObserver>>,
 }
 
 impl SSHBuffer {
@@ -79,6 +83,7 @@ impl SSHBuffer {
             len: 0,
             bytes: 0,
             seqn: Wrapping(0),
+            observer: None,
         }
     }
 
@@ -0,0 +1,234 @@
+//! Bandwidth shaping, with token buckets.
+
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::connection::*;
+
+/// A rate limit: `bytes_per_second` on average, and at most `burst`
+/// bytes at once after an idle period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub bytes_per_second: u64,
+    pub burst: u64,
+}
+
+impl RateLimit {
+    /// A limit of `bytes_per_second`, allowing bursts of one second.
+    pub fn new(bytes_per_second: u64) -> Self {
+        RateLimit {
+            bytes_per_second,
+            burst: bytes_per_second,
+        }
+    }
+
+    /// Allow bursts of `burst` bytes.
+    pub fn with_burst(self, burst: u64) -> Self {
+        RateLimit { burst, ..self }
+    }
+}
+
+/// A token bucket, filled with `rate.bytes_per_second` tokens per
+/// second up to `rate.burst`. Taking more tokens than available puts
+/// the bucket in debt, which is paid back before any more data goes
+/// through.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    bytes_per_second: f64,
+    burst: f64,
+    tokens: f64,
+    updated: russh_util::time::Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: RateLimit) -> Self {
+        // A bucket smaller than one byte would never let anything through.
+        let burst = rate.burst.max(1) as f64;
+        TokenBucket {
+            bytes_per_second: rate.bytes_per_second.max(1) as f64,
+            burst,
+            tokens: burst,
+            updated: russh_util::time::Instant::now(),
+        }
+    }
+
+    fn tokens(&self) -> f64 {
+        let elapsed = russh_util::time::Instant::now()
+            .duration_since(self.updated)
+            .as_secs_f64();
+        (self.tokens + elapsed * self.bytes_per_second).min(self.burst)
+    }
+
+    /// The number of bytes that can go through now.
+    pub(crate) fn available(&self) -> u64 {
+        self.tokens().max(0.) as u64
+    }
+
+    /// Count `n` bytes that went through.
+    pub(crate) fn take(&mut self, n: usize) {
+        self.tokens = self.tokens() - n as f64;
+        self.updated = russh_util::time::Instant::now();
+    }
+
+    /// How long until `n` bytes, or a full bucket if `n` is larger,
+    /// can go through, or `None` if they can now.
+    pub(crate) fn delay(&self, n: u64) -> Option<Duration> {
+        let missing = (n as f64).min(self.burst) - self.tokens();
+        if missing <= 0. {
+            return None;
+        }
+        // Round up, so that the tokens are there when we wake up.
+        Some(Duration::from_secs_f64(missing / self.bytes_per_second) + Duration::from_millis(1))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod connection {
+    use std::future::Future;
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+    use tokio::time::Sleep;
+
+    use super::{RateLimit, TokenBucket};
+
+    /// Limits on the data of each connection of a server.
+    #[derive(Debug, Clone, Default)]
+    pub struct DataLimits {
+        /// The rate of the bytes read from the connection.
+        pub inbound: Option<RateLimit>,
+        /// The rate of the bytes written to the connection.
+        pub outbound: Option<RateLimit>,
+        /// The rate of the data received on each channel. The windows
+        /// of the channels are opened no faster than this, so that the
+        /// client slows down, instead of filling our buffers.
+        pub channel_inbound: Option<RateLimit>,
+        /// The rate of the data sent on each channel. Data written
+        /// faster is queued, like when the window of the client is full.
+        pub channel_outbound: Option<RateLimit>,
+        /// The most bytes sent and received on a connection, after
+        /// which the client is disconnected.
+        pub max_bytes: Option<u64>,
+    }
+
+    impl DataLimits {
+        /// Start limiting `channel` at the channel rates.
+        pub(crate) fn apply(&self, channel: &mut crate::ChannelParams) {
+            channel.inbound_rate = self.channel_inbound.map(TokenBucket::new);
+            channel.outbound_rate = self.channel_outbound.map(TokenBucket::new);
+        }
+    }
+
+    /// A token bucket, and the timer to wait for its tokens.
+    struct Limiter {
+        bucket: TokenBucket,
+        sleep: Option<Pin<Box<Sleep>>>,
+    }
+
+    impl Limiter {
+        fn new(rate: Option<RateLimit>) -> Option<Self> {
+            Some(Limiter {
+                bucket: TokenBucket::new(rate?),
+                sleep: None,
+            })
+        }
+
+        /// Wait until the debt of the bucket is paid back.
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+            while let Some(delay) = self.bucket.delay(1) {
+                let deadline = tokio::time::Instant::now() + delay;
+                let sleep = self
+                    .sleep
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+                if sleep.deadline() < deadline {
+                    sleep.as_mut().reset(deadline)
+                }
+                futures::ready!(sleep.as_mut().poll(cx));
+            }
+            Poll::Ready(())
+        }
+    }
+
+    /// A stream whose reads and writes are limited to the rates of
+    /// [`DataLimits::inbound`] and [`DataLimits::outbound`].
+    pub(crate) struct Throttled<S> {
+        stream: S,
+        read: Option<Limiter>,
+        write: Option<Limiter>,
+    }
+
+    impl<S> Throttled<S> {
+        pub(crate) fn new(stream: S, limits: &DataLimits) -> Self {
+            Throttled {
+                stream,
+                read: Limiter::new(limits.inbound),
+                write: Limiter::new(limits.outbound),
+            }
+        }
+    }
+
+    impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = &mut *self;
+            if let Some(ref mut read) = this.read {
+                futures::ready!(read.poll_ready(cx));
+            }
+            let before = buf.filled().len();
+            futures::ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+            if let Some(ref mut read) = this.read {
+                read.bucket.take(buf.filled().len() - before);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = &mut *self;
+            if let Some(ref mut write) = this.write {
+                futures::ready!(write.poll_ready(cx));
+            }
+            let n = futures::ready!(Pin::new(&mut this.stream).poll_write(cx, buf))?;
+            if let Some(ref mut write) = this.write {
+                write.bucket.take(n);
+            }
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_shutdown(cx)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_debt() {
+        let mut bucket = TokenBucket::new(RateLimit::new(1000).with_burst(100));
+        assert_eq!(bucket.available(), 100);
+        assert_eq!(bucket.delay(50), None);
+        bucket.take(300);
+        assert_eq!(bucket.available(), 0);
+        // The debt of 200 bytes, and then 100 more bytes (a full
+        // bucket, rather than 500), take 300 ms.
+        let delay = bucket.delay(500).unwrap_or_default();
+        assert!(delay > Duration::from_millis(250) && delay <= Duration::from_millis(301));
+    }
+}
@@ -0,0 +1,58 @@
+//! # Transports
+//!
+//! SSH runs over any reliable byte stream, not only TCP: clients and
+//! servers accept any [`AsyncRead`](tokio::io::AsyncRead) +
+//! [`AsyncWrite`](tokio::io::AsyncWrite) + [`Unpin`] + [`Send`]
+//! stream, on which they exchange versions and keys before returning.
+//!
+//! - on the client side, [`client::connect`] opens a TCP connection,
+//!   and [`client::connect_stream`] runs the session on a stream opened
+//!   otherwise: a TLS or WebSocket connection, a Unix socket, the
+//!   stdio of a `ProxyCommand`, a channel of another session...
+//! - on the server side, [`server::run_stream`] serves one stream, and
+//!   [`server::Server::run_on_listener`] serves the connections of a
+//!   [`server::Listener`], such as a [`tokio::net::UnixListener`].
+//!   [`server::Listener::upgrade`] wraps the connections of a listener,
+//!   for instance in TLS:
+//!
+//! ```ignore
+//! use russh::server::{Listener, Server};
+//!
+//! let acceptor = tokio_rustls::TlsAcceptor::from(tls_config);
+//! let listener = tokio::net::TcpListener::bind("0.0.0.0:443")
+//!     .await?
+//!     .upgrade(move |stream| acceptor.accept(stream));
+//! server.run_on_listener(config, &listener).await?;
+//! ```
+//!
+//! Tests can run both sides in memory with [`connect_duplex`].
+
+use std::sync::Arc;
+
+use crate::{client, server};
+
+/// The size of the buffers of [`connect_duplex`].
+const DUPLEX_BUFFER_SIZE: usize = 65536;
+
+/// Serve a client session with `server_handler`, over an in-memory
+/// pipe, which is convenient to test handlers. The server session runs
+/// in the background, and its errors only appear as the end of the
+/// client session.
+pub async fn connect_duplex<S, C>(
+    server_config: Arc<server::Config>,
+    server_handler: S,
+    client_config: Arc<client::Config>,
+    client_handler: C,
+) -> Result<client::Handle<C>, C::Error>
+where
+    S: server::Handler + Send + 'static,
+    C: client::Handler + Send + 'static,
+{
+    let (client_stream, server_stream) = tokio::io::duplex(DUPLEX_BUFFER_SIZE);
+    russh_util::runtime::spawn(server::run_stream(
+        server_config,
+        server_stream,
+        server_handler,
+    ));
+    client::connect_stream(client_config, client_stream, client_handler).await
+}
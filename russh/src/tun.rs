@@ -0,0 +1,180 @@
+//! Layer 2 and layer 3 tunnels (`tun@openssh.com` channels), as
+//! opened by `ssh -w`.
+//!
+//! Tunnel channels carry packets, each prefixed by its length. In
+//! [`TunMode::PointToPoint`] mode, packets are also prefixed by their
+//! address family, which [`TunChannel`] adds and removes, so that
+//! applications only see IP packets (or Ethernet frames in
+//! [`TunMode::Ethernet`] mode).
+
+use std::convert::TryFrom;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::{Channel, ChannelId, ChannelMsg, CryptoVec};
+
+/// Ask the other side to pick the unit number of the tunnel device.
+pub const TUN_ANY_UNIT: u32 = 0x7fff_ffff;
+
+/// Address families in point-to-point mode, as sent by OpenSSH.
+const AF_INET: u32 = 2;
+const AF_INET6: u32 = 24;
+
+/// Tunnel mode requested when opening a `tun@openssh.com` channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunMode {
+    /// Layer 3: IPv4 and IPv6 packets.
+    PointToPoint,
+    /// Layer 2: Ethernet frames.
+    Ethernet,
+}
+
+impl From<TunMode> for u32 {
+    fn from(mode: TunMode) -> u32 {
+        match mode {
+            TunMode::PointToPoint => 1,
+            TunMode::Ethernet => 2,
+        }
+    }
+}
+
+impl TryFrom<u32> for TunMode {
+    type Error = crate::Error;
+
+    fn try_from(mode: u32) -> Result<Self, Self::Error> {
+        match mode {
+            1 => Ok(TunMode::PointToPoint),
+            2 => Ok(TunMode::Ethernet),
+            _ => Err(crate::Error::Inconsistent),
+        }
+    }
+}
+
+/// A `tun@openssh.com` channel, sending and receiving whole packets.
+#[derive(Debug)]
+pub struct TunChannel<S: From<(ChannelId, ChannelMsg)>> {
+    channel: Channel<S>,
+    mode: TunMode,
+    buffer: Vec<u8>,
+}
+
+impl<S: From<(ChannelId, ChannelMsg)> + Send + Sync + 'static> TunChannel<S> {
+    pub fn new(channel: Channel<S>, mode: TunMode) -> Self {
+        TunChannel {
+            channel,
+            mode,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn mode(&self) -> TunMode {
+        self.mode
+    }
+
+    /// The underlying channel.
+    pub fn into_inner(self) -> Channel<S> {
+        self.channel
+    }
+
+    /// Send one packet: an IP packet in point-to-point mode, or an
+    /// Ethernet frame.
+    pub async fn send(&self, packet: &[u8]) -> Result<(), crate::Error> {
+        let mut frame = CryptoVec::new();
+        frame_packet(self.mode, packet, &mut frame)?;
+        self.channel.data(&*frame).await
+    }
+
+    /// Receive the next packet. Returns `None` when the other side
+    /// closes the tunnel.
+    pub async fn recv(&mut self) -> Result<Option<Vec<u8>>, crate::Error> {
+        loop {
+            if let Some(packet) = unframe_packet(self.mode, &mut self.buffer)? {
+                return Ok(Some(packet));
+            }
+            match self.channel.wait().await {
+                Some(ChannelMsg::Data { data }) => self.buffer.extend_from_slice(&data),
+                Some(ChannelMsg::Eof) | Some(ChannelMsg::Close) | None => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+
+    /// Signal that no more packets will be sent.
+    pub async fn eof(&self) -> Result<(), crate::Error> {
+        self.channel.eof().await
+    }
+}
+
+fn frame_packet(mode: TunMode, packet: &[u8], out: &mut CryptoVec) -> Result<(), crate::Error> {
+    let af = match mode {
+        TunMode::Ethernet => None,
+        TunMode::PointToPoint => match packet.first().map(|b| b >> 4) {
+            Some(4) => Some(AF_INET),
+            Some(6) => Some(AF_INET6),
+            _ => return Err(crate::Error::Inconsistent),
+        },
+    };
+    let len = packet.len() + if af.is_some() { 4 } else { 0 };
+    out.extend(&(len as u32).to_be_bytes());
+    if let Some(af) = af {
+        out.extend(&af.to_be_bytes());
+    }
+    out.extend(packet);
+    Ok(())
+}
+
+/// Remove the first complete packet from `buffer`, if any.
+fn unframe_packet(mode: TunMode, buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, crate::Error> {
+    let Some(len) = buffer.get(..4).map(BigEndian::read_u32) else {
+        return Ok(None);
+    };
+    let end = 4 + len as usize;
+    if buffer.len() < end {
+        return Ok(None);
+    }
+    let mut packet: Vec<u8> = buffer.drain(..end).skip(4).collect();
+    if mode == TunMode::PointToPoint {
+        if packet.len() < 4 {
+            return Err(crate::Error::Inconsistent);
+        }
+        packet.drain(..4);
+    }
+    Ok(Some(packet))
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)] // Allow unwraps, expects and panics in the test suite
+
+    use super::*;
+
+    #[test]
+    fn test_tun_framing() {
+        let ipv6 = [0x60, 0, 0, 0, 0, 0, 59, 64];
+        let mut framed = CryptoVec::new();
+        frame_packet(TunMode::PointToPoint, &ipv6, &mut framed).unwrap();
+        assert_eq!(framed.get(..8), Some(&[0, 0, 0, 12, 0, 0, 0, 24][..]));
+
+        let mut buffer = framed.to_vec();
+        buffer.extend_from_slice(framed.get(..6).unwrap());
+        assert_eq!(
+            unframe_packet(TunMode::PointToPoint, &mut buffer).unwrap(),
+            Some(ipv6.to_vec())
+        );
+        assert_eq!(
+            unframe_packet(TunMode::PointToPoint, &mut buffer).unwrap(),
+            None
+        );
+        assert_eq!(buffer.len(), 6);
+
+        let frame = [0xff; 14];
+        let mut framed = CryptoVec::new();
+        frame_packet(TunMode::Ethernet, &frame, &mut framed).unwrap();
+        let mut buffer = framed.to_vec();
+        assert_eq!(
+            unframe_packet(TunMode::Ethernet, &mut buffer).unwrap(),
+            Some(frame.to_vec())
+        );
+        assert!(frame_packet(TunMode::PointToPoint, &frame, &mut framed).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+//! SSH over WebSocket (`websocket` feature), to go through HTTPS-only
+//! proxies and ingresses. The SSH byte stream is carried in binary
+//! messages, and text messages are refused.
+//!
+//! Clients connect with [`connect`], and servers accept connections
+//! with [`accept`], usually through
+//! [`Listener::upgrade`](crate::server::Listener::upgrade):
+//!
+//! ```no_run
+//! # async fn f<S: russh::server::Server + Send>(mut server: S, config: std::sync::Arc<russh::server::Config>) -> std::io::Result<()> {
+//! use russh::server::{Listener, Server};
+//!
+//! let listener = tokio::net::TcpListener::bind("0.0.0.0:8080")
+//!     .await?
+//!     .upgrade(russh::websocket::accept);
+//! server.run_on_listener(config, &listener).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes};
+use futures::{ready, Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::client;
+
+fn io_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
+/// A byte stream carried by a WebSocket connection.
+pub struct WebSocketTransport<S> {
+    inner: WebSocketStream<S>,
+    /// What remains of the last message received.
+    read: Bytes,
+}
+
+impl<S> WebSocketTransport<S> {
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        WebSocketTransport {
+            inner,
+            read: Bytes::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> WebSocketStream<S> {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketTransport<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while !self.read.has_remaining() {
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.read = data.into(),
+                // Pings are answered by tungstenite.
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "text message in a binary stream",
+                    )))
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(io_error(e))),
+            }
+        }
+        let n = self.read.remaining().min(buf.remaining());
+        buf.put_slice(&self.read.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketTransport<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(io_error)?;
+        Pin::new(&mut self.inner)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(io_error)
+    }
+}
+
+/// Accept a WebSocket connection on `stream`, after its HTTP upgrade
+/// request.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+) -> io::Result<WebSocketTransport<S>> {
+    let inner = tokio_tungstenite::accept_async(stream)
+        .await
+        .map_err(io_error)?;
+    Ok(WebSocketTransport::new(inner))
+}
+
+/// Connect to the server at `url` (`ws://` or, with the
+/// `websocket-rustls` feature, `wss://`), and start a session with it
+/// like [`client::connect_stream`].
+pub async fn connect<H: client::Handler + Send + 'static>(
+    config: Arc<client::Config>,
+    url: &str,
+    handler: H,
+) -> Result<client::Handle<H>, H::Error> {
+    let (inner, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .map_err(|e| crate::Error::from(io_error(e)))?;
+    client::connect_stream(config, WebSocketTransport::new(inner), handler).await
+}
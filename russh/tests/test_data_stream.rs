@@ -35,8 +35,8 @@ async fn stream(addr: SocketAddr, data: &[u8]) -> Result<(), anyhow::Error> {
 
     let mut session = russh::client::connect(config, addr, Client).await?;
     let mut channel = match session.authenticate_publickey("user", key).await {
-        Ok(true) => session.channel_open_session().await?,
-        Ok(false) => panic!("Authentication failed"),
+        Ok(result) if result.success() => session.channel_open_session().await?,
+        Ok(_) => panic!("Authentication failed"),
         Err(err) => return Err(err.into()),
     };
 
@@ -103,7 +103,6 @@ impl russh::server::Server for Server {
     }
 }
 
-#[async_trait::async_trait]
 impl russh::server::Handler for Server {
     type Error = anyhow::Error;
 